@@ -1,8 +1,12 @@
 mod bencode_value;
+#[cfg(feature = "mmap")]
+mod mmap;
 mod serde_bencode_de;
 mod serde_bencode_ser;
 
 pub use bencode_value::*;
+#[cfg(feature = "mmap")]
+pub use mmap::MmapFile;
 pub use serde_bencode_de::*;
 pub use serde_bencode_ser::*;
 
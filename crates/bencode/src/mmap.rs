@@ -0,0 +1,80 @@
+// Deserializing from a memory-mapped file. The OS pages the file in as the deserializer walks it,
+// instead of it being read into memory first, and borrowed types such as ByteBuf point into the
+// map. This is for huge files, e.g. .torrent files with hundreds of MB of piece hashes.
+//
+// It's not a streaming decoder: the whole file has to be mapped, and owned types still copy what
+// they deserialize.
+
+use std::{fs::File, path::Path};
+
+use anyhow::Context;
+use serde::Deserialize;
+
+use crate::from_bytes;
+
+/// A read-only memory-mapped file. Derefs to its contents.
+pub struct MmapFile {
+    mmap: memmap2::Mmap,
+}
+
+impl MmapFile {
+    /// Map the file at "path".
+    ///
+    /// # Safety
+    ///
+    /// The file must not be modified or truncated, by this or another process, while the
+    /// returned map or anything borrowed from it is alive. The contents would change under
+    /// references that are assumed immutable, and reading a truncated part kills the process
+    /// with SIGBUS.
+    pub unsafe fn open(path: impl AsRef<Path>) -> anyhow::Result<Self> {
+        let path = path.as_ref();
+        let file = File::open(path).with_context(|| format!("error opening {path:?}"))?;
+        // SAFETY: the map is read-only, and the caller guarantees that the file isn't modified
+        // while it's mapped.
+        let mmap = unsafe { memmap2::Mmap::map(&file) }
+            .with_context(|| format!("error mapping {path:?}"))?;
+        Ok(Self { mmap })
+    }
+
+    /// Deserialize the whole file. Borrowed types point into the map, so live as long as it.
+    pub fn deserialize<'de, T: Deserialize<'de>>(&'de self) -> anyhow::Result<T> {
+        from_bytes(&self.mmap)
+    }
+}
+
+impl std::ops::Deref for MmapFile {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        &self.mmap
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::MmapFile;
+    use crate::{BencodeValue, BencodeValueBorrowed};
+    use buffers::ByteBuf;
+
+    fn get<'a>(value: BencodeValueBorrowed<'a>, key: &'a [u8]) -> BencodeValueBorrowed<'a> {
+        match value {
+            BencodeValue::Dict(mut d) => d.remove(&ByteBuf(key)).unwrap(),
+            _ => panic!("expected a dict"),
+        }
+    }
+
+    #[test]
+    fn test_deserialize_mmap_borrows() {
+        // SAFETY: nothing writes to the test resources.
+        let file = unsafe {
+            MmapFile::open("../librqbit/resources/ubuntu-21.04-desktop-amd64.iso.torrent")
+        }
+        .unwrap();
+        let torrent: BencodeValueBorrowed = file.deserialize().unwrap();
+        let pieces = match get(get(torrent, b"info"), b"pieces") {
+            BencodeValue::Bytes(b) => b,
+            _ => panic!("expected bytes"),
+        };
+        assert!(file.as_ptr_range().contains(&pieces.as_ptr()));
+    }
+}
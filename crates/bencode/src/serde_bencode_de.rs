@@ -52,9 +52,12 @@ impl<'de> BencodeDeserializer<'de> {
                 let bytes_start = length_delim + 1;
                 let bytes_end = bytes_start + length;
                 let bytes = &self.buf.get(bytes_start..bytes_end).ok_or_else(|| {
+                    // Not printing the buffer, it can be hundreds of MB.
                     Error::custom(format!(
-                        "could not get byte range {}..{}, data in the buffer: {:?}",
-                        bytes_start, bytes_end, &self.buf
+                        "could not get byte range {}..{}, {} bytes in the buffer",
+                        bytes_start,
+                        bytes_end,
+                        self.buf.len()
                     ))
                     .set_context(self)
                 })?;
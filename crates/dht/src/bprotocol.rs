@@ -314,12 +314,20 @@ pub struct Response<BufT> {
     pub nodes: Option<CompactNodeInfo>,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub token: Option<BufT>,
+    /// BEP 33 bloom filters of the seeds and of the other peers, sent when scraping.
+    #[serde(rename = "BFsd", skip_serializing_if = "Option::is_none")]
+    pub bf_seeds: Option<BufT>,
+    #[serde(rename = "BFpe", skip_serializing_if = "Option::is_none")]
+    pub bf_peers: Option<BufT>,
 }
 
 #[derive(Debug, Serialize, Deserialize)]
 pub struct GetPeersRequest {
     pub id: Id20,
     pub info_hash: Id20,
+    /// Set to 1 to ask for BEP 33 scrape bloom filters.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub scrape: Option<u8>,
 }
 
 #[derive(Debug, Serialize, Deserialize)]
@@ -334,6 +342,9 @@ pub struct AnnouncePeer<BufT> {
     pub info_hash: Id20,
     pub port: u16,
     pub token: BufT,
+    /// Set to 1 by seeds (BEP 33).
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub seed: Option<u8>,
 }
 
 #[derive(Debug, Serialize, Deserialize)]
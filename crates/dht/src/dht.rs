@@ -1,6 +1,6 @@
 use std::{
     cmp::Reverse,
-    net::SocketAddr,
+    net::{Ipv4Addr, SocketAddr},
     str::FromStr,
     sync::{
         atomic::{AtomicU16, Ordering},
@@ -17,6 +17,7 @@ use crate::{
     },
     peer_store::PeerStore,
     routing_table::{InsertResult, NodeStatus, RoutingTable},
+    scrape::{DhtScrape, ScrapeWindow},
     INACTIVITY_TIMEOUT, REQUERY_INTERVAL, RESPONSE_TIMEOUT,
 };
 use anyhow::{bail, Context};
@@ -29,6 +30,7 @@ use futures::{
 
 use leaky_bucket::RateLimiter;
 use librqbit_core::{
+    bind::OutgoingBind,
     hash_id::Id20,
     peer_id::generate_peer_id,
     spawn_utils::{spawn, spawn_with_cancel},
@@ -50,6 +52,21 @@ pub struct DhtStats {
     pub id: Id20,
     pub outstanding_requests: usize,
     pub routing_table_size: usize,
+    /// Nodes in each bucket of the routing table, by status.
+    pub buckets: Vec<DhtBucketStats>,
+    /// Peers announced to us by other nodes, and the number of info hashes they are for.
+    pub stored_peers: usize,
+    pub stored_info_hashes: usize,
+}
+
+#[derive(Debug, Default, Serialize)]
+pub struct DhtBucketStats {
+    /// How many leading bits of the bucket's range are fixed.
+    pub bits: u8,
+    pub good: usize,
+    pub questionable: usize,
+    pub bad: usize,
+    pub unknown: usize,
 }
 
 struct OutstandingRequest {
@@ -101,10 +118,25 @@ trait RecursiveRequestCallbacks: Sized + Send + Sync + 'static {
     );
 }
 
+/// Asked before every requery of a peer lookup. While it returns true, the lookup starts
+/// from more nodes and is repeated sooner.
+pub type WantsMorePeers = Box<dyn Fn() -> bool + Send + Sync>;
+
+/// Asked before every announce of a peer lookup. Seeds are announced as such (BEP 33), so that
+/// scrapes count them.
+pub type IsSeed = Box<dyn Fn() -> bool + Send + Sync>;
+
+// How many of the closest nodes a peer lookup starts from, normally and when more peers are
+// wanted.
+const ROOT_NODES: usize = 8;
+const WIDE_ROOT_NODES: usize = 32;
+
 struct RecursiveRequestCallbacksGetPeers {
     // Id20::from_str("00000fffffffffffffffffffffffffffffffffff").unwrap()
     min_distance_to_announce: Id20,
     announce_port: Option<u16>,
+    wants_more_peers: Option<WantsMorePeers>,
+    is_seed: Option<IsSeed>,
 }
 
 impl RecursiveRequestCallbacks for RecursiveRequestCallbacksGetPeers {
@@ -141,6 +173,7 @@ impl RecursiveRequestCallbacks for RecursiveRequestCallbacksGetPeers {
             info_hash: req.info_hash,
             token: token.clone(),
             port: announce_port,
+            seed: self.is_seed.as_ref().is_some_and(|f| f()),
         });
 
         let _ = req.dht.worker_sender.send(WorkerSendRequest {
@@ -194,18 +227,27 @@ struct RecursiveRequest<C: RecursiveRequestCallbacks> {
 pub struct RequestPeersStream {
     rx: tokio::sync::mpsc::UnboundedReceiver<SocketAddr>,
     cancel_join_handle: tokio::task::JoinHandle<()>,
+    dht: Arc<DhtState>,
+    info_hash: Id20,
 }
 
 impl RequestPeersStream {
-    fn new(dht: Arc<DhtState>, info_hash: Id20, announce_port: Option<u16>) -> Self {
+    fn new(
+        dht: Arc<DhtState>,
+        info_hash: Id20,
+        announce_port: Option<u16>,
+        wants_more_peers: Option<WantsMorePeers>,
+        is_seed: Option<IsSeed>,
+    ) -> Self {
         let (peer_tx, peer_rx) = unbounded_channel();
         let (node_tx, node_rx) = unbounded_channel();
+        dht.scrapes.entry(info_hash).or_default().lookups += 1;
         let rp = Arc::new(RecursiveRequest {
             max_depth: 4,
             info_hash,
             useful_nodes_limit: 256,
             request: Request::GetPeers(info_hash),
-            dht,
+            dht: dht.clone(),
             useful_nodes: RwLock::new(Vec::new()),
             peer_tx,
             node_tx,
@@ -215,12 +257,16 @@ impl RequestPeersStream {
                 )
                 .unwrap(),
                 announce_port,
+                wants_more_peers,
+                is_seed,
             },
         });
         let join_handle = rp.request_peers_forever(node_rx);
         Self {
             rx: peer_rx,
             cancel_join_handle: join_handle,
+            dht,
+            info_hash,
         }
     }
 }
@@ -228,6 +274,10 @@ impl RequestPeersStream {
 impl Drop for RequestPeersStream {
     fn drop(&mut self) {
         self.cancel_join_handle.abort();
+        self.dht.scrapes.remove_if_mut(&self.info_hash, |_, w| {
+            w.lookups -= 1;
+            w.lookups == 0
+        });
     }
 }
 
@@ -336,9 +386,22 @@ impl RecursiveRequest<RecursiveRequestCallbacksGetPeers> {
                         let mut iteration = 0;
                         loop {
                             trace!("iteration {}", iteration);
-                            let sleep = match this.get_peers_root() {
+                            if let Some(mut w) = this.dht.scrapes.get_mut(&this.info_hash) {
+                                w.next_round();
+                            }
+                            let wide = this
+                                .callbacks
+                                .wants_more_peers
+                                .as_ref()
+                                .is_some_and(|f| f());
+                            let root_nodes = if wide { WIDE_ROOT_NODES } else { ROOT_NODES };
+                            let sleep = match this.get_peers_root(root_nodes) {
                                 Ok(0) => Duration::from_secs(1),
                                 Ok(n) if n < 8 => REQUERY_INTERVAL / 8 * (n as u32),
+                                Ok(_) if wide => {
+                                    debug!("more peers wanted, widened the lookup");
+                                    REQUERY_INTERVAL / 4
+                                }
                                 Ok(_) => REQUERY_INTERVAL,
                                 Err(e) => {
                                     error!("error in get_peers_root(): {e:?}");
@@ -373,7 +436,7 @@ impl RecursiveRequest<RecursiveRequestCallbacksGetPeers> {
         )
     }
 
-    fn get_peers_root(&self) -> anyhow::Result<usize> {
+    fn get_peers_root(&self, root_nodes: usize) -> anyhow::Result<usize> {
         let mut count = 0;
         for (id, addr) in self
             .dht
@@ -382,7 +445,7 @@ impl RecursiveRequest<RecursiveRequestCallbacksGetPeers> {
             .sorted_by_distance_from(self.info_hash)
             .iter()
             .map(|n| (n.id(), n.addr()))
-            .take(8)
+            .take(root_nodes)
         {
             count += 1;
             self.node_tx.send((Some(id), addr, 0))?;
@@ -419,6 +482,12 @@ impl<C: RecursiveRequestCallbacks> RecursiveRequest<C> {
             }
         };
 
+        if response.bf_seeds.is_some() || response.bf_peers.is_some() {
+            if let Some(mut w) = self.dht.scrapes.get_mut(&self.info_hash) {
+                w.add(response.bf_seeds.as_deref(), response.bf_peers.as_deref());
+            }
+        }
+
         if let Some(peers) = response.values {
             for peer in peers {
                 self.peer_tx.send(SocketAddr::V4(peer.addr))?;
@@ -546,6 +615,12 @@ pub struct DhtState {
     cancellation_token: CancellationToken,
 
     pub(crate) peer_store: PeerStore,
+
+    // BEP 33 scrape filters received by the running peer lookups.
+    scrapes: DashMap<Id20, ScrapeWindow>,
+
+    // See DhtConfig::announce_implied_port.
+    announce_implied_port: bool,
 }
 
 impl DhtState {
@@ -556,6 +631,7 @@ impl DhtState {
         listen_addr: SocketAddr,
         peer_store: PeerStore,
         cancellation_token: CancellationToken,
+        announce_implied_port: bool,
     ) -> Self {
         let routing_table = routing_table.unwrap_or_else(|| RoutingTable::new(id, None));
         Self {
@@ -568,6 +644,8 @@ impl DhtState {
             rate_limiter: make_rate_limiter(),
             peer_store,
             cancellation_token,
+            scrapes: Default::default(),
+            announce_implied_port,
         }
     }
 
@@ -619,6 +697,7 @@ impl DhtState {
                 kind: MessageKind::GetPeersRequest(GetPeersRequest {
                     id: self.id,
                     info_hash,
+                    scrape: Some(1),
                 }),
             },
             Request::FindNode(target) => Message {
@@ -640,13 +719,15 @@ impl DhtState {
                 info_hash,
                 token,
                 port,
+                seed,
             } => Message {
                 kind: MessageKind::AnnouncePeer(AnnouncePeer {
                     id: self.id,
-                    implied_port: 0,
+                    implied_port: self.announce_implied_port.into(),
                     info_hash,
                     port,
                     token,
+                    seed: seed.then_some(1),
                 }),
                 transaction_id: ByteString::from(transaction_id_buf.as_ref()),
                 version: None,
@@ -761,6 +842,17 @@ impl DhtState {
             MessageKind::GetPeersRequest(req) => {
                 let compact_node_info = generate_compact_nodes(req.info_hash);
                 let compact_peer_info = self.peer_store.get_for_info_hash(req.info_hash);
+                let scrape_filters = match req.scrape {
+                    Some(1) => self.peer_store.scrape_filters(req.info_hash),
+                    _ => None,
+                };
+                let (bf_seeds, bf_peers) = match scrape_filters {
+                    Some((seeds, peers)) => (
+                        Some(ByteString(seeds.as_bytes().to_vec())),
+                        Some(ByteString(peers.as_bytes().to_vec())),
+                    ),
+                    None => (None, None),
+                };
                 self.routing_table.write().mark_last_query(&req.id);
                 let message = Message {
                     transaction_id: msg.transaction_id,
@@ -773,6 +865,8 @@ impl DhtState {
                         token: Some(ByteString(
                             self.peer_store.gen_token_for(req.id, addr).to_vec(),
                         )),
+                        bf_seeds,
+                        bf_peers,
                     }),
                 };
                 self.worker_sender.send(WorkerSendRequest {
@@ -807,10 +901,35 @@ impl DhtState {
     }
 
     pub fn get_stats(&self) -> DhtStats {
+        let (routing_table_size, buckets) = {
+            let table = self.routing_table.read();
+            let buckets = table
+                .iter_buckets()
+                .map(|bucket| {
+                    let mut stats = DhtBucketStats {
+                        bits: bucket.bits,
+                        ..Default::default()
+                    };
+                    for node in bucket.leaf.nodes.iter() {
+                        match node.status() {
+                            NodeStatus::Good => stats.good += 1,
+                            NodeStatus::Questionable => stats.questionable += 1,
+                            NodeStatus::Bad => stats.bad += 1,
+                            NodeStatus::Unknown => stats.unknown += 1,
+                        }
+                    }
+                    stats
+                })
+                .collect();
+            (table.len(), buckets)
+        };
         DhtStats {
             id: self.id,
             outstanding_requests: self.inflight_by_transaction_id.len(),
-            routing_table_size: self.routing_table.read().len(),
+            routing_table_size,
+            buckets,
+            stored_peers: self.peer_store.peers_len(),
+            stored_info_hashes: self.peer_store.info_hashes_len(),
         }
     }
 }
@@ -823,6 +942,7 @@ enum Request {
         info_hash: Id20,
         token: ByteString,
         port: u16,
+        seed: bool,
     },
     Ping,
 }
@@ -891,6 +1011,12 @@ impl DhtWorker {
     }
 
     async fn bootstrap(&self, bootstrap_addrs: &[String]) -> anyhow::Result<()> {
+        if bootstrap_addrs.is_empty() {
+            // Nothing to bootstrap from, the routing table will be filled by incoming queries
+            // and the persisted nodes, if any.
+            info!("no DHT bootstrap nodes configured");
+            return Ok(());
+        }
         let mut futs = FuturesUnordered::new();
 
         for addr in bootstrap_addrs.iter() {
@@ -1137,6 +1263,12 @@ pub struct DhtConfig {
     pub listen_addr: Option<SocketAddr>,
     pub peer_store: Option<PeerStore>,
     pub cancellation_token: Option<CancellationToken>,
+    /// Set implied_port in announces, so that the nodes store the port our DHT packets come
+    /// from instead of the announced one. Behind a NAT that is the port mapped for us, which is
+    /// what peers connecting over UDP (uTP) can reach.
+    pub announce_implied_port: bool,
+    /// Send and receive only through this network interface, e.g. "wg0". Linux only.
+    pub bind_device: Option<String>,
 }
 
 impl DhtState {
@@ -1150,14 +1282,17 @@ impl DhtState {
     #[inline(never)]
     pub fn with_config(mut config: DhtConfig) -> BoxFuture<'static, anyhow::Result<Arc<Self>>> {
         async move {
-            let socket = match config.listen_addr {
-                Some(addr) => UdpSocket::bind(addr)
-                    .await
-                    .with_context(|| format!("error binding socket, address {addr}")),
-                None => UdpSocket::bind("0.0.0.0:0")
-                    .await
-                    .context("error binding socket, address 0.0.0.0:0"),
-            }?;
+            let bind = OutgoingBind {
+                device: config.bind_device.take(),
+                ..Default::default()
+            };
+            let addr = config
+                .listen_addr
+                .unwrap_or_else(|| SocketAddr::from((Ipv4Addr::UNSPECIFIED, 0)));
+            let socket = bind
+                .udp_bind(addr)
+                .await
+                .with_context(|| format!("error binding socket, address {addr}"))?;
 
             let listen_addr = socket
                 .local_addr()
@@ -1180,6 +1315,7 @@ impl DhtState {
                 listen_addr,
                 config.peer_store.unwrap_or_else(|| PeerStore::new(peer_id)),
                 token,
+                config.announce_implied_port,
             ));
 
             spawn_with_cancel(error_span!("dht"), state.cancellation_token.clone(), {
@@ -1204,9 +1340,35 @@ impl DhtState {
             self.clone(),
             info_hash,
             announce_port,
+            None,
+            None,
+        ))
+    }
+
+    /// Like [`DhtState::get_peers`], but the lookup widens while "wants_more_peers" returns
+    /// true, and we're announced as a seed while "is_seed" returns true.
+    pub fn get_peers_adaptive(
+        self: &Arc<Self>,
+        info_hash: Id20,
+        announce_port: Option<u16>,
+        wants_more_peers: WantsMorePeers,
+        is_seed: IsSeed,
+    ) -> anyhow::Result<RequestPeersStream> {
+        Ok(RequestPeersStream::new(
+            self.clone(),
+            info_hash,
+            announce_port,
+            Some(wants_more_peers),
+            Some(is_seed),
         ))
     }
 
+    /// How many seeders and leechers the DHT nodes know of for "info_hash", from the BEP 33
+    /// scrapes done by the peer lookup. None if there's no lookup running or no node answered.
+    pub fn scrape(&self, info_hash: Id20) -> Option<DhtScrape> {
+        self.scrapes.get(&info_hash)?.estimate()
+    }
+
     pub fn listen_addr(&self) -> SocketAddr {
         self.listen_addr
     }
@@ -1222,4 +1384,58 @@ impl DhtState {
     pub fn clone_routing_table(&self) -> RoutingTable {
         self.routing_table.read().clone()
     }
+
+    /// Addresses of the nodes in the routing table that responded recently.
+    pub fn good_node_addrs(&self) -> Vec<SocketAddr> {
+        self.routing_table
+            .read()
+            .iter()
+            .filter(|n| matches!(n.status(), NodeStatus::Good))
+            .map(|n| n.addr())
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use bencode::ByteString;
+    use librqbit_core::hash_id::Id20;
+    use tokio::sync::mpsc::unbounded_channel;
+    use tokio_util::sync::CancellationToken;
+
+    use crate::{bprotocol::MessageKind, peer_store::PeerStore};
+
+    use super::{DhtState, Request};
+
+    fn announce_implied_port(announce_implied_port: bool) -> u8 {
+        let id = Id20::new([1; 20]);
+        let state = DhtState::new_internal(
+            id,
+            unbounded_channel().0,
+            None,
+            "127.0.0.1:6881".parse().unwrap(),
+            PeerStore::new(id),
+            CancellationToken::new(),
+            announce_implied_port,
+        );
+        let (_, message) = state.create_request(Request::Announce {
+            info_hash: Id20::new([2; 20]),
+            token: ByteString::from(&b"token"[..]),
+            port: 6881,
+            seed: false,
+        });
+        match message.kind {
+            MessageKind::AnnouncePeer(announce) => {
+                assert_eq!(announce.port, 6881);
+                announce.implied_port
+            }
+            _ => panic!("expected an announce"),
+        }
+    }
+
+    #[test]
+    fn test_announce_implied_port() {
+        assert_eq!(announce_implied_port(false), 0);
+        assert_eq!(announce_implied_port(true), 1);
+    }
 }
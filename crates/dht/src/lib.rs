@@ -1,17 +1,21 @@
 mod bprotocol;
 mod dht;
+mod node_id;
 mod peer_store;
 mod persistence;
 mod routing_table;
+mod scrape;
 mod utils;
 
 use std::sync::Arc;
 use std::time::Duration;
 
-pub use crate::dht::DhtStats;
-pub use crate::dht::{DhtConfig, DhtState, RequestPeersStream};
+pub use crate::dht::{DhtBucketStats, DhtStats};
+pub use crate::dht::{DhtConfig, DhtState, IsSeed, RequestPeersStream, WantsMorePeers};
 pub use librqbit_core::hash_id::Id20;
+pub use node_id::{node_id_for_ip, node_id_matches_ip};
 pub use persistence::{PersistentDht, PersistentDhtConfig};
+pub use scrape::DhtScrape;
 
 pub type Dht = Arc<DhtState>;
 
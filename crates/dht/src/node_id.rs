@@ -0,0 +1,93 @@
+// Node IDs derived from the external IP address (BEP 42), so that nodes enforcing it don't
+// ignore us, and a node can't pick its place in the ID space at will.
+
+use std::net::IpAddr;
+
+use librqbit_core::hash_id::Id20;
+use rand::Rng;
+
+const IPV4_MASK: [u8; 4] = [0x03, 0x0f, 0x3f, 0xff];
+const IPV6_MASK: [u8; 8] = [0x01, 0x03, 0x07, 0x0f, 0x1f, 0x3f, 0x7f, 0xff];
+
+fn crc32c(data: &[u8]) -> u32 {
+    let mut crc = !0u32;
+    for byte in data {
+        crc ^= *byte as u32;
+        for _ in 0..8 {
+            crc = if crc & 1 != 0 {
+                (crc >> 1) ^ 0x82f6_3b78
+            } else {
+                crc >> 1
+            };
+        }
+    }
+    !crc
+}
+
+fn node_id_from_parts(ip: IpAddr, rand: u8, random_bytes: [u8; 17]) -> Id20 {
+    let r = rand & 0x07;
+    let mut masked = match ip {
+        IpAddr::V4(ip) => {
+            let mut b = ip.octets();
+            b.iter_mut().zip(IPV4_MASK).for_each(|(b, m)| *b &= m);
+            b.to_vec()
+        }
+        IpAddr::V6(ip) => {
+            let mut b = [0u8; 8];
+            b.copy_from_slice(&ip.octets()[..8]);
+            b.iter_mut().zip(IPV6_MASK).for_each(|(b, m)| *b &= m);
+            b.to_vec()
+        }
+    };
+    masked[0] |= r << 5;
+    let crc = crc32c(&masked);
+
+    let mut id = [0u8; 20];
+    id[0] = (crc >> 24) as u8;
+    id[1] = (crc >> 16) as u8;
+    id[2] = ((crc >> 8) as u8 & 0xf8) | (random_bytes[0] & 0x07);
+    id[3..19].copy_from_slice(&random_bytes[1..]);
+    id[19] = rand;
+    Id20::new(id)
+}
+
+/// Whether "id" could have been generated by node_id_for_ip(ip).
+pub fn node_id_matches_ip(id: Id20, ip: IpAddr) -> bool {
+    let expected = node_id_from_parts(ip, id.0[19], [0; 17]).0;
+    id.0[..2] == expected[..2] && id.0[2] & 0xf8 == expected[2] & 0xf8
+}
+
+/// A random node ID that is valid for "ip" as the external address.
+pub fn node_id_for_ip(ip: IpAddr) -> Id20 {
+    let mut rng = rand::thread_rng();
+    node_id_from_parts(ip, rng.gen(), rng.gen())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{node_id_for_ip, node_id_from_parts, node_id_matches_ip};
+
+    #[test]
+    fn test_bep42_vectors() {
+        // From the BEP, only the first 21 bits and the last byte are determined.
+        for (ip, rand, prefix) in [
+            ("124.31.75.21", 1u8, [0x5f, 0xbf, 0xbf]),
+            ("21.75.31.124", 86, [0x5a, 0x3c, 0xe9]),
+            ("65.23.51.170", 22, [0xa5, 0xd4, 0x32]),
+            ("84.124.73.14", 65, [0x1b, 0x03, 0x21]),
+            ("43.213.53.83", 90, [0xe5, 0x6f, 0x6c]),
+        ] {
+            let id = node_id_from_parts(ip.parse().unwrap(), rand, [0; 17]).0;
+            assert_eq!(id[..2], prefix[..2], "{ip}");
+            assert_eq!(id[2] & 0xf8, prefix[2] & 0xf8, "{ip}");
+            assert_eq!(id[19], rand);
+        }
+
+        let ip = "2001:db8::1".parse().unwrap();
+        assert!(node_id_matches_ip(node_id_for_ip(ip), ip));
+        assert!(!node_id_matches_ip(
+            node_id_for_ip("124.31.75.21".parse().unwrap()),
+            ip
+        ));
+    }
+}
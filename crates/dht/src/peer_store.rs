@@ -16,7 +16,10 @@ use serde::{
 };
 use tracing::trace;
 
-use crate::bprotocol::{AnnouncePeer, CompactPeerInfo};
+use crate::{
+    bprotocol::{AnnouncePeer, CompactPeerInfo},
+    scrape::BloomFilter,
+};
 
 #[derive(Serialize, Deserialize)]
 struct StoredToken {
@@ -30,6 +33,8 @@ struct StoredToken {
 struct StoredPeer {
     addr: SocketAddrV4,
     time: DateTime<Utc>,
+    #[serde(default)]
+    seed: bool,
 }
 
 pub struct PeerStore {
@@ -164,6 +169,7 @@ impl PeerStore {
             addr.set_port(announce.port);
         }
 
+        let seed = announce.seed == Some(1);
         use dashmap::mapref::entry::Entry;
         let peers_entry = self.peers.entry(announce.info_hash);
         let peers_len = self.peers_len.load(std::sync::atomic::Ordering::SeqCst);
@@ -171,6 +177,7 @@ impl PeerStore {
             Entry::Occupied(mut occ) => {
                 if let Some(s) = occ.get_mut().iter_mut().find(|s| s.addr == addr) {
                     s.time = Utc::now();
+                    s.seed = seed;
                     return true;
                 }
                 if peers_len >= self.max_remembered_peers {
@@ -180,6 +187,7 @@ impl PeerStore {
                 occ.get_mut().push(StoredPeer {
                     addr,
                     time: Utc::now(),
+                    seed,
                 });
             }
             Entry::Vacant(vac) => {
@@ -190,6 +198,7 @@ impl PeerStore {
                 vac.insert(vec![StoredPeer {
                     addr,
                     time: Utc::now(),
+                    seed,
                 }]);
             }
         }
@@ -209,6 +218,26 @@ impl PeerStore {
         Vec::new()
     }
 
+    // BEP 33 bloom filters of the stored seeds and other peers. None if there are none stored.
+    pub(crate) fn scrape_filters(&self, info_hash: Id20) -> Option<(BloomFilter, BloomFilter)> {
+        let stored_peers = self.peers.get(&info_hash)?;
+        let mut seeds = BloomFilter::default();
+        let mut peers = BloomFilter::default();
+        for p in stored_peers.iter() {
+            let filter = if p.seed { &mut seeds } else { &mut peers };
+            filter.insert((*p.addr.ip()).into());
+        }
+        Some((seeds, peers))
+    }
+
+    pub fn peers_len(&self) -> usize {
+        self.peers_len.load(std::sync::atomic::Ordering::SeqCst) as usize
+    }
+
+    pub fn info_hashes_len(&self) -> usize {
+        self.peers.len()
+    }
+
     #[allow(dead_code)]
     pub fn garbage_collect_peers(&self) {
         todo!()
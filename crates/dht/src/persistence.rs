@@ -7,7 +7,7 @@ use librqbit_core::spawn_utils::spawn_with_cancel;
 use serde::{Deserialize, Serialize};
 use std::fs::OpenOptions;
 use std::io::{BufReader, BufWriter};
-use std::net::SocketAddr;
+use std::net::{IpAddr, SocketAddr};
 use std::path::{Path, PathBuf};
 use std::time::Duration;
 use tokio_util::sync::CancellationToken;
@@ -15,6 +15,7 @@ use tokio_util::sync::CancellationToken;
 use anyhow::Context;
 use tracing::{error, error_span, info, trace, warn};
 
+use crate::node_id::{node_id_for_ip, node_id_matches_ip};
 use crate::peer_store::PeerStore;
 use crate::routing_table::RoutingTable;
 use crate::{Dht, DhtConfig, DhtState};
@@ -23,6 +24,15 @@ use crate::{Dht, DhtConfig, DhtState};
 pub struct PersistentDhtConfig {
     pub dump_interval: Option<Duration>,
     pub config_filename: Option<PathBuf>,
+    /// Overrides the default bootstrap nodes, see [`DhtConfig::bootstrap_addrs`].
+    pub bootstrap_addrs: Option<Vec<String>>,
+    /// Our external IP address. If set, the node ID is derived from it (BEP 42), and a persisted
+    /// routing table of an ID that doesn't match it is discarded.
+    pub external_ip: Option<IpAddr>,
+    /// See [`DhtConfig::announce_implied_port`].
+    pub announce_implied_port: bool,
+    /// See [`DhtConfig::bind_device`].
+    pub bind_device: Option<String>,
 }
 
 #[derive(Serialize, Deserialize)]
@@ -126,10 +136,22 @@ impl PersistentDht {
                     }
                 },
             };
-            let (listen_addr, routing_table, peer_store) = de
+            let (listen_addr, mut routing_table, peer_store) = de
                 .map(|de| (Some(de.addr), Some(de.table), de.peer_store))
                 .unwrap_or((None, None, None));
-            let peer_id = routing_table.as_ref().map(|r| r.id());
+            if let Some(ip) = config.external_ip {
+                if routing_table
+                    .as_ref()
+                    .is_some_and(|r| !node_id_matches_ip(r.id(), ip))
+                {
+                    info!("node ID doesn't match the external IP, starting with an empty routing table");
+                    routing_table = None;
+                }
+            }
+            let peer_id = routing_table
+                .as_ref()
+                .map(|r| r.id())
+                .or(config.external_ip.map(node_id_for_ip));
 
             let dht_config = DhtConfig {
                 peer_id,
@@ -137,7 +159,9 @@ impl PersistentDht {
                 listen_addr,
                 peer_store,
                 cancellation_token,
-                ..Default::default()
+                bootstrap_addrs: config.bootstrap_addrs.take(),
+                announce_implied_port: config.announce_implied_port,
+                bind_device: config.bind_device.take(),
             };
             let dht = DhtState::with_config(dht_config).await?;
             spawn_with_cancel(
@@ -0,0 +1,185 @@
+// DHT scrapes (BEP 33). Nodes that store peers for an info hash answer a get_peers with "scrape"
+// set with two bloom filters of the peers' IPs, one for seeds and one for the rest. The filters
+// of all nodes are OR'd together, and the number of distinct peers is estimated from how many of
+// the bits are set.
+
+use std::net::IpAddr;
+
+use serde::Serialize;
+use sha1w::{ISha1, Sha1};
+
+const BLOOM_FILTER_BYTES: usize = 256;
+const BLOOM_FILTER_BITS: usize = BLOOM_FILTER_BYTES * 8;
+
+#[derive(Clone, PartialEq, Eq)]
+pub(crate) struct BloomFilter([u8; BLOOM_FILTER_BYTES]);
+
+impl Default for BloomFilter {
+    fn default() -> Self {
+        Self([0; BLOOM_FILTER_BYTES])
+    }
+}
+
+impl std::fmt::Debug for BloomFilter {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "BloomFilter(~{})", self.estimate())
+    }
+}
+
+impl BloomFilter {
+    // None if it's not of the size BEP 33 mandates.
+    pub fn from_bytes(b: &[u8]) -> Option<Self> {
+        Some(Self(b.try_into().ok()?))
+    }
+
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.0
+    }
+
+    pub fn insert(&mut self, ip: IpAddr) {
+        let mut sha1 = Sha1::new();
+        match ip {
+            IpAddr::V4(ip) => sha1.update(&ip.octets()),
+            IpAddr::V6(ip) => sha1.update(&ip.octets()),
+        }
+        let hash = sha1.finish();
+        for index in [
+            hash[0] as usize | (hash[1] as usize) << 8,
+            hash[2] as usize | (hash[3] as usize) << 8,
+        ] {
+            let index = index % BLOOM_FILTER_BITS;
+            self.0[index / 8] |= 1 << (index % 8);
+        }
+    }
+
+    pub fn union(&mut self, other: &Self) {
+        self.0
+            .iter_mut()
+            .zip(other.0.iter())
+            .for_each(|(a, b)| *a |= b);
+    }
+
+    // How many distinct IPs were inserted, approximately.
+    pub fn estimate(&self) -> u64 {
+        let m = BLOOM_FILTER_BITS as f64;
+        let ones = self
+            .0
+            .iter()
+            .map(|b| b.count_ones() as usize)
+            .sum::<usize>();
+        // All bits set would be infinity.
+        let zeros = (BLOOM_FILTER_BITS - ones).max(1) as f64;
+        ((zeros / m).ln() / (2. * (1. - 1. / m).ln())).round() as u64
+    }
+}
+
+/// How many peers the DHT nodes store for a torrent, estimated from their scrape filters.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub struct DhtScrape {
+    pub seeders: u64,
+    pub leechers: u64,
+}
+
+#[derive(Default)]
+struct ScrapeFilters {
+    // Whether any node sent filters, so that no answers aren't reported as an empty swarm.
+    received: bool,
+    seeds: BloomFilter,
+    peers: BloomFilter,
+}
+
+// The filters received by the lookups of one info hash. They are kept for two rounds of
+// requerying, so that peers that left the swarm are eventually forgotten.
+#[derive(Default)]
+pub(crate) struct ScrapeWindow {
+    // Lookups of the info hash that are running. The window is dropped with the last one.
+    pub lookups: usize,
+    previous: ScrapeFilters,
+    current: ScrapeFilters,
+}
+
+impl ScrapeWindow {
+    pub fn add(&mut self, seeds: Option<&[u8]>, peers: Option<&[u8]>) {
+        let seeds = seeds.and_then(BloomFilter::from_bytes);
+        let peers = peers.and_then(BloomFilter::from_bytes);
+        if seeds.is_none() && peers.is_none() {
+            return;
+        }
+        self.current.received = true;
+        if let Some(seeds) = seeds {
+            self.current.seeds.union(&seeds);
+        }
+        if let Some(peers) = peers {
+            self.current.peers.union(&peers);
+        }
+    }
+
+    pub fn next_round(&mut self) {
+        self.previous = std::mem::take(&mut self.current);
+    }
+
+    pub fn estimate(&self) -> Option<DhtScrape> {
+        if !self.previous.received && !self.current.received {
+            return None;
+        }
+        let mut seeds = self.previous.seeds.clone();
+        seeds.union(&self.current.seeds);
+        let mut peers = self.previous.peers.clone();
+        peers.union(&self.current.peers);
+        Some(DhtScrape {
+            seeders: seeds.estimate(),
+            leechers: peers.estimate(),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::{Ipv4Addr, Ipv6Addr};
+
+    use super::*;
+
+    // The example from BEP 33.
+    #[test]
+    fn test_bloom_filter_bep33_example() {
+        let mut filter = BloomFilter::default();
+        for i in 0..=255u8 {
+            filter.insert(Ipv4Addr::new(192, 0, 2, i).into());
+        }
+        let base = u128::from(Ipv6Addr::new(0x2001, 0xdb8, 0, 0, 0, 0, 0, 0));
+        for i in 0..=0x3e7 {
+            filter.insert(Ipv6Addr::from(base + i).into());
+        }
+        assert_eq!(hex::encode(&filter.as_bytes()[..8]), "f6c3f5eaa07ffd91");
+        assert_eq!(filter.estimate(), 1225);
+    }
+
+    #[test]
+    fn test_scrape_window() {
+        let mut seeds = BloomFilter::default();
+        seeds.insert(Ipv4Addr::new(10, 0, 0, 1).into());
+        let mut peers = BloomFilter::default();
+        peers.insert(Ipv4Addr::new(10, 0, 0, 2).into());
+        peers.insert(Ipv4Addr::new(10, 0, 0, 3).into());
+
+        let mut window = ScrapeWindow::default();
+        assert_eq!(window.estimate(), None);
+        // Wrong sizes are ignored.
+        window.add(Some(&[0xff; 16][..]), None);
+        assert_eq!(window.estimate(), None);
+
+        window.add(Some(seeds.as_bytes()), Some(peers.as_bytes()));
+        // The same peers from another node.
+        window.add(Some(seeds.as_bytes()), Some(peers.as_bytes()));
+        let expected = Some(DhtScrape {
+            seeders: 1,
+            leechers: 2,
+        });
+        assert_eq!(window.estimate(), expected);
+
+        window.next_round();
+        assert_eq!(window.estimate(), expected);
+        window.next_round();
+        assert_eq!(window.estimate(), None);
+    }
+}
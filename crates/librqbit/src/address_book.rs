@@ -0,0 +1,111 @@
+// DHT nodes and peers known to a running session, exported so that another instance (e.g. a
+// fresh container) can start from them instead of discovering everything from scratch.
+//
+// Imported DHT nodes are used as bootstrap nodes in addition to the default ones. Imported
+// peers are tried when a torrent with the same info hash is added.
+
+use std::{collections::HashMap, net::SocketAddr, path::Path, str::FromStr};
+
+use anyhow::Context;
+use dht::Id20;
+use serde::{Deserialize, Serialize};
+
+use crate::{session::Session, torrent_state::ManagedTorrentState};
+
+// Don't make the importing side bootstrap from thousands of nodes.
+const MAX_DHT_NODES: usize = 64;
+
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct AddressBook {
+    /// DHT nodes that responded recently.
+    #[serde(default)]
+    pub dht_nodes: Vec<SocketAddr>,
+    /// Connected peers, keyed by the hex info hash of the torrent.
+    #[serde(default)]
+    pub peers: HashMap<String, Vec<SocketAddr>>,
+}
+
+impl AddressBook {
+    pub fn from_json_file(path: &Path) -> anyhow::Result<Self> {
+        let file = std::fs::File::open(path).with_context(|| format!("error opening {path:?}"))?;
+        serde_json::from_reader(std::io::BufReader::new(file))
+            .with_context(|| format!("error reading address book from {path:?}"))
+    }
+
+    pub(crate) fn dht_bootstrap_addrs(&self) -> Vec<String> {
+        self.dht_nodes.iter().map(|a| a.to_string()).collect()
+    }
+
+    // Peers by info hash. Entries with invalid info hashes are skipped.
+    pub(crate) fn peers_by_info_hash(&self) -> HashMap<Id20, Vec<SocketAddr>> {
+        self.peers
+            .iter()
+            .filter_map(|(info_hash, peers)| Some((Id20::from_str(info_hash).ok()?, peers.clone())))
+            .collect()
+    }
+}
+
+impl Session {
+    /// Good DHT nodes and the connected peers of live torrents.
+    pub fn export_address_book(&self) -> AddressBook {
+        let mut dht_nodes = self
+            .get_dht()
+            .map(|dht| dht.good_node_addrs())
+            .unwrap_or_default();
+        dht_nodes.truncate(MAX_DHT_NODES);
+        let peers = self.with_torrents(|torrents| {
+            torrents
+                .filter_map(|(_, t)| {
+                    let peers = t.with_state(|s| match s {
+                        ManagedTorrentState::Live(live) => Some(live.live_peer_addrs()),
+                        _ => None,
+                    })?;
+                    if peers.is_empty() {
+                        return None;
+                    }
+                    Some((t.info_hash().as_string(), peers))
+                })
+                .collect()
+        });
+        AddressBook { dht_nodes, peers }
+    }
+
+    // Add the imported peers for "info_hash" to "peers".
+    pub(crate) fn with_imported_peers(
+        &self,
+        info_hash: Id20,
+        mut peers: Vec<SocketAddr>,
+    ) -> Vec<SocketAddr> {
+        for addr in self.imported_peers.get(&info_hash).into_iter().flatten() {
+            if !peers.contains(addr) {
+                peers.push(*addr);
+            }
+        }
+        peers
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::AddressBook;
+
+    #[test]
+    fn test_address_book_serde() {
+        let info_hash = "a621779b5e3d486e127c3efbca9b6f8d135f52e5";
+        let book: AddressBook = serde_json::from_str(&format!(
+            r#"{{"peers": {{"{info_hash}": ["1.2.3.4:6881"], "invalid": ["1.2.3.4:1"]}}}}"#
+        ))
+        .unwrap();
+        assert!(book.dht_nodes.is_empty());
+
+        let peers = book.peers_by_info_hash();
+        assert_eq!(peers.len(), 1);
+        let (hash, addrs) = peers.into_iter().next().unwrap();
+        assert_eq!(hash.as_string(), info_hash);
+        assert_eq!(addrs, ["1.2.3.4:6881".parse().unwrap()]);
+
+        let roundtrip: AddressBook =
+            serde_json::from_str(&serde_json::to_string(&book).unwrap()).unwrap();
+        assert_eq!(roundtrip, book);
+    }
+}
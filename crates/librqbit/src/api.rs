@@ -1,4 +1,4 @@
-use std::{net::SocketAddr, sync::Arc};
+use std::{collections::HashMap, net::SocketAddr, path::PathBuf, sync::Arc};
 
 use anyhow::Context;
 use buffers::ByteString;
@@ -12,18 +12,27 @@ use tokio_stream::wrappers::{errors::BroadcastStreamRecvError, BroadcastStream};
 use tracing::warn;
 
 use crate::{
+    address_book::AddressBook,
     api_error::{ApiError, ApiErrorExt},
+    file_selection::{file_priorities_matching_paths, FilePriority},
+    label_policy::LabelPolicy,
+    seed_limits::SeedLimits,
     session::{
         AddTorrent, AddTorrentOptions, AddTorrentResponse, ListOnlyResponse, Session, TorrentId,
     },
+    session_snapshot::SessionSnapshot,
     torrent_state::{
+        live::{piece_trace::PieceTraceSnapshot, stats::history::BandwidthHistorySnapshot},
         peer::stats::snapshot::{PeerStatsFilter, PeerStatsSnapshot},
-        ManagedTorrentHandle,
+        utils::{lock_metrics, LockMetrics},
+        ManagedTorrentHandle, PeerLimits, TunableOptions,
     },
     tracing_subscriber_config_utils::LineBroadcast,
 };
 
-pub use crate::torrent_state::stats::{LiveStats, TorrentStats};
+pub use crate::torrent_state::stats::{
+    InitializingStats, LiveStats, PartialPiece, PartialPiecesStats, TorrentStats,
+};
 
 pub type Result<T> = std::result::Result<T, ApiError>;
 
@@ -59,6 +68,14 @@ impl Api {
             .ok_or(ApiError::torrent_not_found(idx))
     }
 
+    pub fn api_session_snapshot(&self) -> SessionSnapshot {
+        self.session.snapshot()
+    }
+
+    pub fn api_address_book(&self) -> AddressBook {
+        self.session.export_address_book()
+    }
+
     pub fn api_torrent_list(&self) -> TorrentListResponse {
         let items = self.session.with_torrents(|torrents| {
             torrents
@@ -78,6 +95,69 @@ impl Api {
         make_torrent_details(&info_hash, &handle.info().info, only_files.as_deref())
     }
 
+    pub fn api_torrent_files(&self, idx: TorrentId) -> Result<TorrentFilesResponse> {
+        let handle = self.mgr_handle(idx)?;
+        let only_files = handle.only_files();
+        let file_priorities = handle.file_priorities();
+        // Not available while initializing.
+        let have = handle
+            .with_chunk_tracker(|c| c.get_have_pieces().clone())
+            .ok();
+        let lengths = &handle.info().lengths;
+        let piece_length = lengths.default_piece_length() as u64;
+
+        let mut offset = 0u64;
+        let files = handle
+            .info()
+            .info
+            .iter_filenames_and_lengths()
+            .context("error iterating filenames and lengths")?
+            .enumerate()
+            .map(|(idx, (filename_it, length))| {
+                let start = offset;
+                offset += length;
+                let pieces = if length == 0 {
+                    None
+                } else {
+                    Some((
+                        (start / piece_length) as u32,
+                        ((offset - 1) / piece_length) as u32,
+                    ))
+                };
+                let have_bytes = match (&have, pieces) {
+                    (Some(have), Some((first, last))) => (first..=last)
+                        .filter_map(|p| lengths.validate_piece_index(p))
+                        .filter(|p| have.get(p.get() as usize).map(|b| *b).unwrap_or(false))
+                        .map(|p| {
+                            let piece_start = lengths.piece_offset(p);
+                            let piece_end = piece_start + lengths.piece_length(p) as u64;
+                            piece_end.min(offset) - piece_start.max(start)
+                        })
+                        .sum(),
+                    _ => 0,
+                };
+                TorrentFilesResponseFile {
+                    id: idx,
+                    name: filename_it.to_string().unwrap_or_else(|err| {
+                        warn!("error reading filename: {:?}", err);
+                        "<INVALID NAME>".to_string()
+                    }),
+                    components: filename_it.to_vec().unwrap_or_default(),
+                    length,
+                    included: only_files
+                        .as_ref()
+                        .map(|o| o.contains(&idx))
+                        .unwrap_or(true),
+                    priority: file_priorities.get(&idx).copied().unwrap_or_default(),
+                    have_bytes,
+                    first_piece: pieces.map(|(first, _)| first),
+                    last_piece: pieces.map(|(_, last)| last),
+                }
+            })
+            .collect();
+        Ok(TorrentFilesResponse { files })
+    }
+
     pub fn api_peer_stats(
         &self,
         idx: TorrentId,
@@ -90,6 +170,40 @@ impl Api {
             .per_peer_stats_snapshot(filter))
     }
 
+    pub fn api_peer_add(&self, idx: TorrentId, addr: SocketAddr) -> Result<EmptyJsonResponse> {
+        let handle = self.mgr_handle(idx)?;
+        handle
+            .add_peer(addr)
+            .with_error_status_code(StatusCode::BAD_REQUEST)?;
+        Ok(Default::default())
+    }
+
+    pub fn api_peer_disconnect(
+        &self,
+        idx: TorrentId,
+        addr: SocketAddr,
+    ) -> Result<EmptyJsonResponse> {
+        let handle = self.mgr_handle(idx)?;
+        handle
+            .live()
+            .context("not live")
+            .with_error_status_code(StatusCode::BAD_REQUEST)?
+            .disconnect_peer(addr)
+            .with_error_status_code(StatusCode::NOT_FOUND)?;
+        Ok(Default::default())
+    }
+
+    pub fn api_peer_ban(&self, idx: TorrentId, addr: SocketAddr) -> Result<EmptyJsonResponse> {
+        let handle = self.mgr_handle(idx)?;
+        handle
+            .live()
+            .context("not live")
+            .with_error_status_code(StatusCode::BAD_REQUEST)?
+            .ban_peer(addr)
+            .with_error_status_code(StatusCode::NOT_FOUND)?;
+        Ok(Default::default())
+    }
+
     pub fn api_torrent_action_pause(&self, idx: TorrentId) -> Result<EmptyJsonResponse> {
         let handle = self.mgr_handle(idx)?;
         handle
@@ -99,6 +213,84 @@ impl Api {
         Ok(Default::default())
     }
 
+    /// Switch downloading and uploading independently. None leaves the switch as is.
+    pub fn api_torrent_action_transfer(
+        &self,
+        idx: TorrentId,
+        download: Option<bool>,
+        upload: Option<bool>,
+    ) -> Result<EmptyJsonResponse> {
+        let handle = self.mgr_handle(idx)?;
+        if let Some(download) = download {
+            handle.set_download_enabled(download);
+        }
+        if let Some(upload) = upload {
+            handle.set_upload_enabled(upload);
+        }
+        Ok(Default::default())
+    }
+
+    pub fn api_torrent_action_peer_limits(
+        &self,
+        idx: TorrentId,
+        limits: PeerLimits,
+    ) -> Result<EmptyJsonResponse> {
+        let handle = self.mgr_handle(idx)?;
+        handle
+            .set_peer_limits(limits)
+            .context("error changing peer limits")
+            .with_error_status_code(StatusCode::BAD_REQUEST)?;
+        Ok(Default::default())
+    }
+
+    pub fn api_torrent_action_seed_limits(
+        &self,
+        idx: TorrentId,
+        limits: SeedLimits,
+    ) -> Result<EmptyJsonResponse> {
+        let handle = self.mgr_handle(idx)?;
+        handle
+            .set_seed_limits(limits)
+            .context("error changing seed limits")
+            .with_error_status_code(StatusCode::BAD_REQUEST)?;
+        Ok(Default::default())
+    }
+
+    pub fn api_torrent_action_tunable_options(
+        &self,
+        idx: TorrentId,
+        options: TunableOptions,
+    ) -> Result<EmptyJsonResponse> {
+        let handle = self.mgr_handle(idx)?;
+        handle
+            .set_tunable_options(options)
+            .context("error changing torrent options")
+            .with_error_status_code(StatusCode::BAD_REQUEST)?;
+        Ok(Default::default())
+    }
+
+    pub fn api_torrent_action_reannounce(&self, idx: TorrentId) -> Result<EmptyJsonResponse> {
+        let handle = self.mgr_handle(idx)?;
+        handle
+            .live()
+            .context("not live")
+            .with_error_status_code(StatusCode::BAD_REQUEST)?
+            .reannounce();
+        Ok(Default::default())
+    }
+
+    pub fn api_torrent_action_recheck(
+        &self,
+        idx: TorrentId,
+        max_bytes_per_sec: Option<u64>,
+    ) -> Result<EmptyJsonResponse> {
+        let handle = self.mgr_handle(idx)?;
+        handle
+            .recheck_in_background(max_bytes_per_sec)
+            .with_error_status_code(StatusCode::BAD_REQUEST)?;
+        Ok(Default::default())
+    }
+
     pub fn api_torrent_action_start(&self, idx: TorrentId) -> Result<EmptyJsonResponse> {
         let handle = self.mgr_handle(idx)?;
         self.session
@@ -108,6 +300,39 @@ impl Api {
         Ok(Default::default())
     }
 
+    pub fn api_torrent_action_update_only_files(
+        &self,
+        idx: TorrentId,
+        req: UpdateFileSelectionRequest,
+    ) -> Result<EmptyJsonResponse> {
+        let handle = self.mgr_handle(idx)?;
+        let mut file_priorities =
+            file_priorities_matching_paths(&handle.info().info, &req.file_priorities_paths)
+                .with_error_status_code(StatusCode::BAD_REQUEST)?;
+        file_priorities.extend(req.file_priorities);
+        handle
+            .update_file_selection(req.only_files, file_priorities)
+            .context("error updating file selection")
+            .with_error_status_code(StatusCode::BAD_REQUEST)?;
+        Ok(Default::default())
+    }
+
+    /// Move the files of the torrent to another folder. Doesn't return until they are moved,
+    /// which takes a while if they have to be copied.
+    pub async fn api_torrent_action_move_storage(
+        &self,
+        idx: TorrentId,
+        output_folder: PathBuf,
+    ) -> Result<EmptyJsonResponse> {
+        let handle = self.mgr_handle(idx)?;
+        tokio::task::spawn_blocking(move || handle.move_storage(output_folder))
+            .await
+            .context("move task panicked")?
+            .context("error moving torrent files")
+            .with_error_status_code(StatusCode::BAD_REQUEST)?;
+        Ok(Default::default())
+    }
+
     pub fn api_torrent_action_forget(&self, idx: TorrentId) -> Result<EmptyJsonResponse> {
         self.session
             .delete(idx, false)
@@ -152,51 +377,113 @@ impl Api {
         add: AddTorrent<'_>,
         opts: Option<AddTorrentOptions>,
     ) -> Result<ApiAddTorrentResponse> {
-        let response = match self
+        let response = self
             .session
             .add_torrent(add, opts)
             .await
             .context("error adding torrent")
-            .with_error_status_code(StatusCode::BAD_REQUEST)?
-        {
-            AddTorrentResponse::AlreadyManaged(id, managed) => {
-                return Err(anyhow::anyhow!(
-                    "{:?} is already managed, id={}, downloaded to {:?}",
-                    managed.info_hash(),
-                    id,
-                    &managed.info().out_dir
-                ))
-                .with_error_status_code(StatusCode::CONFLICT);
-            }
-            AddTorrentResponse::ListOnly(ListOnlyResponse {
-                info_hash,
-                info,
-                only_files,
-                seen_peers,
-                output_folder,
-            }) => ApiAddTorrentResponse {
-                id: None,
-                output_folder: output_folder.to_string_lossy().into_owned(),
-                seen_peers: Some(seen_peers),
-                details: make_torrent_details(&info_hash, &info, only_files.as_deref())
-                    .context("error making torrent details")?,
-            },
-            AddTorrentResponse::Added(id, handle) => {
-                let details = make_torrent_details(
-                    &handle.info_hash(),
-                    &handle.info().info,
-                    handle.only_files().as_deref(),
-                )
-                .context("error making torrent details")?;
-                ApiAddTorrentResponse {
-                    id: Some(id),
-                    details,
-                    output_folder: handle.info().out_dir.to_string_lossy().into_owned(),
-                    seen_peers: None,
+            .with_error_status_code(StatusCode::BAD_REQUEST)?;
+        make_add_torrent_response(response)
+    }
+
+    /// Add the URLs with the same options, see [`Session::add_torrents`]. Returns a result for
+    /// each URL, in the same order.
+    pub async fn api_add_torrents(
+        &self,
+        urls: Vec<String>,
+        opts: AddTorrentOptions,
+    ) -> Vec<ApiBatchAddResult> {
+        let adds = urls
+            .into_iter()
+            .map(|url| (AddTorrent::from_url(url), Some(opts.clone())))
+            .collect();
+        self.session
+            .add_torrents(adds)
+            .await
+            .into_iter()
+            .map(|r| {
+                match r
+                    .map_err(ApiError::from)
+                    .and_then(make_add_torrent_response)
+                {
+                    Ok(added) => ApiBatchAddResult {
+                        added: Some(added),
+                        error: None,
+                    },
+                    Err(e) => ApiBatchAddResult {
+                        added: None,
+                        error: Some(format!("{e:#}")),
+                    },
                 }
-            }
-        };
-        Ok(response)
+            })
+            .collect()
+    }
+
+    /// Export the torrent with its progress, see [`Session::export_torrent`].
+    pub fn api_export_torrent(&self, idx: TorrentId) -> Result<Vec<u8>> {
+        self.mgr_handle(idx)?;
+        let mut buf = Vec::new();
+        self.session.export_torrent(idx, &mut buf)?;
+        Ok(buf)
+    }
+
+    pub async fn api_import_torrent(
+        &self,
+        bundle: &[u8],
+        output_folder: Option<PathBuf>,
+    ) -> Result<ApiAddTorrentResponse> {
+        let response = self
+            .session
+            .import_torrent(bundle, output_folder)
+            .await
+            .context("error importing torrent")
+            .with_error_status_code(StatusCode::BAD_REQUEST)?;
+        make_add_torrent_response(response)
+    }
+
+    pub fn api_label_policies(&self) -> HashMap<String, LabelPolicy> {
+        self.session.label_policies()
+    }
+
+    pub fn api_set_label_policy(
+        &self,
+        label: &str,
+        policy: LabelPolicy,
+    ) -> Result<EmptyJsonResponse> {
+        self.session.set_label_policy(label, policy);
+        Ok(Default::default())
+    }
+
+    pub fn api_remove_label_policy(&self, label: &str) -> Result<EmptyJsonResponse> {
+        self.session.remove_label_policy(label);
+        Ok(Default::default())
+    }
+
+    /// Start recording the state changes of a piece, see [`Api::api_piece_trace`].
+    pub fn api_trace_piece(&self, idx: TorrentId, piece: u32) -> Result<EmptyJsonResponse> {
+        let live = self.mgr_handle(idx)?.live().context("torrent not live")?;
+        live.trace_piece(piece)
+            .with_error_status_code(StatusCode::BAD_REQUEST)?;
+        Ok(Default::default())
+    }
+
+    pub fn api_piece_trace(&self, idx: TorrentId, piece: u32) -> Result<PieceTraceSnapshot> {
+        let live = self.mgr_handle(idx)?.live().context("torrent not live")?;
+        live.piece_trace(piece)
+            .with_context(|| format!("piece {piece} is not traced"))
+            .with_error_status_code(StatusCode::NOT_FOUND)
+    }
+
+    pub fn api_stop_tracing_piece(&self, idx: TorrentId, piece: u32) -> Result<PieceTraceSnapshot> {
+        let live = self.mgr_handle(idx)?.live().context("torrent not live")?;
+        live.stop_tracing_piece(piece)
+            .with_context(|| format!("piece {piece} is not traced"))
+            .with_error_status_code(StatusCode::NOT_FOUND)
+    }
+
+    /// Lock wait and hold times, aggregated per lock reason across all torrents.
+    pub fn api_lock_metrics(&self) -> LockMetrics {
+        lock_metrics()
     }
 
     pub fn api_dht_stats(&self) -> Result<DhtStats> {
@@ -223,6 +510,12 @@ impl Api {
         Ok(mgr.stats())
     }
 
+    pub fn api_stats_history(&self, idx: TorrentId) -> Result<BandwidthHistorySnapshot> {
+        let mgr = self.mgr_handle(idx)?;
+        let live = mgr.live().context("torrent not live")?;
+        Ok(live.bandwidth_history())
+    }
+
     pub fn api_dump_haves(&self, idx: usize) -> Result<String> {
         let mgr = self.mgr_handle(idx)?;
         Ok(mgr.with_chunk_tracker(|chunks| format!("{:?}", chunks.get_have_pieces()))?)
@@ -248,9 +541,41 @@ pub struct TorrentDetailsResponseFile {
     pub included: bool,
 }
 
+#[derive(Serialize, Deserialize)]
+pub struct TorrentFilesResponseFile {
+    pub id: usize,
+    pub name: String,
+    pub components: Vec<String>,
+    pub length: u64,
+    pub included: bool,
+    pub priority: FilePriority,
+    /// Bytes of the file in downloaded and verified pieces.
+    pub have_bytes: u64,
+    /// The range of pieces the file spans, inclusive. Not set for empty files.
+    pub first_piece: Option<u32>,
+    pub last_piece: Option<u32>,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct TorrentFilesResponse {
+    pub files: Vec<TorrentFilesResponseFile>,
+}
+
 #[derive(Default, Serialize)]
 pub struct EmptyJsonResponse {}
 
+#[derive(Default, Serialize, Deserialize)]
+pub struct UpdateFileSelectionRequest {
+    /// Files to download, all if not set.
+    pub only_files: Option<Vec<usize>>,
+    #[serde(default)]
+    pub file_priorities: HashMap<usize, FilePriority>,
+    /// Priorities of paths, applied before "file_priorities". See
+    /// [`crate::AddTorrentOptions::file_priorities_paths`].
+    #[serde(default)]
+    pub file_priorities_paths: Vec<(String, FilePriority)>,
+}
+
 #[derive(Serialize, Deserialize)]
 pub struct TorrentDetailsResponse {
     pub info_hash: String,
@@ -266,6 +591,56 @@ pub struct ApiAddTorrentResponse {
     pub seen_peers: Option<Vec<SocketAddr>>,
 }
 
+#[derive(Serialize, Deserialize)]
+pub struct ApiBatchAddResult {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub added: Option<ApiAddTorrentResponse>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+fn make_add_torrent_response(response: AddTorrentResponse) -> Result<ApiAddTorrentResponse> {
+    let response = match response {
+        AddTorrentResponse::AlreadyManaged(id, managed) => {
+            return Err(anyhow::anyhow!(
+                "{:?} is already managed, id={}, downloaded to {:?}",
+                managed.info_hash(),
+                id,
+                managed.info().out_dir()
+            ))
+            .with_error_status_code(StatusCode::CONFLICT);
+        }
+        AddTorrentResponse::ListOnly(ListOnlyResponse {
+            info_hash,
+            info,
+            only_files,
+            seen_peers,
+            output_folder,
+        }) => ApiAddTorrentResponse {
+            id: None,
+            output_folder: output_folder.to_string_lossy().into_owned(),
+            seen_peers: Some(seen_peers),
+            details: make_torrent_details(&info_hash, &info, only_files.as_deref())
+                .context("error making torrent details")?,
+        },
+        AddTorrentResponse::Added(id, handle) => {
+            let details = make_torrent_details(
+                &handle.info_hash(),
+                &handle.info().info,
+                handle.only_files().as_deref(),
+            )
+            .context("error making torrent details")?;
+            ApiAddTorrentResponse {
+                id: Some(id),
+                details,
+                output_folder: handle.info().out_dir().to_string_lossy().into_owned(),
+                seen_peers: None,
+            }
+        }
+    };
+    Ok(response)
+}
+
 fn make_torrent_details(
     info_hash: &Id20,
     info: &TorrentMetaV1Info<ByteString>,
@@ -0,0 +1,74 @@
+// Adding many torrents at once, e.g. a burst of RSS items.
+//
+// Magnet links need their metadata fetched from peers first, which is the slow part and what
+// hammers DHT and trackers. The batch resolves a bounded number of them at a time. A magnet
+// that's in the batch more than once is added one after another, so that it's resolved once
+// and the later adds find it managed.
+
+use std::{collections::HashMap, sync::Arc};
+
+use futures::{future::BoxFuture, FutureExt, StreamExt};
+use librqbit_core::{hash_id::Id20, magnet::Magnet};
+
+use crate::session::{AddTorrent, AddTorrentOptions, AddTorrentResponse, Session};
+
+const MAX_CONCURRENT_ADDS: usize = 8;
+
+fn magnet_info_hash(add: &AddTorrent<'_>) -> Option<Id20> {
+    match add {
+        AddTorrent::Url(url) => Magnet::parse(url).ok()?.as_id20(),
+        _ => None,
+    }
+}
+
+impl Session {
+    /// Add several torrents. Returns the result of each add, in the same order.
+    pub async fn add_torrents<'a>(
+        self: &'a Arc<Self>,
+        adds: Vec<(AddTorrent<'a>, Option<AddTorrentOptions>)>,
+    ) -> Vec<anyhow::Result<AddTorrentResponse>> {
+        let count = adds.len();
+        let mut groups: Vec<Vec<_>> = Vec::new();
+        let mut group_by_info_hash = HashMap::new();
+        for (idx, (add, opts)) in adds.into_iter().enumerate() {
+            let group = match magnet_info_hash(&add) {
+                Some(info_hash) => *group_by_info_hash.entry(info_hash).or_insert_with(|| {
+                    groups.push(Vec::new());
+                    groups.len() - 1
+                }),
+                None => {
+                    groups.push(Vec::new());
+                    groups.len() - 1
+                }
+            };
+            groups[group].push((idx, add, opts));
+        }
+
+        // Boxed with a concrete lifetime, otherwise the batch future isn't Send (e.g. for axum).
+        let groups = groups
+            .into_iter()
+            .map(|group| -> BoxFuture<'a, _> {
+                async move {
+                    let mut results = Vec::with_capacity(group.len());
+                    for (idx, add, opts) in group {
+                        results.push((idx, self.add_torrent(add, opts).await));
+                    }
+                    results
+                }
+                .boxed()
+            })
+            .collect::<Vec<_>>();
+        let mut added = futures::stream::iter(groups).buffer_unordered(MAX_CONCURRENT_ADDS);
+
+        let mut results = (0..count).map(|_| None).collect::<Vec<_>>();
+        while let Some(group_results) = added.next().await {
+            for (idx, result) in group_results {
+                results[idx] = Some(result);
+            }
+        }
+        results
+            .into_iter()
+            .map(|r| r.expect("bug: every add has a result"))
+            .collect()
+    }
+}
@@ -2,7 +2,7 @@ use librqbit_core::lengths::{ChunkInfo, Lengths, ValidPieceIndex};
 use peer_binary_protocol::Piece;
 use tracing::{debug, trace};
 
-use crate::type_aliases::BF;
+use crate::{file_selection::FilePriority, type_aliases::BF};
 
 pub struct ChunkTracker {
     // This forms the basis of a "queue" to pull from.
@@ -25,6 +25,9 @@ pub struct ChunkTracker {
     // What pieces to download first.
     priority_piece_ids: Vec<usize>,
 
+    // Priority of each piece, derived from file priorities. None if all are normal.
+    piece_priorities: Option<Vec<FilePriority>>,
+
     total_selected_bytes: u64,
 }
 
@@ -87,10 +90,22 @@ impl ChunkTracker {
             lengths,
             have: have_pieces,
             priority_piece_ids,
+            piece_priorities: None,
             total_selected_bytes,
         }
     }
 
+    pub fn set_piece_priorities(&mut self, piece_priorities: Option<Vec<FilePriority>>) {
+        self.piece_priorities = piece_priorities;
+    }
+
+    fn piece_priority(&self, piece_id: usize) -> FilePriority {
+        self.piece_priorities
+            .as_ref()
+            .and_then(|p| p.get(piece_id).copied())
+            .unwrap_or_default()
+    }
+
     pub fn get_total_selected_bytes(&self) -> u64 {
         self.total_selected_bytes
     }
@@ -102,6 +117,91 @@ impl ChunkTracker {
     pub fn get_have_pieces(&self) -> &BF {
         &self.have
     }
+    pub fn is_piece_have(&self, index: ValidPieceIndex) -> bool {
+        self.have
+            .get(index.get() as usize)
+            .map(|b| *b)
+            .unwrap_or(false)
+    }
+
+    pub fn is_piece_needed(&self, index: ValidPieceIndex) -> bool {
+        self.needed_pieces
+            .get(index.get() as usize)
+            .map(|b| *b)
+            .unwrap_or(false)
+    }
+
+    /// How many chunks of the piece were received since it was last reset.
+    pub fn count_received_chunks(&self, index: ValidPieceIndex) -> usize {
+        self.chunk_status
+            .get(self.lengths.chunk_range(index))
+            .map(|c| c.count_ones())
+            .unwrap_or_default()
+    }
+
+    /// Move the piece to the front of the download queue, e.g. when someone is waiting
+    /// to read it. Pieces we have are skipped. A piece that isn't selected stays in front,
+    /// but is only downloaded once it is selected.
+    pub fn prioritize_piece(&mut self, index: ValidPieceIndex) {
+        self.prioritize_pieces(std::iter::once(index))
+    }
+
+    /// Move the pieces to the front of the download queue, keeping their order.
+    pub fn prioritize_pieces(&mut self, pieces: impl IntoIterator<Item = ValidPieceIndex>) {
+        let have = &self.have;
+        let is_have = |id: usize| have.get(id).map(|b| *b).unwrap_or(false);
+        let mut prioritized: Vec<usize> = pieces
+            .into_iter()
+            .map(|p| p.get() as usize)
+            .filter(|id| !is_have(*id))
+            .collect();
+        self.priority_piece_ids
+            .retain(|id| !prioritized.contains(id) && !is_have(*id));
+        prioritized.append(&mut self.priority_piece_ids);
+        self.priority_piece_ids = prioritized;
+    }
+
+    /// Change which pieces are selected for download, e.g. when the file selection changes.
+    ///
+    /// Pieces in "inflight" are being downloaded and stay out of the queue. Returns how many
+    /// bytes are left to download for the new selection, including the in-flight pieces.
+    pub fn update_selected_pieces(&mut self, selected: &BF, inflight: &[ValidPieceIndex]) -> u64 {
+        let lengths = self.lengths;
+        let mut total_selected_bytes = 0;
+        let mut remaining_bytes = 0;
+        for piece in lengths.iter_piece_infos() {
+            let index = piece.piece_index;
+            let id = index.get() as usize;
+            if !selected.get(id).map(|b| *b).unwrap_or(false) {
+                if !self.have[id] {
+                    // Same as for pieces that were never selected, see compute_chunk_status().
+                    self.needed_pieces.set(id, false);
+                    self.chunk_status
+                        .get_mut(lengths.chunk_range(index))
+                        .unwrap()
+                        .fill(true);
+                }
+                continue;
+            }
+            total_selected_bytes += piece.len as u64;
+            if self.have[id] {
+                continue;
+            }
+            remaining_bytes += piece.len as u64;
+            if self.needed_pieces[id] || inflight.contains(&index) {
+                continue;
+            }
+            // Newly selected.
+            self.needed_pieces.set(id, true);
+            self.chunk_status
+                .get_mut(lengths.chunk_range(index))
+                .unwrap()
+                .fill(false);
+        }
+        self.total_selected_bytes = total_selected_bytes;
+        remaining_bytes
+    }
+
     pub fn reserve_needed_piece(&mut self, index: ValidPieceIndex) {
         self.needed_pieces.set(index.get() as usize, false)
     }
@@ -116,6 +216,25 @@ impl ChunkTracker {
             .sum()
     }
 
+    /// Pieces that we don't have, but some of the chunks of were written.
+    /// Yields the piece, the number of written chunks, and their total length.
+    pub fn iter_partial_pieces(&self) -> impl Iterator<Item = (ValidPieceIndex, u32, u64)> + '_ {
+        self.have.iter_zeros().filter_map(move |piece_id| {
+            let index = self.lengths.validate_piece_index(piece_id as u32)?;
+            let chunks = self.chunk_status.get(self.lengths.chunk_range(index))?;
+            // All chunks are set for pieces that aren't selected for download.
+            if chunks.not_any() || chunks.all() {
+                return None;
+            }
+            let bytes = chunks
+                .iter_ones()
+                .filter_map(|chunk| self.lengths.chunk_size(index, chunk as u32))
+                .map(|s| s as u64)
+                .sum();
+            Some((index, chunks.count_ones() as u32, bytes))
+        })
+    }
+
     pub fn calc_needed_bytes(&self) -> u64 {
         self.needed_pieces
             .iter_ones()
@@ -132,9 +251,15 @@ impl ChunkTracker {
             .copied()
             .filter(move |piece_id| self.needed_pieces[*piece_id])
             .chain(
-                self.needed_pieces
-                    .iter_ones()
-                    .filter(move |id| !self.priority_piece_ids.contains(id)),
+                [FilePriority::High, FilePriority::Normal, FilePriority::Low]
+                    .into_iter()
+                    .filter(move |p| self.piece_priorities.is_some() || *p == FilePriority::Normal)
+                    .flat_map(move |priority| {
+                        self.needed_pieces.iter_ones().filter(move |id| {
+                            self.piece_priority(*id) == priority
+                                && !self.priority_piece_ids.contains(id)
+                        })
+                    }),
             )
     }
 
@@ -173,6 +298,12 @@ impl ChunkTracker {
         }
     }
 
+    // The piece was verified, but couldn't be written. It has to be downloaded again.
+    pub fn mark_piece_lost(&mut self, index: ValidPieceIndex) {
+        self.have.set(index.get() as usize, false);
+        self.mark_piece_broken_if_not_have(index);
+    }
+
     pub fn mark_piece_downloaded(&mut self, idx: ValidPieceIndex) {
         self.have.set(idx.get() as usize, true);
     }
@@ -216,3 +347,74 @@ impl ChunkTracker {
         Some(ChunkMarkingResult::NotCompleted)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use buffers::ByteBuf;
+    use librqbit_core::lengths::Lengths;
+    use peer_binary_protocol::Piece;
+
+    use super::ChunkTracker;
+    use crate::type_aliases::BF;
+
+    // Four pieces of two chunks, the last one of a single chunk.
+    fn lengths() -> Lengths {
+        Lengths::new(3 * 32768 + 10_000, 32768, None).unwrap()
+    }
+
+    fn pieces(ids: &[usize]) -> BF {
+        let mut bf = BF::from_vec(vec![0u8]);
+        for id in ids {
+            bf.set(*id, true);
+        }
+        bf
+    }
+
+    fn needed(chunks: &ChunkTracker) -> Vec<usize> {
+        let mut needed = chunks.iter_needed_pieces().collect::<Vec<_>>();
+        needed.sort();
+        needed
+    }
+
+    #[test]
+    fn test_update_selected_pieces() {
+        let lengths = lengths();
+        let mut chunks = ChunkTracker::new(pieces(&[0, 1]), pieces(&[]), lengths, 2 * 32768);
+        let first = lengths.validate_piece_index(0).unwrap();
+        chunks.reserve_needed_piece(first);
+        chunks.mark_piece_downloaded(first);
+
+        // The first file is replaced by the last one.
+        let remaining = chunks.update_selected_pieces(&pieces(&[0, 2, 3]), &[]);
+        assert_eq!(remaining, 32768 + 10_000);
+        assert_eq!(chunks.get_total_selected_bytes(), 2 * 32768 + 10_000);
+        assert_eq!(needed(&chunks), vec![2, 3]);
+        assert_eq!(chunks.iter_partial_pieces().count(), 0);
+
+        // Pieces being downloaded count as remaining, but aren't queued again.
+        let in_flight = lengths.validate_piece_index(2).unwrap();
+        chunks.reserve_needed_piece(in_flight);
+        let remaining = chunks.update_selected_pieces(&pieces(&[0, 1, 2, 3]), &[in_flight]);
+        assert_eq!(remaining, 2 * 32768 + 10_000);
+        assert_eq!(chunks.get_total_selected_bytes(), 3 * 32768 + 10_000);
+        assert_eq!(needed(&chunks), vec![1, 3]);
+    }
+
+    #[test]
+    fn test_partial_pieces() {
+        let lengths = lengths();
+        let mut chunks = ChunkTracker::new(pieces(&[0, 1, 2, 3]), pieces(&[]), lengths, 0);
+        let block = vec![0u8; 16384];
+
+        chunks.mark_chunk_downloaded(&Piece::<ByteBuf>::from_data(1, 16384, &block[..]));
+        let partial = chunks
+            .iter_partial_pieces()
+            .map(|(index, chunks, bytes)| (index.get(), chunks, bytes))
+            .collect::<Vec<_>>();
+        assert_eq!(partial, vec![(1, 1, 16384)]);
+
+        // Fully written pieces are waiting for the hash check, not partial.
+        chunks.mark_chunk_downloaded(&Piece::<ByteBuf>::from_data(1, 0, &block[..]));
+        assert_eq!(chunks.iter_partial_pieces().count(), 0);
+    }
+}
@@ -1,4 +1,5 @@
 use std::borrow::Cow;
+use std::collections::BTreeMap;
 use std::ffi::OsStr;
 use std::io::{BufWriter, Read};
 use std::path::Path;
@@ -6,16 +7,90 @@ use std::path::Path;
 use anyhow::Context;
 use bencode::bencode_serialize_to_writer;
 use buffers::ByteString;
-use librqbit_core::torrent_metainfo::{TorrentMetaV1File, TorrentMetaV1Info, TorrentMetaV1Owned};
+use librqbit_core::hash_id::Id32;
+use librqbit_core::torrent_metainfo::{
+    torrent_from_bytes, TorrentMetaV1File, TorrentMetaV1Info, TorrentMetaV1Owned,
+};
 use librqbit_core::Id20;
+use serde::{ser::SerializeMap, Serialize};
 use sha1w::{ISha1, Sha1};
 
 use crate::spawn_utils::BlockingSpawner;
 
+/// Files are added in path order and no creation date is written, so the same content and
+/// options always give the same torrent and info hash.
 #[derive(Debug, Clone, Default)]
 pub struct CreateTorrentOptions<'a> {
     pub name: Option<&'a str>,
+    /// Chosen from the size of the content if not set, see [`auto_piece_length`].
     pub piece_length: Option<u32>,
+    pub version: TorrentVersion,
+}
+
+/// Which BitTorrent versions a created torrent can be downloaded with.
+///
+/// With v2 hashes (the file tree and the piece layers, BEP 52) the piece length must be a power
+/// of two of at least 16 KiB.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum TorrentVersion {
+    #[default]
+    V1,
+    /// Both v1 and v2 hashes. Files are aligned to pieces with padding files in the v1 file
+    /// list, which rqbit itself downloads like any other file.
+    Hybrid,
+    /// Only v2 hashes. rqbit can create these, but not download them.
+    V2,
+}
+
+impl TorrentVersion {
+    fn has_v1(self) -> bool {
+        self != TorrentVersion::V2
+    }
+
+    fn has_v2(self) -> bool {
+        self != TorrentVersion::V1
+    }
+}
+
+impl std::fmt::Display for TorrentVersion {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            TorrentVersion::V1 => f.write_str("v1"),
+            TorrentVersion::Hybrid => f.write_str("hybrid"),
+            TorrentVersion::V2 => f.write_str("v2"),
+        }
+    }
+}
+
+impl std::str::FromStr for TorrentVersion {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "v1" => Ok(Self::V1),
+            "hybrid" => Ok(Self::Hybrid),
+            "v2" => Ok(Self::V2),
+            s => anyhow::bail!("invalid torrent version {s:?}, expected v1, hybrid or v2"),
+        }
+    }
+}
+
+const MIN_PIECE_LENGTH: u32 = 16 * 1024;
+const MAX_PIECE_LENGTH: u32 = 16 * 1024 * 1024;
+// Bigger torrents get bigger pieces, so that the .torrent file stays small. Smaller pieces are
+// verified and shared sooner, so they are used as long as there aren't too many.
+const TARGET_PIECE_COUNT: u64 = 1500;
+
+// v2 hashes files in blocks of this size.
+const V2_BLOCK_SIZE: usize = 16 * 1024;
+
+/// The piece length used for content of "total_length" bytes when not set explicitly: a power
+/// of two between 16 KiB and 16 MiB, giving at most about 1500 pieces.
+pub fn auto_piece_length(total_length: u64) -> u32 {
+    total_length
+        .div_ceil(TARGET_PIECE_COUNT)
+        .next_power_of_two()
+        .clamp(MIN_PIECE_LENGTH as u64, MAX_PIECE_LENGTH as u64) as u32
 }
 
 fn walk_dir_find_paths(dir: &Path, out: &mut Vec<Cow<'_, Path>>) -> anyhow::Result<()> {
@@ -67,19 +142,302 @@ fn compute_info_hash(t: &TorrentMetaV1Info<ByteString>) -> anyhow::Result<Id20>
     Ok(Id20::new(hash.finish()))
 }
 
-fn choose_piece_length(_input_files: &[Cow<'_, Path>]) -> u32 {
-    // TODO: make this smarter or smth
-    2 * 1024 * 1024
-}
-
 fn osstr_to_bytes(o: &OsStr) -> Vec<u8> {
     o.to_str().unwrap().to_owned().into_bytes()
 }
 
+// v1 hashes all files one after another, in pieces.
+struct V1Hasher {
+    piece_length: u32,
+    remaining: u32,
+    hash: Sha1,
+    pieces: Vec<u8>,
+}
+
+impl V1Hasher {
+    fn new(piece_length: u32) -> Self {
+        Self {
+            piece_length,
+            remaining: piece_length,
+            hash: Sha1::new(),
+            pieces: Vec::new(),
+        }
+    }
+
+    fn update(&mut self, mut buf: &[u8]) {
+        while !buf.is_empty() {
+            let len = buf.len().min(self.remaining as usize);
+            self.hash.update(&buf[..len]);
+            self.remaining -= len as u32;
+            buf = &buf[len..];
+            if self.remaining == 0 {
+                let hash = std::mem::replace(&mut self.hash, Sha1::new());
+                self.pieces.extend_from_slice(&hash.finish());
+                self.remaining = self.piece_length;
+            }
+        }
+    }
+
+    // How many bytes are needed to pad the content to the end of the current piece.
+    fn padding_len(&self) -> u32 {
+        self.remaining % self.piece_length
+    }
+
+    fn finish(mut self) -> Vec<u8> {
+        if self.remaining < self.piece_length {
+            self.pieces.extend_from_slice(&self.hash.finish());
+        }
+        self.pieces
+    }
+}
+
+fn sha256(parts: &[&[u8]]) -> [u8; 32] {
+    use sha1w::ISha256;
+    let mut hash = sha1w::Sha256::new();
+    for part in parts {
+        hash.update(part);
+    }
+    hash.finish()
+}
+
+// Pads "layer" to a power of two with "pad" and hashes it pairwise up to the root.
+fn merkle_root(mut layer: Vec<[u8; 32]>, pad: [u8; 32]) -> [u8; 32] {
+    layer.resize(layer.len().next_power_of_two(), pad);
+    while layer.len() > 1 {
+        layer = layer
+            .chunks_exact(2)
+            .map(|pair| sha256(&[&pair[0], &pair[1]]))
+            .collect();
+    }
+    layer[0]
+}
+
+// v2 hashes every file on its own, as a merkle tree of 16 KiB blocks.
+#[derive(Default)]
+struct V2FileHasher {
+    block: Vec<u8>,
+    leaves: Vec<[u8; 32]>,
+}
+
+impl V2FileHasher {
+    fn update(&mut self, mut buf: &[u8]) {
+        while !buf.is_empty() {
+            let len = (V2_BLOCK_SIZE - self.block.len()).min(buf.len());
+            self.block.extend_from_slice(&buf[..len]);
+            buf = &buf[len..];
+            if self.block.len() == V2_BLOCK_SIZE {
+                self.leaves.push(sha256(&[&self.block]));
+                self.block.clear();
+            }
+        }
+    }
+
+    // The root of the file's tree and, if the file is longer than a piece, its piece layer
+    // (the roots of the piece subtrees). None for empty files.
+    fn finish(mut self, piece_length: u32) -> Option<([u8; 32], Option<Vec<u8>>)> {
+        if !self.block.is_empty() {
+            self.leaves.push(sha256(&[&self.block]));
+        }
+        if self.leaves.is_empty() {
+            return None;
+        }
+        let leaves_per_piece = piece_length as usize / V2_BLOCK_SIZE;
+        if self.leaves.len() <= leaves_per_piece {
+            return Some((merkle_root(self.leaves, [0; 32]), None));
+        }
+        let piece_roots: Vec<[u8; 32]> = self
+            .leaves
+            .chunks(leaves_per_piece)
+            .map(|leaves| {
+                let mut leaves = leaves.to_vec();
+                leaves.resize(leaves_per_piece, [0; 32]);
+                merkle_root(leaves, [0; 32])
+            })
+            .collect();
+        let layer = piece_roots.concat();
+        let pad = merkle_root(vec![[0; 32]; leaves_per_piece], [0; 32]);
+        Some((merkle_root(piece_roots, pad), Some(layer)))
+    }
+}
+
+// A file in the v2 file tree, which is stored under an empty key.
+#[derive(Serialize)]
+struct V2File {
+    length: u64,
+    #[serde(rename = "pieces root", skip_serializing_if = "Option::is_none")]
+    pieces_root: Option<ByteString>,
+}
+
+enum FileTreeNode {
+    File(V2File),
+    Dir(BTreeMap<ByteString, FileTreeNode>),
+}
+
+impl Serialize for FileTreeNode {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        match self {
+            FileTreeNode::File(file) => {
+                let mut map = serializer.serialize_map(Some(1))?;
+                map.serialize_entry("", file)?;
+                map.end()
+            }
+            FileTreeNode::Dir(dir) => dir.serialize(serializer),
+        }
+    }
+}
+
+fn insert_into_file_tree(
+    tree: &mut BTreeMap<ByteString, FileTreeNode>,
+    path: &[ByteString],
+    file: V2File,
+) -> anyhow::Result<()> {
+    match path {
+        [] => anyhow::bail!("empty path"),
+        [name] => {
+            tree.insert(name.clone(), FileTreeNode::File(file));
+        }
+        [dir, rest @ ..] => {
+            match tree
+                .entry(dir.clone())
+                .or_insert_with(|| FileTreeNode::Dir(Default::default()))
+            {
+                FileTreeNode::Dir(dir) => insert_into_file_tree(dir, rest, file)?,
+                FileTreeNode::File(_) => anyhow::bail!("{:?} is both a file and a directory", dir),
+            }
+        }
+    }
+    Ok(())
+}
+
+struct HashedFile {
+    length: u64,
+    path: Vec<ByteString>,
+    padding: bool,
+}
+
+struct HashedContent {
+    name: ByteString,
+    single_file_mode: bool,
+    version: TorrentVersion,
+    piece_length: u32,
+    // Empty for v2-only torrents.
+    pieces: Vec<u8>,
+    // In v1 order, including padding files in hybrid torrents.
+    files: Vec<HashedFile>,
+    file_tree: BTreeMap<ByteString, FileTreeNode>,
+    piece_layers: BTreeMap<ByteString, ByteString>,
+}
+
+impl HashedContent {
+    fn into_v1_info(self) -> TorrentMetaV1Info<ByteString> {
+        let length = self.files.iter().map(|f| f.length).sum();
+        TorrentMetaV1Info {
+            name: Some(self.name),
+            pieces: self.pieces.into(),
+            piece_length: self.piece_length,
+            length: if self.single_file_mode {
+                Some(length)
+            } else {
+                None
+            },
+            md5sum: None,
+            files: if self.single_file_mode {
+                None
+            } else {
+                Some(
+                    self.files
+                        .into_iter()
+                        .map(|f| TorrentMetaV1File {
+                            length: f.length,
+                            path: f.path,
+                        })
+                        .collect(),
+                )
+            },
+        }
+    }
+
+    // The serialized torrent and its v2 info hash.
+    fn into_v2_bytes(self) -> anyhow::Result<(Vec<u8>, Id32)> {
+        #[derive(Serialize)]
+        struct HybridFile {
+            length: u64,
+            path: Vec<ByteString>,
+            #[serde(skip_serializing_if = "Option::is_none")]
+            attr: Option<ByteString>,
+        }
+
+        #[derive(Serialize)]
+        struct HybridInfo {
+            #[serde(rename = "file tree")]
+            file_tree: BTreeMap<ByteString, FileTreeNode>,
+            #[serde(skip_serializing_if = "Option::is_none")]
+            files: Option<Vec<HybridFile>>,
+            #[serde(skip_serializing_if = "Option::is_none")]
+            length: Option<u64>,
+            #[serde(rename = "meta version")]
+            meta_version: u32,
+            name: ByteString,
+            #[serde(rename = "piece length")]
+            piece_length: u32,
+            #[serde(skip_serializing_if = "Option::is_none")]
+            pieces: Option<ByteString>,
+        }
+
+        #[derive(Serialize)]
+        struct HybridTorrent {
+            announce: ByteString,
+            encoding: ByteString,
+            info: HybridInfo,
+            #[serde(rename = "piece layers")]
+            piece_layers: BTreeMap<ByteString, ByteString>,
+        }
+
+        let length = self.files.iter().map(|f| f.length).sum();
+        let has_v1 = self.version.has_v1();
+        let torrent = HybridTorrent {
+            announce: b""[..].into(),
+            encoding: b"utf-8"[..].into(),
+            info: HybridInfo {
+                file_tree: self.file_tree,
+                files: if self.single_file_mode || !has_v1 {
+                    None
+                } else {
+                    Some(
+                        self.files
+                            .into_iter()
+                            .map(|f| HybridFile {
+                                length: f.length,
+                                path: f.path,
+                                attr: f.padding.then(|| b"p"[..].into()),
+                            })
+                            .collect(),
+                    )
+                },
+                length: if self.single_file_mode && has_v1 {
+                    Some(length)
+                } else {
+                    None
+                },
+                meta_version: 2,
+                name: self.name,
+                piece_length: self.piece_length,
+                pieces: has_v1.then(|| self.pieces.into()),
+            },
+            piece_layers: self.piece_layers,
+        };
+        let mut info = Vec::new();
+        bencode_serialize_to_writer(&torrent.info, &mut info).context("error serializing info")?;
+        let mut b = Vec::new();
+        bencode_serialize_to_writer(&torrent, &mut b).context("error serializing torrent")?;
+        Ok((b, Id32::new(sha256(&[&info]))))
+    }
+}
+
 async fn create_torrent_raw<'a>(
     path: &'a Path,
     options: CreateTorrentOptions<'a>,
-) -> anyhow::Result<TorrentMetaV1Info<ByteString>> {
+) -> anyhow::Result<HashedContent> {
     path.try_exists()
         .with_context(|| format!("path {:?} doesn't exist", path))?;
     let basename = path
@@ -99,95 +457,198 @@ async fn create_torrent_raw<'a>(
     } else {
         input_files.push(Cow::Borrowed(path));
     }
-
-    let piece_length = options
-        .piece_length
-        .unwrap_or_else(|| choose_piece_length(&input_files));
+    // v2 orders files by path, and the v1 files of hybrid torrents must be in the same order.
+    // v1 torrents are sorted too, so that they don't depend on the order of the directory
+    // listing.
+    input_files.sort();
+    let version = options.version;
+
+    let piece_length = match options.piece_length {
+        Some(0) => anyhow::bail!("piece length can't be 0"),
+        Some(piece_length) => piece_length,
+        None => {
+            let mut total_length = 0;
+            for file in input_files.iter() {
+                total_length += std::fs::metadata(file)
+                    .with_context(|| format!("error reading metadata of {:?}", file))?
+                    .len();
+            }
+            auto_piece_length(total_length)
+        }
+    };
+    if version.has_v2() && (!piece_length.is_power_of_two() || piece_length < MIN_PIECE_LENGTH) {
+        anyhow::bail!(
+            "piece length of v2 torrents must be a power of two of at least {}, got {}",
+            MIN_PIECE_LENGTH,
+            piece_length
+        );
+    }
 
     // Calculate hashes etc.
-    const READ_SIZE: u32 = 8192; // todo: twea
-    let mut read_buf = vec![0; READ_SIZE as usize];
+    const READ_SIZE: usize = 65536;
+    let mut read_buf = vec![0; READ_SIZE];
 
-    let mut length = 0;
-    let mut remaining_piece_length = piece_length;
-    let mut piece_checksum = sha1w::Sha1::new();
-    let mut piece_hashes = Vec::<u8>::new();
-    let mut output_files: Vec<TorrentMetaV1File<ByteString>> = Vec::new();
+    let mut v1 = version.has_v1().then(|| V1Hasher::new(piece_length));
+    let mut output_files: Vec<HashedFile> = Vec::new();
+    let mut file_tree = BTreeMap::new();
+    let mut piece_layers = BTreeMap::new();
 
     let spawner = BlockingSpawner::default();
+    let input_files_count = input_files.len();
 
-    'outer: for file in input_files {
+    for (idx, file) in input_files.into_iter().enumerate() {
         let filename = &*file;
-        length = 0;
-        let mut fd = std::io::BufReader::new(
-            std::fs::File::open(&file).with_context(|| format!("error opening {:?}", filename))?,
-        );
+        let mut length = 0;
+        let mut v2 = version.has_v2().then(V2FileHasher::default);
+        let mut fd =
+            std::fs::File::open(&file).with_context(|| format!("error opening {:?}", filename))?;
 
         loop {
-            let max_bytes_to_read = remaining_piece_length.min(READ_SIZE) as usize;
             let size = spawner
-                .spawn_block_in_place(|| fd.read(&mut read_buf[..max_bytes_to_read]))
+                .spawn_block_in_place(|| fd.read(&mut read_buf))
                 .with_context(|| format!("error reading {:?}", filename))?;
-
-            // EOF: swap file
             if size == 0 {
-                let filename = filename
-                    .strip_prefix(path)
-                    .context("internal error, can't strip prefix")?;
-                let path = filename
-                    .components()
-                    .map(|c| osstr_to_bytes(c.as_os_str()).into())
-                    .collect();
-                output_files.push(TorrentMetaV1File { length, path });
-                continue 'outer;
+                break;
             }
-
             length += size as u64;
-            piece_checksum.update(&read_buf[..size]);
+            if let Some(v1) = v1.as_mut() {
+                v1.update(&read_buf[..size]);
+            }
+            if let Some(v2) = v2.as_mut() {
+                v2.update(&read_buf[..size]);
+            }
+        }
+
+        let file_path: Vec<ByteString> = if single_file_mode {
+            vec![name.clone()]
+        } else {
+            filename
+                .strip_prefix(path)
+                .context("internal error, can't strip prefix")?
+                .components()
+                .map(|c| osstr_to_bytes(c.as_os_str()).into())
+                .collect()
+        };
+
+        if let Some(v2) = v2 {
+            let pieces_root = match v2.finish(piece_length) {
+                Some((root, layer)) => {
+                    let root = ByteString::from(&root[..]);
+                    if let Some(layer) = layer {
+                        piece_layers.insert(root.clone(), layer.into());
+                    }
+                    Some(root)
+                }
+                None => None,
+            };
+            insert_into_file_tree(
+                &mut file_tree,
+                &file_path,
+                V2File {
+                    length,
+                    pieces_root,
+                },
+            )
+            .with_context(|| format!("error adding {:?} to the file tree", filename))?;
+        }
 
-            remaining_piece_length -= size as u32;
-            if remaining_piece_length == 0 {
-                remaining_piece_length = piece_length;
-                piece_hashes.extend_from_slice(&piece_checksum.finish());
-                piece_checksum = sha1w::Sha1::new();
+        output_files.push(HashedFile {
+            length,
+            path: file_path,
+            padding: false,
+        });
+
+        // In hybrid torrents every file starts on a piece boundary.
+        let hybrid_v1 = match v1.as_mut() {
+            Some(v1) if version == TorrentVersion::Hybrid => v1,
+            _ => continue,
+        };
+        let padding = hybrid_v1.padding_len();
+        if padding > 0 && idx + 1 < input_files_count {
+            let zeroes = [0u8; 16384];
+            let mut remaining = padding as usize;
+            while remaining > 0 {
+                let len = remaining.min(zeroes.len());
+                hybrid_v1.update(&zeroes[..len]);
+                remaining -= len;
             }
+            output_files.push(HashedFile {
+                length: padding as u64,
+                path: vec![b".pad"[..].into(), padding.to_string().into_bytes().into()],
+                padding: true,
+            });
         }
     }
 
-    if remaining_piece_length > 0 && length > 0 {
-        piece_hashes.extend_from_slice(&piece_checksum.finish());
-    }
-    Ok(TorrentMetaV1Info {
-        name: Some(name),
-        pieces: piece_hashes.into(),
+    Ok(HashedContent {
+        name,
+        single_file_mode,
+        version,
         piece_length,
-        length: if single_file_mode { Some(length) } else { None },
-        md5sum: None,
-        files: if single_file_mode {
-            None
-        } else {
-            Some(output_files)
-        },
+        pieces: v1.map(V1Hasher::finish).unwrap_or_default(),
+        files: output_files,
+        file_tree,
+        piece_layers,
     })
 }
 
 #[derive(Debug)]
 pub struct CreateTorrentResult {
-    meta: TorrentMetaV1Owned,
+    // None for v2-only torrents.
+    meta: Option<TorrentMetaV1Owned>,
+    // The serialized torrent, for torrents with v2 fields that TorrentMetaV1Owned doesn't know
+    // about.
+    raw: Option<Vec<u8>>,
+    info_hash_v2: Option<Id32>,
+    piece_length: u32,
 }
 
 impl CreateTorrentResult {
+    /// The torrent as v1.
+    ///
+    /// Panics for v2-only torrents, use [`Self::as_v1_info`] if they can be created.
     pub fn as_info(&self) -> &TorrentMetaV1Owned {
-        &self.meta
+        self.meta
+            .as_ref()
+            .expect("v2-only torrents have no v1 metainfo")
+    }
+
+    /// The torrent as v1. None for v2-only torrents.
+    pub fn as_v1_info(&self) -> Option<&TorrentMetaV1Owned> {
+        self.meta.as_ref()
     }
 
+    /// The v1 info hash, or for v2-only torrents the truncated v2 one, which is what they are
+    /// announced with.
     pub fn info_hash(&self) -> Id20 {
-        self.meta.info_hash
+        match (&self.meta, self.info_hash_v2) {
+            (Some(meta), _) => meta.info_hash,
+            (None, Some(v2)) => {
+                let mut id = [0u8; 20];
+                id.copy_from_slice(&v2.0[..20]);
+                Id20::new(id)
+            }
+            (None, None) => unreachable!("a created torrent has at least one info hash"),
+        }
+    }
+
+    /// The SHA-256 info hash of torrents with v2 hashes.
+    pub fn info_hash_v2(&self) -> Option<Id32> {
+        self.info_hash_v2
+    }
+
+    pub fn piece_length(&self) -> u32 {
+        self.piece_length
     }
 
     pub fn as_bytes(&self) -> anyhow::Result<Vec<u8>> {
+        let meta = match (&self.raw, &self.meta) {
+            (Some(raw), _) => return Ok(raw.clone()),
+            (None, Some(meta)) => meta,
+            (None, None) => unreachable!("v2-only torrents are kept serialized"),
+        };
         let mut b = Vec::new();
-        bencode_serialize_to_writer(&self.meta, &mut b).context("error serializing torrent")?;
+        bencode_serialize_to_writer(meta, &mut b).context("error serializing torrent")?;
         Ok(b)
     }
 }
@@ -196,10 +657,30 @@ pub async fn create_torrent<'a>(
     path: &'a Path,
     options: CreateTorrentOptions<'a>,
 ) -> anyhow::Result<CreateTorrentResult> {
-    let info = create_torrent_raw(path, options).await?;
+    let version = options.version;
+    let content = create_torrent_raw(path, options).await?;
+    let piece_length = content.piece_length;
+    if version.has_v2() {
+        let (raw, info_hash_v2) = content.into_v2_bytes()?;
+        let meta = if version.has_v1() {
+            Some(
+                torrent_from_bytes::<ByteString>(&raw)
+                    .context("error parsing the created torrent")?,
+            )
+        } else {
+            None
+        };
+        return Ok(CreateTorrentResult {
+            meta,
+            raw: Some(raw),
+            info_hash_v2: Some(info_hash_v2),
+            piece_length,
+        });
+    }
+    let info = content.into_v1_info();
     let info_hash = compute_info_hash(&info).context("error computing info hash")?;
     Ok(CreateTorrentResult {
-        meta: TorrentMetaV1Owned {
+        meta: Some(TorrentMetaV1Owned {
             announce: b""[..].into(),
             announce_list: Vec::new(),
             info,
@@ -210,7 +691,10 @@ pub async fn create_torrent<'a>(
             publisher_url: None,
             creation_date: None,
             info_hash,
-        },
+        }),
+        raw: None,
+        info_hash_v2: None,
+        piece_length,
     })
 }
 
@@ -221,6 +705,8 @@ mod tests {
 
     use crate::create_torrent;
 
+    use super::auto_piece_length;
+
     #[tokio::test]
     async fn test_create_torrent() {
         use crate::tests::test_util;
@@ -239,4 +725,181 @@ mod tests {
         let deserialized = torrent_from_bytes::<ByteBuf>(&bytes).unwrap();
         assert_eq!(torrent.info_hash(), deserialized.info_hash);
     }
+
+    #[test]
+    fn test_auto_piece_length() {
+        assert_eq!(auto_piece_length(0), 16384);
+        assert_eq!(auto_piece_length(1000 * 1000), 16384);
+        assert_eq!(auto_piece_length(1 << 30), 1 << 20);
+        assert_eq!(auto_piece_length(1 << 50), 16 * 1024 * 1024);
+    }
+
+    #[tokio::test]
+    async fn test_create_hybrid_torrent() {
+        use bencode::{dyn_from_bytes, BencodeValue};
+        use buffers::ByteString;
+
+        use super::sha256;
+        use crate::{CreateTorrentOptions, TorrentVersion};
+
+        let dir = tempfile::TempDir::with_prefix("rqbit_test_create_hybrid_torrent").unwrap();
+        let big: Vec<u8> = (0..40000u32).map(|i| i as u8).collect();
+        std::fs::write(dir.path().join("a"), &big).unwrap();
+        std::fs::write(dir.path().join("b"), b"hello").unwrap();
+        std::fs::write(dir.path().join("c"), b"").unwrap();
+
+        let torrent = create_torrent(
+            dir.path(),
+            CreateTorrentOptions {
+                piece_length: Some(16384),
+                version: TorrentVersion::Hybrid,
+                ..Default::default()
+            },
+        )
+        .await
+        .unwrap();
+
+        // "a" is padded to the end of its third piece, "b" to the end of its piece.
+        let files = torrent.as_info().info.files.as_ref().unwrap();
+        let files: Vec<(u64, Vec<&[u8]>)> = files
+            .iter()
+            .map(|f| (f.length, f.path.iter().map(|p| p.as_ref()).collect()))
+            .collect();
+        assert_eq!(
+            files,
+            vec![
+                (40000, vec![&b"a"[..]]),
+                (9152, vec![&b".pad"[..], &b"9152"[..]]),
+                (5, vec![&b"b"[..]]),
+                (16379, vec![&b".pad"[..], &b"16379"[..]]),
+                (0, vec![&b"c"[..]]),
+            ]
+        );
+        assert_eq!(torrent.as_info().info.pieces.as_ref().len(), 4 * 20);
+        assert!(torrent.info_hash_v2().is_some());
+
+        let bytes = torrent.as_bytes().unwrap();
+        let deserialized = torrent_from_bytes::<ByteBuf>(&bytes).unwrap();
+        assert_eq!(torrent.info_hash(), deserialized.info_hash);
+
+        let value = dyn_from_bytes::<ByteString>(&bytes).unwrap();
+        let BencodeValue::Dict(top) = value else {
+            panic!("expected a dict")
+        };
+        type Dict = std::collections::HashMap<ByteString, BencodeValue<ByteString>>;
+        fn get<'a>(d: &'a Dict, key: &[u8]) -> &'a Dict {
+            match d.get(&ByteString::from(key)) {
+                Some(BencodeValue::Dict(d)) => d,
+                other => panic!("expected a dict at {:?}, got {:?}", key, other),
+            }
+        }
+        let info = get(&top, b"info");
+        assert_eq!(
+            info.get(&ByteString::from(&b"meta version"[..])),
+            Some(&BencodeValue::Integer(2))
+        );
+        let tree = get(info, b"file tree");
+        let pieces_root = |name: &[u8]| {
+            let file = get(get(tree, name), b"");
+            match file.get(&ByteString::from(&b"pieces root"[..])) {
+                Some(BencodeValue::Bytes(b)) => Some(b.0.clone()),
+                None => None,
+                other => panic!("bad pieces root {:?}", other),
+            }
+        };
+
+        // A file of one block has the block's hash as the root.
+        assert_eq!(pieces_root(b"b").unwrap(), sha256(&[b"hello"]));
+        assert_eq!(pieces_root(b"c"), None);
+
+        // "a" has three pieces of one block each, padded with a zero hash to four.
+        let leaves: Vec<[u8; 32]> = big.chunks(16384).map(|c| sha256(&[c])).collect();
+        let root = sha256(&[
+            &sha256(&[&leaves[0], &leaves[1]]),
+            &sha256(&[&leaves[2], &[0; 32]]),
+        ]);
+        assert_eq!(pieces_root(b"a").unwrap(), root);
+        let layers = get(&top, b"piece layers");
+        assert_eq!(layers.len(), 1);
+        assert_eq!(
+            layers.get(&ByteString::from(&root[..])),
+            Some(&BencodeValue::Bytes(ByteString::from(leaves.concat())))
+        );
+    }
+
+    #[tokio::test]
+    async fn test_create_v2_torrent() {
+        use bencode::{dyn_from_bytes, BencodeValue};
+        use buffers::ByteString;
+
+        use crate::{CreateTorrentOptions, TorrentVersion};
+
+        let dir = tempfile::TempDir::with_prefix("rqbit_test_create_v2_torrent").unwrap();
+        std::fs::write(dir.path().join("a"), vec![1u8; 40000]).unwrap();
+        std::fs::write(dir.path().join("b"), b"hello").unwrap();
+
+        let torrent = create_torrent(
+            dir.path(),
+            CreateTorrentOptions {
+                piece_length: Some(16384),
+                version: TorrentVersion::V2,
+                ..Default::default()
+            },
+        )
+        .await
+        .unwrap();
+        assert!(torrent.as_v1_info().is_none());
+        let info_hash_v2 = torrent.info_hash_v2().unwrap();
+        assert_eq!(torrent.info_hash().0[..], info_hash_v2.0[..20]);
+
+        let bytes = torrent.as_bytes().unwrap();
+        let BencodeValue::Dict(top) = dyn_from_bytes::<ByteString>(&bytes).unwrap() else {
+            panic!("expected a dict")
+        };
+        let Some(BencodeValue::Dict(info)) = top.get(&ByteString::from(&b"info"[..])) else {
+            panic!("expected an info dict")
+        };
+        let keys = |d: &std::collections::HashMap<ByteString, _>| {
+            let mut keys: Vec<Vec<u8>> = d.keys().map(|k| k.0.clone()).collect();
+            keys.sort();
+            keys
+        };
+        assert_eq!(
+            keys(info),
+            vec![
+                b"file tree".to_vec(),
+                b"meta version".to_vec(),
+                b"name".to_vec(),
+                b"piece length".to_vec(),
+            ]
+        );
+        assert!(top.contains_key(&ByteString::from(&b"piece layers"[..])));
+    }
+
+    #[tokio::test]
+    async fn test_create_torrent_is_reproducible() {
+        let dir = tempfile::TempDir::with_prefix("rqbit_test_create_torrent_reproducible").unwrap();
+        for name in ["c", "a", "b"] {
+            std::fs::write(dir.path().join(name), name.repeat(1000)).unwrap();
+        }
+
+        let first = create_torrent(dir.path(), Default::default())
+            .await
+            .unwrap();
+        let second = create_torrent(dir.path(), Default::default())
+            .await
+            .unwrap();
+        assert_eq!(first.as_bytes().unwrap(), second.as_bytes().unwrap());
+
+        let files: Vec<Vec<u8>> = first
+            .as_info()
+            .info
+            .files
+            .as_ref()
+            .unwrap()
+            .iter()
+            .map(|f| f.path[0].0.clone())
+            .collect();
+        assert_eq!(files, vec![b"a".to_vec(), b"b".to_vec(), b"c".to_vec()]);
+    }
 }
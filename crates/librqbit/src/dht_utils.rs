@@ -1,13 +1,21 @@
-use std::{collections::HashSet, net::SocketAddr};
+use std::{
+    collections::{hash_map::Entry, HashMap, HashSet},
+    net::SocketAddr,
+    sync::Arc,
+};
 
 use anyhow::Context;
 use buffers::ByteString;
 use futures::{stream::FuturesUnordered, Stream, StreamExt};
 use librqbit_core::torrent_metainfo::TorrentMetaV1Info;
-use tracing::debug;
+use tracing::{debug, warn};
 
 use crate::{
-    peer_connection::PeerConnectionOptions, peer_info_reader, spawn_utils::BlockingSpawner,
+    dialer::PeerDialer,
+    peer_connection::PeerConnectionOptions,
+    peer_info_reader::{self, MetadataHashMismatch},
+    spawn_utils::BlockingSpawner,
+    torrent_state::live::peer::PeerSource,
 };
 use librqbit_core::hash_id::Id20;
 
@@ -16,27 +24,34 @@ pub enum ReadMetainfoResult<Rx> {
     Found {
         info: TorrentMetaV1Info<ByteString>,
         rx: Rx,
-        seen: HashSet<SocketAddr>,
+        seen: HashMap<SocketAddr, PeerSource>,
     },
     ChannelClosed {
-        seen: HashSet<SocketAddr>,
+        seen: HashMap<SocketAddr, PeerSource>,
     },
 }
 
-pub async fn read_metainfo_from_peer_receiver<A: Stream<Item = SocketAddr> + Unpin>(
+pub async fn read_metainfo_from_peer_receiver<
+    A: Stream<Item = (SocketAddr, PeerSource)> + Unpin,
+>(
     peer_id: Id20,
     info_hash: Id20,
     initial_addrs: Vec<SocketAddr>,
     addrs_stream: A,
     peer_connection_options: Option<PeerConnectionOptions>,
+    dialer: Option<Arc<PeerDialer>>,
 ) -> ReadMetainfoResult<A> {
-    let mut seen = HashSet::<SocketAddr>::new();
+    let mut seen = HashMap::<SocketAddr, PeerSource>::new();
+    // Peers that sent us metadata not matching the info hash. They stay in "seen" so that
+    // we don't connect to them again, but are not returned to the caller.
+    let mut banned = HashSet::<SocketAddr>::new();
     let mut addrs = addrs_stream;
 
     let semaphore = tokio::sync::Semaphore::new(128);
 
     let read_info_guarded = |addr| {
         let semaphore = &semaphore;
+        let dialer = dialer.clone();
         async move {
             let token = semaphore.acquire().await?;
             let ret = peer_info_reader::read_metainfo_from_peer(
@@ -44,6 +59,7 @@ pub async fn read_metainfo_from_peer_receiver<A: Stream<Item = SocketAddr> + Unp
                 peer_id,
                 info_hash,
                 peer_connection_options,
+                dialer,
                 BlockingSpawner::new(true),
             )
             .await
@@ -54,29 +70,45 @@ pub async fn read_metainfo_from_peer_receiver<A: Stream<Item = SocketAddr> + Unp
     };
 
     let mut unordered = FuturesUnordered::new();
+    let mut addrs_closed = false;
 
     for a in initial_addrs {
-        seen.insert(a);
+        seen.insert(a, PeerSource::Manual);
         unordered.push(read_info_guarded(a));
     }
 
     loop {
         tokio::select! {
-            next_addr = addrs.next() => {
+            next_addr = addrs.next(), if !addrs_closed => {
                 match next_addr {
-                    Some(addr) => {
-                        if seen.insert(addr) {
+                    Some((addr, source)) => {
+                        if let Entry::Vacant(vac) = seen.entry(addr) {
+                            vac.insert(source);
                             unordered.push(read_info_guarded(addr));
                         }
                     },
+                    // Still wait for the peers we are already connecting to.
+                    None if !unordered.is_empty() => addrs_closed = true,
                     None => return ReadMetainfoResult::ChannelClosed { seen },
                 }
             },
             done = unordered.next(), if !unordered.is_empty() => {
                 match done {
-                    Some(Ok(info)) => return ReadMetainfoResult::Found { info, seen, rx: addrs },
+                    Some(Ok(info)) => {
+                        seen.retain(|a, _| !banned.contains(a));
+                        return ReadMetainfoResult::Found { info, seen, rx: addrs }
+                    },
                     Some(Err(e)) => {
-                        debug!("{:#}", e);
+                        match e.downcast_ref::<MetadataHashMismatch>() {
+                            Some(m) => {
+                                warn!(addr=%m.addr, "banning peer");
+                                banned.insert(m.addr);
+                            }
+                            None => debug!("{:#}", e),
+                        }
+                        if addrs_closed && unordered.is_empty() {
+                            return ReadMetainfoResult::ChannelClosed { seen };
+                        }
                     },
                     None => unreachable!()
                 }
@@ -110,9 +142,13 @@ mod tests {
         let info_hash = Id20::from_str("cab507494d02ebb1178b38f2e9d7be299c86b862").unwrap();
         let dht = DhtBuilder::new().await.unwrap();
 
-        let peer_rx = dht.get_peers(info_hash, None).unwrap();
+        let peer_rx = dht
+            .get_peers(info_hash, None)
+            .unwrap()
+            .map(|addr| (addr, PeerSource::Dht));
         let peer_id = generate_peer_id();
-        match read_metainfo_from_peer_receiver(peer_id, info_hash, Vec::new(), peer_rx, None).await
+        match read_metainfo_from_peer_receiver(peer_id, info_hash, Vec::new(), peer_rx, None, None)
+            .await
         {
             ReadMetainfoResult::Found { info, .. } => dbg!(info),
             ReadMetainfoResult::ChannelClosed { .. } => todo!("should not have happened"),
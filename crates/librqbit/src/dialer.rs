@@ -0,0 +1,193 @@
+// How outgoing peer connections are made: from which local address or network interface, and
+// through which proxy. One per session, shared by its torrents and the metadata downloads.
+
+use std::net::SocketAddr;
+
+use librqbit_core::{bind::OutgoingBind, hash_id::Id20};
+use sha1w::{ISha1, Sha1};
+use tokio::net::TcpStream;
+
+use crate::{peer_connection::PeerSocketOptions, socks::SocksProxyConfig};
+
+/// Which connections through the SOCKS5 proxy share credentials. Tor puts connections with
+/// different credentials on different circuits, so that the traffic of one torrent or peer
+/// can't be linked to another one by the exit nodes.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum ProxyIsolation {
+    /// Use the credentials of the proxy URL for all connections.
+    #[default]
+    None,
+    /// Different credentials for each torrent.
+    Torrent,
+    /// Different credentials for each torrent and peer.
+    Peer,
+}
+
+impl std::fmt::Display for ProxyIsolation {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ProxyIsolation::None => f.write_str("none"),
+            ProxyIsolation::Torrent => f.write_str("torrent"),
+            ProxyIsolation::Peer => f.write_str("peer"),
+        }
+    }
+}
+
+impl std::str::FromStr for ProxyIsolation {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "none" => Ok(Self::None),
+            "torrent" => Ok(Self::Torrent),
+            "peer" => Ok(Self::Peer),
+            s => anyhow::bail!(
+                "invalid proxy isolation {s:?}, expected \"none\", \"torrent\" or \"peer\""
+            ),
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+pub(crate) struct PeerDialer {
+    pub bind: OutgoingBind,
+    pub proxy: Option<SocksProxyConfig>,
+    pub isolation: ProxyIsolation,
+    // Mixed into the isolation credentials, so that they don't reveal the info hash to the proxy.
+    pub isolation_secret: [u8; 20],
+}
+
+impl PeerDialer {
+    /// Connect to "addr", or to "other_addr" if it answers first. Only "addr" is used through
+    /// the proxy. The buffer sizes of "socket" are set before connecting.
+    pub async fn connect(
+        &self,
+        addr: SocketAddr,
+        other_addr: Option<SocketAddr>,
+        info_hash: Id20,
+        socket: &PeerSocketOptions,
+    ) -> anyhow::Result<TcpStream> {
+        let bind = self.bind_for(socket);
+        match &self.proxy {
+            Some(proxy) => match self.isolation_auth(addr, info_hash, proxy) {
+                Some(auth) => {
+                    let proxy = SocksProxyConfig {
+                        addr: proxy.addr.clone(),
+                        auth: Some(auth),
+                    };
+                    proxy.connect(addr, &bind).await
+                }
+                None => proxy.connect(addr, &bind).await,
+            },
+            None => {
+                let targets: Vec<SocketAddr> = std::iter::once(addr).chain(other_addr).collect();
+                Ok(bind.tcp_connect_any(&targets).await?)
+            }
+        }
+    }
+
+    /// Connect to "addr" from the port we listen on, see
+    /// [`OutgoingBind::tcp_connect_from_port`]. Not possible through the proxy.
+    pub async fn connect_from_port(
+        &self,
+        addr: SocketAddr,
+        port: u16,
+        socket: &PeerSocketOptions,
+    ) -> anyhow::Result<TcpStream> {
+        if self.proxy.is_some() {
+            anyhow::bail!("can't connect from the listening port through a proxy");
+        }
+        Ok(self
+            .bind_for(socket)
+            .tcp_connect_from_port(addr, port)
+            .await?)
+    }
+
+    // The buffer sizes of a torrent's connections may differ from the session's.
+    fn bind_for(&self, socket: &PeerSocketOptions) -> OutgoingBind {
+        self.bind
+            .with_buffer_sizes(socket.send_buffer_size, socket.recv_buffer_size)
+    }
+
+    // The credentials of the proxy URL (or "rqbit"), with a token appended to the username
+    // that's the same for the connections that may share a circuit. The password is kept, so
+    // that proxies requiring it still accept us.
+    fn isolation_auth(
+        &self,
+        addr: SocketAddr,
+        info_hash: Id20,
+        proxy: &SocksProxyConfig,
+    ) -> Option<(String, String)> {
+        let mut sha1 = Sha1::new();
+        sha1.update(&self.isolation_secret);
+        match self.isolation {
+            ProxyIsolation::None => return None,
+            ProxyIsolation::Torrent => sha1.update(&info_hash.0),
+            ProxyIsolation::Peer => {
+                sha1.update(&info_hash.0);
+                sha1.update(addr.to_string().as_bytes());
+            }
+        }
+        let token = hex::encode(sha1.finish());
+        Some(match &proxy.auth {
+            Some((username, password)) => (format!("{username}+{token}"), password.clone()),
+            None => (format!("rqbit+{token}"), "rqbit".to_owned()),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::SocketAddr;
+
+    use librqbit_core::hash_id::Id20;
+
+    use super::{PeerDialer, ProxyIsolation};
+    use crate::socks::SocksProxyConfig;
+
+    #[test]
+    fn test_isolation_auth() {
+        let proxy = SocksProxyConfig {
+            addr: "127.0.0.1:9050".to_owned(),
+            auth: None,
+        };
+        let a: SocketAddr = "10.0.0.1:6881".parse().unwrap();
+        let b: SocketAddr = "10.0.0.2:6881".parse().unwrap();
+        let t1 = Id20::new([1; 20]);
+        let t2 = Id20::new([2; 20]);
+        let auth = |isolation, addr, info_hash| {
+            PeerDialer {
+                isolation,
+                isolation_secret: [3; 20],
+                ..Default::default()
+            }
+            .isolation_auth(addr, info_hash, &proxy)
+        };
+
+        assert_eq!(auth(ProxyIsolation::None, a, t1), None);
+
+        let torrent = auth(ProxyIsolation::Torrent, a, t1).unwrap();
+        assert!(torrent.0.starts_with("rqbit+"));
+        assert!(!torrent.0.contains(&t1.as_string()));
+        assert_eq!(auth(ProxyIsolation::Torrent, b, t1), Some(torrent.clone()));
+        assert_ne!(auth(ProxyIsolation::Torrent, a, t2), Some(torrent));
+
+        let peer = auth(ProxyIsolation::Peer, a, t1);
+        assert_ne!(auth(ProxyIsolation::Peer, b, t1), peer);
+        assert_ne!(auth(ProxyIsolation::Peer, a, t2), peer);
+
+        let with_auth = SocksProxyConfig {
+            addr: proxy.addr.clone(),
+            auth: Some(("user".to_owned(), "secret".to_owned())),
+        };
+        let (username, password) = PeerDialer {
+            isolation: ProxyIsolation::Torrent,
+            isolation_secret: [3; 20],
+            ..Default::default()
+        }
+        .isolation_auth(a, t1, &with_auth)
+        .unwrap();
+        assert!(username.starts_with("user+"));
+        assert_eq!(password, "secret");
+    }
+}
@@ -0,0 +1,120 @@
+// Storage operations that fail with errors that may go away on their own, e.g. an NFS hiccup
+// or a USB reset, are retried with a backoff before the error is treated as fatal.
+
+use std::time::Duration;
+
+use tracing::{debug, warn};
+
+/// How failed disk reads and writes are retried. Only errors that may be transient are
+/// retried; others, and the last failed attempt, stop the torrent with an error.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DiskRetryPolicy {
+    /// How many times a failed operation is retried. 0 turns retrying off.
+    pub retries: u32,
+    /// The wait before the first retry, doubled for every next one.
+    pub initial_backoff: Duration,
+    pub max_backoff: Duration,
+}
+
+impl Default for DiskRetryPolicy {
+    fn default() -> Self {
+        Self {
+            retries: 3,
+            initial_backoff: Duration::from_millis(200),
+            max_backoff: Duration::from_secs(5),
+        }
+    }
+}
+
+fn is_transient_io_error(e: &std::io::Error) -> bool {
+    use std::io::ErrorKind as K;
+    #[cfg(target_os = "linux")]
+    if e.raw_os_error() == Some(libc::EIO) {
+        return true;
+    }
+    matches!(
+        e.kind(),
+        K::Interrupted
+            | K::WouldBlock
+            | K::TimedOut
+            | K::ResourceBusy
+            | K::StaleNetworkFileHandle
+            | K::NetworkDown
+            | K::NetworkUnreachable
+            | K::HostUnreachable
+            | K::ConnectionReset
+            | K::ConnectionAborted
+    )
+}
+
+pub(crate) fn is_transient(e: &anyhow::Error) -> bool {
+    e.chain()
+        .filter_map(|e| e.downcast_ref::<std::io::Error>())
+        .any(is_transient_io_error)
+}
+
+impl DiskRetryPolicy {
+    // Run "op", retrying it on transient errors. Blocks the thread while waiting.
+    pub(crate) fn run<T>(
+        &self,
+        what: &str,
+        mut op: impl FnMut() -> anyhow::Result<T>,
+    ) -> anyhow::Result<T> {
+        let mut backoff = self.initial_backoff;
+        let mut attempt = 0;
+        loop {
+            match op() {
+                Ok(v) => {
+                    if attempt > 0 {
+                        debug!(what, attempt, "disk operation succeeded after retrying");
+                    }
+                    return Ok(v);
+                }
+                Err(e) if attempt < self.retries && is_transient(&e) => {
+                    attempt += 1;
+                    warn!(
+                        what,
+                        attempt, "disk error, retrying in {:?}: {:#}", backoff, e
+                    );
+                    std::thread::sleep(backoff);
+                    backoff = (backoff * 2).min(self.max_backoff);
+                }
+                Err(e) => return Err(e),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{io::ErrorKind, time::Duration};
+
+    use super::DiskRetryPolicy;
+
+    #[test]
+    fn test_disk_retry_policy() {
+        let policy = DiskRetryPolicy {
+            retries: 2,
+            initial_backoff: Duration::from_millis(1),
+            max_backoff: Duration::from_millis(2),
+        };
+        let failing = |kind: ErrorKind, failures: u32| {
+            let mut calls = 0;
+            let result = policy.run("test", || {
+                calls += 1;
+                if calls <= failures {
+                    return Err(std::io::Error::from(kind).into());
+                }
+                Ok(())
+            });
+            (result.is_ok(), calls)
+        };
+
+        assert_eq!(failing(ErrorKind::TimedOut, 2), (true, 3));
+        // Retries exhausted.
+        assert_eq!(failing(ErrorKind::TimedOut, 3), (false, 3));
+        // Not transient.
+        assert_eq!(failing(ErrorKind::PermissionDenied, 1), (false, 1));
+        assert_eq!(failing(ErrorKind::StorageFull, 1), (false, 1));
+    }
+}
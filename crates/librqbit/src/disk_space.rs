@@ -0,0 +1,106 @@
+// Running out of disk space pauses the torrent instead of failing it for good. The session
+// resumes it once there's room again. Where free space can't be queried, it's retried after a
+// while; if the disk is still full, the torrent is paused again.
+
+use std::{
+    path::Path,
+    sync::{Arc, Weak},
+    time::Duration,
+};
+
+use tracing::{debug, info};
+
+use crate::{session::Session, ManagedTorrentState};
+
+const CHECK_INTERVAL: Duration = Duration::from_secs(10);
+
+// Don't resume just to fill the disk again right away.
+#[cfg(target_os = "linux")]
+const RESUME_MIN_FREE_BYTES: u64 = 64 * 1024 * 1024;
+
+#[cfg(not(target_os = "linux"))]
+const RESUME_RETRY_INTERVAL: Duration = Duration::from_secs(300);
+
+pub(crate) fn is_out_of_space(e: &anyhow::Error) -> bool {
+    e.chain()
+        .filter_map(|e| e.downcast_ref::<std::io::Error>())
+        .any(|e| {
+            matches!(
+                e.kind(),
+                std::io::ErrorKind::StorageFull | std::io::ErrorKind::QuotaExceeded
+            )
+        })
+}
+
+#[cfg(target_os = "linux")]
+fn available_bytes(path: &Path) -> Option<u64> {
+    use std::os::unix::ffi::OsStrExt;
+
+    let existing = path.ancestors().find(|p| p.exists())?;
+    let path = std::ffi::CString::new(existing.as_os_str().as_bytes()).ok()?;
+    let mut stat = std::mem::MaybeUninit::<libc::statvfs>::uninit();
+    // SAFETY: "path" is NUL-terminated and "stat" is only read if the call succeeded.
+    let stat = unsafe {
+        if libc::statvfs(path.as_ptr(), stat.as_mut_ptr()) != 0 {
+            return None;
+        }
+        stat.assume_init()
+    };
+    Some(stat.f_bavail as u64 * stat.f_frsize as u64)
+}
+
+#[cfg(target_os = "linux")]
+fn has_room(out_dir: &Path, _paused_for: Duration) -> bool {
+    available_bytes(out_dir).is_some_and(|b| b >= RESUME_MIN_FREE_BYTES)
+}
+
+#[cfg(not(target_os = "linux"))]
+fn has_room(_out_dir: &Path, paused_for: Duration) -> bool {
+    paused_for >= RESUME_RETRY_INTERVAL
+}
+
+impl Session {
+    pub(crate) async fn task_resume_out_of_space(session: Weak<Self>) -> anyhow::Result<()> {
+        loop {
+            tokio::time::sleep(CHECK_INTERVAL).await;
+            let session = match session.upgrade() {
+                Some(s) => s,
+                None => return Ok(()),
+            };
+            session.resume_out_of_space_torrents();
+        }
+    }
+
+    fn resume_out_of_space_torrents(self: &Arc<Self>) {
+        let torrents = self.with_torrents(|torrents| {
+            torrents
+                .filter_map(|(id, t)| Some((id, t.clone(), t.paused_out_of_space_since()?)))
+                // Restored torrents may still be checking their files.
+                .filter(|(_, t, _)| t.with_state(|s| matches!(s, ManagedTorrentState::Paused(_))))
+                .collect::<Vec<_>>()
+        });
+        for (id, torrent, since) in torrents {
+            if !has_room(&torrent.info().out_dir(), since.elapsed()) {
+                continue;
+            }
+            info!(id, "resuming torrent paused for lack of disk space");
+            if let Err(e) = self.unpause(&torrent) {
+                debug!(id, "error resuming torrent: {e:#}");
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::is_out_of_space;
+
+    #[test]
+    fn test_is_out_of_space() {
+        let full = std::io::Error::from(std::io::ErrorKind::StorageFull);
+        let e = anyhow::Error::from(full).context("error writing chunk");
+        assert!(is_out_of_space(&e));
+        let other = std::io::Error::from(std::io::ErrorKind::PermissionDenied);
+        assert!(!is_out_of_space(&anyhow::Error::from(other)));
+    }
+}
@@ -0,0 +1,106 @@
+// Write rate limits per storage device, so that downloading doesn't starve other
+// applications sharing the same disk.
+//
+// All torrents with files on the device share one rate limiter. Writers wait in the order
+// they reserved their bytes, so torrents get a fair share regardless of how many peers they
+// have.
+
+use std::{
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+
+use anyhow::Context;
+use serde::Serialize;
+
+use crate::{limits::RateLimiter, session::Session};
+
+#[derive(Default)]
+pub(crate) struct DeviceWriteLimit {
+    // The path the limit was configured with, for display.
+    path: PathBuf,
+    bytes_per_sec: Option<u64>,
+    limiter: Arc<RateLimiter>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct DiskWriteLimit {
+    pub path: PathBuf,
+    pub bytes_per_sec: u64,
+}
+
+// Identifies the device (filesystem) that "path" is, or would be created, on.
+#[cfg(unix)]
+fn device_id(path: &Path) -> anyhow::Result<u64> {
+    use std::os::unix::fs::MetadataExt;
+
+    let existing = path
+        .ancestors()
+        .find(|p| p.exists())
+        .with_context(|| format!("no part of {path:?} exists"))?;
+    let meta = std::fs::metadata(existing)
+        .with_context(|| format!("error getting metadata of {existing:?}"))?;
+    Ok(meta.dev())
+}
+
+#[cfg(not(unix))]
+fn device_id(_path: &Path) -> anyhow::Result<u64> {
+    anyhow::bail!("disk write limits are only supported on unix")
+}
+
+impl Session {
+    /// Limit the write rate to the device (filesystem) that "path" is on, shared by all
+    /// torrents writing to it. None removes the limit. Applies to running torrents too.
+    pub fn set_disk_write_limit(
+        &self,
+        path: &Path,
+        bytes_per_sec: Option<u64>,
+    ) -> anyhow::Result<()> {
+        let device = device_id(path)?;
+        let mut g = self.device_write_limits.write();
+        let limit = g.entry(device).or_default();
+        limit.path = path.to_owned();
+        limit.bytes_per_sec = bytes_per_sec;
+        limit.limiter.set_limit(bytes_per_sec);
+        Ok(())
+    }
+
+    pub fn disk_write_limits(&self) -> Vec<DiskWriteLimit> {
+        self.device_write_limits
+            .read()
+            .values()
+            .filter_map(|l| {
+                Some(DiskWriteLimit {
+                    path: l.path.clone(),
+                    bytes_per_sec: l.bytes_per_sec?,
+                })
+            })
+            .collect()
+    }
+
+    // The limiter for the device "path" is on. Created unlimited on first use, so that
+    // a limit set later applies to torrents added before.
+    pub(crate) fn disk_write_limiter(&self, path: &Path) -> Option<Arc<RateLimiter>> {
+        let device = device_id(path).ok()?;
+        let limiter = self
+            .device_write_limits
+            .write()
+            .entry(device)
+            .or_default()
+            .limiter
+            .clone();
+        Some(limiter)
+    }
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::device_id;
+
+    #[test]
+    fn test_device_id_of_missing_path() {
+        let dir = tempfile::TempDir::with_prefix("rqbit_device_id").unwrap();
+        let missing = dir.path().join("not").join("created").join("yet");
+        assert_eq!(device_id(dir.path()).unwrap(), device_id(&missing).unwrap());
+    }
+}
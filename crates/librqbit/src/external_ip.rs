@@ -0,0 +1,133 @@
+// Our public IP address as others see it. Trackers send it as "external ip" (BEP 24), peers as
+// "yourip" in the extended handshake. Any of them may be wrong or lying, so the address is the
+// one most sources agree on, trackers counting more than peers. It can also be set manually.
+
+use std::{collections::VecDeque, net::IpAddr};
+
+use parking_lot::Mutex;
+use tracing::info;
+
+// Sources remembered, the oldest reports are forgotten first.
+const MAX_REPORTS: usize = 64;
+const TRACKER_VOTES: usize = 2;
+const PEER_VOTES: usize = 1;
+// No single peer decides alone.
+const MIN_VOTES: usize = 2;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) enum ExternalIpSource {
+    Tracker(String),
+    // Keyed by IP, so that one host with many ports gets one vote.
+    Peer(IpAddr),
+}
+
+impl ExternalIpSource {
+    fn votes(&self) -> usize {
+        match self {
+            ExternalIpSource::Tracker(_) => TRACKER_VOTES,
+            ExternalIpSource::Peer(_) => PEER_VOTES,
+        }
+    }
+}
+
+pub(crate) struct ExternalIp {
+    manual: Option<IpAddr>,
+    reports: Mutex<VecDeque<(ExternalIpSource, IpAddr)>>,
+    detected: Mutex<Option<IpAddr>>,
+}
+
+impl ExternalIp {
+    pub fn new(manual: Option<IpAddr>) -> Self {
+        Self {
+            manual,
+            reports: Default::default(),
+            detected: Default::default(),
+        }
+    }
+
+    /// The address set manually, which is told to trackers.
+    pub fn manual(&self) -> Option<IpAddr> {
+        self.manual
+    }
+
+    /// The manual address, or else the detected one.
+    pub fn get(&self) -> Option<IpAddr> {
+        self.manual.or(*self.detected.lock())
+    }
+
+    pub fn report(&self, source: ExternalIpSource, ip: IpAddr) {
+        if ip.is_unspecified() || ip.is_loopback() || ip.is_multicast() {
+            return;
+        }
+        let mut reports = self.reports.lock();
+        reports.retain(|(s, _)| *s != source);
+        reports.push_back((source, ip));
+        while reports.len() > MAX_REPORTS {
+            reports.pop_front();
+        }
+        let detected = most_voted(&reports);
+        drop(reports);
+
+        let mut current = self.detected.lock();
+        if *current != detected {
+            if let Some(ip) = detected {
+                info!(%ip, "detected external IP address");
+            }
+            *current = detected;
+        }
+    }
+}
+
+fn most_voted(reports: &VecDeque<(ExternalIpSource, IpAddr)>) -> Option<IpAddr> {
+    let mut votes: Vec<(IpAddr, usize)> = Vec::new();
+    for (source, ip) in reports {
+        match votes.iter_mut().find(|(v, _)| v == ip) {
+            Some((_, count)) => *count += source.votes(),
+            None => votes.push((*ip, source.votes())),
+        }
+    }
+    // Ties go to the address reported first.
+    votes
+        .into_iter()
+        .rev()
+        .max_by_key(|(_, count)| *count)
+        .filter(|(_, count)| *count >= MIN_VOTES)
+        .map(|(ip, _)| ip)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::IpAddr;
+
+    use super::{ExternalIp, ExternalIpSource};
+
+    #[test]
+    fn test_external_ip_votes() {
+        let ip = |s: &str| s.parse::<IpAddr>().unwrap();
+        let peer = |s: &str| ExternalIpSource::Peer(ip(s));
+
+        let e = ExternalIp::new(None);
+        e.report(peer("10.0.0.1"), ip("203.0.113.7"));
+        assert_eq!(e.get(), None);
+        e.report(peer("10.0.0.2"), ip("203.0.113.7"));
+        assert_eq!(e.get(), Some(ip("203.0.113.7")));
+
+        // The same peer changing its mind doesn't count twice.
+        e.report(peer("10.0.0.3"), ip("198.51.100.1"));
+        e.report(peer("10.0.0.3"), ip("198.51.100.1"));
+        assert_eq!(e.get(), Some(ip("203.0.113.7")));
+
+        // A tracker outweighs a peer.
+        e.report(
+            ExternalIpSource::Tracker("udp://t:1".into()),
+            ip("198.51.100.1"),
+        );
+        assert_eq!(e.get(), Some(ip("198.51.100.1")));
+        e.report(peer("127.0.0.1"), ip("127.0.0.1"));
+        assert_eq!(e.get(), Some(ip("198.51.100.1")));
+
+        let e = ExternalIp::new(Some(ip("192.0.2.1")));
+        e.report(ExternalIpSource::Tracker("t".into()), ip("198.51.100.1"));
+        assert_eq!(e.get(), Some(ip("192.0.2.1")));
+    }
+}
@@ -2,6 +2,7 @@ use std::{
     fs::File,
     io::{Read, Seek, SeekFrom, Write},
     marker::PhantomData,
+    path::{Path, PathBuf},
     sync::{
         atomic::{AtomicU64, Ordering},
         Arc,
@@ -10,16 +11,227 @@ use std::{
 
 use anyhow::Context;
 use buffers::ByteString;
+use itertools::Itertools;
 use librqbit_core::{
     lengths::{ChunkInfo, Lengths, ValidPieceIndex},
     torrent_metainfo::{FileIteratorName, TorrentMetaV1Info},
 };
-use parking_lot::Mutex;
+use parking_lot::{Mutex, MutexGuard};
 use peer_binary_protocol::Piece;
 use sha1w::ISha1;
 use tracing::{debug, trace, warn};
 
-use crate::type_aliases::{PeerHandle, BF};
+use crate::{
+    output_dir::{OpenMode, OutputDir},
+    part_file::PartFile,
+    torrent_state::stats::DiskUsage,
+    type_aliases::{PeerHandle, BF},
+};
+
+/// How disk space is allocated for the files of a torrent when it starts.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum FileAllocation {
+    /// Only set the file lengths. On filesystems that support sparse files, space is
+    /// allocated as pieces are written, so the files take no more than what was downloaded.
+    /// This may fragment the files, and a full disk is only noticed when writing.
+    #[default]
+    Sparse,
+    /// Allocate the space for the selected files upfront. Fails right away if the disk is too
+    /// small, and avoids fragmentation. Slow if the filesystem can't allocate without writing
+    /// zeroes.
+    Preallocate,
+}
+
+impl std::fmt::Display for FileAllocation {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            FileAllocation::Sparse => f.write_str("sparse"),
+            FileAllocation::Preallocate => f.write_str("preallocate"),
+        }
+    }
+}
+
+impl std::str::FromStr for FileAllocation {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "sparse" => Ok(Self::Sparse),
+            "preallocate" => Ok(Self::Preallocate),
+            s => anyhow::bail!(
+                "invalid file allocation {s:?}, expected \"sparse\" or \"preallocate\""
+            ),
+        }
+    }
+}
+
+impl serde::Serialize for FileAllocation {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+impl<'de> serde::Deserialize<'de> for FileAllocation {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        use serde::de::Error;
+        String::deserialize(deserializer)?
+            .parse()
+            .map_err(D::Error::custom)
+    }
+}
+
+// Make "file" "length" bytes long, allocating the space as "mode" says. Existing data is kept.
+pub(crate) fn allocate_file(
+    file: &mut File,
+    length: u64,
+    mode: FileAllocation,
+) -> anyhow::Result<()> {
+    match mode {
+        // Leave files of the right length alone, so that their modification times are kept.
+        FileAllocation::Sparse if file.metadata()?.len() == length => Ok(()),
+        FileAllocation::Sparse => Ok(file.set_len(length)?),
+        FileAllocation::Preallocate => preallocate_file(file, length),
+    }
+}
+
+// Allocate disk space for the whole file, so that running out of space is noticed now
+// rather than in the middle of the download. Existing data is kept.
+fn preallocate_file(file: &mut File, length: u64) -> anyhow::Result<()> {
+    #[cfg(target_os = "linux")]
+    {
+        use std::os::fd::AsRawFd;
+        // SAFETY: the descriptor is valid for the lifetime of "file".
+        let ret = unsafe { libc::fallocate(file.as_raw_fd(), 0, 0, length as libc::off_t) };
+        if ret == 0 {
+            return Ok(());
+        }
+        let err = std::io::Error::last_os_error();
+        if err.raw_os_error() != Some(libc::EOPNOTSUPP) {
+            return Err(err.into());
+        }
+        debug!("fallocate not supported by the filesystem, writing zeroes instead");
+    }
+    zero_fill_file(file, length)
+}
+
+// Write zeroes from the current end of the file up to "length".
+fn zero_fill_file(file: &mut File, length: u64) -> anyhow::Result<()> {
+    let mut pos = file.metadata()?.len();
+    if pos >= length {
+        return Ok(());
+    }
+    let zeroes = vec![0u8; 1024 * 1024];
+    file.seek(SeekFrom::Start(pos))?;
+    while pos < length {
+        let len = (length - pos).min(zeroes.len() as u64) as usize;
+        file.write_all(&zeroes[..len])?;
+        pos += len as u64;
+    }
+    file.flush()?;
+    Ok(())
+}
+
+// The logical size of the files and the space they take on disk. The latter is only known
+// on unix, and is less than the former for sparse files that aren't fully downloaded.
+pub(crate) fn disk_usage(filenames: &[PathBuf]) -> DiskUsage {
+    let mut usage = DiskUsage::default();
+    // Files that aren't downloaded share the parts file.
+    for meta in filenames
+        .iter()
+        .unique()
+        .filter_map(|f| std::fs::metadata(f).ok())
+    {
+        usage.logical_bytes += meta.len();
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::MetadataExt;
+            *usage.allocated_bytes.get_or_insert(0) += meta.blocks() * 512;
+        }
+    }
+    usage
+}
+
+// Move an open file, and reopen it at the new place. Renamed if possible, copied otherwise,
+// e.g. to another filesystem. Reads and writes of the file wait until it's done.
+//
+// Files that aren't downloaded share the parts file, so it's moved once with all its handles.
+fn move_open_file(
+    handles: &[&Mutex<File>],
+    (from_dir, from): (&OutputDir, &Path),
+    (to_dir, to): (&OutputDir, &Path),
+    writable: bool,
+) -> anyhow::Result<()> {
+    let mut guards = handles.iter().map(|h| h.lock()).collect::<Vec<_>>();
+    if to_dir.exists(to) {
+        anyhow::bail!("{:?} already exists", to);
+    }
+    if let Err(e) = from_dir.rename(from, to_dir, to) {
+        debug!(?from, ?to, "can't rename ({:#}), copying", e);
+        from_dir
+            .open(from, OpenMode::Read)
+            .and_then(|mut src| {
+                let mut dst = to_dir.open(to, OpenMode::CreateNew)?;
+                std::io::copy(&mut src, &mut dst)?;
+                dst.sync_all()?;
+                Ok(())
+            })
+            .with_context(|| format!("error copying {:?} to {:?}", from, to))?;
+        from_dir.remove_file(from)?;
+    }
+    let mode = if writable {
+        OpenMode::ReadWrite
+    } else {
+        OpenMode::Read
+    };
+    for g in guards.iter_mut() {
+        **g = to_dir.open(to, mode)?;
+    }
+    Ok(())
+}
+
+// Move the open files of a torrent one by one, and update "filenames" once all are moved.
+// If one fails, the ones moved before are moved back.
+pub(crate) fn move_open_files(
+    files: &[Arc<Mutex<File>>],
+    (dir, filenames): (&OutputDir, &mut [PathBuf]),
+    (new_dir, new_filenames): (&OutputDir, &[PathBuf]),
+    writable: bool,
+) -> anyhow::Result<()> {
+    // The indices of the files opened from each path, in order of first appearance.
+    let groups = filenames
+        .iter()
+        .enumerate()
+        .into_group_map_by(|(_, f)| *f)
+        .into_values()
+        .map(|g| g.into_iter().map(|(idx, _)| idx).collect::<Vec<_>>())
+        .sorted_by_key(|g| g[0])
+        .collect::<Vec<_>>();
+    let handles = |group: &[usize]| group.iter().map(|idx| &*files[*idx]).collect::<Vec<_>>();
+    for (moved, group) in groups.iter().enumerate() {
+        let idx = group[0];
+        if let Err(e) = move_open_file(
+            &handles(group),
+            (dir, &filenames[idx]),
+            (new_dir, &new_filenames[idx]),
+            writable,
+        ) {
+            for group in groups[..moved].iter().rev() {
+                let idx = group[0];
+                if let Err(e) = move_open_file(
+                    &handles(group),
+                    (new_dir, &new_filenames[idx]),
+                    (dir, &filenames[idx]),
+                    writable,
+                ) {
+                    warn!(file = ?new_filenames[idx], "error moving file back: {:#}", e);
+                }
+            }
+            return Err(e);
+        }
+    }
+    filenames.clone_from_slice(new_filenames);
+    Ok(())
+}
 
 pub(crate) struct InitialCheckResults {
     // The pieces that we need to download.
@@ -36,6 +248,38 @@ pub(crate) struct InitialCheckResults {
     pub total_selected_bytes: u64,
 }
 
+impl InitialCheckResults {
+    // Build the results from previously verified pieces instead of reading the files.
+    pub fn from_have_pieces(have_pieces: BF, selected_pieces: &BF, lengths: &Lengths) -> Self {
+        let mut needed_pieces = BF::from_vec(vec![0u8; lengths.piece_bitfield_bytes()]);
+        let mut have_bytes = 0u64;
+        let mut needed_bytes = 0u64;
+        let mut total_selected_bytes = 0u64;
+        for piece_info in lengths.iter_piece_infos() {
+            let idx = piece_info.piece_index.get() as usize;
+            let len = piece_info.len as u64;
+            let have = have_pieces.get(idx).map(|v| *v).unwrap_or(false);
+            let selected = selected_pieces.get(idx).map(|v| *v).unwrap_or(false);
+            if selected {
+                total_selected_bytes += len;
+            }
+            if have {
+                have_bytes += len;
+            } else if selected {
+                needed_bytes += len;
+                needed_pieces.set(idx, true);
+            }
+        }
+        Self {
+            needed_pieces,
+            have_pieces,
+            have_bytes,
+            needed_bytes,
+            total_selected_bytes,
+        }
+    }
+}
+
 pub fn update_hash_from_file<Sha1: ISha1>(
     file: &mut File,
     hash: &mut Sha1,
@@ -54,9 +298,17 @@ pub fn update_hash_from_file<Sha1: ISha1>(
     Ok(())
 }
 
+// A part of a chunk that is stored in one file.
+pub(crate) struct FileSlice {
+    pub file: Arc<Mutex<File>>,
+    pub offset: u64,
+    pub len: u64,
+}
+
 pub(crate) struct FileOps<'a, Sha1> {
     torrent: &'a TorrentMetaV1Info<ByteString>,
     files: &'a [Arc<Mutex<File>>],
+    part_file: &'a PartFile,
     lengths: &'a Lengths,
     phantom_data: PhantomData<Sha1>,
 }
@@ -65,16 +317,37 @@ impl<'a, Sha1Impl: ISha1> FileOps<'a, Sha1Impl> {
     pub fn new(
         torrent: &'a TorrentMetaV1Info<ByteString>,
         files: &'a [Arc<Mutex<File>>],
+        part_file: &'a PartFile,
         lengths: &'a Lengths,
     ) -> Self {
         Self {
             torrent,
             files,
+            part_file,
             lengths,
             phantom_data: PhantomData,
         }
     }
 
+    // Lock the file and seek to "offset" in it, to read or write "len" bytes. Files that
+    // aren't downloaded are in the parts file, that only has their bytes shared with other files.
+    fn lock_file_at(
+        &self,
+        file_idx: usize,
+        offset: u64,
+        len: u64,
+    ) -> anyhow::Result<MutexGuard<'a, File>> {
+        let files: &'a [Arc<Mutex<File>>] = self.files;
+        let mut g = files[file_idx].lock();
+        let pos = self
+            .part_file
+            .position(file_idx, offset, len)?
+            .unwrap_or(offset);
+        g.seek(SeekFrom::Start(pos))
+            .with_context(|| format!("error seeking to {pos}, file id: {file_idx}"))?;
+        Ok(g)
+    }
+
     pub fn initial_check(
         &self,
         only_files: Option<&[usize]>,
@@ -90,7 +363,6 @@ impl<'a, Sha1Impl: ISha1> FileOps<'a, Sha1Impl> {
         #[derive(Debug)]
         struct CurrentFile<'a> {
             index: usize,
-            fd: &'a Arc<Mutex<File>>,
             len: u64,
             name: FileIteratorName<'a, ByteString>,
             full_file_required: bool,
@@ -105,27 +377,25 @@ impl<'a, Sha1Impl: ISha1> FileOps<'a, Sha1Impl> {
                 self.processed_bytes += bytes
             }
         }
-        let mut file_iterator = self
-            .files
-            .iter()
-            .zip(self.torrent.iter_filenames_and_lengths()?)
-            .enumerate()
-            .map(|(idx, (fd, (name, len)))| {
-                let full_file_required = if let Some(only_files) = only_files {
-                    only_files.contains(&idx)
-                } else {
-                    true
-                };
-                CurrentFile {
-                    index: idx,
-                    fd,
-                    len,
-                    name,
-                    full_file_required,
-                    processed_bytes: 0,
-                    is_broken: false,
-                }
-            });
+        let mut file_iterator =
+            self.torrent
+                .iter_filenames_and_lengths()?
+                .enumerate()
+                .map(|(idx, (name, len))| {
+                    let full_file_required = if let Some(only_files) = only_files {
+                        only_files.contains(&idx)
+                    } else {
+                        true
+                    };
+                    CurrentFile {
+                        index: idx,
+                        len,
+                        name,
+                        full_file_required,
+                        processed_bytes: 0,
+                        is_broken: false,
+                    }
+                });
 
         let mut current_file = file_iterator
             .next()
@@ -165,16 +435,17 @@ impl<'a, Sha1Impl: ISha1> FileOps<'a, Sha1Impl> {
                     continue;
                 }
 
-                let mut fd = current_file.fd.lock();
-
-                fd.seek(SeekFrom::Start(pos))
-                    .context("bug? error seeking")?;
-                if let Err(err) = update_hash_from_file(
-                    &mut fd,
-                    &mut computed_hash,
-                    &mut read_buffer,
-                    to_read_in_file,
-                ) {
+                if let Err(err) = self
+                    .lock_file_at(current_file.index, pos, to_read_in_file as u64)
+                    .and_then(|mut fd| {
+                        update_hash_from_file(
+                            &mut fd,
+                            &mut computed_hash,
+                            &mut read_buffer,
+                            to_read_in_file,
+                        )
+                    })
+                {
                     debug!(
                         "error reading from file {} ({:?}) at {}: {:#}",
                         current_file.index, current_file.name, pos, &err
@@ -240,6 +511,17 @@ impl<'a, Sha1Impl: ISha1> FileOps<'a, Sha1Impl> {
         piece_index: ValidPieceIndex,
         last_received_chunk: &ChunkInfo,
     ) -> anyhow::Result<bool> {
+        trace!(
+            "piece={}, handle={}, checking on disk. Last received chunk: {:?}",
+            piece_index,
+            who_sent,
+            &last_received_chunk
+        );
+        self.check_piece_on_disk(piece_index)
+    }
+
+    // Read a piece back from disk and check its hash.
+    pub fn check_piece_on_disk(&self, piece_index: ValidPieceIndex) -> anyhow::Result<bool> {
         let mut h = Sha1Impl::new();
         let piece_length = self.lengths.piece_length(piece_index);
         let mut absolute_offset = self.lengths.piece_offset(piece_index);
@@ -256,20 +538,14 @@ impl<'a, Sha1Impl: ISha1> FileOps<'a, Sha1Impl> {
 
             let to_read_in_file =
                 std::cmp::min(file_remaining_len, piece_remaining_bytes as u64) as usize;
-            let mut file_g = self.files[file_idx].lock();
             trace!(
-                "piece={}, handle={}, file_idx={}, seeking to {}. Last received chunk: {:?}",
+                "piece={}, file_idx={}, seeking to {}",
                 piece_index,
-                who_sent,
                 file_idx,
                 absolute_offset,
-                &last_received_chunk
             );
-            file_g
-                .seek(SeekFrom::Start(absolute_offset))
-                .with_context(|| {
-                    format!("error seeking to {absolute_offset}, file id: {file_idx}")
-                })?;
+            let mut file_g =
+                self.lock_file_at(file_idx, absolute_offset, to_read_in_file as u64)?;
             update_hash_from_file(&mut file_g, &mut h, &mut buf, to_read_in_file).with_context(
                 || {
                     format!(
@@ -281,12 +557,27 @@ impl<'a, Sha1Impl: ISha1> FileOps<'a, Sha1Impl> {
             piece_remaining_bytes -= to_read_in_file;
 
             if piece_remaining_bytes == 0 {
-                return Ok(true);
+                break;
             }
 
             absolute_offset = 0;
         }
 
+        self.compare_hash(piece_index, h)
+    }
+
+    // Check a piece that was assembled in memory.
+    pub fn check_piece_data(
+        &self,
+        piece_index: ValidPieceIndex,
+        data: &[u8],
+    ) -> anyhow::Result<bool> {
+        let mut h = Sha1Impl::new();
+        h.update(data);
+        self.compare_hash(piece_index, h)
+    }
+
+    fn compare_hash(&self, piece_index: ValidPieceIndex, h: Sha1Impl) -> anyhow::Result<bool> {
         match self.torrent.compare_hash(piece_index.get(), h.finish()) {
             Some(true) => {
                 trace!("piece={} hash matches", piece_index);
@@ -304,6 +595,63 @@ impl<'a, Sha1Impl: ISha1> FileOps<'a, Sha1Impl> {
         }
     }
 
+    // The hash of each chunk of the piece, to find out who sent which part of a broken piece.
+    // Hashes "data" if the piece is in memory, and reads it from disk otherwise.
+    pub fn chunk_hashes(
+        &self,
+        who_sent: PeerHandle,
+        piece_index: ValidPieceIndex,
+        data: Option<&[u8]>,
+    ) -> anyhow::Result<Vec<[u8; 20]>> {
+        let mut buf = vec![0u8; self.lengths.default_chunk_length() as usize];
+        let mut hashes = Vec::new();
+        for chunk in self.lengths.iter_chunk_infos(piece_index) {
+            let range = chunk.offset as usize..(chunk.offset + chunk.size) as usize;
+            let bytes = match data {
+                Some(data) => data.get(range).context("bug: piece data is too short")?,
+                None => {
+                    self.read_chunk(who_sent, &chunk, &mut buf)?;
+                    &buf[..chunk.size as usize]
+                }
+            };
+            let mut h = Sha1Impl::new();
+            h.update(bytes);
+            hashes.push(h.finish());
+        }
+        Ok(hashes)
+    }
+
+    // Where the chunk is stored, so that it can be sent without reading it into memory first.
+    pub fn chunk_file_slices(&self, chunk_info: &ChunkInfo) -> anyhow::Result<Vec<FileSlice>> {
+        let mut absolute_offset = self.lengths.chunk_absolute_offset(chunk_info);
+        let mut remaining = chunk_info.size as u64;
+        let mut slices = Vec::new();
+
+        for (file_idx, file_len) in self.torrent.iter_file_lengths()?.enumerate() {
+            if absolute_offset >= file_len {
+                absolute_offset -= file_len;
+                continue;
+            }
+            // The file may be moved out of the parts file before the slice is sent.
+            if self.part_file.is_parted(file_idx) {
+                anyhow::bail!("file {file_idx} is in the parts file");
+            }
+            let len = remaining.min(file_len - absolute_offset);
+            slices.push(FileSlice {
+                file: self.files[file_idx].clone(),
+                offset: absolute_offset,
+                len,
+            });
+            remaining -= len;
+            if remaining == 0 {
+                break;
+            }
+            absolute_offset = 0;
+        }
+
+        Ok(slices)
+    }
+
     pub fn read_chunk(
         &self,
         who_sent: PeerHandle,
@@ -324,7 +672,6 @@ impl<'a, Sha1Impl: ISha1> FileOps<'a, Sha1Impl> {
             let file_remaining_len = file_len - absolute_offset;
             let to_read_in_file = std::cmp::min(file_remaining_len, buf.len() as u64) as usize;
 
-            let mut file_g = self.files[file_idx].lock();
             trace!(
                 "piece={}, handle={}, file_idx={}, seeking to {}. To read chunk: {:?}",
                 chunk_info.piece_index,
@@ -333,11 +680,8 @@ impl<'a, Sha1Impl: ISha1> FileOps<'a, Sha1Impl> {
                 absolute_offset,
                 &chunk_info
             );
-            file_g
-                .seek(SeekFrom::Start(absolute_offset))
-                .with_context(|| {
-                    format!("error seeking to {absolute_offset}, file id: {file_idx}")
-                })?;
+            let mut file_g =
+                self.lock_file_at(file_idx, absolute_offset, to_read_in_file as u64)?;
             file_g
                 .read_exact(&mut buf[..to_read_in_file])
                 .with_context(|| {
@@ -356,6 +700,17 @@ impl<'a, Sha1Impl: ISha1> FileOps<'a, Sha1Impl> {
         Ok(())
     }
 
+    // Read from one file, e.g. for streaming it.
+    pub fn read_file_at(&self, file_idx: usize, offset: u64, buf: &mut [u8]) -> anyhow::Result<()> {
+        let mut g = self.lock_file_at(file_idx, offset, buf.len() as u64)?;
+        g.read_exact(buf).with_context(|| {
+            format!(
+                "error reading {} bytes at {offset}, file id: {file_idx}",
+                buf.len()
+            )
+        })
+    }
+
     pub fn write_chunk<ByteBuf>(
         &self,
         who_sent: PeerHandle,
@@ -365,9 +720,27 @@ impl<'a, Sha1Impl: ISha1> FileOps<'a, Sha1Impl> {
     where
         ByteBuf: AsRef<[u8]>,
     {
-        let mut buf = data.block.as_ref();
-        let mut absolute_offset = self.lengths.chunk_absolute_offset(chunk_info);
+        trace!(
+            "piece={}, chunk={:?}, handle={}, begin={}, writing {} bytes",
+            chunk_info.piece_index,
+            chunk_info,
+            who_sent,
+            chunk_info.offset,
+            data.block.as_ref().len(),
+        );
+        self.write_at(
+            self.lengths.chunk_absolute_offset(chunk_info),
+            data.block.as_ref(),
+        )
+    }
+
+    // Write a whole verified piece, in one write per file it spans.
+    pub fn write_piece(&self, piece_index: ValidPieceIndex, data: &[u8]) -> anyhow::Result<()> {
+        trace!("piece={}, writing {} bytes", piece_index, data.len());
+        self.write_at(self.lengths.piece_offset(piece_index), data)
+    }
 
+    fn write_at(&self, mut absolute_offset: u64, mut buf: &[u8]) -> anyhow::Result<()> {
         for (file_idx, (name, file_len)) in self.torrent.iter_filenames_and_lengths()?.enumerate() {
             if absolute_offset > file_len {
                 absolute_offset -= file_len;
@@ -377,22 +750,15 @@ impl<'a, Sha1Impl: ISha1> FileOps<'a, Sha1Impl> {
             let remaining_len = file_len - absolute_offset;
             let to_write = std::cmp::min(buf.len(), remaining_len as usize);
 
-            let mut file_g = self.files[file_idx].lock();
             trace!(
-                "piece={}, chunk={:?}, handle={}, begin={}, file={}, writing {} bytes at {}",
-                chunk_info.piece_index,
-                chunk_info,
-                who_sent,
-                chunk_info.offset,
+                "file={}, writing {} bytes at {}",
                 file_idx,
                 to_write,
                 absolute_offset
             );
-            file_g
-                .seek(SeekFrom::Start(absolute_offset))
-                .with_context(|| {
-                    format!("error seeking to {absolute_offset} in file {file_idx} (\"{name:?}\")")
-                })?;
+            let mut file_g = self
+                .lock_file_at(file_idx, absolute_offset, to_write as u64)
+                .with_context(|| format!("error writing to file {file_idx} (\"{name:?}\")"))?;
             file_g
                 .write_all(&buf[..to_write])
                 .with_context(|| format!("error writing to file {file_idx} (\"{name:?}\")"))?;
@@ -407,3 +773,47 @@ impl<'a, Sha1Impl: ISha1> FileOps<'a, Sha1Impl> {
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::{fs::File, sync::Arc};
+
+    use librqbit_core::lengths::Lengths;
+    use parking_lot::Mutex;
+    use sha1w::Sha1;
+
+    use super::FileOps;
+    use crate::{
+        create_torrent, part_file::PartFile,
+        tests::test_util::create_default_random_dir_with_torrents, CreateTorrentOptions,
+    };
+
+    #[tokio::test]
+    async fn test_check_piece_ending_inside_file() {
+        let dir = create_default_random_dir_with_torrents(1, 100_000, Some("rqbit_check_piece"));
+        let torrent = create_torrent(
+            dir.path(),
+            CreateTorrentOptions {
+                piece_length: Some(16384),
+                ..Default::default()
+            },
+        )
+        .await
+        .unwrap();
+        let info = &torrent.as_info().info;
+        let lengths = Lengths::from_torrent(info).unwrap();
+        let part_file = PartFile::new(info, &lengths).unwrap();
+
+        let path = dir.path().join("0.data");
+        let mut data = std::fs::read(&path).unwrap();
+        data[1000] ^= 0xff;
+        std::fs::write(&path, data).unwrap();
+        let files = [Arc::new(Mutex::new(File::open(&path).unwrap()))];
+
+        // Piece 0 ends inside the file, its hash must still be compared.
+        let file_ops = FileOps::<Sha1>::new(info, &files, &part_file, &lengths);
+        let piece = |i| lengths.validate_piece_index(i).unwrap();
+        assert!(!file_ops.check_piece_on_disk(piece(0)).unwrap());
+        assert!(file_ops.check_piece_on_disk(piece(1)).unwrap());
+    }
+}
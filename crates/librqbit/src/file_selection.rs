@@ -0,0 +1,323 @@
+use std::collections::HashMap;
+
+use anyhow::Context;
+use librqbit_core::{lengths::Lengths, torrent_metainfo::TorrentMetaV1Info};
+use serde::{Deserialize, Serialize};
+
+use crate::type_aliases::BF;
+
+/// Download priority of a file. Pieces of higher priority files are requested first.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum FilePriority {
+    Low,
+    #[default]
+    Normal,
+    High,
+}
+
+impl std::fmt::Display for FilePriority {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            FilePriority::Low => f.write_str("low"),
+            FilePriority::Normal => f.write_str("normal"),
+            FilePriority::High => f.write_str("high"),
+        }
+    }
+}
+
+impl std::str::FromStr for FilePriority {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "low" => Ok(Self::Low),
+            "normal" => Ok(Self::Normal),
+            "high" => Ok(Self::High),
+            _ => anyhow::bail!("invalid priority {s:?}, expected one of low, normal, high"),
+        }
+    }
+}
+
+/// A pattern matching one or more files in a torrent by their path within the torrent.
+///
+/// Paths are always "/"-separated, regardless of the platform, and don't include the torrent name.
+/// If the pattern contains any of "*", "?" or "[", it's treated as a glob, where "*" and "?" don't
+/// cross directory boundaries, and "**" does. "**/" matches zero or more directories, so "**/*.srt"
+/// matches "a.srt" too. Otherwise it's a path prefix, matching the file itself or everything under
+/// the directory, e.g. "Season 1" matches "Season 1/ep1.mkv".
+pub enum PathPattern {
+    Prefix(String),
+    Glob(regex::Regex),
+}
+
+fn glob_to_regex(glob: &str) -> anyhow::Result<regex::Regex> {
+    let mut re = String::from("^");
+    let mut chars = glob.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            '*' if chars.peek() == Some(&'*') => {
+                chars.next();
+                if chars.peek() == Some(&'/') {
+                    chars.next();
+                    re.push_str("(?:.*/)?");
+                } else {
+                    re.push_str(".*");
+                }
+            }
+            '*' => re.push_str("[^/]*"),
+            '?' => re.push_str("[^/]"),
+            '[' => {
+                re.push('[');
+                for c in chars.by_ref() {
+                    if c == ']' {
+                        break;
+                    }
+                    if c == '\\' {
+                        re.push('\\');
+                    }
+                    re.push(c);
+                }
+                re.push(']');
+            }
+            c => re.push_str(&regex::escape(c.encode_utf8(&mut [0u8; 4]))),
+        }
+    }
+    re.push('$');
+    regex::Regex::new(&re).with_context(|| format!("invalid glob {glob:?}"))
+}
+
+impl PathPattern {
+    pub fn parse(s: &str) -> anyhow::Result<Self> {
+        let s = s.trim_matches('/');
+        if s.is_empty() {
+            anyhow::bail!("empty path pattern");
+        }
+        if s.contains(['*', '?', '[']) {
+            return Ok(Self::Glob(glob_to_regex(s)?));
+        }
+        Ok(Self::Prefix(s.to_owned()))
+    }
+
+    pub fn matches(&self, path: &str) -> bool {
+        match self {
+            PathPattern::Prefix(p) => {
+                path == p
+                    || (path.starts_with(p.as_str()) && path.as_bytes().get(p.len()) == Some(&b'/'))
+            }
+            PathPattern::Glob(re) => re.is_match(path),
+        }
+    }
+}
+
+// The "/"-separated paths of the files within the torrent.
+fn file_paths<ByteBuf: AsRef<[u8]>>(
+    info: &TorrentMetaV1Info<ByteBuf>,
+) -> anyhow::Result<Vec<String>> {
+    info.iter_filenames_and_lengths()?
+        .enumerate()
+        .map(|(idx, (filename, _))| {
+            Ok(filename
+                .iter_components()
+                .collect::<anyhow::Result<Vec<_>>>()
+                .with_context(|| format!("filename of file {idx} is not valid"))?
+                .join("/"))
+        })
+        .collect()
+}
+
+/// Resolve the patterns to the ids of the files they match.
+pub fn file_ids_matching_paths<ByteBuf: AsRef<[u8]>>(
+    info: &TorrentMetaV1Info<ByteBuf>,
+    patterns: &[String],
+) -> anyhow::Result<Vec<usize>> {
+    let patterns = patterns
+        .iter()
+        .map(|p| PathPattern::parse(p))
+        .collect::<anyhow::Result<Vec<_>>>()?;
+    Ok(file_paths(info)?
+        .iter()
+        .enumerate()
+        .filter(|(_, path)| patterns.iter().any(|p| p.matches(path)))
+        .map(|(idx, _)| idx)
+        .collect())
+}
+
+/// Resolve the priorities of paths, e.g. whole directories, to priorities of the files they
+/// match. Files matched by several patterns get the priority of the last one.
+pub fn file_priorities_matching_paths<ByteBuf: AsRef<[u8]>>(
+    info: &TorrentMetaV1Info<ByteBuf>,
+    patterns: &[(String, FilePriority)],
+) -> anyhow::Result<HashMap<usize, FilePriority>> {
+    let patterns = patterns
+        .iter()
+        .map(|(p, priority)| Ok((PathPattern::parse(p)?, *priority)))
+        .collect::<anyhow::Result<Vec<_>>>()?;
+    Ok(file_paths(info)?
+        .iter()
+        .enumerate()
+        .filter_map(|(idx, path)| {
+            patterns
+                .iter()
+                .rev()
+                .find(|(p, _)| p.matches(path))
+                .map(|(_, priority)| (idx, *priority))
+        })
+        .collect())
+}
+
+/// Patterns for files not to download in any torrent, e.g. "*.nfo" or "sample".
+///
+/// A pattern without "/" matches a file if it matches its name or any of its parent directories,
+/// at any depth. Other patterns match the path within the torrent, as in [`PathPattern`].
+pub(crate) struct DefaultSkipPatterns {
+    patterns: Vec<(bool, PathPattern)>,
+}
+
+impl DefaultSkipPatterns {
+    pub fn parse(patterns: &[String]) -> anyhow::Result<Self> {
+        let patterns = patterns
+            .iter()
+            .map(|p| Ok((!p.trim_matches('/').contains('/'), PathPattern::parse(p)?)))
+            .collect::<anyhow::Result<Vec<_>>>()?;
+        Ok(Self { patterns })
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.patterns.is_empty()
+    }
+
+    pub fn matches(&self, path: &str) -> bool {
+        self.patterns.iter().any(|(any_component, p)| {
+            if *any_component {
+                path.split('/').any(|c| p.matches(c))
+            } else {
+                p.matches(path)
+            }
+        })
+    }
+
+    pub fn file_ids<ByteBuf: AsRef<[u8]>>(
+        &self,
+        info: &TorrentMetaV1Info<ByteBuf>,
+    ) -> anyhow::Result<Vec<usize>> {
+        Ok(file_paths(info)?
+            .iter()
+            .enumerate()
+            .filter(|(_, path)| self.matches(path))
+            .map(|(idx, _)| idx)
+            .collect())
+    }
+}
+
+/// Compute the priority of each piece from the priorities of the files it overlaps.
+/// A piece shared by several selected files gets the highest of their priorities.
+/// Returns None if all pieces are of normal priority.
+pub(crate) fn compute_piece_priorities<ByteBuf: AsRef<[u8]>>(
+    info: &TorrentMetaV1Info<ByteBuf>,
+    lengths: &Lengths,
+    only_files: Option<&[usize]>,
+    file_priorities: &HashMap<usize, FilePriority>,
+) -> anyhow::Result<Option<Vec<FilePriority>>> {
+    if file_priorities.values().all(|p| *p == FilePriority::Normal) {
+        return Ok(None);
+    }
+
+    // Start with the lowest, and raise for every selected file touching the piece.
+    let mut pieces = vec![FilePriority::Low; lengths.total_pieces() as usize];
+    let piece_length = lengths.default_piece_length() as u64;
+    let mut offset = 0u64;
+    for (idx, len) in info.iter_file_lengths()?.enumerate() {
+        let start = offset;
+        offset += len;
+        if len == 0 || only_files.map(|o| !o.contains(&idx)).unwrap_or(false) {
+            continue;
+        }
+        let priority = file_priorities.get(&idx).copied().unwrap_or_default();
+        let first_piece = (start / piece_length) as usize;
+        let last_piece = ((offset - 1) / piece_length) as usize;
+        for p in &mut pieces[first_piece..=last_piece] {
+            *p = (*p).max(priority);
+        }
+    }
+    Ok(Some(pieces))
+}
+
+/// Compute which pieces are needed to download the selected files, i.e. the pieces that overlap
+/// at least one of them. All pieces are selected if "only_files" is None.
+pub(crate) fn compute_selected_pieces<ByteBuf: AsRef<[u8]>>(
+    info: &TorrentMetaV1Info<ByteBuf>,
+    lengths: &Lengths,
+    only_files: Option<&[usize]>,
+) -> anyhow::Result<BF> {
+    let total_pieces = lengths.total_pieces() as usize;
+    let mut selected = BF::from_vec(vec![0u8; lengths.piece_bitfield_bytes()]);
+    if only_files.is_none() {
+        selected[..total_pieces].fill(true);
+        return Ok(selected);
+    }
+    let piece_length = lengths.default_piece_length() as u64;
+    let mut offset = 0u64;
+    for (idx, len) in info.iter_file_lengths()?.enumerate() {
+        let start = offset;
+        offset += len;
+        if len == 0 || only_files.map(|o| !o.contains(&idx)).unwrap_or(false) {
+            continue;
+        }
+        let first_piece = (start / piece_length) as usize;
+        let last_piece = ((offset - 1) / piece_length) as usize;
+        selected[first_piece..=last_piece].fill(true);
+    }
+    Ok(selected)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{DefaultSkipPatterns, PathPattern};
+
+    #[test]
+    fn test_prefix() {
+        let p = PathPattern::parse("Season 1/").unwrap();
+        assert!(p.matches("Season 1"));
+        assert!(p.matches("Season 1/ep1.mkv"));
+        assert!(p.matches("Season 1/extras/a.mkv"));
+        assert!(!p.matches("Season 10/ep1.mkv"));
+    }
+
+    #[test]
+    fn test_glob() {
+        let p = PathPattern::parse("Season */*.mkv").unwrap();
+        assert!(p.matches("Season 1/ep1.mkv"));
+        assert!(!p.matches("Season 1/extras/a.mkv"));
+        assert!(!p.matches("Season 1/ep1.srt"));
+
+        let p = PathPattern::parse("**/*.srt").unwrap();
+        assert!(p.matches("Season 1/extras/a.srt"));
+        assert!(p.matches("a.srt"));
+        assert!(!p.matches("a.srt.txt"));
+
+        let p = PathPattern::parse("Season 1/**/*.srt").unwrap();
+        assert!(p.matches("Season 1/a.srt"));
+        assert!(p.matches("Season 1/extras/a.srt"));
+        assert!(!p.matches("Season 10/a.srt"));
+
+        let p = PathPattern::parse("ep[12].mkv").unwrap();
+        assert!(p.matches("ep1.mkv"));
+        assert!(!p.matches("ep3.mkv"));
+    }
+
+    #[test]
+    fn test_default_skip_patterns() {
+        let p =
+            DefaultSkipPatterns::parse(&["*.nfo".into(), "sample".into(), "extras/*.txt".into()])
+                .unwrap();
+        assert!(p.matches("movie.nfo"));
+        assert!(p.matches("Season 1/ep1.nfo"));
+        assert!(!p.matches("Sample/a.mkv"));
+        assert!(p.matches("sample/a.mkv"));
+        assert!(p.matches("Season 1/sample/a.mkv"));
+        assert!(p.matches("extras/a.txt"));
+        assert!(!p.matches("Season 1/extras/a.txt"));
+        assert!(!p.matches("movie.mkv"));
+    }
+}
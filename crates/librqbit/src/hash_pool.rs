@@ -0,0 +1,100 @@
+// Verifying a piece means hashing all of it, and possibly reading it back from disk first. For
+// big pieces that takes long enough to stall the peer that completed the piece, so pieces are
+// verified on a few dedicated threads instead, shared by all torrents of a session.
+//
+// The queue is bounded: when hashing can't keep up, peers wait to submit their pieces, which
+// slows down downloading instead of piling up work.
+
+use std::sync::{
+    mpsc::{sync_channel, Receiver, SyncSender},
+    Arc,
+};
+
+use anyhow::Context;
+use parking_lot::Mutex;
+use tracing::debug;
+
+type Job = Box<dyn FnOnce() + Send + 'static>;
+
+// How many pieces may wait for a hashing thread, per thread.
+const QUEUE_LEN_PER_THREAD: usize = 4;
+
+pub(crate) struct HashPool {
+    tx: SyncSender<Job>,
+}
+
+impl HashPool {
+    pub fn new(threads: usize) -> anyhow::Result<Self> {
+        let threads = threads.max(1);
+        let (tx, rx) = sync_channel::<Job>(threads * QUEUE_LEN_PER_THREAD);
+        let rx = Arc::new(Mutex::new(rx));
+        for idx in 0..threads {
+            let rx = rx.clone();
+            std::thread::Builder::new()
+                .name(format!("rqbit-hasher-{idx}"))
+                .spawn(move || worker(rx))
+                .context("error spawning hashing thread")?;
+        }
+        debug!(threads, "started hashing threads");
+        Ok(Self { tx })
+    }
+
+    // One thread per CPU, up to 4, as there's usually more than hashing to do.
+    pub fn default_threads() -> usize {
+        std::thread::available_parallelism()
+            .map(|n| n.get())
+            .unwrap_or(1)
+            .min(4)
+    }
+
+    // Blocks while the queue is full.
+    pub fn submit(&self, job: impl FnOnce() + Send + 'static) -> anyhow::Result<()> {
+        self.tx
+            .send(Box::new(job))
+            .ok()
+            .context("hashing threads are gone")
+    }
+}
+
+fn worker(rx: Arc<Mutex<Receiver<Job>>>) {
+    loop {
+        // The lock is released before running the job, so other threads pick up the next one.
+        let job = rx.lock().recv();
+        match job {
+            Ok(job) => job(),
+            // The pool was dropped.
+            Err(_) => return,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{
+        atomic::{AtomicUsize, Ordering},
+        mpsc::channel,
+        Arc,
+    };
+
+    use super::HashPool;
+
+    #[test]
+    fn test_hash_pool_runs_all_jobs() {
+        let pool = HashPool::new(2).unwrap();
+        let done = Arc::new(AtomicUsize::new(0));
+        let (tx, rx) = channel();
+        for _ in 0..100 {
+            let done = done.clone();
+            let tx = tx.clone();
+            pool.submit(move || {
+                done.fetch_add(1, Ordering::Relaxed);
+                tx.send(()).unwrap();
+            })
+            .unwrap();
+        }
+        for _ in 0..100 {
+            rx.recv().unwrap();
+        }
+        assert_eq!(done.load(Ordering::Relaxed), 100);
+    }
+}
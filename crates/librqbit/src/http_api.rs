@@ -8,17 +8,27 @@ use futures::{FutureExt, TryStreamExt};
 use itertools::Itertools;
 
 use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::collections::HashMap;
 use std::net::SocketAddr;
+use std::path::PathBuf;
 use std::str::FromStr;
 use std::time::Duration;
 use tracing::{debug, info};
 
 use axum::Router;
 
-use crate::api::Api;
+use crate::api::{Api, UpdateFileSelectionRequest};
+use crate::file_ops::FileAllocation;
+use crate::file_selection::FilePriority;
+use crate::http_mirror;
+use crate::label_policy::LabelPolicy;
+use crate::limits::UploadCoupling;
 use crate::peer_connection::PeerConnectionOptions;
+use crate::seed_limits::{SeedLimitAction, SeedLimits};
 use crate::session::{AddTorrent, AddTorrentOptions, SUPPORTED_SCHEMES};
 use crate::torrent_state::peer::stats::snapshot::PeerStatsFilter;
+use crate::torrent_state::{CompletionAction, FinishedPeerPolicy, PeerLimits, TunableOptions};
 
 type ApiState = Api;
 
@@ -33,6 +43,9 @@ pub struct HttpApi {
 #[derive(Debug, Default)]
 pub struct HttpApiOptions {
     pub read_only: bool,
+    /// "user:password" to require with HTTP basic auth for the mirror of finished torrents
+    /// under /mirror/. It's open if not set.
+    pub mirror_basic_auth: Option<String>,
 }
 
 impl HttpApi {
@@ -49,25 +62,54 @@ impl HttpApi {
     pub fn make_http_api_and_run(self, addr: SocketAddr) -> BoxFuture<'static, anyhow::Result<()>> {
         let state = self.inner;
 
+        // Too many entries for a single json!() within the default recursion limit.
+        const APIS: &[(&str, &str)] = &[
+            ("GET /", "list all available APIs"),
+            ("GET /dht/stats", "DHT stats"),
+            ("GET /dht/table", "DHT routing table"),
+            ("GET /debug/lock_metrics", "Lock wait/hold times per lock (needs the timed_existence feature)"),
+            ("GET /snapshot", "All torrents with their stats and the session state, taken at once"),
+            ("GET /address_book", "Good DHT nodes and connected peers, to start another instance with (--address-book)"),
+            ("GET /torrents", "List torrents (default torrent is 0)"),
+            ("GET /torrents/{index}", "Torrent details"),
+            ("GET /torrents/{index}/files", "Files with their piece ranges and progress"),
+            ("GET /torrents/{index}/haves", "The bitfield of have pieces"),
+            ("GET /torrents/{index}/stats/v1", "Torrent stats"),
+            ("GET /torrents/{index}/stats/history", "Download/upload rate history for charting"),
+            ("GET /torrents/{index}/peer_stats", "Per peer stats"),
+            ("POST /torrents/{index}/peers/{addr}/add", "Connect to a peer, e.g. one on the local network"),
+            ("POST /torrents/{index}/peers/{addr}/disconnect", "Disconnect from a peer"),
+            ("POST /torrents/{index}/peers/{addr}/ban", "Disconnect from a peer and refuse its connections until the session ends"),
+            ("GET /torrents/{index}/export", "Export the torrent with its progress and options as a single file"),
+            ("GET /torrents/{index}/debug/pieces/{piece}/trace", "State changes of a traced piece"),
+            ("POST /torrents/{index}/debug/pieces/{piece}/trace", "Start tracing a piece"),
+            ("POST /torrents/{index}/debug/pieces/{piece}/trace/stop", "Stop tracing a piece, returns its state changes"),
+            ("POST /torrents/{index}/pause", "Pause torrent"),
+            ("POST /torrents/{index}/reannounce", "Announce to trackers right away"),
+            ("POST /torrents/{index}/recheck", "Re-verify the downloaded pieces in the background while staying live (?rate=<bytes per second>, 16 MiB/s by default)"),
+            ("POST /torrents/{index}/start", "Resume torrent"),
+            ("POST /torrents/{index}/transfer", "Enable or disable downloading (?download=) and uploading (?upload=) separately"),
+            ("POST /torrents/{index}/peer_limits", "Change the connection limits (?max_connections=&max_seeds=&max_pending_dials=), unset ones are reset"),
+            ("POST /torrents/{index}/seed_limits", "Change when to stop seeding (?ratio=&time=<seconds>&action=pause|forget), unset ones are reset"),
+            ("POST /torrents/{index}/options", "Change the rate limits and timeouts (?download_rate_limit=&upload_rate_limit=<bytes per second>&peer_connect_timeout=&peer_read_write_timeout=&peer_request_timeout=&force_tracker_interval=<seconds>), unset ones are reset"),
+            ("POST /torrents/{index}/update_only_files", "Change selected files and their priorities"),
+            ("POST /torrents/{index}/move_storage", "Move the files to another folder (?output_folder=), keeping the torrent running"),
+            ("POST /torrents/{index}/forget", "Forget about the torrent, keep the files"),
+            ("POST /torrents/{index}/delete", "Forget about the torrent, remove the files"),
+            ("POST /torrents", "Add a torrent here. magnet: or http:// or a local file."),
+            ("POST /torrents/batch", "Add many torrents at once, a JSON array of URLs. Returns a result for each"),
+            ("POST /torrents/import", "Add a torrent exported from /torrents/{index}/export"),
+            ("POST /rust_log", "Set RUST_LOG to this post launch (for debugging)"),
+            ("GET /labels", "Policies of torrent labels"),
+            ("POST /labels/{label}", "Set the policy of a label (JSON body)"),
+            ("POST /labels/{label}/remove", "Remove the policy of a label"),
+            ("GET /web/", "Web UI"),
+            ("GET /mirror/", "Browse and download the files of finished torrents"),
+        ];
+
         async fn api_root() -> impl IntoResponse {
             axum::Json(serde_json::json!({
-                "apis": {
-                    "GET /": "list all available APIs",
-                    "GET /dht/stats": "DHT stats",
-                    "GET /dht/table": "DHT routing table",
-                    "GET /torrents": "List torrents (default torrent is 0)",
-                    "GET /torrents/{index}": "Torrent details",
-                    "GET /torrents/{index}/haves": "The bitfield of have pieces",
-                    "GET /torrents/{index}/stats/v1": "Torrent stats",
-                    "GET /torrents/{index}/peer_stats": "Per peer stats",
-                    "POST /torrents/{index}/pause": "Pause torrent",
-                    "POST /torrents/{index}/start": "Resume torrent",
-                    "POST /torrents/{index}/forget": "Forget about the torrent, keep the files",
-                    "POST /torrents/{index}/delete": "Forget about the torrent, remove the files",
-                    "POST /torrents": "Add a torrent here. magnet: or http:// or a local file.",
-                    "POST /rust_log": "Set RUST_LOG to this post launch (for debugging)",
-                    "GET /web/": "Web UI",
-                },
+                "apis": APIS.iter().copied().collect::<BTreeMap<_, _>>(),
                 "server": "rqbit",
                 "version": env!("CARGO_PKG_VERSION"),
             }))
@@ -81,6 +123,18 @@ impl HttpApi {
             state.api_dht_table().map(axum::Json)
         }
 
+        async fn lock_metrics(State(state): State<ApiState>) -> impl IntoResponse {
+            axum::Json(state.api_lock_metrics())
+        }
+
+        async fn session_snapshot(State(state): State<ApiState>) -> impl IntoResponse {
+            axum::Json(state.api_session_snapshot())
+        }
+
+        async fn address_book(State(state): State<ApiState>) -> impl IntoResponse {
+            axum::Json(state.api_address_book())
+        }
+
         async fn torrents_list(State(state): State<ApiState>) -> impl IntoResponse {
             axum::Json(state.api_torrent_list())
         }
@@ -124,6 +178,13 @@ impl HttpApi {
             state.api_torrent_details(idx).map(axum::Json)
         }
 
+        async fn torrent_files(
+            State(state): State<ApiState>,
+            Path(idx): Path<usize>,
+        ) -> Result<impl IntoResponse> {
+            state.api_torrent_files(idx).map(axum::Json)
+        }
+
         async fn torrent_haves(
             State(state): State<ApiState>,
             Path(idx): Path<usize>,
@@ -145,6 +206,13 @@ impl HttpApi {
             state.api_stats_v1(idx).map(axum::Json)
         }
 
+        async fn torrent_stats_history(
+            State(state): State<ApiState>,
+            Path(idx): Path<usize>,
+        ) -> Result<impl IntoResponse> {
+            state.api_stats_history(idx).map(axum::Json)
+        }
+
         async fn peer_stats(
             State(state): State<ApiState>,
             Path(idx): Path<usize>,
@@ -153,6 +221,90 @@ impl HttpApi {
             state.api_peer_stats(idx, filter).map(axum::Json)
         }
 
+        async fn torrent_export(
+            State(state): State<ApiState>,
+            Path(idx): Path<usize>,
+        ) -> Result<impl IntoResponse> {
+            let bundle = state.api_export_torrent(idx)?;
+            Ok((
+                [
+                    ("Content-Type", "application/json".to_owned()),
+                    (
+                        "Content-Disposition",
+                        format!("attachment; filename=\"torrent-{idx}.json\""),
+                    ),
+                ],
+                bundle,
+            ))
+        }
+
+        async fn torrents_post_batch(
+            State(state): State<ApiState>,
+            Query(params): Query<TorrentAddQueryParams>,
+            axum::Json(urls): axum::Json<Vec<String>>,
+        ) -> impl IntoResponse {
+            let opts = params.into_add_torrent_options();
+            axum::Json(state.api_add_torrents(urls, opts).await)
+        }
+
+        #[derive(Deserialize)]
+        struct TorrentImportQueryParams {
+            output_folder: Option<PathBuf>,
+        }
+
+        async fn torrents_import(
+            State(state): State<ApiState>,
+            Query(params): Query<TorrentImportQueryParams>,
+            data: Bytes,
+        ) -> Result<impl IntoResponse> {
+            state
+                .api_import_torrent(&data, params.output_folder)
+                .await
+                .map(axum::Json)
+        }
+
+        async fn piece_trace(
+            State(state): State<ApiState>,
+            Path((idx, piece)): Path<(usize, u32)>,
+        ) -> Result<impl IntoResponse> {
+            state.api_piece_trace(idx, piece).map(axum::Json)
+        }
+
+        async fn piece_trace_start(
+            State(state): State<ApiState>,
+            Path((idx, piece)): Path<(usize, u32)>,
+        ) -> Result<impl IntoResponse> {
+            state.api_trace_piece(idx, piece).map(axum::Json)
+        }
+
+        async fn piece_trace_stop(
+            State(state): State<ApiState>,
+            Path((idx, piece)): Path<(usize, u32)>,
+        ) -> Result<impl IntoResponse> {
+            state.api_stop_tracing_piece(idx, piece).map(axum::Json)
+        }
+
+        async fn peer_add(
+            State(state): State<ApiState>,
+            Path((idx, addr)): Path<(usize, SocketAddr)>,
+        ) -> Result<impl IntoResponse> {
+            state.api_peer_add(idx, addr).map(axum::Json)
+        }
+
+        async fn peer_disconnect(
+            State(state): State<ApiState>,
+            Path((idx, addr)): Path<(usize, SocketAddr)>,
+        ) -> Result<impl IntoResponse> {
+            state.api_peer_disconnect(idx, addr).map(axum::Json)
+        }
+
+        async fn peer_ban(
+            State(state): State<ApiState>,
+            Path((idx, addr)): Path<(usize, SocketAddr)>,
+        ) -> Result<impl IntoResponse> {
+            state.api_peer_ban(idx, addr).map(axum::Json)
+        }
+
         async fn torrent_action_pause(
             State(state): State<ApiState>,
             Path(idx): Path<usize>,
@@ -160,6 +312,28 @@ impl HttpApi {
             state.api_torrent_action_pause(idx).map(axum::Json)
         }
 
+        async fn torrent_action_reannounce(
+            State(state): State<ApiState>,
+            Path(idx): Path<usize>,
+        ) -> Result<impl IntoResponse> {
+            state.api_torrent_action_reannounce(idx).map(axum::Json)
+        }
+
+        #[derive(Deserialize)]
+        struct RecheckQueryParams {
+            rate: Option<u64>,
+        }
+
+        async fn torrent_action_recheck(
+            State(state): State<ApiState>,
+            Path(idx): Path<usize>,
+            Query(params): Query<RecheckQueryParams>,
+        ) -> Result<impl IntoResponse> {
+            state
+                .api_torrent_action_recheck(idx, params.rate)
+                .map(axum::Json)
+        }
+
         async fn torrent_action_start(
             State(state): State<ApiState>,
             Path(idx): Path<usize>,
@@ -167,6 +341,78 @@ impl HttpApi {
             state.api_torrent_action_start(idx).map(axum::Json)
         }
 
+        #[derive(Deserialize)]
+        struct TransferQueryParams {
+            download: Option<bool>,
+            upload: Option<bool>,
+        }
+
+        async fn torrent_action_transfer(
+            State(state): State<ApiState>,
+            Path(idx): Path<usize>,
+            Query(params): Query<TransferQueryParams>,
+        ) -> Result<impl IntoResponse> {
+            state
+                .api_torrent_action_transfer(idx, params.download, params.upload)
+                .map(axum::Json)
+        }
+
+        async fn torrent_action_peer_limits(
+            State(state): State<ApiState>,
+            Path(idx): Path<usize>,
+            Query(limits): Query<PeerLimits>,
+        ) -> Result<impl IntoResponse> {
+            state
+                .api_torrent_action_peer_limits(idx, limits)
+                .map(axum::Json)
+        }
+
+        async fn torrent_action_seed_limits(
+            State(state): State<ApiState>,
+            Path(idx): Path<usize>,
+            Query(limits): Query<SeedLimits>,
+        ) -> Result<impl IntoResponse> {
+            state
+                .api_torrent_action_seed_limits(idx, limits)
+                .map(axum::Json)
+        }
+
+        async fn torrent_action_options(
+            State(state): State<ApiState>,
+            Path(idx): Path<usize>,
+            Query(options): Query<TunableOptions>,
+        ) -> Result<impl IntoResponse> {
+            state
+                .api_torrent_action_tunable_options(idx, options)
+                .map(axum::Json)
+        }
+
+        #[derive(Deserialize)]
+        struct MoveStorageQueryParams {
+            output_folder: PathBuf,
+        }
+
+        async fn torrent_action_move_storage(
+            State(state): State<ApiState>,
+            Path(idx): Path<usize>,
+            Query(params): Query<MoveStorageQueryParams>,
+        ) -> Result<impl IntoResponse> {
+            state
+                .api_torrent_action_move_storage(idx, params.output_folder)
+                .await
+                .map(axum::Json)
+        }
+
+        async fn torrent_action_update_only_files(
+            State(state): State<ApiState>,
+            Path(idx): Path<usize>,
+            axum::Json(req): axum::Json<UpdateFileSelectionRequest>,
+        ) -> Result<impl IntoResponse> {
+            state
+                .api_torrent_action_update_only_files(idx, req)
+                .map(axum::Json)
+        }
+
         async fn torrent_action_forget(
             State(state): State<ApiState>,
             Path(idx): Path<usize>,
@@ -181,6 +427,25 @@ impl HttpApi {
             state.api_torrent_action_delete(idx).map(axum::Json)
         }
 
+        async fn label_policies(State(state): State<ApiState>) -> impl IntoResponse {
+            axum::Json(state.api_label_policies())
+        }
+
+        async fn label_policy_set(
+            State(state): State<ApiState>,
+            Path(label): Path<String>,
+            axum::Json(policy): axum::Json<LabelPolicy>,
+        ) -> Result<impl IntoResponse> {
+            state.api_set_label_policy(&label, policy).map(axum::Json)
+        }
+
+        async fn label_policy_remove(
+            State(state): State<ApiState>,
+            Path(label): Path<String>,
+        ) -> Result<impl IntoResponse> {
+            state.api_remove_label_policy(&label).map(axum::Json)
+        }
+
         async fn set_rust_log(
             State(state): State<ApiState>,
             new_value: String,
@@ -202,20 +467,69 @@ impl HttpApi {
             .route("/rust_log", post(set_rust_log))
             .route("/dht/stats", get(dht_stats))
             .route("/dht/table", get(dht_table))
+            .route("/debug/lock_metrics", get(lock_metrics))
+            .route("/snapshot", get(session_snapshot))
+            .route("/address_book", get(address_book))
             .route("/torrents", get(torrents_list))
             .route("/torrents/:id", get(torrent_details))
+            .route("/torrents/:id/files", get(torrent_files))
             .route("/torrents/:id/haves", get(torrent_haves))
             .route("/torrents/:id/stats", get(torrent_stats_v0))
             .route("/torrents/:id/stats/v1", get(torrent_stats_v1))
-            .route("/torrents/:id/peer_stats", get(peer_stats));
+            .route("/torrents/:id/stats/history", get(torrent_stats_history))
+            .route("/torrents/:id/peer_stats", get(peer_stats))
+            .route("/torrents/:id/peers/:addr/add", post(peer_add))
+            .route(
+                "/torrents/:id/peers/:addr/disconnect",
+                post(peer_disconnect),
+            )
+            .route("/torrents/:id/peers/:addr/ban", post(peer_ban))
+            .route("/torrents/:id/export", get(torrent_export))
+            .route("/torrents/:id/debug/pieces/:piece/trace", get(piece_trace))
+            .route("/labels", get(label_policies))
+            .merge(http_mirror::make_router(
+                self.opts.mirror_basic_auth.clone(),
+            ));
 
         if !self.opts.read_only {
             app = app
                 .route("/torrents", post(torrents_post))
+                .route("/torrents/batch", post(torrents_post_batch))
+                .route("/torrents/import", post(torrents_import))
                 .route("/torrents/:id/pause", post(torrent_action_pause))
+                .route("/torrents/:id/reannounce", post(torrent_action_reannounce))
+                .route("/torrents/:id/recheck", post(torrent_action_recheck))
                 .route("/torrents/:id/start", post(torrent_action_start))
+                .route("/torrents/:id/transfer", post(torrent_action_transfer))
+                .route(
+                    "/torrents/:id/peer_limits",
+                    post(torrent_action_peer_limits),
+                )
+                .route(
+                    "/torrents/:id/seed_limits",
+                    post(torrent_action_seed_limits),
+                )
+                .route("/torrents/:id/options", post(torrent_action_options))
+                .route(
+                    "/torrents/:id/update_only_files",
+                    post(torrent_action_update_only_files),
+                )
+                .route(
+                    "/torrents/:id/move_storage",
+                    post(torrent_action_move_storage),
+                )
                 .route("/torrents/:id/forget", post(torrent_action_forget))
-                .route("/torrents/:id/delete", post(torrent_action_delete));
+                .route("/torrents/:id/delete", post(torrent_action_delete))
+                .route(
+                    "/torrents/:id/debug/pieces/:piece/trace",
+                    post(piece_trace_start),
+                )
+                .route(
+                    "/torrents/:id/debug/pieces/:piece/trace/stop",
+                    post(piece_trace_stop),
+                )
+                .route("/labels/:label", post(label_policy_set))
+                .route("/labels/:label/remove", post(label_policy_remove));
         }
 
         #[cfg(feature = "webui")]
@@ -302,25 +616,55 @@ impl HttpApi {
     }
 }
 
-pub(crate) struct OnlyFiles(Vec<usize>);
+pub(crate) struct FileIds(pub Vec<usize>);
 pub(crate) struct InitialPeers(pub Vec<SocketAddr>);
+// Comma-separated list of path patterns.
+pub(crate) struct PathPatterns(pub Vec<String>);
+// Comma-separated list of "file_id:priority", e.g. "0:high,3:low".
+pub(crate) struct FilePriorities(pub HashMap<usize, FilePriority>);
+// Comma-separated list of "path:priority", e.g. "Season 1:high,**/*.nfo:low".
+pub(crate) struct PathPriorities(pub Vec<(String, FilePriority)>);
 
 #[derive(Serialize, Deserialize, Default)]
 pub(crate) struct TorrentAddQueryParams {
     pub overwrite: Option<bool>,
+    pub force_recheck: Option<bool>,
+    pub file_allocation: Option<FileAllocation>,
+    pub disable_download: Option<bool>,
+    pub disable_upload: Option<bool>,
     pub output_folder: Option<String>,
     pub sub_folder: Option<String>,
     pub only_files_regex: Option<String>,
-    pub only_files: Option<OnlyFiles>,
+    pub only_files: Option<FileIds>,
+    pub skip_files: Option<FileIds>,
+    pub only_files_paths: Option<PathPatterns>,
+    pub skip_files_paths: Option<PathPatterns>,
+    pub ignore_default_skip_paths: Option<bool>,
+    pub file_priorities: Option<FilePriorities>,
+    pub file_priorities_paths: Option<PathPriorities>,
+    pub finished_peer_policy: Option<FinishedPeerPolicy>,
+    pub upload_coupling: Option<UploadCoupling>,
+    pub completion_action: Option<CompletionAction>,
+    pub max_connections: Option<usize>,
+    pub max_seeds: Option<usize>,
+    pub max_pending_dials: Option<usize>,
+    pub seed_ratio_limit: Option<f64>,
+    // Seconds.
+    pub seed_time_limit: Option<u64>,
+    pub seed_limit_action: Option<SeedLimitAction>,
+    // Bytes per second.
+    pub download_rate_limit: Option<u64>,
+    pub upload_rate_limit: Option<u64>,
     pub peer_connect_timeout: Option<u64>,
     pub peer_read_write_timeout: Option<u64>,
     pub initial_peers: Option<InitialPeers>,
+    pub label: Option<String>,
     // Will force interpreting the content as a URL.
     pub is_url: Option<bool>,
     pub list_only: Option<bool>,
 }
 
-impl Serialize for OnlyFiles {
+impl Serialize for FileIds {
     fn serialize<S>(&self, serializer: S) -> core::result::Result<S::Ok, S::Error>
     where
         S: serde::Serializer,
@@ -330,7 +674,7 @@ impl Serialize for OnlyFiles {
     }
 }
 
-impl<'de> Deserialize<'de> for OnlyFiles {
+impl<'de> Deserialize<'de> for FileIds {
     fn deserialize<D>(deserializer: D) -> core::result::Result<Self, D::Error>
     where
         D: serde::Deserializer<'de>,
@@ -346,16 +690,14 @@ impl<'de> Deserialize<'de> for OnlyFiles {
                     Ok(acc)
                 }
                 Err(_) => Err(D::Error::custom(format!(
-                    "only_files: failed to parse {:?} as integer",
+                    "failed to parse file id {:?} as integer",
                     c
                 ))),
             })?;
         if list.is_empty() {
-            return Err(D::Error::custom(
-                "only_files: should contain at least one file id",
-            ));
+            return Err(D::Error::custom("should contain at least one file id"));
         }
-        Ok(OnlyFiles(list))
+        Ok(FileIds(list))
     }
 }
 
@@ -387,16 +729,136 @@ impl Serialize for InitialPeers {
     }
 }
 
+impl<'de> Deserialize<'de> for PathPatterns {
+    fn deserialize<D>(deserializer: D) -> core::result::Result<Self, D::Error>
+    where
+        D: serde::Deserializer<'de>,
+    {
+        let s = String::deserialize(deserializer)?;
+        Ok(PathPatterns(
+            s.split(',')
+                .filter(|s| !s.is_empty())
+                .map(|s| s.to_owned())
+                .collect(),
+        ))
+    }
+}
+
+impl Serialize for PathPatterns {
+    fn serialize<S>(&self, serializer: S) -> core::result::Result<S::Ok, S::Error>
+    where
+        S: serde::Serializer,
+    {
+        self.0.join(",").serialize(serializer)
+    }
+}
+
+impl<'de> Deserialize<'de> for FilePriorities {
+    fn deserialize<D>(deserializer: D) -> core::result::Result<Self, D::Error>
+    where
+        D: serde::Deserializer<'de>,
+    {
+        use serde::de::Error;
+
+        let s = String::deserialize(deserializer)?;
+        let mut priorities = HashMap::new();
+        for item in s.split(',').filter(|s| !s.is_empty()) {
+            let (id, priority) = item.split_once(':').ok_or_else(|| {
+                D::Error::custom(format!("expected \"file_id:priority\", got {item:?}"))
+            })?;
+            let id = id.parse().map_err(|_| {
+                D::Error::custom(format!("failed to parse file id {:?} as integer", id))
+            })?;
+            let priority = priority.parse().map_err(D::Error::custom)?;
+            priorities.insert(id, priority);
+        }
+        Ok(FilePriorities(priorities))
+    }
+}
+
+impl Serialize for FilePriorities {
+    fn serialize<S>(&self, serializer: S) -> core::result::Result<S::Ok, S::Error>
+    where
+        S: serde::Serializer,
+    {
+        self.0
+            .iter()
+            .map(|(id, p)| format!("{id}:{p}"))
+            .join(",")
+            .serialize(serializer)
+    }
+}
+
+impl<'de> Deserialize<'de> for PathPriorities {
+    fn deserialize<D>(deserializer: D) -> core::result::Result<Self, D::Error>
+    where
+        D: serde::Deserializer<'de>,
+    {
+        use serde::de::Error;
+
+        let s = String::deserialize(deserializer)?;
+        s.split(',')
+            .filter(|s| !s.is_empty())
+            .map(|item| {
+                let (path, priority) = item.rsplit_once(':').ok_or_else(|| {
+                    D::Error::custom(format!("expected \"path:priority\", got {item:?}"))
+                })?;
+                Ok((path.to_owned(), priority.parse().map_err(D::Error::custom)?))
+            })
+            .collect::<core::result::Result<_, _>>()
+            .map(PathPriorities)
+    }
+}
+
+impl Serialize for PathPriorities {
+    fn serialize<S>(&self, serializer: S) -> core::result::Result<S::Ok, S::Error>
+    where
+        S: serde::Serializer,
+    {
+        self.0
+            .iter()
+            .map(|(path, p)| format!("{path}:{p}"))
+            .join(",")
+            .serialize(serializer)
+    }
+}
+
 impl TorrentAddQueryParams {
     pub fn into_add_torrent_options(self) -> AddTorrentOptions {
         AddTorrentOptions {
             overwrite: self.overwrite.unwrap_or(false),
+            force_recheck: self.force_recheck.unwrap_or(false),
+            file_allocation: self.file_allocation.unwrap_or_default(),
+            disable_download: self.disable_download.unwrap_or(false),
+            disable_upload: self.disable_upload.unwrap_or(false),
             only_files_regex: self.only_files_regex,
             only_files: self.only_files.map(|o| o.0),
+            skip_files: self.skip_files.map(|o| o.0),
+            only_files_paths: self.only_files_paths.map(|p| p.0),
+            skip_files_paths: self.skip_files_paths.map(|p| p.0),
+            ignore_default_skip_paths: self.ignore_default_skip_paths.unwrap_or(false),
+            file_priorities: self.file_priorities.map(|p| p.0),
+            file_priorities_paths: self.file_priorities_paths.map(|p| p.0),
+            finished_peer_policy: self.finished_peer_policy,
+            upload_coupling: self.upload_coupling,
+            completion_action: self.completion_action.unwrap_or_default(),
+            peer_limits: PeerLimits {
+                max_connections: self.max_connections,
+                max_seeds: self.max_seeds,
+                max_pending_dials: self.max_pending_dials,
+            },
+            seed_limits: SeedLimits {
+                ratio: self.seed_ratio_limit,
+                time: self.seed_time_limit.map(Duration::from_secs),
+                action: self.seed_limit_action.unwrap_or_default(),
+            },
+            download_rate_limit: self.download_rate_limit,
+            upload_rate_limit: self.upload_rate_limit,
             output_folder: self.output_folder,
             sub_folder: self.sub_folder,
             list_only: self.list_only.unwrap_or(false),
             initial_peers: self.initial_peers.map(|i| i.0),
+            label: self.label,
             peer_opts: Some(PeerConnectionOptions {
                 connect_timeout: self.peer_connect_timeout.map(Duration::from_secs),
                 read_write_timeout: self.peer_read_write_timeout.map(Duration::from_secs),
@@ -4,7 +4,7 @@ use serde::Deserialize;
 
 use crate::{
     api::ApiAddTorrentResponse,
-    http_api::TorrentAddQueryParams,
+    http_api::{FileIds, FilePriorities, PathPatterns, PathPriorities, TorrentAddQueryParams},
     session::{AddTorrent, AddTorrentOptions},
 };
 
@@ -94,11 +94,33 @@ impl HttpApiClient {
             let opts = opts.unwrap_or_default();
             let params = TorrentAddQueryParams {
                 overwrite: Some(opts.overwrite),
+                force_recheck: Some(opts.force_recheck),
+                file_allocation: Some(opts.file_allocation),
+                disable_download: Some(opts.disable_download),
+                disable_upload: Some(opts.disable_upload),
                 only_files_regex: opts.only_files_regex,
                 only_files: None,
+                skip_files: opts.skip_files.map(FileIds),
+                only_files_paths: opts.only_files_paths.map(PathPatterns),
+                skip_files_paths: opts.skip_files_paths.map(PathPatterns),
+                ignore_default_skip_paths: Some(opts.ignore_default_skip_paths),
+                file_priorities: opts.file_priorities.map(FilePriorities),
+                file_priorities_paths: opts.file_priorities_paths.map(PathPriorities),
+                finished_peer_policy: opts.finished_peer_policy,
+                upload_coupling: opts.upload_coupling,
+                completion_action: Some(opts.completion_action),
+                max_connections: opts.peer_limits.max_connections,
+                max_seeds: opts.peer_limits.max_seeds,
+                max_pending_dials: opts.peer_limits.max_pending_dials,
+                seed_ratio_limit: opts.seed_limits.ratio,
+                seed_time_limit: opts.seed_limits.time.map(|t| t.as_secs()),
+                seed_limit_action: Some(opts.seed_limits.action),
+                download_rate_limit: opts.download_rate_limit,
+                upload_rate_limit: opts.upload_rate_limit,
                 output_folder: opts.output_folder,
                 sub_folder: opts.sub_folder,
                 list_only: Some(opts.list_only),
+                label: opts.label,
                 ..Default::default()
             };
             let qs = serde_urlencoded::to_string(&params).unwrap();
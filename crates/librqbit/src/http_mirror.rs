@@ -0,0 +1,360 @@
+// A read-only mirror of the finished torrents over HTTP, under /mirror/ of the HTTP API. Each
+// torrent is a folder with its files, as plain HTML listings a browser or wget -r can walk.
+// Only the selected files of finished torrents are served, straight from disk, with support for
+// ranges so that downloads can be resumed.
+
+use std::{
+    fmt::Write as _,
+    io::{Read, Seek, SeekFrom},
+    path::PathBuf,
+    sync::Arc,
+};
+
+use anyhow::Context;
+use axum::{
+    body::Body,
+    extract::{Path, Request, State},
+    middleware::Next,
+    response::{Html, IntoResponse, Redirect, Response},
+    routing::get,
+    Router,
+};
+use base64::Engine;
+use http::{header, HeaderMap, HeaderValue, StatusCode};
+use itertools::Itertools;
+
+use crate::{
+    api::{Api, Result},
+    api_error::ApiError,
+    output_dir::OpenMode,
+    torrent_state::ManagedTorrentHandle,
+};
+
+const READ_CHUNK: usize = 64 * 1024;
+
+pub(crate) fn make_router(basic_auth: Option<String>) -> Router<Api> {
+    let router = Router::new()
+        .route("/mirror", get(|| async { Redirect::permanent("/mirror/") }))
+        .route("/mirror/", get(list_torrents))
+        .route("/mirror/:id/", get(torrent_root))
+        .route("/mirror/:id/*path", get(torrent_path));
+    match basic_auth {
+        Some(credentials) => router.route_layer(axum::middleware::from_fn_with_state(
+            Arc::new(credentials),
+            check_basic_auth,
+        )),
+        None => router,
+    }
+}
+
+// "credentials" is "user:password".
+async fn check_basic_auth(
+    State(credentials): State<Arc<String>>,
+    request: Request,
+    next: Next,
+) -> Response {
+    let provided = request
+        .headers()
+        .get(header::AUTHORIZATION)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.strip_prefix("Basic "))
+        .and_then(|v| base64::engine::general_purpose::STANDARD.decode(v).ok());
+    if provided.is_some_and(|p| constant_time_eq(&p, credentials.as_bytes())) {
+        return next.run(request).await;
+    }
+    (
+        StatusCode::UNAUTHORIZED,
+        [(header::WWW_AUTHENTICATE, "Basic realm=\"rqbit mirror\"")],
+    )
+        .into_response()
+}
+
+// Takes as long wherever the first difference is, so that the credentials can't be guessed
+// byte by byte from the response times. Only the length leaks.
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0u8, |diff, (x, y)| diff | (x ^ y)) == 0
+}
+
+fn not_found() -> ApiError {
+    ApiError::new_from_text(StatusCode::NOT_FOUND, "not found")
+}
+
+fn escape_html(s: &str) -> String {
+    let mut out = String::with_capacity(s.len());
+    for c in s.chars() {
+        match c {
+            '&' => out.push_str("&amp;"),
+            '<' => out.push_str("&lt;"),
+            '>' => out.push_str("&gt;"),
+            '"' => out.push_str("&quot;"),
+            '\'' => out.push_str("&#39;"),
+            c => out.push(c),
+        }
+    }
+    out
+}
+
+// (href, text) pairs, folders first.
+fn listing(title: &str, entries: impl IntoIterator<Item = (String, String)>) -> Html<String> {
+    let title = escape_html(title);
+    let mut body = format!(
+        "<!DOCTYPE html>\n<html><head><meta charset=\"utf-8\"><title>{title}</title></head>\
+         <body><h1>{title}</h1><ul>\n<li><a href=\"../\">../</a></li>\n"
+    );
+    for (href, text) in entries {
+        let _ = writeln!(
+            body,
+            "<li><a href=\"{}\">{}</a></li>",
+            escape_html(&href),
+            escape_html(&text)
+        );
+    }
+    body.push_str("</ul></body></html>\n");
+    Html(body)
+}
+
+fn is_mirrored(handle: &ManagedTorrentHandle) -> bool {
+    handle.stats().finished
+}
+
+async fn list_torrents(State(api): State<Api>) -> impl IntoResponse {
+    let torrents = api
+        .session()
+        .with_torrents(|torrents| torrents.map(|(id, h)| (id, h.clone())).collect_vec());
+    let entries = torrents
+        .into_iter()
+        .filter(|(_, handle)| is_mirrored(handle))
+        .map(|(id, handle)| {
+            let name = handle
+                .info()
+                .info
+                .name
+                .as_ref()
+                .map(|n| String::from_utf8_lossy(n.as_ref()).into_owned())
+                .unwrap_or_else(|| handle.info_hash().as_string());
+            (format!("{id}/"), format!("{name}/"))
+        });
+    listing("rqbit mirror", entries)
+}
+
+struct MirroredFile {
+    components: Vec<String>,
+    filename: PathBuf,
+    len: u64,
+}
+
+// The selected files of a finished torrent.
+fn mirrored_files(api: &Api, id: usize) -> Result<(ManagedTorrentHandle, Vec<MirroredFile>)> {
+    let handle = api.mgr_handle(id)?;
+    if !is_mirrored(&handle) {
+        return Err(not_found());
+    }
+    let only_files = handle.only_files();
+    let info = handle.info();
+    let filenames = info.file_paths_in(&info.out_dir())?;
+    let mut files = Vec::new();
+    for (idx, (name, len)) in info
+        .info
+        .iter_filenames_and_lengths()
+        .context("error iterating filenames")?
+        .enumerate()
+    {
+        if only_files.as_ref().is_some_and(|o| !o.contains(&idx)) {
+            continue;
+        }
+        files.push(MirroredFile {
+            components: name
+                .iter_components()
+                .map(|c| c.map(|c| c.to_owned()))
+                .collect::<anyhow::Result<_>>()?,
+            filename: filenames[idx].clone(),
+            len,
+        });
+    }
+    Ok((handle, files))
+}
+
+async fn torrent_root(
+    State(api): State<Api>,
+    Path(id): Path<usize>,
+    headers: HeaderMap,
+) -> Result<Response> {
+    serve(&api, id, "", &headers)
+}
+
+async fn torrent_path(
+    State(api): State<Api>,
+    Path((id, path)): Path<(usize, String)>,
+    headers: HeaderMap,
+) -> Result<Response> {
+    serve(&api, id, &path, &headers)
+}
+
+fn serve(api: &Api, id: usize, path: &str, headers: &HeaderMap) -> Result<Response> {
+    let (handle, files) = mirrored_files(api, id)?;
+    let is_dir = path.is_empty() || path.ends_with('/');
+    let requested = path
+        .split('/')
+        .filter(|c| !c.is_empty())
+        .map(|c| c.to_owned())
+        .collect_vec();
+
+    if !is_dir {
+        if let Some(file) = files.iter().find(|f| f.components == requested) {
+            return serve_file(handle, file, headers);
+        }
+    }
+
+    // Folders are the common prefixes of the files in them.
+    let entries = files
+        .iter()
+        .filter(|f| f.components.len() > requested.len() && f.components.starts_with(&requested))
+        .map(|f| {
+            let name = &f.components[requested.len()];
+            let is_dir = f.components.len() > requested.len() + 1;
+            (!is_dir, name.clone(), is_dir)
+        })
+        .unique()
+        .sorted()
+        .map(|(_, name, is_dir)| {
+            let suffix = if is_dir { "/" } else { "" };
+            (
+                format!("{}{suffix}", urlencoding::encode(&name)),
+                format!("{name}{suffix}"),
+            )
+        })
+        .collect_vec();
+    if entries.is_empty() {
+        return Err(not_found());
+    }
+    if !is_dir {
+        // Relative links in the listing need the trailing slash.
+        let name = path.rsplit('/').next().unwrap_or_default();
+        return Ok((
+            StatusCode::MOVED_PERMANENTLY,
+            [(header::LOCATION, format!("{}/", urlencoding::encode(name)))],
+        )
+            .into_response());
+    }
+    Ok(listing(&format!("/{}", requested.join("/")), entries).into_response())
+}
+
+// "bytes=start-end", "bytes=start-" or "bytes=-suffix_len". None if not satisfiable, Some(None)
+// if there's no range. Several ranges aren't supported, the whole file is sent for them.
+fn parse_range(headers: &HeaderMap, len: u64) -> Option<Option<(u64, u64)>> {
+    let value = match headers.get(header::RANGE) {
+        Some(v) => v.to_str().ok()?,
+        None => return Some(None),
+    };
+    let spec = value.strip_prefix("bytes=")?;
+    if len == 0 {
+        return None;
+    }
+    if spec.contains(',') {
+        return Some(None);
+    }
+    let (start, end) = spec.split_once('-')?;
+    let (start, end) = match (start.trim(), end.trim()) {
+        ("", suffix) => {
+            let suffix = suffix.parse::<u64>().ok()?.min(len);
+            (len - suffix, len.checked_sub(1)?)
+        }
+        (start, "") => (start.parse().ok()?, len.checked_sub(1)?),
+        (start, end) => (start.parse().ok()?, end.parse::<u64>().ok()?.min(len - 1)),
+    };
+    if start > end || start >= len {
+        return None;
+    }
+    Some(Some((start, end)))
+}
+
+fn serve_file(
+    handle: ManagedTorrentHandle,
+    file: &MirroredFile,
+    headers: &HeaderMap,
+) -> Result<Response> {
+    let range = match parse_range(headers, file.len) {
+        Some(r) => r,
+        None => {
+            return Ok((
+                StatusCode::RANGE_NOT_SATISFIABLE,
+                [(header::CONTENT_RANGE, format!("bytes */{}", file.len))],
+            )
+                .into_response())
+        }
+    };
+    let (start, end) = range.unwrap_or((0, file.len.saturating_sub(1)));
+    let body_len = if file.len == 0 { 0 } else { end - start + 1 };
+
+    let mut f = handle
+        .info()
+        .out_dir_handle()
+        .open(&file.filename, OpenMode::Read)?;
+    f.seek(SeekFrom::Start(start))
+        .with_context(|| format!("error seeking {:?}", file.filename))?;
+    let spawner = handle.info().spawner;
+    let stream = futures::stream::unfold((f, body_len), move |(mut f, remaining)| async move {
+        if remaining == 0 {
+            return None;
+        }
+        let mut buf = vec![0u8; (remaining as usize).min(READ_CHUNK)];
+        match spawner.spawn_block_in_place(|| f.read_exact(&mut buf)) {
+            Ok(()) => {
+                let remaining = remaining - buf.len() as u64;
+                Some((Ok(buf), (f, remaining)))
+            }
+            Err(e) => Some((Err(e), (f, 0))),
+        }
+    });
+
+    let mut response = Response::new(Body::from_stream(stream));
+    let h = response.headers_mut();
+    h.insert(
+        header::CONTENT_TYPE,
+        HeaderValue::from_static("application/octet-stream"),
+    );
+    h.insert(header::ACCEPT_RANGES, HeaderValue::from_static("bytes"));
+    h.insert(header::CONTENT_LENGTH, HeaderValue::from(body_len));
+    if range.is_some() {
+        *response.status_mut() = StatusCode::PARTIAL_CONTENT;
+        response.headers_mut().insert(
+            header::CONTENT_RANGE,
+            HeaderValue::try_from(format!("bytes {start}-{end}/{}", file.len))
+                .context("bug: invalid header")?,
+        );
+    }
+    Ok(response)
+}
+
+#[cfg(test)]
+mod tests {
+    use http::{header, HeaderMap};
+
+    use super::{constant_time_eq, parse_range};
+
+    #[test]
+    fn test_constant_time_eq() {
+        assert!(constant_time_eq(b"user:pass", b"user:pass"));
+        assert!(!constant_time_eq(b"user:pass", b"user:pasS"));
+        assert!(!constant_time_eq(b"user:pass", b"user:pas"));
+        assert!(constant_time_eq(b"", b""));
+    }
+
+    #[test]
+    fn test_parse_range() {
+        let range = |v: &str| {
+            let mut h = HeaderMap::new();
+            h.insert(header::RANGE, v.parse().unwrap());
+            parse_range(&h, 100)
+        };
+        assert_eq!(parse_range(&HeaderMap::new(), 100), Some(None));
+        assert_eq!(range("bytes=0-9"), Some(Some((0, 9))));
+        assert_eq!(range("bytes=90-"), Some(Some((90, 99))));
+        assert_eq!(range("bytes=-10"), Some(Some((90, 99))));
+        assert_eq!(range("bytes=50-500"), Some(Some((50, 99))));
+        assert_eq!(range("bytes=0-1,5-6"), Some(None));
+        assert_eq!(range("bytes=100-"), None);
+        assert_eq!(range("bytes=9-0"), None);
+        assert_eq!(range("items=0-1"), None);
+    }
+}
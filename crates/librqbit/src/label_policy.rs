@@ -0,0 +1,127 @@
+// Policies attached to torrent labels.
+//
+// Every torrent added with a label shares the label's rate limiters and peer cap, so
+// changing a policy applies to the running torrents immediately. Seeding limits are
+// evaluated periodically by the session.
+
+use std::{
+    collections::HashMap,
+    sync::{Arc, Weak},
+    time::Duration,
+};
+
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    limits::{Limits, UploadCoupling},
+    session::Session,
+    torrent_state::FinishedPeerPolicy,
+};
+
+const POLICY_CHECK_INTERVAL: Duration = Duration::from_secs(10);
+
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct LabelPolicy {
+    /// Download limit in bytes per second, shared by all torrents with the label.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub download_limit_bps: Option<u64>,
+    /// Upload limit in bytes per second, shared by all torrents with the label.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub upload_limit_bps: Option<u64>,
+    /// The maximum number of connected peers for each torrent with the label.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_peers_per_torrent: Option<usize>,
+    /// Used for torrents added with the label, unless set explicitly when adding.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub finished_peer_policy: Option<FinishedPeerPolicy>,
+    /// Used for torrents added with the label, unless set explicitly when adding.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub upload_coupling: Option<UploadCoupling>,
+    /// The seed ratio limit of torrents with the label that don't have one of their own, see
+    /// [`crate::SeedLimits::ratio`].
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub seed_ratio_limit: Option<f64>,
+}
+
+#[derive(Default)]
+pub(crate) struct Label {
+    pub policy: LabelPolicy,
+    pub limits: Arc<Limits>,
+}
+
+impl Label {
+    fn set_policy(&mut self, policy: LabelPolicy) {
+        self.limits.download.set_limit(policy.download_limit_bps);
+        self.limits.upload.set_limit(policy.upload_limit_bps);
+        self.limits.set_max_peers(policy.max_peers_per_torrent);
+        self.policy = policy;
+    }
+}
+
+impl Session {
+    /// Set the policy for torrents with the given label, replacing the previous one.
+    /// Limits apply to the running torrents right away.
+    pub fn set_label_policy(&self, label: &str, policy: LabelPolicy) {
+        self.labels
+            .write()
+            .entry(label.to_owned())
+            .or_default()
+            .set_policy(policy);
+    }
+
+    /// Remove the policy of the label. Its torrents are no longer limited.
+    pub fn remove_label_policy(&self, label: &str) {
+        if let Some(l) = self.labels.write().get_mut(label) {
+            l.set_policy(Default::default());
+        }
+    }
+
+    pub fn label_policy(&self, label: &str) -> Option<LabelPolicy> {
+        self.labels
+            .read()
+            .get(label)
+            .filter(|l| l.policy != LabelPolicy::default())
+            .map(|l| l.policy.clone())
+    }
+
+    /// All labels that have a policy set.
+    pub fn label_policies(&self) -> HashMap<String, LabelPolicy> {
+        self.labels
+            .read()
+            .iter()
+            .filter(|(_, l)| l.policy != LabelPolicy::default())
+            .map(|(name, l)| (name.clone(), l.policy.clone()))
+            .collect()
+    }
+
+    // The limits shared by all torrents with the label. Created on first use, so that
+    // setting a policy later applies to torrents that were added before.
+    pub(crate) fn label_limits(&self, label: &str) -> Arc<Limits> {
+        self.labels
+            .write()
+            .entry(label.to_owned())
+            .or_default()
+            .limits
+            .clone()
+    }
+
+    pub(crate) async fn task_label_policies(session: Weak<Self>) -> anyhow::Result<()> {
+        loop {
+            tokio::time::sleep(POLICY_CHECK_INTERVAL).await;
+            let session = match session.upgrade() {
+                Some(s) => s,
+                None => return Ok(()),
+            };
+            session.enforce_seed_limits();
+        }
+    }
+
+    // The seed ratio limits of the labels that have one.
+    pub(crate) fn label_seed_ratio_limits(&self) -> HashMap<String, f64> {
+        self.labels
+            .read()
+            .iter()
+            .filter_map(|(name, l)| Some((name.clone(), l.policy.seed_ratio_limit?)))
+            .collect()
+    }
+}
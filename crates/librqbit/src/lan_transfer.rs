@@ -0,0 +1,173 @@
+// Zero-configuration transfers between two machines on the same network.
+//
+// The sender creates a torrent on the fly and announces it through LSD (and DHT if enabled).
+// The receiver gets a short pairing code, which is a prefix of the info hash, and finds
+// the sender by listening for LSD announcements matching it.
+
+use std::{path::Path, sync::Arc, time::Duration};
+
+use anyhow::Context;
+use librqbit_core::hash_id::Id20;
+use tracing::{debug, error_span, info};
+
+use crate::{
+    create_torrent,
+    lsd::Lsd,
+    session::{AddTorrent, AddTorrentOptions, AddTorrentResponse, Session},
+    torrent_state::ManagedTorrentHandle,
+    CreateTorrentOptions,
+};
+
+// How many hex characters of the info hash are in the pairing code.
+const PAIRING_CODE_LEN: usize = 8;
+// Much more often than BEP 14 suggests, but the receiver is waiting for it.
+const LAN_ANNOUNCE_INTERVAL: Duration = Duration::from_secs(5);
+
+/// A torrent being shared with [`Session::lan_send`].
+pub struct LanSend {
+    /// Pass this to [`Session::lan_receive`] on the other machine.
+    pub code: String,
+    pub handle: ManagedTorrentHandle,
+}
+
+fn pairing_code(info_hash: Id20) -> String {
+    let hex = info_hash.as_string();
+    format!(
+        "{}-{}",
+        &hex[..PAIRING_CODE_LEN / 2],
+        &hex[PAIRING_CODE_LEN / 2..PAIRING_CODE_LEN]
+    )
+}
+
+// Returns the lowercase hex info hash prefix.
+fn parse_pairing_code(code: &str) -> anyhow::Result<String> {
+    let code = code.replace('-', "").to_ascii_lowercase();
+    if code.len() < PAIRING_CODE_LEN
+        || code.len() > 40
+        || !code.chars().all(|c| c.is_ascii_hexdigit())
+    {
+        anyhow::bail!(
+            "invalid pairing code, expected at least {PAIRING_CODE_LEN} hex characters, e.g. \"1a2b-3c4d\""
+        );
+    }
+    Ok(code)
+}
+
+impl Session {
+    /// Share a file or a directory on the local network. Returns the pairing code for
+    /// [`Session::lan_receive`]. The torrent is seeded for as long as the session runs.
+    pub async fn lan_send(self: &Arc<Self>, path: &Path) -> anyhow::Result<LanSend> {
+        let port = self
+            .tcp_listen_port()
+            .context("can't share without listening for peer connections")?;
+        let path = path
+            .canonicalize()
+            .with_context(|| format!("error opening {:?}", path))?;
+        let torrent = create_torrent(&path, CreateTorrentOptions::default())
+            .await
+            .with_context(|| format!("error creating torrent from {:?}", path))?;
+        let info_hash = torrent.info_hash();
+
+        // The files are already in place, point the torrent to them.
+        let output_folder = if path.is_dir() {
+            path.as_path()
+        } else {
+            path.parent().context("file has no parent directory")?
+        };
+        let opts = AddTorrentOptions {
+            output_folder: Some(
+                output_folder
+                    .to_str()
+                    .context("path is not valid UTF-8")?
+                    .to_owned(),
+            ),
+            overwrite: true,
+            ..Default::default()
+        };
+        let handle = match self
+            .add_torrent(
+                AddTorrent::TorrentInfo(Box::new(
+                    torrent
+                        .as_v1_info()
+                        .context("expected a v1 torrent")?
+                        .clone(),
+                )),
+                Some(opts),
+            )
+            .await?
+        {
+            AddTorrentResponse::Added(_, handle)
+            | AddTorrentResponse::AlreadyManaged(_, handle) => handle,
+            AddTorrentResponse::ListOnly(_) => anyhow::bail!("bug: unexpected list only"),
+        };
+
+        let lsd = Lsd::new()?;
+        self.spawn(error_span!("lan_send_announcer", ?info_hash), async move {
+            loop {
+                lsd.announce(info_hash, port).await?;
+                tokio::time::sleep(LAN_ANNOUNCE_INTERVAL).await;
+            }
+        });
+
+        Ok(LanSend {
+            code: pairing_code(info_hash),
+            handle,
+        })
+    }
+
+    /// Download what the other machine shares with [`Session::lan_send`], given its pairing code.
+    ///
+    /// Waits until the sender is found on the local network. "opts" are used to add the torrent.
+    pub async fn lan_receive(
+        self: &Arc<Self>,
+        code: &str,
+        opts: Option<AddTorrentOptions>,
+    ) -> anyhow::Result<ManagedTorrentHandle> {
+        let prefix = parse_pairing_code(code)?;
+        let lsd = Lsd::new()?;
+        info!(code, "looking for the sender on the local network");
+        let found = 'found: loop {
+            for a in lsd.recv().await? {
+                if a.info_hash.as_string().starts_with(&prefix) {
+                    break 'found a;
+                }
+                debug!(info_hash = ?a.info_hash, "ignoring announcement for another torrent");
+            }
+        };
+        info!(info_hash = ?found.info_hash, addr = %found.addr, "found the sender");
+
+        let magnet = format!("magnet:?xt=urn:btih:{}", found.info_hash.as_string());
+        let mut opts = opts.unwrap_or_default();
+        opts.initial_peers
+            .get_or_insert_with(Default::default)
+            .push(found.addr);
+        match self
+            .add_torrent(AddTorrent::from_url(magnet), Some(opts))
+            .await?
+        {
+            AddTorrentResponse::Added(_, handle)
+            | AddTorrentResponse::AlreadyManaged(_, handle) => Ok(handle),
+            AddTorrentResponse::ListOnly(_) => anyhow::bail!("bug: unexpected list only"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::str::FromStr;
+
+    use librqbit_core::hash_id::Id20;
+
+    use super::{pairing_code, parse_pairing_code};
+
+    #[test]
+    fn test_pairing_code() {
+        let info_hash = Id20::from_str("cab507494d02ebb1178b38f2e9d7be299c86b862").unwrap();
+        let code = pairing_code(info_hash);
+        assert_eq!(code, "cab5-0749");
+        assert_eq!(parse_pairing_code(&code).unwrap(), "cab50749");
+        assert_eq!(parse_pairing_code("CAB5-0749").unwrap(), "cab50749");
+        assert!(parse_pairing_code("cab5").is_err());
+        assert!(parse_pairing_code("zzzz-zzzz").is_err());
+    }
+}
@@ -22,39 +22,93 @@
 //! It also proved useful to use the [`Api`] when building the rqbit desktop app, as it provides
 //! a facade that works with simple serializable types.
 
+mod address_book;
 pub mod api;
 mod api_error;
+mod batch_add;
 mod chunk_tracker;
 mod create_torrent_file;
 mod dht_utils;
+mod dialer;
+mod disk_retry;
+mod disk_space;
+mod disk_write_limits;
+mod external_ip;
 mod file_ops;
+mod file_selection;
+mod hash_pool;
 pub mod http_api;
 pub mod http_api_client;
+mod http_mirror;
+mod label_policy;
+mod lan_transfer;
+mod limits;
+mod lsd;
+mod output_dir;
+mod part_file;
 mod peer_connection;
 mod peer_info_reader;
 mod read_buf;
+mod resume_data;
+mod seed_limits;
+#[cfg(target_os = "linux")]
+mod sendfile;
 mod session;
+mod session_snapshot;
+mod socks;
 mod spawn_utils;
+mod torrent_bundle;
 mod torrent_state;
 pub mod tracing_subscriber_config_utils;
+mod transmission_import;
+mod trusted_peers;
 mod type_aliases;
+#[cfg(all(target_os = "linux", feature = "io-uring"))]
+mod uring;
 
+pub use address_book::AddressBook;
 pub use api::Api;
 pub use api_error::ApiError;
-pub use create_torrent_file::{create_torrent, CreateTorrentOptions};
+pub use create_torrent_file::{
+    auto_piece_length, create_torrent, CreateTorrentOptions, TorrentVersion,
+};
 pub use dht;
-pub use peer_connection::PeerConnectionOptions;
+pub use dialer::ProxyIsolation;
+pub use disk_retry::DiskRetryPolicy;
+pub use disk_write_limits::DiskWriteLimit;
+pub use file_ops::FileAllocation;
+pub use file_selection::{FilePriority, PathPattern};
+pub use label_policy::LabelPolicy;
+pub use lan_transfer::LanSend;
+pub use limits::UploadCoupling;
+pub use lsd::{Lsd, LsdAnnouncement};
+pub use peer_connection::{PeerConnectionOptions, PeerSocketOptions};
+pub use seed_limits::{SeedLimitAction, SeedLimits};
 pub use session::{
-    AddTorrent, AddTorrentOptions, AddTorrentResponse, ListOnlyResponse, Session, SessionOptions,
-    SUPPORTED_SCHEMES,
+    AddTorrent, AddTorrentOptions, AddTorrentResponse, CompletionCallback, ListOnlyResponse,
+    Session, SessionOptions, SUPPORTED_SCHEMES,
 };
+pub use session_snapshot::{SessionSnapshot, SessionTotals, TorrentSummary};
+pub use sha1w::Sha1Backend;
 pub use spawn_utils::spawn as librqbit_spawn;
-pub use torrent_state::{ManagedTorrent, ManagedTorrentState, TorrentStats, TorrentStatsState};
+pub use torrent_state::{
+    events::TorrentEvent,
+    live::{
+        peer::PeerSource,
+        recheck::{RecheckProgress, DEFAULT_RECHECK_BYTES_PER_SEC},
+    },
+    streaming::{ReadaheadOptions, TorrentFileReader},
+    CompletionAction, FinishedPeerPolicy, ManagedTorrent, ManagedTorrentState, PeerLimits,
+    TorrentStats, TorrentStatsState, TransferTotals, TunableOptions,
+};
+pub use tracker_comms::AnnounceOptions;
+pub use transmission_import::TransmissionImportedTorrent;
 
 pub use buffers::*;
 pub use clone_to_owned::CloneToOwned;
 pub use librqbit_core::magnet::*;
 pub use librqbit_core::peer_id::*;
+pub use librqbit_core::torrent_editor::TorrentEditor;
 pub use librqbit_core::torrent_metainfo::*;
 
 #[cfg(test)]
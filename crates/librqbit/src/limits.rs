@@ -0,0 +1,532 @@
+// Bandwidth and connection limits that can be shared by several torrents, and changed while
+// they are running.
+
+use std::{
+    collections::HashMap,
+    net::SocketAddr,
+    sync::{
+        atomic::{AtomicBool, AtomicUsize, Ordering},
+        Arc, Weak,
+    },
+    time::{Duration, Instant},
+};
+
+use parking_lot::Mutex;
+use tokio::sync::{Notify, OwnedSemaphorePermit, Semaphore};
+
+struct Bucket {
+    bytes_per_sec: Option<u64>,
+    // Can go negative, the next caller waits until it's paid back.
+    tokens: f64,
+    last_refill: Instant,
+}
+
+/// A token bucket. Allows bursts of up to a second worth of bytes.
+pub(crate) struct RateLimiter {
+    bucket: Mutex<Bucket>,
+}
+
+impl RateLimiter {
+    pub fn new(bytes_per_sec: Option<u64>) -> Self {
+        Self {
+            bucket: Mutex::new(Bucket {
+                bytes_per_sec,
+                tokens: bytes_per_sec.unwrap_or_default() as f64,
+                last_refill: Instant::now(),
+            }),
+        }
+    }
+
+    pub fn set_limit(&self, bytes_per_sec: Option<u64>) {
+        let mut g = self.bucket.lock();
+        g.bytes_per_sec = bytes_per_sec;
+        g.tokens = g.tokens.min(bytes_per_sec.unwrap_or_default() as f64);
+    }
+
+    // How long to wait before sending "bytes".
+    fn reserve(&self, bytes: u64, now: Instant) -> Option<Duration> {
+        let mut g = self.bucket.lock();
+        let bps = g.bytes_per_sec? as f64;
+        let elapsed = now.saturating_duration_since(g.last_refill).as_secs_f64();
+        g.tokens = (g.tokens + elapsed * bps).min(bps);
+        g.last_refill = now;
+        g.tokens -= bytes as f64;
+        if g.tokens >= 0. {
+            return None;
+        }
+        Some(Duration::from_secs_f64(-g.tokens / bps.max(1.)))
+    }
+
+    /// Wait until "bytes" can be sent or received without exceeding the limit.
+    pub async fn acquire(&self, bytes: u64) {
+        if let Some(wait) = self.reserve(bytes, Instant::now()) {
+            tokio::time::sleep(wait).await;
+        }
+    }
+
+    /// Like [`RateLimiter::acquire`], for code that runs on blocking threads, e.g. disk writes.
+    pub fn acquire_blocking(&self, bytes: u64) {
+        if let Some(wait) = self.reserve(bytes, Instant::now()) {
+            std::thread::sleep(wait);
+        }
+    }
+}
+
+/// Upload no more than a multiple of what was downloaded, e.g. to keep the ratio under control
+/// on metered upload links. Applies while the torrent is downloading.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct UploadCoupling {
+    /// Bytes that can be uploaded for every downloaded byte.
+    pub ratio: f64,
+    /// Count what each peer sent us separately, and upload to it in proportion, tit-for-tat.
+    /// Otherwise all peers share what the torrent downloaded.
+    pub per_peer: bool,
+}
+
+impl std::fmt::Display for UploadCoupling {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.ratio)?;
+        if self.per_peer {
+            f.write_str(":peer")?;
+        }
+        Ok(())
+    }
+}
+
+impl std::str::FromStr for UploadCoupling {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (ratio, per_peer) = match s.strip_suffix(":peer") {
+            Some(ratio) => (ratio, true),
+            None => (s, false),
+        };
+        match ratio.parse::<f64>() {
+            Ok(ratio) if ratio.is_finite() && ratio >= 0. => Ok(Self { ratio, per_peer }),
+            _ => anyhow::bail!(
+                "invalid upload coupling {s:?}, expected a ratio, optionally followed by \":peer\""
+            ),
+        }
+    }
+}
+
+impl serde::Serialize for UploadCoupling {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+impl<'de> serde::Deserialize<'de> for UploadCoupling {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        use serde::de::Error;
+        String::deserialize(deserializer)?
+            .parse()
+            .map_err(D::Error::custom)
+    }
+}
+
+// Credit that isn't used can't pile up beyond this, so that uploading doesn't burst after a
+// long time of only downloading.
+const MAX_UPLOAD_CREDIT: f64 = 4. * 1024. * 1024.;
+
+/// Upload credit earned by downloading, see [`UploadCoupling`].
+pub(crate) struct UploadCredit {
+    coupling: UploadCoupling,
+    // Keyed by peer, or None for the whole torrent.
+    credit: Mutex<HashMap<Option<SocketAddr>, f64>>,
+    // Uploads aren't limited when not active, e.g. once the torrent is finished.
+    active: AtomicBool,
+    earned: Notify,
+}
+
+impl UploadCredit {
+    pub fn new(coupling: UploadCoupling, active: bool) -> Self {
+        Self {
+            coupling,
+            credit: Default::default(),
+            active: AtomicBool::new(active),
+            earned: Notify::new(),
+        }
+    }
+
+    fn key(&self, peer: SocketAddr) -> Option<SocketAddr> {
+        self.coupling.per_peer.then_some(peer)
+    }
+
+    pub fn set_active(&self, active: bool) {
+        self.active.store(active, Ordering::Relaxed);
+        self.earned.notify_waiters();
+    }
+
+    pub fn on_downloaded(&self, peer: SocketAddr, bytes: u64) {
+        {
+            let mut g = self.credit.lock();
+            let credit = g.entry(self.key(peer)).or_default();
+            *credit = (*credit + bytes as f64 * self.coupling.ratio).min(MAX_UPLOAD_CREDIT);
+        }
+        self.earned.notify_waiters();
+    }
+
+    pub fn try_acquire(&self, peer: SocketAddr, bytes: u64) -> bool {
+        if !self.active.load(Ordering::Relaxed) {
+            return true;
+        }
+        let mut g = self.credit.lock();
+        let credit = g.entry(self.key(peer)).or_default();
+        if *credit < bytes as f64 {
+            return false;
+        }
+        *credit -= bytes as f64;
+        true
+    }
+
+    /// Wait until enough was downloaded to upload "bytes" to the peer. Cancel safe.
+    pub async fn acquire(&self, peer: SocketAddr, bytes: u64) {
+        loop {
+            let earned = self.earned.notified();
+            if self.try_acquire(peer, bytes) {
+                return;
+            }
+            earned.await;
+        }
+    }
+
+    pub fn forget_peer(&self, peer: SocketAddr) {
+        if self.coupling.per_peer {
+            self.credit.lock().remove(&Some(peer));
+        }
+    }
+}
+
+pub(crate) struct Limits {
+    pub download: RateLimiter,
+    pub upload: RateLimiter,
+    // Per torrent. 0 means no limit.
+    max_peers: AtomicUsize,
+}
+
+impl Default for RateLimiter {
+    fn default() -> Self {
+        Self::new(None)
+    }
+}
+
+impl Default for Limits {
+    fn default() -> Self {
+        Self {
+            download: Default::default(),
+            upload: Default::default(),
+            max_peers: AtomicUsize::new(0),
+        }
+    }
+}
+
+impl Limits {
+    pub fn max_peers(&self) -> Option<usize> {
+        match self.max_peers.load(Ordering::Relaxed) {
+            0 => None,
+            v => Some(v),
+        }
+    }
+
+    pub fn set_max_peers(&self, max_peers: Option<usize>) {
+        self.max_peers
+            .store(max_peers.unwrap_or_default(), Ordering::Relaxed);
+    }
+}
+
+/// Connections to peers of all the torrents of a session together, to stay within the sockets
+/// and file descriptors there are. Each live torrent has an equal share of it. Torrents can go
+/// above their share while there's room, but while another torrent is starved below its share,
+/// they don't get more connections, and shed the ones above their share.
+pub(crate) struct ConnectionBudget {
+    max: Option<usize>,
+    used: AtomicUsize,
+    shares: Mutex<Vec<Weak<ConnectionShare>>>,
+}
+
+impl ConnectionBudget {
+    pub fn new(max: Option<usize>) -> Arc<Self> {
+        Arc::new(Self {
+            max,
+            used: Default::default(),
+            shares: Default::default(),
+        })
+    }
+
+    /// The share of a live torrent, for as long as it's kept.
+    pub fn share(self: &Arc<Self>) -> Arc<ConnectionShare> {
+        let share = Arc::new(ConnectionShare {
+            budget: self.clone(),
+            used: Default::default(),
+            starved: Default::default(),
+        });
+        let mut g = self.shares.lock();
+        g.retain(|s| s.strong_count() > 0);
+        g.push(Arc::downgrade(&share));
+        share
+    }
+
+    fn fair_share(&self, max: usize) -> usize {
+        let torrents = self
+            .shares
+            .lock()
+            .iter()
+            .filter(|s| s.strong_count() > 0)
+            .count();
+        (max / torrents.max(1)).max(1)
+    }
+
+    // Is a torrent other than "except" starved below its share.
+    fn someone_starved(&self, except: &ConnectionShare, max: usize) -> bool {
+        let fair_share = self.fair_share(max);
+        self.shares
+            .lock()
+            .iter()
+            .filter_map(|s| s.upgrade())
+            .any(|s| {
+                !std::ptr::eq(&*s, except)
+                    && s.starved.load(Ordering::Relaxed)
+                    && s.used.load(Ordering::Relaxed) < fair_share
+            })
+    }
+
+    fn try_take(&self, max: usize) -> bool {
+        self.used
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |used| {
+                (used < max).then_some(used + 1)
+            })
+            .is_ok()
+    }
+}
+
+pub(crate) struct ConnectionShare {
+    budget: Arc<ConnectionBudget>,
+    used: AtomicUsize,
+    // Couldn't dial for lack of budget while below its share. Cleared once it gets a connection.
+    starved: AtomicBool,
+}
+
+impl ConnectionShare {
+    /// A connection out of the budget for dialing a peer, held for as long as the peer is
+    /// connecting or live. Without one, the torrent is starved until it gets one, so callers
+    /// must keep retrying.
+    pub fn try_acquire(self: &Arc<Self>) -> Option<ConnectionPermit> {
+        self.try_acquire_impl(true)
+    }
+
+    /// Like [`Self::try_acquire`] for a peer that connected to us. Not getting one doesn't make
+    /// the torrent starved, as nothing retries it.
+    pub fn try_acquire_incoming(self: &Arc<Self>) -> Option<ConnectionPermit> {
+        self.try_acquire_impl(false)
+    }
+
+    fn try_acquire_impl(self: &Arc<Self>, mark_starved: bool) -> Option<ConnectionPermit> {
+        let budget = &self.budget;
+        if let Some(max) = budget.max {
+            let below_share = self.used.load(Ordering::Relaxed) < budget.fair_share(max);
+            if !below_share && budget.someone_starved(self, max) {
+                return None;
+            }
+            if !budget.try_take(max) {
+                if below_share && mark_starved {
+                    self.starved.store(true, Ordering::Relaxed);
+                }
+                return None;
+            }
+        }
+        self.used.fetch_add(1, Ordering::Relaxed);
+        self.starved.store(false, Ordering::Relaxed);
+        Some(ConnectionPermit {
+            share: self.clone(),
+        })
+    }
+
+    /// How many connections to shed for a starved torrent.
+    pub fn excess(&self) -> usize {
+        let budget = &self.budget;
+        let Some(max) = budget.max else {
+            return 0;
+        };
+        if !budget.someone_starved(self, max) {
+            return 0;
+        }
+        self.used
+            .load(Ordering::Relaxed)
+            .saturating_sub(budget.fair_share(max))
+    }
+}
+
+pub(crate) struct ConnectionPermit {
+    share: Arc<ConnectionShare>,
+}
+
+impl Drop for ConnectionPermit {
+    fn drop(&mut self) {
+        self.share.used.fetch_sub(1, Ordering::Relaxed);
+        if self.share.budget.max.is_some() {
+            self.share.budget.used.fetch_sub(1, Ordering::Relaxed);
+        }
+    }
+}
+
+/// Outgoing connections still being established (half-open), of all the torrents together.
+/// Too many at once overflow the NAT tables of consumer routers, and trip the connection limits
+/// of some OSes. Dials are also paced, so that they don't all start at the same moment.
+pub(crate) struct DialLimiter {
+    // None if not limited.
+    half_open: Option<Arc<Semaphore>>,
+    // Between the starts of two dials.
+    interval: Option<Duration>,
+    next_dial: Mutex<Instant>,
+}
+
+/// Held from the start of a dial until the connection is established or fails.
+pub(crate) struct DialPermit {
+    _permit: Option<OwnedSemaphorePermit>,
+}
+
+impl DialLimiter {
+    /// 0 doesn't limit.
+    pub fn new(max_half_open: usize, dials_per_second: u32) -> Self {
+        Self {
+            half_open: (max_half_open > 0).then(|| Arc::new(Semaphore::new(max_half_open))),
+            interval: (dials_per_second > 0).then(|| Duration::from_secs(1) / dials_per_second),
+            next_dial: Mutex::new(Instant::now()),
+        }
+    }
+
+    // When the next dial can start.
+    fn reserve(&self, now: Instant) -> Option<Instant> {
+        let interval = self.interval?;
+        let mut next = self.next_dial.lock();
+        let at = (*next).max(now);
+        *next = at + interval;
+        Some(at)
+    }
+
+    pub async fn acquire(&self) -> anyhow::Result<DialPermit> {
+        let permit = match &self.half_open {
+            Some(s) => Some(s.clone().acquire_owned().await?),
+            None => None,
+        };
+        if let Some(at) = self.reserve(Instant::now()) {
+            tokio::time::sleep_until(at.into()).await;
+        }
+        Ok(DialPermit { _permit: permit })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, Instant};
+
+    use super::{ConnectionBudget, DialLimiter, RateLimiter, UploadCoupling, UploadCredit};
+
+    #[test]
+    fn test_rate_limiter() {
+        let l = RateLimiter::new(Some(1000));
+        let now = Instant::now();
+        // The initial burst is allowed.
+        assert_eq!(l.reserve(1000, now), None);
+        // Then it has to wait.
+        let wait = l.reserve(500, now).unwrap();
+        assert!((wait.as_secs_f64() - 0.5).abs() < 0.01, "{wait:?}");
+        // After waiting, the debt is paid back.
+        assert_eq!(l.reserve(100, now + Duration::from_secs_f64(0.6)), None);
+
+        let unlimited = RateLimiter::new(None);
+        assert_eq!(unlimited.reserve(u32::MAX as u64, now), None);
+    }
+
+    #[test]
+    fn test_upload_credit() {
+        let a = "127.0.0.1:1".parse().unwrap();
+        let b = "127.0.0.1:2".parse().unwrap();
+
+        let per_peer = UploadCredit::new("0.5:peer".parse().unwrap(), true);
+        assert!(!per_peer.try_acquire(a, 1));
+        per_peer.on_downloaded(a, 1000);
+        assert!(per_peer.try_acquire(a, 500));
+        assert!(!per_peer.try_acquire(a, 1));
+        // Other peers didn't send anything.
+        per_peer.on_downloaded(a, 1000);
+        assert!(!per_peer.try_acquire(b, 1));
+        // Not limited once inactive.
+        per_peer.set_active(false);
+        assert!(per_peer.try_acquire(b, 1000));
+
+        let torrent = UploadCredit::new(
+            UploadCoupling {
+                ratio: 2.,
+                per_peer: false,
+            },
+            true,
+        );
+        torrent.on_downloaded(a, 1000);
+        assert!(torrent.try_acquire(b, 1500));
+        assert!(!torrent.try_acquire(a, 1000));
+        assert_eq!(
+            "2:peer".parse::<UploadCoupling>().unwrap().to_string(),
+            "2:peer"
+        );
+        assert!("-1".parse::<UploadCoupling>().is_err());
+    }
+
+    #[test]
+    fn test_connection_budget() {
+        let budget = ConnectionBudget::new(Some(4));
+        let a = budget.share();
+        // Alone, a torrent can use the whole budget.
+        let mut a_permits = (0..4).map(|_| a.try_acquire().unwrap()).collect::<Vec<_>>();
+        assert!(a.try_acquire().is_none());
+        assert_eq!(a.excess(), 0);
+
+        // Incoming peers that don't fit don't starve a torrent, nothing would clear it.
+        let b = budget.share();
+        assert!(b.try_acquire_incoming().is_none());
+        assert_eq!(a.excess(), 0);
+
+        // Another torrent is starved below its share of 2, so "a" sheds its extra ones, and
+        // doesn't get them back.
+        assert!(b.try_acquire().is_none());
+        assert_eq!(a.excess(), 2);
+        a_permits.truncate(2);
+        assert!(a.try_acquire().is_none());
+        let mut b_permits = (0..2).map(|_| b.try_acquire().unwrap()).collect::<Vec<_>>();
+        assert_eq!(a.excess(), 0);
+
+        // Getting a connection clears it, so "a" can use what "b" doesn't need any more.
+        b_permits.pop();
+        a_permits.push(a.try_acquire().unwrap());
+        assert_eq!(a.excess(), 0);
+
+        // Shares grow back when torrents go away.
+        drop(b_permits);
+        drop(b);
+        a_permits.push(a.try_acquire().unwrap());
+        assert_eq!(a_permits.len(), 4);
+        assert!(a.try_acquire().is_none());
+
+        let unlimited = ConnectionBudget::new(None).share();
+        let permits = (0..1000)
+            .map(|_| unlimited.try_acquire().unwrap())
+            .collect::<Vec<_>>();
+        assert_eq!(permits.len(), 1000);
+    }
+
+    #[test]
+    fn test_dial_pacing() {
+        let l = DialLimiter::new(0, 10);
+        let now = Instant::now();
+        let step = Duration::from_millis(100);
+        assert_eq!(l.reserve(now), Some(now));
+        assert_eq!(l.reserve(now), Some(now + step));
+        assert_eq!(l.reserve(now), Some(now + step * 2));
+        // Idle time isn't saved up for bursts.
+        let later = now + Duration::from_secs(10);
+        assert_eq!(l.reserve(later), Some(later));
+
+        assert_eq!(DialLimiter::new(0, 0).reserve(now), None);
+    }
+}
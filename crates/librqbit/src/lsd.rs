@@ -0,0 +1,151 @@
+// BEP 14 Local Service Discovery: announcing and discovering torrents on the local network
+// through UDP multicast.
+
+use std::net::{Ipv4Addr, SocketAddr, SocketAddrV4};
+use std::str::FromStr;
+
+use anyhow::Context;
+use librqbit_core::hash_id::Id20;
+use tokio::net::UdpSocket;
+use tracing::{debug, trace};
+
+const LSD_IPV4: Ipv4Addr = Ipv4Addr::new(239, 192, 152, 143);
+const LSD_PORT: u16 = 6771;
+
+/// A peer on the local network announced that it has a torrent.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct LsdAnnouncement {
+    pub info_hash: Id20,
+    pub addr: SocketAddr,
+}
+
+fn format_announce(info_hash: Id20, port: u16, cookie: &str) -> String {
+    format!(
+        "BT-SEARCH * HTTP/1.1\r\nHost: {LSD_IPV4}:{LSD_PORT}\r\nPort: {port}\r\nInfohash: {}\r\ncookie: {cookie}\r\n\r\n\r\n",
+        info_hash.as_string()
+    )
+}
+
+// Returns the announced info hashes and port, unless it's our own message.
+fn parse_announce(buf: &[u8], own_cookie: &str) -> Option<(Vec<Id20>, u16)> {
+    let msg = std::str::from_utf8(buf).ok()?;
+    let mut lines = msg.split("\r\n");
+    if lines.next()? != "BT-SEARCH * HTTP/1.1" {
+        return None;
+    }
+    let mut port = None;
+    let mut info_hashes = Vec::new();
+    for line in lines {
+        let (name, value) = match line.split_once(':') {
+            Some(h) => h,
+            None => continue,
+        };
+        let value = value.trim();
+        match name.trim().to_ascii_lowercase().as_str() {
+            "port" => port = value.parse().ok(),
+            "infohash" => info_hashes.extend(Id20::from_str(value).ok()),
+            "cookie" if value == own_cookie => return None,
+            _ => {}
+        }
+    }
+    if info_hashes.is_empty() {
+        return None;
+    }
+    Some((info_hashes, port?))
+}
+
+pub struct Lsd {
+    socket: UdpSocket,
+    cookie: String,
+}
+
+impl Lsd {
+    /// Join the LSD multicast group. Several processes on the same machine may do this at a time.
+    pub fn new() -> anyhow::Result<Self> {
+        use socket2::{Domain, Protocol, Socket, Type};
+
+        let socket = Socket::new(Domain::IPV4, Type::DGRAM, Some(Protocol::UDP))
+            .context("error creating UDP socket")?;
+        socket
+            .set_reuse_address(true)
+            .context("error setting SO_REUSEADDR")?;
+        socket.set_nonblocking(true)?;
+        socket
+            .bind(&SocketAddr::from((Ipv4Addr::UNSPECIFIED, LSD_PORT)).into())
+            .with_context(|| format!("error binding to port {LSD_PORT}"))?;
+        socket
+            .join_multicast_v4(&LSD_IPV4, &Ipv4Addr::UNSPECIFIED)
+            .context("error joining LSD multicast group")?;
+        socket.set_multicast_loop_v4(true)?;
+        let socket = UdpSocket::from_std(socket.into())?;
+        Ok(Self {
+            socket,
+            cookie: format!("rqbit-{:x}", rand::random::<u64>()),
+        })
+    }
+
+    /// Announce that we have the torrent, and are listening for peer connections on "port".
+    pub async fn announce(&self, info_hash: Id20, port: u16) -> anyhow::Result<()> {
+        let msg = format_announce(info_hash, port, &self.cookie);
+        self.socket
+            .send_to(msg.as_bytes(), SocketAddrV4::new(LSD_IPV4, LSD_PORT))
+            .await
+            .context("error sending LSD announce")?;
+        trace!(?info_hash, port, "sent LSD announce");
+        Ok(())
+    }
+
+    /// Wait for the next announcement from another process.
+    pub async fn recv(&self) -> anyhow::Result<Vec<LsdAnnouncement>> {
+        let mut buf = [0u8; 1500];
+        loop {
+            let (size, from) = self
+                .socket
+                .recv_from(&mut buf)
+                .await
+                .context("error receiving from LSD socket")?;
+            match parse_announce(&buf[..size], &self.cookie) {
+                Some((info_hashes, port)) => {
+                    return Ok(info_hashes
+                        .into_iter()
+                        .map(|info_hash| LsdAnnouncement {
+                            info_hash,
+                            addr: SocketAddr::new(from.ip(), port),
+                        })
+                        .collect())
+                }
+                None => debug!(%from, "ignoring LSD message"),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::str::FromStr;
+
+    use librqbit_core::hash_id::Id20;
+
+    use super::{format_announce, parse_announce};
+
+    #[test]
+    fn test_announce_roundtrip() {
+        let info_hash = Id20::from_str("cab507494d02ebb1178b38f2e9d7be299c86b862").unwrap();
+        let msg = format_announce(info_hash, 4240, "a");
+        assert_eq!(
+            parse_announce(msg.as_bytes(), "b"),
+            Some((vec![info_hash], 4240))
+        );
+        // Our own message.
+        assert_eq!(parse_announce(msg.as_bytes(), "a"), None);
+    }
+
+    #[test]
+    fn test_parse_announce_multiple_hashes() {
+        let msg = b"BT-SEARCH * HTTP/1.1\r\nhost: 239.192.152.143:6771\r\nport: 6881\r\ninfohash: CAB507494D02EBB1178B38F2E9D7BE299C86B862\r\ninfohash: 0000000000000000000000000000000000000000\r\n\r\n\r\n";
+        let (hashes, port) = parse_announce(msg, "x").unwrap();
+        assert_eq!(port, 6881);
+        assert_eq!(hashes.len(), 2);
+        assert!(parse_announce(b"M-SEARCH * HTTP/1.1\r\n\r\n", "x").is_none());
+    }
+}
@@ -0,0 +1,390 @@
+// The files of a torrent are only opened, created, renamed and removed through the handle of
+// its output folder. Paths are resolved one component at a time, refusing symlinks, ".." and
+// anything absolute, so whatever a path ends up as (a strange metainfo, a bug when moving or
+// renaming files), it can't point outside of the folder.
+//
+// On unix the folder is opened once, and everything is resolved with openat() and friends
+// relative to it, so replacing the folders of a running torrent with symlinks doesn't escape it
+// either. Elsewhere the same checks are done on the paths before using them.
+
+use std::{
+    fs::File,
+    path::{Component, Path, PathBuf},
+};
+
+use anyhow::Context;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum OpenMode {
+    Read,
+    ReadWrite,
+    // Read-write, creating the file and its folders if needed.
+    Create,
+    // Like Create, but fails if the file exists.
+    CreateNew,
+}
+
+impl OpenMode {
+    fn creates(self) -> bool {
+        matches!(self, OpenMode::Create | OpenMode::CreateNew)
+    }
+}
+
+#[derive(Debug)]
+pub(crate) struct OutputDir {
+    root: PathBuf,
+    // Opened (and created) on first use.
+    #[cfg(unix)]
+    handle: std::sync::OnceLock<std::os::fd::OwnedFd>,
+}
+
+impl OutputDir {
+    pub fn new(root: PathBuf) -> Self {
+        Self {
+            root,
+            #[cfg(unix)]
+            handle: Default::default(),
+        }
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.root
+    }
+
+    // "path" is either relative to the folder, or starts with it.
+    fn relative<'a>(&self, path: &'a Path) -> anyhow::Result<&'a Path> {
+        let rel = if path.is_absolute() || path.starts_with(&self.root) {
+            path.strip_prefix(&self.root)
+                .with_context(|| format!("{:?} is outside of {:?}", path, self.root))?
+        } else {
+            path
+        };
+        if rel.as_os_str().is_empty() {
+            anyhow::bail!("{:?} is not a file in {:?}", path, self.root);
+        }
+        if let Some(c) = rel
+            .components()
+            .find(|c| !matches!(c, Component::Normal(_)))
+        {
+            anyhow::bail!("{:?} in {:?} is not allowed", c, path);
+        }
+        Ok(rel)
+    }
+
+    pub fn open(&self, path: &Path, mode: OpenMode) -> anyhow::Result<File> {
+        let rel = self.relative(path)?;
+        imp::open(self, rel, mode).with_context(|| format!("error opening {:?}", path))
+    }
+
+    // Doesn't follow symlinks, i.e. true for a symlink even if it points nowhere.
+    pub fn exists(&self, path: &Path) -> bool {
+        match self.relative(path) {
+            Ok(rel) => imp::exists(self, rel),
+            Err(_) => false,
+        }
+    }
+
+    pub fn remove_file(&self, path: &Path) -> anyhow::Result<()> {
+        let rel = self.relative(path)?;
+        imp::remove_file(self, rel).with_context(|| format!("error removing {:?}", path))
+    }
+
+    // Only removes empty folders.
+    pub fn remove_dir(&self, path: &Path) -> anyhow::Result<()> {
+        let rel = self.relative(path)?;
+        imp::remove_dir(self, rel).with_context(|| format!("error removing {:?}", path))
+    }
+
+    // Rename "from" in this folder to "to" in "to_dir", which may be the same folder. Folders
+    // of "to" are created. Fails (e.g. with EXDEV) if they are on different filesystems.
+    pub fn rename(&self, from: &Path, to_dir: &OutputDir, to: &Path) -> anyhow::Result<()> {
+        let from_rel = self.relative(from)?;
+        let to_rel = to_dir.relative(to)?;
+        imp::rename(self, from_rel, to_dir, to_rel)
+            .with_context(|| format!("error renaming {:?} to {:?}", from, to))
+    }
+}
+
+#[cfg(unix)]
+mod imp {
+    use std::{
+        ffi::{CString, OsStr},
+        fs::File,
+        io,
+        os::{
+            fd::{AsRawFd, FromRawFd, OwnedFd, RawFd},
+            unix::ffi::OsStrExt,
+        },
+        path::Path,
+    };
+
+    use super::{OpenMode, OutputDir};
+
+    fn cstr(name: &OsStr) -> io::Result<CString> {
+        CString::new(name.as_bytes()).map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))
+    }
+
+    fn check(ret: libc::c_int) -> io::Result<libc::c_int> {
+        if ret < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(ret)
+    }
+
+    fn root(dir: &OutputDir) -> io::Result<RawFd> {
+        if let Some(fd) = dir.handle.get() {
+            return Ok(fd.as_raw_fd());
+        }
+        std::fs::create_dir_all(&dir.root)?;
+        let fd = OwnedFd::from(File::open(&dir.root)?);
+        // Another thread may have opened it in the meantime, that one is kept.
+        let _ = dir.handle.set(fd);
+        Ok(dir.handle.get().unwrap().as_raw_fd())
+    }
+
+    // The folder "rel" is in, and its file name. None is the root.
+    fn open_parent<'a>(
+        dir: &OutputDir,
+        rel: &'a Path,
+        create_dirs: bool,
+    ) -> io::Result<(Option<OwnedFd>, &'a OsStr)> {
+        let root = root(dir)?;
+        let mut components = rel.iter().collect::<Vec<_>>();
+        let name = components.pop().unwrap();
+        let mut parent: Option<OwnedFd> = None;
+        for component in components {
+            let name = cstr(component)?;
+            let at = parent.as_ref().map_or(root, |p| p.as_raw_fd());
+            if create_dirs {
+                // SAFETY: "at" is an open folder and "name" a NUL-terminated string, both
+                // outlive the call.
+                if let Err(e) = check(unsafe { libc::mkdirat(at, name.as_ptr(), 0o777) }) {
+                    if e.kind() != io::ErrorKind::AlreadyExists {
+                        return Err(e);
+                    }
+                }
+            }
+            // SAFETY: as above.
+            let fd = check(unsafe {
+                libc::openat(
+                    at,
+                    name.as_ptr(),
+                    libc::O_RDONLY | libc::O_DIRECTORY | libc::O_NOFOLLOW | libc::O_CLOEXEC,
+                )
+            })?;
+            // SAFETY: openat() succeeded, so "fd" is a new descriptor nothing else owns.
+            parent = Some(unsafe { OwnedFd::from_raw_fd(fd) });
+        }
+        Ok((parent, name))
+    }
+
+    fn at(dir: &OutputDir, parent: &Option<OwnedFd>) -> io::Result<RawFd> {
+        match parent {
+            Some(p) => Ok(p.as_raw_fd()),
+            None => root(dir),
+        }
+    }
+
+    pub fn open(dir: &OutputDir, rel: &Path, mode: OpenMode) -> io::Result<File> {
+        let (parent, name) = open_parent(dir, rel, mode.creates())?;
+        let name = cstr(name)?;
+        let flags = match mode {
+            OpenMode::Read => libc::O_RDONLY,
+            OpenMode::ReadWrite => libc::O_RDWR,
+            OpenMode::Create => libc::O_RDWR | libc::O_CREAT,
+            OpenMode::CreateNew => libc::O_RDWR | libc::O_CREAT | libc::O_EXCL,
+        };
+        // SAFETY: the folder descriptor is kept open by "parent" or "dir", and "name" is a
+        // NUL-terminated string, both outlive the call.
+        let fd = check(unsafe {
+            libc::openat(
+                at(dir, &parent)?,
+                name.as_ptr(),
+                flags | libc::O_NOFOLLOW | libc::O_CLOEXEC,
+                0o666 as libc::c_uint,
+            )
+        })?;
+        // SAFETY: openat() succeeded, so "fd" is a new descriptor nothing else owns.
+        Ok(File::from(unsafe { OwnedFd::from_raw_fd(fd) }))
+    }
+
+    pub fn exists(dir: &OutputDir, rel: &Path) -> bool {
+        let Ok((parent, name)) = open_parent(dir, rel, false) else {
+            return false;
+        };
+        let (Ok(at), Ok(name)) = (at(dir, &parent), cstr(name)) else {
+            return false;
+        };
+        let mut stat = std::mem::MaybeUninit::<libc::stat>::uninit();
+        // SAFETY: as in open(), and "stat" is valid for writes. It's not read, so it may stay
+        // uninitialized if the call fails.
+        unsafe {
+            libc::fstatat(
+                at,
+                name.as_ptr(),
+                stat.as_mut_ptr(),
+                libc::AT_SYMLINK_NOFOLLOW,
+            ) == 0
+        }
+    }
+
+    pub fn remove_file(dir: &OutputDir, rel: &Path) -> io::Result<()> {
+        let (parent, name) = open_parent(dir, rel, false)?;
+        let name = cstr(name)?;
+        // SAFETY: as in open().
+        check(unsafe { libc::unlinkat(at(dir, &parent)?, name.as_ptr(), 0) })?;
+        Ok(())
+    }
+
+    pub fn remove_dir(dir: &OutputDir, rel: &Path) -> io::Result<()> {
+        let (parent, name) = open_parent(dir, rel, false)?;
+        let name = cstr(name)?;
+        // SAFETY: as in open().
+        check(unsafe { libc::unlinkat(at(dir, &parent)?, name.as_ptr(), libc::AT_REMOVEDIR) })?;
+        Ok(())
+    }
+
+    pub fn rename(
+        from_dir: &OutputDir,
+        from: &Path,
+        to_dir: &OutputDir,
+        to: &Path,
+    ) -> io::Result<()> {
+        let (from_parent, from_name) = open_parent(from_dir, from, false)?;
+        let (to_parent, to_name) = open_parent(to_dir, to, true)?;
+        let (from_name, to_name) = (cstr(from_name)?, cstr(to_name)?);
+        // SAFETY: as in open(), for both folders and names.
+        check(unsafe {
+            libc::renameat(
+                at(from_dir, &from_parent)?,
+                from_name.as_ptr(),
+                at(to_dir, &to_parent)?,
+                to_name.as_ptr(),
+            )
+        })?;
+        Ok(())
+    }
+}
+
+#[cfg(not(unix))]
+mod imp {
+    use std::{
+        fs::{File, OpenOptions},
+        io,
+        path::{Path, PathBuf},
+    };
+
+    use super::{OpenMode, OutputDir};
+
+    // Join "rel" to the folder, refusing symlinks on the way.
+    fn resolve(dir: &OutputDir, rel: &Path, create_dirs: bool) -> io::Result<PathBuf> {
+        let mut path = dir.root.clone();
+        let components = rel.iter().collect::<Vec<_>>();
+        for (idx, component) in components.iter().enumerate() {
+            path.push(component);
+            let is_last = idx + 1 == components.len();
+            match std::fs::symlink_metadata(&path) {
+                Ok(m) if m.file_type().is_symlink() => {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidInput,
+                        format!("{:?} is a symlink", path),
+                    ))
+                }
+                Ok(_) => {}
+                Err(e) if e.kind() == io::ErrorKind::NotFound && create_dirs && !is_last => {
+                    std::fs::create_dir(&path)?
+                }
+                Err(_) => {}
+            }
+        }
+        Ok(path)
+    }
+
+    pub fn open(dir: &OutputDir, rel: &Path, mode: OpenMode) -> io::Result<File> {
+        if mode.creates() {
+            std::fs::create_dir_all(&dir.root)?;
+        }
+        let path = resolve(dir, rel, mode.creates())?;
+        let mut opts = OpenOptions::new();
+        opts.read(true).write(mode != OpenMode::Read);
+        match mode {
+            OpenMode::Create => opts.create(true),
+            OpenMode::CreateNew => opts.create_new(true),
+            OpenMode::Read | OpenMode::ReadWrite => &mut opts,
+        };
+        opts.open(path)
+    }
+
+    pub fn exists(dir: &OutputDir, rel: &Path) -> bool {
+        resolve(dir, rel, false)
+            .and_then(std::fs::symlink_metadata)
+            .is_ok()
+    }
+
+    pub fn remove_file(dir: &OutputDir, rel: &Path) -> io::Result<()> {
+        std::fs::remove_file(resolve(dir, rel, false)?)
+    }
+
+    pub fn remove_dir(dir: &OutputDir, rel: &Path) -> io::Result<()> {
+        std::fs::remove_dir(resolve(dir, rel, false)?)
+    }
+
+    pub fn rename(
+        from_dir: &OutputDir,
+        from: &Path,
+        to_dir: &OutputDir,
+        to: &Path,
+    ) -> io::Result<()> {
+        std::fs::create_dir_all(&to_dir.root)?;
+        std::fs::rename(resolve(from_dir, from, false)?, resolve(to_dir, to, true)?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{io::Write, path::Path};
+
+    use super::{OpenMode, OutputDir};
+
+    #[test]
+    fn test_output_dir_stays_inside() {
+        let tmp = tempfile::TempDir::new().unwrap();
+        let dir = OutputDir::new(tmp.path().join("out"));
+
+        let path = tmp.path().join("out/a/b.txt");
+        dir.open(&path, OpenMode::CreateNew)
+            .unwrap()
+            .write_all(b"hello")
+            .unwrap();
+        assert!(dir.exists(Path::new("a/b.txt")));
+        assert!(dir.open(&path, OpenMode::CreateNew).is_err());
+        assert_eq!(std::fs::read(&path).unwrap(), b"hello");
+
+        dir.rename(&path, &dir, Path::new("c/d.txt")).unwrap();
+        assert!(!dir.exists(&path));
+        dir.open(Path::new("c/d.txt"), OpenMode::Read).unwrap();
+
+        for bad in [
+            tmp.path().join("outside.txt"),
+            "../outside.txt".into(),
+            "a/../../outside.txt".into(),
+            tmp.path().join("out"),
+        ] {
+            assert!(dir.open(&bad, OpenMode::Create).is_err(), "{:?}", bad);
+        }
+        assert!(!tmp.path().join("outside.txt").exists());
+
+        #[cfg(unix)]
+        {
+            std::os::unix::fs::symlink(tmp.path(), tmp.path().join("out/link")).unwrap();
+            assert!(dir.open(Path::new("link/x.txt"), OpenMode::Create).is_err());
+            assert!(!tmp.path().join("x.txt").exists());
+        }
+
+        dir.remove_file(Path::new("c/d.txt")).unwrap();
+        assert!(!dir.exists(Path::new("c/d.txt")));
+        dir.remove_dir(Path::new("c")).unwrap();
+        assert!(!dir.exists(Path::new("c")));
+        assert!(dir.remove_dir(tmp.path()).is_err());
+    }
+}
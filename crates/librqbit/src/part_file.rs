@@ -0,0 +1,208 @@
+// Files that aren't downloaded can still share pieces with the ones that are. Their bytes in
+// these pieces are needed to check and upload the pieces, and are kept in a parts file next to
+// the torrent's files, instead of creating the unwanted files.
+//
+// Only the first and the last piece of a file can be shared with other files. The parts file
+// has room for the bytes of every file in these pieces, in file order, so that where a byte is
+// stored doesn't depend on which files are selected.
+//
+// A file that isn't downloaded is opened on the parts file, so that it's read, written, moved
+// and reopened like the other files, and only the offsets differ.
+
+use std::{
+    fs::File,
+    io::{Seek, SeekFrom},
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+};
+
+use anyhow::Context;
+use buffers::ByteString;
+use librqbit_core::{hash_id::Id20, lengths::Lengths, torrent_metainfo::TorrentMetaV1Info};
+use parking_lot::Mutex;
+use tracing::{debug, warn};
+
+use crate::output_dir::{OpenMode, OutputDir};
+
+pub(crate) fn part_file_path(out_dir: &Path, info_hash: Id20) -> PathBuf {
+    out_dir.join(format!(".{}.parts", info_hash.as_string()))
+}
+
+// Where the bytes of a file are in the parts file. Bytes [0, head_len) and [tail_start, len)
+// of the file are stored, one after another.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Region {
+    part_offset: u64,
+    head_len: u64,
+    tail_start: u64,
+    len: u64,
+}
+
+impl Region {
+    // Where "offset" of the file is in the parts file, and how many bytes from there are stored.
+    fn locate(&self, offset: u64) -> Option<(u64, u64)> {
+        if offset < self.head_len {
+            Some((self.part_offset + offset, self.head_len - offset))
+        } else if offset >= self.tail_start && offset < self.len {
+            let pos = self.part_offset + self.head_len + offset - self.tail_start;
+            Some((pos, self.len - offset))
+        } else {
+            None
+        }
+    }
+
+    fn stored_len(&self) -> u64 {
+        self.head_len + self.len - self.tail_start
+    }
+}
+
+pub(crate) struct PartFile {
+    regions: Vec<Region>,
+    // Whether the file is opened on the parts file instead of its own.
+    parted: Vec<AtomicBool>,
+}
+
+impl PartFile {
+    pub fn new(info: &TorrentMetaV1Info<ByteString>, lengths: &Lengths) -> anyhow::Result<Self> {
+        let piece_length = lengths.default_piece_length() as u64;
+        let mut regions = Vec::new();
+        let mut start = 0u64;
+        let mut part_offset = 0u64;
+        for len in info.iter_file_lengths()? {
+            let end = start + len;
+            // Up to the end of the first piece, unless the file starts on a piece boundary.
+            let head_end = end.min(start.next_multiple_of(piece_length));
+            // From the start of the last piece, unless the file ends on a piece boundary.
+            let tail_start = head_end.max(end - end % piece_length);
+            let region = Region {
+                part_offset,
+                head_len: head_end - start,
+                tail_start: tail_start - start,
+                len,
+            };
+            part_offset += region.stored_len();
+            regions.push(region);
+            start = end;
+        }
+        let parted = regions.iter().map(|_| AtomicBool::new(false)).collect();
+        Ok(Self { regions, parted })
+    }
+
+    pub fn is_parted(&self, file_idx: usize) -> bool {
+        self.parted[file_idx].load(Ordering::Acquire)
+    }
+
+    // The length the parts file needs to have all stored bytes of the file.
+    pub fn stored_end(&self, file_idx: usize) -> u64 {
+        let r = &self.regions[file_idx];
+        r.part_offset + r.stored_len()
+    }
+
+    pub fn any_parted(&self) -> bool {
+        (0..self.parted.len()).any(|idx| self.is_parted(idx))
+    }
+
+    // Must be called with the file locked, together with reopening it.
+    pub fn set_parted(&self, file_idx: usize, parted: bool) {
+        self.parted[file_idx].store(parted, Ordering::Release);
+    }
+
+    // Where to read or write "len" bytes at "offset" of the file, if it's in the parts file.
+    // Must be called with the file locked.
+    pub fn position(&self, file_idx: usize, offset: u64, len: u64) -> anyhow::Result<Option<u64>> {
+        if !self.is_parted(file_idx) {
+            return Ok(None);
+        }
+        match self.regions[file_idx].locate(offset) {
+            Some((pos, stored)) if stored >= len => Ok(Some(pos)),
+            _ => anyhow::bail!(
+                "bytes {}..{} of file {} are not downloaded",
+                offset,
+                offset + len,
+                file_idx
+            ),
+        }
+    }
+
+    // Copy what's stored of the file from the parts file to its own file.
+    fn copy_out(&self, file_idx: usize, parts: &mut File, dst: &mut File) -> anyhow::Result<()> {
+        let r = &self.regions[file_idx];
+        for (offset, len) in [(0, r.head_len), (r.tail_start, r.len - r.tail_start)] {
+            if len == 0 {
+                continue;
+            }
+            let (pos, _) = r.locate(offset).context("bug: region not stored")?;
+            parts.seek(SeekFrom::Start(pos))?;
+            dst.seek(SeekFrom::Start(offset))?;
+            // The parts file may be shorter if some pieces were never received.
+            std::io::copy(&mut std::io::Read::take(&mut *parts, len), dst)?;
+        }
+        Ok(())
+    }
+
+    // Give the selected files that are in the parts file their own files, with what was
+    // downloaded of them. The parts file is removed once no file is in it.
+    #[allow(clippy::too_many_arguments)]
+    pub fn unpart_selected_files(
+        &self,
+        dir: &OutputDir,
+        files: &[Arc<Mutex<File>>],
+        filenames: &mut [PathBuf],
+        own_filenames: &[PathBuf],
+        part_path: &Path,
+        only_files: Option<&[usize]>,
+        writable: bool,
+    ) -> anyhow::Result<()> {
+        for (idx, file) in files.iter().enumerate() {
+            let selected = only_files.map(|o| o.contains(&idx)).unwrap_or(true);
+            if !selected || !self.is_parted(idx) {
+                continue;
+            }
+            let own = &own_filenames[idx];
+            let mut g = file.lock();
+            let mut dst = dir.open(own, OpenMode::Create)?;
+            dst.set_len(self.regions[idx].len)?;
+            self.copy_out(idx, &mut g, &mut dst)
+                .with_context(|| format!("error copying {:?} out of the parts file", own))?;
+            if !writable {
+                dst = dir.open(own, OpenMode::Read)?;
+            }
+            *g = dst;
+            filenames[idx] = own.clone();
+            self.set_parted(idx, false);
+            debug!(file = ?own, "moved file out of the parts file");
+        }
+        if !self.any_parted() && dir.exists(part_path) {
+            if let Err(e) = dir.remove_file(part_path) {
+                warn!(file = ?part_path, "error removing parts file: {:#}", e);
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Region;
+
+    #[test]
+    fn test_region_locate() {
+        // A file of 40 bytes at 10..50 with pieces of 16 bytes: 10..16 and 48..50 are shared.
+        let r = Region {
+            part_offset: 100,
+            head_len: 6,
+            tail_start: 38,
+            len: 40,
+        };
+        assert_eq!(r.stored_len(), 8);
+        assert_eq!(r.locate(0), Some((100, 6)));
+        assert_eq!(r.locate(5), Some((105, 1)));
+        assert_eq!(r.locate(6), None);
+        assert_eq!(r.locate(37), None);
+        assert_eq!(r.locate(38), Some((106, 2)));
+        assert_eq!(r.locate(40), None);
+    }
+}
@@ -1,12 +1,17 @@
 use std::{
-    net::SocketAddr,
+    collections::VecDeque,
+    net::{IpAddr, SocketAddr},
+    sync::Arc,
     time::{Duration, Instant},
 };
 
 use anyhow::{bail, Context};
 use buffers::{ByteBuf, ByteString};
 use clone_to_owned::CloneToOwned;
-use librqbit_core::{hash_id::Id20, lengths::ChunkInfo, peer_id::try_decode_peer_id};
+use librqbit_core::{
+    bind::OutgoingBind, clock::clock_jumps, hash_id::Id20, lengths::ChunkInfo,
+    peer_id::try_decode_peer_id,
+};
 use parking_lot::RwLock;
 use peer_binary_protocol::{
     extended::{handshake::ExtendedHandshake, ExtendedMessage},
@@ -15,9 +20,15 @@ use peer_binary_protocol::{
 use serde::{Deserialize, Serialize};
 use serde_with::serde_as;
 use tokio::time::timeout;
-use tracing::trace;
-
-use crate::{read_buf::ReadBuf, spawn_utils::BlockingSpawner};
+use tracing::{debug, trace};
+
+use crate::{
+    dialer::PeerDialer,
+    file_ops::FileSlice,
+    limits::{RateLimiter, UploadCredit},
+    read_buf::ReadBuf,
+    spawn_utils::BlockingSpawner,
+};
 
 pub trait PeerConnectionHandler {
     fn on_connected(&self, _connection_time: Duration) {}
@@ -28,9 +39,32 @@ pub trait PeerConnectionHandler {
         &self,
         extended_handshake: &ExtendedHandshake<ByteBuf>,
     ) -> anyhow::Result<()>;
+    fn update_my_extended_handshake(
+        &self,
+        _handshake: &mut ExtendedHandshake<ByteBuf<'static>>,
+    ) -> anyhow::Result<()> {
+        Ok(())
+    }
+    // Our public address, told to the peer in the extended handshake.
+    fn my_ip(&self) -> Option<IpAddr> {
+        None
+    }
     fn on_received_message(&self, msg: Message<ByteBuf<'_>>) -> anyhow::Result<()>;
     fn on_uploaded_bytes(&self, bytes: u32);
     fn read_chunk(&self, chunk: &ChunkInfo, buf: &mut [u8]) -> anyhow::Result<()>;
+    // Where the chunk is stored, to send it straight from the files. None if it has to be
+    // read with "read_chunk", e.g. as it's not on disk yet.
+    fn chunk_file_slices(&self, _chunk: &ChunkInfo) -> Option<Vec<FileSlice>> {
+        None
+    }
+    // Chunks are sent to the peer no faster than all of these allow.
+    fn upload_limiters(&self) -> Vec<&RateLimiter> {
+        Vec::new()
+    }
+    // Chunks are sent only once enough was downloaded to earn them.
+    fn upload_credit(&self) -> Option<&UploadCredit> {
+        None
+    }
 }
 
 #[derive(Debug)]
@@ -49,8 +83,115 @@ pub struct PeerConnectionOptions {
     #[serde_as(as = "Option<serde_with::DurationSeconds>")]
     pub read_write_timeout: Option<Duration>,
 
+    /// How long the connection may be idle on our side before a keep-alive is sent.
+    /// 60 seconds if not set.
     #[serde_as(as = "Option<serde_with::DurationSeconds>")]
     pub keep_alive_interval: Option<Duration>,
+
+    /// Vary the easily fingerprintable parts of the connection setup: timing, the order
+    /// of the first messages and unused reserved bits. This makes throttling based on deep
+    /// packet inspection less effective.
+    #[serde(default)]
+    pub randomize_fingerprint: bool,
+
+    #[serde(default)]
+    pub socket: PeerSocketOptions,
+}
+
+/// Options of the TCP sockets of peer connections. The buffer sizes are set before connecting,
+/// and the session's on the listener too, the rest once connected. The OS defaults are kept for
+/// the ones not set.
+#[serde_as]
+#[derive(Default, Debug, Copy, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PeerSocketOptions {
+    /// Turn off Nagle's algorithm, so that small messages like requests are sent right away.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub nodelay: Option<bool>,
+    /// Turn on TCP keepalive, probing the peer after the connection was idle this long.
+    #[serde_as(as = "Option<serde_with::DurationSeconds>")]
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub keepalive_time: Option<Duration>,
+    /// The time between TCP keepalive probes. Ignored on platforms that don't support it.
+    #[serde_as(as = "Option<serde_with::DurationSeconds>")]
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub keepalive_interval: Option<Duration>,
+    /// SO_SNDBUF, in bytes. Larger buffers help on links with a high bandwidth-delay product.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub send_buffer_size: Option<usize>,
+    /// SO_RCVBUF, in bytes.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub recv_buffer_size: Option<usize>,
+}
+
+impl PeerSocketOptions {
+    // The options set in "self", and the other ones from "fallback".
+    pub(crate) fn or(self, fallback: Self) -> Self {
+        Self {
+            nodelay: self.nodelay.or(fallback.nodelay),
+            keepalive_time: self.keepalive_time.or(fallback.keepalive_time),
+            keepalive_interval: self.keepalive_interval.or(fallback.keepalive_interval),
+            send_buffer_size: self.send_buffer_size.or(fallback.send_buffer_size),
+            recv_buffer_size: self.recv_buffer_size.or(fallback.recv_buffer_size),
+        }
+    }
+
+    pub(crate) fn apply(&self, conn: &tokio::net::TcpStream) -> std::io::Result<()> {
+        let socket = socket2::SockRef::from(conn);
+        if let Some(nodelay) = self.nodelay {
+            socket.set_nodelay(nodelay)?;
+        }
+        if self.keepalive_time.is_some() || self.keepalive_interval.is_some() {
+            let mut keepalive = socket2::TcpKeepalive::new();
+            if let Some(time) = self.keepalive_time {
+                keepalive = keepalive.with_time(time);
+            }
+            #[cfg(any(
+                target_os = "linux",
+                target_os = "android",
+                target_os = "macos",
+                target_os = "ios",
+                target_os = "freebsd",
+                windows
+            ))]
+            if let Some(interval) = self.keepalive_interval {
+                keepalive = keepalive.with_interval(interval);
+            }
+            socket.set_tcp_keepalive(&keepalive)?;
+        }
+        if let Some(size) = self.send_buffer_size {
+            socket.set_send_buffer_size(size)?;
+        }
+        if let Some(size) = self.recv_buffer_size {
+            socket.set_recv_buffer_size(size)?;
+        }
+        Ok(())
+    }
+}
+
+// Peers commonly drop connections that were silent for 2 minutes. Staying well under it, as a
+// keep-alive that is late by a few seconds is already too late.
+const DEFAULT_KEEP_ALIVE_INTERVAL: Duration = Duration::from_secs(60);
+
+// Sent as "v" in the extended handshake.
+const MY_CLIENT_VERSION: &str = concat!("rqbit ", env!("CARGO_PKG_VERSION"));
+
+// Sent as "reqq": how many requests peers may have outstanding with us. Requests beyond it
+// aren't dropped, but well-behaved peers won't send more.
+const MY_REQQ: u32 = 250;
+
+// Upper bound of the random delay before each of the first messages, with randomize_fingerprint.
+const MAX_HANDSHAKE_JITTER: Duration = Duration::from_millis(200);
+
+// Reserved bits that no known extension uses, and that can be randomly set without peers
+// misinterpreting them.
+const RANDOMIZABLE_RESERVED_BITS: [u8; 8] = [0, 0xff, 0, 0xff, 0xff, 0, 0, 0];
+
+fn randomize_reserved_bits(reserved: &mut [u8; 8]) {
+    use rand::Rng;
+    let mut rng = rand::thread_rng();
+    for (byte, mask) in reserved.iter_mut().zip(RANDOMIZABLE_RESERVED_BITS) {
+        *byte |= rng.gen::<u8>() & mask;
+    }
 }
 
 pub(crate) struct PeerConnection<H> {
@@ -60,6 +201,9 @@ pub(crate) struct PeerConnection<H> {
     peer_id: Id20,
     options: PeerConnectionOptions,
     spawner: BlockingSpawner,
+    dialer: Option<Arc<PeerDialer>>,
+    other_addr: Option<SocketAddr>,
+    local_port: Option<u16>,
 }
 
 pub(crate) async fn with_timeout<T, E>(
@@ -91,7 +235,75 @@ impl<H: PeerConnectionHandler> PeerConnection<H> {
             peer_id,
             spawner,
             options: options.unwrap_or_default(),
+            dialer: None,
+            other_addr: None,
+            local_port: None,
+        }
+    }
+
+    /// Make the outgoing connection with "dialer", e.g. through a proxy. Directly if None.
+    pub fn with_dialer(mut self, dialer: Option<Arc<PeerDialer>>) -> Self {
+        self.dialer = dialer;
+        self
+    }
+
+    /// Another address of the peer, of the other IP version, to race against "addr" when
+    /// connecting.
+    pub fn with_other_addr(mut self, other_addr: Option<SocketAddr>) -> Self {
+        self.other_addr = other_addr;
+        self
+    }
+
+    /// Connect from this port we listen on, for a simultaneous open with a peer behind a NAT.
+    /// "other_addr" isn't raced then.
+    pub fn with_local_port(mut self, local_port: Option<u16>) -> Self {
+        self.local_port = local_port;
+        self
+    }
+
+    // Not being able to set them isn't a reason to drop the peer.
+    fn apply_socket_options(&self, conn: &tokio::net::TcpStream) {
+        if let Err(e) = self.options.socket.apply(conn) {
+            debug!(addr = %self.addr, "error setting socket options: {e:#}");
+        }
+    }
+
+    fn my_handshake(&self) -> Handshake<ByteBuf<'static>> {
+        let mut handshake = Handshake::new(self.info_hash, self.peer_id);
+        if self.options.randomize_fingerprint {
+            randomize_reserved_bits(&mut handshake.reserved);
+        }
+        handshake
+    }
+
+    // With randomize_fingerprint, sleep for a random time so that messages aren't sent
+    // at predictable intervals.
+    async fn jitter(&self) {
+        if self.options.randomize_fingerprint {
+            use rand::Rng;
+            let delay = rand::thread_rng().gen_range(Duration::ZERO..MAX_HANDSHAKE_JITTER);
+            tokio::time::sleep(delay).await;
+        }
+    }
+
+    async fn write_bitfield(
+        &self,
+        conn: &mut (impl tokio::io::AsyncWrite + Unpin),
+        write_buf: &mut Vec<u8>,
+        rwtimeout: Duration,
+    ) -> anyhow::Result<()> {
+        use tokio::io::AsyncWriteExt;
+
+        if self.handler.get_have_bytes() == 0 {
+            return Ok(());
         }
+        let len = self.handler.serialize_bitfield_message_to_buf(write_buf)?;
+        with_timeout(rwtimeout, conn.write_all(&write_buf[..len]))
+            .await
+            .context("error writing bitfield to peer")?;
+        write_buf.clear();
+        trace!("sent bitfield");
+        Ok(())
     }
 
     // By the time this is called:
@@ -118,13 +330,15 @@ impl<H: PeerConnectionHandler> PeerConnection<H> {
             bail!("looks like we are connecting to ourselves");
         }
 
+        self.apply_socket_options(&conn);
         trace!(
             "incoming connection: id={:?}",
             try_decode_peer_id(Id20::new(handshake.peer_id))
         );
 
         let mut write_buf = Vec::<u8>::with_capacity(PIECE_MESSAGE_DEFAULT_LEN);
-        let handshake = Handshake::new(self.info_hash, self.peer_id);
+        self.jitter().await;
+        let handshake = self.my_handshake();
         handshake.serialize(&mut write_buf);
         with_timeout(rwtimeout, conn.write_all(&write_buf))
             .await
@@ -162,13 +376,36 @@ impl<H: PeerConnectionHandler> PeerConnection<H> {
             .unwrap_or_else(|| Duration::from_secs(10));
 
         let now = Instant::now();
-        let mut conn = with_timeout(connect_timeout, tokio::net::TcpStream::connect(self.addr))
+        let socket = &self.options.socket;
+        let bind = OutgoingBind::default()
+            .with_buffer_sizes(socket.send_buffer_size, socket.recv_buffer_size);
+        let connect = async {
+            match (&self.dialer, self.local_port) {
+                (Some(dialer), Some(port)) => {
+                    dialer.connect_from_port(self.addr, port, socket).await
+                }
+                (Some(dialer), None) => {
+                    dialer
+                        .connect(self.addr, self.other_addr, self.info_hash, socket)
+                        .await
+                }
+                (None, Some(port)) => Ok(bind.tcp_connect_from_port(self.addr, port).await?),
+                (None, None) => {
+                    let targets: Vec<SocketAddr> =
+                        std::iter::once(self.addr).chain(self.other_addr).collect();
+                    Ok(bind.tcp_connect_any(&targets).await?)
+                }
+            }
+        };
+        let mut conn = with_timeout(connect_timeout, connect)
             .await
             .context("error connecting")?;
+        self.apply_socket_options(&conn);
         self.handler.on_connected(now.elapsed());
 
         let mut write_buf = Vec::<u8>::with_capacity(PIECE_MESSAGE_DEFAULT_LEN);
-        let handshake = Handshake::new(self.info_hash, self.peer_id);
+        self.jitter().await;
+        let handshake = self.my_handshake();
         handshake.serialize(&mut write_buf);
         with_timeout(rwtimeout, conn.write_all(&write_buf))
             .await
@@ -224,54 +461,104 @@ impl<H: PeerConnectionHandler> PeerConnection<H> {
         let extended_handshake_ref = &extended_handshake;
         let supports_extended = handshake_supports_extended;
 
+        // Normally the extended handshake goes first.
+        let bitfield_first = self.options.randomize_fingerprint && rand::random::<bool>();
+        if bitfield_first {
+            self.jitter().await;
+            self.write_bitfield(&mut conn, &mut write_buf, rwtimeout)
+                .await?;
+        }
+
         if supports_extended {
-            let my_extended =
-                Message::Extended(ExtendedMessage::Handshake(ExtendedHandshake::new()));
+            self.jitter().await;
+            let mut my_extended_handshake = ExtendedHandshake::new();
+            // The version would tell rqbit apart, the point of randomize_fingerprint.
+            if !self.options.randomize_fingerprint {
+                my_extended_handshake.v = Some(ByteBuf(MY_CLIENT_VERSION.as_bytes()));
+            }
+            my_extended_handshake.reqq = Some(MY_REQQ);
+            self.handler
+                .update_my_extended_handshake(&mut my_extended_handshake)?;
+            let (my_ipv4, my_ipv6) = match self.handler.my_ip() {
+                Some(IpAddr::V4(ip)) => (Some(ip.octets()), None),
+                Some(IpAddr::V6(ip)) => (None, Some(ip.octets())),
+                None => (None, None),
+            };
+            let mut my_extended_handshake: ExtendedHandshake<ByteBuf> = my_extended_handshake;
+            my_extended_handshake.ipv4 = my_ipv4.as_ref().map(|ip| ByteBuf(ip));
+            my_extended_handshake.ipv6 = my_ipv6.as_ref().map(|ip| ByteBuf(ip));
+            let my_extended = Message::Extended(ExtendedMessage::Handshake(my_extended_handshake));
             trace!("sending extended handshake: {:?}", &my_extended);
-            my_extended.serialize(&mut write_buf, &|| None).unwrap();
+            my_extended.serialize(&mut write_buf, &|_| None).unwrap();
             with_timeout(rwtimeout, conn.write_all(&write_buf))
                 .await
                 .context("error writing extended handshake")?;
             write_buf.clear();
         }
 
-        let (mut read_half, mut write_half) = tokio::io::split(conn);
+        let (mut read_half, mut write_half) = conn.into_split();
 
         let writer = async move {
             let keep_alive_interval = self
                 .options
                 .keep_alive_interval
-                .unwrap_or_else(|| Duration::from_secs(120));
+                .unwrap_or(DEFAULT_KEEP_ALIVE_INTERVAL);
 
-            if self.handler.get_have_bytes() > 0 {
-                let len = self
-                    .handler
-                    .serialize_bitfield_message_to_buf(&mut write_buf)?;
-                with_timeout(rwtimeout, write_half.write_all(&write_buf[..len]))
-                    .await
-                    .context("error writing bitfield to peer")?;
-                trace!("sent bitfield");
+            if !bitfield_first {
+                self.write_bitfield(&mut write_half, &mut write_buf, rwtimeout)
+                    .await?;
             }
 
+            // Chunks waiting for upload credit. Other messages (e.g. our requests) are sent
+            // meanwhile, as it's downloading that earns the credit.
+            let mut waiting_for_credit = VecDeque::<ChunkInfo>::new();
+            let mut clock_jumps = clock_jumps();
+
             loop {
-                let req = match timeout(keep_alive_interval, outgoing_chan.recv()).await {
-                    Ok(Some(msg)) => msg,
-                    Ok(None) => {
-                        anyhow::bail!("closing writer, channel closed")
+                let next = async {
+                    tokio::select! {
+                        msg = timeout(keep_alive_interval, outgoing_chan.recv()) => match msg {
+                            Ok(Some(msg)) => Ok(msg),
+                            Ok(None) => {
+                                anyhow::bail!("closing writer, channel closed")
+                            }
+                            Err(_) => Ok(WriterRequest::Message(MessageOwned::KeepAlive)),
+                        },
+                        // E.g. after a suspend, find out right away if the connection survived.
+                        _ = clock_jumps.changed() => Ok(WriterRequest::Message(MessageOwned::KeepAlive)),
                     }
-                    Err(_) => WriterRequest::Message(MessageOwned::KeepAlive),
+                };
+                let waiting_size = waiting_for_credit.front().map(|c| c.size as u64);
+                let (req, credited) = match (self.handler.upload_credit(), waiting_size) {
+                    (Some(credit), Some(size)) => tokio::select! {
+                        _ = credit.acquire(self.addr, size) => {
+                            let chunk = waiting_for_credit.pop_front().unwrap();
+                            (WriterRequest::ReadChunkRequest(chunk), true)
+                        }
+                        req = next => (req?, false),
+                    },
+                    _ => (next.await?, false),
                 };
 
                 let mut uploaded_add = None;
 
                 let len = match &req {
-                    WriterRequest::Message(msg) => msg.serialize(&mut write_buf, &|| {
+                    WriterRequest::Message(msg) => msg.serialize(&mut write_buf, &|name| {
                         extended_handshake_ref
                             .read()
                             .as_ref()
-                            .and_then(|e| e.ut_metadata())
+                            .and_then(|e| e.get_msgid(name))
                     })?,
                     WriterRequest::ReadChunkRequest(chunk) => {
+                        if let (Some(credit), false) = (self.handler.upload_credit(), credited) {
+                            if !waiting_for_credit.is_empty()
+                                || !credit.try_acquire(self.addr, chunk.size as u64)
+                            {
+                                waiting_for_credit.push_back(*chunk);
+                                continue;
+                            }
+                        }
+
                         #[cfg(test)]
                         {
                             // This is poor-mans fault injection for running e2e tests.
@@ -288,6 +575,31 @@ impl<H: PeerConnectionHandler> PeerConnection<H> {
                             tokio::time::sleep(Duration::from_millis(sleep_ms)).await;
                         }
 
+                        for limiter in self.handler.upload_limiters() {
+                            limiter.acquire(chunk.size as u64).await;
+                        }
+
+                        #[cfg(target_os = "linux")]
+                        if let Some(slices) = self.handler.chunk_file_slices(chunk) {
+                            write_buf.resize(PIECE_MESSAGE_DEFAULT_LEN, 0);
+                            let preamble_len = serialize_piece_preamble(chunk, &mut write_buf);
+                            trace!("sending chunk {:?} from files", chunk);
+                            with_timeout(rwtimeout, async {
+                                write_half.write_all(&write_buf[..preamble_len]).await?;
+                                crate::sendfile::send_file_slices(
+                                    write_half.as_ref(),
+                                    &slices,
+                                    &self.spawner,
+                                )
+                                .await
+                            })
+                            .await
+                            .with_context(|| format!("error sending chunk {chunk:?}"))?;
+                            write_buf.clear();
+                            self.handler.on_uploaded_bytes(chunk.size);
+                            continue;
+                        }
+
                         // this whole section is an optimization
                         write_buf.resize(PIECE_MESSAGE_DEFAULT_LEN, 0);
                         let preamble_len = serialize_piece_preamble(chunk, &mut write_buf);
@@ -333,7 +645,17 @@ impl<H: PeerConnectionHandler> PeerConnection<H> {
                         trace!("received: {:?}", &message);
 
                         if let Message::Extended(ExtendedMessage::Handshake(h)) = &message {
-                            *extended_handshake_ref.write() = Some(h.clone_to_owned());
+                            let mut h_owned = h.clone_to_owned();
+                            let mut g = extended_handshake_ref.write();
+                            // A later handshake may only carry updates, e.g. upload_only. The
+                            // extensions add up (BEP 10).
+                            if let Some(prev) = g.take() {
+                                for (name, id) in prev.m {
+                                    h_owned.m.entry(name).or_insert(id);
+                                }
+                            }
+                            *g = Some(h_owned);
+                            drop(g);
                             self.handler.on_extended_handshake(h)?;
                             trace!("remembered extended handshake for future serializing");
                         } else {
@@ -364,3 +686,110 @@ impl<H: PeerConnectionHandler> PeerConnection<H> {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use buffers::ByteBuf;
+    use librqbit_core::{hash_id::Id20, lengths::ChunkInfo};
+    use peer_binary_protocol::{
+        extended::handshake::ExtendedHandshake, Handshake, Message, MessageBorrowed,
+        MessageDeserializeError,
+    };
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    use super::{PeerConnection, PeerConnectionHandler, PeerConnectionOptions};
+    use crate::spawn_utils::BlockingSpawner;
+
+    // Has nothing, and ignores everything.
+    struct IdleHandler;
+
+    impl PeerConnectionHandler for IdleHandler {
+        fn get_have_bytes(&self) -> u64 {
+            0
+        }
+
+        fn serialize_bitfield_message_to_buf(&self, _buf: &mut Vec<u8>) -> anyhow::Result<usize> {
+            anyhow::bail!("nothing to send")
+        }
+
+        fn on_handshake<B>(&self, _handshake: Handshake<B>) -> anyhow::Result<()> {
+            Ok(())
+        }
+
+        fn on_extended_handshake(
+            &self,
+            _extended_handshake: &ExtendedHandshake<ByteBuf>,
+        ) -> anyhow::Result<()> {
+            Ok(())
+        }
+
+        fn on_received_message(&self, _msg: Message<ByteBuf<'_>>) -> anyhow::Result<()> {
+            Ok(())
+        }
+
+        fn on_uploaded_bytes(&self, _bytes: u32) {}
+
+        fn read_chunk(&self, _chunk: &ChunkInfo, _buf: &mut [u8]) -> anyhow::Result<()> {
+            anyhow::bail!("nothing to read")
+        }
+    }
+
+    #[tokio::test]
+    async fn test_keep_alive_sent_when_idle() {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let info_hash = Id20::new([1; 20]);
+        let conn = PeerConnection::new(
+            listener.local_addr().unwrap(),
+            info_hash,
+            Id20::new([2; 20]),
+            IdleHandler,
+            Some(PeerConnectionOptions {
+                keep_alive_interval: Some(Duration::from_millis(200)),
+                ..Default::default()
+            }),
+            BlockingSpawner::new(true),
+        );
+        // Kept open, but nothing is ever sent through it.
+        let (_tx, rx) = tokio::sync::mpsc::unbounded_channel();
+
+        let peer = async {
+            let (mut sock, _) = listener.accept().await.unwrap();
+            let mut handshake = [0u8; 68];
+            sock.read_exact(&mut handshake).await.unwrap();
+            let mut buf = Vec::new();
+            Handshake::new(info_hash, Id20::new([3; 20])).serialize(&mut buf);
+            sock.write_all(&buf).await.unwrap();
+            buf.clear();
+
+            // The extended handshake comes first.
+            loop {
+                let parsed = match MessageBorrowed::deserialize(&buf) {
+                    Ok((msg, len)) => Some((matches!(msg, Message::KeepAlive), len)),
+                    Err(MessageDeserializeError::NotEnoughData(..)) => None,
+                    Err(e) => panic!("{e}"),
+                };
+                match parsed {
+                    Some((true, _)) => return,
+                    Some((false, len)) => {
+                        buf.drain(..len);
+                    }
+                    None => {
+                        let mut chunk = [0u8; 1024];
+                        let read = sock.read(&mut chunk).await.unwrap();
+                        assert!(read > 0, "connection closed");
+                        buf.extend_from_slice(&chunk[..read]);
+                    }
+                }
+            }
+        };
+
+        tokio::select! {
+            r = conn.manage_peer_outgoing(rx) => panic!("connection ended: {r:?}"),
+            r = tokio::time::timeout(Duration::from_secs(5), peer) => {
+                r.expect("no keep-alive was sent")
+            }
+        }
+    }
+}
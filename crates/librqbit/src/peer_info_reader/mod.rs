@@ -1,4 +1,8 @@
-use std::net::SocketAddr;
+use std::{
+    net::SocketAddr,
+    sync::Arc,
+    time::{Duration, Instant},
+};
 
 use bencode::from_bytes;
 use buffers::{ByteBuf, ByteString};
@@ -15,20 +19,44 @@ use peer_binary_protocol::{
 };
 use sha1w::{ISha1, Sha1};
 use tokio::sync::mpsc::UnboundedSender;
-use tracing::trace;
+use tracing::{trace, warn};
 
 use crate::{
+    dialer::PeerDialer,
     peer_connection::{
         PeerConnection, PeerConnectionHandler, PeerConnectionOptions, WriterRequest,
     },
     spawn_utils::BlockingSpawner,
 };
 
+// Real-world info dicts are way below this, but torrents with lots of files and small pieces
+// can get into a few megabytes.
+const MAX_METADATA_SIZE: u32 = 8 * 1024 * 1024;
+
+// How many ut_metadata data messages a single peer may send us per second.
+const MAX_METADATA_PIECES_PER_SECOND: u32 = 64;
+
+/// The peer sent us metadata that doesn't hash to the info hash we asked for.
+/// Such peers are either broken or malicious, and should not be talked to again.
+#[derive(Debug)]
+pub(crate) struct MetadataHashMismatch {
+    pub addr: SocketAddr,
+}
+
+impl std::fmt::Display for MetadataHashMismatch {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}: info checksum invalid", self.addr)
+    }
+}
+
+impl std::error::Error for MetadataHashMismatch {}
+
 pub(crate) async fn read_metainfo_from_peer(
     addr: SocketAddr,
     peer_id: Id20,
     info_hash: Id20,
     peer_connection_options: Option<PeerConnectionOptions>,
+    dialer: Option<Arc<PeerDialer>>,
     spawner: BlockingSpawner,
 ) -> anyhow::Result<TorrentMetaV1Info<ByteString>> {
     let (result_tx, result_rx) =
@@ -48,7 +76,8 @@ pub(crate) async fn read_metainfo_from_peer(
         handler,
         peer_connection_options,
         spawner,
-    );
+    )
+    .with_dialer(dialer);
 
     let result_reader = async move { result_rx.await? };
     let connection_runner = async move { connection.manage_peer_outgoing(writer_rx).await };
@@ -62,17 +91,49 @@ pub(crate) async fn read_metainfo_from_peer(
     }
 }
 
-#[derive(Default)]
+struct RateLimiter {
+    window_start: Instant,
+    count: u32,
+}
+
+impl RateLimiter {
+    fn new() -> Self {
+        Self {
+            window_start: Instant::now(),
+            count: 0,
+        }
+    }
+
+    fn check(&mut self, now: Instant) -> anyhow::Result<()> {
+        if now.saturating_duration_since(self.window_start) >= Duration::from_secs(1) {
+            self.window_start = now;
+            self.count = 0;
+        }
+        self.count += 1;
+        if self.count > MAX_METADATA_PIECES_PER_SECOND {
+            anyhow::bail!(
+                "peer sent more than {} metadata pieces per second",
+                MAX_METADATA_PIECES_PER_SECOND
+            );
+        }
+        Ok(())
+    }
+}
+
 struct HandlerLocked {
     metadata_size: u32,
     total_pieces: usize,
     buffer: Vec<u8>,
     received_pieces: Vec<bool>,
+    rate_limiter: RateLimiter,
 }
 
 impl HandlerLocked {
     fn new(metadata_size: u32) -> anyhow::Result<Self> {
-        if metadata_size > 1024 * 1024 {
+        if metadata_size == 0 {
+            anyhow::bail!("metadata size is 0");
+        }
+        if metadata_size > MAX_METADATA_SIZE {
             anyhow::bail!("metadata size {} is too big", metadata_size);
         }
         let buffer = vec![0u8; metadata_size as usize];
@@ -83,6 +144,7 @@ impl HandlerLocked {
             received_pieces,
             buffer,
             total_pieces: total_pieces as usize,
+            rate_limiter: RateLimiter::new(),
         })
     }
     fn piece_size(&self, index: u32) -> usize {
@@ -92,7 +154,22 @@ impl HandlerLocked {
             CHUNK_SIZE as usize
         }
     }
-    fn record_piece(&mut self, index: u32, data: &[u8], info_hash: Id20) -> anyhow::Result<bool> {
+    fn record_piece(
+        &mut self,
+        addr: SocketAddr,
+        index: u32,
+        total_size: u32,
+        data: &[u8],
+        info_hash: Id20,
+    ) -> anyhow::Result<bool> {
+        self.rate_limiter.check(Instant::now())?;
+        if total_size != self.metadata_size {
+            anyhow::bail!(
+                "total_size {} doesn't match metadata_size {} from the extended handshake",
+                total_size,
+                self.metadata_size
+            );
+        }
         if index as usize >= self.total_pieces {
             anyhow::bail!("wrong index");
         }
@@ -118,7 +195,8 @@ impl HandlerLocked {
             let mut hash = Sha1::new();
             hash.update(&self.buffer);
             if hash.finish() != info_hash.0 {
-                anyhow::bail!("info checksum invalid");
+                warn!(%addr, ?info_hash, "peer sent metadata with invalid checksum");
+                return Err(MetadataHashMismatch { addr }.into());
             }
             Ok(true)
         } else {
@@ -157,16 +235,18 @@ impl PeerConnectionHandler for Handler {
 
         if let Message::Extended(ExtendedMessage::UtMetadata(UtMetadata::Data {
             piece,
-            total_size: _,
+            total_size,
             data,
         })) = msg
         {
-            let piece_ready =
-                self.locked
-                    .write()
-                    .as_mut()
-                    .unwrap()
-                    .record_piece(piece, &data, self.info_hash)?;
+            let piece_ready = self
+                .locked
+                .write()
+                .as_mut()
+                .ok_or_else(|| {
+                    anyhow::anyhow!("received metadata piece before the extended handshake")
+                })?
+                .record_piece(self.addr, piece, total_size, &data, self.info_hash)?;
             if piece_ready {
                 let buf = self.locked.write().take().unwrap().buffer;
                 let info = from_bytes::<TorrentMetaV1Info<ByteString>>(&buf);
@@ -231,7 +311,7 @@ mod tests {
 
     use crate::spawn_utils::BlockingSpawner;
 
-    use super::read_metainfo_from_peer;
+    use super::{read_metainfo_from_peer, HandlerLocked, MetadataHashMismatch};
 
     static LOG_INIT: Once = std::sync::Once::new();
 
@@ -250,10 +330,47 @@ mod tests {
         let addr = SocketAddr::from_str("127.0.0.1:27311").unwrap();
         let peer_id = generate_peer_id();
         let info_hash = Id20::from_str("9905f844e5d8787ecd5e08fb46b2eb0a42c131d7").unwrap();
-        dbg!(
-            read_metainfo_from_peer(addr, peer_id, info_hash, None, BlockingSpawner::new(true))
-                .await
-                .unwrap()
+        dbg!(read_metainfo_from_peer(
+            addr,
+            peer_id,
+            info_hash,
+            None,
+            None,
+            BlockingSpawner::new(true),
+        )
+        .await
+        .unwrap());
+    }
+
+    #[test]
+    fn test_metadata_size_bounds() {
+        assert!(HandlerLocked::new(0).is_err());
+        assert!(HandlerLocked::new(u32::MAX).is_err());
+        assert!(HandlerLocked::new(100).is_ok());
+    }
+
+    #[test]
+    fn test_metadata_hash_mismatch() {
+        let addr = SocketAddr::from_str("127.0.0.1:1").unwrap();
+        let info_hash = Id20::from_str("9905f844e5d8787ecd5e08fb46b2eb0a42c131d7").unwrap();
+        let mut h = HandlerLocked::new(4).unwrap();
+        assert!(h.record_piece(addr, 0, 5, b"test", info_hash).is_err());
+        let err = h.record_piece(addr, 0, 4, b"test", info_hash).unwrap_err();
+        assert_eq!(
+            err.downcast_ref::<MetadataHashMismatch>().unwrap().addr,
+            addr
         );
     }
+
+    #[test]
+    fn test_metadata_pieces_rate_limited() {
+        let addr = SocketAddr::from_str("127.0.0.1:1").unwrap();
+        let info_hash = Id20::from_str("9905f844e5d8787ecd5e08fb46b2eb0a42c131d7").unwrap();
+        let mut h = HandlerLocked::new(4).unwrap();
+        let mut last = None;
+        for _ in 0..=super::MAX_METADATA_PIECES_PER_SECOND {
+            last = Some(h.record_piece(addr, 1, 4, b"test", info_hash).unwrap_err());
+        }
+        assert!(format!("{:#}", last.unwrap()).contains("per second"));
+    }
 }
@@ -0,0 +1,229 @@
+// Snapshots of verified download progress ("resume data"), so that after a restart, or a crash,
+// a torrent doesn't need to re-check all of its files, and continues from the last snapshot.
+//
+// The sizes and modification times of the files are saved too. The pieces we have in files that
+// changed since are rechecked, as something other than us may have written to them.
+//
+// What trackers know the torrent by is kept here too, so that they don't see a new client after
+// a restart, and so are the lifetime transfer totals that seeding limits are checked against.
+
+use std::{
+    collections::HashMap,
+    io::BufWriter,
+    path::{Path, PathBuf},
+    time::UNIX_EPOCH,
+};
+
+use anyhow::Context;
+use librqbit_core::hash_id::Id20;
+use serde::{Deserialize, Serialize};
+use tracing::trace;
+
+use crate::{torrent_state::stats::TransferTotals, type_aliases::BF};
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct FileState {
+    pub len: u64,
+    // Nanoseconds since the epoch. None if the filesystem doesn't record it.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub mtime_ns: Option<u64>,
+}
+
+impl FileState {
+    // None if the file doesn't exist.
+    pub fn of(filename: &Path) -> Option<Self> {
+        let meta = std::fs::metadata(filename).ok()?;
+        let mtime_ns = meta
+            .modified()
+            .ok()
+            .and_then(|m| m.duration_since(UNIX_EPOCH).ok())
+            .map(|d| d.as_nanos() as u64);
+        Some(Self {
+            len: meta.len(),
+            mtime_ns,
+        })
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub(crate) struct TrackerIdentity {
+    // The "key" sent in announces.
+    pub key: u32,
+    // The "tracker id" each tracker gave us, by announce URL.
+    #[serde(default)]
+    pub tracker_ids: HashMap<String, String>,
+}
+
+impl TrackerIdentity {
+    pub fn random() -> Self {
+        Self {
+            key: rand::random(),
+            tracker_ids: Default::default(),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+pub(crate) struct ResumeData {
+    pub info_hash: String,
+    // The "have" bitfield, base64-encoded.
+    pub have_pieces: String,
+    // In torrent order. Empty in resume data written by older versions.
+    #[serde(default)]
+    pub files: Vec<Option<FileState>>,
+    // None in resume data written by older versions.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub trackers: Option<TrackerIdentity>,
+    #[serde(default)]
+    pub totals: TransferTotals,
+}
+
+pub(crate) fn encode_have_pieces(have_pieces: &BF) -> String {
+    use base64::{engine::general_purpose, Engine as _};
+    general_purpose::STANDARD_NO_PAD.encode(have_pieces.as_raw_slice())
+}
+
+pub(crate) fn decode_have_pieces(s: &str) -> anyhow::Result<BF> {
+    use base64::{engine::general_purpose, Engine as _};
+    let bytes = general_purpose::STANDARD_NO_PAD
+        .decode(s)
+        .context("error decoding have_pieces")?;
+    Ok(BF::from_vec(bytes))
+}
+
+impl ResumeData {
+    pub fn have_pieces(&self) -> anyhow::Result<BF> {
+        decode_have_pieces(&self.have_pieces)
+    }
+}
+
+/// A directory with one resume file per torrent.
+pub(crate) struct ResumeStore {
+    dir: PathBuf,
+}
+
+impl ResumeStore {
+    pub fn new(dir: PathBuf) -> anyhow::Result<Self> {
+        std::fs::create_dir_all(&dir)
+            .with_context(|| format!("error creating resume data directory {:?}", dir))?;
+        Ok(Self { dir })
+    }
+
+    fn filename(&self, info_hash: Id20) -> PathBuf {
+        self.dir.join(format!("{}.json", info_hash.as_string()))
+    }
+
+    // The files must be synced before, so that their modification times don't change later.
+    pub fn save(
+        &self,
+        info_hash: Id20,
+        have_pieces: &BF,
+        filenames: &[PathBuf],
+        trackers: &TrackerIdentity,
+        totals: &TransferTotals,
+    ) -> anyhow::Result<()> {
+        let data = ResumeData {
+            info_hash: info_hash.as_string(),
+            have_pieces: encode_have_pieces(have_pieces),
+            files: filenames.iter().map(|f| FileState::of(f)).collect(),
+            trackers: Some(trackers.clone()),
+            totals: *totals,
+        };
+        let filename = self.filename(info_hash);
+        let tmp_filename = filename.with_extension("json.tmp");
+        write_file(&tmp_filename, &data)?;
+        std::fs::rename(&tmp_filename, &filename)
+            .with_context(|| format!("error renaming {:?}", tmp_filename))?;
+        trace!(?filename, "wrote resume data");
+        Ok(())
+    }
+
+    pub fn load(&self, info_hash: Id20) -> anyhow::Result<Option<ResumeData>> {
+        let filename = self.filename(info_hash);
+        let file = match std::fs::File::open(&filename) {
+            Ok(f) => f,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e).with_context(|| format!("error opening {:?}", filename)),
+        };
+        let data: ResumeData = serde_json::from_reader(std::io::BufReader::new(file))
+            .with_context(|| format!("error parsing {:?}", filename))?;
+        if data.info_hash != info_hash.as_string() {
+            anyhow::bail!("{:?} is for a different torrent", filename);
+        }
+        Ok(Some(data))
+    }
+
+    pub fn remove(&self, info_hash: Id20) -> anyhow::Result<()> {
+        match std::fs::remove_file(self.filename(info_hash)) {
+            Err(e) if e.kind() != std::io::ErrorKind::NotFound => Err(e.into()),
+            _ => Ok(()),
+        }
+    }
+}
+
+fn write_file(filename: &Path, data: &ResumeData) -> anyhow::Result<()> {
+    let file = std::fs::OpenOptions::new()
+        .create(true)
+        .truncate(true)
+        .write(true)
+        .open(filename)
+        .with_context(|| format!("error opening {:?}", filename))?;
+    let mut writer = BufWriter::new(file);
+    serde_json::to_writer(&mut writer, data).context("error serializing resume data")?;
+    let file = writer.into_inner().context("error flushing resume data")?;
+    file.sync_all()
+        .with_context(|| format!("error syncing {:?}", filename))?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::str::FromStr;
+
+    use librqbit_core::hash_id::Id20;
+
+    use super::{FileState, ResumeStore, TrackerIdentity};
+    use crate::{torrent_state::stats::TransferTotals, type_aliases::BF};
+
+    #[test]
+    fn test_save_load_remove() {
+        let dir = tempfile::TempDir::with_prefix("test_resume_data").unwrap();
+        let store = ResumeStore::new(dir.path().join("resume")).unwrap();
+        let info_hash = Id20::from_str("cab507494d02ebb1178b38f2e9d7be299c86b862").unwrap();
+        assert!(store.load(info_hash).unwrap().is_none());
+
+        let mut have = BF::from_vec(vec![0u8; 3]);
+        have.set(1, true);
+        have.set(17, true);
+        let file = dir.path().join("file");
+        std::fs::write(&file, b"data").unwrap();
+        let missing = dir.path().join("missing");
+        let mut trackers = TrackerIdentity::random();
+        trackers
+            .tracker_ids
+            .insert("http://t/announce".to_owned(), "abc".to_owned());
+        let totals = TransferTotals {
+            uploaded_bytes: 100,
+            downloaded_bytes: 50,
+            seeding_secs: 3600,
+        };
+        store
+            .save(
+                info_hash,
+                &have,
+                &[file.clone(), missing],
+                &trackers,
+                &totals,
+            )
+            .unwrap();
+        let loaded = store.load(info_hash).unwrap().unwrap();
+        assert_eq!(loaded.have_pieces().unwrap(), have);
+        assert_eq!(loaded.files, [FileState::of(&file), None]);
+        assert_eq!(loaded.files[0].unwrap().len, 4);
+        assert_eq!(loaded.trackers, Some(trackers));
+        assert_eq!(loaded.totals, totals);
+
+        store.remove(info_hash).unwrap();
+        assert!(store.load(info_hash).unwrap().is_none());
+    }
+}
@@ -0,0 +1,139 @@
+// Stopping torrents that seeded enough, by share ratio or by seeding time. Both are counted over
+// the lifetime of the torrent, see TransferTotals, and checked periodically by the session.
+// Torrents without a ratio limit of their own use the one of their label, if any.
+
+use std::time::Duration;
+
+use serde::{Deserialize, Serialize};
+use serde_with::serde_as;
+use tracing::{debug, info};
+
+use crate::{
+    session::Session,
+    torrent_state::{stats::TransferTotals, ManagedTorrentState},
+};
+
+/// When to stop seeding a finished torrent. Can be changed while it's running with
+/// [`crate::ManagedTorrent::set_seed_limits`]. None means no limit.
+#[serde_as]
+#[derive(Debug, Default, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct SeedLimits {
+    /// Stop once the share ratio reaches this, see [`TransferTotals::share_ratio`].
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub ratio: Option<f64>,
+    /// Stop once the torrent seeded this long in total.
+    #[serde_as(as = "Option<serde_with::DurationSeconds>")]
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub time: Option<Duration>,
+    #[serde(default)]
+    pub action: SeedLimitAction,
+}
+
+impl SeedLimits {
+    pub(crate) fn validate(&self) -> anyhow::Result<()> {
+        if let Some(ratio) = self.ratio {
+            if !ratio.is_finite() || ratio < 0. {
+                anyhow::bail!("invalid seed ratio limit {ratio}");
+            }
+        }
+        Ok(())
+    }
+
+    pub(crate) fn is_reached(&self, totals: &TransferTotals, total_bytes: u64) -> bool {
+        let ratio_reached = self
+            .ratio
+            .is_some_and(|r| totals.share_ratio(total_bytes) >= r);
+        let time_reached = self
+            .time
+            .is_some_and(|t| totals.seeding_secs >= t.as_secs());
+        ratio_reached || time_reached
+    }
+}
+
+/// What to do with a torrent once it reached one of its [`SeedLimits`].
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum SeedLimitAction {
+    #[default]
+    Pause,
+    /// Remove the torrent from the session, keeping its files.
+    Forget,
+}
+
+impl std::fmt::Display for SeedLimitAction {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            SeedLimitAction::Pause => f.write_str("pause"),
+            SeedLimitAction::Forget => f.write_str("forget"),
+        }
+    }
+}
+
+impl std::str::FromStr for SeedLimitAction {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "pause" => Ok(Self::Pause),
+            "forget" => Ok(Self::Forget),
+            s => anyhow::bail!("invalid seed limit action {s:?}, expected \"pause\" or \"forget\""),
+        }
+    }
+}
+
+impl serde::Serialize for SeedLimitAction {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+impl<'de> serde::Deserialize<'de> for SeedLimitAction {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        use serde::de::Error;
+        String::deserialize(deserializer)?
+            .parse()
+            .map_err(D::Error::custom)
+    }
+}
+
+impl Session {
+    pub(crate) fn enforce_seed_limits(&self) {
+        let label_ratios = self.label_seed_ratio_limits();
+        let torrents = self.with_torrents(|torrents| {
+            torrents
+                .filter_map(|(id, t)| {
+                    let mut limits = t.seed_limits();
+                    if limits.ratio.is_none() {
+                        limits.ratio = t
+                            .info()
+                            .label
+                            .as_deref()
+                            .and_then(|l| label_ratios.get(l))
+                            .copied();
+                    }
+                    (limits != SeedLimits::default()).then(|| (id, t.clone(), limits))
+                })
+                .collect::<Vec<_>>()
+        });
+        for (id, torrent, limits) in torrents {
+            let reached = torrent.with_state(|s| match s {
+                ManagedTorrentState::Live(live) => {
+                    live.is_finished()
+                        && limits
+                            .is_reached(&live.transfer_totals(), live.get_total_selected_bytes())
+                }
+                _ => false,
+            });
+            if !reached {
+                continue;
+            }
+            info!(id, action = %limits.action, "seed limit reached");
+            let result = match limits.action {
+                SeedLimitAction::Pause => torrent.pause(),
+                SeedLimitAction::Forget => self.delete(id, false),
+            };
+            if let Err(e) = result {
+                debug!(id, "error stopping torrent: {e:#}");
+            }
+        }
+    }
+}
@@ -0,0 +1,51 @@
+// Uploading chunks straight from the files to the socket with sendfile(2), so that the data
+// isn't copied through a buffer in userspace.
+
+use std::os::fd::AsRawFd;
+
+use anyhow::Context;
+use tokio::{io::Interest, net::TcpStream};
+
+use crate::{file_ops::FileSlice, spawn_utils::BlockingSpawner};
+
+pub(crate) async fn send_file_slices(
+    sock: &TcpStream,
+    slices: &[FileSlice],
+    spawner: &BlockingSpawner,
+) -> anyhow::Result<()> {
+    for slice in slices {
+        let mut offset = slice.offset;
+        let end = slice.offset + slice.len;
+        while offset < end {
+            sock.writable().await?;
+            let res = sock.try_io(Interest::WRITABLE, || {
+                // The file is locked so that it isn't reopened meanwhile. Reading it may block
+                // on the disk.
+                spawner.spawn_block_in_place(|| {
+                    let file = slice.file.lock();
+                    let mut file_offset = offset as libc::off_t;
+                    // SAFETY: both descriptors stay open while "file" and "sock" are borrowed.
+                    let sent = unsafe {
+                        libc::sendfile(
+                            sock.as_raw_fd(),
+                            file.as_raw_fd(),
+                            &mut file_offset,
+                            (end - offset) as usize,
+                        )
+                    };
+                    if sent < 0 {
+                        return Err(std::io::Error::last_os_error());
+                    }
+                    Ok(sent as u64)
+                })
+            });
+            match res {
+                Ok(0) => anyhow::bail!("file ended at {offset}, expected at least {end} bytes"),
+                Ok(sent) => offset += sent,
+                Err(e) if e.kind() == std::io::ErrorKind::WouldBlock => continue,
+                Err(e) => return Err(e).context("error sending file data"),
+            }
+        }
+    }
+    Ok(())
+}
@@ -1,8 +1,8 @@
 use std::{
     borrow::Cow,
     collections::{HashMap, HashSet},
-    io::{BufReader, BufWriter, Read},
-    net::SocketAddr,
+    io::{BufReader, BufWriter, Read, Write},
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
     path::PathBuf,
     str::FromStr,
     sync::Arc,
@@ -10,14 +10,34 @@ use std::{
 };
 
 use crate::{
+    address_book::AddressBook,
     dht_utils::{read_metainfo_from_peer_receiver, ReadMetainfoResult},
+    dialer::{PeerDialer, ProxyIsolation},
+    disk_retry::DiskRetryPolicy,
+    disk_write_limits::DeviceWriteLimit,
+    external_ip::{ExternalIp, ExternalIpSource},
+    file_ops::FileAllocation,
+    file_selection::{
+        file_ids_matching_paths, file_priorities_matching_paths, DefaultSkipPatterns, FilePriority,
+    },
+    hash_pool::HashPool,
+    label_policy::{Label, LabelPolicy},
+    limits::{ConnectionBudget, DialLimiter, UploadCoupling},
     peer_connection::PeerConnectionOptions,
     read_buf::ReadBuf,
+    resume_data::{decode_have_pieces, encode_have_pieces, ResumeStore},
+    seed_limits::SeedLimits,
+    socks::SocksProxyConfig,
     spawn_utils::BlockingSpawner,
     torrent_state::{
-        ManagedTorrentBuilder, ManagedTorrentHandle, ManagedTorrentState, TorrentStateLive,
+        events::TorrentEvent,
+        live::{peer::PeerSource, peers::canonical_peer_addr, write_cache::WriteCacheBudget},
+        CompletionAction, FinishedPeerPolicy, ManagedTorrentBuilder, ManagedTorrentHandle,
+        ManagedTorrentState, PeerLimits, TorrentStateLive, TunableOptions,
+        DEFAULT_TARGET_DOWNLOAD_SPEED,
     },
-    type_aliases::PeerStream,
+    trusted_peers::TrustedPeers,
+    type_aliases::{PeerStream, BF},
 };
 use anyhow::{bail, Context};
 use bencode::{bencode_serialize_to_writer, BencodeDeserializer};
@@ -31,6 +51,7 @@ use futures::{
 };
 use itertools::Itertools;
 use librqbit_core::{
+    bind::{allow_connect_from_listen_port, OutgoingBind},
     directories::get_configuration_directory,
     magnet::Magnet,
     peer_id::generate_peer_id,
@@ -39,20 +60,33 @@ use librqbit_core::{
         torrent_from_bytes as bencode_torrent_from_bytes, TorrentMetaV1Info, TorrentMetaV1Owned,
     },
 };
-use parking_lot::RwLock;
+use parking_lot::{Mutex, RwLock};
 use peer_binary_protocol::Handshake;
 use serde::{Deserialize, Deserializer, Serialize, Serializer};
 use serde_with::serde_as;
-use tokio::net::{TcpListener, TcpStream};
+use sha1w::Sha1Backend;
+use tokio::{
+    net::{TcpListener, TcpStream},
+    time::timeout,
+};
 use tokio_stream::StreamExt;
-use tokio_util::sync::{CancellationToken, DropGuard};
+use tokio_util::{
+    sync::{CancellationToken, DropGuard},
+    task::{task_tracker::TaskTrackerToken, TaskTracker},
+};
 use tracing::{debug, error, error_span, info, trace, warn, Instrument};
-use tracker_comms::TrackerComms;
+use tracker_comms::{AnnounceOptions, PeerDemand, TrackerComms};
 
 pub const SUPPORTED_SCHEMES: [&str; 3] = ["http:", "https:", "magnet:"];
 
 pub type TorrentId = usize;
 
+const DEFAULT_WRITE_CACHE_BYTES: u64 = 64 * 1024 * 1024;
+const DEFAULT_MAX_HALF_OPEN_CONNECTIONS: usize = 32;
+const DEFAULT_DIALS_PER_SECOND: u32 = 20;
+// Each tracker has its own shorter timeout, this is for all of them.
+const SHUTDOWN_TRACKERS_TIMEOUT: Duration = Duration::from_secs(10);
+
 fn torrent_from_bytes(bytes: &[u8]) -> anyhow::Result<TorrentMetaV1Owned> {
     debug!(
         "all fields in torrent: {:#?}",
@@ -90,37 +124,28 @@ impl SessionDatabase {
         idx
     }
 
+    fn find_by_info_hash(&self, info_hash: Id20) -> Option<(TorrentId, ManagedTorrentHandle)> {
+        self.torrents
+            .iter()
+            .find(|(_, t)| t.info_hash() == info_hash)
+            .map(|(id, t)| (*id, t.clone()))
+    }
+
     fn serialize(&self) -> SerializedSessionDatabase {
         SerializedSessionDatabase {
             torrents: self
                 .torrents
                 .iter()
-                .map(|(id, torrent)| {
-                    (
-                        *id,
-                        SerializedTorrent {
-                            trackers: torrent
-                                .info()
-                                .trackers
-                                .iter()
-                                .map(|u| u.to_string())
-                                .collect(),
-                            info_hash: torrent.info_hash().as_string(),
-                            info: torrent.info().info.clone(),
-                            only_files: torrent.only_files.clone(),
-                            is_paused: torrent
-                                .with_state(|s| matches!(s, ManagedTorrentState::Paused(_))),
-                            output_folder: torrent.info().out_dir.clone(),
-                        },
-                    )
-                })
+                .map(|(id, torrent)| (*id, SerializedTorrent::from_torrent(torrent)))
                 .collect(),
+            label_policies: Default::default(),
         }
     }
 }
 
+#[serde_as]
 #[derive(Serialize, Deserialize)]
-struct SerializedTorrent {
+pub(crate) struct SerializedTorrent {
     info_hash: String,
     #[serde(
         serialize_with = "serialize_torrent",
@@ -128,9 +153,94 @@ struct SerializedTorrent {
     )]
     info: TorrentMetaV1Info<ByteString>,
     trackers: HashSet<String>,
-    output_folder: PathBuf,
+    pub(crate) output_folder: PathBuf,
     only_files: Option<Vec<usize>>,
+    #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+    file_priorities: HashMap<usize, FilePriority>,
     is_paused: bool,
+    #[serde_as(as = "Option<serde_with::DurationSeconds>")]
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    force_tracker_interval: Option<Duration>,
+    #[serde_as(as = "Option<serde_with::DurationSeconds>")]
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    peer_request_timeout: Option<Duration>,
+    #[serde(default)]
+    announce_options: AnnounceOptions,
+    #[serde(default)]
+    peer_opts: PeerConnectionOptions,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    download_rate_limit: Option<u64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    upload_rate_limit: Option<u64>,
+    #[serde(default)]
+    finished_peer_policy: FinishedPeerPolicy,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    upload_coupling: Option<UploadCoupling>,
+    #[serde(default)]
+    completion_action: CompletionAction,
+    #[serde(default)]
+    peer_limits: PeerLimits,
+    #[serde(default)]
+    seed_limits: SeedLimits,
+    #[serde(default)]
+    file_allocation: FileAllocation,
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    download_disabled: bool,
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    upload_disabled: bool,
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    paused_out_of_space: bool,
+    // The verified pieces, base64-encoded.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    have_pieces: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) label: Option<String>,
+}
+
+impl SerializedTorrent {
+    // Saving one torrent can't fail, so that it doesn't keep the others from being saved.
+    pub(crate) fn from_torrent(torrent: &ManagedTorrentHandle) -> Self {
+        let options = &torrent.info().options;
+        let tunable = torrent.tunable_options();
+        let have_pieces = torrent.have_pieces_snapshot();
+        SerializedTorrent {
+            trackers: torrent
+                .info()
+                .trackers
+                .iter()
+                .map(|u| u.to_string())
+                .collect(),
+            info_hash: torrent.info_hash().as_string(),
+            info: torrent.info().info.clone(),
+            only_files: torrent.only_files(),
+            file_priorities: torrent.file_priorities(),
+            is_paused: torrent.with_state(|s| matches!(s, ManagedTorrentState::Paused(_))),
+            output_folder: torrent.info().out_dir(),
+            force_tracker_interval: tunable.force_tracker_interval,
+            peer_request_timeout: tunable.peer_request_timeout,
+            announce_options: options.announce_options,
+            peer_opts: PeerConnectionOptions {
+                connect_timeout: tunable.peer_connect_timeout,
+                read_write_timeout: tunable.peer_read_write_timeout,
+                randomize_fingerprint: options.peer_randomize_fingerprint,
+                socket: options.peer_socket_options,
+                ..Default::default()
+            },
+            download_rate_limit: tunable.download_rate_limit,
+            upload_rate_limit: tunable.upload_rate_limit,
+            finished_peer_policy: options.finished_peer_policy,
+            upload_coupling: options.upload_coupling,
+            completion_action: options.completion_action,
+            peer_limits: torrent.peer_limits(),
+            seed_limits: torrent.seed_limits(),
+            file_allocation: options.file_allocation,
+            download_disabled: !torrent.is_download_enabled(),
+            upload_disabled: !torrent.is_upload_enabled(),
+            paused_out_of_space: torrent.paused_out_of_space_since().is_some(),
+            have_pieces: have_pieces.as_ref().map(encode_have_pieces),
+            label: torrent.info().label.clone(),
+        }
+    }
 }
 
 fn serialize_torrent<S>(t: &TorrentMetaV1Info<ByteString>, serializer: S) -> Result<S::Ok, S::Error>
@@ -162,20 +272,54 @@ where
 #[derive(Serialize, Deserialize)]
 struct SerializedSessionDatabase {
     torrents: HashMap<usize, SerializedTorrent>,
+    #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+    label_policies: HashMap<String, LabelPolicy>,
 }
 
 pub struct Session {
     peer_id: Id20,
     dht: Option<Dht>,
+    persistence: bool,
     persistence_filename: PathBuf,
+    // Serializes writes of the session file.
+    persistence_lock: Mutex<()>,
+    resume_store: Option<Arc<ResumeStore>>,
     peer_opts: PeerConnectionOptions,
     spawner: BlockingSpawner,
     db: RwLock<SessionDatabase>,
+    pub(crate) labels: RwLock<HashMap<String, Label>>,
+    // Keyed by device id.
+    pub(crate) device_write_limits: RwLock<HashMap<u64, DeviceWriteLimit>>,
+    pub(crate) write_cache_budget: Option<Arc<WriteCacheBudget>>,
+    connection_budget: Arc<ConnectionBudget>,
+    dial_limiter: Arc<DialLimiter>,
+    dialer: Arc<PeerDialer>,
+    bind: OutgoingBind,
+    tracker_http_client: reqwest::Client,
+    external_ip: Arc<ExternalIp>,
+    trusted_peers: Option<Arc<TrustedPeers>>,
+    disk_retry_policy: DiskRetryPolicy,
+    hash_pool: Arc<HashPool>,
+    // Where received chunks are written. On blocking threads if io_uring isn't available.
+    #[cfg(all(target_os = "linux", feature = "io-uring"))]
+    uring: Option<Arc<crate::uring::UringWriter>>,
+    target_download_speed: u64,
+    default_skip_paths: RwLock<Vec<String>>,
+    // From the address book imported at startup.
+    pub(crate) imported_peers: HashMap<Id20, Vec<SocketAddr>>,
     output_folder: PathBuf,
 
     tcp_listen_port: Option<u16>,
+    announce_port: Option<u16>,
+    // The port outgoing connections to peers behind NATs are made from, see
+    // torrent_state::live::holepunch. None if we don't take part in holepunching.
+    holepunch_port: Option<u16>,
 
     cancellation_token: CancellationToken,
+    // The trackers of all torrents, and the "stopped" announces sent when they stop.
+    tracker_tasks: TaskTracker,
+    // Torrents finish on the hasher threads, which aren't part of the runtime.
+    runtime: tokio::runtime::Handle,
 
     // This is stored for all tasks to stop when session is dropped.
     _cancellation_token_drop_guard: DropGuard,
@@ -217,37 +361,87 @@ fn compute_only_files_regex<ByteBuf: AsRef<[u8]>>(
 
 fn compute_only_files(
     info: &TorrentMetaV1Info<ByteString>,
-    only_files: Option<Vec<usize>>,
-    only_files_regex: Option<String>,
-    list_only: bool,
+    opts: &AddTorrentOptions,
+    default_skip: &DefaultSkipPatterns,
 ) -> anyhow::Result<Option<Vec<usize>>> {
-    match (only_files, only_files_regex) {
-        (Some(_), Some(_)) => {
-            bail!("only_files and only_files_regex are mutually exclusive");
+    let AddTorrentOptions {
+        only_files,
+        only_files_regex,
+        only_files_paths,
+        skip_files,
+        skip_files_paths,
+        ignore_default_skip_paths,
+        list_only,
+        ..
+    } = opts;
+    let only_files = only_files.clone();
+    let only_files_regex = only_files_regex.clone();
+    let mut skip_files = skip_files.clone();
+    let total_files = info.iter_file_lengths()?.count();
+    let check_range = |ids: &[usize]| -> anyhow::Result<()> {
+        for id in ids.iter().copied() {
+            if id >= total_files {
+                bail!("file id {} is out of range", id);
+            }
         }
-        (Some(only_files), None) => {
-            let total_files = info.iter_file_lengths()?.count();
-            for id in only_files.iter().copied() {
-                if id >= total_files {
-                    bail!("file id {} is out of range", id);
-                }
+        Ok(())
+    };
+
+    let selected = match (only_files, only_files_regex, only_files_paths) {
+        (Some(_), Some(_), _) | (Some(_), _, Some(_)) | (_, Some(_), Some(_)) => {
+            bail!("only_files, only_files_regex and only_files_paths are mutually exclusive");
+        }
+        (Some(only_files), None, None) => {
+            check_range(&only_files)?;
+            Some(only_files)
+        }
+        (None, None, Some(paths)) => {
+            let only_files = file_ids_matching_paths(info, paths)?;
+            if only_files.is_empty() {
+                bail!("none of the filenames match the given paths")
             }
-            Ok(Some(only_files))
+            Some(only_files)
         }
-        (None, Some(filename_re)) => {
+        (None, Some(filename_re), None) => {
             let only_files = compute_only_files_regex(info, &filename_re)?;
             for (idx, (filename, _)) in info.iter_filenames_and_lengths()?.enumerate() {
                 if !only_files.contains(&idx) {
                     continue;
                 }
-                if !list_only {
+                if !*list_only {
                     info!(?filename, "will download");
                 }
             }
-            Ok(Some(only_files))
+            Some(only_files)
+        }
+        (None, None, None) => None,
+    };
+
+    // The session's default exclusions don't apply to files selected explicitly, and are
+    // ignored if they would skip the whole torrent.
+    if selected.is_none() && !*ignore_default_skip_paths && !default_skip.is_empty() {
+        let ids = default_skip.file_ids(info)?;
+        if ids.len() < total_files {
+            skip_files.get_or_insert_with(Vec::new).extend(ids);
         }
-        (None, None) => Ok(None),
     }
+
+    if let Some(paths) = skip_files_paths {
+        skip_files
+            .get_or_insert_with(Vec::new)
+            .extend(file_ids_matching_paths(info, paths)?);
+    }
+    let skip_files = match skip_files {
+        Some(skip_files) if !skip_files.is_empty() => skip_files,
+        _ => return Ok(selected),
+    };
+    check_range(&skip_files)?;
+    let mut selected = selected.unwrap_or_else(|| (0..total_files).collect());
+    selected.retain(|id| !skip_files.contains(id));
+    if selected.is_empty() {
+        bail!("all files were skipped, nothing to download");
+    }
+    Ok(Some(selected))
 }
 
 fn merge_two_optional_streams<T>(
@@ -262,6 +456,9 @@ fn merge_two_optional_streams<T>(
     }
 }
 
+/// See [`AddTorrentOptions::on_completed`].
+pub type CompletionCallback = Arc<dyn Fn(TorrentId, &ManagedTorrentHandle) + Send + Sync>;
+
 /// Options for adding new torrents to the session.
 #[serde_as]
 #[derive(Default, Clone, Serialize, Deserialize)]
@@ -274,9 +471,32 @@ pub struct AddTorrentOptions {
     /// An explicit list of file IDs to download.
     /// To see the file indices, run with "list_only".
     pub only_files: Option<Vec<usize>>,
+    /// File IDs not to download. Applied on top of "only_files" or "only_files_regex".
+    /// Pieces that only contain skipped files won't be downloaded.
+    pub skip_files: Option<Vec<usize>>,
+    /// Paths within the torrent to download, e.g. whole directories.
+    /// See [crate::PathPattern] for the syntax.
+    pub only_files_paths: Option<Vec<String>>,
+    /// Paths within the torrent not to download. Applied the same way as "skip_files".
+    pub skip_files_paths: Option<Vec<String>>,
+    /// Download the files matching the session's default exclusions too, see
+    /// [`Session::set_default_skip_paths`].
+    pub ignore_default_skip_paths: bool,
+    /// Download priorities of files by file ID. Files not listed are of normal priority.
+    pub file_priorities: Option<HashMap<usize, FilePriority>>,
+    /// Download priorities of paths within the torrent, e.g. whole directories, with the
+    /// syntax of "only_files_paths". Later paths override earlier ones, and "file_priorities"
+    /// overrides both.
+    pub file_priorities_paths: Option<Vec<(String, FilePriority)>>,
     /// Allow writing on top of existing files, including when resuming a torrent.
     /// You probably want to set it, however for safety it's not default.
     pub overwrite: bool,
+    /// Check all pieces of existing files, even if their sizes and modification times match
+    /// the ones saved when the torrent last ran.
+    pub force_recheck: bool,
+    /// How to allocate disk space for the selected files when the torrent starts. Sparse
+    /// by default.
+    pub file_allocation: FileAllocation,
     /// Only list the files in the torrent without starting it.
     pub list_only: bool,
     /// The output folder for the torrent. If not set, the session's default one will be used.
@@ -286,16 +506,54 @@ pub struct AddTorrentOptions {
     pub sub_folder: Option<String>,
     /// Peer connection options, timeouts etc. If not set, session's defaults will be used.
     pub peer_opts: Option<PeerConnectionOptions>,
+    /// What to do with connections to seeds once the torrent finishes downloading.
+    /// By default they are disconnected.
+    pub finished_peer_policy: Option<FinishedPeerPolicy>,
+    /// Upload no more than a multiple of what was downloaded until the torrent is finished.
+    pub upload_coupling: Option<UploadCoupling>,
+    /// What to do once the torrent finishes downloading. It keeps seeding by default.
+    pub completion_action: CompletionAction,
+    /// Called once the torrent finishes downloading, after the completion action. Not kept in
+    /// the session file, torrents restored from it don't have it.
+    #[serde(skip)]
+    pub on_completed: Option<CompletionCallback>,
+    /// Connection limits of the torrent. Can be changed later with
+    /// [`crate::ManagedTorrent::set_peer_limits`].
+    pub peer_limits: PeerLimits,
+    /// When to stop seeding. Can be changed later with
+    /// [`crate::ManagedTorrent::set_seed_limits`].
+    pub seed_limits: SeedLimits,
+
+    /// Download and upload limits of the torrent alone, in bytes per second. Can be changed
+    /// later with [`crate::ManagedTorrent::set_tunable_options`], as can the peer timeouts and
+    /// the tracker interval.
+    pub download_rate_limit: Option<u64>,
+    pub upload_rate_limit: Option<u64>,
 
     /// Force a refresh interval for polling trackers.
     #[serde_as(as = "Option<serde_with::DurationSeconds>")]
     pub force_tracker_interval: Option<Duration>,
+    /// Cancel chunk requests peers didn't answer for this long, 60 seconds if not set.
+    #[serde_as(as = "Option<serde_with::DurationSeconds>")]
+    pub peer_request_timeout: Option<Duration>,
+    /// How many peers to ask trackers for, and bounds of the announce interval.
+    pub announce_options: AnnounceOptions,
 
     pub disable_trackers: bool,
 
+    /// Don't request pieces, only seed what's already there. Can be changed later with
+    /// [`crate::ManagedTorrent::set_download_enabled`].
+    pub disable_download: bool,
+    /// Don't upload to peers. Can be changed later with [`crate::ManagedTorrent::set_upload_enabled`].
+    pub disable_upload: bool,
+
     /// Initial peers to start of with.
     pub initial_peers: Option<Vec<SocketAddr>>,
 
+    /// The torrent gets the limits and defaults of the label's policy, see
+    /// [`Session::set_label_policy`].
+    pub label: Option<String>,
+
     /// This is used to restore the session from serialized state.
     #[serde(skip)]
     pub preferred_id: Option<usize>,
@@ -392,6 +650,15 @@ pub struct SessionOptions {
     /// Pass in to configure DHT persistence filename. This can be used to run multiple
     /// librqbit instances at a time.
     pub dht_config: Option<PersistentDhtConfig>,
+    /// Extra DHT bootstrap nodes as "host:port", used in addition to the default ones.
+    pub dht_bootstrap_nodes: Vec<String>,
+    /// Turn on to not bootstrap DHT from the default public nodes. With no other bootstrap
+    /// nodes, DHT will only learn about nodes that query it.
+    pub disable_dht_default_bootstrap: bool,
+    /// Turn on to announce to DHT with implied_port, so that nodes record the UDP port our DHT
+    /// packets come from rather than [`Self::announce_port`]. Useful behind a NAT when peers
+    /// reach us over UDP.
+    pub dht_announce_implied_port: bool,
 
     /// Turn on to dump session contents into a file periodically, so that on next start
     /// all remembered torrents will continue where they left off.
@@ -406,13 +673,156 @@ pub struct SessionOptions {
 
     pub listen_port_range: Option<std::ops::Range<u16>>,
     pub enable_upnp_port_forwarding: bool,
+    /// The port trackers and DHT tell other peers to connect to. Defaults to the port listened
+    /// on, set it when a router forwards another external port to that one. Nothing is
+    /// announced if not listening.
+    pub announce_port: Option<u16>,
+
+    /// Write rate limits in bytes per second, keyed by a path on the limited device.
+    /// See [`Session::set_disk_write_limit`].
+    pub disk_write_limits: HashMap<PathBuf, u64>,
+
+    /// Memory for assembling received pieces and writing them in batches, shared by all
+    /// torrents. Defaults to 64 MiB, 0 writes every chunk straight to disk.
+    pub write_cache_bytes: Option<u64>,
+
+    /// DHT nodes and peers exported by another instance with
+    /// [`Session::export_address_book`], to skip the initial discovery.
+    pub address_book: Option<AddressBook>,
+
+    /// Files not to download in any torrent, unless ignored when adding it. See
+    /// [`Session::set_default_skip_paths`].
+    pub default_skip_paths: Vec<String>,
+
+    /// How failed disk reads and writes are retried. Defaults to a few retries within
+    /// seconds.
+    pub disk_retry_policy: Option<DiskRetryPolicy>,
+
+    /// Threads to verify downloaded pieces on, shared by all torrents. Defaults to the number
+    /// of CPUs, up to 4.
+    pub hashing_threads: Option<usize>,
+
+    /// Download speed in bytes per second. Slower torrents with room for more connections look
+    /// for more peers: trackers are announced to early, DHT lookups are widened and dead peers
+    /// are retried sooner. Defaults to 256 KiB/s.
+    pub target_download_speed: Option<u64>,
+
+    /// The SHA-1 implementation to verify pieces with, out of the compiled-in ones. Default
+    /// builds only have [`Sha1Backend::System`], so this does nothing unless the "sha1-openssl"
+    /// or "sha1-rust" features are enabled too. Defaults to the fastest one on this machine.
+    /// This is process-wide: it applies to all sessions.
+    pub sha1_backend: Option<Sha1Backend>,
+
+    /// Connections to peers of all the torrents together, on top of the limits of each
+    /// torrent. Shared fairly between the live torrents. Not limited by default.
+    pub connection_budget: Option<usize>,
+
+    /// Outgoing connections being established at the same time, of all the torrents
+    /// together. Defaults to 32, 0 doesn't limit.
+    pub max_half_open_connections: Option<usize>,
+    /// How many outgoing connections can be started per second. Defaults to 20, 0 doesn't
+    /// limit.
+    pub dials_per_second: Option<u32>,
+
+    /// Connect to peers through a SOCKS5 proxy, e.g. a VPN gateway or Tor:
+    /// "socks5://[username:password@]host:port". Peer connections and HTTP trackers (unless
+    /// tracker_proxy_url is set) go through it. DHT, UDP trackers and listening can't, so they
+    /// are disabled, not to give away our address.
+    pub socks_proxy_url: Option<String>,
+    /// Use separate SOCKS5 credentials per torrent or per peer, so that Tor puts them on
+    /// separate circuits. A token is appended to the username, so the proxy must accept any
+    /// username, like Tor does. Only peer connections are isolated, HTTP trackers share one
+    /// circuit.
+    pub socks_proxy_isolation: ProxyIsolation,
+
+    /// Announce to HTTP(S) trackers through this proxy, e.g. "http://proxy.lan:3128".
+    /// Independent of the proxy for peer connections.
+    pub tracker_proxy_url: Option<String>,
+    /// Don't pick up the proxy for HTTP(S) trackers from the HTTP_PROXY, HTTPS_PROXY and
+    /// ALL_PROXY environment variables, which is the default when tracker_proxy_url isn't set.
+    pub tracker_ignore_system_proxy: bool,
+
+    /// Connect to peers and trackers from this local address. Peers and trackers of the other
+    /// IP version are unreachable then.
+    pub bind_address: Option<IpAddr>,
+    /// Connect to peers and trackers, listen and run the DHT only through this network
+    /// interface, e.g. "wg0". Linux only. HTTP trackers can only be bound with bind_address, so
+    /// it has to be set too unless the HTTP trackers use tracker_proxy_url.
+    pub bind_device: Option<String>,
+
+    /// Our public IP address. It's told to trackers and the DHT node ID is derived from it
+    /// (BEP 42). If not set, it's detected from what trackers and peers report.
+    pub external_ip: Option<IpAddr>,
+
+    /// Only ever connect to these peers, and only accept connections from their IPs, e.g. to
+    /// replicate torrents between one's own machines. They are connected to in every torrent,
+    /// peers from trackers, DHT and other torrent options are ignored. Trackers and DHT are
+    /// still announced to unless disabled.
+    pub trusted_peers: Option<Vec<SocketAddr>>,
+
+    /// Take part in holepunching (BEP 55): relaying rendezvous between peers behind NATs, and
+    /// connecting to the peers relays ask us to from the port we listen on. Off by default, as
+    /// it needs SO_REUSEPORT on the listener, which lets other programs of the same user listen
+    /// on the port too.
+    pub enable_holepunch: bool,
+}
+
+fn tracker_http_client(opts: &SessionOptions) -> anyhow::Result<reqwest::Client> {
+    let mut builder = reqwest::Client::builder();
+    if let Some(url) = &opts.tracker_proxy_url {
+        let proxy = reqwest::Proxy::all(url)
+            .with_context(|| format!("invalid tracker proxy URL {url:?}"))?;
+        info!("announcing to HTTP trackers through a proxy");
+        builder = builder.proxy(proxy);
+    } else if let Some(url) = &opts.socks_proxy_url {
+        // socks5h resolves the tracker's name through the proxy too.
+        let url = match url.strip_prefix("socks5://") {
+            Some(rest) => format!("socks5h://{rest}"),
+            None => url.clone(),
+        };
+        let proxy = reqwest::Proxy::all(url).context("invalid SOCKS5 proxy")?;
+        builder = builder.proxy(proxy);
+    } else if opts.tracker_ignore_system_proxy {
+        builder = builder.no_proxy();
+    }
+    if let Some(address) = opts.bind_address {
+        builder = builder.local_address(address);
+    }
+    builder
+        .build()
+        .context("error building HTTP client for trackers")
+}
+
+// On both IPv6 and IPv4 if possible, only on IPv4 if IPv6 is disabled.
+fn bind_dual_stack(port: u16, outgoing: &OutgoingBind) -> std::io::Result<TcpListener> {
+    use socket2::{Domain, Protocol, SockRef, Socket, Type};
+
+    let bind = |domain, addr: SocketAddr| -> std::io::Result<TcpListener> {
+        let socket = Socket::new(domain, Type::STREAM, Some(Protocol::TCP))?;
+        // Accepted connections inherit the buffer sizes.
+        outgoing.configure_tcp(SockRef::from(&socket))?;
+        if domain == Domain::IPV6 {
+            socket.set_only_v6(false)?;
+        }
+        #[cfg(unix)]
+        socket.set_reuse_address(true)?;
+        socket.set_nonblocking(true)?;
+        socket.bind(&addr.into())?;
+        socket.listen(1024)?;
+        TcpListener::from_std(socket.into())
+    };
+    bind(Domain::IPV6, (Ipv6Addr::UNSPECIFIED, port).into()).or_else(|e| {
+        debug!("error listening on IPv6 port {port}, trying IPv4 only: {e:#}");
+        bind(Domain::IPV4, (Ipv4Addr::UNSPECIFIED, port).into())
+    })
 }
 
 async fn create_tcp_listener(
     port_range: std::ops::Range<u16>,
+    bind: &OutgoingBind,
 ) -> anyhow::Result<(TcpListener, u16)> {
     for port in port_range.clone() {
-        match TcpListener::bind(("0.0.0.0", port)).await {
+        match bind_dual_stack(port, bind) {
             Ok(l) => return Ok((l, port)),
             Err(e) => {
                 debug!("error listening on port {port}: {e:#}")
@@ -455,28 +865,135 @@ impl Session {
             let peer_id = opts.peer_id.unwrap_or_else(generate_peer_id);
             let token = CancellationToken::new();
 
+            if let Some(backend) = opts.sha1_backend {
+                sha1w::set_sha1_backend(backend).map_err(anyhow::Error::msg)?;
+            }
+            info!(sha1_backend = %sha1w::sha1_backend(), "verifying pieces with SHA-1");
+
+            let socket_opts = opts.peer_opts.unwrap_or_default().socket;
+            let bind = OutgoingBind {
+                address: opts.bind_address,
+                device: opts.bind_device.clone(),
+                send_buffer_size: socket_opts.send_buffer_size,
+                recv_buffer_size: socket_opts.recv_buffer_size,
+            };
+            if bind.device.is_some() && bind.address.is_none() && opts.tracker_proxy_url.is_none()
+            {
+                bail!("HTTP trackers can't be bound to the network interface, set the bind address too");
+            }
+            let dialer = Arc::new(PeerDialer {
+                proxy: match &opts.socks_proxy_url {
+                    Some(url) => {
+                        let proxy = SocksProxyConfig::parse(url).context("invalid SOCKS5 proxy")?;
+                        info!(proxy = %proxy.addr, "connecting to peers through a SOCKS5 proxy");
+                        Some(proxy)
+                    }
+                    None => None,
+                },
+                bind: bind.clone(),
+                isolation: opts.socks_proxy_isolation,
+                isolation_secret: rand::random(),
+            });
+            if dialer.proxy.is_some() {
+                if !opts.disable_dht {
+                    info!("DHT can't go through the SOCKS5 proxy, disabling it");
+                    opts.disable_dht = true;
+                }
+                if opts.listen_port_range.take().is_some() {
+                    info!("not listening for peer connections, as they would bypass the SOCKS5 proxy");
+                }
+            }
+            let tracker_http_client = tracker_http_client(&opts)?;
+
             let (tcp_listener, tcp_listen_port) = if let Some(port_range) = opts.listen_port_range {
-                let (l, p) = create_tcp_listener(port_range)
+                let (l, p) = create_tcp_listener(port_range, &bind)
                     .await
                     .context("error listening on TCP")?;
-                info!("Listening on 0.0.0.0:{p} for incoming peer connections");
+                info!(
+                    "Listening on {} for incoming peer connections",
+                    l.local_addr().context("error getting listen address")?
+                );
                 (Some(l), Some(p))
             } else {
                 (None, None)
             };
+            let announce_port = match (tcp_listen_port, opts.announce_port) {
+                (Some(_), Some(port)) => {
+                    info!(port, "announcing a different port than the one listened on");
+                    Some(port)
+                }
+                (None, Some(_)) => {
+                    warn!("not listening for peer connections, ignoring the announce port");
+                    None
+                }
+                (listen_port, None) => listen_port,
+            };
+            // Connections through the proxy can't come from our port.
+            let holepunch_port = match (&tcp_listener, tcp_listen_port) {
+                (Some(l), Some(port)) if opts.enable_holepunch && dialer.proxy.is_none() => {
+                    match allow_connect_from_listen_port(socket2::SockRef::from(l)) {
+                        Ok(()) => Some(port),
+                        Err(e) => {
+                            debug!("not holepunching: {e:#}");
+                            None
+                        }
+                    }
+                }
+                _ => None,
+            };
 
             let dht = if opts.disable_dht {
                 None
             } else {
+                let imported_nodes = opts
+                    .address_book
+                    .as_ref()
+                    .map(|b| b.dht_bootstrap_addrs())
+                    .unwrap_or_default();
+                // None keeps the DHT defaults (or the ones from the persisted config).
+                let bootstrap_addrs = if opts.disable_dht_default_bootstrap
+                    || !opts.dht_bootstrap_nodes.is_empty()
+                    || !imported_nodes.is_empty()
+                {
+                    let defaults = if opts.disable_dht_default_bootstrap {
+                        &[][..]
+                    } else {
+                        dht::DHT_BOOTSTRAP
+                    };
+                    Some(
+                        defaults
+                            .iter()
+                            .map(|s| s.to_string())
+                            .chain(opts.dht_bootstrap_nodes.iter().cloned())
+                            .chain(imported_nodes)
+                            .collect::<Vec<_>>(),
+                    )
+                } else {
+                    None
+                };
                 let dht = if opts.disable_dht_persistence {
                     DhtBuilder::with_config(DhtConfig {
+                        peer_id: opts.external_ip.map(dht::node_id_for_ip),
                         cancellation_token: Some(token.child_token()),
+                        bootstrap_addrs,
+                        announce_implied_port: opts.dht_announce_implied_port,
+                        bind_device: opts.bind_device.clone(),
                         ..Default::default()
                     })
                     .await
                     .context("error initializing DHT")?
                 } else {
-                    let pdht_config = opts.dht_config.take().unwrap_or_default();
+                    let mut pdht_config = opts.dht_config.take().unwrap_or_default();
+                    if bootstrap_addrs.is_some() {
+                        pdht_config.bootstrap_addrs = bootstrap_addrs;
+                    }
+                    if opts.external_ip.is_some() {
+                        pdht_config.external_ip = opts.external_ip;
+                    }
+                    if opts.dht_announce_implied_port {
+                        pdht_config.announce_implied_port = true;
+                    }
+                    pdht_config.bind_device = opts.bind_device.clone();
                     PersistentDht::create(Some(pdht_config), Some(token.clone()))
                         .await
                         .context("error initializing persistent DHT")?
@@ -485,25 +1002,94 @@ impl Session {
                 Some(dht)
             };
             let peer_opts = opts.peer_opts.unwrap_or_default();
+            DefaultSkipPatterns::parse(&opts.default_skip_paths)
+                .context("invalid default skip paths")?;
             let persistence_filename = match opts.persistence_filename {
                 Some(filename) => filename,
                 None => Self::default_persistence_filename()?,
             };
             let spawner = BlockingSpawner::default();
+            let resume_store = if opts.persistence {
+                let dir = persistence_filename
+                    .parent()
+                    .map(|p| p.join("resume"))
+                    .unwrap_or_else(|| PathBuf::from("resume"));
+                Some(Arc::new(ResumeStore::new(dir)?))
+            } else {
+                None
+            };
 
             let session = Arc::new(Self {
+                persistence: opts.persistence,
                 persistence_filename,
+                persistence_lock: Default::default(),
+                resume_store,
                 peer_id,
                 dht,
                 peer_opts,
                 spawner,
                 output_folder,
                 db: RwLock::new(Default::default()),
+                labels: Default::default(),
+                device_write_limits: Default::default(),
+                default_skip_paths: RwLock::new(opts.default_skip_paths),
+                disk_retry_policy: opts.disk_retry_policy.unwrap_or_default(),
+                target_download_speed: opts
+                    .target_download_speed
+                    .unwrap_or(DEFAULT_TARGET_DOWNLOAD_SPEED),
+                hash_pool: Arc::new(HashPool::new(
+                    opts.hashing_threads
+                        .unwrap_or_else(HashPool::default_threads),
+                )?),
+                #[cfg(all(target_os = "linux", feature = "io-uring"))]
+                uring: match crate::uring::UringWriter::new() {
+                    Ok(writer) => Some(Arc::new(writer)),
+                    Err(e) => {
+                        warn!("writing chunks on blocking threads: {e:#}");
+                        None
+                    }
+                },
+                imported_peers: opts
+                    .address_book
+                    .as_ref()
+                    .map(|b| b.peers_by_info_hash())
+                    .unwrap_or_default(),
+                write_cache_budget: match opts
+                    .write_cache_bytes
+                    .unwrap_or(DEFAULT_WRITE_CACHE_BYTES)
+                {
+                    0 => None,
+                    bytes => Some(Arc::new(WriteCacheBudget::new(bytes))),
+                },
+                connection_budget: ConnectionBudget::new(opts.connection_budget),
+                dial_limiter: Arc::new(DialLimiter::new(
+                    opts.max_half_open_connections
+                        .unwrap_or(DEFAULT_MAX_HALF_OPEN_CONNECTIONS),
+                    opts.dials_per_second.unwrap_or(DEFAULT_DIALS_PER_SECOND),
+                )),
+                dialer,
+                bind,
+                tracker_http_client,
+                external_ip: Arc::new(ExternalIp::new(opts.external_ip)),
+                trusted_peers: opts
+                    .trusted_peers
+                    .as_deref()
+                    .map(|addrs| Arc::new(TrustedPeers::new(addrs))),
                 _cancellation_token_drop_guard: token.clone().drop_guard(),
                 cancellation_token: token,
+                tracker_tasks: TaskTracker::new(),
+                runtime: tokio::runtime::Handle::current(),
                 tcp_listen_port,
+                announce_port,
+                holepunch_port,
             });
 
+            for (path, bytes_per_sec) in &opts.disk_write_limits {
+                session
+                    .set_disk_write_limit(path, Some(*bytes_per_sec))
+                    .with_context(|| format!("error setting disk write limit for {path:?}"))?;
+            }
+
             if let Some(tcp_listener) = tcp_listener {
                 session.spawn(
                     error_span!("tcp_listen", port = tcp_listen_port),
@@ -520,6 +1106,16 @@ impl Session {
                 }
             }
 
+            session.spawn(
+                error_span!("label_policies"),
+                Self::task_label_policies(Arc::downgrade(&session)),
+            );
+
+            session.spawn(
+                error_span!("resume_out_of_space"),
+                Self::task_resume_out_of_space(Arc::downgrade(&session)),
+            );
+
             if opts.persistence {
                 info!(
                     "will use {:?} for session persistence",
@@ -562,6 +1158,19 @@ impl Session {
         Ok(())
     }
 
+    /// Snapshot the progress of all torrents to disk, e.g. before shutting down. Does nothing if
+    /// persistence is disabled.
+    pub fn save_resume_data(&self) -> anyhow::Result<()> {
+        let torrents =
+            self.with_torrents(|torrents| torrents.map(|(_, t)| t.clone()).collect::<Vec<_>>());
+        for torrent in torrents {
+            torrent.save_resume_data().with_context(|| {
+                format!("error saving resume data for {:?}", torrent.info_hash())
+            })?;
+        }
+        Ok(())
+    }
+
     async fn check_incoming_connection(
         &self,
         addr: SocketAddr,
@@ -622,6 +1231,15 @@ impl Session {
                 r = l.accept() => {
                     match r {
                         Ok((stream, addr)) => {
+                            let addr = canonical_peer_addr(addr);
+                            let trusted = self
+                                .trusted_peers
+                                .as_ref()
+                                .is_none_or(|t| t.contains_ip(addr.ip()));
+                            if !trusted {
+                                debug!("ignoring connection from untrusted peer {addr}");
+                                continue;
+                            }
                             trace!("accepted connection from {addr}");
                             futs.push(
                                 self.check_incoming_connection(addr, stream)
@@ -656,6 +1274,22 @@ impl Session {
         self.dht.as_ref()
     }
 
+    /// Patterns of files not to download when adding any torrent, e.g. "*.nfo" or "sample".
+    /// A pattern without "/" matches the name of a file or of any of its parent directories,
+    /// other ones match the path within the torrent, see [crate::PathPattern] for the syntax.
+    ///
+    /// They don't apply if files are selected explicitly, or if they match all files. Adding
+    /// with "ignore_default_skip_paths" turns them off for one torrent.
+    pub fn set_default_skip_paths(&self, patterns: Vec<String>) -> anyhow::Result<()> {
+        DefaultSkipPatterns::parse(&patterns)?;
+        *self.default_skip_paths.write() = patterns;
+        Ok(())
+    }
+
+    pub fn default_skip_paths(&self) -> Vec<String> {
+        self.default_skip_paths.read().clone()
+    }
+
     fn merge_peer_opts(&self, other: Option<PeerConnectionOptions>) -> PeerConnectionOptions {
         let other = match other {
             Some(o) => o,
@@ -669,6 +1303,9 @@ impl Session {
             keep_alive_interval: other
                 .keep_alive_interval
                 .or(self.peer_opts.keep_alive_interval),
+            randomize_fingerprint: other.randomize_fingerprint
+                || self.peer_opts.randomize_fingerprint,
+            socket: other.socket.or(self.peer_opts.socket),
         }
     }
 
@@ -681,7 +1318,52 @@ impl Session {
         spawn_with_cancel(span, self.cancellation_token.clone(), fut);
     }
 
-    /// Stop the session and all managed tasks.
+    /// Stop the session gracefully: stop accepting peers and all torrent tasks, write the pieces
+    /// still in memory to disk and sync them, save the session file and resume data, and send
+    /// "stopped" announces to the trackers. Resolves when all of it is done, or when the trackers
+    /// took too long to answer.
+    ///
+    /// The torrents are paused afterwards, but the session file remembers their state before.
+    pub async fn shutdown(&self) -> anyhow::Result<()> {
+        self.cancellation_token.cancel();
+
+        let mut result = Ok(());
+        if self.persistence {
+            if let Err(e) = self.dump_to_disk() {
+                warn!("error dumping session to disk: {e:#}");
+                result = Err(e);
+            }
+        }
+
+        let torrents =
+            self.with_torrents(|torrents| torrents.map(|(_, t)| t.clone()).collect::<Vec<_>>());
+        for torrent in torrents {
+            let r = match torrent.live() {
+                Some(live) => live.sync_files().and_then(|_| torrent.pause()),
+                None => torrent.save_resume_data(),
+            };
+            if let Err(e) = r {
+                let e = e.context(format!("error stopping torrent {:?}", torrent.info_hash()));
+                warn!("{e:#}");
+                if result.is_ok() {
+                    result = Err(e);
+                }
+            }
+        }
+
+        // Stopped torrents drop their trackers, which send the "stopped" announces.
+        self.tracker_tasks.close();
+        if timeout(SHUTDOWN_TRACKERS_TIMEOUT, self.tracker_tasks.wait())
+            .await
+            .is_err()
+        {
+            warn!("gave up waiting for stopped announces to trackers");
+        }
+        result
+    }
+
+    /// Stop the session and all managed tasks. Prefer [`Session::shutdown`], which waits for
+    /// everything to be on disk.
     pub async fn stop(&self) {
         let torrents = self
             .db
@@ -701,7 +1383,7 @@ impl Session {
     }
 
     async fn populate_from_stored(self: &Arc<Self>) -> anyhow::Result<()> {
-        let mut rdr = match std::fs::File::open(&self.persistence_filename) {
+        let rdr = match std::fs::File::open(&self.persistence_filename) {
             Ok(f) => BufReader::new(f),
             Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(()),
             Err(e) => {
@@ -711,64 +1393,118 @@ impl Session {
                 ))
             }
         };
+        self.load_state(rdr).await
+    }
+
+    /// Restore torrents saved with [`Session::save_state`]: their output folders, options,
+    /// and progress. Verified pieces aren't re-checked, unless their files changed size since.
+    ///
+    /// Torrents that fail to be added are logged and skipped.
+    pub async fn load_state(self: &Arc<Self>, reader: impl Read) -> anyhow::Result<()> {
         let db: SerializedSessionDatabase =
-            serde_json::from_reader(&mut rdr).context("error deserializing session database")?;
-        let mut futures = Vec::new();
-        for (id, storrent) in db.torrents.into_iter() {
-            let trackers: Vec<ByteString> = storrent
-                .trackers
-                .into_iter()
-                .map(|t| ByteString(t.into_bytes()))
-                .collect();
-            let info = TorrentMetaV1Owned {
-                announce: trackers
-                    .first()
-                    .cloned()
-                    .unwrap_or_else(|| ByteString(b"http://retracker.local/announce".to_vec())),
-                announce_list: vec![trackers],
-                info: storrent.info,
-                comment: None,
-                created_by: None,
-                encoding: None,
-                publisher: None,
-                publisher_url: None,
-                creation_date: None,
-                info_hash: Id20::from_str(&storrent.info_hash)?,
-            };
-            futures.push({
-                let session = self.clone();
-                async move {
-                    session
-                        .add_torrent(
-                            AddTorrent::TorrentInfo(Box::new(info)),
-                            Some(AddTorrentOptions {
-                                paused: storrent.is_paused,
-                                output_folder: Some(
-                                    storrent
-                                        .output_folder
-                                        .to_str()
-                                        .context("broken path")?
-                                        .to_owned(),
-                                ),
-                                only_files: storrent.only_files,
-                                overwrite: true,
-                                preferred_id: Some(id),
-                                ..Default::default()
-                            }),
-                        )
-                        .await
-                        .map_err(|e| {
-                            error!("error adding torrent from stored session: {:?}", e);
-                            e
-                        })
-                }
-            });
+            serde_json::from_reader(reader).context("error deserializing session database")?;
+        for (label, policy) in db.label_policies {
+            self.set_label_policy(&label, policy);
         }
+        let futures = db.torrents.into_iter().map(|(id, storrent)| async move {
+            self.add_serialized_torrent(storrent, Some(id), false)
+                .await
+                .map_err(|e| {
+                    error!("error adding torrent from stored session: {:?}", e);
+                    e
+                })
+        });
         futures::future::join_all(futures).await;
         Ok(())
     }
 
+    pub(crate) async fn add_serialized_torrent(
+        self: &Arc<Self>,
+        storrent: SerializedTorrent,
+        preferred_id: Option<usize>,
+        force_recheck: bool,
+    ) -> anyhow::Result<AddTorrentResponse> {
+        let trackers: Vec<ByteString> = storrent
+            .trackers
+            .into_iter()
+            .map(|t| ByteString(t.into_bytes()))
+            .collect();
+        let info = TorrentMetaV1Owned {
+            announce: trackers
+                .first()
+                .cloned()
+                .unwrap_or_else(|| ByteString(b"http://retracker.local/announce".to_vec())),
+            announce_list: vec![trackers],
+            info: storrent.info,
+            comment: None,
+            created_by: None,
+            encoding: None,
+            publisher: None,
+            publisher_url: None,
+            creation_date: None,
+            info_hash: Id20::from_str(&storrent.info_hash)?,
+        };
+        let have_pieces = match storrent.have_pieces.as_deref().map(decode_have_pieces) {
+            Some(Ok(h)) => Some(h),
+            Some(Err(e)) => {
+                warn!(
+                    info_hash = storrent.info_hash,
+                    "ignoring saved progress: {:#}", e
+                );
+                None
+            }
+            None => None,
+        };
+        let paused_out_of_space = storrent.paused_out_of_space;
+        let response = self
+            .add_torrent_impl(
+                AddTorrent::TorrentInfo(Box::new(info)),
+                Some(AddTorrentOptions {
+                    paused: storrent.is_paused,
+                    output_folder: Some(
+                        storrent
+                            .output_folder
+                            .to_str()
+                            .context("broken path")?
+                            .to_owned(),
+                    ),
+                    only_files: storrent.only_files,
+                    file_priorities: Some(storrent.file_priorities),
+                    overwrite: true,
+                    force_recheck,
+                    preferred_id,
+                    force_tracker_interval: storrent.force_tracker_interval,
+                    peer_request_timeout: storrent.peer_request_timeout,
+                    announce_options: storrent.announce_options,
+                    peer_opts: Some(storrent.peer_opts),
+                    download_rate_limit: storrent.download_rate_limit,
+                    upload_rate_limit: storrent.upload_rate_limit,
+                    finished_peer_policy: Some(storrent.finished_peer_policy),
+                    upload_coupling: storrent.upload_coupling,
+                    completion_action: storrent.completion_action,
+                    peer_limits: storrent.peer_limits,
+                    seed_limits: storrent.seed_limits,
+                    file_allocation: storrent.file_allocation,
+                    disable_download: storrent.download_disabled,
+                    disable_upload: storrent.upload_disabled,
+                    label: storrent.label,
+                    // The selection was computed when the torrent was added.
+                    ignore_default_skip_paths: true,
+                    ..Default::default()
+                }),
+                have_pieces,
+            )
+            .await?;
+        if paused_out_of_space {
+            if let AddTorrentResponse::Added(_, handle) = &response {
+                handle.set_paused_out_of_space();
+            }
+        }
+        Ok(response)
+    }
+
     fn dump_to_disk(&self) -> anyhow::Result<()> {
+        let _guard = self.persistence_lock.lock();
         let tmp_filename = format!("{}.tmp", self.persistence_filename.to_str().unwrap());
         let mut tmp = BufWriter::new(
             std::fs::OpenOptions::new()
@@ -778,8 +1514,11 @@ impl Session {
                 .open(&tmp_filename)
                 .with_context(|| format!("error opening {:?}", tmp_filename))?,
         );
-        let serialized = self.db.read().serialize();
-        serde_json::to_writer(&mut tmp, &serialized).context("error serializing")?;
+        self.save_state(&mut tmp)?;
+        tmp.flush().context("error flushing session file")?;
+        tmp.get_ref()
+            .sync_all()
+            .context("error syncing session file")?;
         drop(tmp);
 
         std::fs::rename(&tmp_filename, &self.persistence_filename)
@@ -788,6 +1527,14 @@ impl Session {
         Ok(())
     }
 
+    /// Serialize all managed torrents, their output folders, options and progress, to be
+    /// restored later with [`Session::load_state`].
+    pub fn save_state(&self, writer: impl Write) -> anyhow::Result<()> {
+        let mut serialized = self.db.read().serialize();
+        serialized.label_policies = self.label_policies();
+        serde_json::to_writer(writer, &serialized).context("error serializing")
+    }
+
     /// Run a callback given the currently managed torrents.
     pub fn with_torrents<R>(
         &self,
@@ -802,17 +1549,27 @@ impl Session {
         self: &'a Arc<Self>,
         add: AddTorrent<'a>,
         opts: Option<AddTorrentOptions>,
+    ) -> BoxFuture<'a, anyhow::Result<AddTorrentResponse>> {
+        self.add_torrent_impl(add, opts, None)
+    }
+
+    // "have_pieces" is the progress restored from a saved session.
+    fn add_torrent_impl<'a>(
+        self: &'a Arc<Self>,
+        add: AddTorrent<'a>,
+        opts: Option<AddTorrentOptions>,
+        have_pieces: Option<BF>,
     ) -> BoxFuture<'a, anyhow::Result<AddTorrentResponse>> {
         async move {
             // Magnet links are different in that we first need to discover the metadata.
             let span = error_span!("add_torrent");
             let _ = span.enter();
 
-            let opts = opts.unwrap_or_default();
+            let mut opts = opts.unwrap_or_default();
 
             let paused = opts.list_only || opts.paused;
 
-            let announce_port = if paused { None } else { self.tcp_listen_port };
+            let announce_port = if paused { None } else { self.announce_port };
 
             // The main difference between magnet link and torrent file, is that we need to resolve the magnet link
             // into a torrent file by connecting to peers that support extended handshakes.
@@ -825,15 +1582,25 @@ impl Session {
                     let info_hash = magnet
                         .as_id20()
                         .context("magnet link didn't contain a BTv1 infohash")?;
+                    // Don't fetch the metadata of a torrent that's already there.
+                    if !opts.list_only {
+                        if let Some((id, handle)) = self.find_by_info_hash(info_hash) {
+                            return Ok(AddTorrentResponse::AlreadyManaged(id, handle));
+                        }
+                    }
 
                     let peer_rx = self.make_peer_rx(
                         info_hash,
                         magnet.trackers.clone(),
                         announce_port,
                         opts.force_tracker_interval,
+                        opts.announce_options,
                     )?;
+                    let initial_peers = self.initial_peers(info_hash, opts.initial_peers.clone());
                     let peer_rx = match peer_rx {
                         Some(peer_rx) => peer_rx,
+                        // The metadata can still be fetched from the peers given explicitly.
+                        None if !initial_peers.is_empty() => Box::pin(futures::stream::empty()),
                         None => bail!("can't find peers: DHT disabled and no trackers in magnet"),
                     };
 
@@ -841,9 +1608,10 @@ impl Session {
                     let (info, peer_rx, initial_peers) = match read_metainfo_from_peer_receiver(
                         self.peer_id,
                         info_hash,
-                        opts.initial_peers.clone().unwrap_or_default(),
+                        initial_peers,
                         peer_rx,
                         Some(self.merge_peer_opts(opts.peer_opts)),
+                        Some(self.dialer.clone()),
                     )
                     .await
                     {
@@ -853,6 +1621,13 @@ impl Session {
                         }
                     };
                     debug!(?info, "received result from DHT");
+                    // Files selected explicitly take precedence over the link's.
+                    if opts.only_files.is_none()
+                        && opts.only_files_regex.is_none()
+                        && opts.only_files_paths.is_none()
+                    {
+                        opts.only_files = magnet.select_only(info.iter_file_lengths()?.count());
+                    }
                     (
                         info_hash,
                         info,
@@ -900,6 +1675,7 @@ impl Session {
                             trackers.clone(),
                             announce_port,
                             opts.force_tracker_interval,
+                            opts.announce_options,
                         )?
                     };
 
@@ -908,10 +1684,9 @@ impl Session {
                         torrent.info,
                         trackers,
                         peer_rx,
-                        opts.initial_peers
-                            .clone()
-                            .unwrap_or_default()
+                        self.initial_peers(torrent.info_hash, opts.initial_peers.clone())
                             .into_iter()
+                            .map(|addr| (addr, PeerSource::Manual))
                             .collect(),
                     )
                 }
@@ -924,6 +1699,7 @@ impl Session {
                 peer_rx,
                 initial_peers.into_iter().collect(),
                 opts,
+                have_pieces,
             )
             .await
         }
@@ -957,23 +1733,35 @@ impl Session {
         Ok::<_, anyhow::Error>(Some(PathBuf::from(longest)))
     }
 
+    #[allow(clippy::too_many_arguments)]
     async fn main_torrent_info(
-        &self,
+        self: &Arc<Self>,
         info_hash: Id20,
         info: TorrentMetaV1Info<ByteString>,
         trackers: Vec<String>,
         peer_rx: Option<PeerStream>,
-        initial_peers: Vec<SocketAddr>,
+        initial_peers: Vec<(SocketAddr, PeerSource)>,
         opts: AddTorrentOptions,
+        have_pieces: Option<BF>,
     ) -> anyhow::Result<AddTorrentResponse> {
         debug!("Torrent info: {:#?}", &info);
 
-        let only_files = compute_only_files(
-            &info,
-            opts.only_files,
-            opts.only_files_regex,
-            opts.list_only,
-        )?;
+        let default_skip = DefaultSkipPatterns::parse(&self.default_skip_paths.read())?;
+        let only_files = compute_only_files(&info, &opts, &default_skip)?;
+        if let Some(file_priorities) = &opts.file_priorities {
+            let total_files = info.iter_file_lengths()?.count();
+            if let Some(id) = file_priorities.keys().find(|id| **id >= total_files) {
+                bail!("file id {} is out of range", id);
+            }
+        }
+        let file_priorities = match &opts.file_priorities_paths {
+            Some(paths) => {
+                let mut priorities = file_priorities_matching_paths(&info, paths)?;
+                priorities.extend(opts.file_priorities.iter().flatten());
+                Some(priorities)
+            }
+            None => opts.file_priorities.clone(),
+        };
 
         let output_folder = match (opts.output_folder, opts.sub_folder) {
             (None, None) => self.output_folder.join(
@@ -991,39 +1779,106 @@ impl Session {
                 info,
                 only_files,
                 output_folder,
-                seen_peers: initial_peers,
+                seen_peers: initial_peers.into_iter().map(|(addr, _)| addr).collect(),
             }));
         }
 
         let mut builder = ManagedTorrentBuilder::new(info, info_hash, output_folder.clone());
         builder
             .overwrite(opts.overwrite)
+            .force_recheck(opts.force_recheck)
+            .file_allocation(opts.file_allocation)
             .spawner(self.spawner)
             .trackers(trackers)
             .peer_id(self.peer_id);
 
+        if let Some(resume_store) = &self.resume_store {
+            builder.resume_store(resume_store.clone());
+        }
+        if let Some(limiter) = self.disk_write_limiter(&output_folder) {
+            builder.disk_write_limiter(limiter);
+        }
+        if let Some(budget) = &self.write_cache_budget {
+            builder.write_cache_budget(budget.clone());
+        }
+        builder.connection_budget(self.connection_budget.clone());
+        builder.dial_limiter(self.dial_limiter.clone());
+        builder.dialer(self.dialer.clone());
+        builder.external_ip(self.external_ip.clone());
+        if let Some(trusted_peers) = &self.trusted_peers {
+            builder.trusted_peers(trusted_peers.clone());
+        }
+        if let Some(dht) = &self.dht {
+            builder.dht(dht.clone());
+        }
+        builder.announce_port(self.announce_port);
+        builder.holepunch_port(self.holepunch_port);
+        builder.disk_retry_policy(self.disk_retry_policy);
+        builder.hash_pool(self.hash_pool.clone());
+        #[cfg(all(target_os = "linux", feature = "io-uring"))]
+        if let Some(uring) = &self.uring {
+            builder.uring(uring.clone());
+        }
+        builder.target_download_speed(self.target_download_speed);
+        if let Some(have_pieces) = have_pieces {
+            builder.have_pieces(have_pieces);
+        }
         if let Some(only_files) = only_files {
             builder.only_files(only_files);
         }
-        if let Some(interval) = opts.force_tracker_interval {
-            builder.force_tracker_interval(interval);
+        if let Some(file_priorities) = file_priorities {
+            builder.file_priorities(file_priorities);
+        }
+        builder.announce_options(opts.announce_options);
+        builder
+            .download_enabled(!opts.disable_download)
+            .upload_enabled(!opts.disable_upload)
+            .peer_limits(opts.peer_limits)
+            .seed_limits(opts.seed_limits)
+            .completion_action(opts.completion_action)
+            .finished_hook({
+                let session = Arc::downgrade(self);
+                let callback = opts.on_completed.clone();
+                Box::new(move || {
+                    if let Some(session) = session.upgrade() {
+                        session.on_torrent_finished(info_hash, callback.clone());
+                    }
+                })
+            });
+        let mut finished_peer_policy = opts.finished_peer_policy;
+        let mut upload_coupling = opts.upload_coupling;
+        if let Some(label) = opts.label {
+            let label_policy = self.label_policy(&label);
+            finished_peer_policy = finished_peer_policy
+                .or_else(|| label_policy.as_ref().and_then(|p| p.finished_peer_policy));
+            upload_coupling =
+                upload_coupling.or_else(|| label_policy.as_ref().and_then(|p| p.upload_coupling));
+            builder.limits(self.label_limits(&label)).label(label);
+        }
+        if let Some(policy) = finished_peer_policy {
+            builder.finished_peer_policy(policy);
+        }
+        if let Some(coupling) = upload_coupling {
+            builder.upload_coupling(coupling);
         }
 
         let peer_opts = self.merge_peer_opts(opts.peer_opts);
 
-        if let Some(t) = peer_opts.connect_timeout {
-            builder.peer_connect_timeout(t);
-        }
-
-        if let Some(t) = peer_opts.read_write_timeout {
-            builder.peer_read_write_timeout(t);
-        }
+        builder.tunable_options(TunableOptions {
+            download_rate_limit: opts.download_rate_limit,
+            upload_rate_limit: opts.upload_rate_limit,
+            peer_connect_timeout: peer_opts.connect_timeout,
+            peer_read_write_timeout: peer_opts.read_write_timeout,
+            peer_request_timeout: opts.peer_request_timeout,
+            force_tracker_interval: opts.force_tracker_interval,
+        });
+        builder.peer_randomize_fingerprint(peer_opts.randomize_fingerprint);
+        builder.peer_socket_options(peer_opts.socket);
 
         let (managed_torrent, id) = {
             let mut g = self.db.write();
-            if let Some((id, handle)) = g.torrents.iter().find(|(_, t)| t.info_hash() == info_hash)
-            {
-                return Ok(AddTorrentResponse::AlreadyManaged(*id, handle.clone()));
+            if let Some((id, handle)) = g.find_by_info_hash(info_hash) {
+                return Ok(AddTorrentResponse::AlreadyManaged(id, handle));
             }
             let next_id = g.torrents.len();
             let managed_torrent =
@@ -1054,10 +1909,51 @@ impl Session {
         Ok(AddTorrentResponse::Added(id, managed_torrent))
     }
 
+    // Apply the completion action, outside of the task or hasher thread that finished the torrent.
+    fn on_torrent_finished(
+        self: &Arc<Self>,
+        info_hash: Id20,
+        callback: Option<CompletionCallback>,
+    ) {
+        let (id, handle) = match self.find_by_info_hash(info_hash) {
+            Some(t) => t,
+            None => return,
+        };
+        let session = Arc::downgrade(self);
+        let _guard = self.runtime.enter();
+        self.spawn(
+            error_span!(parent: handle.info().span.clone(), "completion_action"),
+            async move {
+                let action = handle.info().options.completion_action;
+                match action {
+                    CompletionAction::Seed => {}
+                    CompletionAction::Pause => handle.pause()?,
+                    CompletionAction::Forget => {
+                        if let Some(session) = session.upgrade() {
+                            session.delete(id, false)?;
+                        }
+                    }
+                }
+                info!(%action, "torrent finished");
+                if let Some(callback) = callback {
+                    callback(id, &handle);
+                }
+                Ok(())
+            },
+        );
+    }
+
     pub fn get(&self, id: TorrentId) -> Option<ManagedTorrentHandle> {
         self.db.read().torrents.get(&id).cloned()
     }
 
+    pub(crate) fn find_by_info_hash(
+        &self,
+        info_hash: Id20,
+    ) -> Option<(TorrentId, ManagedTorrentHandle)> {
+        self.db.read().find_by_info_hash(info_hash)
+    }
+
     pub fn delete(&self, id: TorrentId, delete_files: bool) -> anyhow::Result<()> {
         let removed = self
             .db
@@ -1066,6 +1962,19 @@ impl Session {
             .remove(&id)
             .with_context(|| format!("torrent with id {} did not exist", id))?;
 
+        // Keep the resume data of forgotten torrents, so that adding them again doesn't
+        // need to check the files.
+        if let Some(resume_store) = &self.resume_store {
+            let result = if delete_files {
+                resume_store.remove(removed.info_hash())
+            } else {
+                removed.save_resume_data()
+            };
+            if let Err(e) = result {
+                warn!(error=?e, "error updating resume data");
+            }
+        }
+
         let paused = removed
             .with_state_mut(|s| {
                 let paused = match s.take() {
@@ -1084,8 +1993,10 @@ impl Session {
             }
             (Ok(Some(paused)), true) => {
                 drop(paused.files);
-                for file in paused.filenames {
-                    if let Err(e) = std::fs::remove_file(&file) {
+                let out_dir = removed.info().out_dir_handle();
+                // Files that aren't downloaded share the parts file.
+                for file in paused.filenames.into_iter().unique() {
+                    if let Err(e) = out_dir.remove_file(&file) {
                         warn!(?file, error=?e, "could not delete file");
                     }
                 }
@@ -1102,17 +2013,42 @@ impl Session {
         trackers: Vec<String>,
         announce_port: Option<u16>,
         force_tracker_interval: Option<Duration>,
+        announce_options: AnnounceOptions,
     ) -> anyhow::Result<Option<PeerStream>> {
-        let announce_port = announce_port.or(self.tcp_listen_port);
+        let announce_port = announce_port.or(self.announce_port);
+        let trackers = if self.dialer.proxy.is_some() {
+            // UDP can't go through the proxy.
+            trackers
+                .into_iter()
+                .filter(|t| url::Url::parse(t).map_or(true, |u| u.scheme() != "udp"))
+                .collect()
+        } else {
+            trackers
+        };
         let dht_rx = self
             .dht
             .as_ref()
-            .map(|dht| dht.get_peers(info_hash, announce_port))
-            .transpose()?;
+            .map(|dht| {
+                let torrent = Arc::new(PeerRxTorrentInfo {
+                    info_hash,
+                    session: self.clone(),
+                    _tracker_task: None,
+                });
+                let seed = torrent.clone();
+                dht.get_peers_adaptive(
+                    info_hash,
+                    announce_port,
+                    Box::new(move || torrent.peer_demand() == PeerDemand::Starved),
+                    Box::new(move || seed.is_seed()),
+                )
+            })
+            .transpose()?
+            .map(|rx| rx.map(|addr| (addr, PeerSource::Dht)));
 
         let peer_rx_stats = PeerRxTorrentInfo {
             info_hash,
             session: self.clone(),
+            _tracker_task: Some(self.tracker_tasks.token()),
         };
         let peer_rx = TrackerComms::start(
             info_hash,
@@ -1120,18 +2056,40 @@ impl Session {
             trackers,
             Box::new(peer_rx_stats),
             force_tracker_interval,
+            announce_options,
             announce_port,
-        );
+            self.tracker_http_client.clone(),
+            self.bind.clone(),
+        )
+        .map(|rx| rx.map(|addr| (addr, PeerSource::Tracker)));
+
+        let peer_rx = merge_two_optional_streams(dht_rx, peer_rx);
+        Ok(match &self.trusted_peers {
+            Some(trusted) => {
+                let trusted = trusted.clone();
+                peer_rx.map(|rx| -> PeerStream {
+                    Box::pin(rx.filter(move |(addr, _)| trusted.contains(*addr)))
+                })
+            }
+            None => peer_rx,
+        })
+    }
 
-        Ok(merge_two_optional_streams(dht_rx, peer_rx))
+    // The peers given when adding a torrent and the imported ones, or only the trusted peers.
+    fn initial_peers(&self, info_hash: Id20, peers: Option<Vec<SocketAddr>>) -> Vec<SocketAddr> {
+        match &self.trusted_peers {
+            Some(trusted) => trusted.addrs().to_vec(),
+            None => self.with_imported_peers(info_hash, peers.unwrap_or_default()),
+        }
     }
 
     pub fn unpause(self: &Arc<Self>, handle: &ManagedTorrentHandle) -> anyhow::Result<()> {
         let peer_rx = self.make_peer_rx(
             handle.info_hash(),
             handle.info().trackers.clone().into_iter().collect(),
-            self.tcp_listen_port,
-            handle.info().options.force_tracker_interval,
+            self.announce_port,
+            handle.tunable_options().force_tracker_interval,
+            handle.info().options.announce_options,
         )?;
         handle.start(peer_rx, false, self.cancellation_token.child_token())?;
         Ok(())
@@ -1140,25 +2098,146 @@ impl Session {
     pub fn tcp_listen_port(&self) -> Option<u16> {
         self.tcp_listen_port
     }
+
+    /// The port peers are told to connect to, see [`SessionOptions::announce_port`].
+    pub fn announce_port(&self) -> Option<u16> {
+        self.announce_port
+    }
+
+    /// Our public IP address, set in [`SessionOptions::external_ip`] or else detected from what
+    /// trackers and peers report.
+    pub fn external_ip(&self) -> Option<IpAddr> {
+        self.external_ip.get()
+    }
+
+    /// The SHA-1 implementation in use, see [`SessionOptions::sha1_backend`].
+    pub fn sha1_backend(&self) -> Sha1Backend {
+        sha1w::sha1_backend()
+    }
 }
 
 // Ad adapter for converting stats into the format that tracker_comms accepts.
 struct PeerRxTorrentInfo {
     info_hash: Id20,
     session: Arc<Session>,
+    // Held while the trackers of the torrent run, for shutdown to wait for them.
+    _tracker_task: Option<TaskTrackerToken>,
 }
 
-impl tracker_comms::TorrentStatsProvider for PeerRxTorrentInfo {
-    fn get(&self) -> tracker_comms::TrackerCommsStats {
-        let mt = self.session.with_torrents(|torrents| {
+impl PeerRxTorrentInfo {
+    fn torrent(&self) -> Option<ManagedTorrentHandle> {
+        self.session.with_torrents(|torrents| {
             for (_, mt) in torrents {
                 if mt.info_hash() == self.info_hash {
                     return Some(mt.clone());
                 }
             }
             None
-        });
-        let mt = match mt {
+        })
+    }
+
+    fn peer_demand(&self) -> PeerDemand {
+        self.torrent()
+            .and_then(|t| t.live())
+            .map(|l| l.peer_demand())
+            .unwrap_or_default()
+    }
+
+    // Whether we have all the pieces, not only those of the selected files.
+    fn is_seed(&self) -> bool {
+        self.torrent().is_some_and(|t| {
+            let total = t.info().lengths.total_length();
+            t.live()
+                .is_some_and(|l| l.is_finished() && l.get_approx_have_bytes() >= total)
+        })
+    }
+}
+
+impl tracker_comms::TorrentStatsProvider for PeerRxTorrentInfo {
+    fn on_announced(&self) {
+        if let Some(live) = self.torrent().and_then(|t| t.live()) {
+            live.on_announced();
+        }
+    }
+
+    fn on_external_ip(&self, tracker: &str, ip: IpAddr) {
+        self.session
+            .external_ip
+            .report(ExternalIpSource::Tracker(tracker.to_owned()), ip);
+    }
+
+    fn announce_ip(&self) -> Option<IpAddr> {
+        self.session.external_ip.manual()
+    }
+
+    fn on_tracker_status(&self, tracker: &str, status: &tracker_comms::TrackerStatus) {
+        // Late reports after pausing would bring back what the pause cleared.
+        if let Some(t) = self.torrent().filter(|t| t.live().is_some()) {
+            let prev = t
+                .info()
+                .tracker_status
+                .lock()
+                .insert(tracker.to_owned(), status.clone());
+            // Also reported when only the next announce time changed.
+            let announced = status.last_announce.is_some()
+                && prev.is_none_or(|p| p.last_announce != status.last_announce);
+            if announced {
+                t.info().events.emit(TorrentEvent::TrackerAnnounce {
+                    tracker: tracker.to_owned(),
+                    peers: status.peers,
+                    error: status.last_error.clone(),
+                });
+            }
+        }
+    }
+
+    fn wait_reannounce(&self) -> BoxFuture<'static, ()> {
+        let notify = match self.torrent() {
+            Some(t) => t.info().reannounce_notify.clone(),
+            None => return futures::future::pending().boxed(),
+        };
+        async move { notify.notified().await }.boxed()
+    }
+
+    fn spawn_stopped_announce(
+        &self,
+        runtime: &tokio::runtime::Handle,
+        fut: BoxFuture<'static, ()>,
+    ) {
+        self.session.tracker_tasks.spawn_on(fut, runtime);
+    }
+
+    fn force_interval(&self) -> Option<Option<Duration>> {
+        self.torrent()
+            .map(|t| t.tunable_options().force_tracker_interval)
+    }
+
+    fn announce_key(&self) -> Option<u32> {
+        self.torrent().map(|t| t.info().tracker_identity.lock().key)
+    }
+
+    fn tracker_id(&self, tracker: &str) -> Option<String> {
+        self.torrent()?
+            .info()
+            .tracker_identity
+            .lock()
+            .tracker_ids
+            .get(tracker)
+            .cloned()
+    }
+
+    fn on_tracker_id(&self, tracker: &str, id: String) {
+        if let Some(t) = self.torrent() {
+            t.info()
+                .tracker_identity
+                .lock()
+                .tracker_ids
+                .insert(tracker.to_owned(), id);
+        }
+    }
+
+    fn get(&self) -> tracker_comms::TrackerCommsStats {
+        let mt = match self.torrent() {
             Some(mt) => mt,
             None => {
                 warn!(info_hash=?self.info_hash, "can't find torrent in the session");
@@ -1166,6 +2245,8 @@ impl tracker_comms::TorrentStatsProvider for PeerRxTorrentInfo {
             }
         };
         let stats = mt.stats();
+        let live = mt.live();
+        let peer_demand = live.as_ref().map(|l| l.peer_demand()).unwrap_or_default();
 
         use crate::torrent_state::stats::TorrentStatsState as TS;
         use tracker_comms::TrackerCommsStatsState as S;
@@ -1180,6 +2261,10 @@ impl tracker_comms::TorrentStatsProvider for PeerRxTorrentInfo {
                 TS::Paused => S::Paused,
                 TS::Error => S::None,
             },
+            peer_demand,
+            completed_while_live: live.is_some_and(|l| l.finished_while_live()),
+            // The total is of the selected files only.
+            partial_seed: stats.finished && stats.total_bytes < mt.info().lengths.total_length(),
         }
     }
 }
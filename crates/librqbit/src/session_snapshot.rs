@@ -0,0 +1,145 @@
+// A consistent view of the whole session for embedders, taken in one read of the torrent
+// list, instead of assembling it from separate calls that may race with adds and deletes.
+
+use std::{
+    net::{IpAddr, SocketAddr},
+    path::PathBuf,
+};
+
+use dht::DhtStats;
+use serde::Serialize;
+
+use crate::{
+    session::{Session, TorrentId},
+    torrent_state::{
+        stats::{Speed, TorrentStats},
+        ManagedTorrentHandle, TorrentStatsState,
+    },
+};
+
+#[derive(Debug, Serialize)]
+pub struct TorrentSummary {
+    pub id: TorrentId,
+    pub info_hash: String,
+    pub name: Option<String>,
+    pub output_folder: PathBuf,
+    pub label: Option<String>,
+    pub stats: TorrentStats,
+}
+
+/// Sums over all torrents in the snapshot.
+#[derive(Debug, Default, Serialize)]
+pub struct SessionTotals {
+    pub torrents: usize,
+    pub initializing: usize,
+    pub live: usize,
+    pub paused: usize,
+    pub error: usize,
+    pub finished: usize,
+    pub progress_bytes: u64,
+    pub total_bytes: u64,
+    pub uploaded_bytes: u64,
+    pub download_speed: Speed,
+    pub upload_speed: Speed,
+    pub live_peers: usize,
+}
+
+#[derive(Debug, Serialize)]
+pub struct SessionSnapshot {
+    /// Ordered by id.
+    pub torrents: Vec<TorrentSummary>,
+    pub totals: SessionTotals,
+    /// None if DHT is disabled.
+    pub dht: Option<DhtStats>,
+    pub dht_listen_addr: Option<SocketAddr>,
+    /// None if not listening for incoming connections.
+    pub tcp_listen_port: Option<u16>,
+    /// The port peers are told to connect to, usually the listen port.
+    pub announce_port: Option<u16>,
+    /// Our public IP address, set or detected.
+    pub external_ip: Option<IpAddr>,
+    /// Memory used for pieces not written to disk yet, out of "write_cache_max_bytes".
+    pub write_cache_used_bytes: u64,
+    pub write_cache_max_bytes: u64,
+    /// The SHA-1 implementation pieces are verified with.
+    pub sha1_backend: &'static str,
+}
+
+impl SessionTotals {
+    fn add(&mut self, stats: &TorrentStats) {
+        self.torrents += 1;
+        match stats.state {
+            TorrentStatsState::Initializing => self.initializing += 1,
+            TorrentStatsState::Live => self.live += 1,
+            TorrentStatsState::Paused => self.paused += 1,
+            TorrentStatsState::Error => self.error += 1,
+        }
+        self.finished += stats.finished as usize;
+        self.progress_bytes += stats.progress_bytes;
+        self.total_bytes += stats.total_bytes;
+        self.uploaded_bytes += stats.uploaded_bytes;
+        if let Some(live) = &stats.live {
+            self.download_speed.mbps += live.download_speed.mbps;
+            self.upload_speed.mbps += live.upload_speed.mbps;
+            self.live_peers += live.snapshot.peer_stats.live;
+        }
+    }
+}
+
+impl Session {
+    /// The managed torrents, ordered by id.
+    pub fn torrents(&self) -> Vec<(TorrentId, ManagedTorrentHandle)> {
+        let mut torrents = self
+            .with_torrents(|torrents| torrents.map(|(id, t)| (id, t.clone())).collect::<Vec<_>>());
+        torrents.sort_unstable_by_key(|(id, _)| *id);
+        torrents
+    }
+
+    /// All torrents with their stats, and the state of the session. Torrents can't be added or
+    /// removed while the snapshot is taken.
+    pub fn snapshot(&self) -> SessionSnapshot {
+        let mut torrents = self.with_torrents(|torrents| {
+            torrents
+                .map(|(id, t)| {
+                    let info = t.info();
+                    TorrentSummary {
+                        id,
+                        info_hash: info.info_hash.as_string(),
+                        name: info.info.name.as_ref().map(|n| n.to_string()),
+                        output_folder: info.out_dir(),
+                        label: info.label.clone(),
+                        stats: t.stats(),
+                    }
+                })
+                .collect::<Vec<_>>()
+        });
+        torrents.sort_unstable_by_key(|t| t.id);
+
+        let mut totals = SessionTotals::default();
+        for t in torrents.iter() {
+            totals.add(&t.stats);
+        }
+
+        let dht = self.get_dht();
+        SessionSnapshot {
+            torrents,
+            totals,
+            dht: dht.map(|d| d.stats()),
+            dht_listen_addr: dht.map(|d| d.listen_addr()),
+            tcp_listen_port: self.tcp_listen_port(),
+            announce_port: self.announce_port(),
+            external_ip: self.external_ip(),
+            write_cache_used_bytes: self
+                .write_cache_budget
+                .as_ref()
+                .map(|b| b.used_bytes())
+                .unwrap_or_default(),
+            write_cache_max_bytes: self
+                .write_cache_budget
+                .as_ref()
+                .map(|b| b.max_bytes())
+                .unwrap_or_default(),
+            sha1_backend: self.sha1_backend().name(),
+        }
+    }
+}
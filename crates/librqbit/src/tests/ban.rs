@@ -0,0 +1,231 @@
+use std::{borrow::Cow, net::SocketAddr, time::Duration};
+
+use buffers::ByteBuf;
+use peer_binary_protocol::Message;
+use tokio::{io::AsyncReadExt, time::timeout};
+
+use super::session_util::{add_downloading_torrent, new_session, RawPeer};
+use crate::{
+    create_torrent,
+    tests::test_util::create_default_random_dir_with_torrents,
+    torrent_state::live::peer::stats::snapshot::{PeerStatsFilter, PeerStatsFilterState},
+    AddTorrent, AddTorrentOptions, Session, SessionOptions,
+};
+
+#[tokio::test]
+async fn test_ban_peer() {
+    let dir = create_default_random_dir_with_torrents(1, 10_000, Some("rqbit_ban_peer"));
+    let torrent = create_torrent(dir.path(), Default::default())
+        .await
+        .unwrap();
+    // Nobody listens there, the peer stays known but not live.
+    let peer = std::net::SocketAddr::from(([127, 0, 0, 1], 1));
+
+    let session = new_session().await;
+    let handle = session
+        .add_torrent(
+            AddTorrent::TorrentFileBytes(Cow::Owned(torrent.as_bytes().unwrap())),
+            Some(AddTorrentOptions {
+                overwrite: true,
+                output_folder: Some(dir.path().to_str().unwrap().to_owned()),
+                initial_peers: Some(vec![peer]),
+                ..Default::default()
+            }),
+        )
+        .await
+        .unwrap()
+        .into_handle()
+        .unwrap();
+
+    let banned = |live: &crate::torrent_state::TorrentStateLive| {
+        let snapshot = live.per_peer_stats_snapshot(PeerStatsFilter {
+            state: PeerStatsFilterState::All,
+        });
+        snapshot.peers.get(&peer.to_string()).map(|s| s.banned)
+    };
+    let live = timeout(Duration::from_secs(30), async {
+        loop {
+            if let Some(live) = handle.live() {
+                if banned(&live).is_some() {
+                    return live;
+                }
+            }
+            tokio::time::sleep(Duration::from_millis(50)).await;
+        }
+    })
+    .await
+    .unwrap();
+    assert_eq!(banned(&live), Some(false));
+
+    assert!(live.disconnect_peer(peer).is_err());
+    assert!(live
+        .ban_peer(std::net::SocketAddr::from(([127, 0, 0, 1], 2)))
+        .is_err());
+    live.ban_peer(peer).unwrap();
+    assert_eq!(banned(&live), Some(true));
+    assert!(live.add_peer(peer).is_err());
+}
+
+#[tokio::test]
+async fn test_banned_peer_reconnect_refused() {
+    use tokio::io::AsyncWriteExt;
+
+    let dir = create_default_random_dir_with_torrents(1, 10_000, Some("rqbit_ban_reconnect"));
+    let torrent = create_torrent(dir.path(), Default::default())
+        .await
+        .unwrap();
+
+    let session = Session::new_with_opts(
+        std::env::temp_dir().join("does_not_exist"),
+        SessionOptions {
+            disable_dht: true,
+            disable_dht_persistence: true,
+            listen_port_range: Some(17000..19000),
+            ..Default::default()
+        },
+    )
+    .await
+    .unwrap();
+    let handle = session
+        .add_torrent(
+            AddTorrent::TorrentFileBytes(Cow::Owned(torrent.as_bytes().unwrap())),
+            Some(AddTorrentOptions {
+                overwrite: true,
+                output_folder: Some(dir.path().to_str().unwrap().to_owned()),
+                ..Default::default()
+            }),
+        )
+        .await
+        .unwrap()
+        .into_handle()
+        .unwrap();
+    let live = timeout(Duration::from_secs(30), async {
+        loop {
+            if let Some(live) = handle.live() {
+                return live;
+            }
+            tokio::time::sleep(Duration::from_millis(50)).await;
+        }
+    })
+    .await
+    .unwrap();
+    let listen_addr =
+        std::net::SocketAddr::from(([127, 0, 0, 1], session.tcp_listen_port().unwrap()));
+
+    // Connects and returns the bytes the session sent back until it closed the connection or
+    // went quiet.
+    let connect = |peer_id: u8| {
+        let handle = handle.clone();
+        async move {
+            let mut conn = tokio::net::TcpStream::connect(listen_addr).await.unwrap();
+            let mut buf = Vec::new();
+            peer_binary_protocol::Handshake::new(
+                handle.info_hash(),
+                librqbit_core::Id20::new([peer_id; 20]),
+            )
+            .serialize(&mut buf);
+            conn.write_all(&buf).await.unwrap();
+            let mut reply = [0u8; 68];
+            let read = timeout(Duration::from_secs(5), conn.read_exact(&mut reply)).await;
+            (conn, matches!(read, Ok(Ok(_))))
+        }
+    };
+
+    let (conn, replied) = connect(1).await;
+    assert!(replied);
+    let first = conn.local_addr().unwrap();
+    timeout(Duration::from_secs(30), async {
+        while !live
+            .per_peer_stats_snapshot(PeerStatsFilter {
+                state: PeerStatsFilterState::Live,
+            })
+            .peers
+            .contains_key(&first.to_string())
+        {
+            tokio::time::sleep(Duration::from_millis(50)).await;
+        }
+    })
+    .await
+    .unwrap();
+    live.ban_peer(first).unwrap();
+
+    // Same IP, another port and peer ID.
+    let (conn, replied) = connect(2).await;
+    assert_ne!(conn.local_addr().unwrap(), first);
+    assert!(!replied);
+}
+
+#[tokio::test]
+async fn test_ban_live_peer() {
+    // One piece of one chunk.
+    let (_dir, _out, session, live) = add_downloading_torrent(1, 10_000, "rqbit_ban_live").await;
+
+    let mut banned = RawPeer::connect(&session, &live, 1).await;
+    banned
+        .send(Message::Bitfield(ByteBuf(&[0b1000_0000])))
+        .await;
+    banned.send(Message::Unchoke).await;
+    assert_eq!(banned.next_request().await, (false, 0, 0));
+    live.ban_peer(banned.conn.local_addr().unwrap()).unwrap();
+
+    // Disconnected, and no other port of its IP is dialed.
+    let mut closed = [0u8; 1];
+    timeout(Duration::from_secs(30), async {
+        while let Ok(1..) = banned.conn.read(&mut closed).await {}
+    })
+    .await
+    .unwrap();
+    assert!(live
+        .add_peer(SocketAddr::from(([127, 0, 0, 1], 1)))
+        .is_err());
+
+    // What was requested from it is requested from others.
+    let mut other = RawPeer::connect_from([127, 0, 0, 2], &session, &live, 2).await;
+    other.send(Message::Bitfield(ByteBuf(&[0b1000_0000]))).await;
+    other.send(Message::Unchoke).await;
+    assert_eq!(other.next_request().await, (false, 0, 0));
+}
+
+#[tokio::test]
+async fn test_ban_peer_sending_broken_piece_twice() {
+    use peer_binary_protocol::Piece;
+
+    // One piece of one chunk.
+    let (dir, _out, session, live) = add_downloading_torrent(1, 10_000, "rqbit_ban_broken").await;
+    let data = std::fs::read(dir.path().join("0.data")).unwrap();
+
+    // The first broken piece may be bad luck, so it's retried from the same peer.
+    let mut bad = RawPeer::connect(&session, &live, 1).await;
+    bad.send(Message::Bitfield(ByteBuf(&[0b1000_0000]))).await;
+    bad.send(Message::Unchoke).await;
+    for _ in 0..2 {
+        assert_eq!(bad.next_request().await, (false, 0, 0));
+        bad.send(Message::Piece(Piece::from_data(0, 0, &[0u8; 10_000][..])))
+            .await;
+    }
+
+    // The second time, it's banned.
+    let mut closed = [0u8; 1];
+    timeout(Duration::from_secs(30), async {
+        while let Ok(1..) = bad.conn.read(&mut closed).await {}
+    })
+    .await
+    .unwrap();
+    assert!(live
+        .add_peer(SocketAddr::from(([127, 0, 0, 1], 1)))
+        .is_err());
+
+    let mut good = RawPeer::connect_from([127, 0, 0, 2], &session, &live, 2).await;
+    good.send(Message::Bitfield(ByteBuf(&[0b1000_0000]))).await;
+    good.send(Message::Unchoke).await;
+    assert_eq!(good.next_request().await, (false, 0, 0));
+    good.send(Message::Piece(Piece::from_data(0, 0, &data[..])))
+        .await;
+    timeout(Duration::from_secs(30), async {
+        while !live.is_finished() {
+            tokio::time::sleep(Duration::from_millis(50)).await;
+        }
+    })
+    .await
+    .unwrap();
+}
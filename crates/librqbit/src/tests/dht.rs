@@ -0,0 +1,30 @@
+use std::time::Duration;
+
+use crate::{Session, SessionOptions};
+
+#[tokio::test]
+async fn test_dht_without_default_bootstrap() {
+    let _ = tracing_subscriber::fmt::try_init();
+
+    let session = Session::new_with_opts(
+        std::env::temp_dir().join("does_not_exist"),
+        SessionOptions {
+            disable_dht_persistence: true,
+            disable_dht_default_bootstrap: true,
+            ..Default::default()
+        },
+    )
+    .await
+    .unwrap();
+
+    // Nothing to bootstrap from, DHT should stay up with an empty routing table.
+    tokio::time::sleep(Duration::from_millis(100)).await;
+    let dht = session.get_dht().unwrap();
+    assert!(!dht.cancellation_token().is_cancelled());
+    let stats = dht.stats();
+    assert_eq!(stats.routing_table_size, 0);
+    assert_eq!(stats.buckets.len(), 1);
+    assert_eq!(stats.buckets[0].good, 0);
+    assert_eq!(stats.stored_peers, 0);
+    assert_eq!(stats.stored_info_hashes, 0);
+}
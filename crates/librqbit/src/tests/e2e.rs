@@ -66,12 +66,36 @@ async fn test_e2e() {
                         disable_dht: true,
                         disable_dht_persistence: true,
                         dht_config: None,
+                        dht_bootstrap_nodes: Vec::new(),
+                        disable_dht_default_bootstrap: false,
+                        dht_announce_implied_port: false,
                         persistence: false,
                         persistence_filename: None,
                         peer_id: Some(peer_id),
                         peer_opts: None,
                         listen_port_range: Some(15100..17000),
                         enable_upnp_port_forwarding: false,
+                        announce_port: None,
+                        disk_write_limits: Default::default(),
+                        write_cache_bytes: None,
+                        address_book: None,
+                        default_skip_paths: Default::default(),
+                        disk_retry_policy: None,
+                        hashing_threads: None,
+                        target_download_speed: None,
+                        sha1_backend: None,
+                        connection_budget: None,
+                        max_half_open_connections: None,
+                        dials_per_second: None,
+                        socks_proxy_url: None,
+                        socks_proxy_isolation: Default::default(),
+                        tracker_proxy_url: None,
+                        tracker_ignore_system_proxy: false,
+                        bind_address: None,
+                        bind_device: None,
+                        external_ip: None,
+                        trusted_peers: None,
+                        enable_holepunch: true,
                     },
                 )
                 .await
@@ -0,0 +1,183 @@
+use std::{borrow::Cow, time::Duration};
+
+use tokio::{io::AsyncReadExt, time::timeout};
+
+use crate::{
+    create_torrent,
+    tests::test_util::create_default_random_dir_with_torrents,
+    torrent_state::live::peer::stats::snapshot::{PeerStatsFilter, PeerStatsFilterState},
+    AddTorrent, AddTorrentOptions, Session, SessionOptions,
+};
+
+#[tokio::test]
+async fn test_holepunch() {
+    use buffers::ByteBuf;
+    use peer_binary_protocol::{
+        extended::{
+            handshake::ExtendedHandshake,
+            ut_holepunch::{UtHolepunch, UtHolepunchError},
+            ExtendedMessage,
+        },
+        Handshake, Message, MessageBorrowed, MessageDeserializeError, MY_EXTENDED_UT_HOLEPUNCH,
+    };
+    use std::net::SocketAddr;
+    use tokio::{io::AsyncWriteExt, net::TcpStream};
+
+    // Reads messages until the next holepunch one.
+    async fn next_holepunch(conn: &mut TcpStream, buf: &mut Vec<u8>) -> UtHolepunch {
+        timeout(Duration::from_secs(10), async {
+            loop {
+                match MessageBorrowed::deserialize(buf) {
+                    Ok((msg, len)) => {
+                        let msg = match msg {
+                            Message::Extended(ExtendedMessage::UtHolepunch(msg)) => Some(msg),
+                            _ => None,
+                        };
+                        buf.drain(..len);
+                        if let Some(msg) = msg {
+                            return msg;
+                        }
+                    }
+                    Err(MessageDeserializeError::NotEnoughData(..)) => {
+                        let mut chunk = [0u8; 16384];
+                        let read = conn.read(&mut chunk).await.unwrap();
+                        assert!(read > 0, "connection closed");
+                        buf.extend_from_slice(&chunk[..read]);
+                    }
+                    Err(e) => panic!("{e}"),
+                }
+            }
+        })
+        .await
+        .unwrap()
+    }
+
+    async fn send_holepunch(conn: &mut TcpStream, msg: UtHolepunch) {
+        let mut buf = Vec::new();
+        Message::<ByteBuf>::Extended(ExtendedMessage::UtHolepunch(msg))
+            .serialize(&mut buf, &|_| Some(MY_EXTENDED_UT_HOLEPUNCH))
+            .unwrap();
+        conn.write_all(&buf).await.unwrap();
+    }
+
+    let dir = create_default_random_dir_with_torrents(1, 10_000, Some("rqbit_holepunch"));
+    let torrent = create_torrent(dir.path(), Default::default())
+        .await
+        .unwrap();
+    // Nothing downloaded, so that the session dials the peers relays ask it to.
+    let out = tempfile::TempDir::with_prefix("rqbit_holepunch_out").unwrap();
+
+    let session = Session::new_with_opts(
+        std::env::temp_dir().join("does_not_exist"),
+        SessionOptions {
+            disable_dht: true,
+            disable_dht_persistence: true,
+            listen_port_range: Some(19000..21000),
+            enable_holepunch: true,
+            ..Default::default()
+        },
+    )
+    .await
+    .unwrap();
+    let handle = session
+        .add_torrent(
+            AddTorrent::TorrentFileBytes(Cow::Owned(torrent.as_bytes().unwrap())),
+            Some(AddTorrentOptions {
+                output_folder: Some(out.path().to_str().unwrap().to_owned()),
+                ..Default::default()
+            }),
+        )
+        .await
+        .unwrap()
+        .into_handle()
+        .unwrap();
+    let live = timeout(Duration::from_secs(30), async {
+        loop {
+            if let Some(live) = handle.live() {
+                return live;
+            }
+            tokio::time::sleep(Duration::from_millis(50)).await;
+        }
+    })
+    .await
+    .unwrap();
+    let session_port = session.tcp_listen_port().unwrap();
+    let session_addr = SocketAddr::from(([127, 0, 0, 1], session_port));
+
+    // A peer that supports holepunching and says it listens on "listen_port".
+    let connect = |peer_id: u8, listen_port: u16| {
+        let handle = handle.clone();
+        let live = live.clone();
+        async move {
+            let mut conn = TcpStream::connect(session_addr).await.unwrap();
+            let mut buf = Vec::new();
+            Handshake::new(handle.info_hash(), librqbit_core::Id20::new([peer_id; 20]))
+                .serialize(&mut buf);
+            let mut eh = ExtendedHandshake::new();
+            eh.m.insert(ByteBuf(b"ut_holepunch"), MY_EXTENDED_UT_HOLEPUNCH);
+            eh.p = Some(listen_port as u32);
+            conn.write_all(&buf).await.unwrap();
+            Message::Extended(ExtendedMessage::Handshake(eh))
+                .serialize(&mut buf, &|_| None)
+                .unwrap();
+            conn.write_all(&buf).await.unwrap();
+            let mut reply = [0u8; 68];
+            timeout(Duration::from_secs(5), conn.read_exact(&mut reply))
+                .await
+                .unwrap()
+                .unwrap();
+            let addr = conn.local_addr().unwrap();
+            timeout(Duration::from_secs(30), async {
+                while !live
+                    .per_peer_stats_snapshot(PeerStatsFilter {
+                        state: PeerStatsFilterState::Live,
+                    })
+                    .peers
+                    .contains_key(&addr.to_string())
+                {
+                    tokio::time::sleep(Duration::from_millis(50)).await;
+                }
+            })
+            .await
+            .unwrap();
+            conn
+        }
+    };
+
+    let a_listen = SocketAddr::from(([127, 0, 0, 1], 1001));
+    let b_listen = SocketAddr::from(([127, 0, 0, 1], 1002));
+    let (mut a, mut a_buf) = (connect(1, a_listen.port()).await, Vec::new());
+    let (mut b, mut b_buf) = (connect(2, b_listen.port()).await, Vec::new());
+
+    // Relaying: both sides get told to connect where the other one listens.
+    send_holepunch(&mut a, UtHolepunch::Rendezvous(b_listen)).await;
+    assert_eq!(
+        next_holepunch(&mut b, &mut b_buf).await,
+        UtHolepunch::Connect(a_listen)
+    );
+    assert_eq!(
+        next_holepunch(&mut a, &mut a_buf).await,
+        UtHolepunch::Connect(b_listen)
+    );
+
+    let unknown = SocketAddr::from(([127, 0, 0, 1], 1003));
+    send_holepunch(&mut a, UtHolepunch::Rendezvous(unknown)).await;
+    assert_eq!(
+        next_holepunch(&mut a, &mut a_buf).await,
+        UtHolepunch::Error(unknown, UtHolepunchError::NoSuchPeer)
+    );
+    send_holepunch(&mut a, UtHolepunch::Rendezvous(a_listen)).await;
+    assert_eq!(
+        next_holepunch(&mut a, &mut a_buf).await,
+        UtHolepunch::Error(a_listen, UtHolepunchError::NoSelf)
+    );
+
+    // Connecting: the session dials the peer from the port it listens on.
+    let target = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    send_holepunch(&mut b, UtHolepunch::Connect(target.local_addr().unwrap())).await;
+    let (_, from) = timeout(Duration::from_secs(10), target.accept())
+        .await
+        .unwrap()
+        .unwrap();
+    assert_eq!(from.port(), session_port);
+}
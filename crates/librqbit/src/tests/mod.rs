@@ -1,2 +1,11 @@
+mod ban;
+mod dht;
 mod e2e;
+mod holepunch;
+mod peer_requests;
+mod session_state;
+mod session_util;
+mod socket_options;
+mod streaming;
 pub mod test_util;
+mod upload;
@@ -0,0 +1,97 @@
+use std::time::Duration;
+
+use buffers::ByteBuf;
+use peer_binary_protocol::Message;
+use tokio::time::timeout;
+
+use super::session_util::{add_downloading_torrent, RawPeer};
+use crate::TunableOptions;
+
+#[tokio::test]
+async fn test_stale_request_requested_again() {
+    // One piece of one chunk.
+    let (_dir, _out, session, live) = add_downloading_torrent(1, 10_000, "rqbit_stale").await;
+    let handle = session.get(0).unwrap();
+    handle
+        .set_tunable_options(TunableOptions {
+            peer_request_timeout: Some(Duration::from_secs(1)),
+            ..Default::default()
+        })
+        .unwrap();
+
+    let mut peer = RawPeer::connect(&session, &live, 1).await;
+    peer.send(Message::Bitfield(ByteBuf(&[0b1000_0000]))).await;
+    peer.send(Message::Unchoke).await;
+    assert_eq!(peer.next_request().await, (false, 0, 0));
+
+    // Not answered: it's cancelled, and the piece is requested again.
+    assert_eq!(peer.next_request().await, (true, 0, 0));
+    assert_eq!(peer.next_request().await, (false, 0, 0));
+}
+
+#[tokio::test]
+async fn test_cancel_taken_over_piece() {
+    use peer_binary_protocol::Piece;
+
+    // Three pieces of one chunk.
+    let (dir, _out, session, live) = add_downloading_torrent(1, 40_000, "rqbit_cancel").await;
+    let data = std::fs::read(dir.path().join("0.data")).unwrap();
+
+    // A slow peer that has only the first piece, and never sends it.
+    let mut slow = RawPeer::connect(&session, &live, 1).await;
+    slow.send(Message::Bitfield(ByteBuf(&[0b1000_0000]))).await;
+    slow.send(Message::Unchoke).await;
+    assert_eq!(slow.next_request().await, (false, 0, 0));
+    tokio::time::sleep(Duration::from_secs(1)).await;
+
+    // A fast peer takes the first piece over once it sent the others.
+    let mut fast = RawPeer::connect(&session, &live, 2).await;
+    fast.send(Message::Bitfield(ByteBuf(&[0b1110_0000]))).await;
+    fast.send(Message::Unchoke).await;
+    let mut sent = Vec::new();
+    while sent.len() < 3 {
+        let r = fast
+            .next_message(|msg| match msg {
+                Message::Request(r) => Some(r),
+                _ => None,
+            })
+            .await;
+        let start = (r.index * 16384 + r.begin) as usize;
+        let block = &data[start..start + r.length as usize];
+        // Pieces taking no measurable time don't count towards the peer's speed.
+        tokio::time::sleep(Duration::from_millis(20)).await;
+        fast.send(Message::Piece(Piece::from_data(r.index, r.begin, block)))
+            .await;
+        sent.push(r.index);
+    }
+    sent.sort();
+    assert_eq!(sent, vec![0, 1, 2]);
+
+    // The slow peer was told not to send the piece.
+    assert_eq!(slow.next_request().await, (true, 0, 0));
+    timeout(Duration::from_secs(30), async {
+        while !live.is_finished() {
+            tokio::time::sleep(Duration::from_millis(50)).await;
+        }
+    })
+    .await
+    .unwrap();
+
+    // Sending it anyway is ignored, rather than an error disconnecting the peer: the request
+    // for the next piece is still answered.
+    slow.send(Message::Piece(Piece::from_data(0, 0, &data[..16384])))
+        .await;
+    slow.send(Message::Request(peer_binary_protocol::Request {
+        index: 1,
+        begin: 0,
+        length: 16384,
+    }))
+    .await;
+    let block = slow
+        .next_message(|msg| match msg {
+            Message::Piece(p) => Some((p.index, p.begin, p.block.to_vec())),
+            _ => None,
+        })
+        .await;
+    assert_eq!(block, (1, 0, data[16384..32768].to_vec()));
+}
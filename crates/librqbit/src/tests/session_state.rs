@@ -0,0 +1,1283 @@
+use std::{borrow::Cow, net::SocketAddr, sync::Arc, time::Duration};
+
+use buffers::ByteBuf;
+use futures::StreamExt;
+use librqbit_core::Id20;
+use peer_binary_protocol::{Handshake, Message};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::TcpStream,
+    time::timeout,
+};
+use tracker_comms::PeerDemand;
+
+use super::session_util::{
+    add_checked_torrent, add_downloading_torrent, new_session, wait_until_paused, RawPeer,
+};
+use crate::{
+    create_torrent,
+    tests::test_util::create_default_random_dir_with_torrents,
+    torrent_state::{
+        live::peer::stats::snapshot::{PeerStatsFilter, PeerStatsFilterState},
+        ManagedTorrentHandle,
+    },
+    AddTorrent, AddTorrentOptions, AddTorrentResponse, CompletionAction, FilePriority,
+    FinishedPeerPolicy, LabelPolicy, ManagedTorrentState, PeerLimits, PeerSource, SeedLimitAction,
+    SeedLimits, Session, SessionOptions, TorrentEditor, TorrentEvent, TunableOptions,
+};
+
+#[tokio::test]
+async fn test_save_load_state() {
+    let _ = tracing_subscriber::fmt::try_init();
+
+    let session = new_session().await;
+    let (tempdir, _, handle) = add_checked_torrent(
+        &session,
+        2,
+        100_000,
+        "rqbit_session_state",
+        AddTorrentOptions {
+            finished_peer_policy: Some(FinishedPeerPolicy::KeepUpTo(3)),
+            ..Default::default()
+        },
+    )
+    .await;
+    assert!(handle.stats().finished);
+
+    let mut state = Vec::new();
+    session.save_state(&mut state).unwrap();
+    drop(session);
+
+    // Corrupt a file without changing its size. As the progress is restored, this shouldn't
+    // be noticed.
+    let corrupted = tempdir.path().join("0.data");
+    let mut data = std::fs::read(&corrupted).unwrap();
+    data[1000] ^= 0xff;
+    std::fs::write(&corrupted, data).unwrap();
+
+    let session = new_session().await;
+    session.load_state(&state[..]).await.unwrap();
+    let handle = session.get(0).unwrap();
+    wait_until_paused(&handle).await;
+    assert_eq!(handle.info().out_dir(), tempdir.path());
+    assert_eq!(
+        handle.info().options.finished_peer_policy,
+        FinishedPeerPolicy::KeepUpTo(3)
+    );
+    assert!(handle.stats().finished);
+}
+
+#[tokio::test]
+async fn test_export_import_torrent() {
+    let _ = tracing_subscriber::fmt::try_init();
+
+    let policy = LabelPolicy {
+        upload_limit_bps: Some(1000),
+        ..Default::default()
+    };
+    let session = new_session().await;
+    session.set_label_policy("archive", policy.clone());
+    let (tempdir, id, _) = add_checked_torrent(
+        &session,
+        2,
+        100_000,
+        "rqbit_bundle",
+        AddTorrentOptions {
+            label: Some("archive".into()),
+            ..Default::default()
+        },
+    )
+    .await;
+
+    let mut bundle = Vec::new();
+    session.export_torrent(id, &mut bundle).unwrap();
+    drop(session);
+
+    let session = new_session().await;
+    let handle = session
+        .import_torrent(&bundle[..], Some(tempdir.path().to_owned()))
+        .await
+        .unwrap()
+        .into_handle()
+        .unwrap();
+    wait_until_paused(&handle).await;
+    assert_eq!(handle.info().label.as_deref(), Some("archive"));
+    assert_eq!(session.label_policy("archive"), Some(policy));
+    assert!(handle.stats().finished);
+    drop(session);
+
+    // The pieces the bundle has are checked, so a corrupted file is noticed.
+    let corrupted = tempdir.path().join("0.data");
+    let mut data = std::fs::read(&corrupted).unwrap();
+    data[1000] ^= 0xff;
+    std::fs::write(&corrupted, data).unwrap();
+    let session = new_session().await;
+    let handle = session
+        .import_torrent(&bundle[..], Some(tempdir.path().to_owned()))
+        .await
+        .unwrap()
+        .into_handle()
+        .unwrap();
+    wait_until_paused(&handle).await;
+    assert!(!handle.stats().finished);
+}
+
+#[tokio::test]
+async fn test_session_snapshot() {
+    let _ = tracing_subscriber::fmt::try_init();
+
+    let session = new_session().await;
+    let mut dirs = Vec::new();
+    for label in ["a", "b"] {
+        let (tempdir, _, _) = add_checked_torrent(
+            &session,
+            1,
+            100_000,
+            "rqbit_snapshot",
+            AddTorrentOptions {
+                label: Some(label.into()),
+                ..Default::default()
+            },
+        )
+        .await;
+        dirs.push(tempdir);
+    }
+
+    let ids: Vec<_> = session.torrents().into_iter().map(|(id, _)| id).collect();
+    assert_eq!(ids, [0, 1]);
+
+    let snapshot = session.snapshot();
+    assert!(snapshot.dht.is_none());
+    let labels: Vec<_> = snapshot
+        .torrents
+        .iter()
+        .map(|t| t.label.as_deref().unwrap())
+        .collect();
+    assert_eq!(labels, ["a", "b"]);
+    assert_eq!(snapshot.totals.torrents, 2);
+    assert_eq!(snapshot.totals.paused, 2);
+    assert_eq!(snapshot.totals.finished, 2);
+    assert_eq!(snapshot.totals.total_bytes, 200_000);
+}
+
+#[tokio::test]
+async fn test_add_torrents_batch() {
+    let _ = tracing_subscriber::fmt::try_init();
+
+    let session = new_session().await;
+    let mut dirs = Vec::new();
+    let mut torrents = Vec::new();
+    for _ in 0..2 {
+        let tempdir = create_default_random_dir_with_torrents(1, 100_000, Some("rqbit_batch"));
+        torrents.push(
+            create_torrent(tempdir.path(), Default::default())
+                .await
+                .unwrap(),
+        );
+        dirs.push(tempdir);
+    }
+    let opts = |dir: &tempfile::TempDir| {
+        Some(AddTorrentOptions {
+            paused: true,
+            overwrite: true,
+            output_folder: Some(dir.path().to_str().unwrap().to_owned()),
+            ..Default::default()
+        })
+    };
+    session
+        .add_torrent(
+            AddTorrent::TorrentFileBytes(Cow::Owned(torrents[0].as_bytes().unwrap())),
+            opts(&dirs[0]),
+        )
+        .await
+        .unwrap();
+
+    // With DHT disabled and no trackers, the magnets can't be resolved. They are found
+    // managed without resolving them.
+    let magnet = format!(
+        "magnet:?xt=urn:btih:{}",
+        torrents[0].info_hash().as_string()
+    );
+    let results = session
+        .add_torrents(vec![
+            (AddTorrent::from_url(magnet.clone()), None),
+            (
+                AddTorrent::TorrentFileBytes(Cow::Owned(torrents[1].as_bytes().unwrap())),
+                opts(&dirs[1]),
+            ),
+            (AddTorrent::from_url(magnet), None),
+            (AddTorrent::from_url("magnet:?invalid"), None),
+        ])
+        .await;
+    assert_eq!(results.len(), 4);
+    assert!(matches!(
+        results[0],
+        Ok(AddTorrentResponse::AlreadyManaged(0, _))
+    ));
+    assert!(matches!(results[1], Ok(AddTorrentResponse::Added(1, _))));
+    assert!(matches!(
+        results[2],
+        Ok(AddTorrentResponse::AlreadyManaged(0, _))
+    ));
+    assert!(results[3].is_err());
+}
+
+#[tokio::test]
+async fn test_transfer_switches_saved() {
+    let _ = tracing_subscriber::fmt::try_init();
+
+    let session = new_session().await;
+    let (_dir, _, handle) = add_checked_torrent(
+        &session,
+        1,
+        100_000,
+        "rqbit_transfer",
+        AddTorrentOptions {
+            disable_upload: true,
+            ..Default::default()
+        },
+    )
+    .await;
+    assert!(handle.is_download_enabled());
+    assert!(!handle.is_upload_enabled());
+
+    handle.set_download_enabled(false);
+    handle.set_upload_enabled(true);
+    let mut state = Vec::new();
+    session.save_state(&mut state).unwrap();
+    drop(session);
+
+    let session = new_session().await;
+    session.load_state(&state[..]).await.unwrap();
+    let handle = session.get(0).unwrap();
+    wait_until_paused(&handle).await;
+    let stats = handle.stats();
+    assert!(!stats.download_enabled);
+    assert!(stats.upload_enabled);
+}
+
+#[tokio::test]
+async fn test_peer_limits_saved() {
+    let _ = tracing_subscriber::fmt::try_init();
+
+    let session = new_session().await;
+    let (_dir, _, handle) = add_checked_torrent(
+        &session,
+        1,
+        100_000,
+        "rqbit_peer_limits",
+        AddTorrentOptions {
+            peer_limits: PeerLimits {
+                max_connections: Some(10),
+                ..Default::default()
+            },
+            ..Default::default()
+        },
+    )
+    .await;
+    assert_eq!(handle.peer_limits().max_connections, Some(10));
+
+    let limits = PeerLimits {
+        max_connections: Some(200),
+        max_seeds: Some(5),
+        max_pending_dials: Some(3),
+    };
+    assert!(handle
+        .set_peer_limits(PeerLimits {
+            max_pending_dials: Some(0),
+            ..limits
+        })
+        .is_err());
+    handle.set_peer_limits(limits).unwrap();
+    let mut state = Vec::new();
+    session.save_state(&mut state).unwrap();
+    drop(session);
+
+    let session = new_session().await;
+    session.load_state(&state[..]).await.unwrap();
+    let handle = session.get(0).unwrap();
+    wait_until_paused(&handle).await;
+    assert_eq!(handle.peer_limits(), limits);
+}
+
+#[tokio::test]
+async fn test_peer_demand() {
+    let (_dir, _out, session, downloading) =
+        add_downloading_torrent(1, 10_000, "rqbit_demand_starved").await;
+    assert_eq!(downloading.peer_demand(), PeerDemand::Starved);
+
+    let (_dir, _, handle) = add_checked_torrent(
+        &session,
+        1,
+        10_000,
+        "rqbit_demand_saturated",
+        AddTorrentOptions {
+            peer_limits: PeerLimits {
+                max_connections: Some(1),
+                ..Default::default()
+            },
+            ..Default::default()
+        },
+    )
+    .await;
+    session.unpause(&handle).unwrap();
+    let seeding = timeout(Duration::from_secs(30), async {
+        loop {
+            if let Some(live) = handle.live() {
+                return live;
+            }
+            tokio::time::sleep(Duration::from_millis(50)).await;
+        }
+    })
+    .await
+    .unwrap();
+    assert_eq!(seeding.peer_demand(), PeerDemand::Normal);
+
+    // Finished, with all of its connections used.
+    let _peer = RawPeer::connect(&session, &seeding, 1).await;
+    assert_eq!(seeding.peer_demand(), PeerDemand::Saturated);
+}
+
+#[tokio::test]
+async fn test_tunable_options() {
+    let session = new_session().await;
+    let (_dir, _, handle) = add_checked_torrent(
+        &session,
+        1,
+        10_000,
+        "rqbit_tunable",
+        AddTorrentOptions {
+            download_rate_limit: Some(1024 * 1024),
+            force_tracker_interval: Some(Duration::from_secs(60)),
+            ..Default::default()
+        },
+    )
+    .await;
+    assert_eq!(
+        handle.tunable_options().download_rate_limit,
+        Some(1024 * 1024)
+    );
+    assert_eq!(
+        handle.tunable_options().force_tracker_interval,
+        Some(Duration::from_secs(60))
+    );
+
+    let options = TunableOptions {
+        download_rate_limit: None,
+        upload_rate_limit: Some(512 * 1024),
+        peer_connect_timeout: Some(Duration::from_secs(5)),
+        peer_read_write_timeout: Some(Duration::from_secs(20)),
+        peer_request_timeout: Some(Duration::from_secs(30)),
+        force_tracker_interval: Some(Duration::from_secs(300)),
+    };
+    assert!(handle
+        .set_tunable_options(TunableOptions {
+            force_tracker_interval: Some(Duration::ZERO),
+            ..options
+        })
+        .is_err());
+    handle.set_tunable_options(options).unwrap();
+    let mut state = Vec::new();
+    session.save_state(&mut state).unwrap();
+    drop(session);
+
+    let session = new_session().await;
+    session.load_state(&state[..]).await.unwrap();
+    let handle = session.get(0).unwrap();
+    wait_until_paused(&handle).await;
+    assert_eq!(handle.tunable_options(), options);
+}
+
+#[tokio::test]
+async fn test_resume_data_file_states() {
+    let _ = tracing_subscriber::fmt::try_init();
+
+    let tempdir = create_default_random_dir_with_torrents(2, 100_000, Some("rqbit_resume"));
+    let torrent = create_torrent(tempdir.path(), Default::default())
+        .await
+        .unwrap();
+    let state_dir = tempfile::TempDir::with_prefix("rqbit_resume_state").unwrap();
+    let session = Session::new_with_opts(
+        std::env::temp_dir().join("does_not_exist"),
+        SessionOptions {
+            disable_dht: true,
+            disable_dht_persistence: true,
+            persistence: true,
+            persistence_filename: Some(state_dir.path().join("session.json")),
+            ..Default::default()
+        },
+    )
+    .await
+    .unwrap();
+    // Add the torrent, and forget it once checked. Returns how much of it we have.
+    let add_and_forget = |force_recheck| {
+        let session = session.clone();
+        let torrent = torrent.as_bytes().unwrap();
+        let output_folder = tempdir.path().to_str().unwrap().to_owned();
+        async move {
+            let (id, handle) = match session
+                .add_torrent(
+                    AddTorrent::TorrentFileBytes(Cow::Owned(torrent)),
+                    Some(AddTorrentOptions {
+                        paused: true,
+                        overwrite: true,
+                        force_recheck,
+                        output_folder: Some(output_folder),
+                        ..Default::default()
+                    }),
+                )
+                .await
+                .unwrap()
+            {
+                AddTorrentResponse::Added(id, handle) => (id, handle),
+                _ => panic!("expected the torrent to be added"),
+            };
+            wait_until_paused(&handle).await;
+            let have = handle.stats().progress_bytes;
+            session.delete(id, false).unwrap();
+            have
+        }
+    };
+    let total = 200_000;
+    assert_eq!(add_and_forget(false).await, total);
+
+    // Corrupt a file keeping its size and modification time, so that only a check notices.
+    let corrupted = tempdir.path().join("0.data");
+    let mtime = std::fs::metadata(&corrupted).unwrap().modified().unwrap();
+    let original = std::fs::read(&corrupted).unwrap();
+    let mut data = original.clone();
+    data[1000] ^= 0xff;
+    std::fs::write(&corrupted, data).unwrap();
+    let set_mtime = |mtime| {
+        std::fs::File::options()
+            .write(true)
+            .open(&corrupted)
+            .unwrap()
+            .set_modified(mtime)
+            .unwrap()
+    };
+    set_mtime(mtime);
+    assert_eq!(add_and_forget(false).await, total);
+
+    // Once modified, the pieces of the file are checked.
+    set_mtime(mtime + Duration::from_secs(10));
+    assert_eq!(add_and_forget(false).await, total - 16384);
+
+    // Incomplete files too, but only the pieces we have are checked, the missing one is
+    // downloaded again even though it's fixed.
+    let mut data = original.clone();
+    data[50_000] ^= 0xff;
+    std::fs::write(&corrupted, data).unwrap();
+    assert_eq!(add_and_forget(false).await, total - 2 * 16384);
+
+    std::fs::write(&corrupted, original).unwrap();
+    assert_eq!(add_and_forget(true).await, total);
+}
+
+#[tokio::test]
+async fn test_move_storage_while_live() {
+    let _ = tracing_subscriber::fmt::try_init();
+
+    let session = new_session().await;
+    let (tempdir, _, handle) =
+        add_checked_torrent(&session, 2, 100_000, "rqbit_move", Default::default()).await;
+    let original = std::fs::read(tempdir.path().join("1.data")).unwrap();
+    session.unpause(&handle).unwrap();
+    assert!(handle.live().is_some());
+
+    let target = tempfile::TempDir::with_prefix("rqbit_move_target").unwrap();
+    let new_dir = target.path().join("moved");
+    let h = handle.clone();
+    let moved_to = new_dir.clone();
+    tokio::task::spawn_blocking(move || h.move_storage(moved_to))
+        .await
+        .unwrap()
+        .unwrap();
+
+    assert!(handle.live().is_some());
+    assert_eq!(handle.info().out_dir(), new_dir);
+    assert!(!tempdir.path().join("1.data").exists());
+    assert_eq!(std::fs::read(new_dir.join("1.data")).unwrap(), original);
+    assert!(handle.stats().finished);
+
+    // The files are served from the new place.
+    let mut streamed = Vec::new();
+    handle
+        .stream(1)
+        .unwrap()
+        .read_to_end(&mut streamed)
+        .await
+        .unwrap();
+    assert_eq!(streamed, original);
+}
+
+#[tokio::test]
+async fn test_skipped_files_in_parts_file() {
+    let _ = tracing_subscriber::fmt::try_init();
+
+    let seed_dir = create_default_random_dir_with_torrents(3, 100_000, Some("rqbit_parts_seed"));
+    let torrent = create_torrent(seed_dir.path(), Default::default())
+        .await
+        .unwrap();
+    let torrent_bytes = torrent.as_bytes().unwrap();
+
+    let seeder = Session::new_with_opts(
+        std::env::temp_dir().join("does_not_exist"),
+        SessionOptions {
+            disable_dht: true,
+            disable_dht_persistence: true,
+            listen_port_range: Some(15100..17000),
+            ..Default::default()
+        },
+    )
+    .await
+    .unwrap();
+    seeder
+        .add_torrent(
+            AddTorrent::TorrentFileBytes(Cow::Owned(torrent_bytes.clone())),
+            Some(AddTorrentOptions {
+                overwrite: true,
+                output_folder: Some(seed_dir.path().to_str().unwrap().to_owned()),
+                ..Default::default()
+            }),
+        )
+        .await
+        .unwrap()
+        .into_handle()
+        .unwrap()
+        .wait_until_completed()
+        .await
+        .unwrap();
+    let seeder_addr =
+        std::net::SocketAddr::from(([127, 0, 0, 1], seeder.tcp_listen_port().unwrap()));
+
+    let out_dir = tempfile::TempDir::with_prefix("rqbit_parts_leech").unwrap();
+    let session = new_session().await;
+    let handle = session
+        .add_torrent(
+            AddTorrent::TorrentFileBytes(Cow::Owned(torrent_bytes)),
+            Some(AddTorrentOptions {
+                only_files: Some(vec![1]),
+                initial_peers: Some(vec![seeder_addr]),
+                output_folder: Some(out_dir.path().to_str().unwrap().to_owned()),
+                ..Default::default()
+            }),
+        )
+        .await
+        .unwrap()
+        .into_handle()
+        .unwrap();
+    timeout(Duration::from_secs(30), handle.wait_until_completed())
+        .await
+        .unwrap()
+        .unwrap();
+
+    let read = |dir: &std::path::Path, name: &str| std::fs::read(dir.join(name)).unwrap();
+    let parts = out_dir
+        .path()
+        .join(format!(".{}.parts", handle.info_hash().as_string()));
+    assert_eq!(
+        read(out_dir.path(), "1.data"),
+        read(seed_dir.path(), "1.data")
+    );
+    assert!(!out_dir.path().join("0.data").exists());
+    assert!(!out_dir.path().join("2.data").exists());
+    assert!(parts.exists());
+
+    // Selecting the skipped files gives them their own files, and the parts file goes away.
+    handle
+        .update_file_selection(None, Default::default())
+        .unwrap();
+    timeout(Duration::from_secs(30), handle.wait_until_completed())
+        .await
+        .unwrap()
+        .unwrap();
+    for name in ["0.data", "1.data", "2.data"] {
+        assert_eq!(read(out_dir.path(), name), read(seed_dir.path(), name));
+    }
+    assert!(!parts.exists());
+}
+
+#[tokio::test]
+async fn test_announce_port() {
+    let session_with = |listen_port_range, announce_port| {
+        Session::new_with_opts(
+            std::env::temp_dir().join("does_not_exist"),
+            SessionOptions {
+                disable_dht: true,
+                disable_dht_persistence: true,
+                listen_port_range,
+                announce_port,
+                ..Default::default()
+            },
+        )
+    };
+
+    let session = session_with(Some(15100..17000), None).await.unwrap();
+    assert!(session.tcp_listen_port().is_some());
+    assert_eq!(session.announce_port(), session.tcp_listen_port());
+
+    let session = session_with(Some(15100..17000), Some(51413)).await.unwrap();
+    assert_eq!(session.announce_port(), Some(51413));
+    assert_eq!(session.snapshot().announce_port, Some(51413));
+
+    // Nobody would be listening on it.
+    let session = session_with(None, Some(51413)).await.unwrap();
+    assert_eq!(session.announce_port(), None);
+}
+
+#[tokio::test]
+async fn test_external_ip_override() {
+    let ip: std::net::IpAddr = "203.0.113.7".parse().unwrap();
+    let session = Session::new_with_opts(
+        std::env::temp_dir().join("does_not_exist"),
+        SessionOptions {
+            disable_dht: true,
+            disable_dht_persistence: true,
+            external_ip: Some(ip),
+            ..Default::default()
+        },
+    )
+    .await
+    .unwrap();
+    assert_eq!(session.external_ip(), Some(ip));
+    assert_eq!(session.snapshot().external_ip, Some(ip));
+}
+
+#[tokio::test]
+async fn test_peer_source() {
+    let dir = create_default_random_dir_with_torrents(1, 10_000, Some("rqbit_peer_source"));
+    let torrent = create_torrent(dir.path(), Default::default())
+        .await
+        .unwrap();
+    // Nobody listens there, the peer stays known but not live.
+    let peer = std::net::SocketAddr::from(([127, 0, 0, 1], 1));
+
+    let session = new_session().await;
+    let handle = session
+        .add_torrent(
+            AddTorrent::TorrentFileBytes(Cow::Owned(torrent.as_bytes().unwrap())),
+            Some(AddTorrentOptions {
+                overwrite: true,
+                output_folder: Some(dir.path().to_str().unwrap().to_owned()),
+                initial_peers: Some(vec![peer]),
+                ..Default::default()
+            }),
+        )
+        .await
+        .unwrap()
+        .into_handle()
+        .unwrap();
+
+    let source = timeout(Duration::from_secs(30), async {
+        loop {
+            if let Some(live) = handle.live() {
+                let snapshot = live.per_peer_stats_snapshot(PeerStatsFilter {
+                    state: PeerStatsFilterState::All,
+                });
+                if let Some(stats) = snapshot.peers.get(&peer.to_string()) {
+                    return stats.source;
+                }
+            }
+            tokio::time::sleep(Duration::from_millis(50)).await;
+        }
+    })
+    .await
+    .unwrap();
+    assert_eq!(source, PeerSource::Manual);
+}
+
+#[tokio::test]
+async fn test_add_peer() {
+    let dir = create_default_random_dir_with_torrents(1, 10_000, Some("rqbit_add_peer"));
+    let torrent = create_torrent(dir.path(), Default::default())
+        .await
+        .unwrap();
+    let peer = std::net::SocketAddr::from(([127, 0, 0, 1], 1));
+
+    let session = new_session().await;
+    let handle = session
+        .add_torrent(
+            AddTorrent::TorrentFileBytes(Cow::Owned(torrent.as_bytes().unwrap())),
+            Some(AddTorrentOptions {
+                overwrite: true,
+                output_folder: Some(dir.path().to_str().unwrap().to_owned()),
+                ..Default::default()
+            }),
+        )
+        .await
+        .unwrap()
+        .into_handle()
+        .unwrap();
+    timeout(Duration::from_secs(30), async {
+        while handle.live().is_none() {
+            tokio::time::sleep(Duration::from_millis(50)).await;
+        }
+    })
+    .await
+    .unwrap();
+
+    assert!(handle.add_peer(peer).unwrap());
+    let snapshot = handle
+        .live()
+        .unwrap()
+        .per_peer_stats_snapshot(PeerStatsFilter {
+            state: PeerStatsFilterState::All,
+        });
+    assert_eq!(
+        snapshot.peers.get(&peer.to_string()).map(|s| s.source),
+        Some(PeerSource::Manual)
+    );
+}
+
+#[tokio::test]
+async fn test_trusted_peers() {
+    let dir = create_default_random_dir_with_torrents(1, 10_000, Some("rqbit_trusted_peers"));
+    let torrent = create_torrent(dir.path(), Default::default())
+        .await
+        .unwrap();
+    let trusted = std::net::SocketAddr::from(([127, 0, 0, 1], 1));
+    let untrusted = std::net::SocketAddr::from(([127, 0, 0, 1], 2));
+
+    let session = Session::new_with_opts(
+        std::env::temp_dir().join("does_not_exist"),
+        SessionOptions {
+            disable_dht: true,
+            disable_dht_persistence: true,
+            trusted_peers: Some(vec![trusted]),
+            ..Default::default()
+        },
+    )
+    .await
+    .unwrap();
+    let add = |list_only| {
+        session.add_torrent(
+            AddTorrent::TorrentFileBytes(Cow::Owned(torrent.as_bytes().unwrap())),
+            Some(AddTorrentOptions {
+                list_only,
+                overwrite: true,
+                output_folder: Some(dir.path().to_str().unwrap().to_owned()),
+                initial_peers: Some(vec![untrusted]),
+                ..Default::default()
+            }),
+        )
+    };
+
+    match add(true).await.unwrap() {
+        AddTorrentResponse::ListOnly(r) => assert_eq!(r.seen_peers, vec![trusted]),
+        _ => panic!("expected a list only response"),
+    }
+
+    let handle = add(false).await.unwrap().into_handle().unwrap();
+    timeout(Duration::from_secs(30), async {
+        while handle.live().is_none() {
+            tokio::time::sleep(Duration::from_millis(50)).await;
+        }
+    })
+    .await
+    .unwrap();
+    assert!(handle.add_peer(untrusted).is_err());
+    let snapshot = handle
+        .live()
+        .unwrap()
+        .per_peer_stats_snapshot(PeerStatsFilter {
+            state: PeerStatsFilterState::All,
+        });
+    assert!(snapshot.peers.contains_key(&trusted.to_string()));
+    assert!(!snapshot.peers.contains_key(&untrusted.to_string()));
+}
+
+#[tokio::test]
+async fn test_seed_and_leecher_counts() {
+    let (_dir, _out, session, live) = add_downloading_torrent(1, 40_000, "rqbit_seeds").await;
+
+    let mut seed = RawPeer::connect(&session, &live, 1).await;
+    seed.send(Message::Bitfield(ByteBuf(&[0b1110_0000]))).await;
+    let mut leecher = RawPeer::connect(&session, &live, 2).await;
+    leecher
+        .send(Message::Bitfield(ByteBuf(&[0b0100_0000])))
+        .await;
+    // Without a bitfield, it has nothing.
+    let _empty = RawPeer::connect(&session, &live, 3).await;
+
+    timeout(Duration::from_secs(30), async {
+        loop {
+            let stats = live.stats_snapshot();
+            if (stats.live_seeds, stats.live_leechers) == (1, 2) {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(50)).await;
+        }
+    })
+    .await
+    .unwrap();
+}
+
+#[tokio::test]
+async fn test_duplicate_peer_id() {
+    let (_dir, _out, session, live) = add_downloading_torrent(1, 10_000, "rqbit_duplicate").await;
+
+    // The same peer id from another IP isn't taken for the same peer.
+    let _first = RawPeer::connect_from([127, 0, 0, 1], &session, &live, 1).await;
+    let _other_ip = RawPeer::connect_from([127, 0, 0, 2], &session, &live, 1).await;
+
+    // From the same IP, it is, and the connection is dropped.
+    let addr = SocketAddr::from(([127, 0, 0, 1], session.tcp_listen_port().unwrap()));
+    let mut conn = TcpStream::connect(addr).await.unwrap();
+    let mut buf = Vec::new();
+    Handshake::new(live.info_hash(), Id20::new([1; 20])).serialize(&mut buf);
+    conn.write_all(&buf).await.unwrap();
+    let mut rest = Vec::new();
+    let _ = timeout(Duration::from_secs(30), conn.read_to_end(&mut rest))
+        .await
+        .unwrap();
+    assert_eq!(live.stats_snapshot().peer_stats.live, 2);
+}
+
+#[tokio::test]
+async fn test_subscribe_state_changes() {
+    let session = new_session().await;
+    let (_dir, _, handle) =
+        add_checked_torrent(&session, 1, 10_000, "rqbit_subscribe", Default::default()).await;
+
+    let mut events = Box::pin(handle.subscribe());
+    session.unpause(&handle).unwrap();
+    handle.pause().unwrap();
+
+    let mut states = Vec::new();
+    while states.len() < 2 {
+        let event = timeout(Duration::from_secs(5), events.next())
+            .await
+            .unwrap()
+            .unwrap()
+            .unwrap();
+        if let TorrentEvent::StateChanged { state } = event {
+            states.push(state.to_string());
+        }
+    }
+    assert_eq!(states, ["live", "paused"]);
+}
+
+#[tokio::test]
+async fn test_tracker_status_reset_on_pause() {
+    // A tracker that answers the first announce, and never any after it.
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let url = format!("http://{}/announce", listener.local_addr().unwrap());
+    let tracker = tokio::spawn(async move {
+        let mut unanswered = Vec::new();
+        let (mut conn, _) = listener.accept().await.unwrap();
+        let mut buf = vec![0u8; 4096];
+        let mut len = 0;
+        while !buf[..len].windows(4).any(|w| w == b"\r\n\r\n") {
+            len += conn.read(&mut buf[len..]).await.unwrap();
+        }
+        let body = b"d8:intervali1800e5:peers0:e";
+        let head = format!(
+            "HTTP/1.1 200 OK\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+            body.len()
+        );
+        conn.write_all(head.as_bytes()).await.unwrap();
+        conn.write_all(body).await.unwrap();
+        loop {
+            unanswered.push(listener.accept().await.unwrap());
+        }
+    });
+
+    let dir = create_default_random_dir_with_torrents(1, 10_000, Some("rqbit_tracker_status"));
+    let torrent = create_torrent(dir.path(), Default::default())
+        .await
+        .unwrap();
+    let torrent = TorrentEditor::from_bytes(&torrent.as_bytes().unwrap())
+        .unwrap()
+        .set_trackers(&[vec![url]])
+        .to_bytes();
+    let session = new_session().await;
+    let handle = session
+        .add_torrent(
+            AddTorrent::TorrentFileBytes(Cow::Owned(torrent)),
+            Some(AddTorrentOptions {
+                overwrite: true,
+                output_folder: Some(dir.path().to_str().unwrap().to_owned()),
+                ..Default::default()
+            }),
+        )
+        .await
+        .unwrap()
+        .into_handle()
+        .unwrap();
+    timeout(Duration::from_secs(30), async {
+        while handle
+            .live()
+            .is_none_or(|live| live.stats_snapshot().trackers.is_empty())
+        {
+            tokio::time::sleep(Duration::from_millis(50)).await;
+        }
+    })
+    .await
+    .unwrap();
+
+    // The status of the last run isn't shown, the tracker hasn't answered since.
+    handle.pause().unwrap();
+    session.unpause(&handle).unwrap();
+    assert!(handle.live().unwrap().stats_snapshot().trackers.is_empty());
+    tracker.abort();
+}
+
+#[tokio::test]
+async fn test_wait_until_completed() {
+    let session = new_session().await;
+    let (_dir, _, handle) =
+        add_checked_torrent(&session, 1, 10_000, "rqbit_wait", Default::default()).await;
+
+    let wait = tokio::spawn({
+        let handle = handle.clone();
+        async move { handle.wait_until_completed().await }
+    });
+    tokio::time::sleep(Duration::from_millis(100)).await;
+    assert!(!wait.is_finished());
+
+    // Woken up by the state change, rather than noticing it on the next poll.
+    session.unpause(&handle).unwrap();
+    timeout(Duration::from_millis(500), wait)
+        .await
+        .unwrap()
+        .unwrap()
+        .unwrap();
+}
+
+#[tokio::test]
+async fn test_shutdown() {
+    let state_dir = tempfile::TempDir::with_prefix("rqbit_shutdown_state").unwrap();
+    let new_session = || {
+        Session::new_with_opts(
+            std::env::temp_dir().join("does_not_exist"),
+            SessionOptions {
+                disable_dht: true,
+                disable_dht_persistence: true,
+                persistence: true,
+                persistence_filename: Some(state_dir.path().join("session.json")),
+                ..Default::default()
+            },
+        )
+    };
+
+    let session = new_session().await.unwrap();
+    let (_dir, _, handle) =
+        add_checked_torrent(&session, 1, 10_000, "rqbit_shutdown", Default::default()).await;
+    session.unpause(&handle).unwrap();
+
+    timeout(Duration::from_secs(10), session.shutdown())
+        .await
+        .unwrap()
+        .unwrap();
+    assert!(handle.with_state(|s| matches!(s, ManagedTorrentState::Paused(_))));
+    assert!(session.cancellation_token().is_cancelled());
+    drop(session);
+
+    // The torrent was live when the session shut down, and comes back live. Persisted
+    // torrents are loaded in the background.
+    let session = new_session().await.unwrap();
+    let handle = timeout(Duration::from_secs(10), async {
+        loop {
+            if let Some(handle) = session.get(0).filter(|h| h.live().is_some()) {
+                return handle;
+            }
+            tokio::time::sleep(Duration::from_millis(50)).await;
+        }
+    })
+    .await
+    .unwrap();
+    assert!(handle.stats().finished);
+}
+
+#[tokio::test]
+async fn test_pause_retains_peers() {
+    let dir = create_default_random_dir_with_torrents(1, 10_000, Some("rqbit_pause_peers"));
+    let torrent = create_torrent(dir.path(), Default::default())
+        .await
+        .unwrap();
+    // Nobody listens there, the peer stays known but not live.
+    let peer = std::net::SocketAddr::from(([127, 0, 0, 1], 1));
+
+    let session = new_session().await;
+    let handle = session
+        .add_torrent(
+            AddTorrent::TorrentFileBytes(Cow::Owned(torrent.as_bytes().unwrap())),
+            Some(AddTorrentOptions {
+                overwrite: true,
+                output_folder: Some(dir.path().to_str().unwrap().to_owned()),
+                initial_peers: Some(vec![peer]),
+                ..Default::default()
+            }),
+        )
+        .await
+        .unwrap()
+        .into_handle()
+        .unwrap();
+
+    let known_peer = |handle: &ManagedTorrentHandle| {
+        let live = handle.live()?;
+        let snapshot = live.per_peer_stats_snapshot(PeerStatsFilter {
+            state: PeerStatsFilterState::All,
+        });
+        snapshot.peers.get(&peer.to_string()).map(|s| s.source)
+    };
+    timeout(Duration::from_secs(30), async {
+        while known_peer(&handle).is_none() {
+            tokio::time::sleep(Duration::from_millis(50)).await;
+        }
+    })
+    .await
+    .unwrap();
+
+    handle.pause().unwrap();
+    session.unpause(&handle).unwrap();
+    assert_eq!(known_peer(&handle), Some(PeerSource::Manual));
+}
+
+#[tokio::test]
+async fn test_background_recheck() {
+    let _ = tracing_subscriber::fmt::try_init();
+
+    let session = new_session().await;
+    let (tempdir, _, handle) =
+        add_checked_torrent(&session, 2, 100_000, "rqbit_recheck", Default::default()).await;
+    session.unpause(&handle).unwrap();
+    assert!(handle.stats().finished);
+
+    let corrupted = tempdir.path().join("0.data");
+    let mut data = std::fs::read(&corrupted).unwrap();
+    data[1000] ^= 0xff;
+    std::fs::write(&corrupted, data).unwrap();
+
+    handle.recheck_in_background(None).unwrap();
+    let progress = timeout(Duration::from_secs(30), async {
+        loop {
+            let progress = handle.live().unwrap().stats_snapshot().recheck.unwrap();
+            if !progress.running {
+                return progress;
+            }
+            tokio::time::sleep(Duration::from_millis(50)).await;
+        }
+    })
+    .await
+    .unwrap();
+    assert_eq!(progress.checked_pieces, progress.total_pieces);
+    assert_eq!(progress.broken_pieces, 1);
+    // Still live, with the broken piece to download again.
+    assert!(handle.live().is_some());
+    assert!(!handle.stats().finished);
+}
+
+#[tokio::test]
+async fn test_seed_limits() {
+    let dir = create_default_random_dir_with_torrents(1, 10_000, Some("rqbit_seed_limits"));
+    let torrent = create_torrent(dir.path(), Default::default())
+        .await
+        .unwrap();
+
+    let session = new_session().await;
+    let add = |action| {
+        session.add_torrent(
+            AddTorrent::TorrentFileBytes(Cow::Owned(torrent.as_bytes().unwrap())),
+            Some(AddTorrentOptions {
+                overwrite: true,
+                output_folder: Some(dir.path().to_str().unwrap().to_owned()),
+                // Already complete, so any ratio is reached right away.
+                seed_limits: SeedLimits {
+                    ratio: Some(0.),
+                    time: None,
+                    action,
+                },
+                ..Default::default()
+            }),
+        )
+    };
+    let wait_until_live = |handle: ManagedTorrentHandle| async move {
+        timeout(Duration::from_secs(30), async {
+            while handle.live().is_none() {
+                tokio::time::sleep(Duration::from_millis(50)).await;
+            }
+        })
+        .await
+        .unwrap();
+    };
+
+    let (id, handle) = match add(SeedLimitAction::Pause).await.unwrap() {
+        AddTorrentResponse::Added(id, handle) => (id, handle),
+        _ => panic!("expected the torrent to be added"),
+    };
+    wait_until_live(handle.clone()).await;
+    session.enforce_seed_limits();
+    assert!(handle.with_state(|s| matches!(s, ManagedTorrentState::Paused(_))));
+    session.delete(id, false).unwrap();
+
+    let (id, handle) = match add(SeedLimitAction::Forget).await.unwrap() {
+        AddTorrentResponse::Added(id, handle) => (id, handle),
+        _ => panic!("expected the torrent to be added"),
+    };
+    wait_until_live(handle).await;
+    session.enforce_seed_limits();
+    assert!(session.get(id).is_none());
+
+    // Without a ratio limit of its own, the one of the label applies.
+    session.set_label_policy(
+        "limited",
+        LabelPolicy {
+            seed_ratio_limit: Some(0.),
+            ..Default::default()
+        },
+    );
+    let handle = session
+        .add_torrent(
+            AddTorrent::TorrentFileBytes(Cow::Owned(torrent.as_bytes().unwrap())),
+            Some(AddTorrentOptions {
+                overwrite: true,
+                output_folder: Some(dir.path().to_str().unwrap().to_owned()),
+                label: Some("limited".into()),
+                ..Default::default()
+            }),
+        )
+        .await
+        .unwrap()
+        .into_handle()
+        .unwrap();
+    wait_until_live(handle.clone()).await;
+    session.enforce_seed_limits();
+    assert!(handle.with_state(|s| matches!(s, ManagedTorrentState::Paused(_))));
+}
+
+#[tokio::test]
+async fn test_completion_action() {
+    let seed_dir =
+        create_default_random_dir_with_torrents(2, 10_000, Some("rqbit_completion_seed"));
+    let torrent = create_torrent(seed_dir.path(), Default::default())
+        .await
+        .unwrap();
+    let torrent_bytes = torrent.as_bytes().unwrap();
+
+    let seeder = Session::new_with_opts(
+        std::env::temp_dir().join("does_not_exist"),
+        SessionOptions {
+            disable_dht: true,
+            disable_dht_persistence: true,
+            listen_port_range: Some(15100..17000),
+            ..Default::default()
+        },
+    )
+    .await
+    .unwrap();
+    seeder
+        .add_torrent(
+            AddTorrent::TorrentFileBytes(Cow::Owned(torrent_bytes.clone())),
+            Some(AddTorrentOptions {
+                overwrite: true,
+                output_folder: Some(seed_dir.path().to_str().unwrap().to_owned()),
+                ..Default::default()
+            }),
+        )
+        .await
+        .unwrap()
+        .into_handle()
+        .unwrap()
+        .wait_until_completed()
+        .await
+        .unwrap();
+    let seeder_addr =
+        std::net::SocketAddr::from(([127, 0, 0, 1], seeder.tcp_listen_port().unwrap()));
+
+    let (completed_tx, mut completed_rx) = tokio::sync::mpsc::unbounded_channel();
+    let out_dir = tempfile::TempDir::with_prefix("rqbit_completion_leech").unwrap();
+    let session = new_session().await;
+    let (id, handle) = match session
+        .add_torrent(
+            AddTorrent::TorrentFileBytes(Cow::Owned(torrent_bytes)),
+            Some(AddTorrentOptions {
+                initial_peers: Some(vec![seeder_addr]),
+                output_folder: Some(out_dir.path().to_str().unwrap().to_owned()),
+                completion_action: CompletionAction::Pause,
+                on_completed: Some(Arc::new(move |id, handle| {
+                    let paused = handle.with_state(|s| matches!(s, ManagedTorrentState::Paused(_)));
+                    completed_tx.send((id, paused)).unwrap();
+                })),
+                ..Default::default()
+            }),
+        )
+        .await
+        .unwrap()
+    {
+        AddTorrentResponse::Added(id, handle) => (id, handle),
+        _ => panic!("expected the torrent to be added"),
+    };
+
+    let (completed_id, paused) = timeout(Duration::from_secs(30), completed_rx.recv())
+        .await
+        .unwrap()
+        .unwrap();
+    assert_eq!(completed_id, id);
+    assert!(paused);
+    assert!(handle.stats().finished);
+}
+
+#[tokio::test]
+async fn test_paused_out_of_space_saved() {
+    let _ = tracing_subscriber::fmt::try_init();
+
+    let session = new_session().await;
+    let (_dir, _, handle) = add_checked_torrent(
+        &session,
+        1,
+        100_000,
+        "rqbit_out_of_space",
+        Default::default(),
+    )
+    .await;
+    session.unpause(&handle).unwrap();
+    handle.pause_out_of_space(anyhow::anyhow!("no space left"));
+    assert!(handle.paused_out_of_space_since().is_some());
+
+    // Still resumed once there's room after a restart.
+    let mut state = Vec::new();
+    session.save_state(&mut state).unwrap();
+    drop(session);
+    let session = new_session().await;
+    session.load_state(&state[..]).await.unwrap();
+    let handle = session.get(0).unwrap();
+    wait_until_paused(&handle).await;
+    assert!(handle.paused_out_of_space_since().is_some());
+
+    // Pausing it by hand keeps it paused.
+    handle.pause().unwrap();
+    assert!(handle.paused_out_of_space_since().is_none());
+    assert!(handle.pause().is_err());
+}
+
+#[tokio::test]
+async fn test_file_priorities_paths() {
+    let session = new_session().await;
+    let (_dir, _, handle) = add_checked_torrent(
+        &session,
+        3,
+        10_000,
+        "rqbit_path_priorities",
+        AddTorrentOptions {
+            file_priorities_paths: Some(vec![
+                ("**/*.data".to_owned(), FilePriority::Low),
+                ("1.data".to_owned(), FilePriority::High),
+            ]),
+            file_priorities: Some([(2, FilePriority::Normal)].into_iter().collect()),
+            ..Default::default()
+        },
+    )
+    .await;
+    assert_eq!(
+        handle.file_priorities(),
+        [
+            (0, FilePriority::Low),
+            (1, FilePriority::High),
+            (2, FilePriority::Normal)
+        ]
+        .into_iter()
+        .collect()
+    );
+}
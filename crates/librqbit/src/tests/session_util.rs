@@ -0,0 +1,225 @@
+// Sessions, torrents and hand-driven peers shared by the session tests.
+
+use std::{borrow::Cow, net::SocketAddr, sync::Arc, time::Duration};
+
+use librqbit_core::Id20;
+use peer_binary_protocol::{Handshake, Message, MessageBorrowed, MessageDeserializeError};
+use tempfile::TempDir;
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::TcpStream,
+    time::timeout,
+};
+
+use crate::{
+    create_torrent,
+    session::TorrentId,
+    tests::test_util::create_default_random_dir_with_torrents,
+    torrent_state::{
+        live::peer::stats::snapshot::{PeerStatsFilter, PeerStatsFilterState},
+        ManagedTorrentHandle, TorrentStateLive,
+    },
+    AddTorrent, AddTorrentOptions, AddTorrentResponse, ManagedTorrentState, Session,
+    SessionOptions,
+};
+
+pub(crate) async fn new_session() -> std::sync::Arc<Session> {
+    Session::new_with_opts(
+        std::env::temp_dir().join("does_not_exist"),
+        SessionOptions {
+            disable_dht: true,
+            disable_dht_persistence: true,
+            ..Default::default()
+        },
+    )
+    .await
+    .unwrap()
+}
+
+pub(crate) async fn wait_until_paused(handle: &ManagedTorrentHandle) {
+    timeout(Duration::from_secs(30), async {
+        while !handle.with_state(|s| matches!(s, ManagedTorrentState::Paused(_))) {
+            tokio::time::sleep(Duration::from_millis(50)).await;
+        }
+    })
+    .await
+    .unwrap();
+}
+
+// Adds a paused torrent of "num_files" random files that are already in place, and waits for
+// its initial check. "opts" are used for the rest of the options.
+pub(crate) async fn add_checked_torrent(
+    session: &Arc<Session>,
+    num_files: usize,
+    file_size: usize,
+    prefix: &str,
+    opts: AddTorrentOptions,
+) -> (TempDir, TorrentId, ManagedTorrentHandle) {
+    let dir = create_default_random_dir_with_torrents(num_files, file_size, Some(prefix));
+    let torrent = create_torrent(dir.path(), Default::default())
+        .await
+        .unwrap();
+    let (id, handle) = match session
+        .add_torrent(
+            AddTorrent::TorrentFileBytes(Cow::Owned(torrent.as_bytes().unwrap())),
+            Some(AddTorrentOptions {
+                paused: true,
+                overwrite: true,
+                output_folder: Some(dir.path().to_str().unwrap().to_owned()),
+                ..opts
+            }),
+        )
+        .await
+        .unwrap()
+    {
+        AddTorrentResponse::Added(id, handle) => (id, handle),
+        _ => panic!("expected the torrent to be added"),
+    };
+    wait_until_paused(&handle).await;
+    (dir, id, handle)
+}
+
+// A session that takes peer connections, and a live torrent of "num_files" random files that
+// aren't downloaded yet. Returns the files, for peers to serve them.
+pub(crate) async fn add_downloading_torrent(
+    num_files: usize,
+    file_size: usize,
+    prefix: &str,
+) -> (TempDir, TempDir, Arc<Session>, Arc<TorrentStateLive>) {
+    let dir = create_default_random_dir_with_torrents(num_files, file_size, Some(prefix));
+    let torrent = create_torrent(dir.path(), Default::default())
+        .await
+        .unwrap();
+    let out = TempDir::with_prefix(prefix).unwrap();
+    let session = Session::new_with_opts(
+        std::env::temp_dir().join("does_not_exist"),
+        SessionOptions {
+            disable_dht: true,
+            disable_dht_persistence: true,
+            listen_port_range: Some(21000..23000),
+            ..Default::default()
+        },
+    )
+    .await
+    .unwrap();
+    let handle = session
+        .add_torrent(
+            AddTorrent::TorrentFileBytes(Cow::Owned(torrent.as_bytes().unwrap())),
+            Some(AddTorrentOptions {
+                output_folder: Some(out.path().to_str().unwrap().to_owned()),
+                ..Default::default()
+            }),
+        )
+        .await
+        .unwrap()
+        .into_handle()
+        .unwrap();
+    let live = timeout(Duration::from_secs(30), async {
+        loop {
+            if let Some(live) = handle.live() {
+                return live;
+            }
+            tokio::time::sleep(Duration::from_millis(50)).await;
+        }
+    })
+    .await
+    .unwrap();
+    (dir, out, session, live)
+}
+
+// A peer speaking the peer protocol by hand, to see what the session sends it.
+pub(crate) struct RawPeer {
+    pub(crate) conn: TcpStream,
+    buf: Vec<u8>,
+}
+
+impl RawPeer {
+    // Connects to the session, and waits until the torrent has the peer live.
+    pub(crate) async fn connect(session: &Session, live: &TorrentStateLive, peer_id: u8) -> Self {
+        Self::connect_from([127, 0, 0, 1], session, live, peer_id).await
+    }
+
+    // Same as connect(), from another loopback IP.
+    pub(crate) async fn connect_from(
+        ip: [u8; 4],
+        session: &Session,
+        live: &TorrentStateLive,
+        peer_id: u8,
+    ) -> Self {
+        let addr = SocketAddr::from(([127, 0, 0, 1], session.tcp_listen_port().unwrap()));
+        let socket = tokio::net::TcpSocket::new_v4().unwrap();
+        socket.bind(SocketAddr::from((ip, 0))).unwrap();
+        let mut conn = socket.connect(addr).await.unwrap();
+        let mut buf = Vec::new();
+        Handshake::new(live.info_hash(), Id20::new([peer_id; 20])).serialize(&mut buf);
+        conn.write_all(&buf).await.unwrap();
+        let mut reply = [0u8; 68];
+        timeout(Duration::from_secs(5), conn.read_exact(&mut reply))
+            .await
+            .unwrap()
+            .unwrap();
+        let local = conn.local_addr().unwrap().to_string();
+        timeout(Duration::from_secs(30), async {
+            while !live
+                .per_peer_stats_snapshot(PeerStatsFilter {
+                    state: PeerStatsFilterState::Live,
+                })
+                .peers
+                .contains_key(&local)
+            {
+                tokio::time::sleep(Duration::from_millis(50)).await;
+            }
+        })
+        .await
+        .unwrap();
+        Self {
+            conn,
+            buf: Vec::new(),
+        }
+    }
+
+    pub(crate) async fn send(&mut self, msg: MessageBorrowed<'_>) {
+        let mut buf = Vec::new();
+        msg.serialize(&mut buf, &|_| None).unwrap();
+        self.conn.write_all(&buf).await.unwrap();
+    }
+
+    // Reads messages until "f" returns something for one of them.
+    pub(crate) async fn next_message<T>(
+        &mut self,
+        mut f: impl FnMut(MessageBorrowed<'_>) -> Option<T>,
+    ) -> T {
+        timeout(Duration::from_secs(30), async {
+            loop {
+                match MessageBorrowed::deserialize(&self.buf) {
+                    Ok((msg, len)) => {
+                        let found = f(msg);
+                        self.buf.drain(..len);
+                        if let Some(found) = found {
+                            return found;
+                        }
+                    }
+                    Err(MessageDeserializeError::NotEnoughData(..)) => {
+                        let mut chunk = [0u8; 16384];
+                        let read = self.conn.read(&mut chunk).await.unwrap();
+                        assert!(read > 0, "connection closed");
+                        self.buf.extend_from_slice(&chunk[..read]);
+                    }
+                    Err(e) => panic!("{e}"),
+                }
+            }
+        })
+        .await
+        .unwrap()
+    }
+
+    // The next request or cancel, as (is_cancel, index, begin).
+    pub(crate) async fn next_request(&mut self) -> (bool, u32, u32) {
+        self.next_message(|msg| match msg {
+            Message::Request(r) => Some((false, r.index, r.begin)),
+            Message::Cancel(r) => Some((true, r.index, r.begin)),
+            _ => None,
+        })
+        .await
+    }
+}
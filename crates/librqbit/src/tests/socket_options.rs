@@ -0,0 +1,55 @@
+use std::time::Duration;
+
+use super::session_util::add_checked_torrent;
+use crate::{AddTorrentOptions, PeerConnectionOptions, PeerSocketOptions, Session, SessionOptions};
+
+#[tokio::test]
+async fn test_peer_socket_options() {
+    let session = Session::new_with_opts(
+        std::env::temp_dir().join("does_not_exist"),
+        SessionOptions {
+            disable_dht: true,
+            disable_dht_persistence: true,
+            peer_opts: Some(PeerConnectionOptions {
+                socket: PeerSocketOptions {
+                    nodelay: Some(true),
+                    send_buffer_size: Some(1 << 20),
+                    ..Default::default()
+                },
+                ..Default::default()
+            }),
+            ..Default::default()
+        },
+    )
+    .await
+    .unwrap();
+    let (_dir, _, handle) = add_checked_torrent(
+        &session,
+        1,
+        10_000,
+        "rqbit_socket_options",
+        AddTorrentOptions {
+            peer_opts: Some(PeerConnectionOptions {
+                socket: PeerSocketOptions {
+                    send_buffer_size: Some(4 << 20),
+                    keepalive_time: Some(Duration::from_secs(30)),
+                    ..Default::default()
+                },
+                ..Default::default()
+            }),
+            ..Default::default()
+        },
+    )
+    .await;
+
+    // Set per torrent, or else for the session.
+    assert_eq!(
+        handle.info().peer_connection_options().socket,
+        PeerSocketOptions {
+            nodelay: Some(true),
+            keepalive_time: Some(Duration::from_secs(30)),
+            send_buffer_size: Some(4 << 20),
+            ..Default::default()
+        }
+    );
+}
@@ -0,0 +1,135 @@
+use std::time::Duration;
+
+use buffers::ByteBuf;
+use peer_binary_protocol::Message;
+use tokio::{io::AsyncReadExt, time::timeout};
+
+use super::session_util::{add_checked_torrent, add_downloading_torrent, new_session, RawPeer};
+use crate::ReadaheadOptions;
+
+#[tokio::test]
+async fn test_stream_seek() {
+    use tokio::io::AsyncSeekExt;
+
+    let session = new_session().await;
+    let (dir, _, handle) =
+        add_checked_torrent(&session, 2, 40_000, "rqbit_stream_seek", Default::default()).await;
+    let data = std::fs::read(dir.path().join("1.data")).unwrap();
+    session.unpause(&handle).unwrap();
+
+    let mut reader = handle.stream(1).unwrap();
+    assert_eq!(reader.len(), 40_000);
+    // Across the boundary of the first two pieces of the file.
+    reader.seek(std::io::SeekFrom::Start(16_000)).await.unwrap();
+    let mut buf = vec![0u8; 1000];
+    reader.read_exact(&mut buf).await.unwrap();
+    assert_eq!(buf, data[16_000..17_000]);
+    assert_eq!(reader.position(), 17_000);
+
+    reader.seek(std::io::SeekFrom::End(-100)).await.unwrap();
+    let mut rest = Vec::new();
+    reader.read_to_end(&mut rest).await.unwrap();
+    assert_eq!(rest, data[39_900..]);
+    assert!(reader
+        .seek(std::io::SeekFrom::Current(-50_000))
+        .await
+        .is_err());
+}
+
+#[tokio::test]
+async fn test_stream_waits_for_piece() {
+    use peer_binary_protocol::Piece;
+    use tokio::io::AsyncSeekExt;
+
+    // Three pieces of one chunk.
+    let (dir, _out, session, live) = add_downloading_torrent(1, 40_000, "rqbit_stream").await;
+    let data = std::fs::read(dir.path().join("0.data")).unwrap();
+
+    let mut reader = session.get(0).unwrap().stream(0).unwrap();
+    reader.set_readahead(None);
+    let read = tokio::spawn(async move {
+        reader.seek(std::io::SeekFrom::Start(35_000)).await.unwrap();
+        let mut buf = vec![0u8; 100];
+        reader.read_exact(&mut buf).await.unwrap();
+        buf
+    });
+    tokio::time::sleep(Duration::from_millis(100)).await;
+    assert!(!read.is_finished());
+
+    // The piece being read is requested first.
+    let mut peer = RawPeer::connect(&session, &live, 1).await;
+    peer.send(Message::Bitfield(ByteBuf(&[0b1110_0000]))).await;
+    peer.send(Message::Unchoke).await;
+    let r = peer
+        .next_message(|msg| match msg {
+            Message::Request(r) => Some(r),
+            _ => None,
+        })
+        .await;
+    assert_eq!(r.index, 2);
+    let start = (r.index * 16384 + r.begin) as usize;
+    peer.send(Message::Piece(Piece::from_data(
+        r.index,
+        r.begin,
+        &data[start..start + r.length as usize],
+    )))
+    .await;
+
+    let buf = timeout(Duration::from_secs(30), read)
+        .await
+        .unwrap()
+        .unwrap();
+    assert_eq!(buf, data[35_000..35_100]);
+}
+
+#[tokio::test]
+async fn test_stream_readahead() {
+    use peer_binary_protocol::Piece;
+    use tokio::io::AsyncSeekExt;
+
+    // Four pieces of one chunk.
+    let (dir, _out, session, live) = add_downloading_torrent(1, 60_000, "rqbit_readahead").await;
+    let data = std::fs::read(dir.path().join("0.data")).unwrap();
+
+    let mut reader = session.get(0).unwrap().stream(0).unwrap();
+    reader.set_readahead(Some(ReadaheadOptions {
+        duration: Duration::from_secs(30),
+        min_bytes: 2 * 16384,
+        max_bytes: 2 * 16384,
+    }));
+    let read = tokio::spawn(async move {
+        reader.seek(std::io::SeekFrom::Start(16384)).await.unwrap();
+        let mut buf = vec![0u8; 100];
+        reader.read_exact(&mut buf).await.unwrap();
+        buf
+    });
+
+    // The piece being read comes first, then the one after it rather than the first one.
+    let mut peer = RawPeer::connect(&session, &live, 1).await;
+    peer.send(Message::Bitfield(ByteBuf(&[0b1111_0000]))).await;
+    peer.send(Message::Unchoke).await;
+    let mut requested = Vec::new();
+    while requested.len() < 2 {
+        let r = peer
+            .next_message(|msg| match msg {
+                Message::Request(r) => Some(r),
+                _ => None,
+            })
+            .await;
+        let start = (r.index * 16384 + r.begin) as usize;
+        peer.send(Message::Piece(Piece::from_data(
+            r.index,
+            r.begin,
+            &data[start..start + r.length as usize],
+        )))
+        .await;
+        requested.push(r.index);
+    }
+    assert_eq!(requested, vec![1, 2]);
+
+    let buf = timeout(Duration::from_secs(30), read)
+        .await
+        .unwrap()
+        .unwrap();
+    assert_eq!(buf, data[16384..16484]);
+}
@@ -0,0 +1,132 @@
+use std::{borrow::Cow, sync::Arc, time::Duration};
+
+use buffers::ByteBuf;
+use peer_binary_protocol::{Message, Piece, Request};
+use tempfile::TempDir;
+use tokio::time::timeout;
+
+use super::session_util::{add_checked_torrent, RawPeer};
+use crate::{
+    create_torrent, tests::test_util::create_default_random_dir_with_torrents, AddTorrent,
+    AddTorrentOptions, Session, SessionOptions,
+};
+
+async fn listening_session(write_cache_bytes: Option<u64>) -> Arc<Session> {
+    Session::new_with_opts(
+        std::env::temp_dir().join("does_not_exist"),
+        SessionOptions {
+            disable_dht: true,
+            disable_dht_persistence: true,
+            listen_port_range: Some(21000..23000),
+            write_cache_bytes,
+            ..Default::default()
+        },
+    )
+    .await
+    .unwrap()
+}
+
+// Requests a chunk, and returns what the session sent for it.
+async fn request_chunk(peer: &mut RawPeer, index: u32, begin: u32, length: u32) -> Vec<u8> {
+    peer.send(Message::Request(Request {
+        index,
+        begin,
+        length,
+    }))
+    .await;
+    peer.next_message(|msg| match msg {
+        Message::Piece(p) if p.index == index && p.begin == begin => Some(p.block.to_vec()),
+        _ => None,
+    })
+    .await
+}
+
+#[tokio::test]
+async fn test_upload_from_files() {
+    let session = listening_session(None).await;
+    let (dir, _, handle) = add_checked_torrent(
+        &session,
+        2,
+        10_000,
+        "rqbit_upload_files",
+        Default::default(),
+    )
+    .await;
+    session.unpause(&handle).unwrap();
+    let live = timeout(Duration::from_secs(30), async {
+        loop {
+            if let Some(live) = handle.live() {
+                return live;
+            }
+            tokio::time::sleep(Duration::from_millis(50)).await;
+        }
+    })
+    .await
+    .unwrap();
+    let mut data = std::fs::read(dir.path().join("0.data")).unwrap();
+    data.extend(std::fs::read(dir.path().join("1.data")).unwrap());
+
+    // Sent from the files on Linux: the first piece spans both of them.
+    let mut peer = RawPeer::connect(&session, &live, 1).await;
+    assert_eq!(request_chunk(&mut peer, 0, 0, 16384).await, &data[..16384]);
+    assert_eq!(request_chunk(&mut peer, 1, 0, 3616).await, &data[16384..]);
+    assert_eq!(live.write_cache_stats().cached_bytes, 0);
+}
+
+#[tokio::test]
+async fn test_upload_from_write_cache() {
+    // Three pieces of one chunk.
+    let dir = create_default_random_dir_with_torrents(1, 40_000, Some("rqbit_upload_cache"));
+    let data = std::fs::read(dir.path().join("0.data")).unwrap();
+    let torrent = create_torrent(dir.path(), Default::default())
+        .await
+        .unwrap();
+    let out = TempDir::with_prefix("rqbit_upload_cache").unwrap();
+    let session = listening_session(Some(1 << 20)).await;
+    let handle = session
+        .add_torrent(
+            AddTorrent::TorrentFileBytes(Cow::Owned(torrent.as_bytes().unwrap())),
+            Some(AddTorrentOptions {
+                output_folder: Some(out.path().to_str().unwrap().to_owned()),
+                ..Default::default()
+            }),
+        )
+        .await
+        .unwrap()
+        .into_handle()
+        .unwrap();
+    let live = timeout(Duration::from_secs(30), async {
+        loop {
+            if let Some(live) = handle.live() {
+                return live;
+            }
+            tokio::time::sleep(Duration::from_millis(50)).await;
+        }
+    })
+    .await
+    .unwrap();
+
+    // Only the first piece is sent, so the cache isn't under pressure to write it out.
+    let mut peer = RawPeer::connect(&session, &live, 1).await;
+    peer.send(Message::Bitfield(ByteBuf(&[0b1110_0000]))).await;
+    peer.send(Message::Unchoke).await;
+    peer.next_message(|msg| match msg {
+        Message::Request(r) if r.index == 0 => Some(()),
+        _ => None,
+    })
+    .await;
+    peer.send(Message::Piece(Piece::from_data(0, 0, &data[..16384])))
+        .await;
+    timeout(Duration::from_secs(30), async {
+        while live.write_cache_stats().dirty_bytes == 0 {
+            tokio::time::sleep(Duration::from_millis(50)).await;
+        }
+    })
+    .await
+    .unwrap();
+    let on_disk = std::fs::read(out.path().join("0.data")).unwrap_or_default();
+    assert_ne!(on_disk.get(..16384), Some(&data[..16384]));
+
+    // Sent from memory, the file doesn't have it yet.
+    assert_eq!(request_chunk(&mut peer, 0, 0, 16384).await, &data[..16384]);
+}
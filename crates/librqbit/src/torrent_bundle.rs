@@ -0,0 +1,98 @@
+// A single torrent exported with everything needed to restore it: the metainfo, the
+// verified pieces (informational, like the stats), options, the label and its policy. Used for backups and for moving
+// torrents between instances without the session database.
+
+use std::{
+    io::{Read, Write},
+    path::PathBuf,
+    sync::Arc,
+};
+
+use anyhow::Context;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    label_policy::LabelPolicy,
+    session::{AddTorrentResponse, SerializedTorrent, Session, TorrentId},
+};
+
+const BUNDLE_VERSION: u32 = 1;
+
+// Informational, not used when importing.
+#[derive(Serialize, Deserialize)]
+struct BundleStats {
+    progress_bytes: u64,
+    uploaded_bytes: u64,
+    total_bytes: u64,
+    finished: bool,
+}
+
+#[derive(Serialize, Deserialize)]
+struct TorrentBundle {
+    version: u32,
+    torrent: SerializedTorrent,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    label_policy: Option<LabelPolicy>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    stats: Option<BundleStats>,
+}
+
+impl Session {
+    /// Write the torrent and its progress to "writer", to be restored with
+    /// [`Session::import_torrent`], possibly by another instance.
+    pub fn export_torrent(&self, id: TorrentId, writer: impl Write) -> anyhow::Result<()> {
+        let torrent = self.get(id).context("torrent not found")?;
+        let stats = torrent.stats();
+        // The files are likely copied elsewhere next.
+        if let Some(live) = torrent.live() {
+            live.sync_files()?;
+        }
+        let bundle = TorrentBundle {
+            version: BUNDLE_VERSION,
+            torrent: SerializedTorrent::from_torrent(&torrent),
+            label_policy: torrent
+                .info()
+                .label
+                .as_deref()
+                .and_then(|l| self.label_policy(l)),
+            stats: Some(BundleStats {
+                progress_bytes: stats.progress_bytes,
+                uploaded_bytes: stats.uploaded_bytes,
+                total_bytes: stats.total_bytes,
+                finished: stats.finished,
+            }),
+        };
+        serde_json::to_writer(writer, &bundle).context("error serializing torrent bundle")
+    }
+
+    /// Add a torrent exported with [`Session::export_torrent`]. Its files are expected in
+    /// "output_folder" if given, otherwise where they were when exporting. All pieces are
+    /// checked, as the files may not be the ones the bundle's progress was saved for.
+    ///
+    /// The label policy is restored only if this session doesn't have one for the label.
+    pub async fn import_torrent(
+        self: &Arc<Self>,
+        reader: impl Read,
+        output_folder: Option<PathBuf>,
+    ) -> anyhow::Result<AddTorrentResponse> {
+        let mut bundle: TorrentBundle =
+            serde_json::from_reader(reader).context("error deserializing torrent bundle")?;
+        if bundle.version > BUNDLE_VERSION {
+            anyhow::bail!(
+                "torrent bundle version {} is not supported, expected at most {}",
+                bundle.version,
+                BUNDLE_VERSION
+            );
+        }
+        if let Some(output_folder) = output_folder {
+            bundle.torrent.output_folder = output_folder;
+        }
+        if let (Some(label), Some(policy)) = (&bundle.torrent.label, bundle.label_policy) {
+            if self.label_policy(label).is_none() {
+                self.set_label_policy(label, policy);
+            }
+        }
+        self.add_serialized_torrent(bundle.torrent, None, true)
+            .await
+    }
+}
@@ -0,0 +1,67 @@
+// Events of a torrent, for embedders that would rather be told than poll the stats snapshots.
+// A subscriber that falls behind misses the oldest events, and is told how many it missed.
+
+use std::net::SocketAddr;
+
+use serde::Serialize;
+use tokio::sync::broadcast;
+use tokio_stream::wrappers::BroadcastStream;
+
+use super::TorrentStatsState;
+
+const CAPACITY: usize = 1024;
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum TorrentEvent {
+    /// A piece was downloaded and verified.
+    PieceCompleted {
+        piece: u32,
+    },
+    /// All the selected files are downloaded.
+    Finished,
+    PeerConnected {
+        addr: SocketAddr,
+    },
+    /// A live peer went away. Without an error, it had nothing more to give or take.
+    PeerDisconnected {
+        addr: SocketAddr,
+        error: Option<String>,
+    },
+    /// An announce to a tracker succeeded, or failed with an error.
+    TrackerAnnounce {
+        tracker: String,
+        peers: Option<usize>,
+        error: Option<String>,
+    },
+    /// The torrent stopped because of this error, followed by a StateChanged to "error".
+    Error {
+        error: String,
+    },
+    StateChanged {
+        state: TorrentStatsState,
+    },
+}
+
+pub(crate) struct TorrentEvents {
+    tx: broadcast::Sender<TorrentEvent>,
+}
+
+impl Default for TorrentEvents {
+    fn default() -> Self {
+        Self {
+            tx: broadcast::channel(CAPACITY).0,
+        }
+    }
+}
+
+impl TorrentEvents {
+    pub fn emit(&self, event: TorrentEvent) {
+        // Fails only when nobody listens.
+        let _ = self.tx.send(event);
+    }
+
+    pub fn subscribe(&self) -> BroadcastStream<TorrentEvent> {
+        BroadcastStream::new(self.tx.subscribe())
+    }
+}
@@ -1,6 +1,11 @@
 use std::{
-    fs::{File, OpenOptions},
-    sync::{atomic::AtomicU64, Arc},
+    collections::HashMap,
+    fs::File,
+    path::PathBuf,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
     time::Instant,
 };
 
@@ -12,26 +17,42 @@ use sha1w::Sha1;
 use size_format::SizeFormatterBinary as SF;
 use tracing::{debug, info, warn};
 
-use crate::{chunk_tracker::ChunkTracker, file_ops::FileOps};
+use crate::{
+    chunk_tracker::ChunkTracker,
+    file_ops::{allocate_file, FileAllocation, FileOps, InitialCheckResults},
+    file_selection::{compute_piece_priorities, compute_selected_pieces, FilePriority},
+    output_dir::OpenMode,
+    part_file::{part_file_path, PartFile},
+    resume_data::FileState,
+    type_aliases::BF,
+};
 
 use super::{paused::TorrentStatePaused, ManagedTorrentInfo};
 
-fn ensure_file_length(file: &File, length: u64) -> anyhow::Result<()> {
-    Ok(file.set_len(length)?)
-}
-
 pub struct TorrentStateInitializing {
     pub(crate) meta: Arc<ManagedTorrentInfo>,
     pub(crate) only_files: Option<Vec<usize>>,
+    pub(crate) file_priorities: HashMap<usize, FilePriority>,
     pub(crate) checked_bytes: AtomicU64,
+    check_started: Mutex<Option<Instant>>,
+    // Progress restored from a saved session, takes precedence over resume data.
+    pub(crate) restored_have_pieces: Option<BF>,
 }
 
 impl TorrentStateInitializing {
-    pub fn new(meta: Arc<ManagedTorrentInfo>, only_files: Option<Vec<usize>>) -> Self {
+    pub fn new(
+        meta: Arc<ManagedTorrentInfo>,
+        only_files: Option<Vec<usize>>,
+        file_priorities: HashMap<usize, FilePriority>,
+        restored_have_pieces: Option<BF>,
+    ) -> Self {
         Self {
             meta,
             only_files,
+            file_priorities,
             checked_bytes: AtomicU64::new(0),
+            check_started: Mutex::new(None),
+            restored_have_pieces,
         }
     }
 
@@ -40,50 +61,204 @@ impl TorrentStateInitializing {
             .load(std::sync::atomic::Ordering::Relaxed)
     }
 
+    /// How long the checksum validation has been running, None if it didn't start.
+    pub fn check_elapsed(&self) -> Option<std::time::Duration> {
+        self.check_started.lock().map(|s| s.elapsed())
+    }
+
+    fn load_resume_data(
+        &self,
+        files: &[Arc<Mutex<File>>],
+        filenames: &[PathBuf],
+        part_file: &PartFile,
+    ) -> anyhow::Result<Option<InitialCheckResults>> {
+        if self.meta.options.force_recheck {
+            return Ok(None);
+        }
+        // The file states are only known from resume data.
+        let (mut have_pieces, saved_files) = match &self.restored_have_pieces {
+            Some(h) => (h.clone(), Vec::new()),
+            None => match self
+                .meta
+                .resume_store
+                .as_ref()
+                .map(|s| s.load(self.meta.info_hash))
+                .transpose()?
+                .flatten()
+            {
+                Some(d) => (d.have_pieces()?, d.files),
+                None => return Ok(None),
+            },
+        };
+        let lengths = &self.meta.lengths;
+        if have_pieces.len() != lengths.piece_bitfield_bytes() * 8 {
+            anyhow::bail!("resume data bitfield has wrong length");
+        }
+
+        // Don't trust pieces in files that changed size since, e.g. deleted or truncated.
+        let piece_length = lengths.default_piece_length() as u64;
+        let mut to_verify = BF::repeat(false, have_pieces.len());
+        let mut offset = 0u64;
+        for (idx, (filename, (_, expected_len))) in filenames
+            .iter()
+            .zip(self.meta.info.iter_filenames_and_lengths()?)
+            .enumerate()
+        {
+            let start = offset;
+            offset += expected_len;
+            if expected_len == 0 {
+                continue;
+            }
+            let first_piece = (start / piece_length) as usize;
+            let last_piece = ((offset - 1) / piece_length) as usize;
+            if part_file.is_parted(idx) {
+                // Only the pieces shared with other files can have been downloaded, and only
+                // if the parts file has their bytes.
+                if first_piece + 1 < last_piece {
+                    have_pieces[first_piece + 1..last_piece].fill(false);
+                }
+                if FileState::of(filename).map(|s| s.len) < Some(part_file.stored_end(idx)) {
+                    have_pieces[first_piece..=last_piece].fill(false);
+                }
+                continue;
+            }
+            let current = FileState::of(filename);
+            let actual_len = current.map(|s| s.len);
+            if actual_len != Some(expected_len) {
+                debug!(
+                    ?filename,
+                    ?actual_len,
+                    expected_len,
+                    "file size changed, ignoring resume data for its pieces"
+                );
+                have_pieces[first_piece..=last_piece].fill(false);
+                continue;
+            }
+            // A file modified since may have been written to by someone else, or by us after
+            // the resume data was saved if it's incomplete. Either way, the pieces we have in it
+            // are checked again.
+            let saved = saved_files.get(idx).copied().flatten();
+            if saved.is_some_and(|saved| saved != current.unwrap()) {
+                debug!(
+                    ?filename,
+                    "file was modified since the progress was saved, rechecking its pieces"
+                );
+                for piece in first_piece..=last_piece {
+                    if have_pieces[piece] {
+                        to_verify.set(piece, true);
+                    }
+                }
+            }
+        }
+
+        if to_verify.any() {
+            info!(
+                pieces = to_verify.count_ones(),
+                "checking the pieces of the files modified since the progress was saved"
+            );
+            let file_ops = FileOps::<Sha1>::new(&self.meta.info, files, part_file, lengths);
+            for piece in to_verify.iter_ones() {
+                let Some(index) = lengths.validate_piece_index(piece as u32) else {
+                    continue;
+                };
+                if !file_ops.check_piece_on_disk(index).unwrap_or(false) {
+                    debug!(piece, "piece changed since the progress was saved");
+                    have_pieces.set(piece, false);
+                }
+            }
+        }
+
+        let selected =
+            compute_selected_pieces(&self.meta.info, lengths, self.only_files.as_deref())?;
+        Ok(Some(InitialCheckResults::from_have_pieces(
+            have_pieces,
+            &selected,
+            lengths,
+        )))
+    }
+
     pub async fn check(&self) -> anyhow::Result<TorrentStatePaused> {
+        let part_file = PartFile::new(&self.meta.info, &self.meta.lengths)?;
         let (files, filenames) = {
             let mut files =
                 Vec::<Arc<Mutex<File>>>::with_capacity(self.meta.info.iter_file_lengths()?.count());
             let mut filenames = Vec::new();
-            for (path_bits, _) in self.meta.info.iter_filenames_and_lengths()? {
-                let mut full_path = self.meta.out_dir.clone();
+            let mut own_filenames = Vec::new();
+            let dir = self.meta.out_dir_handle();
+            let out_dir = dir.path().to_owned();
+            let part_path = part_file_path(&out_dir, self.meta.info_hash);
+            for (idx, (path_bits, _)) in self.meta.info.iter_filenames_and_lengths()?.enumerate() {
+                let mut full_path = out_dir.clone();
                 let relative_path = path_bits
                     .to_pathbuf()
                     .context("error converting file to path")?;
                 full_path.push(relative_path);
 
-                std::fs::create_dir_all(full_path.parent().unwrap())?;
+                let selected = self
+                    .only_files
+                    .as_ref()
+                    .map(|v| v.contains(&idx))
+                    .unwrap_or(true);
+                let exists = dir.exists(&full_path);
+                own_filenames.push(full_path.clone());
+
+                // Files that aren't downloaded aren't created, what's needed of them is kept
+                // in the parts file. Existing ones are used as before.
+                if !exists && (!selected || dir.exists(&part_path)) {
+                    let file = dir.open(&part_path, OpenMode::Create)?;
+                    part_file.set_parted(idx, true);
+                    filenames.push(part_path.clone());
+                    files.push(Arc::new(Mutex::new(file)));
+                    continue;
+                }
+
                 let file = if self.meta.options.overwrite {
-                    OpenOptions::new()
-                        .create(true)
-                        .read(true)
-                        .write(true)
-                        .open(&full_path)
-                        .with_context(|| {
-                            format!("error opening {full_path:?} in read/write mode")
-                        })?
+                    dir.open(&full_path, OpenMode::Create)?
                 } else {
-                    // TODO: create_new does not seem to work with read(true), so calling this twice.
-                    OpenOptions::new()
-                        .create_new(true)
-                        .write(true)
-                        .open(&full_path)
-                        .with_context(|| format!("error creating {:?}", &full_path))?;
-                    OpenOptions::new().read(true).write(true).open(&full_path)?
+                    dir.open(&full_path, OpenMode::CreateNew)?
                 };
                 filenames.push(full_path);
                 files.push(Arc::new(Mutex::new(file)))
             }
+
+            // Selected files that were downloaded into the parts file before get their own.
+            part_file.unpart_selected_files(
+                &dir,
+                &files,
+                &mut filenames,
+                &own_filenames,
+                &part_path,
+                self.only_files.as_deref(),
+                true,
+            )?;
             (files, filenames)
         };
 
         debug!("computed lengths: {:?}", &self.meta.lengths);
 
-        info!("Doing initial checksum validation, this might take a while...");
-        let initial_check_results = self.meta.spawner.spawn_block_in_place(|| {
-            FileOps::<Sha1>::new(&self.meta.info, &files, &self.meta.lengths)
-                .initial_check(self.only_files.as_deref(), &self.checked_bytes)
-        })?;
+        let initial_check_results = match self
+            .meta
+            .spawner
+            .spawn_block_in_place(|| self.load_resume_data(&files, &filenames, &part_file))
+        {
+            Ok(Some(results)) => {
+                info!("Restored progress from resume data, skipping initial checksum validation");
+                self.checked_bytes
+                    .store(self.meta.lengths.total_length(), Ordering::Relaxed);
+                results
+            }
+            r => {
+                if let Err(e) = r {
+                    warn!("error loading resume data: {:#}", e);
+                }
+                info!("Doing initial checksum validation, this might take a while...");
+                *self.check_started.lock() = Some(Instant::now());
+                self.meta.spawner.spawn_block_in_place(|| {
+                    FileOps::<Sha1>::new(&self.meta.info, &files, &part_file, &self.meta.lengths)
+                        .initial_check(self.only_files.as_deref(), &self.checked_bytes)
+                })?
+            }
+        };
 
         info!(
             "Initial check results: have {}, needed {}, total selected {}",
@@ -107,36 +282,55 @@ impl TorrentStateInitializing {
                     continue;
                 }
                 let now = Instant::now();
-                if let Err(err) = ensure_file_length(&file.lock(), length) {
-                    warn!(
-                        "Error setting length for file {:?} to {}: {:#?}",
-                        name, length, err
-                    );
-                } else {
-                    debug!(
-                        "Set length for file {:?} to {} in {:?}",
-                        name,
+                let allocation = self.meta.options.file_allocation;
+                let res = allocate_file(&mut file.lock(), length, allocation);
+                match (res, allocation) {
+                    (Ok(()), _) => debug!(
+                        "Allocated {} for file {:?} ({}) in {:?}",
                         SF::new(length),
+                        name,
+                        allocation,
                         now.elapsed()
-                    );
+                    ),
+                    // Preallocation was asked for to fail early, so don't continue without it.
+                    (Err(err), FileAllocation::Preallocate) => {
+                        return Err(err.context(format!(
+                            "error preallocating {} for {:?}",
+                            SF::new(length),
+                            name
+                        )))
+                    }
+                    (Err(err), FileAllocation::Sparse) => warn!(
+                        "Error setting length for file {:?} to {}: {:#?}",
+                        name, length, err
+                    ),
                 }
             }
-        });
+            Ok::<_, anyhow::Error>(())
+        })?;
 
-        let chunk_tracker = ChunkTracker::new(
+        let mut chunk_tracker = ChunkTracker::new(
             initial_check_results.needed_pieces,
             initial_check_results.have_pieces,
             self.meta.lengths,
             initial_check_results.total_selected_bytes,
         );
+        chunk_tracker.set_piece_priorities(compute_piece_priorities(
+            &self.meta.info,
+            &self.meta.lengths,
+            self.only_files.as_deref(),
+            &self.file_priorities,
+        )?);
 
         let paused = TorrentStatePaused {
             info: self.meta.clone(),
             files,
             filenames,
+            part_file: Arc::new(part_file),
             chunk_tracker,
             have_bytes: initial_check_results.have_bytes,
             needed_bytes: initial_check_results.needed_bytes,
+            known_peers: Vec::new(),
         };
         Ok(paused)
     }
@@ -0,0 +1,174 @@
+// Holepunching (BEP 55), for peers behind NATs that drop incoming connections. A peer that can't
+// reach another one sends a "rendezvous" to a peer connected to both. That relay tells both sides
+// to connect to each other. Each side connects from the port it listens on to the port the other
+// one listens on, so that the connections cross in the NATs and become one (a TCP simultaneous
+// open).
+//
+// We relay rendezvous and answer the relays' "connect", but don't send rendezvous ourselves:
+// without PEX we don't learn which peers are connected to the ones we can't reach.
+
+use std::net::SocketAddr;
+
+use backoff::backoff::Backoff;
+use buffers::ByteBuf;
+use peer_binary_protocol::{
+    extended::{
+        handshake::ExtendedHandshake,
+        ut_holepunch::{UtHolepunch, UtHolepunchError},
+        ExtendedMessage,
+    },
+    MessageOwned,
+};
+use tracing::debug;
+
+use crate::{peer_connection::WriterRequest, type_aliases::PeerHandle};
+
+use super::{
+    peer::{PeerSource, PeerState, PeerTx},
+    peers::canonical_peer_addr,
+    PeerHandler, TorrentStateLive,
+};
+
+fn send_holepunch(tx: &PeerTx, msg: UtHolepunch) -> bool {
+    tx.send(WriterRequest::Message(MessageOwned::Extended(
+        ExtendedMessage::UtHolepunch(msg),
+    )))
+    .is_ok()
+}
+
+impl TorrentStateLive {
+    // The live peer listening at "addr" that supports holepunching, and where it listens.
+    fn find_holepunch_peer(
+        &self,
+        addr: SocketAddr,
+        requester: PeerHandle,
+    ) -> Result<(PeerTx, SocketAddr), UtHolepunchError> {
+        let mut error = UtHolepunchError::NoSuchPeer;
+        for e in self.peers.states.iter() {
+            let live = e.value().state.get_live();
+            let listen_addr = live.and_then(|l| l.holepunch_addr);
+            if *e.key() != addr && listen_addr != Some(addr) {
+                continue;
+            }
+            if *e.key() == requester {
+                return Err(UtHolepunchError::NoSelf);
+            }
+            match (live, listen_addr) {
+                (Some(live), Some(listen_addr)) => return Ok((live.tx.clone(), listen_addr)),
+                (Some(_), None) => error = UtHolepunchError::NoSupport,
+                (None, _) if error == UtHolepunchError::NoSuchPeer => {
+                    error = UtHolepunchError::NotConnected
+                }
+                (None, _) => {}
+            }
+        }
+        Err(error)
+    }
+
+    // A relay asked us to connect to "addr" while it connects to us. The peer is dialed from
+    // our listening port when the peer adder gets to it.
+    fn holepunch_connect(&self, addr: SocketAddr) -> anyhow::Result<()> {
+        let addr = canonical_peer_addr(addr);
+        if self
+            .meta
+            .trusted_peers
+            .as_ref()
+            .is_some_and(|t| !t.contains(addr))
+        {
+            debug!(%addr, "ignoring holepunch to an untrusted peer");
+            return Ok(());
+        }
+        let is_new = self
+            .peers
+            .add_if_not_seen(addr, PeerSource::Holepunch)
+            .is_some();
+        let queue = self
+            .peers
+            .with_peer_mut(addr, "holepunch_connect", |peer| {
+                let queue = match peer.state.get() {
+                    PeerState::Queued => is_new,
+                    // It's queued again after its backoff.
+                    PeerState::Dead => false,
+                    PeerState::NotNeeded if !peer.banned => {
+                        peer.state.set(PeerState::Queued, &self.peers.stats);
+                        peer.stats.backoff.reset();
+                        true
+                    }
+                    // Connected already, or banned.
+                    _ => return false,
+                };
+                peer.holepunch = true;
+                queue
+            })
+            .unwrap_or(false);
+        if !queue {
+            return Ok(());
+        }
+        debug!(%addr, "connecting to a peer for a holepunch");
+        self.peer_queue_tx.send(addr)?;
+        Ok(())
+    }
+}
+
+impl PeerHandler {
+    // Where the peer listens, if it supports holepunching and we take part in it.
+    pub(super) fn holepunch_addr(&self, eh: &ExtendedHandshake<ByteBuf>) -> Option<SocketAddr> {
+        self.state.meta.holepunch_port?;
+        eh.get_msgid(b"ut_holepunch")?;
+        let port =
+            eh.p.and_then(|p| u16::try_from(p).ok())
+                .filter(|p| *p != 0)
+                .unwrap_or(self.addr.port());
+        Some(SocketAddr::new(self.addr.ip(), port))
+    }
+
+    pub(super) fn on_holepunch(&self, msg: UtHolepunch) -> anyhow::Result<()> {
+        if self.state.meta.holepunch_port.is_none() {
+            debug!("ignoring {msg:?}, we don't holepunch");
+            return Ok(());
+        }
+        match msg {
+            UtHolepunch::Rendezvous(target) => self.relay_rendezvous(target),
+            UtHolepunch::Connect(addr) => self.state.holepunch_connect(addr),
+            UtHolepunch::Error(addr, error) => {
+                debug!(%addr, ?error, "holepunch rendezvous failed");
+                Ok(())
+            }
+        }
+    }
+
+    // Tell the peer and "target" to connect to each other, or the peer why not.
+    fn relay_rendezvous(&self, target: SocketAddr) -> anyhow::Result<()> {
+        let target = canonical_peer_addr(target);
+        let Some(me) = self
+            .state
+            .peers
+            .with_live(self.addr, |l| l.holepunch_addr)
+            .flatten()
+        else {
+            debug!(%target, "ignoring rendezvous from a peer that doesn't support holepunching");
+            return Ok(());
+        };
+        let result = self.state.find_holepunch_peer(target, self.addr).and_then(
+            |(target_tx, target_addr)| {
+                if send_holepunch(&target_tx, UtHolepunch::Connect(me)) {
+                    Ok(target_addr)
+                } else {
+                    Err(UtHolepunchError::NotConnected)
+                }
+            },
+        );
+        let reply = match result {
+            Ok(target_addr) => {
+                debug!(%target_addr, "relaying holepunch rendezvous");
+                UtHolepunch::Connect(target_addr)
+            }
+            Err(error) => {
+                debug!(%target, ?error, "can't relay holepunch rendezvous");
+                UtHolepunch::Error(target, error)
+            }
+        };
+        send_holepunch(&self.tx, reply);
+        Ok(())
+    }
+}
@@ -39,17 +39,24 @@
 // > so don't lock them both at the same time at all, or at the worst lock them in the
 // > same order (peers one first, then the global one).
 
+mod holepunch;
 pub mod peer;
+mod peer_slots;
 pub mod peers;
+pub mod piece_trace;
+pub mod recheck;
+mod request_window;
 pub mod stats;
+pub mod streaming;
+pub mod write_cache;
 
 use std::{
-    collections::HashMap,
+    collections::{HashMap, HashSet},
     fs::File,
-    net::SocketAddr,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
     path::PathBuf,
     sync::{
-        atomic::{AtomicU64, Ordering},
+        atomic::{AtomicBool, AtomicU64, Ordering},
         Arc,
     },
     time::{Duration, Instant},
@@ -62,6 +69,8 @@ use clone_to_owned::CloneToOwned;
 use futures::{stream::FuturesUnordered, StreamExt};
 use itertools::Itertools;
 use librqbit_core::{
+    clock::{sleep_or_clock_jump, wake_up_jitter},
+    constants::CHUNK_SIZE,
     hash_id::Id20,
     lengths::{ChunkInfo, Lengths, ValidPieceIndex},
     spawn_utils::spawn_with_cancel,
@@ -70,27 +79,37 @@ use librqbit_core::{
 };
 use parking_lot::{Mutex, RwLock, RwLockReadGuard, RwLockWriteGuard};
 use peer_binary_protocol::{
-    extended::handshake::ExtendedHandshake, Handshake, Message, MessageOwned, Piece, Request,
-};
-use sha1w::Sha1;
-use tokio::{
-    sync::{
-        mpsc::{unbounded_channel, UnboundedReceiver, UnboundedSender},
-        Notify, OwnedSemaphorePermit, Semaphore,
+    extended::{
+        handshake::{ExtendedHandshake, YourIP},
+        ut_metadata::UtMetadata,
+        ExtendedMessage,
     },
-    time::timeout,
+    Handshake, Message, MessageOwned, Piece, Request, MY_EXTENDED_UT_HOLEPUNCH,
+};
+use sha1w::{ISha1, Sha1};
+use tokio::sync::{
+    mpsc::{unbounded_channel, UnboundedReceiver, UnboundedSender},
+    Notify, Semaphore,
 };
 use tokio_util::sync::CancellationToken;
 use tracing::{debug, error, error_span, info, trace, warn};
+use tracker_comms::PeerDemand;
 
 use crate::{
     chunk_tracker::{ChunkMarkingResult, ChunkTracker},
-    file_ops::FileOps,
-    peer_connection::{
-        PeerConnection, PeerConnectionHandler, PeerConnectionOptions, WriterRequest,
+    disk_space::is_out_of_space,
+    external_ip::ExternalIpSource,
+    file_ops::{disk_usage, move_open_files, FileOps, FileSlice},
+    file_selection::{compute_piece_priorities, compute_selected_pieces, FilePriority},
+    limits::{
+        ConnectionBudget, ConnectionPermit, ConnectionShare, DialPermit, RateLimiter, UploadCredit,
     },
+    output_dir::{OpenMode, OutputDir},
+    part_file::{part_file_path, PartFile},
+    peer_connection::{PeerConnection, PeerConnectionHandler, WriterRequest},
+    resume_data::ResumeStore,
     session::CheckedIncomingConnection,
-    torrent_state::{peer::Peer, utils::atomic_inc},
+    torrent_state::{events::TorrentEvent, peer::Peer, utils::atomic_inc},
     type_aliases::{PeerHandle, BF},
 };
 
@@ -100,14 +119,24 @@ use self::{
             atomic::PeerCountersAtomic as AtomicPeerCounters,
             snapshot::{PeerStatsFilter, PeerStatsSnapshot},
         },
-        InflightRequest, PeerRx, PeerState, PeerTx,
+        InflightRequest, PeerRx, PeerSource, PeerState, PeerTx,
+    },
+    peer_slots::{PeerSlotPermit, PeerSlots},
+    peers::{canonical_peer_addr, PeerStates},
+    piece_trace::{PieceTraceEvent, PieceTraceSnapshot, PieceTraces},
+    recheck::RecheckProgress,
+    request_window::RequestWindow,
+    stats::{
+        atomic::AtomicStats,
+        history::{BandwidthHistory, BandwidthHistorySnapshot},
+        snapshot::{StatsSnapshot, TrackerStatsSnapshot},
     },
-    peers::PeerStates,
-    stats::{atomic::AtomicStats, snapshot::StatsSnapshot},
+    write_cache::{WriteCache, WriteCacheStats},
 };
 
 use super::{
     paused::TorrentStatePaused,
+    stats::{DiskUsage, PartialPiece, PartialPiecesStats, TransferTotals},
     utils::{timeit, TimedExistence},
     ManagedTorrentInfo,
 };
@@ -117,6 +146,81 @@ struct InflightPiece {
     started: Instant,
 }
 
+// After this many failed checks of the same piece, it's only requested from peers that didn't
+// contribute to any of the previous attempts.
+const PIECE_FAILURES_BEFORE_EXCLUDING_PEERS: u32 = 2;
+
+// A peer that contributed to this many failed attempts of the same piece gets disconnected and
+// isn't reconnected to.
+const PIECE_FAILURES_BEFORE_BANNING_PEER: u32 = 2;
+
+// A chunk request the peer didn't answer for this long is cancelled, and its piece can be
+// requested again, from any peer. Otherwise it stays in flight until another peer steals it.
+// Unless TunableOptions::peer_request_timeout is set.
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(60);
+
+// A peer that let requests time out this many times in a row while it had us unchoked gets
+// disconnected.
+const REQUEST_TIMEOUTS_BEFORE_DISCONNECT: u32 = 3;
+
+// Unless PeerLimits::max_connections is set. Can be changed while live, see PeerSlots.
+const MAX_LIVE_PEERS: usize = 128;
+
+// A downloading torrent is saturated when at least this fraction of the peer limit is live and
+// the speed reaches the target, and starved with fewer known peers than this, or when it's
+// slower than the target with free slots and no peers left to dial. See peer_demand().
+const SATURATED_PEERS_FRACTION: f64 = 0.9;
+pub(crate) const DEFAULT_TARGET_DOWNLOAD_SPEED: u64 = 256 * 1024;
+const STARVED_PEERS: u32 = 10;
+// How often the peer demand is acted on, see task_peer_demand_controller().
+const PEER_DEMAND_CHECK_INTERVAL: Duration = Duration::from_secs(10);
+// While starved, a dead peer is retried once this fraction of its backoff has passed.
+const STARVED_BACKOFF_DIVISOR: u32 = 4;
+
+// How often the progress and transfer totals are checkpointed to resume data, if they changed.
+const CHECKPOINT_INTERVAL: Duration = Duration::from_secs(30);
+// Additionally, a checkpoint is written every time another 1/CHECKPOINT_MILESTONES
+// of the pieces is downloaded, and when the torrent finishes.
+const CHECKPOINT_MILESTONES: u32 = 10;
+
+// Some private trackers check if the announced port is connectable right after the announce.
+// To pass that even when at MAX_LIVE_PEERS, a few extra slots are kept for incoming connections
+// from new addresses within this window after announcing.
+const RESERVED_ACCEPT_SLOTS: usize = 4;
+const CONNECTABILITY_CHECK_WINDOW: Duration = Duration::from_secs(60);
+
+fn may_use_reserved_slot(last_announce: Option<Instant>, now: Instant, is_new_addr: bool) -> bool {
+    is_new_addr
+        && last_announce
+            .is_some_and(|t| now.saturating_duration_since(t) <= CONNECTABILITY_CHECK_WINDOW)
+}
+
+#[derive(Default)]
+struct PieceHashFailures {
+    failures: u32,
+    // How many failed attempts each peer contributed chunks to.
+    contributors: HashMap<PeerHandle, u32>,
+    // The hashes of the chunks of the failed attempts, by chunk index, with who sent them.
+    // Once the piece is verified, the peers whose chunks differ from it sent the bad data.
+    received_chunks: HashSet<(u32, PeerHandle, [u8; 20])>,
+}
+
+impl PieceHashFailures {
+    fn is_excluded(&self, peer: PeerHandle) -> bool {
+        self.failures >= PIECE_FAILURES_BEFORE_EXCLUDING_PEERS
+            && self.contributors.contains_key(&peer)
+    }
+
+    // The peers that sent chunks that differ from "good_chunks" of the verified piece.
+    fn peers_with_bad_chunks(&self, good_chunks: &[[u8; 20]]) -> HashSet<PeerHandle> {
+        self.received_chunks
+            .iter()
+            .filter(|(chunk, _, hash)| good_chunks.get(*chunk as usize) != Some(hash))
+            .map(|(_, peer, _)| *peer)
+            .collect()
+    }
+}
+
 fn dummy_file() -> anyhow::Result<std::fs::File> {
     #[cfg(target_os = "windows")]
     const DEVNULL: &str = "NUL";
@@ -129,6 +233,18 @@ fn dummy_file() -> anyhow::Result<std::fs::File> {
         .with_context(|| format!("error opening {}", DEVNULL))
 }
 
+fn serialize_metadata(meta: &ManagedTorrentInfo) -> Option<ByteString> {
+    let mut buf = Vec::new();
+    bencode::bencode_serialize_to_writer(&meta.info, &mut buf).ok()?;
+    let mut hash = Sha1::new();
+    hash.update(&buf);
+    if hash.finish() != meta.info_hash.0 {
+        debug!("re-serialized info doesn't match the info hash, won't serve metadata to peers");
+        return None;
+    }
+    Some(ByteString(buf))
+}
+
 fn make_piece_bitfield(lengths: &Lengths) -> BF {
     BF::from_vec(vec![0; lengths.piece_bitfield_bytes()])
 }
@@ -142,6 +258,17 @@ pub(crate) struct TorrentStateLocked {
     // inflight_pieces stores this information.
     inflight_pieces: HashMap<ValidPieceIndex, InflightPiece>,
 
+    // Pieces that were completely received and are being hashed. They aren't inflight anymore,
+    // so pausing has to mark them broken too.
+    verifying_pieces: HashSet<ValidPieceIndex>,
+
+    // Who sent each chunk (by index) of the current attempt to download a piece. Stealing keeps
+    // the chunks already received, so there can be more than one peer.
+    piece_contributors: HashMap<ValidPieceIndex, HashMap<u32, PeerHandle>>,
+
+    // Pieces that failed the hash check, and who sent them.
+    piece_hash_failures: HashMap<ValidPieceIndex, PieceHashFailures>,
+
     // If this is None, then it was already used
     fatal_errors_tx: Option<tokio::sync::oneshot::Sender<anyhow::Error>>,
 }
@@ -166,30 +293,74 @@ pub struct TorrentStateOptions {
     pub peer_read_write_timeout: Option<Duration>,
 }
 
+// Held for as long as a peer is connecting or live.
+struct PeerSlot {
+    _permit: PeerSlotPermit,
+    _budget: ConnectionPermit,
+}
+
 pub struct TorrentStateLive {
     peers: PeerStates,
     meta: Arc<ManagedTorrentInfo>,
     locked: RwLock<TorrentStateLocked>,
 
     files: Vec<Arc<Mutex<File>>>,
-    filenames: Vec<PathBuf>,
+    // Changed when the files are moved.
+    filenames: RwLock<Vec<PathBuf>>,
+    part_file: Arc<PartFile>,
+
+    // The bencoded "info" dictionary, served to peers that only have the magnet link.
+    // None if it can't be reproduced byte-for-byte from the parsed torrent.
+    metadata: Option<ByteString>,
 
-    initially_needed_bytes: u64,
-    total_selected_bytes: u64,
+    // These change when the file selection is updated.
+    initially_needed_bytes: AtomicU64,
+    total_selected_bytes: AtomicU64,
 
     stats: AtomicStats,
     lengths: Lengths,
 
     // Limits how many active (occupying network resources) peers there are at a moment in time.
-    peer_semaphore: Arc<Semaphore>,
+    // Resized when PeerLimits::max_connections changes.
+    peer_slots: Arc<PeerSlots>,
+    // This torrent's part of the session's connection budget.
+    connection_share: Arc<ConnectionShare>,
+    // Used by incoming connections when "peer_slots" are exhausted, see RESERVED_ACCEPT_SLOTS.
+    reserved_peer_semaphore: Arc<Semaphore>,
+    last_announce: Mutex<Option<Instant>>,
 
     // The queue for peer manager to connect to them.
     peer_queue_tx: UnboundedSender<SocketAddr>,
 
     finished_notify: Notify,
+    // The download finished while live, rather than being complete from the start. Trackers
+    // are told with the "completed" event.
+    finished_while_live: AtomicBool,
+
+    // Notified every time a piece is downloaded and verified.
+    piece_downloaded_notify: Notify,
+
+    // Notified while starved of peers, to retry dead peers without waiting for the backoff.
+    peers_wanted_notify: Notify,
+
+    // Notified when the file selection changes, so that peers may resume requesting.
+    selection_changed_notify: Notify,
+    download_enabled_notify: Notify,
+    // Notified when pieces of timed out requests are given up, or pieces fail the hash check, so
+    // that idle peers may take them.
+    pieces_released_notify: Notify,
 
     down_speed_estimator: SpeedEstimator,
     up_speed_estimator: SpeedEstimator,
+    bandwidth_history: BandwidthHistory,
+    piece_traces: PieceTraces,
+    write_cache: WriteCache,
+    // Chunks on the io_uring thread, not written yet.
+    #[cfg(all(target_os = "linux", feature = "io-uring"))]
+    uring_writes: crate::uring::PieceWrites,
+    recheck: Mutex<Option<RecheckProgress>>,
+    // Set if uploading is coupled to downloading, see UploadCoupling.
+    upload_credit: Option<UploadCredit>,
     cancellation_token: CancellationToken,
 }
 
@@ -209,28 +380,65 @@ impl TorrentStateLive {
         let total_selected_bytes = paused.chunk_tracker.get_total_selected_bytes();
         let lengths = *paused.chunk_tracker.get_lengths();
 
+        let metadata = serialize_metadata(&paused.info);
+        let peer_slots = paused
+            .info
+            .peer_limits()
+            .max_connections
+            .unwrap_or(MAX_LIVE_PEERS);
+
         let state = Arc::new(TorrentStateLive {
+            metadata,
             meta: paused.info.clone(),
             peers: Default::default(),
             locked: RwLock::new(TorrentStateLocked {
                 chunks: Some(paused.chunk_tracker),
                 inflight_pieces: Default::default(),
+                verifying_pieces: Default::default(),
+                piece_contributors: Default::default(),
+                piece_hash_failures: Default::default(),
                 fatal_errors_tx: Some(fatal_errors_tx),
             }),
             files: paused.files,
-            filenames: paused.filenames,
+            filenames: RwLock::new(paused.filenames),
+            part_file: paused.part_file,
             stats: AtomicStats {
                 have_bytes: AtomicU64::new(have_bytes),
                 ..Default::default()
             },
-            initially_needed_bytes: needed_bytes,
+            initially_needed_bytes: AtomicU64::new(needed_bytes),
             lengths,
-            total_selected_bytes,
-            peer_semaphore: Arc::new(Semaphore::new(128)),
+            total_selected_bytes: AtomicU64::new(total_selected_bytes),
+            peer_slots: PeerSlots::new(peer_slots),
+            connection_share: paused
+                .info
+                .connection_budget
+                .clone()
+                .unwrap_or_else(|| ConnectionBudget::new(None))
+                .share(),
+            reserved_peer_semaphore: Arc::new(Semaphore::new(RESERVED_ACCEPT_SLOTS)),
+            last_announce: Mutex::new(None),
             peer_queue_tx,
             finished_notify: Notify::new(),
+            finished_while_live: AtomicBool::new(false),
+            piece_downloaded_notify: Notify::new(),
+            peers_wanted_notify: Notify::new(),
+            selection_changed_notify: Notify::new(),
+            download_enabled_notify: Notify::new(),
+            pieces_released_notify: Notify::new(),
             down_speed_estimator,
             up_speed_estimator,
+            bandwidth_history: Default::default(),
+            piece_traces: Default::default(),
+            write_cache: WriteCache::new(paused.info.write_cache_budget.clone(), lengths),
+            #[cfg(all(target_os = "linux", feature = "io-uring"))]
+            uring_writes: Default::default(),
+            recheck: Mutex::new(None),
+            upload_credit: paused
+                .info
+                .options
+                .upload_coupling
+                .map(|c| UploadCredit::new(c, needed_bytes > 0)),
             cancellation_token,
         });
 
@@ -239,12 +447,20 @@ impl TorrentStateLive {
             {
                 let state = Arc::downgrade(&state);
                 async move {
+                    let mut last_tick = Instant::now();
                     loop {
                         let state = match state.upgrade() {
                             Some(state) => state,
                             None => return Ok(()),
                         };
                         let now = Instant::now();
+                        if state.is_finished() {
+                            state.stats.seeding_ms.fetch_add(
+                                now.duration_since(last_tick).as_millis() as u64,
+                                Ordering::Relaxed,
+                            );
+                        }
+                        last_tick = now;
                         let stats = state.stats_snapshot();
                         let fetched = stats.fetched_bytes;
                         let needed = state.initially_needed();
@@ -258,19 +474,107 @@ impl TorrentStateLive {
                         state
                             .up_speed_estimator
                             .add_snapshot(stats.uploaded_bytes, None, now);
+                        state
+                            .bandwidth_history
+                            .add_sample(fetched, stats.uploaded_bytes, now);
                         tokio::time::sleep(Duration::from_secs(1)).await;
                     }
                 }
             },
         );
 
+        for peer in state.peers.restore_retained(paused.known_peers) {
+            let _ = state.peer_queue_tx.send(peer);
+        }
         state.spawn(
             error_span!(parent: state.meta.span.clone(), "peer_adder"),
             state.clone().task_peer_adder(peer_queue_rx),
         );
+
+        state.spawn(
+            error_span!(parent: state.meta.span.clone(), "peer_demand_controller"),
+            state.clone().task_peer_demand_controller(),
+        );
+
+        if let Some(resume_store) = state.meta.resume_store.clone() {
+            state.spawn(
+                error_span!(parent: state.meta.span.clone(), "checkpoint"),
+                state.clone().task_checkpoint(resume_store),
+            );
+        }
         state
     }
 
+    fn have_pieces_count(&self) -> anyhow::Result<usize> {
+        Ok(self
+            .lock_read("have_pieces_count")
+            .get_chunks()?
+            .get_have_pieces()
+            .count_ones())
+    }
+
+    // Write the verified pieces to resume data, after making sure they are on disk.
+    fn checkpoint(&self, resume_store: &ResumeStore) -> anyhow::Result<()> {
+        let have_pieces = self
+            .lock_read("checkpoint")
+            .get_chunks()?
+            .get_have_pieces()
+            .clone();
+        self.sync_files()?;
+        let trackers = self.meta.tracker_identity.lock().clone();
+        resume_store.save(
+            self.meta.info_hash,
+            &have_pieces,
+            &self.filenames.read(),
+            &trackers,
+            &self.transfer_totals(),
+        )
+    }
+
+    async fn task_checkpoint(
+        self: Arc<Self>,
+        resume_store: Arc<ResumeStore>,
+    ) -> anyhow::Result<()> {
+        let milestone_pieces =
+            (self.lengths.total_pieces() / CHECKPOINT_MILESTONES).max(1) as usize;
+        let mut saved = self.have_pieces_count()?;
+        let mut saved_totals = self.transfer_totals();
+        let mut interval = tokio::time::interval(CHECKPOINT_INTERVAL);
+        interval.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+        // The first tick completes immediately.
+        interval.tick().await;
+
+        loop {
+            let on_piece = tokio::select! {
+                _ = interval.tick() => false,
+                _ = self.piece_downloaded_notify.notified() => true,
+            };
+            let have = self.have_pieces_count()?;
+            let totals = self.transfer_totals();
+            if have == saved && (on_piece || totals == saved_totals) {
+                continue;
+            }
+            if on_piece
+                && have / milestone_pieces == saved / milestone_pieces
+                && !self.is_finished()
+            {
+                continue;
+            }
+            let res = self
+                .meta
+                .spawner
+                .spawn_block_in_place(|| self.checkpoint(&resume_store));
+            match res {
+                Ok(()) => {
+                    trace!(have, "wrote checkpoint");
+                    saved = have;
+                    saved_totals = totals;
+                }
+                Err(e) => warn!("error writing checkpoint: {:#}", e),
+            }
+        }
+    }
+
     pub(crate) fn spawn(
         &self,
         span: tracing::Span,
@@ -287,15 +591,54 @@ impl TorrentStateLive {
         &self.up_speed_estimator
     }
 
+    pub fn bandwidth_history(&self) -> BandwidthHistorySnapshot {
+        self.bandwidth_history.snapshot()
+    }
+
+    /// Announce to all trackers right away, e.g. after the network changed.
+    pub fn reannounce(&self) {
+        self.meta.reannounce_notify.notify_waiters();
+    }
+
+    /// Called after announcing to a tracker.
+    pub(crate) fn on_announced(&self) {
+        *self.last_announce.lock() = Some(Instant::now());
+    }
+
     pub(crate) fn add_incoming_peer(
         self: &Arc<Self>,
         checked_peer: CheckedIncomingConnection,
     ) -> anyhow::Result<()> {
         use dashmap::mapref::entry::Entry;
+        if self.peers.is_banned(checked_peer.addr) {
+            debug!(addr = %checked_peer.addr, "peer is banned, dropping incoming connection");
+            return Ok(());
+        }
+        if self.at_peer_limit() {
+            debug!(addr = %checked_peer.addr, "peer limit of the torrent reached, dropping incoming peer");
+            return Ok(());
+        }
+        if let Some(other) = self
+            .peers
+            .find_live_duplicate(checked_peer.addr, Id20::new(checked_peer.handshake.peer_id))
+        {
+            debug!(addr = %checked_peer.addr, %other, "already connected to this peer, dropping incoming connection");
+            return Ok(());
+        }
         let (tx, rx) = unbounded_channel();
-        let permit = match self.peer_semaphore.clone().try_acquire_owned() {
-            Ok(permit) => permit,
-            Err(_) => {
+        let permit = self.peer_slots.try_acquire().or_else(|| {
+            let is_new_addr = !self.peers.states.contains_key(&checked_peer.addr);
+            if may_use_reserved_slot(*self.last_announce.lock(), Instant::now(), is_new_addr) {
+                debug!(addr = %checked_peer.addr, "using reserved slot for incoming peer");
+                let permit = self.reserved_peer_semaphore.clone().try_acquire_owned();
+                permit.ok().map(PeerSlotPermit::fixed)
+            } else {
+                None
+            }
+        });
+        let permit = match permit {
+            Some(permit) => permit,
+            None => {
                 warn!("limit of live peers reached, dropping incoming peer");
                 self.peers.with_peer(checked_peer.addr, |p| {
                     atomic_inc(&p.stats.counters.incoming_connections);
@@ -303,7 +646,18 @@ impl TorrentStateLive {
                 return Ok(());
             }
         };
+        let slot = match self.connection_share.try_acquire_incoming() {
+            Some(budget) => PeerSlot {
+                _permit: permit,
+                _budget: budget,
+            },
+            None => {
+                debug!(addr = %checked_peer.addr, "connection budget of the session used up, dropping incoming peer");
+                return Ok(());
+            }
+        };
 
+        let request_window = Arc::new(RequestWindow::new(self.lengths.default_chunk_length()));
         let counters = match self.peers.states.entry(checked_peer.addr) {
             Entry::Occupied(mut occ) => {
                 let peer = occ.get_mut();
@@ -311,6 +665,7 @@ impl TorrentStateLive {
                     .incoming_connection(
                         Id20::new(checked_peer.handshake.peer_id),
                         tx.clone(),
+                        request_window.clone(),
                         &self.peers.stats,
                     )
                     .context("peer already existed")?;
@@ -321,6 +676,7 @@ impl TorrentStateLive {
                 let peer = Peer::new_live_for_incoming_connection(
                     Id20::new(checked_peer.handshake.peer_id),
                     tx.clone(),
+                    request_window.clone(),
                     &self.peers.stats,
                 );
                 let counters = peer.stats.counters.clone();
@@ -329,6 +685,10 @@ impl TorrentStateLive {
             }
         };
         atomic_inc(&counters.incoming_connections);
+        counters.on_activity();
+        self.meta.events.emit(TorrentEvent::PeerConnected {
+            addr: checked_peer.addr,
+        });
 
         self.spawn(
             error_span!(
@@ -336,8 +696,14 @@ impl TorrentStateLive {
                 "manage_incoming_peer",
                 addr = %checked_peer.addr
             ),
-            self.clone()
-                .task_manage_incoming_peer(checked_peer, counters, tx, rx, permit),
+            self.clone().task_manage_incoming_peer(
+                checked_peer,
+                counters,
+                request_window,
+                tx,
+                rx,
+                slot,
+            ),
         );
         Ok(())
     }
@@ -346,26 +712,27 @@ impl TorrentStateLive {
         self: Arc<Self>,
         checked_peer: CheckedIncomingConnection,
         counters: Arc<AtomicPeerCounters>,
+        request_window: Arc<RequestWindow>,
         tx: PeerTx,
         rx: PeerRx,
-        permit: OwnedSemaphorePermit,
+        slot: PeerSlot,
     ) -> anyhow::Result<()> {
         // TODO: bump counters for incoming
         let handler = PeerHandler {
             addr: checked_peer.addr,
             on_bitfield_notify: Default::default(),
             unchoke_notify: Default::default(),
-            locked: RwLock::new(PeerHandlerLocked { i_am_choked: true }),
-            requests_sem: Semaphore::new(0),
+            locked: RwLock::new(PeerHandlerLocked {
+                i_am_choked: true,
+                requests_timed_out_in_a_row: 0,
+            }),
+            request_window,
             state: self.clone(),
             tx,
             counters,
+            dial_permit: Default::default(),
         };
-        let options = PeerConnectionOptions {
-            connect_timeout: self.meta.options.peer_connect_timeout,
-            read_write_timeout: self.meta.options.peer_read_write_timeout,
-            ..Default::default()
-        };
+        let options = self.meta.peer_connection_options();
         let peer_connection = PeerConnection::new(
             checked_peer.addr,
             self.meta.info_hash,
@@ -396,14 +763,15 @@ impl TorrentStateLive {
                 handler.on_peer_died(Some(e))?;
             }
         };
-        drop(permit);
+        drop(slot);
         Ok(())
     }
 
     async fn task_manage_outgoing_peer(
         self: Arc<Self>,
         addr: SocketAddr,
-        permit: OwnedSemaphorePermit,
+        slot: PeerSlot,
+        dial_permit: Option<DialPermit>,
     ) -> anyhow::Result<()> {
         let state = self;
         let (rx, tx) = state.peers.mark_peer_connecting(addr)?;
@@ -416,17 +784,23 @@ impl TorrentStateLive {
             addr,
             on_bitfield_notify: Default::default(),
             unchoke_notify: Default::default(),
-            locked: RwLock::new(PeerHandlerLocked { i_am_choked: true }),
-            requests_sem: Semaphore::new(0),
+            locked: RwLock::new(PeerHandlerLocked {
+                i_am_choked: true,
+                requests_timed_out_in_a_row: 0,
+            }),
+            request_window: Arc::new(RequestWindow::new(state.lengths.default_chunk_length())),
             state: state.clone(),
             tx,
             counters,
+            dial_permit: Mutex::new(dial_permit),
         };
-        let options = PeerConnectionOptions {
-            connect_timeout: state.meta.options.peer_connect_timeout,
-            read_write_timeout: state.meta.options.peer_read_write_timeout,
-            ..Default::default()
-        };
+        let options = state.meta.peer_connection_options();
+        let (other_addr, holepunch) = state
+            .peers
+            .with_peer_mut(addr, "take_holepunch", |p| {
+                (p.other_addr, std::mem::take(&mut p.holepunch))
+            })
+            .context("bug: peer not found")?;
         let peer_connection = PeerConnection::new(
             addr,
             state.meta.info_hash,
@@ -434,7 +808,10 @@ impl TorrentStateLive {
             &handler,
             Some(options),
             state.meta.spawner,
-        );
+        )
+        .with_dialer(state.meta.dialer.clone())
+        .with_other_addr(other_addr)
+        .with_local_port(state.meta.holepunch_port.filter(|_| holepunch));
         let requester = handler.task_peer_chunk_requester();
 
         handler
@@ -456,7 +833,7 @@ impl TorrentStateLive {
                 handler.on_peer_died(Some(e))?;
             }
         }
-        drop(permit);
+        drop(slot);
         Ok::<_, anyhow::Error>(())
     }
 
@@ -467,20 +844,261 @@ impl TorrentStateLive {
         let state = self;
         loop {
             let addr = peer_queue_rx.recv().await.context("torrent closed")?;
+            if state.peers.is_banned(addr) {
+                debug!("ignoring peer {} as it is banned", addr);
+                state.peers.mark_peer_not_needed(addr);
+                continue;
+            }
             if state.is_finished() {
                 debug!("ignoring peer {} as we are finished", addr);
                 state.peers.mark_peer_not_needed(addr);
                 continue;
             }
 
-            let permit = state.peer_semaphore.clone().acquire_owned().await?;
+            // Dialing more peers doesn't help while saturated, they stay queued until it does.
+            while state.at_peer_limit()
+                || state.at_pending_dials_limit()
+                || state.peer_demand() == PeerDemand::Saturated
+            {
+                tokio::time::sleep(Duration::from_secs(1)).await;
+            }
+            let permit = state.peer_slots.acquire().await?;
+            let budget = loop {
+                if let Some(budget) = state.connection_share.try_acquire() {
+                    break budget;
+                }
+                tokio::time::sleep(Duration::from_secs(1)).await;
+            };
+            let slot = PeerSlot {
+                _permit: permit,
+                _budget: budget,
+            };
+            // Waits here, rather than in each peer's task, so that peers stay queued.
+            let dial_permit = match &state.meta.dial_limiter {
+                Some(limiter) => Some(limiter.acquire().await?),
+                None => None,
+            };
             state.spawn(
                 error_span!(parent: state.meta.span.clone(), "manage_peer", peer = addr.to_string()),
-                state.clone().task_manage_outgoing_peer(addr, permit),
+                state.clone().task_manage_outgoing_peer(addr, slot, dial_permit),
             );
         }
     }
 
+    /// Whether more peers would help. Trackers are announced to and DHT is queried more or less
+    /// often depending on it, and the peer adder stops dialing when saturated.
+    pub(crate) fn peer_demand(&self) -> PeerDemand {
+        let max_peers = self.max_connections();
+        let stats = &self.peers.stats;
+        let live = stats.live.load(Ordering::Relaxed);
+        let finished = self.is_finished();
+        let fast_enough = self.down_speed_estimator.bps() >= self.meta.target_download_speed;
+        let at_limit = live as f64 >= max_peers as f64 * SATURATED_PEERS_FRACTION;
+        if at_limit && (finished || fast_enough) {
+            return PeerDemand::Saturated;
+        }
+        if finished || !self.is_download_enabled() {
+            return PeerDemand::Normal;
+        }
+        let pending =
+            stats.connecting.load(Ordering::Relaxed) + stats.queued.load(Ordering::Relaxed);
+        if live + pending < STARVED_PEERS || (!fast_enough && !at_limit && pending == 0) {
+            return PeerDemand::Starved;
+        }
+        PeerDemand::Normal
+    }
+
+    // Acts on the peer demand where nothing else checks it: dead peers are retried sooner while
+    // starved, see STARVED_BACKOFF_DIVISOR.
+    async fn task_peer_demand_controller(self: Arc<Self>) -> anyhow::Result<()> {
+        let mut prev = PeerDemand::Normal;
+        loop {
+            tokio::time::sleep(PEER_DEMAND_CHECK_INTERVAL).await;
+            let demand = self.peer_demand();
+            if demand != prev {
+                debug!(?demand, "peer demand changed");
+                prev = demand;
+            }
+            if demand == PeerDemand::Starved {
+                self.peers_wanted_notify.notify_waiters();
+            }
+            self.disconnect_stalled_peers();
+            self.disconnect_excess_peers();
+        }
+    }
+
+    // Stalled peers only keep the connection open with keep-alives. While all slots are taken
+    // and other peers wait in the queue, they are disconnected (longest idle first) to make
+    // room. They aren't banned, and their requests go back to the pool when they die.
+    fn disconnect_stalled_peers(&self) {
+        let queued = self.peers.stats.queued.load(Ordering::Relaxed) as usize;
+        let no_slots = self.at_peer_limit() || self.peer_slots.available() == 0;
+        if queued == 0 || !no_slots {
+            return;
+        }
+        let stalled = self
+            .peers
+            .states
+            .iter()
+            .filter(|p| p.value().is_stalled())
+            .filter_map(|p| {
+                let tx = p.value().state.get_live()?.tx.clone();
+                Some((*p.key(), p.value().live_idle_time(), tx))
+            })
+            .collect_vec();
+        for (addr, idle, tx) in stalled
+            .into_iter()
+            .sorted_by_key(|(_, idle, _)| std::cmp::Reverse(*idle))
+            .take(queued)
+        {
+            debug!(?addr, ?idle, "disconnecting stalled peer to free its slot");
+            let _ = tx.send(WriterRequest::Disconnect);
+        }
+    }
+
+    // The lower of the cap from the label policy, if any, and the size of "peer_slots".
+    fn max_connections(&self) -> usize {
+        let slots = self.peer_slots.size();
+        match self.meta.limits.as_ref().and_then(|l| l.max_peers()) {
+            Some(label) => label.min(slots),
+            None => slots,
+        }
+    }
+
+    // The per-torrent cap from the label policy, on top of "peer_slots".
+    fn at_peer_limit(&self) -> bool {
+        let max_peers = match self.meta.limits.as_ref().and_then(|l| l.max_peers()) {
+            Some(m) => m,
+            None => return false,
+        };
+        let stats = &self.peers.stats;
+        let connected =
+            stats.connecting.load(Ordering::Relaxed) + stats.live.load(Ordering::Relaxed);
+        connected as usize >= max_peers
+    }
+
+    fn at_pending_dials_limit(&self) -> bool {
+        match self.meta.peer_limits().max_pending_dials {
+            Some(max) => self.peers.stats.connecting.load(Ordering::Relaxed) as usize >= max,
+            None => false,
+        }
+    }
+
+    // Growing takes effect right away. When shrinking, the peers above the new size are
+    // drained here and by the peer demand controller.
+    pub(crate) fn on_peer_limits_changed(&self) {
+        let size = self
+            .meta
+            .peer_limits()
+            .max_connections
+            .unwrap_or(MAX_LIVE_PEERS);
+        if size != self.peer_slots.size() {
+            debug!(size, "resizing peer slots");
+            self.peer_slots.resize(size);
+        }
+        self.disconnect_excess_peers();
+        self.peers_wanted_notify.notify_waiters();
+    }
+
+    // Live peers above the limits, e.g. after they were lowered, or above the torrent's share of
+    // the session's connection budget while another torrent is starved, are disconnected,
+    // longest idle first. They're queued again after a backoff, so they can be dialed again if
+    // the limits go up. Seeds above max_seeds are marked as not needed instead, so they aren't
+    // dialed again only to be dropped on the next tick.
+    fn disconnect_excess_peers(&self) {
+        let limits = self.meta.peer_limits();
+        // Incoming peers in reserved slots are on top of the limits.
+        let reserved = RESERVED_ACCEPT_SLOTS - self.reserved_peer_semaphore.available_permits();
+        let max_connections = self.max_connections() + reserved;
+        let over_budget = self.connection_share.excess();
+        let stats = &self.peers.stats;
+        let connected =
+            stats.connecting.load(Ordering::Relaxed) + stats.live.load(Ordering::Relaxed);
+        if connected as usize <= max_connections && limits.max_seeds.is_none() && over_budget == 0 {
+            return;
+        }
+        let total_pieces = self.lengths.total_pieces() as usize;
+        let live = self
+            .peers
+            .states
+            .iter()
+            .filter_map(|p| {
+                let live = p.value().state.get_live()?;
+                Some((
+                    *p.key(),
+                    p.value().live_idle_time(),
+                    live.has_full_torrent(total_pieces),
+                    live.tx.clone(),
+                ))
+            })
+            .sorted_by_key(|(_, idle, _, _)| std::cmp::Reverse(*idle))
+            .collect_vec();
+
+        let connecting = self.peers.stats.connecting.load(Ordering::Relaxed) as usize;
+        let mut excess = (live.len() + connecting)
+            .saturating_sub(max_connections)
+            .max(over_budget);
+        let seeds = live.iter().filter(|(_, _, is_seed, _)| *is_seed).count();
+        let mut excess_seeds = limits
+            .max_seeds
+            .map(|max| seeds.saturating_sub(max))
+            .unwrap_or_default();
+        if excess == 0 && excess_seeds == 0 {
+            return;
+        }
+        debug!(excess, excess_seeds, "disconnecting peers above the limits");
+        for (handle, _, is_seed, tx) in live {
+            // A seed above max_seeds counts against both limits.
+            let drop_seed = is_seed && excess_seeds > 0;
+            if !drop_seed && excess == 0 {
+                continue;
+            }
+            if drop_seed {
+                excess_seeds -= 1;
+            } else {
+                self.peers
+                    .with_peer_mut(handle, "disconnect_excess_peers", |p| {
+                        p.requeue_on_disconnect = true
+                    });
+            }
+            excess = excess.saturating_sub(1);
+            let _ = tx.send(WriterRequest::Disconnect);
+        }
+    }
+
+    /// Start recording the state changes of the piece, to debug pieces that never complete.
+    /// The log is bounded, see [`TorrentStateLive::piece_trace`].
+    pub fn trace_piece(&self, piece: u32) -> anyhow::Result<()> {
+        let piece = self
+            .lengths
+            .validate_piece_index(piece)
+            .context("invalid piece index")?;
+        let g = self.lock_read("trace_piece");
+        let chunks = g.get_chunks()?;
+        let state = if chunks.is_piece_have(piece) {
+            "have".to_owned()
+        } else if let Some(inflight) = g.inflight_pieces.get(&piece) {
+            format!("in flight from {}", inflight.peer)
+        } else if chunks.is_piece_needed(piece) {
+            "needed".to_owned()
+        } else {
+            "not selected".to_owned()
+        };
+        self.piece_traces.start(piece, state)?;
+        Ok(())
+    }
+
+    pub fn piece_trace(&self, piece: u32) -> Option<PieceTraceSnapshot> {
+        self.piece_traces
+            .snapshot(self.lengths.validate_piece_index(piece)?)
+    }
+
+    /// Stop tracing the piece, returning the recorded events.
+    pub fn stop_tracing_piece(&self, piece: u32) -> Option<PieceTraceSnapshot> {
+        self.piece_traces
+            .stop(self.lengths.validate_piece_index(piece)?)
+    }
+
     pub fn meta(&self) -> &ManagedTorrentInfo {
         &self.meta
     }
@@ -495,10 +1113,10 @@ impl TorrentStateLive {
         self.meta.peer_id
     }
     pub(crate) fn file_ops(&self) -> FileOps<'_, Sha1> {
-        FileOps::new(&self.meta.info, &self.files, &self.lengths)
+        FileOps::new(&self.meta.info, &self.files, &self.part_file, &self.lengths)
     }
     pub fn initially_needed(&self) -> u64 {
-        self.initially_needed_bytes
+        self.initially_needed_bytes.load(Ordering::Acquire)
     }
 
     pub(crate) fn lock_read(
@@ -514,15 +1132,64 @@ impl TorrentStateLive {
         TimedExistence::new(timeit(reason, || self.locked.write()), reason)
     }
 
-    fn set_peer_live<B>(&self, handle: PeerHandle, h: Handshake<B>) {
-        self.peers.with_peer_mut(handle, "set_peer_live", |p| {
-            p.state
-                .connecting_to_live(Id20::new(h.peer_id), &self.peers.stats);
+    // Cancel the requests for the piece that are in flight to the peer, e.g. because another
+    // peer took over the piece, or it isn't needed anymore. Their window is given back right
+    // away.
+    fn cancel_piece_requests(&self, handle: PeerHandle, piece: ValidPieceIndex) {
+        self.peers
+            .with_live_mut(handle, "cancel_piece_requests", |live| {
+                let mut cancelled = 0;
+                for chunk in self.lengths.iter_chunk_infos(piece) {
+                    let request = InflightRequest::from(&chunk);
+                    if live.inflight_requests.remove(&request).is_none() {
+                        continue;
+                    }
+                    live.cancelled_requests.insert(request);
+                    cancelled += 1;
+                    let _ = live
+                        .tx
+                        .send(WriterRequest::Message(MessageOwned::Cancel(Request {
+                            index: piece.get(),
+                            begin: chunk.offset,
+                            length: chunk.size,
+                        })));
+                }
+                if cancelled > 0 {
+                    live.request_window.on_requests_cancelled(cancelled);
+                    self.piece_traces
+                        .record(piece, || PieceTraceEvent::RequestsCancelled {
+                            peer: handle,
+                            chunks: cancelled,
+                        });
+                }
+            });
+    }
+
+    fn set_peer_live<B>(
+        &self,
+        handle: PeerHandle,
+        h: Handshake<B>,
+        request_window: Arc<RequestWindow>,
+    ) {
+        let is_live = self.peers.with_peer_mut(handle, "set_peer_live", |p| {
+            let is_live = p
+                .state
+                .connecting_to_live(Id20::new(h.peer_id), request_window, &self.peers.stats)
+                .is_some();
+            if is_live {
+                p.stats.counters.on_activity();
+            }
+            is_live
         });
+        if is_live == Some(true) {
+            self.meta
+                .events
+                .emit(TorrentEvent::PeerConnected { addr: handle });
+        }
     }
 
     pub fn get_total_selected_bytes(&self) -> u64 {
-        self.total_selected_bytes
+        self.total_selected_bytes.load(Ordering::Relaxed)
     }
 
     pub fn get_uploaded_bytes(&self) -> u64 {
@@ -534,6 +1201,17 @@ impl TorrentStateLive {
             .load(Ordering::Acquire)
     }
 
+    /// The lifetime totals of the torrent, including this run.
+    pub fn transfer_totals(&self) -> TransferTotals {
+        let previous = *self.meta.transfer_totals.lock();
+        TransferTotals {
+            uploaded_bytes: previous.uploaded_bytes + self.get_uploaded_bytes(),
+            downloaded_bytes: previous.downloaded_bytes + self.get_downloaded_bytes(),
+            seeding_secs: previous.seeding_secs
+                + self.stats.seeding_ms.load(Ordering::Relaxed) / 1000,
+        }
+    }
+
     pub fn get_approx_have_bytes(&self) -> u64 {
         self.stats.have_bytes.load(Ordering::Relaxed)
     }
@@ -542,8 +1220,13 @@ impl TorrentStateLive {
         self.get_left_to_download_bytes() == 0
     }
 
+    /// Whether the download finished since the torrent went live.
+    pub(crate) fn finished_while_live(&self) -> bool {
+        self.finished_while_live.load(Ordering::Relaxed)
+    }
+
     pub fn get_left_to_download_bytes(&self) -> u64 {
-        self.initially_needed_bytes - self.get_downloaded_bytes()
+        self.initially_needed() - self.get_downloaded_bytes()
     }
 
     fn maybe_transmit_haves(&self, index: ValidPieceIndex) {
@@ -603,8 +1286,13 @@ impl TorrentStateLive {
         );
     }
 
-    pub(crate) fn add_peer_if_not_seen(&self, addr: SocketAddr) -> anyhow::Result<bool> {
-        match self.peers.add_if_not_seen(addr) {
+    pub(crate) fn add_peer_if_not_seen(
+        &self,
+        addr: SocketAddr,
+        source: PeerSource,
+    ) -> anyhow::Result<bool> {
+        let addr = canonical_peer_addr(addr);
+        match self.peers.add_if_not_seen(addr, source) {
             Some(handle) => handle,
             None => return Ok(false),
         };
@@ -613,9 +1301,86 @@ impl TorrentStateLive {
         Ok(true)
     }
 
+    /// Connect to a peer, e.g. one known out of band. Peers we stopped talking to are connected
+    /// to again. Returns false if the peer is already queued, connecting or connected.
+    pub fn add_peer(&self, addr: SocketAddr) -> anyhow::Result<bool> {
+        let addr = canonical_peer_addr(addr);
+        if self
+            .meta
+            .trusted_peers
+            .as_ref()
+            .is_some_and(|t| !t.contains(addr))
+        {
+            bail!("not a trusted peer");
+        }
+        if self.peers.is_banned(addr) {
+            bail!("peer is banned");
+        }
+        if self.add_peer_if_not_seen(addr, PeerSource::Manual)? {
+            return Ok(true);
+        }
+        let requeue = self
+            .peers
+            .with_peer_mut(addr, "add_peer", |peer| {
+                if peer.banned {
+                    bail!("peer is banned");
+                }
+                if !matches!(peer.state.get(), PeerState::NotNeeded) {
+                    return Ok(false);
+                }
+                peer.state.set(PeerState::Queued, &self.peers.stats);
+                peer.stats.backoff.reset();
+                Ok(true)
+            })
+            .context("peer disappeared")??;
+        if requeue {
+            self.peer_queue_tx.send(addr)?;
+        }
+        Ok(requeue)
+    }
+
+    pub(crate) fn partial_pieces_stats(&self) -> PartialPiecesStats {
+        let g = self.lock_read("partial_pieces_stats");
+        let chunks = match g.get_chunks() {
+            Ok(c) => c,
+            Err(_) => return Default::default(),
+        };
+        let mut stats = PartialPiecesStats::default();
+        for (index, downloaded_chunks, bytes) in chunks.iter_partial_pieces() {
+            stats.downloaded_bytes += bytes;
+            stats.pieces.push(PartialPiece {
+                piece: index.get(),
+                downloaded_chunks,
+                total_chunks: self.lengths.chunks_per_piece(index),
+            });
+        }
+        stats
+    }
+
     pub fn stats_snapshot(&self) -> StatsSnapshot {
         use Ordering::*;
         let downloaded_bytes = self.stats.downloaded_and_checked_bytes.load(Relaxed);
+        let total_pieces = self.lengths.total_pieces() as usize;
+        let (mut live_seeds, mut live_leechers) = (0, 0);
+        for pe in self.peers.states.iter() {
+            match pe.value().state.get() {
+                PeerState::Live(l) if l.has_full_torrent(total_pieces) => live_seeds += 1,
+                PeerState::Live(_) => live_leechers += 1,
+                _ => {}
+            }
+        }
+        let trackers = self
+            .meta
+            .tracker_status
+            .lock()
+            .iter()
+            .map(|(url, status)| TrackerStatsSnapshot::new(url, status))
+            .collect::<Vec<_>>();
+        let dht_scrape = self
+            .meta
+            .dht
+            .as_ref()
+            .and_then(|dht| dht.scrape(self.meta.info_hash));
         StatsSnapshot {
             downloaded_and_checked_bytes: downloaded_bytes,
             downloaded_and_checked_pieces: self.stats.downloaded_and_checked_pieces.load(Relaxed),
@@ -623,9 +1388,42 @@ impl TorrentStateLive {
             uploaded_bytes: self.stats.uploaded_bytes.load(Relaxed),
             total_piece_download_ms: self.stats.total_piece_download_ms.load(Relaxed),
             peer_stats: self.peers.stats(),
+            live_seeds,
+            live_leechers,
+            swarm_seeders: trackers
+                .iter()
+                .filter_map(|t| t.seeders)
+                .max()
+                .or(dht_scrape.map(|s| s.seeders)),
+            swarm_leechers: trackers
+                .iter()
+                .filter_map(|t| t.leechers)
+                .max()
+                .or(dht_scrape.map(|s| s.leechers)),
+            trackers,
+            recheck: self.recheck_progress(),
         }
     }
 
+    // Should we stay connected to a peer that has the full torrent when we have it too.
+    fn keep_seed_connection(&self, addr: PeerHandle) -> bool {
+        let total_pieces = self.lengths.total_pieces() as usize;
+        let other_seeds = self
+            .peers
+            .states
+            .iter()
+            .filter(|pe| *pe.key() != addr)
+            .filter(|pe| match pe.value().state.get() {
+                PeerState::Live(l) => l.has_full_torrent(total_pieces),
+                _ => false,
+            })
+            .count();
+        self.meta
+            .options
+            .finished_peer_policy
+            .keep_seed(other_seeds)
+    }
+
     pub fn per_peer_stats_snapshot(&self, filter: PeerStatsFilter) -> PeerStatsSnapshot {
         PeerStatsSnapshot {
             peers: self
@@ -647,8 +1445,24 @@ impl TorrentStateLive {
 
     pub fn pause(&self) -> anyhow::Result<TorrentStatePaused> {
         self.cancellation_token.cancel();
-
+        // Before locking, as failed writes report their errors under the lock.
+        #[cfg(all(target_os = "linux", feature = "io-uring"))]
+        self.uring_writes.wait_idle();
+        // Flushed under the lock, so that no piece is marked "have" after its data was flushed.
         let mut g = self.locked.write();
+        // Pieces that can't be written for lack of space are downloaded again after resuming.
+        let lost_pieces = match self.flush_write_cache() {
+            Ok(()) => Vec::new(),
+            Err(e) if is_out_of_space(&e) => {
+                let lost = self.write_cache.discard_dirty();
+                warn!(
+                    "out of disk space, dropped {} verified pieces that weren't written",
+                    lost.len()
+                );
+                lost
+            }
+            Err(e) => return Err(e.context("error writing cached pieces")),
+        };
 
         let files = self
             .files
@@ -661,51 +1475,614 @@ impl TorrentStateLive {
             })
             .try_collect()?;
 
-        let filenames = self.filenames.clone();
+        let filenames = self.filenames.read().clone();
 
         let mut chunk_tracker = g
             .chunks
             .take()
             .context("bug: pausing already paused torrent")?;
-        for piece_id in g.inflight_pieces.keys().copied() {
+        for piece_id in g
+            .inflight_pieces
+            .keys()
+            .chain(g.verifying_pieces.iter())
+            .copied()
+        {
             chunk_tracker.mark_piece_broken_if_not_have(piece_id);
         }
+        for piece_id in lost_pieces {
+            chunk_tracker.mark_piece_lost(piece_id);
+        }
         let have_bytes = chunk_tracker.calc_have_bytes();
         let needed_bytes = chunk_tracker.calc_needed_bytes();
+        // The next run counts from zero.
+        let totals = self.transfer_totals();
+        *self.meta.transfer_totals.lock() = totals;
+        // The trackers aren't announced to while paused, their next announce times are stale.
+        self.meta.tracker_status.lock().clear();
 
         // g.chunks;
         Ok(TorrentStatePaused {
             info: self.meta.clone(),
             files,
             filenames,
+            part_file: self.part_file.clone(),
             chunk_tracker,
             have_bytes,
             needed_bytes,
+            known_peers: self.peers.take_retained(),
         })
     }
 
-    fn on_fatal_error(&self, e: anyhow::Error) -> anyhow::Result<()> {
-        let mut g = self.lock_write("fatal_error");
-        let tx = g
-            .fatal_errors_tx
-            .take()
-            .context("fatal_errors_tx already taken")?;
-        let res = anyhow::anyhow!("fatal error: {:?}", e);
-        if tx.send(e).is_err() {
-            warn!("there's nowhere to send fatal error, receiver is dead");
+    /// Change the selected files and their priorities without pausing.
+    ///
+    /// Pieces that are no longer needed are dropped from the download queue, and in-flight pieces
+    /// for them are cancelled: chunks that peers still send for them will be ignored.
+    pub(crate) fn update_file_selection(
+        &self,
+        only_files: Option<&[usize]>,
+        file_priorities: &HashMap<usize, FilePriority>,
+    ) -> anyhow::Result<()> {
+        let selected = compute_selected_pieces(&self.meta.info, &self.lengths, only_files)?;
+        let piece_priorities =
+            compute_piece_priorities(&self.meta.info, &self.lengths, only_files, file_priorities)?;
+        let was_finished = self.is_finished();
+
+        let mut deselected = Vec::new();
+        {
+            let mut g = self.lock_write("update_file_selection");
+            g.inflight_pieces.retain(|piece, inflight| {
+                let keep = selected[piece.get() as usize];
+                if !keep {
+                    debug!(piece = piece.get(), peer = %inflight.peer, "cancelling in-flight piece, it's not selected anymore");
+                    self.piece_traces
+                        .record(*piece, || PieceTraceEvent::Deselected);
+                    // Its chunks are downloaded again if it's selected again.
+                    self.write_cache.discard_receiving(*piece);
+                    deselected.push((inflight.peer, *piece));
+                }
+                keep
+            });
+            let inflight = g.inflight_pieces.keys().copied().collect::<Vec<_>>();
+            let chunks = g.get_chunks_mut()?;
+            let remaining = chunks.update_selected_pieces(&selected, &inflight);
+            chunks.set_piece_priorities(piece_priorities);
+            let total_selected_bytes = chunks.get_total_selected_bytes();
+
+            // Keep "initially_needed - downloaded" equal to what's left to download.
+            self.initially_needed_bytes
+                .store(remaining + self.get_downloaded_bytes(), Ordering::Release);
+            self.total_selected_bytes
+                .store(total_selected_bytes, Ordering::Relaxed);
         }
-        Err(res)
+        for (peer, piece) in deselected {
+            self.cancel_piece_requests(peer, piece);
+        }
+
+        let is_finished = self.is_finished();
+        {
+            let out_dir = self.meta.out_dir();
+            self.part_file.unpart_selected_files(
+                &self.meta.out_dir_handle(),
+                &self.files,
+                &mut self.filenames.write(),
+                &self.meta.file_paths_in(&out_dir)?,
+                &part_file_path(&out_dir, self.meta.info_hash),
+                only_files,
+                !was_finished,
+            )?;
+        }
+        if was_finished && !is_finished {
+            // Files were reopened read-only on completion.
+            self.reopen_read_write()?;
+            self.on_upload_only_changed(false);
+            for peer in self.peers.requeue_not_needed() {
+                self.peer_queue_tx.send(peer)?;
+            }
+        }
+        if let Some(credit) = &self.upload_credit {
+            credit.set_active(!is_finished);
+        }
+        if is_finished && !was_finished {
+            info!("torrent finished downloading after the file selection changed");
+            self.on_finished(false);
+        }
+        self.selection_changed_notify.notify_waiters();
+        Ok(())
     }
-}
 
-struct PeerHandlerLocked {
-    pub i_am_choked: bool,
-}
+    // All the selected pieces are there. "downloaded" if the last one was just downloaded, not
+    // if the missing ones got deselected: only that tells trackers we completed.
+    fn on_finished(&self, downloaded: bool) {
+        let was_finished = downloaded && self.finished_while_live.swap(true, Ordering::Relaxed);
+        self.on_upload_only_changed(true);
+        self.finished_notify.notify_waiters();
+        self.meta.events.emit(TorrentEvent::Finished);
+        // Pieces a recheck broke finish the torrent again, that isn't a new completion, and
+        // neither is deselecting what's missing.
+        if !downloaded || was_finished {
+            return;
+        }
+        if let Some(hook) = &self.meta.finished_hook {
+            hook();
+        }
+    }
 
-// All peer state that would never be used by other actors should pe put here.
-// This state tracks a live peer.
-struct PeerHandler {
-    state: Arc<TorrentStateLive>,
+    pub(crate) fn on_download_enabled_changed(&self) {
+        self.download_enabled_notify.notify_waiters();
+    }
+
+    // Choke all peers when uploading gets disabled, and unchoke them when it's enabled again.
+    pub(crate) fn on_upload_enabled_changed(&self, enabled: bool) {
+        for pe in self.peers.states.iter() {
+            if let PeerState::Live(live) = pe.value().state.get() {
+                let msg = if enabled {
+                    MessageOwned::Unchoke
+                } else {
+                    MessageOwned::Choke
+                };
+                // The peer may be gone already, nothing to do then.
+                let _ = live.tx.send(WriterRequest::Message(msg));
+            }
+        }
+    }
+
+    // BEP 21: tell the connected peers whether we only upload now, i.e. have everything that's
+    // selected, so that a partial seed isn't taken for a stuck leecher. Peers that connect later
+    // get it in the extended handshake.
+    fn on_upload_only_changed(&self, upload_only: bool) {
+        for pe in self.peers.states.iter() {
+            if let PeerState::Live(live) = pe.value().state.get() {
+                if !live.supports_extended {
+                    continue;
+                }
+                let handshake = ExtendedHandshake {
+                    upload_only: Some(upload_only.into()),
+                    ..Default::default()
+                };
+                // The peer may be gone already, nothing to do then.
+                let _ = live.tx.send(WriterRequest::Message(Message::Extended(
+                    ExtendedMessage::Handshake(handshake),
+                )));
+            }
+        }
+    }
+
+    fn is_download_enabled(&self) -> bool {
+        self.meta.download_enabled.load(Ordering::Relaxed)
+    }
+
+    fn is_upload_enabled(&self) -> bool {
+        self.meta.upload_enabled.load(Ordering::Relaxed)
+    }
+
+    pub(crate) fn disk_usage(&self) -> DiskUsage {
+        disk_usage(&self.filenames.read())
+    }
+
+    pub(crate) fn live_peer_addrs(&self) -> Vec<SocketAddr> {
+        self.peers
+            .states
+            .iter()
+            .filter(|pe| matches!(pe.value().state.get(), PeerState::Live(_)))
+            .map(|pe| *pe.key())
+            .collect()
+    }
+
+    pub(crate) fn filenames(&self) -> Vec<PathBuf> {
+        self.filenames.read().clone()
+    }
+
+    // Move the files to new places, e.g. another folder. Reads and writes of a file wait while
+    // it's being moved.
+    pub(crate) fn move_files(
+        &self,
+        new_dir: &OutputDir,
+        new_filenames: &[PathBuf],
+    ) -> anyhow::Result<()> {
+        #[cfg(all(target_os = "linux", feature = "io-uring"))]
+        self.uring_writes.wait_idle();
+        self.flush_write_cache()?;
+        // Finished torrents have their files reopened read-only.
+        let writable = !self.is_finished();
+        let mut filenames = self.filenames.write();
+        move_open_files(
+            &self.files,
+            (&self.meta.out_dir_handle(), &mut filenames),
+            (new_dir, new_filenames),
+            writable,
+        )
+    }
+
+    pub(crate) fn write_cache_stats(&self) -> WriteCacheStats {
+        self.write_cache.stats()
+    }
+
+    // Hash a piece that was completely received from "peer", and mark it as downloaded or as
+    // broken. Runs on the hashing threads.
+    fn verify_received_piece(
+        &self,
+        peer: PeerHandle,
+        counters: &AtomicPeerCounters,
+        chunk_info: ChunkInfo,
+        download_time: Duration,
+    ) -> anyhow::Result<()> {
+        if self.cancellation_token.is_cancelled() {
+            // Paused or removed meanwhile. Request it again on resume rather than never.
+            if let Some(data) = self.write_cache.take_received(chunk_info.piece_index) {
+                self.write_cache.discard(data);
+            }
+            let mut g = self.lock_write("mark_piece_broken");
+            g.verifying_pieces.remove(&chunk_info.piece_index);
+            if let Ok(chunks) = g.get_chunks_mut() {
+                chunks.mark_piece_broken_if_not_have(chunk_info.piece_index);
+            }
+            return Ok(());
+        }
+        let index = chunk_info.piece_index.get();
+        let cached_piece = self.write_cache.take_received(chunk_info.piece_index);
+        let verified = match &cached_piece {
+            Some(data) => self
+                .file_ops()
+                .check_piece_data(chunk_info.piece_index, data),
+            None => self
+                .file_ops()
+                .check_piece(peer, chunk_info.piece_index, &chunk_info),
+        }
+        .with_context(|| format!("error checking piece={index}"));
+        let verified = match verified {
+            Ok(verified) => verified,
+            Err(e) => {
+                // Request it again rather than never.
+                let mut g = self.lock_write("mark_piece_broken");
+                g.verifying_pieces.remove(&chunk_info.piece_index);
+                g.get_chunks_mut()?
+                    .mark_piece_broken_if_not_have(chunk_info.piece_index);
+                return Err(e);
+            }
+        };
+
+        // Hashes of the chunks, to compare a piece that failed before with the good one, or to
+        // record a failed attempt.
+        let chunk_hashes = |why: &str| {
+            let hashes =
+                self.file_ops()
+                    .chunk_hashes(peer, chunk_info.piece_index, cached_piece.as_deref());
+            hashes
+                .map_err(|e| warn!(piece = index, "error hashing chunks {why}: {:#}", e))
+                .ok()
+        };
+
+        match verified {
+            true => {
+                let good_chunks = if self
+                    .lock_read("piece_hash_failures")
+                    .piece_hash_failures
+                    .contains_key(&chunk_info.piece_index)
+                {
+                    chunk_hashes("of a piece that failed before")
+                } else {
+                    None
+                };
+                if let Some(data) = cached_piece {
+                    self.write_cache
+                        .insert_verified(chunk_info.piece_index, data);
+                }
+                let piece_len = self.lengths.piece_length(chunk_info.piece_index) as u64;
+                let past_failures = {
+                    let mut g = self.lock_write("mark_piece_downloaded");
+                    g.verifying_pieces.remove(&chunk_info.piece_index);
+                    g.get_chunks_mut()?
+                        .mark_piece_downloaded(chunk_info.piece_index);
+                    g.piece_contributors.remove(&chunk_info.piece_index);
+                    let past_failures = g.piece_hash_failures.remove(&chunk_info.piece_index);
+                    self.piece_traces.record(chunk_info.piece_index, || {
+                        PieceTraceEvent::Verified { peer }
+                    });
+                    // Updated under the lock to be consistent with "have" when the file
+                    // selection changes.
+                    self.stats
+                        .downloaded_and_checked_bytes
+                        // This counter is used to compute "is_finished", so using
+                        // stronger ordering.
+                        .fetch_add(piece_len, Ordering::Release);
+                    past_failures
+                };
+                self.piece_downloaded_notify.notify_waiters();
+                self.meta.events.emit(TorrentEvent::PieceCompleted {
+                    piece: chunk_info.piece_index.get(),
+                });
+
+                // Whoever sent chunks that differ from the good piece poisoned it, others
+                // were just unlucky to share a piece with them.
+                if let (Some(failures), Some(good_chunks)) = (past_failures, good_chunks) {
+                    for bad_peer in failures.peers_with_bad_chunks(&good_chunks) {
+                        self.ban_peer_for_broken_piece(bad_peer, index);
+                    }
+                }
+
+                // Global piece counters.
+                self.stats
+                    .downloaded_and_checked_pieces
+                    // This counter is used to compute "is_finished", so using
+                    // stronger ordering.
+                    .fetch_add(1, Ordering::Release);
+                self.stats
+                    .have_bytes
+                    .fetch_add(piece_len, Ordering::Relaxed);
+                self.stats
+                    .total_piece_download_ms
+                    .fetch_add(download_time.as_millis() as u64, Ordering::Relaxed);
+
+                // Per-peer piece counters.
+                counters.on_piece_downloaded(piece_len, download_time);
+                self.peers.reset_peer_backoff(peer);
+
+                debug!("piece={} successfully downloaded and verified", index);
+
+                if self.is_finished() {
+                    info!("torrent finished downloading");
+                    if let Some(credit) = &self.upload_credit {
+                        credit.set_active(false);
+                    }
+                    // Writes the cached pieces first, so that the files are complete for whoever
+                    // waits for the torrent to finish.
+                    self.reopen_read_only()?;
+                    self.on_finished(true);
+                    self.disconnect_all_peers_that_have_full_torrent();
+                }
+
+                self.maybe_transmit_haves(chunk_info.piece_index);
+
+                if self.write_cache.should_flush() {
+                    if let Err(e) = self.flush_write_cache() {
+                        if is_out_of_space(&e) {
+                            warn!("out of disk space writing cached pieces, pausing");
+                        } else {
+                            error!("FATAL: error writing cached pieces to disk: {:?}", e);
+                        }
+                        return self.on_fatal_error(e);
+                    }
+                }
+            }
+            false => {
+                warn!("checksum for piece={} did not validate", index,);
+                let received_chunks = chunk_hashes("of a broken piece");
+                if let Some(data) = cached_piece {
+                    self.write_cache.discard(data);
+                }
+                let to_ban = {
+                    let mut g = self.lock_write("mark_piece_broken");
+                    g.verifying_pieces.remove(&chunk_info.piece_index);
+                    g.get_chunks_mut()?
+                        .mark_piece_broken_if_not_have(chunk_info.piece_index);
+                    self.piece_traces.record(chunk_info.piece_index, || {
+                        PieceTraceEvent::HashFailed { peer }
+                    });
+                    let contributors = g
+                        .piece_contributors
+                        .remove(&chunk_info.piece_index)
+                        .unwrap_or_default();
+                    let failures = g
+                        .piece_hash_failures
+                        .entry(chunk_info.piece_index)
+                        .or_default();
+                    failures.failures += 1;
+                    if let Some(hashes) = &received_chunks {
+                        for (chunk, peer) in contributors.iter() {
+                            if let Some(hash) = hashes.get(*chunk as usize) {
+                                failures.received_chunks.insert((*chunk, *peer, *hash));
+                            }
+                        }
+                    }
+                    let mut to_ban = Vec::new();
+                    for peer in contributors.into_values().collect::<HashSet<_>>() {
+                        let count = failures.contributors.entry(peer).or_default();
+                        *count += 1;
+                        if *count >= PIECE_FAILURES_BEFORE_BANNING_PEER {
+                            to_ban.push(peer);
+                        }
+                    }
+                    if failures.failures >= PIECE_FAILURES_BEFORE_EXCLUDING_PEERS {
+                        warn!(
+                            "piece={} failed the check {} times, will only request it from peers other than {:?}",
+                            index,
+                            failures.failures,
+                            failures.contributors.keys().collect::<Vec<_>>()
+                        );
+                    }
+                    to_ban
+                };
+                for peer in to_ban {
+                    self.ban_peer_for_broken_piece(peer, index);
+                }
+                self.pieces_released_notify.notify_waiters();
+            }
+        };
+        Ok(())
+    }
+
+    fn reopen_read_only(&self) -> anyhow::Result<()> {
+        self.flush_write_cache()?;
+        // Lock exclusive just in case to ensure in-flight operations finish.??
+        let _guard = self.lock_write("reopen_read_only");
+
+        let out_dir = self.meta.out_dir_handle();
+        let filenames = self.filenames.read();
+        for (file, filename) in self.files.iter().zip(filenames.iter()) {
+            let mut g = file.lock();
+            // this should close the original file
+            // putting in a block just in case to guarantee drop.
+            {
+                *g = dummy_file()?;
+            }
+            *g = out_dir
+                .open(filename, OpenMode::Read)
+                .with_context(|| format!("error re-opening {:?} readonly", filename))?;
+            debug!("reopened {:?} read-only", filename);
+        }
+        info!("reopened all torrent files in read-only mode");
+        Ok(())
+    }
+
+    fn ban_peer_for_broken_piece(&self, peer: PeerHandle, piece: u32) {
+        warn!(
+            ?peer,
+            "banning peer, it sent broken data for piece={}", piece
+        );
+        let _ = self.ban_peer(peer);
+    }
+
+    /// Disconnect from a peer and refuse its connections until the session ends, from any port
+    /// of its IP. The ban is kept across pauses.
+    pub fn ban_peer(&self, addr: SocketAddr) -> anyhow::Result<()> {
+        let addr = canonical_peer_addr(addr);
+        if !self.peers.states.contains_key(&addr) {
+            bail!("peer not found");
+        }
+        self.peers.banned_ips.insert(addr.ip());
+        let mut disconnected = Vec::new();
+        for mut pe in self.peers.states.iter_mut() {
+            if pe.key().ip() != addr.ip() {
+                continue;
+            }
+            pe.value_mut().banned = true;
+            // Queued peers are skipped by the peer adder once they are not needed.
+            let prev = pe.value_mut().state.set_not_needed(&self.peers.stats);
+            if let Some(live) = prev.take_live_no_counters() {
+                let _ = live.tx.send(WriterRequest::Disconnect);
+                disconnected.push((*pe.key(), live.inflight_requests));
+            }
+        }
+        // on_peer_died() ignores peers that are not needed, so their requests are released here.
+        for (handle, requests) in disconnected {
+            self.cancel_chunk_requests(handle, requests.into_keys())?;
+        }
+        Ok(())
+    }
+
+    // Put the chunks requested from a peer that went away back in the queue.
+    fn cancel_chunk_requests(
+        &self,
+        handle: PeerHandle,
+        requests: impl IntoIterator<Item = InflightRequest>,
+    ) -> anyhow::Result<()> {
+        let mut g = self.lock_write("mark_chunk_requests_canceled");
+        for req in requests {
+            debug!(
+                "peer gone, marking chunk request cancelled, index={}, chunk={}",
+                req.piece.get(),
+                req.chunk
+            );
+            if g.get_chunks_mut()?
+                .mark_chunk_request_cancelled(req.piece, req.chunk)
+                == Some(true)
+            {
+                self.piece_traces
+                    .record(req.piece, || PieceTraceEvent::Requeued { peer: handle });
+            }
+        }
+        Ok(())
+    }
+
+    /// Disconnect from a live peer. It isn't dialed again, but may connect to us.
+    pub fn disconnect_peer(&self, addr: SocketAddr) -> anyhow::Result<()> {
+        let tx = self
+            .peers
+            .with_live(canonical_peer_addr(addr), |live| live.tx.clone())
+            .context("peer isn't connected")?;
+        let _ = tx.send(WriterRequest::Disconnect);
+        Ok(())
+    }
+
+    fn disconnect_all_peers_that_have_full_torrent(&self) {
+        let policy = self.meta.options.finished_peer_policy;
+        let mut kept = 0;
+        for mut pe in self.peers.states.iter_mut() {
+            if let PeerState::Live(l) = pe.value().state.get() {
+                if l.has_full_torrent(self.lengths.total_pieces() as usize) {
+                    if policy.keep_seed(kept) {
+                        kept += 1;
+                        continue;
+                    }
+                    let prev = pe.value_mut().state.set_not_needed(&self.peers.stats);
+                    let _ = prev
+                        .take_live_no_counters()
+                        .unwrap()
+                        .tx
+                        .send(WriterRequest::Disconnect);
+                }
+            }
+        }
+    }
+
+    // Write the verified pieces that are still in memory.
+    fn flush_write_cache(&self) -> anyhow::Result<()> {
+        let file_ops = self.file_ops();
+        self.write_cache.flush(|piece, data| {
+            if let Some(limiter) = &self.meta.disk_write_limiter {
+                limiter.acquire_blocking(data.len() as u64);
+            }
+            self.meta
+                .disk_retry_policy
+                .run("write_piece", || file_ops.write_piece(piece, data))
+        })
+    }
+
+    // Verified pieces that are only in memory yet.
+    pub(crate) fn unflushed_pieces(&self) -> Vec<ValidPieceIndex> {
+        self.write_cache.dirty_pieces()
+    }
+
+    // Flush written data to disk.
+    pub(crate) fn sync_files(&self) -> anyhow::Result<()> {
+        #[cfg(all(target_os = "linux", feature = "io-uring"))]
+        self.uring_writes.wait_idle();
+        self.flush_write_cache()?;
+        for (file, filename) in self.files.iter().zip(self.filenames.read().iter()) {
+            file.lock()
+                .sync_data()
+                .with_context(|| format!("error syncing {:?}", filename))?;
+        }
+        Ok(())
+    }
+
+    fn reopen_read_write(&self) -> anyhow::Result<()> {
+        let _guard = self.lock_write("reopen_read_write");
+        let out_dir = self.meta.out_dir_handle();
+        for (file, filename) in self.files.iter().zip(self.filenames.read().iter()) {
+            let mut g = file.lock();
+            *g = out_dir
+                .open(filename, OpenMode::ReadWrite)
+                .with_context(|| format!("error re-opening {:?} read-write", filename))?;
+        }
+        debug!("reopened all torrent files in read-write mode");
+        Ok(())
+    }
+
+    fn on_fatal_error(&self, e: anyhow::Error) -> anyhow::Result<()> {
+        let mut g = self.lock_write("fatal_error");
+        let tx = g
+            .fatal_errors_tx
+            .take()
+            .context("fatal_errors_tx already taken")?;
+        let res = anyhow::anyhow!("fatal error: {:?}", e);
+        if tx.send(e).is_err() {
+            warn!("there's nowhere to send fatal error, receiver is dead");
+        }
+        Err(res)
+    }
+}
+
+struct PeerHandlerLocked {
+    pub i_am_choked: bool,
+    // Reset when a requested chunk arrives, see REQUEST_TIMEOUTS_BEFORE_DISCONNECT.
+    pub requests_timed_out_in_a_row: u32,
+}
+
+// All peer state that would never be used by other actors should pe put here.
+// This state tracks a live peer.
+struct PeerHandler {
+    state: Arc<TorrentStateLive>,
     counters: Arc<AtomicPeerCounters>,
     // Semantically, we don't need an RwLock here, as this is only requested from
     // one future (requester + manage_peer).
@@ -722,15 +2099,20 @@ struct PeerHandler {
     unchoke_notify: Notify,
 
     // This is used to limit the number of chunk requests we send to a peer at a time.
-    requests_sem: Semaphore,
+    // Sized from the measured bandwidth-delay product of the peer.
+    request_window: Arc<RequestWindow>,
 
     addr: SocketAddr,
 
     tx: PeerTx,
+
+    // Released once connected, see DialLimiter.
+    dial_permit: Mutex<Option<DialPermit>>,
 }
 
 impl<'a> PeerConnectionHandler for &'a PeerHandler {
     fn on_connected(&self, connection_time: Duration) {
+        self.dial_permit.lock().take();
         self.counters
             .outgoing_connections
             .fetch_add(1, Ordering::Relaxed);
@@ -739,6 +2121,9 @@ impl<'a> PeerConnectionHandler for &'a PeerHandler {
             .fetch_add(connection_time.as_millis() as u64, Ordering::Relaxed);
     }
     fn on_received_message(&self, message: Message<ByteBuf<'_>>) -> anyhow::Result<()> {
+        if !matches!(message, Message::KeepAlive) {
+            self.counters.on_activity();
+        }
         match message {
             Message::Request(request) => {
                 self.on_download_request(request)
@@ -761,6 +2146,12 @@ impl<'a> PeerConnectionHandler for &'a PeerHandler {
             Message::Cancel(_) => {
                 trace!("received \"cancel\", but we don't process it yet")
             }
+            Message::Extended(ExtendedMessage::UtMetadata(UtMetadata::Request(piece))) => {
+                self.on_metadata_request(piece)?;
+            }
+            Message::Extended(ExtendedMessage::UtHolepunch(msg)) => {
+                self.on_holepunch(msg).context("on_holepunch")?;
+            }
             message => {
                 warn!("received unsupported message {:?}, ignoring", message);
             }
@@ -771,15 +2162,26 @@ impl<'a> PeerConnectionHandler for &'a PeerHandler {
     fn serialize_bitfield_message_to_buf(&self, buf: &mut Vec<u8>) -> anyhow::Result<usize> {
         let g = self.state.lock_read("serialize_bitfield_message_to_buf");
         let msg = Message::Bitfield(ByteBuf(g.get_chunks()?.get_have_pieces().as_raw_slice()));
-        let len = msg.serialize(buf, &|| None)?;
+        let len = msg.serialize(buf, &|_| None)?;
         trace!("sending: {:?}, length={}", &msg, len);
         Ok(len)
     }
 
     fn on_handshake<B>(&self, handshake: Handshake<B>) -> anyhow::Result<()> {
-        self.state.set_peer_live(self.addr, handshake);
-        self.tx
-            .send(WriterRequest::Message(MessageOwned::Unchoke))?;
+        // Keep the connection we already have to a dual-stack peer.
+        if let Some(other) = self
+            .state
+            .peers
+            .find_live_duplicate(self.addr, Id20::new(handshake.peer_id))
+        {
+            bail!("already connected to this peer at {other}");
+        }
+        self.state
+            .set_peer_live(self.addr, handshake, self.request_window.clone());
+        if self.state.is_upload_enabled() {
+            self.tx
+                .send(WriterRequest::Message(MessageOwned::Unchoke))?;
+        }
         Ok(())
     }
 
@@ -791,10 +2193,98 @@ impl<'a> PeerConnectionHandler for &'a PeerHandler {
     }
 
     fn read_chunk(&self, chunk: &ChunkInfo, buf: &mut [u8]) -> anyhow::Result<()> {
-        self.state.file_ops().read_chunk(self.addr, chunk, buf)
+        let size = chunk.size as usize;
+        if buf.len() >= size
+            && self
+                .state
+                .write_cache
+                .read(chunk.piece_index, chunk.offset, &mut buf[..size])
+        {
+            return Ok(());
+        }
+        let file_ops = self.state.file_ops();
+        self.state
+            .meta
+            .disk_retry_policy
+            .run("read_chunk", || file_ops.read_chunk(self.addr, chunk, buf))
     }
 
-    fn on_extended_handshake(&self, _: &ExtendedHandshake<ByteBuf>) -> anyhow::Result<()> {
+    fn chunk_file_slices(&self, chunk: &ChunkInfo) -> Option<Vec<FileSlice>> {
+        // Verified pieces that aren't flushed yet are only in memory.
+        if self.state.write_cache.contains(chunk.piece_index) {
+            return None;
+        }
+        self.state.file_ops().chunk_file_slices(chunk).ok()
+    }
+
+    fn upload_limiters(&self) -> Vec<&RateLimiter> {
+        let mut limiters = vec![&self.state.meta.upload_limiter];
+        limiters.extend(self.state.meta.limits.as_deref().map(|l| &l.upload));
+        limiters
+    }
+
+    fn upload_credit(&self) -> Option<&UploadCredit> {
+        self.state.upload_credit.as_ref()
+    }
+
+    fn update_my_extended_handshake(
+        &self,
+        handshake: &mut ExtendedHandshake<ByteBuf<'static>>,
+    ) -> anyhow::Result<()> {
+        handshake.metadata_size = self.state.metadata.as_ref().map(|m| m.len() as u32);
+        handshake.yourip = Some(YourIP(self.addr.ip()));
+        handshake.p = self.state.meta.announce_port.map(u32::from);
+        if self.state.meta.holepunch_port.is_some() {
+            handshake
+                .m
+                .insert(ByteBuf(b"ut_holepunch"), MY_EXTENDED_UT_HOLEPUNCH);
+        }
+        // BEP 21: a seed has nothing to download.
+        if self.state.is_finished() {
+            handshake.upload_only = Some(1);
+        }
+        Ok(())
+    }
+
+    fn my_ip(&self) -> Option<IpAddr> {
+        // Behind a proxy, the peer shouldn't learn our real address.
+        if self
+            .state
+            .meta
+            .dialer
+            .as_ref()
+            .is_some_and(|d| d.proxy.is_some())
+        {
+            return None;
+        }
+        self.state.meta.external_ip.as_ref()?.get()
+    }
+
+    fn on_extended_handshake(&self, eh: &ExtendedHandshake<ByteBuf>) -> anyhow::Result<()> {
+        if let Some(reqq) = eh.reqq {
+            self.request_window.set_peer_reqq(reqq);
+        }
+        let holepunch_addr = self.holepunch_addr(eh);
+        self.state
+            .peers
+            .with_live_mut(self.addr, "on_extended_handshake", |live| {
+                live.supports_extended = true;
+                live.holepunch_addr = holepunch_addr;
+            });
+        let other_addr = other_ip_version_addr(self.addr, eh).filter(|a| {
+            let trusted = &self.state.meta.trusted_peers;
+            trusted.as_ref().is_none_or(|t| t.contains(*a))
+        });
+        if let Some(other_addr) = other_addr {
+            self.state
+                .peers
+                .with_peer_mut(self.addr, "on_extended_handshake", |peer| {
+                    peer.other_addr = Some(other_addr)
+                });
+        }
+        if let (Some(YourIP(ip)), Some(external_ip)) = (eh.yourip, &self.state.meta.external_ip) {
+            external_ip.report(ExternalIpSource::Peer(self.addr.ip()), ip);
+        }
         Ok(())
     }
 
@@ -803,6 +2293,26 @@ impl<'a> PeerConnectionHandler for &'a PeerHandler {
     }
 }
 
+// Where the peer says it can be reached over the IP version it's not connected with, see
+// Peer::other_addr.
+fn other_ip_version_addr(addr: SocketAddr, eh: &ExtendedHandshake<ByteBuf>) -> Option<SocketAddr> {
+    let port = u16::try_from(eh.p?).ok().filter(|p| *p != 0)?;
+    let ip: IpAddr = match addr {
+        SocketAddr::V4(_) => {
+            let octets: [u8; 16] = eh.ipv6.as_ref()?.0.try_into().ok()?;
+            Ipv6Addr::from(octets).into()
+        }
+        SocketAddr::V6(_) => {
+            let octets: [u8; 4] = eh.ipv4.as_ref()?.0.try_into().ok()?;
+            Ipv4Addr::from(octets).into()
+        }
+    };
+    if ip.is_unspecified() || ip.is_loopback() || ip.is_multicast() {
+        return None;
+    }
+    Some(SocketAddr::new(ip, port))
+}
+
 impl PeerHandler {
     fn on_peer_died(self, error: Option<anyhow::Error>) -> anyhow::Result<()> {
         let peers = &self.state.peers;
@@ -816,27 +2326,32 @@ impl PeerHandler {
             }
         };
         let prev = pe.value_mut().state.take(pstats);
+        if let Some(credit) = &self.state.upload_credit {
+            credit.forget_peer(handle);
+        }
 
         match prev {
             PeerState::Connecting(_) => {}
             PeerState::Live(live) => {
-                let mut g = self.state.lock_write("mark_chunk_requests_canceled");
-                for req in live.inflight_requests {
-                    debug!(
-                        "peer dead, marking chunk request cancelled, index={}, chunk={}",
-                        req.piece.get(),
-                        req.chunk
-                    );
-                    g.get_chunks_mut()?
-                        .mark_chunk_request_cancelled(req.piece, req.chunk);
-                }
+                self.state.meta.events.emit(TorrentEvent::PeerDisconnected {
+                    addr: handle,
+                    error: error.as_ref().map(|e| format!("{e:#}")),
+                });
+                self.state
+                    .cancel_chunk_requests(handle, live.inflight_requests.into_keys())?;
             }
             PeerState::NotNeeded => {
                 // Restore it as std::mem::take() replaced it above.
                 pe.value_mut().state.set(PeerState::NotNeeded, pstats);
                 return Ok(());
             }
-            s @ PeerState::Queued | s @ PeerState::Dead => {
+            PeerState::Queued => {
+                // It was requeued while its connection was being let go, e.g. as the file
+                // selection changed. It's connected to again from the queue.
+                pe.value_mut().state.set(PeerState::Queued, pstats);
+                return Ok(());
+            }
+            s @ PeerState::Dead => {
                 warn!("bug: peer was in a wrong state {s:?}, ignoring it forever");
                 // Prevent deadlocks.
                 drop(pe);
@@ -845,16 +2360,20 @@ impl PeerHandler {
             }
         };
 
-        let _error = match error {
-            Some(e) => e,
+        let requeue = std::mem::take(&mut pe.value_mut().requeue_on_disconnect);
+        match error {
+            Some(_) => {
+                self.counters.errors.fetch_add(1, Ordering::Relaxed);
+            }
+            None if requeue => {
+                trace!("peer disconnected above the limits, re-queueing after a backoff");
+            }
             None => {
                 trace!("peer died without errors, not re-queueing");
                 pe.value_mut().state.set(PeerState::NotNeeded, pstats);
                 return Ok(());
             }
-        };
-
-        self.counters.errors.fetch_add(1, Ordering::Relaxed);
+        }
 
         if self.state.is_finished() {
             trace!("torrent finished, not re-queueing");
@@ -878,7 +2397,28 @@ impl PeerHandler {
                     duration = format!("{dur:?}")
                 ),
                 async move {
-                    tokio::time::sleep(dur).await;
+                    let started = Instant::now();
+                    let sleep = sleep_or_clock_jump(dur);
+                    tokio::pin!(sleep);
+                    loop {
+                        tokio::select! {
+                            jumped = &mut sleep => {
+                                if jumped {
+                                    debug!("clock jumped, retrying early");
+                                    tokio::time::sleep(wake_up_jitter()).await;
+                                }
+                                break;
+                            }
+                            _ = self.state.peers_wanted_notify.notified() => {
+                                // The backoff is shortened, not skipped, so peers that keep
+                                // failing aren't dialed on every check.
+                                if started.elapsed() >= dur / STARVED_BACKOFF_DIVISOR {
+                                    debug!("starved of peers, retrying early");
+                                    break;
+                                }
+                            }
+                        }
+                    }
                     self.state
                         .peers
                         .with_peer_mut(handle, "dead_to_queued", |peer| {
@@ -920,10 +2460,21 @@ impl PeerHandler {
                     let mut n_opt = None;
                     let bf = &live.bitfield;
                     for n in g.get_chunks()?.iter_needed_pieces() {
-                        if bf.get(n).map(|v| *v) == Some(true) {
-                            n_opt = Some(n);
-                            break;
+                        if bf.get(n).map(|v| *v) != Some(true) {
+                            continue;
+                        }
+                        let excluded = self
+                            .state
+                            .lengths
+                            .validate_piece_index(n as u32)
+                            .and_then(|n| g.piece_hash_failures.get(&n))
+                            .map(|f| f.is_excluded(self.addr))
+                            .unwrap_or(false);
+                        if excluded {
+                            continue;
                         }
+                        n_opt = Some(n);
+                        break;
                     }
 
                     let n_opt = match n_opt {
@@ -944,6 +2495,9 @@ impl PeerHandler {
                     },
                 );
                 g.get_chunks_mut()?.reserve_needed_piece(n);
+                self.state
+                    .piece_traces
+                    .record(n, || PieceTraceEvent::Reserved { peer: self.addr });
                 Ok(Some(n))
             })
             .transpose()
@@ -960,29 +2514,54 @@ impl PeerHandler {
             None => return None,
         };
 
-        let mut g = self.state.lock_write("try_steal_old_slow_piece");
+        let mut guard = self.state.lock_write("try_steal_old_slow_piece");
+        let g: &mut TorrentStateLocked = &mut guard;
+        let piece_hash_failures = &g.piece_hash_failures;
         let (idx, elapsed, piece_req) = g
             .inflight_pieces
             .iter_mut()
             // don't steal from myself
             .filter(|(_, r)| r.peer != self.addr)
+            // don't steal pieces we already sent broken data for
+            .filter(|(p, _)| {
+                !piece_hash_failures
+                    .get(p)
+                    .map(|f| f.is_excluded(self.addr))
+                    .unwrap_or(false)
+            })
             .map(|(p, r)| (p, r.started.elapsed(), r))
             .max_by_key(|(_, e, _)| *e)?;
 
         // heuristic for "too slow peer"
-        if elapsed.as_secs_f64() > my_avg_time.as_secs_f64() * threshold {
-            debug!(
-                "will steal piece {} from {}: elapsed time {:?}, my avg piece time: {:?}",
-                idx, piece_req.peer, elapsed, my_avg_time
-            );
-            piece_req.peer = self.addr;
-            piece_req.started = Instant::now();
-            return Some(*idx);
+        if elapsed.as_secs_f64() <= my_avg_time.as_secs_f64() * threshold {
+            return None;
         }
-        None
+        debug!(
+            "will steal piece {} from {}: elapsed time {:?}, my avg piece time: {:?}",
+            idx, piece_req.peer, elapsed, my_avg_time
+        );
+        self.state
+            .piece_traces
+            .record(*idx, || PieceTraceEvent::Stolen {
+                from: piece_req.peer,
+                by: self.addr,
+            });
+        let (idx, from) = (*idx, piece_req.peer);
+        piece_req.peer = self.addr;
+        piece_req.started = Instant::now();
+        drop(guard);
+
+        // So that the slow peer doesn't keep sending us chunks that we'll ignore.
+        self.state.cancel_piece_requests(from, idx);
+        Some(idx)
     }
 
     fn on_download_request(&self, request: Request) -> anyhow::Result<()> {
+        if !self.state.is_upload_enabled() {
+            // The peer is choked, the request was probably sent before it knew.
+            trace!("uploading is disabled, ignoring {:?}", request);
+            return Ok(());
+        }
         let piece_index = match self.state.lengths.validate_piece_index(request.index) {
             Some(p) => p,
             None => {
@@ -1088,6 +2667,86 @@ impl PeerHandler {
     }
 
     async fn task_peer_chunk_requester(&self) -> anyhow::Result<()> {
+        tokio::select! {
+            r = self.request_chunks() => r,
+            r = self.expire_stale_requests() => r,
+        }
+    }
+
+    // Cancels the requests the peer didn't answer in time, and gives their pieces up, so that
+    // they don't stay stuck with a peer that doesn't send anything.
+    async fn expire_stale_requests(&self) -> anyhow::Result<()> {
+        let timeout = self
+            .state
+            .meta
+            .tunable_options()
+            .peer_request_timeout
+            .unwrap_or(REQUEST_TIMEOUT);
+        let mut interval = tokio::time::interval(timeout / 4);
+        loop {
+            interval.tick().await;
+            let now = Instant::now();
+            let expired = self
+                .state
+                .peers
+                .with_live(self.addr, |live| {
+                    live.inflight_requests
+                        .iter()
+                        .filter(|(_, sent)| now.saturating_duration_since(**sent) >= timeout)
+                        .map(|(r, _)| *r)
+                        .collect::<Vec<_>>()
+                })
+                .unwrap_or_default();
+            if expired.is_empty() {
+                continue;
+            }
+
+            // The requests are lost anyway while we are choked, that's not the peer's fault.
+            let penalize = !self.locked.read().i_am_choked;
+            debug!(count = expired.len(), penalize, "chunk requests timed out");
+            self.counters
+                .timed_out_requests
+                .fetch_add(expired.len() as u32, Ordering::Relaxed);
+
+            let mut pieces = HashSet::new();
+            {
+                let mut g = self.state.lock_write("expire_stale_requests");
+                for req in expired {
+                    if !pieces.insert(req.piece) {
+                        continue;
+                    }
+                    if g.inflight_pieces.get(&req.piece).map(|p| p.peer) != Some(self.addr) {
+                        continue;
+                    }
+                    g.inflight_pieces.remove(&req.piece);
+                    g.get_chunks_mut()?
+                        .mark_chunk_request_cancelled(req.piece, req.chunk);
+                    self.state
+                        .piece_traces
+                        .record(req.piece, || PieceTraceEvent::TimedOut {
+                            peer: self.addr,
+                            chunk: req.chunk,
+                        });
+                }
+            }
+            // Also the requests for the same pieces that didn't time out yet, as the pieces
+            // aren't ours anymore.
+            for piece in pieces {
+                self.state.cancel_piece_requests(self.addr, piece);
+            }
+            self.state.pieces_released_notify.notify_waiters();
+
+            if penalize {
+                let mut g = self.locked.write();
+                g.requests_timed_out_in_a_row += 1;
+                if g.requests_timed_out_in_a_row >= REQUEST_TIMEOUTS_BEFORE_DISCONNECT {
+                    bail!("peer didn't answer our requests");
+                }
+            }
+        }
+    }
+
+    async fn request_chunks(&self) -> anyhow::Result<()> {
         let handle = self.addr;
         self.wait_for_bitfield().await;
 
@@ -1104,6 +2763,7 @@ impl PeerHandler {
                     l.has_full_torrent(self.state.lengths.total_pieces() as usize)
                 })
                 .unwrap_or_default()
+                && !self.state.keep_seed_connection(self.addr)
             {
                 debug!("both peer and us have full torrent, disconnecting");
                 self.tx.send(WriterRequest::Disconnect)?;
@@ -1119,17 +2779,45 @@ impl PeerHandler {
         loop {
             self.wait_for_unchoke().await;
 
+            if !self.state.is_download_enabled() {
+                debug!("downloading is disabled, waiting until it's enabled");
+                self.tx
+                    .send(WriterRequest::Message(MessageOwned::NotInterested))?;
+                loop {
+                    let notified = self.state.download_enabled_notify.notified();
+                    if self.state.is_download_enabled() {
+                        break;
+                    }
+                    notified.await;
+                }
+                if !self.state.is_finished() {
+                    self.tx
+                        .send(WriterRequest::Message(MessageOwned::Interested))?;
+                }
+                continue;
+            }
+
             if self.state.is_finished() {
-                debug!("nothing left to download, looping forever until manage_peer quits");
+                debug!("nothing left to download, waiting until the file selection changes");
                 loop {
-                    tokio::time::sleep(Duration::from_secs(86400)).await;
+                    let notified = self.state.selection_changed_notify.notified();
+                    if !self.state.is_finished() {
+                        break;
+                    }
+                    notified.await;
                 }
+                self.tx
+                    .send(WriterRequest::Message(MessageOwned::Interested))?;
+                continue;
             }
 
             // Try steal a pice from a very slow peer first. Otherwise we might wait too long
             // to download early pieces.
             // Then try get the next one in queue.
             // Afterwards means we are close to completion, try stealing more aggressively.
+            // Pieces may become free to take, or to steal from slower peers, meanwhile.
+            let released = self.state.pieces_released_notify.notified();
+            let downloaded = self.state.piece_downloaded_notify.notified();
             let next = match self
                 .try_steal_old_slow_piece(10.)
                 .map_or_else(|| self.reserve_next_needed_piece(), |v| Ok(Some(v)))?
@@ -1138,7 +2826,11 @@ impl PeerHandler {
                 Some(next) => next,
                 None => {
                     debug!("no pieces to request");
-                    tokio::time::sleep(Duration::from_secs(10)).await;
+                    tokio::select! {
+                        _ = released => {}
+                        _ = downloaded => {}
+                        _ = tokio::time::sleep(Duration::from_secs(10)) => {}
+                    }
                     continue;
                 }
             };
@@ -1150,14 +2842,45 @@ impl PeerHandler {
                     length: chunk.size,
                 };
 
+                self.request_window.acquire().await;
+                self.state
+                    .meta
+                    .download_limiter
+                    .acquire(chunk.size as u64)
+                    .await;
+                if let Some(limits) = &self.state.meta.limits {
+                    limits.download.acquire(chunk.size as u64).await;
+                }
+
+                // The piece might have been stolen from us while we were waiting.
+                let still_ours = self
+                    .state
+                    .lock_read("is_piece_still_ours")
+                    .inflight_pieces
+                    .get(&next)
+                    .is_some_and(|p| p.peer == self.addr);
+                if !still_ours {
+                    self.request_window.on_requests_cancelled(1);
+                    break;
+                }
+
+                // Sent while the peer is locked, so that a "cancel" for it can't get ahead of it.
                 match self
                     .state
                     .peers
                     .with_live_mut(handle, "add chunk request", |live| {
-                        live.inflight_requests.insert(InflightRequest::from(&chunk))
+                        let request_key = InflightRequest::from(&chunk);
+                        if live.inflight_requests.contains_key(&request_key) {
+                            return Some(false);
+                        }
+                        live.inflight_requests.insert(request_key, Instant::now());
+                        live.tx
+                            .send(WriterRequest::Message(MessageOwned::Request(request)))
+                            .ok()
+                            .map(|_| true)
                     }) {
-                    Some(true) => {}
-                    Some(false) => {
+                    Some(Some(true)) => {}
+                    Some(Some(false)) => {
                         // This request was already in-flight for this peer for this chunk.
                         // This might happen in theory, but not very likely.
                         //
@@ -1165,30 +2888,41 @@ impl PeerHandler {
                         // someone stole a piece from us, and then died, the piece became "needed" again, and we reserved it
                         // all before the piece request was processed by us.
                         warn!("we already requested {:?} previously", chunk);
+                        self.request_window.on_requests_cancelled(1);
                         continue;
                     }
                     // peer died
-                    None => return Ok(()),
+                    Some(None) | None => return Ok(()),
                 };
-
-                loop {
-                    match timeout(Duration::from_secs(10), self.requests_sem.acquire()).await {
-                        Ok(acq) => break acq?.forget(),
-                        Err(_) => continue,
-                    };
-                }
-
-                if self
-                    .tx
-                    .send(WriterRequest::Message(MessageOwned::Request(request)))
-                    .is_err()
-                {
-                    return Ok(());
-                }
             }
         }
     }
 
+    fn on_metadata_request(&self, piece: u32) -> anyhow::Result<()> {
+        let metadata = match self.state.metadata.as_ref() {
+            Some(m) => m,
+            None => {
+                self.tx.send(WriterRequest::Message(MessageOwned::Extended(
+                    ExtendedMessage::UtMetadata(UtMetadata::Reject(piece)),
+                )))?;
+                return Ok(());
+            }
+        };
+        let start = piece as usize * CHUNK_SIZE as usize;
+        let msg = match metadata.get(start..) {
+            Some(rest) if !rest.is_empty() => UtMetadata::Data {
+                piece,
+                total_size: metadata.len() as u32,
+                data: ByteString(rest[..rest.len().min(CHUNK_SIZE as usize)].to_vec()),
+            },
+            _ => UtMetadata::Reject(piece),
+        };
+        self.tx.send(WriterRequest::Message(MessageOwned::Extended(
+            ExtendedMessage::UtMetadata(msg),
+        )))?;
+        Ok(())
+    }
+
     fn on_i_am_choked(&self) {
         self.locked.write().i_am_choked = true;
     }
@@ -1198,32 +2932,16 @@ impl PeerHandler {
         self.state.peers.mark_peer_interested(self.addr, true);
     }
 
-    fn reopen_read_only(&self) -> anyhow::Result<()> {
-        // Lock exclusive just in case to ensure in-flight operations finish.??
-        let _guard = self.state.lock_write("reopen_read_only");
-
-        for (file, filename) in self.state.files.iter().zip(self.state.filenames.iter()) {
-            let mut g = file.lock();
-            // this should close the original file
-            // putting in a block just in case to guarantee drop.
-            {
-                *g = dummy_file()?;
-            }
-            *g = std::fs::OpenOptions::new()
-                .read(true)
-                .open(filename)
-                .with_context(|| format!("error re-opening {:?} readonly", filename))?;
-            debug!("reopened {:?} read-only", filename);
-        }
-        info!("reopened all torrent files in read-only mode");
-        Ok(())
-    }
-
     fn on_i_am_unchoked(&self) {
         trace!("we are unchoked");
         self.locked.write().i_am_choked = false;
         self.unchoke_notify.notify_waiters();
-        self.requests_sem.add_permits(16);
+        self.request_window.reset_outstanding();
+        self.state
+            .peers
+            .with_live_mut(self.addr, "cancelled_requests.clear", |live| {
+                live.cancelled_requests.clear()
+            });
     }
 
     fn on_received_piece(&self, piece: Piece<ByteBuf>) -> anyhow::Result<()> {
@@ -1238,8 +2956,6 @@ impl PeerHandler {
             }
         };
 
-        self.requests_sem.add_permits(1);
-
         // Peer chunk/byte counters.
         self.counters
             .fetched_bytes
@@ -1252,24 +2968,61 @@ impl PeerHandler {
             .fetched_bytes
             .fetch_add(piece.block.len() as u64, Ordering::Relaxed);
 
-        self.state
+        if let Some(credit) = &self.state.upload_credit {
+            credit.on_downloaded(self.addr, piece.block.len() as u64);
+        }
+
+        let request = InflightRequest::from(&chunk_info);
+        let was_cancelled = self
+            .state
             .peers
             .with_live_mut(self.addr, "inflight_requests.remove", |h| {
-                if !h
-                    .inflight_requests
-                    .remove(&InflightRequest::from(&chunk_info))
-                {
-                    anyhow::bail!(
-                        "peer sent us a piece we did not ask. Requested pieces: {:?}. Got: {:?}",
-                        &h.inflight_requests,
-                        &piece,
-                    );
+                if h.inflight_requests.remove(&request).is_some() {
+                    return Ok(false);
                 }
-                Ok(())
+                if h.cancelled_requests.remove(&request) {
+                    return Ok(true);
+                }
+                anyhow::bail!(
+                    "peer sent us a piece we did not ask. Requested pieces: {:?}. Got: {:?}",
+                    h.inflight_requests.keys().collect::<Vec<_>>(),
+                    &piece,
+                );
             })
             .context("peer not found")??;
 
-        let full_piece_download_time = {
+        let trace_chunk_ignored = || {
+            self.state.piece_traces.record(chunk_info.piece_index, || {
+                PieceTraceEvent::ChunkIgnored {
+                    peer: self.addr,
+                    chunk: chunk_info.chunk_index,
+                }
+            })
+        };
+
+        if was_cancelled {
+            // The peer sent it before getting our "cancel". Its window was given back already.
+            trace!("received cancelled chunk {:?}, ignoring", chunk_info);
+            trace_chunk_ignored();
+            return Ok(());
+        }
+
+        // The rest of the piece isn't needed from the peer either, so that it doesn't waste its
+        // upload on chunks we'd ignore. Called without the state lock held, see the deadlock
+        // notice at the top.
+        let cancel_rest_of_piece = || {
+            self.state
+                .cancel_piece_requests(self.addr, chunk_info.piece_index)
+        };
+
+        self.locked.write().requests_timed_out_in_a_row = 0;
+        self.request_window
+            .on_chunk_received(piece.block.len() as u32, Instant::now());
+        self.counters
+            .request_window
+            .store(self.request_window.window(), Ordering::Relaxed);
+
+        let (full_piece_download_time, cached) = {
             let mut g = self.state.lock_write("mark_chunk_downloaded");
 
             match g.inflight_pieces.get(&chunk_info.piece_index) {
@@ -1279,6 +3032,9 @@ impl PeerHandler {
                         "in-flight piece {} was stolen by {}, ignoring",
                         chunk_info.piece_index, peer
                     );
+                    trace_chunk_ignored();
+                    drop(g);
+                    cancel_rest_of_piece();
                     return Ok(());
                 }
                 None => {
@@ -1286,23 +3042,58 @@ impl PeerHandler {
                         "in-flight piece {} not found. it was probably completed by someone else",
                         chunk_info.piece_index
                     );
+                    trace_chunk_ignored();
+                    drop(g);
+                    cancel_rest_of_piece();
                     return Ok(());
                 }
             };
 
-            match g.get_chunks_mut()?.mark_chunk_downloaded(&piece) {
+            let marking_result = g.get_chunks_mut()?.mark_chunk_downloaded(&piece);
+            let mut cached = false;
+            if matches!(
+                marking_result,
+                Some(ChunkMarkingResult::Completed | ChunkMarkingResult::NotCompleted)
+            ) {
+                g.piece_contributors
+                    .entry(chunk_info.piece_index)
+                    .or_default()
+                    .insert(chunk_info.chunk_index, self.addr);
+                self.state.piece_traces.record(chunk_info.piece_index, || {
+                    PieceTraceEvent::ChunkReceived {
+                        peer: self.addr,
+                        chunk: chunk_info.chunk_index,
+                    }
+                });
+                // Copied under the lock, so that the cache agrees with the chunk tracker on
+                // which chunk was the first.
+                let first_chunk = g
+                    .get_chunks()?
+                    .count_received_chunks(chunk_info.piece_index)
+                    == 1;
+                cached = self.state.write_cache.insert_chunk(
+                    &chunk_info,
+                    piece.block.as_ref(),
+                    first_chunk,
+                );
+            }
+
+            let full_piece_download_time = match marking_result {
                 Some(ChunkMarkingResult::Completed) => {
                     trace!("piece={} done, will write and checksum", piece.index,);
                     // This will prevent others from stealing it.
                     {
                         let piece = chunk_info.piece_index;
+                        g.verifying_pieces.insert(piece);
                         g.inflight_pieces.remove(&piece)
                     }
                     .map(|t| t.started.elapsed())
                 }
                 Some(ChunkMarkingResult::PreviouslyCompleted) => {
-                    // TODO: we might need to send cancellations here.
                     debug!("piece={} was done by someone else, ignoring", piece.index,);
+                    trace_chunk_ignored();
+                    drop(g);
+                    cancel_rest_of_piece();
                     return Ok(());
                 }
                 Some(ChunkMarkingResult::NotCompleted) => None,
@@ -1312,31 +3103,54 @@ impl PeerHandler {
                         piece
                     );
                 }
-            }
+            };
+            (full_piece_download_time, cached)
         };
 
         // By this time we reach here, no other peer can for this piece. All others, even if they steal pieces would
         // have fallen off above in one of the defensive checks.
 
+        #[cfg(all(target_os = "linux", feature = "io-uring"))]
+        if !cached {
+            if let Some(uring) = &self.state.meta.uring {
+                // Files in the parts file are written the usual way.
+                if let Ok(slices) = self.state.file_ops().chunk_file_slices(&chunk_info) {
+                    return self.write_chunk_uring(
+                        uring,
+                        slices,
+                        &piece,
+                        chunk_info,
+                        full_piece_download_time,
+                    );
+                }
+            }
+        }
+
         self.state
             .meta
             .spawner
             .spawn_block_in_place(move || {
-                let index = piece.index;
-
                 // TODO: in theory we should unmark the piece as downloaded here. But if there was a disk error, what
                 // should we really do? If we unmark it, it will get requested forever...
                 //
                 // So let's just unwrap and abort.
-                match self
-                    .state
-                    .file_ops()
-                    .write_chunk(self.addr, &piece, &chunk_info)
-                {
-                    Ok(()) => {}
-                    Err(e) => {
-                        error!("FATAL: error writing chunk to disk: {:?}", e);
-                        return self.state.on_fatal_error(e);
+                if !cached {
+                    if let Some(limiter) = &self.state.meta.disk_write_limiter {
+                        limiter.acquire_blocking(piece.block.len() as u64);
+                    }
+                    let file_ops = self.state.file_ops();
+                    match self.state.meta.disk_retry_policy.run("write_chunk", || {
+                        file_ops.write_chunk(self.addr, &piece, &chunk_info)
+                    }) {
+                        Ok(()) => {}
+                        Err(e) if is_out_of_space(&e) => {
+                            warn!("out of disk space writing chunk, pausing: {:#}", e);
+                            return self.state.on_fatal_error(e);
+                        }
+                        Err(e) => {
+                            error!("FATAL: error writing chunk to disk: {:?}", e);
+                            return self.state.on_fatal_error(e);
+                        }
                     }
                 }
 
@@ -1344,86 +3158,157 @@ impl PeerHandler {
                     Some(t) => t,
                     None => return Ok(()),
                 };
-
-                match self
-                    .state
-                    .file_ops()
-                    .check_piece(self.addr, chunk_info.piece_index, &chunk_info)
-                    .with_context(|| format!("error checking piece={index}"))?
-                {
-                    true => {
-                        {
-                            let mut g = self.state.lock_write("mark_piece_downloaded");
-                            g.get_chunks_mut()?
-                                .mark_piece_downloaded(chunk_info.piece_index);
-                        }
-
-                        // Global piece counters.
-                        let piece_len =
-                            self.state.lengths.piece_length(chunk_info.piece_index) as u64;
-                        self.state
-                            .stats
-                            .downloaded_and_checked_bytes
-                            // This counter is used to compute "is_finished", so using
-                            // stronger ordering.
-                            .fetch_add(piece_len, Ordering::Release);
-                        self.state
-                            .stats
-                            .downloaded_and_checked_pieces
-                            // This counter is used to compute "is_finished", so using
-                            // stronger ordering.
-                            .fetch_add(1, Ordering::Release);
-                        self.state
-                            .stats
-                            .have_bytes
-                            .fetch_add(piece_len, Ordering::Relaxed);
-                        self.state.stats.total_piece_download_ms.fetch_add(
-                            full_piece_download_time.as_millis() as u64,
-                            Ordering::Relaxed,
-                        );
-
-                        // Per-peer piece counters.
-                        self.counters
-                            .on_piece_downloaded(piece_len, full_piece_download_time);
-                        self.state.peers.reset_peer_backoff(self.addr);
-
-                        debug!("piece={} successfully downloaded and verified", index);
-
-                        if self.state.is_finished() {
-                            info!("torrent finished downloading");
-                            self.state.finished_notify.notify_waiters();
-                            self.disconnect_all_peers_that_have_full_torrent();
-                            self.reopen_read_only()?;
-                        }
-
-                        self.state.maybe_transmit_haves(chunk_info.piece_index);
-                    }
-                    false => {
-                        warn!("checksum for piece={} did not validate", index,);
-                        self.state
-                            .lock_write("mark_piece_broken")
-                            .get_chunks_mut()?
-                            .mark_piece_broken_if_not_have(chunk_info.piece_index);
-                    }
-                };
-                Ok::<_, anyhow::Error>(())
+                self.verify_when_written(chunk_info, full_piece_download_time)
             })
             .with_context(|| format!("error processing received chunk {chunk_info:?}"))?;
         Ok(())
     }
 
-    fn disconnect_all_peers_that_have_full_torrent(&self) {
-        for mut pe in self.state.peers.states.iter_mut() {
-            if let PeerState::Live(l) = pe.value().state.get() {
-                if l.has_full_torrent(self.state.lengths.total_pieces() as usize) {
-                    let prev = pe.value_mut().state.set_not_needed(&self.state.peers.stats);
-                    let _ = prev
-                        .take_live_no_counters()
-                        .unwrap()
-                        .tx
-                        .send(WriterRequest::Disconnect);
+    // Verify the piece once all its chunks are written, which with io_uring may be later.
+    fn verify_when_written(
+        &self,
+        chunk_info: ChunkInfo,
+        full_piece_download_time: Duration,
+    ) -> anyhow::Result<()> {
+        let verify = self.verification(chunk_info, full_piece_download_time);
+        #[cfg(all(target_os = "linux", feature = "io-uring"))]
+        let verify = match self
+            .state
+            .uring_writes
+            .when_written(chunk_info.piece_index, Box::new(verify))
+        {
+            Some(verify) => verify,
+            None => return Ok(()),
+        };
+        verify()
+    }
+
+    // Verifies the piece on the hashing threads, so that this peer can go on sending chunks.
+    fn verification(
+        &self,
+        chunk_info: ChunkInfo,
+        full_piece_download_time: Duration,
+    ) -> impl FnOnce() -> anyhow::Result<()> + Send + 'static {
+        let state = self.state.clone();
+        let peer = self.addr;
+        let counters = self.counters.clone();
+        let span = tracing::Span::current();
+        move || {
+            let pool = state.meta.hash_pool.clone();
+            let index = chunk_info.piece_index;
+            let verify = move || {
+                let _entered = span.enter();
+                if let Err(e) = state.verify_received_piece(
+                    peer,
+                    &counters,
+                    chunk_info,
+                    full_piece_download_time,
+                ) {
+                    warn!("error verifying piece={}: {:#}", index, e);
                 }
+            };
+            match pool {
+                Some(pool) => pool.submit(verify)?,
+                None => verify(),
             }
+            Ok(())
+        }
+    }
+
+    // Queue the chunk on the io_uring thread instead of writing it here. If it completes the
+    // piece, the piece is verified once all its chunks are written.
+    #[cfg(all(target_os = "linux", feature = "io-uring"))]
+    fn write_chunk_uring(
+        &self,
+        uring: &crate::uring::UringWriter,
+        slices: Vec<FileSlice>,
+        piece: &Piece<ByteBuf>,
+        chunk_info: ChunkInfo,
+        full_piece_download_time: Option<Duration>,
+    ) -> anyhow::Result<()> {
+        let index = chunk_info.piece_index;
+        let data: Arc<[u8]> = Arc::from(piece.block.as_ref());
+        if let Some(limiter) = &self.state.meta.disk_write_limiter {
+            self.state
+                .meta
+                .spawner
+                .spawn_block_in_place(|| limiter.acquire_blocking(data.len() as u64));
         }
+        let mut writes = Vec::with_capacity(slices.len());
+        let mut start = 0;
+        for slice in slices {
+            let end = start + slice.len as usize;
+            writes.push(crate::uring::Write {
+                file: slice
+                    .file
+                    .lock()
+                    .try_clone()
+                    .context("error duplicating file handle")?,
+                offset: slice.offset,
+                data: data.clone(),
+                range: start..end,
+            });
+            start = end;
+        }
+
+        self.state.uring_writes.started(index);
+        let state = self.state.clone();
+        let peer = self.addr;
+        let span = tracing::Span::current();
+        uring.submit(writes, move |res| {
+            let _entered = span.enter();
+            // Failed writes are retried the usual way, which knows the transient errors.
+            let res = res.or_else(|e| {
+                debug!("io_uring write failed, retrying: {:#}", e);
+                let file_ops = state.file_ops();
+                let piece = Piece::<ByteBuf>::from_data(index.get(), chunk_info.offset, &data[..]);
+                state.meta.disk_retry_policy.run("write_chunk", || {
+                    file_ops.write_chunk(peer, &piece, &chunk_info)
+                })
+            });
+            match res {
+                Ok(()) => {}
+                Err(e) if is_out_of_space(&e) => {
+                    warn!("out of disk space writing chunk, pausing: {:#}", e);
+                    let _ = state.on_fatal_error(e);
+                }
+                Err(e) => {
+                    error!("FATAL: error writing chunk to disk: {:?}", e);
+                    let _ = state.on_fatal_error(e);
+                }
+            }
+            if let Some(then) = state.uring_writes.finished(index) {
+                if let Err(e) = then() {
+                    warn!("error verifying piece={}: {:#}", index, e);
+                }
+            }
+        })?;
+
+        match full_piece_download_time {
+            Some(t) => self
+                .state
+                .meta
+                .spawner
+                .spawn_block_in_place(|| self.verify_when_written(chunk_info, t)),
+            None => Ok(()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::PieceHashFailures;
+
+    #[test]
+    fn test_peers_with_bad_chunks() {
+        let good = "127.0.0.1:1".parse().unwrap();
+        let bad = "127.0.0.1:2".parse().unwrap();
+        let mut failures = PieceHashFailures::default();
+        failures.received_chunks.insert((0, good, [1; 20]));
+        failures.received_chunks.insert((1, bad, [0; 20]));
+        failures.received_chunks.insert((2, good, [3; 20]));
+
+        let banned = failures.peers_with_bad_chunks(&[[1; 20], [2; 20], [3; 20]]);
+        assert_eq!(banned.into_iter().collect::<Vec<_>>(), vec![bad]);
     }
 }
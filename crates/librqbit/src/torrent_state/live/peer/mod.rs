@@ -1,18 +1,24 @@
 pub mod stats;
 
-use std::collections::HashSet;
+use std::{
+    collections::{HashMap, HashSet},
+    net::SocketAddr,
+    sync::Arc,
+    time::{Duration, Instant},
+};
 
 use librqbit_core::hash_id::Id20;
 use librqbit_core::lengths::{ChunkInfo, ValidPieceIndex};
 
+use serde::{Deserialize, Serialize};
 use tokio::sync::mpsc::{unbounded_channel, UnboundedReceiver, UnboundedSender};
 
 use crate::peer_connection::WriterRequest;
 use crate::type_aliases::BF;
 
-use super::peers::stats::atomic::AggregatePeerStatsAtomic;
+use super::{peers::stats::atomic::AggregatePeerStatsAtomic, request_window::RequestWindow};
 
-#[derive(Debug, Hash, PartialEq, Eq)]
+#[derive(Debug, Clone, Copy, Hash, PartialEq, Eq)]
 pub(crate) struct InflightRequest {
     pub piece: ValidPieceIndex,
     pub chunk: u32,
@@ -27,30 +33,121 @@ impl From<&ChunkInfo> for InflightRequest {
     }
 }
 
+// A live peer that sent nothing but keep-alives for this long is considered stalled. It's
+// kept while there's nobody else to connect to, but gives its slot up to queued peers.
+pub(crate) const STALL_TIMEOUT: Duration = Duration::from_secs(60);
+
 pub(crate) type PeerRx = UnboundedReceiver<WriterRequest>;
 pub(crate) type PeerTx = UnboundedSender<WriterRequest>;
 
-#[derive(Debug, Default)]
+/// How we learned about a peer. A peer found by several means keeps the first one.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum PeerSource {
+    Tracker,
+    Dht,
+    /// It connected to us.
+    Incoming,
+    /// Given when adding the torrent or with [`crate::ManagedTorrent::add_peer`], or remembered
+    /// from a previous run.
+    Manual,
+    /// A peer we relayed a holepunch rendezvous for asked us to connect to it (BEP 55).
+    Holepunch,
+}
+
+#[derive(Debug)]
 pub(crate) struct Peer {
     pub state: PeerStateNoMut,
     pub stats: stats::atomic::PeerStats,
+    // Banned peers stay "not needed" even if we need peers again.
+    pub banned: bool,
+    pub source: PeerSource,
+    // The address of the other IP version the peer told us in its extended handshake. Both are
+    // raced when connecting again.
+    pub other_addr: Option<SocketAddr>,
+    // A relay asked us to connect to the peer while it connects to us, so the next connection
+    // is made from our listening port, see live::holepunch.
+    pub holepunch: bool,
+    // It's being disconnected as it's above the connection limits, not because we don't need
+    // it, so it's queued again after a backoff when it dies.
+    pub requeue_on_disconnect: bool,
 }
 
 impl Peer {
+    pub fn new_queued(source: PeerSource) -> Self {
+        Self {
+            state: Default::default(),
+            stats: Default::default(),
+            banned: false,
+            source,
+            other_addr: None,
+            holepunch: false,
+            requeue_on_disconnect: false,
+        }
+    }
+
+    // How long the peer has been silent, if it's live.
+    pub fn live_idle_time(&self) -> Option<Duration> {
+        self.state.get_live()?;
+        self.stats.counters.idle_time()
+    }
+
+    pub fn is_stalled(&self) -> bool {
+        self.live_idle_time().is_some_and(|t| t >= STALL_TIMEOUT)
+    }
+
+    pub fn from_retained(
+        retained: RetainedPeer,
+        state: PeerState,
+        counters: &AggregatePeerStatsAtomic,
+    ) -> Self {
+        let state = PeerStateNoMut(state);
+        counters.inc(&state.0);
+        Self {
+            state,
+            stats: retained.stats,
+            banned: retained.banned,
+            source: retained.source,
+            other_addr: retained.other_addr,
+            holepunch: false,
+            requeue_on_disconnect: false,
+        }
+    }
+
     pub fn new_live_for_incoming_connection(
         peer_id: Id20,
         tx: PeerTx,
+        request_window: Arc<RequestWindow>,
         counters: &AggregatePeerStatsAtomic,
     ) -> Self {
-        let state = PeerStateNoMut(PeerState::Live(LivePeerState::new(peer_id, tx)));
+        let state = PeerStateNoMut(PeerState::Live(LivePeerState::new(
+            peer_id,
+            tx,
+            request_window,
+        )));
         counters.inc(&state.0);
         Self {
             state,
             stats: Default::default(),
+            banned: false,
+            source: PeerSource::Incoming,
+            other_addr: None,
+            holepunch: false,
+            requeue_on_disconnect: false,
         }
     }
 }
 
+/// A peer remembered while the torrent is paused, with its stats and backoff, to be queued again
+/// on resume.
+#[derive(Debug)]
+pub(crate) struct RetainedPeer {
+    pub stats: stats::atomic::PeerStats,
+    pub banned: bool,
+    pub source: PeerSource,
+    pub other_addr: Option<SocketAddr>,
+}
+
 #[derive(Debug, Default)]
 pub(crate) enum PeerState {
     #[default]
@@ -140,6 +237,7 @@ impl PeerStateNoMut {
         &mut self,
         peer_id: Id20,
         tx: PeerTx,
+        request_window: Arc<RequestWindow>,
         counters: &AggregatePeerStatsAtomic,
     ) -> anyhow::Result<()> {
         if matches!(&self.0, PeerState::Connecting(..) | PeerState::Live(..)) {
@@ -147,7 +245,10 @@ impl PeerStateNoMut {
         }
         match self.take(counters) {
             PeerState::Queued | PeerState::Dead | PeerState::NotNeeded => {
-                self.set(PeerState::Live(LivePeerState::new(peer_id, tx)), counters);
+                self.set(
+                    PeerState::Live(LivePeerState::new(peer_id, tx, request_window)),
+                    counters,
+                );
             }
             PeerState::Connecting(..) | PeerState::Live(..) => unreachable!(),
         }
@@ -157,6 +258,7 @@ impl PeerStateNoMut {
     pub fn connecting_to_live(
         &mut self,
         peer_id: Id20,
+        request_window: Arc<RequestWindow>,
         counters: &AggregatePeerStatsAtomic,
     ) -> Option<&mut LivePeerState> {
         if let PeerState::Connecting(_) = &self.0 {
@@ -164,7 +266,10 @@ impl PeerStateNoMut {
                 PeerState::Connecting(tx) => tx,
                 _ => unreachable!(),
             };
-            self.set(PeerState::Live(LivePeerState::new(peer_id, tx)), counters);
+            self.set(
+                PeerState::Live(LivePeerState::new(peer_id, tx, request_window)),
+                counters,
+            );
             self.get_live_mut()
         } else {
             None
@@ -178,29 +283,49 @@ impl PeerStateNoMut {
 
 #[derive(Debug)]
 pub(crate) struct LivePeerState {
-    #[allow(dead_code)]
-    peer_id: Id20,
+    pub peer_id: Id20,
 
     pub peer_interested: bool,
 
+    // The peer sent an extended handshake, so it can take updates of ours (BEP 10).
+    pub supports_extended: bool,
+
     // This is used to track the pieces the peer has.
     pub bitfield: BF,
 
-    // When the peer sends us data this is used to track if we asked for it.
-    pub inflight_requests: HashSet<InflightRequest>,
+    // When the peer sends us data this is used to track if we asked for it. With when the
+    // request was sent, to cancel the ones that the peer never answers.
+    pub inflight_requests: HashMap<InflightRequest, Instant>,
+
+    // Requests we sent "cancel" for. The peer may have sent the data already, so it's
+    // ignored when it arrives. Cleared when we get unchoked, as the peer forgets all
+    // requests when choking us.
+    pub cancelled_requests: HashSet<InflightRequest>,
 
     // The main channel to send requests to peer.
     pub tx: PeerTx,
+
+    // Shared with the peer's handler, so that other peers can give back the window of
+    // requests they cancelled.
+    pub request_window: Arc<RequestWindow>,
+
+    // Where the peer listens, if it supports holepunching (BEP 55). Relayed rendezvous are
+    // matched against it.
+    pub holepunch_addr: Option<SocketAddr>,
 }
 
 impl LivePeerState {
-    pub fn new(peer_id: Id20, tx: PeerTx) -> Self {
+    pub fn new(peer_id: Id20, tx: PeerTx, request_window: Arc<RequestWindow>) -> Self {
         LivePeerState {
             peer_id,
             peer_interested: false,
+            supports_extended: false,
             bitfield: BF::new(),
             inflight_requests: Default::default(),
+            cancelled_requests: Default::default(),
             tx,
+            request_window,
+            holepunch_addr: None,
         }
     }
 
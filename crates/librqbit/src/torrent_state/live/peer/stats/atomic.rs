@@ -1,9 +1,9 @@
 use std::{
     sync::{
         atomic::{AtomicU32, AtomicU64, Ordering},
-        Arc,
+        Arc, OnceLock,
     },
-    time::Duration,
+    time::{Duration, Instant},
 };
 
 use backoff::{ExponentialBackoff, ExponentialBackoffBuilder};
@@ -20,6 +20,18 @@ pub(crate) struct PeerCountersAtomic {
     pub downloaded_and_checked_pieces: AtomicU32,
     pub downloaded_and_checked_bytes: AtomicU64,
     pub total_piece_download_ms: AtomicU64,
+    // The current limit of outstanding chunk requests to the peer.
+    pub request_window: AtomicU32,
+    // Chunk requests the peer didn't answer in time, see REQUEST_TIMEOUT.
+    pub timed_out_requests: AtomicU32,
+    // When the peer last sent something other than a keep-alive, as milliseconds since
+    // activity_clock_start() plus one. 0 if it never did.
+    pub last_activity_ms: AtomicU64,
+}
+
+fn activity_clock_start() -> Instant {
+    static START: OnceLock<Instant> = OnceLock::new();
+    *START.get_or_init(Instant::now)
 }
 
 impl PeerCountersAtomic {
@@ -33,6 +45,22 @@ impl PeerCountersAtomic {
             .fetch_add(piece_len, Ordering::Relaxed);
     }
 
+    // Called when the peer becomes live, and on every message it sends except keep-alives.
+    pub(crate) fn on_activity(&self) {
+        let now = activity_clock_start().elapsed().as_millis() as u64 + 1;
+        self.last_activity_ms.store(now, Ordering::Relaxed);
+    }
+
+    // How long the peer hasn't sent anything but keep-alives.
+    pub(crate) fn idle_time(&self) -> Option<Duration> {
+        let last = self.last_activity_ms.load(Ordering::Relaxed);
+        if last == 0 {
+            return None;
+        }
+        let now = activity_clock_start().elapsed().as_millis() as u64 + 1;
+        Some(Duration::from_millis(now.saturating_sub(last)))
+    }
+
     pub(crate) fn average_piece_download_time(&self) -> Option<Duration> {
         let downloaded_pieces = self.downloaded_and_checked_pieces.load(Ordering::Acquire);
         let total_download_time = self.total_piece_download_ms.load(Ordering::Acquire);
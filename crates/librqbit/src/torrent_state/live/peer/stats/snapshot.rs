@@ -2,7 +2,7 @@ use std::{collections::HashMap, sync::atomic::Ordering};
 
 use serde::{Deserialize, Serialize};
 
-use crate::torrent_state::live::peer::{Peer, PeerState};
+use crate::torrent_state::live::peer::{Peer, PeerSource, PeerState};
 
 #[derive(Serialize, Deserialize)]
 pub struct PeerCounters {
@@ -15,12 +15,25 @@ pub struct PeerCounters {
     pub fetched_chunks: u32,
     pub downloaded_and_checked_pieces: u32,
     pub total_piece_download_ms: u64,
+    pub request_window: u32,
+    #[serde(default)]
+    pub timed_out_requests: u32,
 }
 
 #[derive(Serialize, Deserialize)]
 pub struct PeerStats {
     pub counters: PeerCounters,
     pub state: &'static str,
+    pub source: PeerSource,
+    // Live peers only: for how long the peer sent nothing but keep-alives.
+    #[serde(default)]
+    pub idle_ms: Option<u64>,
+    // The peer is live, but was idle for too long. It will be disconnected when there are
+    // other peers to take its slot.
+    #[serde(default)]
+    pub stalled: bool,
+    #[serde(default)]
+    pub banned: bool,
 }
 
 impl From<&super::atomic::PeerCountersAtomic> for PeerCounters {
@@ -39,6 +52,8 @@ impl From<&super::atomic::PeerCountersAtomic> for PeerCounters {
                 .downloaded_and_checked_pieces
                 .load(Ordering::Relaxed),
             total_piece_download_ms: counters.total_piece_download_ms.load(Ordering::Relaxed),
+            request_window: counters.request_window.load(Ordering::Relaxed),
+            timed_out_requests: counters.timed_out_requests.load(Ordering::Relaxed),
         }
     }
 }
@@ -48,6 +63,10 @@ impl From<&Peer> for PeerStats {
         Self {
             counters: peer.stats.counters.as_ref().into(),
             state: peer.state.get().name(),
+            source: peer.source,
+            idle_ms: peer.live_idle_time().map(|t| t.as_millis() as u64),
+            stalled: peer.is_stalled(),
+            banned: peer.banned,
         }
     }
 }
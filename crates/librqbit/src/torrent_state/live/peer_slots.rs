@@ -0,0 +1,144 @@
+// The connection slots of a live torrent: a semaphore that can be resized while peers hold its
+// permits. Growing adds permits. Shrinking forgets the free ones right away, and the ones in
+// use as they are released. Meanwhile the peers above the new size are drained, see
+// TorrentStateLive::disconnect_excess_peers().
+
+use std::sync::{
+    atomic::{AtomicUsize, Ordering},
+    Arc,
+};
+
+use parking_lot::Mutex;
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+
+pub(crate) struct PeerSlots {
+    semaphore: Arc<Semaphore>,
+    size: Mutex<usize>,
+    // Permits to forget instead of releasing them, after shrinking.
+    owed: AtomicUsize,
+}
+
+impl PeerSlots {
+    pub fn new(size: usize) -> Arc<Self> {
+        Arc::new(Self {
+            semaphore: Arc::new(Semaphore::new(size)),
+            size: Mutex::new(size),
+            owed: Default::default(),
+        })
+    }
+
+    pub fn size(&self) -> usize {
+        *self.size.lock()
+    }
+
+    pub fn available(&self) -> usize {
+        self.semaphore.available_permits()
+    }
+
+    pub async fn acquire(self: &Arc<Self>) -> anyhow::Result<PeerSlotPermit> {
+        let permit = self.semaphore.clone().acquire_owned().await?;
+        Ok(self.permit(permit))
+    }
+
+    pub fn try_acquire(self: &Arc<Self>) -> Option<PeerSlotPermit> {
+        let permit = self.semaphore.clone().try_acquire_owned().ok()?;
+        Some(self.permit(permit))
+    }
+
+    fn permit(self: &Arc<Self>, permit: OwnedSemaphorePermit) -> PeerSlotPermit {
+        PeerSlotPermit {
+            permit: Some(permit),
+            slots: Some(self.clone()),
+        }
+    }
+
+    pub fn resize(&self, new_size: usize) {
+        let mut size = self.size.lock();
+        if new_size >= *size {
+            // Permits that weren't forgotten yet count towards growing.
+            let grow = new_size - *size;
+            let owed = self
+                .owed
+                .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |o| {
+                    Some(o.saturating_sub(grow))
+                })
+                .unwrap();
+            self.semaphore.add_permits(grow - owed.min(grow));
+        } else {
+            let mut shrink = *size - new_size;
+            while shrink > 0 {
+                match self.semaphore.try_acquire() {
+                    Ok(permit) => permit.forget(),
+                    Err(_) => break,
+                }
+                shrink -= 1;
+            }
+            self.owed.fetch_add(shrink, Ordering::Relaxed);
+        }
+        *size = new_size;
+    }
+
+    // Whether a released permit should be forgotten.
+    fn take_owed(&self) -> bool {
+        self.owed
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |o| o.checked_sub(1))
+            .is_ok()
+    }
+}
+
+pub(crate) struct PeerSlotPermit {
+    permit: Option<OwnedSemaphorePermit>,
+    // None if the permit isn't from resizable slots.
+    slots: Option<Arc<PeerSlots>>,
+}
+
+impl PeerSlotPermit {
+    /// A permit of a semaphore that isn't resized.
+    pub fn fixed(permit: OwnedSemaphorePermit) -> Self {
+        Self {
+            permit: Some(permit),
+            slots: None,
+        }
+    }
+}
+
+impl Drop for PeerSlotPermit {
+    fn drop(&mut self) {
+        if let (Some(permit), Some(slots)) = (self.permit.take(), &self.slots) {
+            if slots.take_owed() {
+                permit.forget();
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::PeerSlots;
+
+    #[test]
+    fn test_peer_slots_resize() {
+        let slots = PeerSlots::new(4);
+        let mut permits = (0..3)
+            .map(|_| slots.try_acquire().unwrap())
+            .collect::<Vec<_>>();
+
+        // One free permit is forgotten right away, the other one once a peer is gone.
+        slots.resize(2);
+        assert_eq!(slots.available(), 0);
+        permits.pop();
+        assert_eq!(slots.available(), 0);
+        permits.pop();
+        assert_eq!(slots.available(), 1);
+        permits.push(slots.try_acquire().unwrap());
+        assert!(slots.try_acquire().is_none());
+
+        // Growing back before the permits were released.
+        slots.resize(1);
+        slots.resize(5);
+        assert_eq!(slots.available(), 3);
+        permits.clear();
+        assert_eq!(slots.available(), 5);
+        assert_eq!(slots.size(), 5);
+    }
+}
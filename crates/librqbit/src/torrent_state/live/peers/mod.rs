@@ -1,8 +1,9 @@
-use std::net::SocketAddr;
+use std::net::{IpAddr, SocketAddr, SocketAddrV6};
 
 use anyhow::Context;
 use backoff::backoff::Backoff;
-use dashmap::DashMap;
+use dashmap::{DashMap, DashSet};
+use librqbit_core::hash_id::Id20;
 
 use crate::{
     torrent_state::utils::{atomic_inc, TimedExistence},
@@ -11,14 +12,29 @@ use crate::{
 
 use self::stats::{atomic::AggregatePeerStatsAtomic, snapshot::AggregatePeerStats};
 
-use super::peer::{LivePeerState, Peer, PeerRx, PeerState, PeerTx};
+use super::peer::{LivePeerState, Peer, PeerRx, PeerSource, PeerState, PeerTx, RetainedPeer};
 
 pub mod stats;
 
+/// The address to key a peer by. IPv4 peers connecting to a dual-stack listener show up as
+/// IPv4-mapped IPv6 addresses, they are the same peers as the ones we connect to over IPv4.
+pub(crate) fn canonical_peer_addr(addr: SocketAddr) -> SocketAddr {
+    match addr {
+        SocketAddr::V6(a) => match a.ip().to_ipv4_mapped() {
+            Some(ip) => SocketAddr::new(ip.into(), a.port()),
+            None => SocketAddr::V6(SocketAddrV6::new(*a.ip(), a.port(), 0, a.scope_id())),
+        },
+        a => a,
+    }
+}
+
 #[derive(Default)]
 pub(crate) struct PeerStates {
     pub stats: AggregatePeerStatsAtomic,
     pub states: DashMap<PeerHandle, Peer>,
+    // The IPs of the banned peers. Whatever port they come from, e.g. when connecting to us
+    // again, they stay banned.
+    pub banned_ips: DashSet<IpAddr>,
 }
 
 impl PeerStates {
@@ -26,18 +42,36 @@ impl PeerStates {
         AggregatePeerStats::from(&self.stats)
     }
 
-    pub fn add_if_not_seen(&self, addr: SocketAddr) -> Option<PeerHandle> {
+    pub fn is_banned(&self, addr: SocketAddr) -> bool {
+        self.banned_ips.contains(&canonical_peer_addr(addr).ip())
+    }
+
+    pub fn add_if_not_seen(&self, addr: SocketAddr, source: PeerSource) -> Option<PeerHandle> {
         use dashmap::mapref::entry::Entry;
+        if self.is_banned(addr) {
+            return None;
+        }
         match self.states.entry(addr) {
             Entry::Occupied(_) => None,
             Entry::Vacant(vac) => {
-                vac.insert(Default::default());
+                vac.insert(Peer::new_queued(source));
                 atomic_inc(&self.stats.queued);
                 atomic_inc(&self.stats.seen);
                 Some(addr)
             }
         }
     }
+    /// Another live peer from the same IP with the same peer id, e.g. when we and the peer
+    /// connected to each other at the same time. Anyone can claim any peer id, so it's not
+    /// enough on its own to drop a connection.
+    pub fn find_live_duplicate(&self, handle: PeerHandle, peer_id: Id20) -> Option<PeerHandle> {
+        self.states.iter().find_map(|e| {
+            let live = e.value().state.get_live()?;
+            (*e.key() != handle && e.key().ip() == handle.ip() && live.peer_id == peer_id)
+                .then(|| *e.key())
+        })
+    }
+
     pub fn with_peer<R>(&self, addr: PeerHandle, f: impl FnOnce(&Peer) -> R) -> Option<R> {
         self.states.get(&addr).map(|e| f(e.value()))
     }
@@ -109,4 +143,55 @@ impl PeerStates {
         })?;
         Some(prev)
     }
+
+    // Queue the peers that we stopped talking to as we didn't need them, e.g. seeds once the
+    // torrent finished. Returns the peers to connect to.
+    pub fn requeue_not_needed(&self) -> Vec<PeerHandle> {
+        let mut requeued = Vec::new();
+        for mut pe in self.states.iter_mut() {
+            if pe.value().banned || !matches!(pe.value().state.get(), PeerState::NotNeeded) {
+                continue;
+            }
+            pe.value_mut().state.set(PeerState::Queued, &self.stats);
+            pe.value_mut().stats.backoff.reset();
+            requeued.push(*pe.key());
+        }
+        requeued
+    }
+
+    // Take the history of all known peers, to remember them while the torrent is paused.
+    pub fn take_retained(&self) -> Vec<(PeerHandle, RetainedPeer)> {
+        self.states
+            .iter_mut()
+            .map(|mut pe| {
+                let peer = pe.value_mut();
+                let retained = RetainedPeer {
+                    stats: std::mem::take(&mut peer.stats),
+                    banned: peer.banned,
+                    source: peer.source,
+                    other_addr: peer.other_addr,
+                };
+                (*pe.key(), retained)
+            })
+            .collect()
+    }
+
+    // Add the peers remembered across a pause. Returns the ones to connect to, i.e. all but the
+    // banned ones.
+    pub fn restore_retained(&self, peers: Vec<(PeerHandle, RetainedPeer)>) -> Vec<PeerHandle> {
+        let mut queued = Vec::new();
+        for (handle, retained) in peers {
+            let state = if retained.banned {
+                self.banned_ips.insert(handle.ip());
+                PeerState::NotNeeded
+            } else {
+                queued.push(handle);
+                PeerState::Queued
+            };
+            let peer = Peer::from_retained(retained, state, &self.stats);
+            atomic_inc(&self.stats.seen);
+            self.states.insert(handle, peer);
+        }
+        queued
+    }
 }
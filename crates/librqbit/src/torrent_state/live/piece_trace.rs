@@ -0,0 +1,202 @@
+// Debug traces of the state changes of individual pieces.
+//
+// Tracing is enabled per piece on request, to diagnose pieces that never complete. When no
+// piece is traced, recording costs a single atomic load.
+
+use std::{
+    collections::{HashMap, VecDeque},
+    net::SocketAddr,
+    sync::atomic::{AtomicUsize, Ordering},
+    time::Instant,
+};
+
+use librqbit_core::lengths::ValidPieceIndex;
+use parking_lot::Mutex;
+use serde::Serialize;
+
+// Older events are dropped.
+const MAX_EVENTS_PER_PIECE: usize = 1024;
+// Tracing more pieces than this at once isn't useful, and would slow the download down.
+const MAX_TRACED_PIECES: usize = 64;
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum PieceTraceEvent {
+    /// Tracing started, the piece was in this state.
+    Started {
+        state: String,
+    },
+    Reserved {
+        peer: SocketAddr,
+    },
+    Stolen {
+        from: SocketAddr,
+        by: SocketAddr,
+    },
+    ChunkReceived {
+        peer: SocketAddr,
+        chunk: u32,
+    },
+    /// The piece was stolen, completed or deselected, and the requests still in flight to the
+    /// peer were cancelled.
+    RequestsCancelled {
+        peer: SocketAddr,
+        chunks: u32,
+    },
+    /// The chunk came from a peer that the piece was stolen from, or after it was completed.
+    ChunkIgnored {
+        peer: SocketAddr,
+        chunk: u32,
+    },
+    /// The peer died with requests in flight, and the piece is needed again.
+    Requeued {
+        peer: SocketAddr,
+    },
+    /// The peer didn't answer a request in time, and the piece is needed again.
+    TimedOut {
+        peer: SocketAddr,
+        chunk: u32,
+    },
+    /// The piece was in flight, but isn't selected anymore.
+    Deselected,
+    Verified {
+        peer: SocketAddr,
+    },
+    HashFailed {
+        peer: SocketAddr,
+    },
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct PieceTraceEntry {
+    /// Since tracing started.
+    pub elapsed_ms: u64,
+    #[serde(flatten)]
+    pub event: PieceTraceEvent,
+}
+
+struct PieceTrace {
+    started: Instant,
+    events: VecDeque<PieceTraceEntry>,
+    dropped: usize,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct PieceTraceSnapshot {
+    pub piece: u32,
+    /// How many of the oldest events were dropped to bound the log.
+    pub dropped_events: usize,
+    pub events: Vec<PieceTraceEntry>,
+}
+
+#[derive(Default)]
+pub(crate) struct PieceTraces {
+    traced: AtomicUsize,
+    traces: Mutex<HashMap<u32, PieceTrace>>,
+}
+
+impl PieceTraces {
+    // Returns false if the piece was already traced.
+    pub fn start(&self, piece: ValidPieceIndex, state: String) -> anyhow::Result<bool> {
+        let mut g = self.traces.lock();
+        if g.contains_key(&piece.get()) {
+            return Ok(false);
+        }
+        if g.len() >= MAX_TRACED_PIECES {
+            anyhow::bail!("already tracing {MAX_TRACED_PIECES} pieces, stop some first");
+        }
+        let now = Instant::now();
+        let mut events = VecDeque::new();
+        events.push_back(PieceTraceEntry {
+            elapsed_ms: 0,
+            event: PieceTraceEvent::Started { state },
+        });
+        g.insert(
+            piece.get(),
+            PieceTrace {
+                started: now,
+                events,
+                dropped: 0,
+            },
+        );
+        self.traced.store(g.len(), Ordering::Relaxed);
+        Ok(true)
+    }
+
+    pub fn stop(&self, piece: ValidPieceIndex) -> Option<PieceTraceSnapshot> {
+        let mut g = self.traces.lock();
+        let trace = g.remove(&piece.get())?;
+        self.traced.store(g.len(), Ordering::Relaxed);
+        Some(PieceTraceSnapshot {
+            piece: piece.get(),
+            dropped_events: trace.dropped,
+            events: trace.events.into(),
+        })
+    }
+
+    pub fn snapshot(&self, piece: ValidPieceIndex) -> Option<PieceTraceSnapshot> {
+        let g = self.traces.lock();
+        let trace = g.get(&piece.get())?;
+        Some(PieceTraceSnapshot {
+            piece: piece.get(),
+            dropped_events: trace.dropped,
+            events: trace.events.iter().cloned().collect(),
+        })
+    }
+
+    pub fn record(&self, piece: ValidPieceIndex, event: impl FnOnce() -> PieceTraceEvent) {
+        if self.traced.load(Ordering::Relaxed) == 0 {
+            return;
+        }
+        let mut g = self.traces.lock();
+        let trace = match g.get_mut(&piece.get()) {
+            Some(t) => t,
+            None => return,
+        };
+        if trace.events.len() >= MAX_EVENTS_PER_PIECE {
+            trace.events.pop_front();
+            trace.dropped += 1;
+        }
+        trace.events.push_back(PieceTraceEntry {
+            elapsed_ms: trace.started.elapsed().as_millis() as u64,
+            event: event(),
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use librqbit_core::lengths::Lengths;
+
+    use super::{PieceTraceEvent, PieceTraces, MAX_EVENTS_PER_PIECE};
+
+    #[test]
+    fn test_piece_traces_bounded() {
+        let lengths = Lengths::new(16384 * 4, 16384, None).unwrap();
+        let traced = lengths.validate_piece_index(1).unwrap();
+        let other = lengths.validate_piece_index(2).unwrap();
+        let peer = "127.0.0.1:1".parse().unwrap();
+
+        let traces = PieceTraces::default();
+        traces.record(traced, || unreachable!("not traced yet"));
+        assert!(traces.start(traced, "needed".into()).unwrap());
+        assert!(!traces.start(traced, "needed".into()).unwrap());
+
+        for chunk in 0..MAX_EVENTS_PER_PIECE as u32 {
+            traces.record(traced, || PieceTraceEvent::ChunkReceived { peer, chunk });
+        }
+        traces.record(other, || unreachable!("not traced"));
+
+        let snapshot = traces.snapshot(traced).unwrap();
+        assert_eq!(snapshot.events.len(), MAX_EVENTS_PER_PIECE);
+        assert_eq!(snapshot.dropped_events, 1);
+        assert!(matches!(
+            snapshot.events[0].event,
+            PieceTraceEvent::ChunkReceived { chunk: 0, .. }
+        ));
+
+        assert!(traces.stop(traced).is_some());
+        assert!(traces.snapshot(traced).is_none());
+        traces.record(traced, || unreachable!("stopped"));
+    }
+}
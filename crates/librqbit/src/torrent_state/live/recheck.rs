@@ -0,0 +1,162 @@
+// Re-verifying the pieces we have while the torrent stays live, e.g. to catch data that got
+// corrupted on disk. Pieces are read back one at a time on a blocking thread, rate limited, so
+// that downloading and seeding go on. Pieces that fail are downloaded again.
+
+use std::sync::{atomic::Ordering, Arc};
+
+use anyhow::{bail, Context};
+use librqbit_core::lengths::ValidPieceIndex;
+use serde::Serialize;
+use tracing::{error_span, info, warn};
+
+use crate::{file_selection::compute_selected_pieces, limits::RateLimiter};
+
+use super::TorrentStateLive;
+
+/// The background recheck's read rate when no limit is given, low enough to leave the disk to
+/// the downloads and uploads.
+pub const DEFAULT_RECHECK_BYTES_PER_SEC: u64 = 16 * 1024 * 1024;
+
+/// How the last background recheck went, see [`crate::ManagedTorrent::recheck_in_background`].
+#[derive(Debug, Default, Clone, Copy, Serialize)]
+pub struct RecheckProgress {
+    pub running: bool,
+    pub total_pieces: u32,
+    pub checked_pieces: u32,
+    /// Pieces that failed the check, and are downloaded again.
+    pub broken_pieces: u32,
+}
+
+impl TorrentStateLive {
+    pub(crate) fn recheck_progress(&self) -> Option<RecheckProgress> {
+        *self.recheck.lock()
+    }
+
+    /// Start re-verifying the selected pieces we have in the background.
+    pub(crate) fn start_background_recheck(
+        self: &Arc<Self>,
+        only_files: Option<&[usize]>,
+        max_bytes_per_sec: Option<u64>,
+    ) -> anyhow::Result<()> {
+        let selected = compute_selected_pieces(&self.meta.info, &self.lengths, only_files)?;
+        let pieces = self
+            .lock_read("start_background_recheck")
+            .get_chunks()?
+            .get_have_pieces()
+            .iter_ones()
+            .filter(|id| selected[*id])
+            .filter_map(|id| self.lengths.validate_piece_index(id as u32))
+            .collect::<Vec<_>>();
+        {
+            let mut progress = self.recheck.lock();
+            if progress.is_some_and(|p| p.running) {
+                bail!("already rechecking");
+            }
+            *progress = Some(RecheckProgress {
+                running: true,
+                total_pieces: pieces.len() as u32,
+                ..Default::default()
+            });
+        }
+        info!(pieces = pieces.len(), "rechecking in the background");
+        self.spawn(
+            error_span!(parent: self.meta.span.clone(), "background_recheck"),
+            self.clone().task_background_recheck(
+                pieces,
+                RateLimiter::new(Some(
+                    max_bytes_per_sec.unwrap_or(DEFAULT_RECHECK_BYTES_PER_SEC),
+                )),
+            ),
+        );
+        Ok(())
+    }
+
+    async fn task_background_recheck(
+        self: Arc<Self>,
+        pieces: Vec<ValidPieceIndex>,
+        limiter: RateLimiter,
+    ) -> anyhow::Result<()> {
+        let result = self.recheck_pieces(pieces, &limiter).await;
+        let progress = {
+            let mut g = self.recheck.lock();
+            let progress = g.as_mut().context("bug: recheck progress missing")?;
+            progress.running = false;
+            *progress
+        };
+        info!(
+            checked = progress.checked_pieces,
+            broken = progress.broken_pieces,
+            "background recheck finished"
+        );
+        result
+    }
+
+    async fn recheck_pieces(
+        self: &Arc<Self>,
+        pieces: Vec<ValidPieceIndex>,
+        limiter: &RateLimiter,
+    ) -> anyhow::Result<()> {
+        for piece in pieces {
+            limiter
+                .acquire(self.lengths.piece_length(piece) as u64)
+                .await;
+            // Verified pieces that are still in memory aren't on disk yet.
+            let verified = if self.write_cache.contains(piece) {
+                true
+            } else {
+                let state = self.clone();
+                tokio::task::spawn_blocking(move || state.file_ops().check_piece_on_disk(piece))
+                    .await?
+                    .unwrap_or_else(|e| {
+                        warn!(piece = piece.get(), "error rechecking: {:#}", e);
+                        false
+                    })
+            };
+            if !verified {
+                self.on_recheck_failed(piece)?;
+            }
+            if let Some(p) = self.recheck.lock().as_mut() {
+                p.checked_pieces += 1;
+                p.broken_pieces += u32::from(!verified);
+            }
+        }
+        Ok(())
+    }
+
+    // A piece we had is broken on disk: download it again.
+    fn on_recheck_failed(&self, piece: ValidPieceIndex) -> anyhow::Result<()> {
+        warn!(
+            piece = piece.get(),
+            "piece failed the recheck, downloading it again"
+        );
+        let was_finished = self.is_finished();
+        let piece_len = self.lengths.piece_length(piece) as u64;
+        {
+            let mut g = self.lock_write("recheck_failed");
+            let chunks = g.get_chunks_mut()?;
+            if !chunks.is_piece_have(piece) {
+                return Ok(());
+            }
+            chunks.mark_piece_lost(piece);
+            self.stats
+                .have_bytes
+                .fetch_sub(piece_len, Ordering::Relaxed);
+            // Keep "initially_needed - downloaded" equal to what's left to download.
+            self.initially_needed_bytes
+                .fetch_add(piece_len, Ordering::Release);
+        }
+        if was_finished {
+            // Files were reopened read-only on completion.
+            self.reopen_read_write()?;
+            self.on_upload_only_changed(false);
+            if let Some(credit) = &self.upload_credit {
+                credit.set_active(true);
+            }
+            for peer in self.peers.requeue_not_needed() {
+                self.peer_queue_tx.send(peer)?;
+            }
+        }
+        self.selection_changed_notify.notify_waiters();
+        Ok(())
+    }
+}
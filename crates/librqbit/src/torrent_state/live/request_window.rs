@@ -0,0 +1,244 @@
+// Sizing of the per-peer outstanding request window.
+//
+// To saturate a link we need to have at least "bandwidth * round-trip time" bytes (the bandwidth-delay
+// product) requested at any moment. Fast peers thus get deep pipelines, while slow peers keep only a few
+// requests, so that they don't hoard chunks that faster peers could download.
+
+use std::time::{Duration, Instant};
+
+use parking_lot::Mutex;
+use tokio::sync::Notify;
+
+// The window before we have any measurements.
+const INITIAL_WINDOW: u32 = 16;
+const MIN_WINDOW: u32 = 2;
+// Used when the peer doesn't advertise "reqq". Most clients accept a lot more than libtorrent's
+// default of 250, but it's the safe choice.
+const DEFAULT_MAX_WINDOW: u32 = 250;
+// Extra requests on top of the bandwidth-delay product to absorb jitter.
+const EXTRA_REQUESTS: u32 = 4;
+// The window is set somewhat larger than the measured bandwidth-delay product. While the window is the
+// bottleneck, throughput is underestimated, and this lets it grow until the link becomes the bottleneck.
+const GROWTH_FACTOR: f64 = 1.25;
+// How often throughput is sampled.
+const THROUGHPUT_SAMPLE_INTERVAL: Duration = Duration::from_secs(1);
+// Weight of the new sample in the throughput moving average.
+const EWMA_ALPHA: f64 = 0.25;
+
+#[derive(Debug)]
+struct RequestWindowLocked {
+    window: u32,
+    max_window: u32,
+    outstanding: u32,
+    // When the outstanding requests were sent, oldest first, used to measure round-trip times.
+    sent: std::collections::VecDeque<Instant>,
+    // The lowest round-trip time seen. Higher samples include the time the request was queued
+    // behind others, so they aren't a good estimate of the delay.
+    min_rtt: Option<Duration>,
+    throughput_bps: Option<f64>,
+    sample_start: Option<Instant>,
+    sample_bytes: u64,
+}
+
+#[derive(Debug)]
+pub(crate) struct RequestWindow {
+    chunk_size: u32,
+    locked: Mutex<RequestWindowLocked>,
+    notify: Notify,
+}
+
+fn ewma(prev: Option<f64>, sample: f64) -> f64 {
+    match prev {
+        Some(prev) => prev * (1. - EWMA_ALPHA) + sample * EWMA_ALPHA,
+        None => sample,
+    }
+}
+
+impl RequestWindow {
+    pub fn new(chunk_size: u32) -> Self {
+        Self {
+            chunk_size,
+            locked: Mutex::new(RequestWindowLocked {
+                window: INITIAL_WINDOW,
+                max_window: DEFAULT_MAX_WINDOW,
+                outstanding: 0,
+                sent: Default::default(),
+                min_rtt: None,
+                throughput_bps: None,
+                sample_start: None,
+                sample_bytes: 0,
+            }),
+            notify: Notify::new(),
+        }
+    }
+
+    /// The current limit of outstanding requests.
+    pub fn window(&self) -> u32 {
+        self.locked.lock().window
+    }
+
+    /// Limit the window to what the peer advertised in its extended handshake.
+    pub fn set_peer_reqq(&self, reqq: u32) {
+        let mut g = self.locked.lock();
+        g.max_window = reqq.max(1);
+        g.window = g.window.min(g.max_window);
+    }
+
+    fn try_acquire(&self, now: Instant) -> bool {
+        let mut g = self.locked.lock();
+        if g.outstanding >= g.window {
+            return false;
+        }
+        g.outstanding += 1;
+        g.sent.push_back(now);
+        true
+    }
+
+    /// Wait until another request can be sent to the peer.
+    pub async fn acquire(&self) {
+        loop {
+            let notified = self.notify.notified();
+            if self.try_acquire(Instant::now()) {
+                return;
+            }
+            // Re-check periodically in case a notification was missed.
+            let _ = tokio::time::timeout(Duration::from_secs(10), notified).await;
+        }
+    }
+
+    /// Called when a requested chunk was received.
+    pub fn on_chunk_received(&self, bytes: u32, now: Instant) {
+        {
+            let mut g = self.locked.lock();
+            g.outstanding = g.outstanding.saturating_sub(1);
+
+            // Peers answer requests in order, so the oldest one is the one that got answered.
+            if let Some(sent) = g.sent.pop_front() {
+                let sample = now.saturating_duration_since(sent);
+                g.min_rtt = Some(g.min_rtt.map_or(sample, |r| r.min(sample)));
+            }
+
+            let sample_start = *g.sample_start.get_or_insert(now);
+            g.sample_bytes += bytes as u64;
+            let elapsed = now.saturating_duration_since(sample_start);
+            if elapsed >= THROUGHPUT_SAMPLE_INTERVAL {
+                let sample = g.sample_bytes as f64 / elapsed.as_secs_f64();
+                g.throughput_bps = Some(ewma(g.throughput_bps, sample));
+                g.sample_start = Some(now);
+                g.sample_bytes = 0;
+                self.resize(&mut g);
+            }
+        }
+        self.notify.notify_waiters();
+    }
+
+    /// Called when requests were cancelled. They don't count as outstanding anymore, even
+    /// though the peer might still answer some of them.
+    pub fn on_requests_cancelled(&self, count: u32) {
+        {
+            let mut g = self.locked.lock();
+            g.outstanding = g.outstanding.saturating_sub(count);
+            // The remaining ones aren't necessarily the oldest, but it's only used for
+            // round-trip times, and only the lowest one counts.
+            for _ in 0..count {
+                g.sent.pop_back();
+            }
+        }
+        self.notify.notify_waiters();
+    }
+
+    /// Called when we got unchoked. The peer discards all pending requests when choking us,
+    /// so start from scratch.
+    pub fn reset_outstanding(&self) {
+        {
+            let mut g = self.locked.lock();
+            g.outstanding = 0;
+            g.sent.clear();
+            g.sample_start = None;
+            g.sample_bytes = 0;
+        }
+        self.notify.notify_waiters();
+    }
+
+    fn resize(&self, g: &mut RequestWindowLocked) {
+        let (rtt, throughput) = match (g.min_rtt, g.throughput_bps) {
+            (Some(rtt), Some(t)) => (rtt, t),
+            _ => return,
+        };
+        let bdp_chunks = throughput * rtt.as_secs_f64() / self.chunk_size as f64;
+        let window = (bdp_chunks * GROWTH_FACTOR).ceil() as u32 + EXTRA_REQUESTS;
+        // The peer's reqq wins over our minimum, requests beyond it could be dropped.
+        g.window = window.clamp(MIN_WINDOW.min(g.max_window), g.max_window);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, Instant};
+
+    use super::{RequestWindow, INITIAL_WINDOW, MIN_WINDOW};
+
+    const CHUNK: u32 = 16384;
+
+    // Simulate a peer with the given throughput and round-trip time that answers everything we ask.
+    fn simulate(w: &RequestWindow, bytes_per_sec: f64, rtt: Duration) -> u32 {
+        let start = Instant::now();
+        let chunk_time = Duration::from_secs_f64(CHUNK as f64 / bytes_per_sec);
+        let mut now = start;
+        for _ in 0..20000 {
+            while w.try_acquire(now) {}
+            // The link delivers one chunk per "chunk_time", and the first one arrives after "rtt".
+            now += chunk_time;
+            let sent = *w.locked.lock().sent.front().unwrap();
+            let arrival = (sent + rtt).max(now);
+            now = arrival;
+            w.on_chunk_received(CHUNK, now);
+        }
+        w.window()
+    }
+
+    #[test]
+    fn test_fast_peer_gets_deep_pipeline() {
+        let w = RequestWindow::new(CHUNK);
+        // 50 MiB/s, 100ms RTT => BDP is 320 chunks.
+        let window = simulate(&w, 50. * 1024. * 1024., Duration::from_millis(100));
+        assert!(window > INITIAL_WINDOW * 4, "window={window}");
+    }
+
+    #[test]
+    fn test_slow_peer_gets_shallow_pipeline() {
+        let w = RequestWindow::new(CHUNK);
+        // 32 KiB/s, 100ms RTT => BDP is less than a chunk.
+        let window = simulate(&w, 32. * 1024., Duration::from_millis(100));
+        assert!(window < INITIAL_WINDOW, "window={window}");
+        assert!(window >= MIN_WINDOW);
+    }
+
+    #[test]
+    fn test_reqq_limits_window() {
+        let w = RequestWindow::new(CHUNK);
+        w.set_peer_reqq(50);
+        let window = simulate(&w, 50. * 1024. * 1024., Duration::from_millis(100));
+        assert_eq!(window, 50);
+    }
+
+    #[test]
+    fn test_reqq_below_min_window() {
+        let w = RequestWindow::new(CHUNK);
+        w.set_peer_reqq(1);
+        let window = simulate(&w, 50. * 1024. * 1024., Duration::from_millis(100));
+        assert_eq!(window, 1);
+    }
+
+    #[test]
+    fn test_cancelled_requests_free_the_window() {
+        let w = RequestWindow::new(CHUNK);
+        let now = Instant::now();
+        while w.try_acquire(now) {}
+        w.on_requests_cancelled(3);
+        for _ in 0..3 {
+            assert!(w.try_acquire(now));
+        }
+        assert!(!w.try_acquire(now));
+    }
+}
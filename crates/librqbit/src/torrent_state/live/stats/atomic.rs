@@ -8,4 +8,6 @@ pub struct AtomicStats {
     pub uploaded_bytes: AtomicU64,
     pub fetched_bytes: AtomicU64,
     pub total_piece_download_ms: AtomicU64,
+    // Time spent finished while live.
+    pub seeding_ms: AtomicU64,
 }
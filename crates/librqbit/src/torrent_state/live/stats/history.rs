@@ -0,0 +1,152 @@
+use std::{
+    collections::VecDeque,
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+use parking_lot::Mutex;
+use serde::Serialize;
+
+// 24 hours at 10 second resolution.
+pub const DEFAULT_HISTORY_RESOLUTION: Duration = Duration::from_secs(10);
+pub const DEFAULT_HISTORY_LENGTH: usize = 24 * 60 * 6;
+
+#[derive(Clone, Copy)]
+struct LastSample {
+    instant: Instant,
+    fetched_bytes: u64,
+    uploaded_bytes: u64,
+}
+
+#[derive(Debug, Clone, Copy, Serialize, PartialEq, Eq)]
+pub struct BandwidthHistoryEntry {
+    /// Unix timestamp (seconds) of the end of the sampled interval.
+    pub timestamp: u64,
+    pub download_bps: u64,
+    pub upload_bps: u64,
+}
+
+#[derive(Debug, Serialize)]
+pub struct BandwidthHistorySnapshot {
+    pub resolution_secs: u64,
+    pub entries: Vec<BandwidthHistoryEntry>,
+}
+
+/// A rolling history of average download/upload rates, one entry per "resolution" interval.
+/// Unlike SpeedEstimator, which only knows the current speed, this keeps enough history for charting.
+pub struct BandwidthHistory {
+    resolution: Duration,
+    capacity: usize,
+    last: Mutex<Option<LastSample>>,
+    entries: Mutex<VecDeque<BandwidthHistoryEntry>>,
+}
+
+impl Default for BandwidthHistory {
+    fn default() -> Self {
+        Self::new(DEFAULT_HISTORY_RESOLUTION, DEFAULT_HISTORY_LENGTH)
+    }
+}
+
+impl BandwidthHistory {
+    pub fn new(resolution: Duration, capacity: usize) -> Self {
+        assert!(capacity > 0);
+        assert!(!resolution.is_zero());
+        Self {
+            resolution,
+            capacity,
+            last: Mutex::new(None),
+            entries: Mutex::new(VecDeque::new()),
+        }
+    }
+
+    /// Feed in the current values of monotonically growing byte counters.
+    /// This is cheap to call more often than "resolution", intermediate calls are ignored.
+    pub fn add_sample(&self, fetched_bytes: u64, uploaded_bytes: u64, instant: Instant) {
+        self.add_sample_at(fetched_bytes, uploaded_bytes, instant, SystemTime::now())
+    }
+
+    fn add_sample_at(
+        &self,
+        fetched_bytes: u64,
+        uploaded_bytes: u64,
+        instant: Instant,
+        now: SystemTime,
+    ) {
+        let current = LastSample {
+            instant,
+            fetched_bytes,
+            uploaded_bytes,
+        };
+
+        let prev = {
+            let mut g = self.last.lock();
+            match *g {
+                None => {
+                    *g = Some(current);
+                    return;
+                }
+                Some(prev) if instant.saturating_duration_since(prev.instant) < self.resolution => {
+                    return
+                }
+                Some(prev) => {
+                    *g = Some(current);
+                    prev
+                }
+            }
+        };
+
+        let elapsed = instant - prev.instant;
+        let rate =
+            |cur: u64, prev: u64| (cur.saturating_sub(prev) as f64 / elapsed.as_secs_f64()) as u64;
+        let entry = BandwidthHistoryEntry {
+            timestamp: now
+                .duration_since(UNIX_EPOCH)
+                .map(|d| d.as_secs())
+                .unwrap_or_default(),
+            download_bps: rate(fetched_bytes, prev.fetched_bytes),
+            upload_bps: rate(uploaded_bytes, prev.uploaded_bytes),
+        };
+
+        let mut g = self.entries.lock();
+        if g.len() == self.capacity {
+            g.pop_front();
+        }
+        g.push_back(entry);
+    }
+
+    pub fn snapshot(&self) -> BandwidthHistorySnapshot {
+        BandwidthHistorySnapshot {
+            resolution_secs: self.resolution.as_secs(),
+            entries: self.entries.lock().iter().copied().collect(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, Instant, SystemTime};
+
+    use super::BandwidthHistory;
+
+    #[test]
+    fn test_bandwidth_history_rolls_over() {
+        let h = BandwidthHistory::new(Duration::from_secs(10), 2);
+        let start = Instant::now();
+        let now = SystemTime::now();
+
+        h.add_sample_at(0, 0, start, now);
+        // Too early, ignored.
+        h.add_sample_at(500, 0, start + Duration::from_secs(5), now);
+        h.add_sample_at(1000, 100, start + Duration::from_secs(10), now);
+        h.add_sample_at(3000, 100, start + Duration::from_secs(20), now);
+        h.add_sample_at(3000, 300, start + Duration::from_secs(30), now);
+
+        let s = h.snapshot();
+        assert_eq!(s.resolution_secs, 10);
+        let rates = s
+            .entries
+            .iter()
+            .map(|e| (e.download_bps, e.upload_bps))
+            .collect::<Vec<_>>();
+        assert_eq!(rates, vec![(200, 0), (0, 20)]);
+    }
+}
@@ -1,2 +1,3 @@
 pub mod atomic;
+pub mod history;
 pub mod snapshot;
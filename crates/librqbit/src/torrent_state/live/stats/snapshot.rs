@@ -1,8 +1,11 @@
-use std::time::Duration;
+use std::time::{Duration, Instant};
 
 use serde::Serialize;
+use tracker_comms::TrackerStatus;
 
-use crate::torrent_state::live::peers::stats::snapshot::AggregatePeerStats;
+use crate::torrent_state::live::{
+    peers::stats::snapshot::AggregatePeerStats, recheck::RecheckProgress,
+};
 
 #[derive(Debug, Serialize, Default)]
 pub struct StatsSnapshot {
@@ -14,6 +17,51 @@ pub struct StatsSnapshot {
     pub downloaded_and_checked_pieces: u64,
     pub total_piece_download_ms: u64,
     pub peer_stats: AggregatePeerStats,
+    /// Live peers that have the whole torrent.
+    pub live_seeds: usize,
+    /// Live peers that don't.
+    pub live_leechers: usize,
+    /// Seeders and leechers in the whole swarm, the most any tracker reported. Without tracker
+    /// reports, estimated from DHT scrapes (BEP 33). None until either is known.
+    pub swarm_seeders: Option<u64>,
+    pub swarm_leechers: Option<u64>,
+    pub trackers: Vec<TrackerStatsSnapshot>,
+    /// None if there was no background recheck since the torrent went live.
+    pub recheck: Option<RecheckProgress>,
+}
+
+/// How announcing to a tracker went, to tell why a torrent gets no peers.
+#[derive(Debug, Serialize)]
+pub struct TrackerStatsSnapshot {
+    pub url: String,
+    /// How long ago the last announce finished. None if there wasn't one yet.
+    pub last_announce_ago: Option<Duration>,
+    /// Why the last announce failed. None if it succeeded.
+    pub last_error: Option<String>,
+    pub next_announce_in: Option<Duration>,
+    /// Peers the last successful announce returned.
+    pub peers: Option<usize>,
+    pub seeders: Option<u64>,
+    pub leechers: Option<u64>,
+}
+
+impl TrackerStatsSnapshot {
+    pub(crate) fn new(url: &str, status: &TrackerStatus) -> Self {
+        let now = Instant::now();
+        Self {
+            url: url.to_owned(),
+            last_announce_ago: status
+                .last_announce
+                .map(|t| now.saturating_duration_since(t)),
+            last_error: status.last_error.clone(),
+            next_announce_in: status
+                .next_announce
+                .map(|t| t.saturating_duration_since(now)),
+            peers: status.peers,
+            seeders: status.seeders,
+            leechers: status.leechers,
+        }
+    }
 }
 
 impl StatsSnapshot {
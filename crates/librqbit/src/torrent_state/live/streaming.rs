@@ -0,0 +1,315 @@
+use std::{
+    io::SeekFrom,
+    pin::Pin,
+    sync::Arc,
+    task::{Context as TaskContext, Poll},
+    time::{Duration, Instant},
+};
+
+use anyhow::Context;
+use futures::{future::BoxFuture, FutureExt};
+use librqbit_core::lengths::ValidPieceIndex;
+use tokio::io::{AsyncRead, AsyncSeek, ReadBuf};
+use tracing::trace;
+
+use super::TorrentStateLive;
+
+// How often the read rate is sampled.
+const READ_RATE_SAMPLE_INTERVAL: Duration = Duration::from_secs(1);
+// Weight of the new sample in the read rate moving average.
+const READ_RATE_EWMA_ALPHA: f64 = 0.3;
+
+/// How far ahead of the read position [`TorrentFileReader`] prioritizes pieces.
+#[derive(Debug, Clone, Copy)]
+pub struct ReadaheadOptions {
+    /// Prioritize what is expected to be read within this time, at the current read rate.
+    pub duration: Duration,
+    pub min_bytes: u64,
+    pub max_bytes: u64,
+}
+
+impl Default for ReadaheadOptions {
+    fn default() -> Self {
+        Self {
+            duration: Duration::from_secs(30),
+            min_bytes: 4 * 1024 * 1024,
+            max_bytes: 64 * 1024 * 1024,
+        }
+    }
+}
+
+#[derive(Default)]
+struct ReadRate {
+    bytes_per_sec: Option<f64>,
+    sample_start: Option<Instant>,
+    sample_bytes: u64,
+}
+
+impl ReadRate {
+    fn on_read(&mut self, bytes: u64, now: Instant) {
+        let sample_start = *self.sample_start.get_or_insert(now);
+        self.sample_bytes += bytes;
+        let elapsed = now.saturating_duration_since(sample_start);
+        if elapsed >= READ_RATE_SAMPLE_INTERVAL {
+            let sample = self.sample_bytes as f64 / elapsed.as_secs_f64();
+            self.bytes_per_sec = Some(match self.bytes_per_sec {
+                Some(prev) => prev * (1. - READ_RATE_EWMA_ALPHA) + sample * READ_RATE_EWMA_ALPHA,
+                None => sample,
+            });
+            self.sample_start = Some(now);
+            self.sample_bytes = 0;
+        }
+    }
+}
+
+/// Reads a single file of a torrent while it's downloading.
+///
+/// Reads wait for the underlying pieces to be downloaded and verified, and move them
+/// to the front of the download queue, so this can be used e.g. to stream video
+/// straight out of the torrent.
+///
+/// The pieces right after the read position are prioritized too, the more the faster
+/// the file is being read, see [`ReadaheadOptions`].
+pub struct TorrentFileReader {
+    state: Arc<TorrentStateLive>,
+    file_id: usize,
+    // Offset of the first byte of the file within the torrent.
+    file_torrent_offset: u64,
+    file_len: u64,
+    position: u64,
+    pending_seek: Option<u64>,
+    pending_read: Option<BoxFuture<'static, anyhow::Result<Vec<u8>>>>,
+    readahead: Option<ReadaheadOptions>,
+    read_rate: ReadRate,
+    // The pieces that were last prioritized, to avoid doing it on every read.
+    prioritized_pieces: Option<std::ops::Range<u32>>,
+}
+
+impl TorrentFileReader {
+    pub(crate) fn new(state: Arc<TorrentStateLive>, file_id: usize) -> anyhow::Result<Self> {
+        let mut file_torrent_offset = 0;
+        let mut file_len = None;
+        for (idx, len) in state.meta.info.iter_file_lengths()?.enumerate() {
+            if idx == file_id {
+                file_len = Some(len);
+                break;
+            }
+            file_torrent_offset += len;
+        }
+        let file_len = file_len.with_context(|| format!("file id {file_id} is out of range"))?;
+        Ok(Self {
+            state,
+            file_id,
+            file_torrent_offset,
+            file_len,
+            position: 0,
+            pending_seek: None,
+            pending_read: None,
+            readahead: Some(Default::default()),
+            read_rate: Default::default(),
+            prioritized_pieces: None,
+        })
+    }
+
+    /// Change how far ahead of the read position pieces are prioritized. None to only
+    /// prioritize the pieces being read.
+    pub fn set_readahead(&mut self, readahead: Option<ReadaheadOptions>) {
+        self.readahead = readahead;
+        self.prioritized_pieces = None;
+    }
+
+    // Prioritize the pieces from the read position until the end of the read-ahead window.
+    fn update_readahead(&mut self) -> anyhow::Result<()> {
+        let opts = match self.readahead {
+            Some(o) => o,
+            None => return Ok(()),
+        };
+        let window = self
+            .read_rate
+            .bytes_per_sec
+            .map(|r| (r * opts.duration.as_secs_f64()) as u64)
+            .unwrap_or(0)
+            .clamp(opts.min_bytes, opts.max_bytes.max(opts.min_bytes));
+        let start = self.file_torrent_offset + self.position;
+        let end = self.file_torrent_offset + self.file_len.min(self.position + window);
+        if end <= start {
+            return Ok(());
+        }
+        let piece_length = self.state.lengths.default_piece_length() as u64;
+        let pieces = (start / piece_length) as u32..((end - 1) / piece_length) as u32 + 1;
+        if self.prioritized_pieces.as_ref() == Some(&pieces) {
+            return Ok(());
+        }
+        self.state.prioritize_pieces(pieces.clone())?;
+        self.prioritized_pieces = Some(pieces);
+        Ok(())
+    }
+
+    pub fn len(&self) -> u64 {
+        self.file_len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.file_len == 0
+    }
+
+    pub fn position(&self) -> u64 {
+        self.position
+    }
+
+    fn make_read_future(&self, max_len: usize) -> BoxFuture<'static, anyhow::Result<Vec<u8>>> {
+        let state = self.state.clone();
+        let file_id = self.file_id;
+        let position = self.position;
+        let absolute_offset = self.file_torrent_offset + position;
+        let file_remaining = self.file_len - position;
+        async move {
+            let lengths = &state.lengths;
+            let piece = lengths
+                .validate_piece_index(
+                    (absolute_offset / lengths.default_piece_length() as u64) as u32,
+                )
+                .context("bug: offset is out of torrent bounds")?;
+            state.wait_for_piece(piece).await?;
+
+            let piece_remaining =
+                lengths.piece_offset(piece) + lengths.piece_length(piece) as u64 - absolute_offset;
+            let len = (max_len as u64).min(piece_remaining).min(file_remaining) as usize;
+            let mut buf = vec![0u8; len];
+
+            let offset_in_piece = (absolute_offset - lengths.piece_offset(piece)) as u32;
+            if state.write_cache.read(piece, offset_in_piece, &mut buf) {
+                trace!(file_id, position, len, "read from write cache");
+                return Ok(buf);
+            }
+
+            state.meta.spawner.spawn_block_in_place(|| {
+                state.file_ops().read_file_at(file_id, position, &mut buf)
+            })?;
+            trace!(file_id, position, len, "read from torrent file");
+            Ok(buf)
+        }
+        .boxed()
+    }
+}
+
+impl TorrentStateLive {
+    fn prioritize_pieces(&self, pieces: std::ops::Range<u32>) -> anyhow::Result<()> {
+        let mut g = self.lock_write("prioritize_pieces");
+        let pieces = pieces.filter_map(|p| self.lengths.validate_piece_index(p));
+        g.get_chunks_mut()?.prioritize_pieces(pieces);
+        Ok(())
+    }
+
+    /// Wait until the piece is downloaded and verified, bumping its priority if we are still
+    /// waiting for it.
+    pub(crate) async fn wait_for_piece(&self, piece: ValidPieceIndex) -> anyhow::Result<()> {
+        loop {
+            let notified = self.piece_downloaded_notify.notified();
+            {
+                let mut g = self.lock_write("wait_for_piece");
+                let chunks = g.get_chunks_mut()?;
+                if chunks.is_piece_have(piece) {
+                    return Ok(());
+                }
+                chunks.prioritize_piece(piece);
+            }
+            tokio::select! {
+                _ = notified => {},
+                _ = self.cancellation_token.cancelled() => {
+                    anyhow::bail!("torrent was paused")
+                }
+            }
+        }
+    }
+}
+
+fn to_io_error(e: anyhow::Error) -> std::io::Error {
+    std::io::Error::other(format!("{e:#}"))
+}
+
+impl AsyncRead for TorrentFileReader {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut TaskContext<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        if self.position >= self.file_len || buf.remaining() == 0 {
+            return Poll::Ready(Ok(()));
+        }
+
+        if self.pending_read.is_none() {
+            self.update_readahead().map_err(to_io_error)?;
+            let fut = self.make_read_future(buf.remaining());
+            self.pending_read = Some(fut);
+        }
+
+        let result = match self.pending_read.as_mut().unwrap().poll_unpin(cx) {
+            Poll::Ready(r) => r,
+            Poll::Pending => return Poll::Pending,
+        };
+        self.pending_read = None;
+
+        let data = result.map_err(to_io_error)?;
+        // The buffer might have shrunk between polls, the rest will be re-read next time.
+        let len = data.len().min(buf.remaining());
+        buf.put_slice(&data[..len]);
+        self.position += len as u64;
+        self.read_rate.on_read(len as u64, Instant::now());
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl AsyncSeek for TorrentFileReader {
+    fn start_seek(mut self: Pin<&mut Self>, position: SeekFrom) -> std::io::Result<()> {
+        let new_position = match position {
+            SeekFrom::Start(p) => Some(p),
+            SeekFrom::End(d) => self.file_len.checked_add_signed(d),
+            SeekFrom::Current(d) => self.position.checked_add_signed(d),
+        };
+        let new_position = new_position.ok_or_else(|| {
+            std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                "invalid seek to a negative or overflowing position",
+            )
+        })?;
+        self.pending_seek = Some(new_position);
+        Ok(())
+    }
+
+    fn poll_complete(
+        mut self: Pin<&mut Self>,
+        _cx: &mut TaskContext<'_>,
+    ) -> Poll<std::io::Result<u64>> {
+        if let Some(position) = self.pending_seek.take() {
+            // Any in-flight read is for the old position.
+            self.pending_read = None;
+            self.position = position;
+        }
+        Poll::Ready(Ok(self.position))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, Instant};
+
+    use super::ReadRate;
+
+    #[test]
+    fn test_read_rate() {
+        let start = Instant::now();
+        let mut rate = ReadRate::default();
+        rate.on_read(1000, start);
+        assert_eq!(rate.bytes_per_sec, None);
+        rate.on_read(1000, start + Duration::from_secs(1));
+        assert_eq!(rate.bytes_per_sec, Some(2000.));
+
+        // Reads within the sample interval don't change it yet.
+        rate.on_read(4000, start + Duration::from_millis(1500));
+        assert_eq!(rate.bytes_per_sec, Some(2000.));
+        rate.on_read(0, start + Duration::from_secs(2));
+        let bytes_per_sec = rate.bytes_per_sec.unwrap();
+        assert!((bytes_per_sec - 2600.).abs() < 1e-6, "{bytes_per_sec}");
+    }
+}
@@ -0,0 +1,334 @@
+// Received pieces are assembled in memory and written to disk in batches once verified,
+// instead of writing every 16KiB chunk as it arrives.
+//
+// A piece is cached only if its first chunk is, so that it's either fully in memory or fully
+// on disk. The memory is bounded by a budget shared by all torrents of the session. When the
+// budget runs out, chunks are written directly as before.
+
+use std::{
+    collections::HashMap,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+};
+
+use librqbit_core::lengths::{ChunkInfo, Lengths, ValidPieceIndex};
+use parking_lot::Mutex;
+use serde::Serialize;
+
+pub(crate) struct WriteCacheBudget {
+    max_bytes: u64,
+    used_bytes: AtomicU64,
+}
+
+impl WriteCacheBudget {
+    pub fn new(max_bytes: u64) -> Self {
+        Self {
+            max_bytes,
+            used_bytes: AtomicU64::new(0),
+        }
+    }
+
+    pub fn max_bytes(&self) -> u64 {
+        self.max_bytes
+    }
+
+    pub fn used_bytes(&self) -> u64 {
+        self.used_bytes.load(Ordering::Relaxed)
+    }
+
+    fn try_reserve(&self, bytes: u64) -> bool {
+        self.used_bytes
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |used| {
+                (used + bytes <= self.max_bytes).then_some(used + bytes)
+            })
+            .is_ok()
+    }
+
+    fn release(&self, bytes: u64) {
+        self.used_bytes.fetch_sub(bytes, Ordering::Relaxed);
+    }
+
+    // Start flushing before the budget runs out, so that new pieces keep being cached.
+    fn is_under_pressure(&self) -> bool {
+        self.used_bytes() * 2 >= self.max_bytes
+    }
+}
+
+enum CachedPiece {
+    Receiving(Vec<u8>),
+    // Verified and marked as "have", but not written yet.
+    Dirty(Arc<[u8]>),
+}
+
+#[derive(Serialize, Default, Debug)]
+pub struct WriteCacheStats {
+    /// Memory used by pieces of the torrent, both being received and waiting to be written.
+    pub cached_bytes: u64,
+    /// Verified pieces that aren't written to disk yet.
+    pub dirty_bytes: u64,
+}
+
+pub(crate) struct WriteCache {
+    // None if caching is disabled.
+    budget: Option<Arc<WriteCacheBudget>>,
+    lengths: Lengths,
+    pieces: Mutex<HashMap<u32, CachedPiece>>,
+    cached_bytes: AtomicU64,
+    dirty_bytes: AtomicU64,
+    // Only one flush at a time, so that pieces are written in order.
+    flush_lock: Mutex<()>,
+}
+
+impl WriteCache {
+    pub fn new(budget: Option<Arc<WriteCacheBudget>>, lengths: Lengths) -> Self {
+        Self {
+            budget,
+            lengths,
+            pieces: Default::default(),
+            cached_bytes: AtomicU64::new(0),
+            dirty_bytes: AtomicU64::new(0),
+            flush_lock: Mutex::new(()),
+        }
+    }
+
+    // Returns false if the chunk has to be written to disk instead.
+    pub fn insert_chunk(&self, chunk: &ChunkInfo, data: &[u8], first_chunk: bool) -> bool {
+        let budget = match &self.budget {
+            Some(b) => b,
+            None => return false,
+        };
+        let offset = chunk.offset as usize;
+        let mut g = self.pieces.lock();
+        match g.get_mut(&chunk.piece_index.get()) {
+            Some(CachedPiece::Receiving(buf)) => {
+                buf[offset..offset + data.len()].copy_from_slice(data);
+                true
+            }
+            Some(CachedPiece::Dirty(_)) => false,
+            None if !first_chunk => false,
+            None => {
+                let len = self.lengths.piece_length(chunk.piece_index) as u64;
+                if !budget.try_reserve(len) {
+                    return false;
+                }
+                let mut buf = vec![0u8; len as usize];
+                buf[offset..offset + data.len()].copy_from_slice(data);
+                g.insert(chunk.piece_index.get(), CachedPiece::Receiving(buf));
+                self.cached_bytes.fetch_add(len, Ordering::Relaxed);
+                true
+            }
+        }
+    }
+
+    // Take a completely received piece out for checking. None if it's on disk.
+    pub fn take_received(&self, piece: ValidPieceIndex) -> Option<Vec<u8>> {
+        let mut g = self.pieces.lock();
+        match g.remove(&piece.get())? {
+            CachedPiece::Receiving(buf) => Some(buf),
+            dirty => {
+                g.insert(piece.get(), dirty);
+                None
+            }
+        }
+    }
+
+    // Keep the checked piece until it's flushed. Must be called before marking it as "have",
+    // so that reads find it.
+    pub fn insert_verified(&self, piece: ValidPieceIndex, data: Vec<u8>) {
+        self.dirty_bytes
+            .fetch_add(data.len() as u64, Ordering::Relaxed);
+        self.pieces
+            .lock()
+            .insert(piece.get(), CachedPiece::Dirty(data.into()));
+    }
+
+    // Give back the memory of a piece that failed the check.
+    pub fn discard(&self, data: Vec<u8>) {
+        self.release(data.len() as u64);
+    }
+
+    // Give back the memory of a piece being received that isn't wanted anymore, e.g. as its
+    // files were deselected.
+    pub fn discard_receiving(&self, piece: ValidPieceIndex) {
+        if let Some(data) = self.take_received(piece) {
+            self.discard(data);
+        }
+    }
+
+    fn release(&self, bytes: u64) {
+        self.cached_bytes.fetch_sub(bytes, Ordering::Relaxed);
+        if let Some(budget) = &self.budget {
+            budget.release(bytes);
+        }
+    }
+
+    // Copy from a verified piece that isn't flushed yet. Returns false if it's not cached.
+    pub fn read(&self, piece: ValidPieceIndex, offset: u32, buf: &mut [u8]) -> bool {
+        let g = self.pieces.lock();
+        let data = match g.get(&piece.get()) {
+            Some(CachedPiece::Dirty(data)) => data,
+            _ => return false,
+        };
+        let offset = offset as usize;
+        buf.copy_from_slice(&data[offset..offset + buf.len()]);
+        true
+    }
+
+    // Whether the piece is in memory, being received or not flushed yet.
+    pub fn contains(&self, piece: ValidPieceIndex) -> bool {
+        self.pieces.lock().contains_key(&piece.get())
+    }
+
+    pub fn should_flush(&self) -> bool {
+        self.dirty_bytes.load(Ordering::Relaxed) > 0
+            && self.budget.as_ref().is_some_and(|b| b.is_under_pressure())
+    }
+
+    // Write out the verified pieces in order, one write per piece and file.
+    pub fn flush(
+        &self,
+        mut write_piece: impl FnMut(ValidPieceIndex, &[u8]) -> anyhow::Result<()>,
+    ) -> anyhow::Result<()> {
+        let _flushing = self.flush_lock.lock();
+        let mut dirty = self
+            .pieces
+            .lock()
+            .iter()
+            .filter_map(|(piece, p)| match p {
+                CachedPiece::Dirty(data) => Some((*piece, data.clone())),
+                CachedPiece::Receiving(_) => None,
+            })
+            .collect::<Vec<_>>();
+        if dirty.is_empty() {
+            return Ok(());
+        }
+        dirty.sort_unstable_by_key(|(piece, _)| *piece);
+        for (piece, data) in dirty {
+            let index = self
+                .lengths
+                .validate_piece_index(piece)
+                .expect("bug: invalid piece in write cache");
+            write_piece(index, &data)?;
+            // Remove only after writing, so that reads keep finding the data meanwhile.
+            self.pieces.lock().remove(&piece);
+            self.dirty_bytes
+                .fetch_sub(data.len() as u64, Ordering::Relaxed);
+            self.release(data.len() as u64);
+        }
+        Ok(())
+    }
+
+    // The verified pieces that weren't written yet.
+    pub fn dirty_pieces(&self) -> Vec<ValidPieceIndex> {
+        self.pieces
+            .lock()
+            .iter()
+            .filter(|(_, p)| matches!(p, CachedPiece::Dirty(_)))
+            .filter_map(|(piece, _)| self.lengths.validate_piece_index(*piece))
+            .collect()
+    }
+
+    // Drop the verified pieces that weren't written, e.g. as the disk is full. Returns them, so
+    // that they are downloaded again.
+    pub fn discard_dirty(&self) -> Vec<ValidPieceIndex> {
+        let _flushing = self.flush_lock.lock();
+        let mut g = self.pieces.lock();
+        let dirty = g
+            .iter()
+            .filter(|(_, p)| matches!(p, CachedPiece::Dirty(_)))
+            .map(|(piece, _)| *piece)
+            .collect::<Vec<_>>();
+        for piece in dirty.iter() {
+            if let Some(CachedPiece::Dirty(data)) = g.remove(piece) {
+                self.dirty_bytes
+                    .fetch_sub(data.len() as u64, Ordering::Relaxed);
+                self.release(data.len() as u64);
+            }
+        }
+        dirty
+            .into_iter()
+            .map(|piece| {
+                self.lengths
+                    .validate_piece_index(piece)
+                    .expect("bug: invalid piece in write cache")
+            })
+            .collect()
+    }
+
+    pub fn stats(&self) -> WriteCacheStats {
+        WriteCacheStats {
+            cached_bytes: self.cached_bytes.load(Ordering::Relaxed),
+            dirty_bytes: self.dirty_bytes.load(Ordering::Relaxed),
+        }
+    }
+}
+
+impl Drop for WriteCache {
+    fn drop(&mut self) {
+        if let Some(budget) = &self.budget {
+            budget.release(*self.cached_bytes.get_mut());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use librqbit_core::lengths::Lengths;
+
+    use super::{WriteCache, WriteCacheBudget};
+
+    #[test]
+    fn test_write_cache() {
+        // 4 pieces of 2 chunks.
+        let lengths = Lengths::new(16384 * 8, 16384 * 2, None).unwrap();
+        let budget = Arc::new(WriteCacheBudget::new(16384 * 4));
+        let cache = WriteCache::new(Some(budget.clone()), lengths);
+        let chunks = |piece| {
+            lengths
+                .iter_chunk_infos(lengths.validate_piece_index(piece).unwrap())
+                .collect::<Vec<_>>()
+        };
+        let data = vec![1u8; 16384];
+
+        // Only pieces starting in the cache are cached.
+        assert!(!cache.insert_chunk(&chunks(0)[1], &data, false));
+        assert!(cache.insert_chunk(&chunks(1)[0], &data, true));
+        assert!(cache.insert_chunk(&chunks(1)[1], &data, false));
+        assert!(cache.insert_chunk(&chunks(2)[1], &data, true));
+        // Out of budget.
+        assert!(!cache.insert_chunk(&chunks(3)[0], &data, true));
+        assert_eq!(budget.used_bytes(), 16384 * 4);
+
+        let piece = lengths.validate_piece_index(1).unwrap();
+        let received = cache.take_received(piece).unwrap();
+        cache.insert_verified(piece, received);
+        let mut buf = vec![0u8; 100];
+        assert!(cache.read(piece, 16384, &mut buf));
+        assert_eq!(buf, [1u8; 100]);
+        assert_eq!(cache.stats().dirty_bytes, 16384 * 2);
+        assert_eq!(cache.dirty_pieces(), [piece]);
+
+        let mut written = Vec::new();
+        cache
+            .flush(|piece, data| {
+                written.push((piece.get(), data.len()));
+                Ok(())
+            })
+            .unwrap();
+        assert_eq!(written, [(1, 16384 * 2)]);
+        assert!(!cache.read(piece, 0, &mut buf));
+        assert_eq!(cache.stats().dirty_bytes, 0);
+        assert_eq!(budget.used_bytes(), 16384 * 2);
+
+        cache.discard_receiving(lengths.validate_piece_index(2).unwrap());
+        assert_eq!(budget.used_bytes(), 0);
+        assert_eq!(cache.stats().cached_bytes, 0);
+
+        drop(cache);
+        assert_eq!(budget.used_bytes(), 0);
+    }
+}
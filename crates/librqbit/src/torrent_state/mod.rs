@@ -1,19 +1,24 @@
+pub mod events;
 pub mod initializing;
 pub mod live;
 pub mod paused;
 pub mod stats;
 pub mod utils;
 
+use std::collections::BTreeMap;
+use std::collections::HashMap;
 use std::collections::HashSet;
+use std::net::SocketAddr;
 use std::path::Path;
 use std::path::PathBuf;
-use std::sync::atomic::Ordering;
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::Arc;
-use std::time::Duration;
+use std::time::{Duration, Instant};
 
 use anyhow::bail;
 use anyhow::Context;
 use buffers::ByteString;
+use dht::Dht;
 use futures::future::BoxFuture;
 use futures::FutureExt;
 use librqbit_core::hash_id::Id20;
@@ -22,26 +27,53 @@ use librqbit_core::peer_id::generate_peer_id;
 
 use librqbit_core::spawn_utils::spawn_with_cancel;
 use librqbit_core::torrent_metainfo::TorrentMetaV1Info;
+use live::streaming::TorrentFileReader;
 pub use live::*;
-use parking_lot::RwLock;
+use parking_lot::{Mutex, RwLock};
 
+use tokio::sync::Notify;
 use tokio::time::timeout;
-use tokio_stream::StreamExt;
+use tokio_stream::wrappers::errors::BroadcastStreamRecvError;
+use tokio_stream::{Stream, StreamExt};
 use tokio_util::sync::CancellationToken;
 use tracing::debug;
 use tracing::error_span;
+use tracing::info;
 use tracing::warn;
+use tracker_comms::{AnnounceOptions, TrackerStatus};
 
 use crate::chunk_tracker::ChunkTracker;
+use crate::dialer::PeerDialer;
+use crate::disk_retry::DiskRetryPolicy;
+use crate::disk_space::is_out_of_space;
+use crate::external_ip::ExternalIp;
+use crate::file_ops::{self, FileAllocation};
+use crate::file_selection::{compute_piece_priorities, compute_selected_pieces, FilePriority};
+use crate::hash_pool::HashPool;
+use crate::limits::{ConnectionBudget, DialLimiter, Limits, RateLimiter, UploadCoupling};
+use crate::output_dir::OutputDir;
+use crate::part_file::part_file_path;
+use crate::peer_connection::{PeerConnectionOptions, PeerSocketOptions};
+use crate::resume_data::{ResumeStore, TrackerIdentity};
+use crate::seed_limits::SeedLimits;
 use crate::spawn_utils::BlockingSpawner;
-use crate::torrent_state::stats::LiveStats;
-use crate::type_aliases::PeerStream;
+use crate::torrent_state::events::{TorrentEvent, TorrentEvents};
+use crate::torrent_state::live::write_cache::WriteCacheBudget;
+use crate::torrent_state::stats::{DiskUsage, InitializingStats, LiveStats};
+use crate::trusted_peers::TrustedPeers;
+use crate::type_aliases::{PeerStream, BF};
 
 use initializing::TorrentStateInitializing;
 
 use self::paused::TorrentStatePaused;
-pub use self::stats::{TorrentStats, TorrentStatsState};
+pub use self::stats::{TorrentStats, TorrentStatsState, TransferTotals};
 
+// The disk usage in the stats is computed at most this often, as it stats every file.
+const DISK_USAGE_INTERVAL: Duration = Duration::from_secs(10);
+
+// Paused is much larger than the other variants since it holds the chunk tracker. Not boxed, as
+// there's one state per torrent that only moves on pause and resume, so the size doesn't matter.
+#[allow(clippy::large_enum_variant)]
 pub enum ManagedTorrentState {
     Initializing(Arc<TorrentStateInitializing>),
     Paused(TorrentStatePaused),
@@ -67,32 +99,328 @@ impl ManagedTorrentState {
 
 pub(crate) struct ManagedTorrentLocked {
     pub state: ManagedTorrentState,
+    pub only_files: Option<Vec<usize>>,
+    pub file_priorities: HashMap<usize, FilePriority>,
+    // Set when paused for lack of disk space, until started again.
+    pub paused_out_of_space_since: Option<Instant>,
 }
 
-#[derive(Default)]
-pub(crate) struct ManagedTorrentOptions {
-    pub force_tracker_interval: Option<Duration>,
+/// What to do with connections to peers that have the full torrent, once we have it too.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum FinishedPeerPolicy {
+    /// Disconnect them, as neither side can download anything from the other.
+    #[default]
+    Disconnect,
+    /// Keep all of them, e.g. to stay visible in the swarm and keep exchanging peers.
+    Keep,
+    /// Keep at most this many, and disconnect the rest.
+    KeepUpTo(usize),
+}
+
+impl FinishedPeerPolicy {
+    /// Should a connection to a seed be kept, given how many seed connections are kept already.
+    pub(crate) fn keep_seed(&self, kept_seeds: usize) -> bool {
+        match self {
+            FinishedPeerPolicy::Disconnect => false,
+            FinishedPeerPolicy::Keep => true,
+            FinishedPeerPolicy::KeepUpTo(limit) => kept_seeds < *limit,
+        }
+    }
+}
+
+impl std::fmt::Display for FinishedPeerPolicy {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            FinishedPeerPolicy::Disconnect => f.write_str("disconnect"),
+            FinishedPeerPolicy::Keep => f.write_str("keep"),
+            FinishedPeerPolicy::KeepUpTo(limit) => write!(f, "{limit}"),
+        }
+    }
+}
+
+impl std::str::FromStr for FinishedPeerPolicy {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "disconnect" => Ok(Self::Disconnect),
+            "keep" => Ok(Self::Keep),
+            s => s.parse().map(Self::KeepUpTo).map_err(|_| {
+                anyhow::anyhow!(
+                    "invalid finished peer policy {s:?}, expected \"disconnect\", \"keep\" or a number"
+                )
+            }),
+        }
+    }
+}
+
+impl serde::Serialize for FinishedPeerPolicy {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+impl<'de> serde::Deserialize<'de> for FinishedPeerPolicy {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        use serde::de::Error;
+        String::deserialize(deserializer)?
+            .parse()
+            .map_err(D::Error::custom)
+    }
+}
+
+/// What to do once a torrent finishes downloading.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum CompletionAction {
+    /// Stay live and upload to peers.
+    #[default]
+    Seed,
+    Pause,
+    /// Remove the torrent from the session, keeping its files.
+    Forget,
+}
+
+impl std::fmt::Display for CompletionAction {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            CompletionAction::Seed => f.write_str("seed"),
+            CompletionAction::Pause => f.write_str("pause"),
+            CompletionAction::Forget => f.write_str("forget"),
+        }
+    }
+}
+
+impl std::str::FromStr for CompletionAction {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "seed" => Ok(Self::Seed),
+            "pause" => Ok(Self::Pause),
+            "forget" => Ok(Self::Forget),
+            s => anyhow::bail!(
+                "invalid completion action {s:?}, expected \"seed\", \"pause\" or \"forget\""
+            ),
+        }
+    }
+}
+
+impl serde::Serialize for CompletionAction {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+impl<'de> serde::Deserialize<'de> for CompletionAction {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        use serde::de::Error;
+        String::deserialize(deserializer)?
+            .parse()
+            .map_err(D::Error::custom)
+    }
+}
+
+// Called when a torrent finishes downloading while live.
+pub(crate) type FinishedHook = Box<dyn Fn() + Send + Sync>;
+
+/// Connection limits of a torrent. Can be changed while it's running with
+/// [`ManagedTorrent::set_peer_limits`]. None leaves the default.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+pub struct PeerLimits {
+    /// Live and connecting peers, 128 by default. The label policy may lower it further.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_connections: Option<usize>,
+    /// Live peers that have the full torrent. The ones above it are disconnected.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_seeds: Option<usize>,
+    /// Outgoing connections being established at the same time.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_pending_dials: Option<usize>,
+}
+
+impl PeerLimits {
+    fn validate(&self) -> anyhow::Result<()> {
+        if self.max_connections == Some(0) {
+            bail!("max_connections can't be 0");
+        }
+        if self.max_pending_dials == Some(0) {
+            bail!("max_pending_dials can't be 0");
+        }
+        Ok(())
+    }
+}
+
+/// Options of a torrent that can be changed while it's running with
+/// [`ManagedTorrent::set_tunable_options`]. None leaves the default.
+#[serde_with::serde_as]
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+pub struct TunableOptions {
+    /// Bytes per second, on top of the limit of the label the torrent is in.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub download_rate_limit: Option<u64>,
+    /// Bytes per second, on top of the limit of the label the torrent is in.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub upload_rate_limit: Option<u64>,
+    /// Applies to the connections made after it's changed.
+    #[serde_as(as = "Option<serde_with::DurationSeconds>")]
+    #[serde(default, skip_serializing_if = "Option::is_none")]
     pub peer_connect_timeout: Option<Duration>,
+    /// Applies to the connections made after it's changed.
+    #[serde_as(as = "Option<serde_with::DurationSeconds>")]
+    #[serde(default, skip_serializing_if = "Option::is_none")]
     pub peer_read_write_timeout: Option<Duration>,
+    /// Chunk requests a peer didn't answer for this long are cancelled, and their pieces can be
+    /// requested again. 60 seconds if not set. Applies to the connections made after it's
+    /// changed.
+    #[serde_as(as = "Option<serde_with::DurationSeconds>")]
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub peer_request_timeout: Option<Duration>,
+    /// Announce to trackers this often instead of when they ask to. Applies from the next
+    /// announce.
+    #[serde_as(as = "Option<serde_with::DurationSeconds>")]
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub force_tracker_interval: Option<Duration>,
+}
+
+impl TunableOptions {
+    fn validate(&self) -> anyhow::Result<()> {
+        if self.force_tracker_interval == Some(Duration::ZERO) {
+            bail!("force_tracker_interval can't be 0");
+        }
+        if self.peer_request_timeout == Some(Duration::ZERO) {
+            bail!("peer_request_timeout can't be 0");
+        }
+        Ok(())
+    }
+}
+
+#[derive(Default)]
+pub(crate) struct ManagedTorrentOptions {
+    pub announce_options: AnnounceOptions,
+    pub peer_randomize_fingerprint: bool,
+    pub peer_socket_options: PeerSocketOptions,
     pub overwrite: bool,
+    pub force_recheck: bool,
+    pub file_allocation: FileAllocation,
+    pub finished_peer_policy: FinishedPeerPolicy,
+    pub upload_coupling: Option<UploadCoupling>,
+    pub completion_action: CompletionAction,
+    // Changed with ManagedTorrent::set_peer_limits().
+    pub peer_limits: RwLock<PeerLimits>,
+    // Changed with ManagedTorrent::set_seed_limits().
+    pub seed_limits: RwLock<SeedLimits>,
+    // Changed with ManagedTorrent::set_tunable_options().
+    pub tunable: RwLock<TunableOptions>,
 }
 
 pub struct ManagedTorrentInfo {
     pub info: TorrentMetaV1Info<ByteString>,
     pub info_hash: Id20,
-    pub out_dir: PathBuf,
+    // Changed when the files are moved with ManagedTorrent::move_storage().
+    out_dir: RwLock<Arc<OutputDir>>,
     pub(crate) spawner: BlockingSpawner,
     pub trackers: HashSet<String>,
     pub peer_id: Id20,
     pub lengths: Lengths,
     pub span: tracing::Span,
     pub(crate) options: ManagedTorrentOptions,
+    pub(crate) resume_store: Option<Arc<ResumeStore>>,
+    // What trackers know us by, kept in resume data.
+    pub(crate) tracker_identity: Mutex<TrackerIdentity>,
+    // The totals of the previous live runs, kept in resume data. See
+    // TorrentStateLive::transfer_totals() for the ones including the current run.
+    pub(crate) transfer_totals: Mutex<TransferTotals>,
+    pub label: Option<String>,
+    pub(crate) limits: Option<Arc<Limits>>,
+    // The rate limits of the torrent alone, see TunableOptions.
+    pub(crate) download_limiter: RateLimiter,
+    pub(crate) upload_limiter: RateLimiter,
+    pub(crate) disk_write_limiter: Option<Arc<RateLimiter>>,
+    // Switched with ManagedTorrent::set_download_enabled() and set_upload_enabled().
+    pub(crate) download_enabled: AtomicBool,
+    pub(crate) upload_enabled: AtomicBool,
+    pub(crate) write_cache_budget: Option<Arc<WriteCacheBudget>>,
+    pub(crate) connection_budget: Option<Arc<ConnectionBudget>>,
+    pub(crate) dial_limiter: Option<Arc<DialLimiter>>,
+    // Makes the outgoing peer connections if set, e.g. through a proxy.
+    pub(crate) dialer: Option<Arc<PeerDialer>>,
+    // Where peers report our address as they see it.
+    pub(crate) external_ip: Option<Arc<ExternalIp>>,
+    // If set, no other peers are connected to.
+    pub(crate) trusted_peers: Option<Arc<TrustedPeers>>,
+    // The port peers can connect to, told to them in the extended handshake.
+    pub(crate) announce_port: Option<u16>,
+    // Where simultaneous opens are made from, see live::holepunch. None if we don't holepunch.
+    pub(crate) holepunch_port: Option<u16>,
+    pub(crate) disk_retry_policy: DiskRetryPolicy,
+    // Where received pieces are verified. Inline if not set.
+    pub(crate) hash_pool: Option<Arc<HashPool>>,
+    // Where received chunks are written. On blocking threads if not set.
+    #[cfg(all(target_os = "linux", feature = "io-uring"))]
+    pub(crate) uring: Option<Arc<crate::uring::UringWriter>>,
+    // Bytes per second below which more peers are looked for, see TorrentStateLive::peer_demand().
+    pub(crate) target_download_speed: u64,
+    // Wakes the tracker announce loops, see TorrentStateLive::reannounce().
+    pub(crate) reannounce_notify: Arc<Notify>,
+    // By tracker URL.
+    pub(crate) tracker_status: Mutex<BTreeMap<String, TrackerStatus>>,
+    // For the swarm size estimates of the DHT lookup, see DhtState::scrape().
+    pub(crate) dht: Option<Dht>,
+    pub(crate) events: TorrentEvents,
+    pub(crate) finished_hook: Option<FinishedHook>,
+}
+
+impl ManagedTorrentInfo {
+    /// The folder the files of the torrent are in.
+    pub fn out_dir(&self) -> PathBuf {
+        self.out_dir.read().path().to_owned()
+    }
+
+    pub(crate) fn peer_limits(&self) -> PeerLimits {
+        *self.options.peer_limits.read()
+    }
+
+    pub(crate) fn tunable_options(&self) -> TunableOptions {
+        *self.options.tunable.read()
+    }
+
+    // What new peer connections are made with.
+    pub(crate) fn peer_connection_options(&self) -> PeerConnectionOptions {
+        let tunable = self.tunable_options();
+        PeerConnectionOptions {
+            connect_timeout: tunable.peer_connect_timeout,
+            read_write_timeout: tunable.peer_read_write_timeout,
+            randomize_fingerprint: self.options.peer_randomize_fingerprint,
+            socket: self.options.peer_socket_options,
+            ..Default::default()
+        }
+    }
+
+    // The files of the torrent are only accessed through this, see OutputDir.
+    pub(crate) fn out_dir_handle(&self) -> Arc<OutputDir> {
+        self.out_dir.read().clone()
+    }
+
+    // Where the files of the torrent are, or would be, in "out_dir".
+    pub(crate) fn file_paths_in(&self, out_dir: &Path) -> anyhow::Result<Vec<PathBuf>> {
+        self.info
+            .iter_filenames_and_lengths()?
+            .map(|(path_bits, _)| {
+                Ok(out_dir.join(
+                    path_bits
+                        .to_pathbuf()
+                        .context("error converting file to path")?,
+                ))
+            })
+            .collect()
+    }
 }
 
 pub struct ManagedTorrent {
     pub info: Arc<ManagedTorrentInfo>,
-    pub(crate) only_files: Option<Vec<usize>>,
     locked: RwLock<ManagedTorrentLocked>,
+    // When it was computed, see DISK_USAGE_INTERVAL.
+    disk_usage: Mutex<Option<(Instant, DiskUsage)>>,
 }
 
 impl ManagedTorrent {
@@ -109,7 +437,70 @@ impl ManagedTorrent {
     }
 
     pub fn only_files(&self) -> Option<Vec<usize>> {
-        self.only_files.clone()
+        self.locked.read().only_files.clone()
+    }
+
+    pub fn file_priorities(&self) -> HashMap<usize, FilePriority> {
+        self.locked.read().file_priorities.clone()
+    }
+
+    /// Change which files to download and their priorities. Takes effect immediately if the
+    /// torrent is live or paused, without re-checking the files.
+    pub fn update_file_selection(
+        &self,
+        only_files: Option<Vec<usize>>,
+        file_priorities: HashMap<usize, FilePriority>,
+    ) -> anyhow::Result<()> {
+        let total_files = self.info.info.iter_file_lengths()?.count();
+        let ids = only_files.iter().flatten().chain(file_priorities.keys());
+        for id in ids.copied() {
+            if id >= total_files {
+                bail!("file id {} is out of range", id);
+            }
+        }
+        if only_files.as_ref().map(|o| o.is_empty()).unwrap_or(false) {
+            bail!("at least one file should be selected");
+        }
+
+        let mut g = self.locked.write();
+        match &mut g.state {
+            ManagedTorrentState::Initializing(_) => {
+                bail!("torrent is initializing, can't change file selection")
+            }
+            ManagedTorrentState::Paused(p) => {
+                let selected = compute_selected_pieces(
+                    &self.info.info,
+                    &self.info.lengths,
+                    only_files.as_deref(),
+                )?;
+                let piece_priorities = compute_piece_priorities(
+                    &self.info.info,
+                    &self.info.lengths,
+                    only_files.as_deref(),
+                    &file_priorities,
+                )?;
+                p.needed_bytes = p.chunk_tracker.update_selected_pieces(&selected, &[]);
+                p.chunk_tracker.set_piece_priorities(piece_priorities);
+                let out_dir = self.info.out_dir();
+                p.part_file.unpart_selected_files(
+                    &self.info.out_dir_handle(),
+                    &p.files,
+                    &mut p.filenames,
+                    &self.info.file_paths_in(&out_dir)?,
+                    &part_file_path(&out_dir, self.info_hash()),
+                    only_files.as_deref(),
+                    true,
+                )?;
+            }
+            ManagedTorrentState::Live(l) => {
+                l.update_file_selection(only_files.as_deref(), &file_priorities)?
+            }
+            // Will be used on the next start.
+            ManagedTorrentState::Error(_) | ManagedTorrentState::None => {}
+        }
+        g.only_files = only_files;
+        g.file_priorities = file_priorities;
+        Ok(())
     }
 
     pub fn with_state<R>(&self, f: impl FnOnce(&ManagedTorrentState) -> R) -> R {
@@ -135,6 +526,18 @@ impl ManagedTorrent {
         }
     }
 
+    /// Open a file of the torrent for reading while it's being downloaded.
+    /// Reads will wait for the required pieces, prioritizing them.
+    pub fn stream(&self, file_id: usize) -> anyhow::Result<TorrentFileReader> {
+        if let Some(only_files) = &self.locked.read().only_files {
+            if !only_files.contains(&file_id) {
+                bail!("file {file_id} is not selected for download");
+            }
+        }
+        let live = self.live().context("torrent is not live")?;
+        TorrentFileReader::new(live, file_id)
+    }
+
     /// Get the live state if the torrent is live.
     pub fn live(&self) -> Option<Arc<TorrentStateLive>> {
         let g = self.locked.read();
@@ -144,6 +547,33 @@ impl ManagedTorrent {
         }
     }
 
+    /// Events of the torrent from now on, see [`TorrentEvent`].
+    pub fn subscribe(
+        &self,
+    ) -> impl Stream<Item = Result<TorrentEvent, BroadcastStreamRecvError>> + Send + 'static {
+        self.info.events.subscribe()
+    }
+
+    // Every change of the state goes through here, so that the subscribers are told.
+    fn set_state(&self, g: &mut ManagedTorrentLocked, state: ManagedTorrentState) {
+        let new_state = match &state {
+            ManagedTorrentState::Initializing(_) => Some(TorrentStatsState::Initializing),
+            ManagedTorrentState::Paused(_) => Some(TorrentStatsState::Paused),
+            ManagedTorrentState::Live(_) => Some(TorrentStatsState::Live),
+            ManagedTorrentState::Error(e) => {
+                self.info.events.emit(TorrentEvent::Error {
+                    error: format!("{e:#}"),
+                });
+                Some(TorrentStatsState::Error)
+            }
+            ManagedTorrentState::None => None,
+        };
+        g.state = state;
+        if let Some(state) = new_state {
+            self.info.events.emit(TorrentEvent::StateChanged { state });
+        }
+    }
+
     fn stop_with_error(&self, error: anyhow::Error) {
         let mut g = self.locked.write();
 
@@ -165,7 +595,43 @@ impl ManagedTorrent {
             _ => {}
         };
 
-        g.state = ManagedTorrentState::Error(error)
+        self.set_state(&mut g, ManagedTorrentState::Error(error));
+    }
+
+    // Pause instead of failing, so that the session resumes the torrent once there's room.
+    pub(crate) fn pause_out_of_space(&self, error: anyhow::Error) {
+        let mut g = self.locked.write();
+        let paused = match &g.state {
+            ManagedTorrentState::Live(live) => live.pause(),
+            _ => {
+                drop(g);
+                return self.stop_with_error(error);
+            }
+        };
+        match paused {
+            Ok(paused) => {
+                warn!("paused for lack of disk space: {:#}", error);
+                self.set_state(&mut g, ManagedTorrentState::Paused(paused));
+                g.paused_out_of_space_since = Some(Instant::now());
+                drop(g);
+                if let Err(e) = self.save_resume_data() {
+                    warn!("error saving resume data: {:#}", e);
+                }
+            }
+            Err(e) => {
+                self.set_state(&mut g, ManagedTorrentState::Error(e.context(error)));
+            }
+        }
+    }
+
+    /// When the torrent was paused for lack of disk space, if it's still waiting for room.
+    pub fn paused_out_of_space_since(&self) -> Option<Instant> {
+        self.locked.read().paused_out_of_space_since
+    }
+
+    // For torrents restored paused for lack of disk space, so that they still get resumed.
+    pub(crate) fn set_paused_out_of_space(&self) {
+        self.locked.write().paused_out_of_space_since = Some(Instant::now());
     }
 
     pub(crate) fn start(
@@ -175,6 +641,7 @@ impl ManagedTorrent {
         live_cancellation_token: CancellationToken,
     ) -> anyhow::Result<()> {
         let mut g = self.locked.write();
+        g.paused_out_of_space_since = None;
 
         let spawn_fatal_errors_receiver =
             |state: &Arc<Self>,
@@ -191,7 +658,11 @@ impl ManagedTorrent {
                             Err(_) => return Ok(()),
                         };
                         if let Some(state) = state.upgrade() {
-                            state.stop_with_error(e);
+                            if is_out_of_space(&e) {
+                                state.pause_out_of_space(e);
+                            } else {
+                                state.stop_with_error(e);
+                            }
                         } else {
                             warn!("tried to stop the torrent with error, but couldn't upgrade the arc");
                         }
@@ -220,12 +691,13 @@ impl ManagedTorrent {
 
                         loop {
                             match timeout(Duration::from_secs(5), peer_rx.next()).await {
-                                Ok(Some(peer)) => {
+                                Ok(Some((peer, source))) => {
                                     let live = match live.upgrade() {
                                         Some(live) => live,
                                         None => return Ok(()),
                                     };
-                                    live.add_peer_if_not_seen(peer).context("torrent closed")?;
+                                    live.add_peer_if_not_seen(peer, source)
+                                        .context("torrent closed")?;
                                 }
                                 Ok(None) => return Ok(()),
                                 // If timeout, check if the torrent is live.
@@ -262,14 +734,14 @@ impl ManagedTorrent {
                                 }
 
                                 if start_paused {
-                                    g.state = ManagedTorrentState::Paused(paused);
+                                    t.set_state(&mut g, ManagedTorrentState::Paused(paused));
                                     return Ok(());
                                 }
 
                                 let (tx, rx) = tokio::sync::oneshot::channel();
                                 let live =
                                     TorrentStateLive::new(paused, tx, live_cancellation_token);
-                                g.state = ManagedTorrentState::Live(live.clone());
+                                t.set_state(&mut g, ManagedTorrentState::Live(live.clone()));
 
                                 spawn_fatal_errors_receiver(&t, rx, token);
                                 spawn_peer_adder(&live, peer_rx);
@@ -278,7 +750,7 @@ impl ManagedTorrent {
                             }
                             Err(err) => {
                                 let result = anyhow::anyhow!("{:?}", err);
-                                t.locked.write().state = ManagedTorrentState::Error(err);
+                                t.set_state(&mut t.locked.write(), ManagedTorrentState::Error(err));
                                 Err(result)
                             }
                         }
@@ -290,7 +762,7 @@ impl ManagedTorrent {
                 let paused = g.state.take().assert_paused();
                 let (tx, rx) = tokio::sync::oneshot::channel();
                 let live = TorrentStateLive::new(paused, tx, live_cancellation_token.clone());
-                g.state = ManagedTorrentState::Live(live.clone());
+                self.set_state(&mut g, ManagedTorrentState::Live(live.clone()));
                 spawn_fatal_errors_receiver(self, rx, live_cancellation_token);
                 spawn_peer_adder(&live, peer_rx);
                 Ok(())
@@ -298,9 +770,14 @@ impl ManagedTorrent {
             ManagedTorrentState::Error(_) => {
                 let initializing = Arc::new(TorrentStateInitializing::new(
                     self.info.clone(),
-                    self.only_files.clone(),
+                    g.only_files.clone(),
+                    g.file_priorities.clone(),
+                    None,
                 ));
-                g.state = ManagedTorrentState::Initializing(initializing.clone());
+                self.set_state(
+                    &mut g,
+                    ManagedTorrentState::Initializing(initializing.clone()),
+                );
                 drop(g);
 
                 // Recurse.
@@ -310,18 +787,136 @@ impl ManagedTorrent {
         }
     }
 
+    pub fn is_download_enabled(&self) -> bool {
+        self.info.download_enabled.load(Ordering::Relaxed)
+    }
+
+    pub fn is_upload_enabled(&self) -> bool {
+        self.info.upload_enabled.load(Ordering::Relaxed)
+    }
+
+    /// Stop or resume requesting pieces. Unlike pausing, the torrent stays live and keeps
+    /// seeding. Kept across pauses.
+    pub fn set_download_enabled(&self, enabled: bool) {
+        if self.info.download_enabled.swap(enabled, Ordering::Relaxed) == enabled {
+            return;
+        }
+        if let Some(live) = self.live() {
+            live.on_download_enabled_changed();
+        }
+    }
+
+    /// Stop or resume uploading. Peers are choked while it's disabled, and their requests are
+    /// ignored. Kept across pauses.
+    pub fn set_upload_enabled(&self, enabled: bool) {
+        if self.info.upload_enabled.swap(enabled, Ordering::Relaxed) == enabled {
+            return;
+        }
+        if let Some(live) = self.live() {
+            live.on_upload_enabled_changed(enabled);
+        }
+    }
+
+    pub fn peer_limits(&self) -> PeerLimits {
+        self.info.peer_limits()
+    }
+
+    /// Change the connection limits. Peers above the new limits are disconnected right away if
+    /// the torrent is live. Kept across pauses.
+    pub fn set_peer_limits(&self, limits: PeerLimits) -> anyhow::Result<()> {
+        limits.validate()?;
+        *self.info.options.peer_limits.write() = limits;
+        if let Some(live) = self.live() {
+            live.on_peer_limits_changed();
+        }
+        Ok(())
+    }
+
+    pub fn seed_limits(&self) -> SeedLimits {
+        *self.info.options.seed_limits.read()
+    }
+
+    /// Connect to a peer, e.g. on the local network without trackers. The torrent has to be
+    /// live, see [`TorrentStateLive::add_peer`].
+    pub fn add_peer(&self, addr: SocketAddr) -> anyhow::Result<bool> {
+        self.live().context("torrent isn't live")?.add_peer(addr)
+    }
+
+    /// Re-verify the pieces we have without stopping the torrent, reading at most
+    /// `max_bytes_per_sec` from disk, [`crate::DEFAULT_RECHECK_BYTES_PER_SEC`] if not set. Pieces that
+    /// fail are downloaded again. The progress is in the live stats.
+    pub fn recheck_in_background(&self, max_bytes_per_sec: Option<u64>) -> anyhow::Result<()> {
+        let live = self.live().context("torrent isn't live")?;
+        live.start_background_recheck(self.only_files().as_deref(), max_bytes_per_sec)
+    }
+
+    /// Change when to stop seeding. Checked periodically by the session, so a limit that is
+    /// already reached applies within seconds. Kept across pauses.
+    pub fn set_seed_limits(&self, limits: SeedLimits) -> anyhow::Result<()> {
+        limits.validate()?;
+        *self.info.options.seed_limits.write() = limits;
+        Ok(())
+    }
+
+    pub fn tunable_options(&self) -> TunableOptions {
+        self.info.tunable_options()
+    }
+
+    fn cached_disk_usage(&self, compute: impl FnOnce() -> DiskUsage) -> DiskUsage {
+        let mut cached = self.disk_usage.lock();
+        match *cached {
+            Some((at, usage)) if at.elapsed() < DISK_USAGE_INTERVAL => usage,
+            _ => {
+                let usage = compute();
+                *cached = Some((Instant::now(), usage));
+                usage
+            }
+        }
+    }
+
+    /// Change the rate limits, timeouts and tracker interval. The rate limits apply right away,
+    /// the rest as noted in [`TunableOptions`]. Kept across pauses.
+    pub fn set_tunable_options(&self, options: TunableOptions) -> anyhow::Result<()> {
+        options.validate()?;
+        *self.info.options.tunable.write() = options;
+        self.info
+            .download_limiter
+            .set_limit(options.download_rate_limit);
+        self.info
+            .upload_limiter
+            .set_limit(options.upload_rate_limit);
+        Ok(())
+    }
+
+    /// What the torrent transferred over its lifetime, including the current run.
+    pub fn transfer_totals(&self) -> TransferTotals {
+        match self.live() {
+            Some(live) => live.transfer_totals(),
+            None => *self.info.transfer_totals.lock(),
+        }
+    }
+
     /// Pause the torrent if it's live.
     pub fn pause(&self) -> anyhow::Result<()> {
         let mut g = self.locked.write();
         match &g.state {
             ManagedTorrentState::Live(live) => {
                 let paused = live.pause()?;
-                g.state = ManagedTorrentState::Paused(paused);
+                self.set_state(&mut g, ManagedTorrentState::Paused(paused));
+                drop(g);
+                if let Err(e) = self.save_resume_data() {
+                    warn!("error saving resume data: {:#}", e);
+                }
                 Ok(())
             }
             ManagedTorrentState::Initializing(_) => {
                 bail!("torrent is initializing, can't pause");
             }
+            // Paused for good now, rather than until there's room.
+            ManagedTorrentState::Paused(_) if g.paused_out_of_space_since.is_some() => {
+                g.paused_out_of_space_since = None;
+                Ok(())
+            }
             ManagedTorrentState::Paused(_) => {
                 bail!("torrent is already paused");
             }
@@ -332,6 +927,126 @@ impl ManagedTorrent {
         }
     }
 
+    /// Move the files of the torrent to another folder, without stopping it. Files are renamed
+    /// if possible, and copied otherwise, so this can block for a long time.
+    pub fn move_storage(&self, new_dir: PathBuf) -> anyhow::Result<()> {
+        let old_dir = self.info.out_dir();
+        if new_dir == old_dir {
+            return Ok(());
+        }
+        let old_handle = self.info.out_dir_handle();
+        let new_handle = Arc::new(OutputDir::new(new_dir.clone()));
+        let own_filenames = self.info.file_paths_in(&new_dir)?;
+        // Files that aren't downloaded go with the parts file.
+        let old_part_path = part_file_path(&old_dir, self.info_hash());
+        let new_part_path = part_file_path(&new_dir, self.info_hash());
+        let new_filenames = |filenames: &[PathBuf]| {
+            filenames
+                .iter()
+                .zip(own_filenames.iter())
+                .map(|(f, own)| {
+                    if *f == old_part_path {
+                        new_part_path.clone()
+                    } else {
+                        own.clone()
+                    }
+                })
+                .collect::<Vec<_>>()
+        };
+
+        let old_filenames = match self.live() {
+            Some(live) => {
+                let old_filenames = live.filenames();
+                live.move_files(&new_handle, &new_filenames(&old_filenames))?;
+                old_filenames
+            }
+            None => self.with_state_mut(|s| match s {
+                ManagedTorrentState::Paused(p) => {
+                    let old_filenames = p.filenames.clone();
+                    let new_filenames = new_filenames(&old_filenames);
+                    file_ops::move_open_files(
+                        &p.files,
+                        (&old_handle, &mut p.filenames),
+                        (&new_handle, &new_filenames),
+                        true,
+                    )?;
+                    Ok(old_filenames)
+                }
+                ManagedTorrentState::Live(_) => bail!("torrent was started, try again"),
+                ManagedTorrentState::Initializing(_) => {
+                    bail!("torrent is initializing, can't move its files")
+                }
+                ManagedTorrentState::Error(_) => {
+                    bail!("can't move files of torrent in error state")
+                }
+                ManagedTorrentState::None => bail!("bug: torrent is in empty state"),
+            })?,
+        };
+        *self.info.out_dir.write() = new_handle;
+        info!(?old_dir, new_dir = ?self.info.out_dir(), "moved the files of the torrent");
+
+        // Remove the folders of the torrent that are empty now.
+        for filename in old_filenames.iter() {
+            for dir in filename.ancestors().skip(1) {
+                if dir == old_dir || old_handle.remove_dir(dir).is_err() {
+                    break;
+                }
+            }
+        }
+
+        // Copied files have new modification times.
+        if let Err(e) = self.save_resume_data() {
+            warn!("error saving resume data: {:#}", e);
+        }
+        Ok(())
+    }
+
+    /// Snapshot the verified pieces into resume data, so that they don't need to be re-checked
+    /// on the next start. Does nothing if the session doesn't persist its state, or if the
+    /// torrent isn't initialized yet.
+    pub fn save_resume_data(&self) -> anyhow::Result<()> {
+        let store = match self.info.resume_store.as_ref() {
+            Some(s) => s,
+            None => return Ok(()),
+        };
+        let have_pieces = match self.have_pieces_snapshot() {
+            Some(have_pieces) => have_pieces,
+            None => return Ok(()),
+        };
+        // Make sure everything in the snapshot is actually on disk before saving it.
+        if let Some(live) = self.live() {
+            live.sync_files()?;
+        }
+        let filenames = self.with_state(|s| match s {
+            ManagedTorrentState::Paused(p) => p.filenames.clone(),
+            ManagedTorrentState::Live(l) => l.filenames(),
+            _ => Vec::new(),
+        });
+        let trackers = self.info.tracker_identity.lock().clone();
+        store.save(
+            self.info_hash(),
+            &have_pieces,
+            &filenames,
+            &trackers,
+            &self.transfer_totals(),
+        )
+    }
+
+    // The verified pieces that were written to the files, though maybe not synced to disk yet.
+    // None if the torrent isn't initialized yet.
+    pub(crate) fn have_pieces_snapshot(&self) -> Option<BF> {
+        let mut have_pieces = self
+            .with_chunk_tracker(|c| c.get_have_pieces().clone())
+            .ok()?;
+        // Looked at after the snapshot, so that pieces written meanwhile count.
+        if let Some(live) = self.live() {
+            for piece in live.unflushed_pieces() {
+                have_pieces.set(piece.get() as usize, false);
+            }
+        }
+        Some(have_pieces)
+    }
+
     /// Get stats.
     pub fn stats(&self) -> TorrentStats {
         use stats::TorrentStatsState as S;
@@ -342,20 +1057,33 @@ impl ManagedTorrent {
             progress_bytes: 0,
             uploaded_bytes: 0,
             finished: false,
+            initializing: None,
             live: None,
+            disk_usage: None,
+            download_enabled: self.is_download_enabled(),
+            upload_enabled: self.is_upload_enabled(),
+            transfer_totals: self.transfer_totals(),
+            share_ratio: 0.,
         };
 
+        let out_of_space = self.paused_out_of_space_since().is_some();
         self.with_state(|s| {
             match s {
                 ManagedTorrentState::Initializing(i) => {
                     resp.state = S::Initializing;
                     resp.progress_bytes = i.checked_bytes.load(Ordering::Relaxed);
+                    resp.initializing = Some(InitializingStats::from(i.as_ref()));
                 }
                 ManagedTorrentState::Paused(p) => {
                     resp.state = S::Paused;
                     resp.total_bytes = p.chunk_tracker.get_total_selected_bytes();
                     resp.progress_bytes = resp.total_bytes - p.needed_bytes;
                     resp.finished = resp.progress_bytes == resp.total_bytes;
+                    resp.disk_usage =
+                        Some(self.cached_disk_usage(|| file_ops::disk_usage(&p.filenames)));
+                    if out_of_space {
+                        resp.error = Some("out of disk space, resuming once there's room".into());
+                    }
                 }
                 ManagedTorrentState::Live(l) => {
                     resp.state = S::Live;
@@ -369,6 +1097,7 @@ impl ManagedTorrent {
                     resp.finished = remaining == 0;
                     resp.uploaded_bytes = l.get_uploaded_bytes();
                     resp.live = Some(live_stats);
+                    resp.disk_usage = Some(self.cached_disk_usage(|| l.disk_usage()));
                 }
                 ManagedTorrentState::Error(e) => {
                     resp.state = S::Error;
@@ -379,6 +1108,7 @@ impl ManagedTorrent {
                     resp.error = Some("bug: torrent in broken \"None\" state".to_string());
                 }
             }
+            resp.share_ratio = resp.transfer_totals.share_ratio(resp.total_bytes);
             resp
         })
     }
@@ -386,7 +1116,8 @@ impl ManagedTorrent {
     #[inline(never)]
     pub fn wait_until_completed(&self) -> BoxFuture<'_, anyhow::Result<()>> {
         async move {
-            // TODO: rewrite, this polling is horrible
+            // Subscribed before looking at the state, so that a change in between isn't missed.
+            let mut events = self.info.events.subscribe();
             let live = loop {
                 let live = self.with_state(|s| match s {
                     ManagedTorrentState::Initializing(_) | ManagedTorrentState::Paused(_) => {
@@ -399,7 +1130,8 @@ impl ManagedTorrent {
                 if let Some(live) = live {
                     break live;
                 }
-                tokio::time::sleep(Duration::from_secs(1)).await;
+                // Any event, or falling behind on them, is a reason to look again.
+                let _ = events.next().await.context("torrent was dropped")?;
             };
 
             live.wait_until_completed().await;
@@ -413,14 +1145,45 @@ pub struct ManagedTorrentBuilder {
     info: TorrentMetaV1Info<ByteString>,
     info_hash: Id20,
     output_folder: PathBuf,
-    force_tracker_interval: Option<Duration>,
-    peer_connect_timeout: Option<Duration>,
-    peer_read_write_timeout: Option<Duration>,
+    announce_options: AnnounceOptions,
+    tunable: TunableOptions,
+    peer_randomize_fingerprint: bool,
+    peer_socket_options: PeerSocketOptions,
     only_files: Option<Vec<usize>>,
+    file_priorities: HashMap<usize, FilePriority>,
     trackers: Vec<String>,
     peer_id: Option<Id20>,
     overwrite: bool,
+    force_recheck: bool,
+    file_allocation: FileAllocation,
+    finished_peer_policy: FinishedPeerPolicy,
+    upload_coupling: Option<UploadCoupling>,
+    completion_action: CompletionAction,
+    peer_limits: PeerLimits,
+    seed_limits: SeedLimits,
     spawner: Option<BlockingSpawner>,
+    resume_store: Option<Arc<ResumeStore>>,
+    have_pieces: Option<BF>,
+    label: Option<String>,
+    limits: Option<Arc<Limits>>,
+    disk_write_limiter: Option<Arc<RateLimiter>>,
+    download_enabled: bool,
+    upload_enabled: bool,
+    write_cache_budget: Option<Arc<WriteCacheBudget>>,
+    connection_budget: Option<Arc<ConnectionBudget>>,
+    dial_limiter: Option<Arc<DialLimiter>>,
+    dialer: Option<Arc<PeerDialer>>,
+    external_ip: Option<Arc<ExternalIp>>,
+    trusted_peers: Option<Arc<TrustedPeers>>,
+    dht: Option<Dht>,
+    announce_port: Option<u16>,
+    holepunch_port: Option<u16>,
+    disk_retry_policy: DiskRetryPolicy,
+    hash_pool: Option<Arc<HashPool>>,
+    #[cfg(all(target_os = "linux", feature = "io-uring"))]
+    uring: Option<Arc<crate::uring::UringWriter>>,
+    target_download_speed: u64,
+    finished_hook: Option<FinishedHook>,
 }
 
 impl ManagedTorrentBuilder {
@@ -434,13 +1197,44 @@ impl ManagedTorrentBuilder {
             info_hash,
             output_folder: output_folder.as_ref().into(),
             spawner: None,
-            force_tracker_interval: None,
-            peer_connect_timeout: None,
-            peer_read_write_timeout: None,
+            announce_options: Default::default(),
+            tunable: Default::default(),
+            peer_randomize_fingerprint: false,
+            peer_socket_options: Default::default(),
             only_files: None,
+            file_priorities: Default::default(),
             trackers: Default::default(),
             peer_id: None,
             overwrite: false,
+            force_recheck: false,
+            file_allocation: Default::default(),
+            finished_peer_policy: Default::default(),
+            upload_coupling: None,
+            completion_action: Default::default(),
+            peer_limits: Default::default(),
+            seed_limits: Default::default(),
+            resume_store: None,
+            have_pieces: None,
+            label: None,
+            limits: None,
+            disk_write_limiter: None,
+            download_enabled: true,
+            upload_enabled: true,
+            write_cache_budget: None,
+            connection_budget: None,
+            dial_limiter: None,
+            dialer: None,
+            external_ip: None,
+            trusted_peers: None,
+            dht: None,
+            announce_port: None,
+            holepunch_port: None,
+            disk_retry_policy: Default::default(),
+            hash_pool: None,
+            #[cfg(all(target_os = "linux", feature = "io-uring"))]
+            uring: None,
+            target_download_speed: DEFAULT_TARGET_DOWNLOAD_SPEED,
+            finished_hook: None,
         }
     }
 
@@ -449,6 +1243,11 @@ impl ManagedTorrentBuilder {
         self
     }
 
+    pub fn file_priorities(&mut self, file_priorities: HashMap<usize, FilePriority>) -> &mut Self {
+        self.file_priorities = file_priorities;
+        self
+    }
+
     pub fn trackers(&mut self, trackers: Vec<String>) -> &mut Self {
         self.trackers = trackers;
         self
@@ -459,8 +1258,172 @@ impl ManagedTorrentBuilder {
         self
     }
 
-    pub fn force_tracker_interval(&mut self, force_tracker_interval: Duration) -> &mut Self {
-        self.force_tracker_interval = Some(force_tracker_interval);
+    /// Check all the files when starting, even if the saved progress says they are unchanged.
+    pub fn force_recheck(&mut self, force_recheck: bool) -> &mut Self {
+        self.force_recheck = force_recheck;
+        self
+    }
+
+    /// How to allocate disk space for the selected files when starting.
+    pub fn file_allocation(&mut self, file_allocation: FileAllocation) -> &mut Self {
+        self.file_allocation = file_allocation;
+        self
+    }
+
+    pub fn finished_peer_policy(&mut self, policy: FinishedPeerPolicy) -> &mut Self {
+        self.finished_peer_policy = policy;
+        self
+    }
+
+    /// Upload no more than a multiple of what was downloaded until the torrent is finished.
+    pub fn upload_coupling(&mut self, coupling: UploadCoupling) -> &mut Self {
+        self.upload_coupling = Some(coupling);
+        self
+    }
+
+    /// What to do once the torrent finishes downloading.
+    pub fn completion_action(&mut self, action: CompletionAction) -> &mut Self {
+        self.completion_action = action;
+        self
+    }
+
+    pub(crate) fn finished_hook(&mut self, hook: FinishedHook) -> &mut Self {
+        self.finished_hook = Some(hook);
+        self
+    }
+
+    /// Connection limits to start with, see [`ManagedTorrent::set_peer_limits`].
+    pub fn peer_limits(&mut self, limits: PeerLimits) -> &mut Self {
+        self.peer_limits = limits;
+        self
+    }
+
+    /// When to stop seeding, see [`ManagedTorrent::set_seed_limits`].
+    pub fn seed_limits(&mut self, limits: SeedLimits) -> &mut Self {
+        self.seed_limits = limits;
+        self
+    }
+
+    /// Options to start with, see [`ManagedTorrent::set_tunable_options`].
+    pub fn tunable_options(&mut self, options: TunableOptions) -> &mut Self {
+        self.tunable = options;
+        self
+    }
+
+    pub fn announce_options(&mut self, options: AnnounceOptions) -> &mut Self {
+        self.announce_options = options;
+        self
+    }
+
+    pub(crate) fn resume_store(&mut self, resume_store: Arc<ResumeStore>) -> &mut Self {
+        self.resume_store = Some(resume_store);
+        self
+    }
+
+    /// Start from previously saved progress instead of checking the files.
+    pub(crate) fn have_pieces(&mut self, have_pieces: BF) -> &mut Self {
+        self.have_pieces = Some(have_pieces);
+        self
+    }
+
+    /// Start with requesting pieces disabled, e.g. to only seed what's already there.
+    pub fn download_enabled(&mut self, enabled: bool) -> &mut Self {
+        self.download_enabled = enabled;
+        self
+    }
+
+    /// Start with uploading to peers disabled.
+    pub fn upload_enabled(&mut self, enabled: bool) -> &mut Self {
+        self.upload_enabled = enabled;
+        self
+    }
+
+    /// Memory for assembling pieces before writing them, shared with other torrents.
+    pub(crate) fn write_cache_budget(&mut self, budget: Arc<WriteCacheBudget>) -> &mut Self {
+        self.write_cache_budget = Some(budget);
+        self
+    }
+
+    /// Connections to peers, shared with other torrents.
+    pub(crate) fn connection_budget(&mut self, budget: Arc<ConnectionBudget>) -> &mut Self {
+        self.connection_budget = Some(budget);
+        self
+    }
+
+    /// Half-open connections and dial pacing, shared with other torrents.
+    pub(crate) fn dial_limiter(&mut self, limiter: Arc<DialLimiter>) -> &mut Self {
+        self.dial_limiter = Some(limiter);
+        self
+    }
+
+    /// How to connect to peers, shared with other torrents.
+    pub(crate) fn dialer(&mut self, dialer: Arc<PeerDialer>) -> &mut Self {
+        self.dialer = Some(dialer);
+        self
+    }
+
+    pub(crate) fn external_ip(&mut self, external_ip: Arc<ExternalIp>) -> &mut Self {
+        self.external_ip = Some(external_ip);
+        self
+    }
+
+    pub(crate) fn trusted_peers(&mut self, trusted_peers: Arc<TrustedPeers>) -> &mut Self {
+        self.trusted_peers = Some(trusted_peers);
+        self
+    }
+
+    pub(crate) fn dht(&mut self, dht: Dht) -> &mut Self {
+        self.dht = Some(dht);
+        self
+    }
+
+    pub(crate) fn announce_port(&mut self, port: Option<u16>) -> &mut Self {
+        self.announce_port = port;
+        self
+    }
+
+    pub(crate) fn holepunch_port(&mut self, port: Option<u16>) -> &mut Self {
+        self.holepunch_port = port;
+        self
+    }
+
+    pub(crate) fn disk_retry_policy(&mut self, policy: DiskRetryPolicy) -> &mut Self {
+        self.disk_retry_policy = policy;
+        self
+    }
+
+    /// Threads to verify pieces on, shared with other torrents.
+    pub(crate) fn hash_pool(&mut self, pool: Arc<HashPool>) -> &mut Self {
+        self.hash_pool = Some(pool);
+        self
+    }
+
+    /// Write received chunks through io_uring, shared with other torrents.
+    #[cfg(all(target_os = "linux", feature = "io-uring"))]
+    pub(crate) fn uring(&mut self, uring: Arc<crate::uring::UringWriter>) -> &mut Self {
+        self.uring = Some(uring);
+        self
+    }
+
+    pub(crate) fn target_download_speed(&mut self, bytes_per_second: u64) -> &mut Self {
+        self.target_download_speed = bytes_per_second;
+        self
+    }
+
+    pub fn label(&mut self, label: String) -> &mut Self {
+        self.label = Some(label);
+        self
+    }
+
+    /// Rate and connection limits, possibly shared with other torrents.
+    pub(crate) fn limits(&mut self, limits: Arc<Limits>) -> &mut Self {
+        self.limits = Some(limits);
+        self
+    }
+
+    /// Shared by all torrents writing to the same device.
+    pub(crate) fn disk_write_limiter(&mut self, limiter: Arc<RateLimiter>) -> &mut Self {
+        self.disk_write_limiter = Some(limiter);
         self
     }
 
@@ -474,44 +1437,102 @@ impl ManagedTorrentBuilder {
         self
     }
 
-    pub fn peer_connect_timeout(&mut self, timeout: Duration) -> &mut Self {
-        self.peer_connect_timeout = Some(timeout);
+    pub fn peer_randomize_fingerprint(&mut self, value: bool) -> &mut Self {
+        self.peer_randomize_fingerprint = value;
         self
     }
 
-    pub fn peer_read_write_timeout(&mut self, timeout: Duration) -> &mut Self {
-        self.peer_read_write_timeout = Some(timeout);
+    pub fn peer_socket_options(&mut self, options: PeerSocketOptions) -> &mut Self {
+        self.peer_socket_options = options;
         self
     }
 
     pub(crate) fn build(self, span: tracing::Span) -> anyhow::Result<ManagedTorrentHandle> {
+        self.peer_limits.validate()?;
+        self.seed_limits.validate()?;
+        self.tunable.validate()?;
         let lengths = Lengths::from_torrent(&self.info)?;
+        let resume_data = match &self.resume_store {
+            Some(store) => match store.load(self.info_hash) {
+                Ok(data) => data,
+                Err(e) => {
+                    debug!("error loading resume data: {:#}", e);
+                    None
+                }
+            },
+            None => None,
+        };
+        let transfer_totals = resume_data.as_ref().map(|d| d.totals).unwrap_or_default();
+        let tracker_identity = resume_data
+            .and_then(|d| d.trackers)
+            .unwrap_or_else(TrackerIdentity::random);
         let info = Arc::new(ManagedTorrentInfo {
             span,
             info: self.info,
             info_hash: self.info_hash,
-            out_dir: self.output_folder,
+            out_dir: RwLock::new(Arc::new(OutputDir::new(self.output_folder))),
             trackers: self.trackers.into_iter().collect(),
             spawner: self.spawner.unwrap_or_default(),
             peer_id: self.peer_id.unwrap_or_else(generate_peer_id),
             lengths,
             options: ManagedTorrentOptions {
-                force_tracker_interval: self.force_tracker_interval,
-                peer_connect_timeout: self.peer_connect_timeout,
-                peer_read_write_timeout: self.peer_read_write_timeout,
+                announce_options: self.announce_options,
+                peer_randomize_fingerprint: self.peer_randomize_fingerprint,
+                peer_socket_options: self.peer_socket_options,
                 overwrite: self.overwrite,
+                force_recheck: self.force_recheck,
+                file_allocation: self.file_allocation,
+                finished_peer_policy: self.finished_peer_policy,
+                upload_coupling: self.upload_coupling,
+                completion_action: self.completion_action,
+                peer_limits: RwLock::new(self.peer_limits),
+                seed_limits: RwLock::new(self.seed_limits),
+                tunable: RwLock::new(self.tunable),
             },
+            resume_store: self.resume_store,
+            tracker_identity: Mutex::new(tracker_identity),
+            transfer_totals: Mutex::new(transfer_totals),
+            label: self.label,
+            limits: self.limits,
+            download_limiter: RateLimiter::new(self.tunable.download_rate_limit),
+            upload_limiter: RateLimiter::new(self.tunable.upload_rate_limit),
+            disk_write_limiter: self.disk_write_limiter,
+            download_enabled: AtomicBool::new(self.download_enabled),
+            upload_enabled: AtomicBool::new(self.upload_enabled),
+            write_cache_budget: self.write_cache_budget,
+            connection_budget: self.connection_budget,
+            dial_limiter: self.dial_limiter,
+            dialer: self.dialer,
+            external_ip: self.external_ip,
+            trusted_peers: self.trusted_peers,
+            dht: self.dht,
+            announce_port: self.announce_port,
+            holepunch_port: self.holepunch_port,
+            disk_retry_policy: self.disk_retry_policy,
+            hash_pool: self.hash_pool,
+            #[cfg(all(target_os = "linux", feature = "io-uring"))]
+            uring: self.uring,
+            target_download_speed: self.target_download_speed,
+            reannounce_notify: Default::default(),
+            tracker_status: Default::default(),
+            events: Default::default(),
+            finished_hook: self.finished_hook,
         });
         let initializing = Arc::new(TorrentStateInitializing::new(
             info.clone(),
             self.only_files.clone(),
+            self.file_priorities.clone(),
+            self.have_pieces,
         ));
         Ok(Arc::new(ManagedTorrent {
-            only_files: self.only_files,
             locked: RwLock::new(ManagedTorrentLocked {
                 state: ManagedTorrentState::Initializing(initializing),
+                only_files: self.only_files,
+                file_priorities: self.file_priorities,
+                paused_out_of_space_since: None,
             }),
             info,
+            disk_usage: Default::default(),
         }))
     }
 }
@@ -2,7 +2,10 @@ use std::{fs::File, path::PathBuf, sync::Arc};
 
 use parking_lot::Mutex;
 
-use crate::chunk_tracker::ChunkTracker;
+use crate::{
+    chunk_tracker::ChunkTracker, part_file::PartFile, torrent_state::live::peer::RetainedPeer,
+    type_aliases::PeerHandle,
+};
 
 use super::ManagedTorrentInfo;
 
@@ -10,9 +13,12 @@ pub struct TorrentStatePaused {
     pub(crate) info: Arc<ManagedTorrentInfo>,
     pub(crate) files: Vec<Arc<Mutex<File>>>,
     pub(crate) filenames: Vec<PathBuf>,
+    pub(crate) part_file: Arc<PartFile>,
     pub(crate) chunk_tracker: ChunkTracker,
     pub(crate) have_bytes: u64,
     pub(crate) needed_bytes: u64,
+    // The peers known before pausing, connected to again right away on resume.
+    pub(crate) known_peers: Vec<(PeerHandle, RetainedPeer)>,
 }
 
 // impl TorrentStatePaused {
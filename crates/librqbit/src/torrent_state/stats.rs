@@ -1,10 +1,57 @@
 use std::time::Duration;
 
-use serde::Serialize;
+use serde::{Deserialize, Serialize};
 
-use super::{live::stats::snapshot::StatsSnapshot, TorrentStateLive};
+use super::{
+    initializing::TorrentStateInitializing,
+    live::{stats::snapshot::StatsSnapshot, write_cache::WriteCacheStats},
+    TorrentStateLive,
+};
 use size_format::SizeFormatterBinary as SF;
 
+/// What a torrent transferred over its lifetime, across pauses and restarts. Kept in resume data.
+#[derive(Serialize, Deserialize, Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct TransferTotals {
+    pub uploaded_bytes: u64,
+    /// Verified pieces only.
+    pub downloaded_bytes: u64,
+    /// Time spent live with all the selected pieces.
+    pub seeding_secs: u64,
+}
+
+impl TransferTotals {
+    /// Uploaded bytes per downloaded byte. Data that was there before counts as downloaded, so
+    /// that a torrent downloaded elsewhere has a ratio too.
+    pub fn share_ratio(&self, total_bytes: u64) -> f64 {
+        let downloaded = self.downloaded_bytes.max(total_bytes);
+        if downloaded == 0 {
+            return 0.;
+        }
+        self.uploaded_bytes as f64 / downloaded as f64
+    }
+}
+
+/// A piece that some, but not all of the chunks were downloaded for.
+#[derive(Serialize, Debug, Clone, Copy)]
+pub struct PartialPiece {
+    pub piece: u32,
+    pub downloaded_chunks: u32,
+    pub total_chunks: u32,
+}
+
+impl PartialPiece {
+    pub fn fraction(&self) -> f64 {
+        self.downloaded_chunks as f64 / self.total_chunks as f64
+    }
+}
+
+/// Data that is on disk, but isn't counted in progress until the pieces are complete and verified.
+#[derive(Serialize, Default, Debug)]
+pub struct PartialPiecesStats {
+    pub downloaded_bytes: u64,
+    pub pieces: Vec<PartialPiece>,
+}
+
 #[derive(Serialize, Default, Debug)]
 pub struct LiveStats {
     pub snapshot: StatsSnapshot,
@@ -12,6 +59,8 @@ pub struct LiveStats {
     pub download_speed: Speed,
     pub upload_speed: Speed,
     pub time_remaining: Option<DurationWithHumanReadable>,
+    pub partial_pieces: PartialPiecesStats,
+    pub write_cache: WriteCacheStats,
 }
 
 impl std::fmt::Display for LiveStats {
@@ -21,6 +70,16 @@ impl std::fmt::Display for LiveStats {
             write!(f, ", eta: {time_remaining}")?;
         }
         write!(f, ", up speed: {}", self.upload_speed)?;
+        if !self.partial_pieces.pieces.is_empty() {
+            write!(f, ", partial pieces: {}", self.partial_pieces.pieces.len())?;
+        }
+        if let Some(recheck) = self.snapshot.recheck.filter(|r| r.running) {
+            write!(
+                f,
+                ", rechecking: {}/{}",
+                recheck.checked_pieces, recheck.total_pieces
+            )?;
+        }
         Ok(())
     }
 }
@@ -39,6 +98,8 @@ impl From<&TorrentStateLive> for LiveStats {
             time_remaining: down_estimator
                 .time_remaining()
                 .map(DurationWithHumanReadable),
+            partial_pieces: live.partial_pieces_stats(),
+            write_cache: live.write_cache_stats(),
         }
     }
 }
@@ -66,6 +127,65 @@ impl std::fmt::Display for TorrentStatsState {
     }
 }
 
+/// Progress of the initial check of existing files.
+#[derive(Serialize, Debug)]
+pub struct InitializingStats {
+    pub checked_bytes: u64,
+    pub checked_pieces: u32,
+    pub total_pieces: u32,
+    pub check_speed: Speed,
+    pub time_remaining: Option<DurationWithHumanReadable>,
+}
+
+impl From<&TorrentStateInitializing> for InitializingStats {
+    fn from(i: &TorrentStateInitializing) -> Self {
+        let lengths = &i.meta.lengths;
+        let checked_bytes = i.get_checked_bytes();
+        let total_pieces = lengths.total_pieces();
+        let piece_length = lengths.default_piece_length() as u64;
+        let checked_pieces = (checked_bytes.div_ceil(piece_length) as u32).min(total_pieces);
+        let bytes_per_sec = i
+            .check_elapsed()
+            .filter(|e| !e.is_zero())
+            .map(|e| checked_bytes as f64 / e.as_secs_f64());
+        let remaining = lengths.total_length().saturating_sub(checked_bytes);
+        let time_remaining = bytes_per_sec
+            .filter(|s| *s > 0.)
+            .map(|s| DurationWithHumanReadable(Duration::from_secs_f64(remaining as f64 / s)));
+        Self {
+            checked_bytes,
+            checked_pieces,
+            total_pieces,
+            check_speed: (bytes_per_sec.unwrap_or(0.) / 1024. / 1024.).into(),
+            time_remaining,
+        }
+    }
+}
+
+impl std::fmt::Display for InitializingStats {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "checked {}/{} pieces, {}",
+            self.checked_pieces, self.total_pieces, self.check_speed
+        )?;
+        if let Some(time_remaining) = &self.time_remaining {
+            write!(f, ", eta: {time_remaining}")?;
+        }
+        Ok(())
+    }
+}
+
+/// Space taken by the files of the torrent.
+#[derive(Serialize, Default, Debug, Clone, Copy)]
+pub struct DiskUsage {
+    /// The sum of the file lengths.
+    pub logical_bytes: u64,
+    /// What is actually allocated on disk. Less than "logical_bytes" for sparse files that
+    /// aren't fully downloaded. Only known on unix.
+    pub allocated_bytes: Option<u64>,
+}
+
 #[derive(Serialize, Debug)]
 pub struct TorrentStats {
     pub state: TorrentStatsState,
@@ -74,7 +194,15 @@ pub struct TorrentStats {
     pub uploaded_bytes: u64,
     pub total_bytes: u64,
     pub finished: bool,
+    pub initializing: Option<InitializingStats>,
     pub live: Option<LiveStats>,
+    /// Not known while initializing, as the files may not be allocated yet.
+    pub disk_usage: Option<DiskUsage>,
+    pub download_enabled: bool,
+    pub upload_enabled: bool,
+    /// Including the current run.
+    pub transfer_totals: TransferTotals,
+    pub share_ratio: f64,
 }
 
 impl std::fmt::Display for TorrentStats {
@@ -89,6 +217,9 @@ impl std::fmt::Display for TorrentStats {
             self.progress_percent_human_readable(),
             self.progress_bytes_human_readable()
         )?;
+        if let Some(initializing) = &self.initializing {
+            write!(f, " [{initializing}]")?;
+        }
         if let Some(live) = &self.live {
             write!(f, " [{live}]")?;
         }
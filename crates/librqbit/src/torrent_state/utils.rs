@@ -1,4 +1,9 @@
-use std::sync::atomic::{AtomicU32, Ordering};
+use std::{
+    collections::BTreeMap,
+    sync::atomic::{AtomicU32, Ordering},
+};
+
+use serde::Serialize;
 
 pub fn atomic_inc(c: &AtomicU32) -> u32 {
     c.fetch_add(1, Ordering::Relaxed)
@@ -8,11 +13,34 @@ pub fn atomic_dec(c: &AtomicU32) -> u32 {
     c.fetch_sub(1, Ordering::Relaxed)
 }
 
+#[derive(Debug, Default, Serialize)]
+pub struct TimingSnapshot {
+    pub count: u64,
+    pub mean_us: u64,
+    pub max_us: u64,
+}
+
+/// How long we waited to acquire a lock, and then held it, aggregated per lock "reason".
+#[derive(Debug, Default, Serialize)]
+pub struct LockMetricsSnapshot {
+    pub wait: TimingSnapshot,
+    pub hold: TimingSnapshot,
+}
+
+#[derive(Debug, Default, Serialize)]
+pub struct LockMetrics {
+    /// Only collected when built with the "timed_existence" feature.
+    pub enabled: bool,
+    pub locks: BTreeMap<&'static str, LockMetricsSnapshot>,
+}
+
 // Used during debugging to see if some locks take too long.
 #[cfg(not(feature = "timed_existence"))]
 mod timed_existence {
     use std::ops::{Deref, DerefMut};
 
+    use super::LockMetrics;
+
     pub struct TimedExistence<T>(T);
 
     impl<T> TimedExistence<T> {
@@ -39,20 +67,74 @@ mod timed_existence {
     }
 
     #[inline(always)]
-    pub fn timeit<R>(_n: impl std::fmt::Display, f: impl FnOnce() -> R) -> R {
+    pub fn timeit<R>(_n: &'static str, f: impl FnOnce() -> R) -> R {
         f()
     }
+
+    pub fn lock_metrics() -> LockMetrics {
+        LockMetrics::default()
+    }
 }
 
 #[cfg(feature = "timed_existence")]
 mod timed_existence {
     use std::ops::{Deref, DerefMut};
+    use std::sync::atomic::{AtomicU64, Ordering};
+    use std::sync::OnceLock;
     use std::time::{Duration, Instant};
+
+    use dashmap::DashMap;
     use tracing::warn;
 
+    use super::{LockMetrics, LockMetricsSnapshot, TimingSnapshot};
+
     const MAX: Duration = Duration::from_millis(1);
 
-    // Prints if the object exists for too long.
+    #[derive(Default)]
+    struct Timing {
+        count: AtomicU64,
+        total_ns: AtomicU64,
+        max_ns: AtomicU64,
+    }
+
+    impl Timing {
+        fn record(&self, elapsed: Duration) {
+            let ns = elapsed.as_nanos() as u64;
+            self.count.fetch_add(1, Ordering::Relaxed);
+            self.total_ns.fetch_add(ns, Ordering::Relaxed);
+            self.max_ns.fetch_max(ns, Ordering::Relaxed);
+        }
+
+        fn snapshot(&self) -> TimingSnapshot {
+            let count = self.count.load(Ordering::Relaxed);
+            let total_ns = self.total_ns.load(Ordering::Relaxed);
+            TimingSnapshot {
+                count,
+                mean_us: total_ns.checked_div(count).unwrap_or(0) / 1000,
+                max_us: self.max_ns.load(Ordering::Relaxed) / 1000,
+            }
+        }
+    }
+
+    #[derive(Default)]
+    struct Metrics {
+        wait: Timing,
+        hold: Timing,
+    }
+
+    fn metrics() -> &'static DashMap<&'static str, Metrics> {
+        static METRICS: OnceLock<DashMap<&'static str, Metrics>> = OnceLock::new();
+        METRICS.get_or_init(Default::default)
+    }
+
+    fn record(reason: &'static str, f: impl FnOnce(&Metrics)) {
+        if let Some(m) = metrics().get(reason) {
+            return f(&m);
+        }
+        f(&metrics().entry(reason).or_default())
+    }
+
+    // Prints if the object exists for too long, and records how long it existed.
     // This is used to track long-lived locks for debugging.
     pub struct TimedExistence<T> {
         object: T,
@@ -74,6 +156,7 @@ mod timed_existence {
         fn drop(&mut self) {
             let elapsed = self.started.elapsed();
             let reason = self.reason;
+            record(reason, |m| m.hold.record(elapsed));
             if elapsed > MAX {
                 warn!("elapsed on lock {reason:?}: {elapsed:?}")
             }
@@ -94,15 +177,34 @@ mod timed_existence {
         }
     }
 
-    pub fn timeit<R>(name: impl std::fmt::Display, f: impl FnOnce() -> R) -> R {
+    pub fn timeit<R>(name: &'static str, f: impl FnOnce() -> R) -> R {
         let now = Instant::now();
         let r = f();
         let elapsed = now.elapsed();
+        record(name, |m| m.wait.record(elapsed));
         if elapsed > MAX {
             warn!("elapsed on \"{name:}\": {elapsed:?}")
         }
         r
     }
+
+    pub fn lock_metrics() -> LockMetrics {
+        LockMetrics {
+            enabled: true,
+            locks: metrics()
+                .iter()
+                .map(|e| {
+                    (
+                        *e.key(),
+                        LockMetricsSnapshot {
+                            wait: e.value().wait.snapshot(),
+                            hold: e.value().hold.snapshot(),
+                        },
+                    )
+                })
+                .collect(),
+        }
+    }
 }
 
-pub use timed_existence::{timeit, TimedExistence};
+pub use timed_existence::{lock_metrics, timeit, TimedExistence};
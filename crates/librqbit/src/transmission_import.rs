@@ -0,0 +1,210 @@
+use std::{
+    collections::HashMap,
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+
+use anyhow::Context;
+use buffers::ByteString;
+use librqbit_core::torrent_metainfo::{torrent_from_bytes, TorrentMetaV1Owned};
+use serde::Deserialize;
+use tracing::{info, warn};
+
+use crate::{
+    file_selection::FilePriority,
+    session::{AddTorrent, AddTorrentOptions, AddTorrentResponse, Session, TorrentId},
+};
+
+// The subset of Transmission's "resume" file fields that we care about.
+// See libtransmission/resume.cc for the full list.
+#[derive(Deserialize, Default)]
+struct TransmissionResume {
+    destination: Option<ByteString>,
+    // One entry per file, 1 means "do not download".
+    #[serde(default)]
+    dnd: Vec<i64>,
+    // One entry per file: -1 low, 0 normal, 1 high.
+    #[serde(default)]
+    priority: Vec<i64>,
+    #[serde(default)]
+    paused: i64,
+    #[serde(default)]
+    uploaded: u64,
+    #[serde(default)]
+    downloaded: u64,
+}
+
+/// The result of importing one torrent from Transmission.
+#[derive(Debug)]
+pub struct TransmissionImportedTorrent {
+    pub resume_filename: PathBuf,
+    /// The id in the session, None if the import failed.
+    pub id: Option<TorrentId>,
+    /// Transmission's all-time counters. rqbit doesn't keep these across restarts, so they
+    /// are returned for information only.
+    pub uploaded_bytes: u64,
+    pub downloaded_bytes: u64,
+    pub error: Option<anyhow::Error>,
+}
+
+fn to_add_torrent_options(
+    resume: &TransmissionResume,
+    torrent: &TorrentMetaV1Owned,
+) -> anyhow::Result<AddTorrentOptions> {
+    let destination = resume
+        .destination
+        .as_ref()
+        .context("resume file has no destination")?;
+    let mut output_folder = PathBuf::from(
+        std::str::from_utf8(&destination.0).context("destination is not valid UTF-8")?,
+    );
+    // Transmission puts multi-file torrents into a folder named after the torrent,
+    // while rqbit expects the exact folder.
+    if torrent.info.files.is_some() {
+        let name = torrent
+            .info
+            .name
+            .as_ref()
+            .context("multi-file torrent without a name")?;
+        output_folder.push(std::str::from_utf8(&name.0).context("invalid torrent name")?);
+    }
+
+    let total_files = torrent.info.iter_file_lengths()?.count();
+    let only_files = if resume.dnd.iter().any(|dnd| *dnd != 0) {
+        Some(
+            (0..total_files)
+                .filter(|idx| resume.dnd.get(*idx).copied().unwrap_or(0) == 0)
+                .collect(),
+        )
+    } else {
+        None
+    };
+    let file_priorities: HashMap<usize, FilePriority> = resume
+        .priority
+        .iter()
+        .take(total_files)
+        .enumerate()
+        .filter_map(|(idx, p)| match p {
+            p if *p < 0 => Some((idx, FilePriority::Low)),
+            p if *p > 0 => Some((idx, FilePriority::High)),
+            _ => None,
+        })
+        .collect();
+
+    Ok(AddTorrentOptions {
+        paused: resume.paused != 0,
+        output_folder: Some(
+            output_folder
+                .to_str()
+                .context("output folder is not valid UTF-8")?
+                .to_owned(),
+        ),
+        only_files,
+        file_priorities: Some(file_priorities).filter(|p| !p.is_empty()),
+        // The files are already there, and will be checked on add.
+        overwrite: true,
+        ..Default::default()
+    })
+}
+
+// Transmission 4 names the files "<info_hash>.torrent", older versions "<name>.<hash prefix>.torrent".
+// In both cases the resume file has the same stem.
+fn find_torrent_file(config_dir: &Path, resume_filename: &Path) -> anyhow::Result<PathBuf> {
+    let stem = resume_filename
+        .file_stem()
+        .context("resume filename has no stem")?;
+    let mut filename = config_dir.join("torrents").join(stem);
+    filename.set_extension("torrent");
+    if !filename.exists() {
+        anyhow::bail!("{:?} not found", filename);
+    }
+    Ok(filename)
+}
+
+impl Session {
+    /// Import torrents from a Transmission configuration directory, e.g. "~/.config/transmission".
+    ///
+    /// Reads the "resume" and "torrents" sub-directories, and adds every torrent with its
+    /// download folder, file selection, file priorities and paused state. Torrents that are
+    /// already in the session are left as is.
+    pub async fn import_transmission(
+        self: &Arc<Self>,
+        config_dir: &Path,
+    ) -> anyhow::Result<Vec<TransmissionImportedTorrent>> {
+        let resume_dir = config_dir.join("resume");
+        let mut resume_filenames = std::fs::read_dir(&resume_dir)
+            .with_context(|| format!("error reading {:?}", resume_dir))?
+            .map(|e| Ok(e?.path()))
+            .collect::<std::io::Result<Vec<_>>>()?;
+        resume_filenames.retain(|p| p.extension().map(|e| e == "resume").unwrap_or(false));
+        resume_filenames.sort();
+
+        let mut results = Vec::with_capacity(resume_filenames.len());
+        for resume_filename in resume_filenames {
+            let mut result = TransmissionImportedTorrent {
+                resume_filename: resume_filename.clone(),
+                id: None,
+                uploaded_bytes: 0,
+                downloaded_bytes: 0,
+                error: None,
+            };
+            let imported = async {
+                let resume_bytes = std::fs::read(&resume_filename)
+                    .with_context(|| format!("error reading {:?}", resume_filename))?;
+                let resume: TransmissionResume = bencode::from_bytes(&resume_bytes)
+                    .with_context(|| format!("error parsing {:?}", resume_filename))?;
+                result.uploaded_bytes = resume.uploaded;
+                result.downloaded_bytes = resume.downloaded;
+
+                let torrent_filename = find_torrent_file(config_dir, &resume_filename)?;
+                let torrent_bytes = std::fs::read(&torrent_filename)
+                    .with_context(|| format!("error reading {:?}", torrent_filename))?;
+                let torrent = torrent_from_bytes::<ByteString>(&torrent_bytes)
+                    .with_context(|| format!("error parsing {:?}", torrent_filename))?;
+                let opts = to_add_torrent_options(&resume, &torrent)?;
+
+                match self
+                    .add_torrent(AddTorrent::TorrentInfo(Box::new(torrent)), Some(opts))
+                    .await?
+                {
+                    AddTorrentResponse::Added(id, _)
+                    | AddTorrentResponse::AlreadyManaged(id, _) => Ok(id),
+                    AddTorrentResponse::ListOnly(_) => anyhow::bail!("bug: unexpected list only"),
+                }
+            }
+            .await;
+            match imported {
+                Ok(id) => {
+                    info!(?resume_filename, id, "imported torrent from Transmission");
+                    result.id = Some(id);
+                }
+                Err(e) => {
+                    warn!(
+                        ?resume_filename,
+                        "error importing torrent from Transmission: {e:#}"
+                    );
+                    result.error = Some(e);
+                }
+            }
+            results.push(result);
+        }
+        Ok(results)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::TransmissionResume;
+
+    #[test]
+    fn test_parse_transmission_resume() {
+        let buf = b"d11:destination13:/data/torrent3:dndli0ei1ei0ee10:downloadedi100e6:pausedi1e8:priorityli-1ei0ei1ee11:ratio-limitd11:ratio-limit4:2.0010:ratio-modei-1ee8:uploadedi42ee";
+        let resume: TransmissionResume = bencode::from_bytes(buf).unwrap();
+        assert_eq!(resume.destination.unwrap().0, b"/data/torrent");
+        assert_eq!(resume.dnd, vec![0, 1, 0]);
+        assert_eq!(resume.priority, vec![-1, 0, 1]);
+        assert_eq!(resume.paused, 1);
+        assert_eq!(resume.uploaded, 42);
+        assert_eq!(resume.downloaded, 100);
+    }
+}
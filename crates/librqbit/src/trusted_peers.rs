@@ -0,0 +1,36 @@
+// Talking only to peers listed explicitly, e.g. to replicate torrents between one's own machines.
+// Peers from trackers, DHT and other sources are ignored, and they are connected to in every
+// torrent. Incoming connections are accepted from their IPs, as peers connect from other ports.
+
+use std::net::{IpAddr, SocketAddr};
+
+use crate::torrent_state::live::peers::canonical_peer_addr;
+
+pub(crate) struct TrustedPeers {
+    addrs: Vec<SocketAddr>,
+}
+
+impl TrustedPeers {
+    pub fn new(addrs: &[SocketAddr]) -> Self {
+        let mut canonical: Vec<SocketAddr> = Vec::with_capacity(addrs.len());
+        for addr in addrs.iter().copied().map(canonical_peer_addr) {
+            if !canonical.contains(&addr) {
+                canonical.push(addr);
+            }
+        }
+        Self { addrs: canonical }
+    }
+
+    pub fn addrs(&self) -> &[SocketAddr] {
+        &self.addrs
+    }
+
+    pub fn contains(&self, addr: SocketAddr) -> bool {
+        self.addrs.contains(&canonical_peer_addr(addr))
+    }
+
+    pub fn contains_ip(&self, ip: IpAddr) -> bool {
+        let ip = canonical_peer_addr(SocketAddr::new(ip, 0)).ip();
+        self.addrs.iter().any(|a| a.ip() == ip)
+    }
+}
@@ -0,0 +1,358 @@
+// Writing received chunks through io_uring, with the "io-uring" feature on Linux. The writes
+// are queued to one thread per session that keeps them in flight on the ring, so peers
+// delivering chunks don't hold blocking threads while the disk catches up. Uploads don't need
+// it, they already use sendfile(2).
+//
+// The writes of a chunk complete after the peer moved on, so a piece must only be verified
+// once all of its chunks landed, see PieceWrites.
+
+use std::{
+    collections::{HashMap, VecDeque},
+    fs::File,
+    io,
+    ops::Range,
+    os::fd::AsRawFd,
+    sync::{
+        mpsc::{sync_channel, Receiver, SyncSender, TryRecvError},
+        Arc,
+    },
+};
+
+use anyhow::Context;
+use io_uring::{opcode, types, IoUring};
+use librqbit_core::lengths::ValidPieceIndex;
+use parking_lot::{Condvar, Mutex};
+use tracing::{debug, warn};
+
+// Writes in flight on the ring at once.
+const RING_ENTRIES: u32 = 256;
+// How many chunks may wait for the ring, before peers wait to queue theirs.
+const QUEUE_LEN: usize = 1024;
+
+type Callback = Box<dyn FnOnce(io::Result<()>) + Send + 'static>;
+
+// A part of a chunk that is stored in one file.
+pub(crate) struct Write {
+    // A handle of its own, so that the file stays open until the write is done even if the
+    // torrent reopens it meanwhile.
+    pub file: File,
+    pub offset: u64,
+    pub data: Arc<[u8]>,
+    pub range: Range<usize>,
+}
+
+struct Job {
+    writes: Vec<Write>,
+    done: Callback,
+}
+
+pub(crate) struct UringWriter {
+    tx: SyncSender<Job>,
+}
+
+impl UringWriter {
+    // Fails if the kernel doesn't support io_uring, or it's not allowed, e.g. in containers.
+    pub fn new() -> anyhow::Result<Self> {
+        let ring = IoUring::new(RING_ENTRIES).context("error creating io_uring")?;
+        let (tx, rx) = sync_channel(QUEUE_LEN);
+        std::thread::Builder::new()
+            .name("rqbit-uring".to_owned())
+            .spawn(move || worker(ring, rx))
+            .context("error spawning io_uring thread")?;
+        debug!(entries = RING_ENTRIES, "started io_uring thread");
+        Ok(Self { tx })
+    }
+
+    // Queue the writes of a chunk. "done" is called on the io_uring thread once all of them
+    // are written, with the first error if any. Blocks while the queue is full.
+    pub fn submit(
+        &self,
+        writes: Vec<Write>,
+        done: impl FnOnce(io::Result<()>) + Send + 'static,
+    ) -> anyhow::Result<()> {
+        self.tx
+            .send(Job {
+                writes,
+                done: Box::new(done),
+            })
+            .ok()
+            .context("io_uring thread is gone")
+    }
+}
+
+struct Op {
+    write: Write,
+    written: usize,
+    job: u64,
+}
+
+struct PendingJob {
+    remaining: usize,
+    result: io::Result<()>,
+    done: Callback,
+}
+
+fn worker(mut ring: IoUring, rx: Receiver<Job>) {
+    let mut ops: HashMap<u64, Op> = HashMap::new();
+    let mut jobs: HashMap<u64, PendingJob> = HashMap::new();
+    let mut queued: VecDeque<u64> = VecDeque::new();
+    let mut next_id = 0u64;
+    let mut inflight = 0usize;
+    let mut closed = false;
+
+    let mut add = |job: Job,
+                   ops: &mut HashMap<u64, Op>,
+                   jobs: &mut HashMap<u64, PendingJob>,
+                   queued: &mut VecDeque<u64>| {
+        let job_id = next_id;
+        next_id += 1;
+        if job.writes.is_empty() {
+            (job.done)(Ok(()));
+            return;
+        }
+        jobs.insert(
+            job_id,
+            PendingJob {
+                remaining: job.writes.len(),
+                result: Ok(()),
+                done: job.done,
+            },
+        );
+        for write in job.writes {
+            let op_id = next_id;
+            next_id += 1;
+            ops.insert(
+                op_id,
+                Op {
+                    write,
+                    written: 0,
+                    job: job_id,
+                },
+            );
+            queued.push_back(op_id);
+        }
+    };
+
+    loop {
+        // Wait for work only when nothing is in flight.
+        if ops.is_empty() {
+            if closed {
+                return;
+            }
+            match rx.recv() {
+                Ok(job) => add(job, &mut ops, &mut jobs, &mut queued),
+                // The writer was dropped.
+                Err(_) => return,
+            }
+        }
+        while !closed && queued.len() < RING_ENTRIES as usize {
+            match rx.try_recv() {
+                Ok(job) => add(job, &mut ops, &mut jobs, &mut queued),
+                Err(TryRecvError::Empty) => break,
+                Err(TryRecvError::Disconnected) => closed = true,
+            }
+        }
+
+        {
+            let mut sq = ring.submission();
+            while inflight < RING_ENTRIES as usize && !sq.is_full() {
+                let Some(op_id) = queued.pop_front() else {
+                    break;
+                };
+                let op = &ops[&op_id];
+                let buf = &op.write.data[op.write.range.clone()][op.written..];
+                let entry = opcode::Write::new(
+                    types::Fd(op.write.file.as_raw_fd()),
+                    buf.as_ptr(),
+                    buf.len() as u32,
+                )
+                .offset(op.write.offset + op.written as u64)
+                .build()
+                .user_data(op_id);
+                // SAFETY: the buffer and the file are owned by "ops", and stay there until the
+                // write completes.
+                unsafe { sq.push(&entry) }.expect("bug: submission queue is full");
+                inflight += 1;
+            }
+        }
+
+        match ring.submit_and_wait(1) {
+            Ok(_) => {}
+            // The completion queue is full, make room below.
+            Err(e) if e.raw_os_error() == Some(libc::EBUSY) => {}
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            Err(e) => {
+                warn!("error waiting for io_uring completions, failing all writes: {e:#}");
+                for (_, job) in jobs.drain() {
+                    (job.done)(Err(io::Error::new(e.kind(), e.to_string())));
+                }
+                // The kernel may still be writing from the buffers.
+                std::mem::forget(ops);
+                return;
+            }
+        }
+
+        let completed = ring
+            .completion()
+            .map(|cqe| (cqe.user_data(), cqe.result()))
+            .collect::<Vec<_>>();
+        for (op_id, res) in completed {
+            inflight -= 1;
+            let op = ops.get_mut(&op_id).expect("bug: unknown io_uring write");
+            let len = op.write.range.len();
+            let result = match res {
+                res if res < 0 => Err(io::Error::from_raw_os_error(-res)),
+                0 => Err(io::Error::from(io::ErrorKind::WriteZero)),
+                written => {
+                    op.written += written as usize;
+                    if op.written < len {
+                        // A short write, queue the rest.
+                        queued.push_back(op_id);
+                        continue;
+                    }
+                    Ok(())
+                }
+            };
+            let job_id = ops.remove(&op_id).unwrap().job;
+            let job = jobs.get_mut(&job_id).unwrap();
+            if let (Err(e), Ok(())) = (result, &job.result) {
+                job.result = Err(e);
+            }
+            job.remaining -= 1;
+            if job.remaining == 0 {
+                let job = jobs.remove(&job_id).unwrap();
+                (job.done)(job.result);
+            }
+        }
+    }
+}
+
+type Then = Box<dyn FnOnce() -> anyhow::Result<()> + Send + 'static>;
+
+#[derive(Default)]
+struct PendingPiece {
+    writes: usize,
+    then: Option<Then>,
+}
+
+// The chunk writes of a torrent that are still on the ring, per piece.
+#[derive(Default)]
+pub(crate) struct PieceWrites {
+    pieces: Mutex<HashMap<ValidPieceIndex, PendingPiece>>,
+    idle: Condvar,
+}
+
+impl PieceWrites {
+    pub fn started(&self, piece: ValidPieceIndex) {
+        self.pieces.lock().entry(piece).or_default().writes += 1;
+    }
+
+    // Returns what is to run once the piece is written, if this was its last write.
+    #[must_use]
+    pub fn finished(&self, piece: ValidPieceIndex) -> Option<Then> {
+        let mut g = self.pieces.lock();
+        let pending = g.get_mut(&piece)?;
+        pending.writes -= 1;
+        if pending.writes > 0 {
+            return None;
+        }
+        let then = g.remove(&piece).and_then(|p| p.then);
+        if g.is_empty() {
+            self.idle.notify_all();
+        }
+        then
+    }
+
+    // Run "then" after the writes of the piece. Returns it back if they are done already.
+    #[must_use]
+    pub fn when_written(&self, piece: ValidPieceIndex, then: Then) -> Option<Then> {
+        match self.pieces.lock().get_mut(&piece) {
+            Some(pending) => {
+                pending.then = Some(then);
+                None
+            }
+            None => Some(then),
+        }
+    }
+
+    // Block until all writes are done, e.g. before syncing or moving the files.
+    pub fn wait_idle(&self) {
+        let mut g = self.pieces.lock();
+        while !g.is_empty() {
+            self.idle.wait(&mut g);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{io::Read, sync::mpsc::channel};
+
+    use librqbit_core::lengths::Lengths;
+
+    use super::{PieceWrites, UringWriter, Write};
+
+    #[test]
+    fn test_uring_writes() {
+        let writer = match UringWriter::new() {
+            Ok(w) => w,
+            Err(e) => {
+                eprintln!("skipping, io_uring isn't available: {e:#}");
+                return;
+            }
+        };
+        let dir = tempfile::TempDir::with_prefix("rqbit_uring").unwrap();
+        let path = dir.path().join("file");
+        let file = std::fs::File::create(&path).unwrap();
+
+        let data: std::sync::Arc<[u8]> = (0..100_000u32).map(|i| i as u8).collect();
+        let (tx, rx) = channel();
+        for start in (0..data.len()).step_by(16384) {
+            let end = (start + 16384).min(data.len());
+            let tx = tx.clone();
+            writer
+                .submit(
+                    vec![Write {
+                        file: file.try_clone().unwrap(),
+                        offset: start as u64,
+                        data: data.clone(),
+                        range: start..end,
+                    }],
+                    move |res| tx.send(res).unwrap(),
+                )
+                .unwrap();
+        }
+        drop(tx);
+        for res in rx {
+            res.unwrap();
+        }
+
+        let mut written = Vec::new();
+        std::fs::File::open(&path)
+            .unwrap()
+            .read_to_end(&mut written)
+            .unwrap();
+        assert_eq!(&written[..], &data[..]);
+    }
+
+    #[test]
+    fn test_piece_writes_run_after_last_write() {
+        let lengths = Lengths::new(65536, 16384, None).unwrap();
+        let piece = lengths.validate_piece_index(0).unwrap();
+        let writes = PieceWrites::default();
+        let (tx, rx) = channel();
+
+        writes.started(piece);
+        writes.started(piece);
+        assert!(writes
+            .when_written(piece, Box::new(move || Ok(tx.send(())?)))
+            .is_none());
+        assert!(writes.finished(piece).is_none());
+        writes.finished(piece).unwrap()().unwrap();
+        rx.try_recv().unwrap();
+        writes.wait_idle();
+
+        // Nothing pending, it's handed back to run right away.
+        assert!(writes.when_written(piece, Box::new(|| Ok(()))).is_some());
+    }
+}
@@ -71,6 +71,14 @@ export interface LiveTorrentStats {
       secs: number;
     };
   } | null;
+  partial_pieces: {
+    downloaded_bytes: number;
+    pieces: Array<{
+      piece: number;
+      downloaded_chunks: number;
+      total_chunks: number;
+    }>;
+  };
 }
 
 export const STATE_INITIALIZING = "initializing";
@@ -84,9 +92,23 @@ export interface TorrentStats {
   progress_bytes: number;
   finished: boolean;
   total_bytes: number;
+  initializing: InitializingTorrentStats | null;
   live: LiveTorrentStats | null;
 }
 
+export interface InitializingTorrentStats {
+  checked_bytes: number;
+  checked_pieces: number;
+  total_pieces: number;
+  check_speed: Speed;
+  time_remaining: {
+    human_readable: string;
+    duration?: {
+      secs: number;
+    };
+  } | null;
+}
+
 export interface ErrorDetails {
   id?: number;
   method?: string;
@@ -108,11 +130,17 @@ export interface AddTorrentOptions {
   paused?: boolean;
   only_files_regex?: string | null;
   only_files?: number[] | null;
+  skip_files?: number[] | null;
+  only_files_paths?: string[] | null;
+  skip_files_paths?: string[] | null;
+  file_priorities?: { [file_id: number]: "low" | "normal" | "high" } | null;
   overwrite?: boolean;
   list_only?: boolean;
   output_folder?: string | null;
   sub_folder?: string | null;
   peer_opts?: PeerConnectionOptions | null;
+  // "disconnect", "keep", or the max number of seed connections to keep.
+  finished_peer_policy?: string | null;
   force_tracker_interval?: Duration | null;
   initial_peers?: string[] | null; // Assuming SocketAddr is equivalent to a string in TypeScript
   preferred_id?: number | null;
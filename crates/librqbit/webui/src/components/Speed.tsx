@@ -12,8 +12,19 @@ export const Speed: React.FC<{ statsResponse: TorrentStats }> = ({
   switch (statsResponse.state) {
     case STATE_PAUSED:
       return "Paused";
-    case STATE_INITIALIZING:
-      return "Checking files";
+    case STATE_INITIALIZING: {
+      const i = statsResponse.initializing;
+      if (!i || i.total_pieces === 0) {
+        return "Checking files";
+      }
+      const pct = Math.floor((i.checked_pieces / i.total_pieces) * 100);
+      return (
+        <>
+          Checking {pct}% ({i.check_speed.human_readable}
+          {i.time_remaining && <>, {i.time_remaining.human_readable} left</>})
+        </>
+      );
+    }
     case STATE_ERROR:
       return "Error";
   }
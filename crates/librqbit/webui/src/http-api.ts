@@ -82,6 +82,9 @@ export const API: RqbitAPI & { getVersion: () => Promise<string> } = {
     if (opts?.only_files != null) {
       url += `&only_files=${opts.only_files.join(",")}`;
     }
+    if (opts?.skip_files != null && opts.skip_files.length > 0) {
+      url += `&skip_files=${opts.skip_files.join(",")}`;
+    }
     if (opts?.peer_opts?.connect_timeout) {
       url += `&peer_connect_timeout=${opts.peer_opts.connect_timeout}`;
     }
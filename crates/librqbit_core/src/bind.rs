@@ -0,0 +1,371 @@
+// Pinning outgoing traffic to a local address or a network interface, e.g. the one of a VPN in
+// a split tunnel setup, so that it fails rather than going out through another route.
+
+use std::{
+    io,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+    time::Duration,
+};
+
+use socket2::SockRef;
+use tokio::{
+    net::{TcpSocket, TcpStream, UdpSocket},
+    task::JoinSet,
+};
+
+// How long a connection attempt has before the next address is tried too (RFC 8305).
+const HAPPY_EYEBALLS_DELAY: Duration = Duration::from_millis(250);
+
+/// Where outgoing connections are made from. The default doesn't restrict anything.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct OutgoingBind {
+    /// The local address to connect from. Destinations of the other IP version can't be
+    /// reached then.
+    pub address: Option<IpAddr>,
+    /// The network interface to connect through, e.g. "wg0". Only supported on Linux.
+    pub device: Option<String>,
+    /// SO_SNDBUF of TCP connections, in bytes. Set before connecting, as the TCP window scale
+    /// is agreed on then.
+    pub send_buffer_size: Option<usize>,
+    /// SO_RCVBUF of TCP connections, in bytes.
+    pub recv_buffer_size: Option<usize>,
+}
+
+impl OutgoingBind {
+    pub fn is_empty(&self) -> bool {
+        self.address.is_none()
+            && self.device.is_none()
+            && self.send_buffer_size.is_none()
+            && self.recv_buffer_size.is_none()
+    }
+
+    /// The same, but with other TCP buffer sizes.
+    pub fn with_buffer_sizes(&self, send: Option<usize>, recv: Option<usize>) -> Self {
+        Self {
+            send_buffer_size: send,
+            recv_buffer_size: recv,
+            ..self.clone()
+        }
+    }
+
+    /// Set the network interface and buffer sizes on a TCP socket, e.g. a listener, whose
+    /// connections inherit them. Call it before binding or connecting.
+    pub fn configure_tcp(&self, socket: SockRef<'_>) -> io::Result<()> {
+        self.bind_device(&socket)?;
+        if let Some(size) = self.send_buffer_size {
+            socket.set_send_buffer_size(size)?;
+        }
+        if let Some(size) = self.recv_buffer_size {
+            socket.set_recv_buffer_size(size)?;
+        }
+        Ok(())
+    }
+
+    /// Send and receive through the network interface on "socket", if one is set. Call it
+    /// before binding or connecting.
+    #[cfg(any(target_os = "android", target_os = "fuchsia", target_os = "linux"))]
+    pub fn bind_device(&self, socket: &SockRef<'_>) -> io::Result<()> {
+        match &self.device {
+            Some(device) => socket.bind_device(Some(device.as_bytes())),
+            None => Ok(()),
+        }
+    }
+
+    #[cfg(not(any(target_os = "android", target_os = "fuchsia", target_os = "linux")))]
+    pub fn bind_device(&self, _socket: &SockRef<'_>) -> io::Result<()> {
+        match &self.device {
+            Some(_) => Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                "binding to a network interface is only supported on Linux",
+            )),
+            None => Ok(()),
+        }
+    }
+
+    // The local address to reach "target" from, if any.
+    fn local_addr_for(&self, target: IpAddr) -> io::Result<Option<SocketAddr>> {
+        match self.address {
+            Some(address) if address.is_ipv4() != target.is_ipv4() => Err(io::Error::new(
+                io::ErrorKind::AddrNotAvailable,
+                format!("can't reach {target} from the bind address {address}"),
+            )),
+            Some(address) => Ok(Some(SocketAddr::new(address, 0))),
+            None => Ok(None),
+        }
+    }
+
+    pub async fn tcp_connect(&self, target: SocketAddr) -> io::Result<TcpStream> {
+        if self.is_empty() {
+            return TcpStream::connect(target).await;
+        }
+        let socket = match target {
+            SocketAddr::V4(_) => TcpSocket::new_v4()?,
+            SocketAddr::V6(_) => TcpSocket::new_v6()?,
+        };
+        self.configure_tcp(SockRef::from(&socket))?;
+        if let Some(local) = self.local_addr_for(target.ip())? {
+            socket.bind(local)?;
+        }
+        socket.connect(target).await
+    }
+
+    /// Connect to whichever of "targets" answers first, e.g. the IPv6 and IPv4 addresses of a
+    /// host. Happy Eyeballs (RFC 8305): addresses are tried alternating between IPv6 and IPv4,
+    /// each one 250ms after the previous one or as soon as it failed, and the first connection
+    /// wins.
+    pub async fn tcp_connect_any(&self, targets: &[SocketAddr]) -> io::Result<TcpStream> {
+        let targets = interleave_ip_versions(targets);
+        if let [target] = targets.as_slice() {
+            return self.tcp_connect(*target).await;
+        }
+        let mut targets = targets.into_iter();
+        let mut attempts = JoinSet::new();
+        let mut last_error = None;
+        loop {
+            match targets.next() {
+                Some(target) => {
+                    let bind = self.clone();
+                    attempts.spawn(async move { bind.tcp_connect(target).await });
+                }
+                None if attempts.is_empty() => {
+                    return Err(last_error.unwrap_or_else(|| {
+                        io::Error::new(io::ErrorKind::InvalidInput, "no addresses to connect to")
+                    }))
+                }
+                None => {}
+            }
+            let more_targets = !targets.as_slice().is_empty();
+            let next_due = async move {
+                if more_targets {
+                    tokio::time::sleep(HAPPY_EYEBALLS_DELAY).await
+                } else {
+                    std::future::pending().await
+                }
+            };
+            tokio::select! {
+                Some(result) = attempts.join_next() => match result {
+                    Ok(Ok(conn)) => return Ok(conn),
+                    Ok(Err(e)) => last_error = Some(e),
+                    Err(e) => last_error = Some(io::Error::other(e)),
+                },
+                _ = next_due => {}
+            }
+        }
+    }
+
+    /// Connect to "target" from a port we listen on. When the peer connects to that port at the
+    /// same time, the connections cross and become one, which gets through NATs that only let
+    /// in replies to outgoing connections. The listener needs [`allow_connect_from_listen_port`].
+    #[cfg(all(unix, not(any(target_os = "solaris", target_os = "illumos"))))]
+    pub async fn tcp_connect_from_port(
+        &self,
+        target: SocketAddr,
+        port: u16,
+    ) -> io::Result<TcpStream> {
+        let socket = match target {
+            SocketAddr::V4(_) => TcpSocket::new_v4()?,
+            SocketAddr::V6(_) => TcpSocket::new_v6()?,
+        };
+        socket.set_reuseaddr(true)?;
+        socket.set_reuseport(true)?;
+        self.configure_tcp(SockRef::from(&socket))?;
+        let local = match self.local_addr_for(target.ip())? {
+            Some(local) => SocketAddr::new(local.ip(), port),
+            None if target.is_ipv4() => SocketAddr::new(Ipv4Addr::UNSPECIFIED.into(), port),
+            None => SocketAddr::new(Ipv6Addr::UNSPECIFIED.into(), port),
+        };
+        socket.bind(local)?;
+        socket.connect(target).await
+    }
+
+    #[cfg(not(all(unix, not(any(target_os = "solaris", target_os = "illumos")))))]
+    pub async fn tcp_connect_from_port(
+        &self,
+        _target: SocketAddr,
+        _port: u16,
+    ) -> io::Result<TcpStream> {
+        Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            "connecting from the listening port isn't supported on this platform",
+        ))
+    }
+
+    /// A UDP socket to talk to addresses of the IP version of "target".
+    pub async fn udp_socket(&self, target: IpAddr) -> io::Result<UdpSocket> {
+        let local = match self.local_addr_for(target)? {
+            Some(local) => local,
+            None if target.is_ipv4() => SocketAddr::new(Ipv4Addr::UNSPECIFIED.into(), 0),
+            None => SocketAddr::new(Ipv6Addr::UNSPECIFIED.into(), 0),
+        };
+        self.udp_bind(local).await
+    }
+
+    /// A UDP socket on "addr", e.g. to listen on. Only the network interface applies, the
+    /// bind address doesn't.
+    pub async fn udp_bind(&self, addr: SocketAddr) -> io::Result<UdpSocket> {
+        let socket = UdpSocket::bind(addr).await?;
+        self.bind_device(&SockRef::from(&socket))?;
+        Ok(socket)
+    }
+
+    /// The global IPv6 address connections are made from, if there's one, to tell trackers.
+    pub fn public_ipv6(&self) -> Option<Ipv6Addr> {
+        let local = match self.address {
+            Some(IpAddr::V6(address)) => address,
+            Some(IpAddr::V4(_)) => return None,
+            None => {
+                // Connecting a UDP socket doesn't send anything, it only picks the route.
+                let socket = std::net::UdpSocket::bind((Ipv6Addr::UNSPECIFIED, 0)).ok()?;
+                self.bind_device(&SockRef::from(&socket)).ok()?;
+                socket.connect(ROUTE_PROBE).ok()?;
+                match socket.local_addr().ok()?.ip() {
+                    IpAddr::V6(address) => address,
+                    IpAddr::V4(_) => return None,
+                }
+            }
+        };
+        is_global_unicast(&local).then_some(local)
+    }
+}
+
+// IPv6 first, as it's usually the better path when it works.
+fn interleave_ip_versions(targets: &[SocketAddr]) -> Vec<SocketAddr> {
+    let (v6, v4): (Vec<SocketAddr>, Vec<SocketAddr>) = targets.iter().partition(|t| t.is_ipv6());
+    itertools::interleave(v6, v4).collect()
+}
+
+// Any global address works, this is one of Google's public DNS servers.
+const ROUTE_PROBE: (Ipv6Addr, u16) = (
+    Ipv6Addr::new(0x2001, 0x4860, 0x4860, 0, 0, 0, 0, 0x8888),
+    53,
+);
+
+// 2000::/3, Ipv6Addr::is_global() isn't stable.
+fn is_global_unicast(address: &Ipv6Addr) -> bool {
+    address.segments()[0] & 0xe000 == 0x2000
+}
+
+/// Let [`OutgoingBind::tcp_connect_from_port`] connect from the port of "listener". This sets
+/// SO_REUSEPORT on it, so other sockets of the same user that set it too can then bind the port
+/// as well, and even listen on it and take a share of the incoming connections.
+#[cfg(all(unix, not(any(target_os = "solaris", target_os = "illumos"))))]
+pub fn allow_connect_from_listen_port(listener: SockRef<'_>) -> io::Result<()> {
+    listener.set_reuse_port(true)
+}
+
+#[cfg(not(all(unix, not(any(target_os = "solaris", target_os = "illumos")))))]
+pub fn allow_connect_from_listen_port(_listener: SockRef<'_>) -> io::Result<()> {
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        "connecting from the listening port isn't supported on this platform",
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::SocketAddr;
+
+    use super::{interleave_ip_versions, is_global_unicast, OutgoingBind};
+
+    #[tokio::test]
+    async fn test_tcp_connect_from_address() {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let target = listener.local_addr().unwrap();
+        let bind = OutgoingBind {
+            address: Some("127.0.0.1".parse().unwrap()),
+            ..Default::default()
+        };
+        let conn = bind.tcp_connect(target).await.unwrap();
+        assert_eq!(conn.local_addr().unwrap().ip(), bind.address.unwrap());
+
+        let v6: SocketAddr = "[::1]:1".parse().unwrap();
+        assert_eq!(
+            bind.tcp_connect(v6).await.unwrap_err().kind(),
+            std::io::ErrorKind::AddrNotAvailable
+        );
+    }
+
+    #[tokio::test]
+    async fn test_tcp_connect_buffer_sizes() {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let target = listener.local_addr().unwrap();
+        let sizes = |size: usize| async move {
+            let conn = OutgoingBind::default()
+                .with_buffer_sizes(Some(size), Some(size))
+                .tcp_connect(target)
+                .await
+                .unwrap();
+            let socket = socket2::SockRef::from(&conn);
+            (
+                socket.send_buffer_size().unwrap(),
+                socket.recv_buffer_size().unwrap(),
+            )
+        };
+        // The OS may round them up, e.g. Linux doubles them, but keeps them apart. Both are
+        // below the usual caps.
+        let small = sizes(16 << 10).await;
+        let large = sizes(64 << 10).await;
+        assert!(small.0 >= 16 << 10 && small.1 >= 16 << 10);
+        assert!(large.0 > small.0 && large.1 > small.1);
+    }
+
+    #[tokio::test]
+    async fn test_tcp_connect_any() {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let target = listener.local_addr().unwrap();
+        // Nothing listens on port 1, or IPv6 isn't there at all: either way it fails first.
+        let closed: SocketAddr = "[::1]:1".parse().unwrap();
+        let conn = OutgoingBind::default()
+            .tcp_connect_any(&[target, closed])
+            .await
+            .unwrap();
+        assert_eq!(conn.peer_addr().unwrap(), target);
+
+        assert!(OutgoingBind::default()
+            .tcp_connect_any(&[closed])
+            .await
+            .is_err());
+        assert!(OutgoingBind::default().tcp_connect_any(&[]).await.is_err());
+    }
+
+    #[cfg(target_os = "linux")]
+    #[tokio::test]
+    async fn test_tcp_connect_from_port() {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let target = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let target = target.local_addr().unwrap();
+
+        assert!(OutgoingBind::default()
+            .tcp_connect_from_port(target, port)
+            .await
+            .is_err());
+
+        super::allow_connect_from_listen_port(socket2::SockRef::from(&listener)).unwrap();
+        let conn = OutgoingBind::default()
+            .tcp_connect_from_port(target, port)
+            .await
+            .unwrap();
+        assert_eq!(conn.local_addr().unwrap().port(), port);
+    }
+
+    #[test]
+    fn test_interleave_ip_versions() {
+        let addrs: Vec<SocketAddr> = ["1.1.1.1:1", "2.2.2.2:2", "[::1]:3", "[::2]:4"]
+            .iter()
+            .map(|a| a.parse().unwrap())
+            .collect();
+        assert_eq!(
+            interleave_ip_versions(&addrs),
+            vec![addrs[2], addrs[0], addrs[3], addrs[1]]
+        );
+    }
+
+    #[test]
+    fn test_is_global_unicast() {
+        assert!(is_global_unicast(&"2a01:4f8::1".parse().unwrap()));
+        assert!(!is_global_unicast(&"fe80::1".parse().unwrap()));
+        assert!(!is_global_unicast(&"fd00::1".parse().unwrap()));
+        assert!(!is_global_unicast(&"::1".parse().unwrap()));
+    }
+}
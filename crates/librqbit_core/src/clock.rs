@@ -0,0 +1,121 @@
+// Timers across a system suspend. On Linux, the monotonic clock tokio's timers run on stops
+// while suspended, so whatever was due during the suspend happens as late as the suspend was
+// long. Elsewhere, and on NTP steps, everything that was due fires at once on wake up.
+//
+// A background thread notices both: the wall clock running away from the monotonic one, and
+// its own ticks taking much longer than they should. Timers that matter after a wake up
+// (tracker announces, retries, keep-alives) use sleep_or_clock_jump(), and spread what they do
+// next with wake_up_jitter().
+
+use std::{
+    sync::OnceLock,
+    time::{Duration, Instant, SystemTime},
+};
+
+use rand::Rng;
+use tokio::sync::watch;
+use tracing::{info, warn};
+
+const CHECK_INTERVAL: Duration = Duration::from_secs(2);
+// Smaller differences are scheduling noise, or NTP slewing the clock gradually.
+const JUMP_THRESHOLD: Duration = Duration::from_secs(10);
+const WAKE_UP_SPREAD: Duration = Duration::from_secs(30);
+
+// How far the clocks moved apart over a tick that was expected to take "expected", if that's a
+// jump. "wall_elapsed_ms" is negative if the wall clock went back.
+fn detect_jump(
+    expected: Duration,
+    mono_elapsed: Duration,
+    wall_elapsed_ms: i128,
+) -> Option<Duration> {
+    let mono_ms = mono_elapsed.as_millis() as i128;
+    let drift = (wall_elapsed_ms - mono_ms).unsigned_abs();
+    let late = mono_ms - expected.as_millis() as i128;
+    let jump = drift.max(late.max(0) as u128);
+    if jump > JUMP_THRESHOLD.as_millis() {
+        return Some(Duration::from_millis(jump as u64));
+    }
+    None
+}
+
+fn wall_ms(t: SystemTime) -> i128 {
+    match t.duration_since(SystemTime::UNIX_EPOCH) {
+        Ok(d) => d.as_millis() as i128,
+        Err(e) => -(e.duration().as_millis() as i128),
+    }
+}
+
+fn watch_clock(tx: &watch::Sender<u64>) {
+    let mut mono = Instant::now();
+    let mut wall = SystemTime::now();
+    loop {
+        std::thread::sleep(CHECK_INTERVAL);
+        let (new_mono, new_wall) = (Instant::now(), SystemTime::now());
+        if let Some(jump) = detect_jump(
+            CHECK_INTERVAL,
+            new_mono - mono,
+            wall_ms(new_wall) - wall_ms(wall),
+        ) {
+            info!(
+                ?jump,
+                "clock jumped, e.g. after a suspend, rescheduling timers"
+            );
+            tx.send_modify(|jumps| *jumps += 1);
+        }
+        (mono, wall) = (new_mono, new_wall);
+    }
+}
+
+fn jumps_sender() -> &'static watch::Sender<u64> {
+    static SENDER: OnceLock<watch::Sender<u64>> = OnceLock::new();
+    SENDER.get_or_init(|| {
+        if let Err(e) = std::thread::Builder::new()
+            .name("clock_jumps".to_owned())
+            .spawn(|| watch_clock(jumps_sender()))
+        {
+            warn!("error starting the clock jump detector: {:#}", e);
+        }
+        watch::channel(0).0
+    })
+}
+
+/// Changes every time the clock jumps, e.g. on wake up from a suspend, or an NTP step.
+pub fn clock_jumps() -> watch::Receiver<u64> {
+    jumps_sender().subscribe()
+}
+
+/// Like [`tokio::time::sleep`], but returns early if the clock jumps meanwhile. Returns whether
+/// it did.
+pub async fn sleep_or_clock_jump(duration: Duration) -> bool {
+    let mut jumps = clock_jumps();
+    tokio::select! {
+        _ = tokio::time::sleep(duration) => false,
+        _ = jumps.changed() => true,
+    }
+}
+
+/// A random delay, so that everything rescheduled after a clock jump doesn't happen at once.
+pub fn wake_up_jitter() -> Duration {
+    rand::thread_rng().gen_range(Duration::ZERO..WAKE_UP_SPREAD)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::detect_jump;
+
+    #[test]
+    fn test_detect_jump() {
+        let s = Duration::from_secs;
+        // Ticks on time, clocks agree.
+        assert_eq!(detect_jump(s(2), s(2), 2_000), None);
+        assert_eq!(detect_jump(s(2), s(3), 2_500), None);
+        // Suspended for an hour with the monotonic clock stopped.
+        assert_eq!(detect_jump(s(2), s(2), 3_602_000), Some(s(3600)));
+        // Suspended with the monotonic clock running.
+        assert_eq!(detect_jump(s(2), s(3602), 3_602_000), Some(s(3600)));
+        // NTP stepped the clock back.
+        assert_eq!(detect_jump(s(2), s(2), -58_000), Some(s(60)));
+    }
+}
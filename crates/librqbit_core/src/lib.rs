@@ -1,3 +1,5 @@
+pub mod bind;
+pub mod clock;
 pub mod constants;
 pub mod directories;
 pub mod hash_id;
@@ -6,6 +8,7 @@ pub mod magnet;
 pub mod peer_id;
 pub mod spawn_utils;
 pub mod speed_estimator;
+pub mod torrent_editor;
 pub mod torrent_metainfo;
 
 pub use hash_id::Id20;
@@ -1,4 +1,4 @@
-use std::str::FromStr;
+use std::{ops::RangeInclusive, str::FromStr};
 
 use anyhow::Context;
 
@@ -9,6 +9,8 @@ pub struct Magnet {
     id20: Option<Id20>,
     id32: Option<Id32>,
     pub trackers: Vec<String>,
+    // File indices from the "so" parameter (BEP 53).
+    select_only: Vec<RangeInclusive<usize>>,
 }
 
 impl Magnet {
@@ -20,6 +22,24 @@ impl Magnet {
         self.id32
     }
 
+    /// The files to download if the link has a "so" parameter (BEP 53), given the number of
+    /// files in the torrent. Indices past the end are ignored. None if there's no "so", or it
+    /// selects none of the files.
+    pub fn select_only(&self, total_files: usize) -> Option<Vec<usize>> {
+        let mut ids = self
+            .select_only
+            .iter()
+            .flat_map(|r| *r.start()..=(*r.end()).min(total_files.saturating_sub(1)))
+            .filter(|id| *id < total_files)
+            .collect::<Vec<_>>();
+        ids.sort_unstable();
+        ids.dedup();
+        if ids.is_empty() {
+            return None;
+        }
+        Some(ids)
+    }
+
     /// Parse a magnet link.
     pub fn parse(url: &str) -> anyhow::Result<Magnet> {
         let url = url::Url::parse(url).context("magnet link must be a valid URL")?;
@@ -30,6 +50,7 @@ impl Magnet {
         let mut id20: Option<Id20> = None;
         let mut id32: Option<Id32> = None;
         let mut trackers = Vec::<String>::new();
+        let mut select_only = Vec::new();
         for (key, value) in url.query_pairs() {
             match key.as_ref() {
                 "xt" => {
@@ -46,6 +67,7 @@ impl Magnet {
                     }
                 }
                 "tr" => trackers.push(value.into()),
+                "so" => select_only.extend(parse_select_only(&value)?),
                 _ => {}
             }
         }
@@ -54,6 +76,7 @@ impl Magnet {
                 id20,
                 id32,
                 trackers,
+                select_only,
             }),
             false => {
                 anyhow::bail!("did not find infohash")
@@ -62,6 +85,28 @@ impl Magnet {
     }
 }
 
+// "0,2,4-6" into [0..=0, 2..=2, 4..=6].
+fn parse_select_only(value: &str) -> anyhow::Result<Vec<RangeInclusive<usize>>> {
+    let parse = |s: &str| -> anyhow::Result<usize> {
+        s.parse()
+            .with_context(|| format!("invalid file index {s:?} in so"))
+    };
+    value
+        .split(',')
+        .filter(|part| !part.is_empty())
+        .map(|part| match part.split_once('-') {
+            Some((start, end)) => {
+                let (start, end) = (parse(start)?, parse(end)?);
+                if start > end {
+                    anyhow::bail!("invalid file range {part:?} in so");
+                }
+                Ok(start..=end)
+            }
+            None => parse(part).map(|id| id..=id),
+        })
+        .collect()
+}
+
 impl std::fmt::Display for Magnet {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         if let (Some(id20), Some(id32)) = (self.id20, self.id32) {
@@ -71,24 +116,36 @@ impl std::fmt::Display for Magnet {
                 id20.as_string(),
                 id32.as_string(),
                 self.trackers.join("&tr=")
-            )
+            )?;
         } else if let Some(id20) = self.id20 {
             write!(
                 f,
                 "magnet:?xt=urn:btih:{}&tr={}",
                 id20.as_string(),
                 self.trackers.join("&tr=")
-            )
+            )?;
         } else if let Some(id32) = self.id32 {
             write!(
                 f,
                 "magnet:?xt=urn:btmh:1220{}&tr={}",
                 id32.as_string(),
                 self.trackers.join("&tr=")
-            )
+            )?;
         } else {
             panic!("no infohash")
         }
+        if !self.select_only.is_empty() {
+            let so = self
+                .select_only
+                .iter()
+                .map(|r| match r.start() == r.end() {
+                    true => r.start().to_string(),
+                    false => format!("{}-{}", r.start(), r.end()),
+                })
+                .collect::<Vec<_>>();
+            write!(f, "&so={}", so.join(","))?;
+        }
+        Ok(())
     }
 }
 
@@ -113,4 +170,28 @@ mod tests {
         let m = Magnet::parse(magnet).unwrap();
         assert!(m.as_id32() == Some(info_hash));
     }
+
+    #[test]
+    fn test_parse_magnet_select_only() {
+        use super::Magnet;
+        let magnet = Magnet::parse(
+            "magnet:?xt=urn:btih:a621779b5e3d486e127c3efbca9b6f8d135f52e5&so=0,2,4-6,5,100",
+        )
+        .unwrap();
+        assert_eq!(magnet.select_only(8), Some(vec![0, 2, 4, 5, 6]));
+        assert_eq!(magnet.select_only(5), Some(vec![0, 2, 4]));
+        assert_eq!(magnet.select_only(0), None);
+        assert!(magnet.to_string().ends_with("&so=0,2,4-6,5,100"));
+
+        let magnet =
+            Magnet::parse("magnet:?xt=urn:btih:a621779b5e3d486e127c3efbca9b6f8d135f52e5").unwrap();
+        assert_eq!(magnet.select_only(8), None);
+
+        for so in ["a", "3-1", "1-"] {
+            assert!(Magnet::parse(&format!(
+                "magnet:?xt=urn:btih:a621779b5e3d486e127c3efbca9b6f8d135f52e5&so={so}"
+            ))
+            .is_err());
+        }
+    }
 }
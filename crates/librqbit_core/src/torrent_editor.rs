@@ -0,0 +1,279 @@
+// Editing the fields of a .torrent file that are outside of the info dict, e.g. to point it to
+// other trackers. The file is split into its top-level entries without decoding them, so that
+// everything that isn't edited is written back byte for byte, including the info dict (and so
+// the info hash) and fields this crate doesn't know about.
+
+use anyhow::{bail, Context};
+use bencode::bencode_serialize_to_writer;
+use buffers::{ByteBuf, ByteString};
+use serde::Serialize;
+
+use crate::{hash_id::Id20, torrent_metainfo::torrent_from_bytes};
+
+// Deeper nesting than this isn't a torrent, and would overflow the stack.
+const MAX_DEPTH: usize = 64;
+
+/// A .torrent file being edited. See [`TorrentEditor::to_bytes`] for the result.
+pub struct TorrentEditor {
+    // Bencoded values by key, in the order of the file.
+    entries: Entries,
+}
+
+type Entries = Vec<(ByteString, Vec<u8>)>;
+
+impl TorrentEditor {
+    pub fn from_bytes(buf: &[u8]) -> anyhow::Result<Self> {
+        torrent_from_bytes::<ByteBuf>(buf).context("error parsing torrent")?;
+        Ok(Self {
+            entries: split_dict(buf).context("error parsing torrent")?,
+        })
+    }
+
+    /// Replace the trackers, one list of URLs per tier. No tiers, or only empty ones, remove
+    /// them all, leaving an empty "announce" like trackerless torrents created by rqbit.
+    pub fn set_trackers(&mut self, tiers: &[Vec<String>]) -> &mut Self {
+        let tiers: Vec<Vec<ByteString>> = tiers
+            .iter()
+            .filter(|tier| !tier.is_empty())
+            .map(|tier| tier.iter().map(|t| t.as_bytes().into()).collect())
+            .collect();
+        match tiers.first().and_then(|tier| tier.first()) {
+            Some(first) => {
+                self.set(b"announce", first);
+                self.set(b"announce-list", &tiers);
+            }
+            None => {
+                self.set(b"announce", &ByteString::default());
+                self.remove(b"announce-list");
+            }
+        }
+        self
+    }
+
+    /// Replace the web seeds (BEP 19). An empty list removes them.
+    pub fn set_web_seeds(&mut self, urls: &[String]) -> &mut Self {
+        if urls.is_empty() {
+            self.remove(b"url-list");
+        } else {
+            let urls: Vec<ByteString> = urls.iter().map(|u| u.as_bytes().into()).collect();
+            self.set(b"url-list", &urls);
+        }
+        self
+    }
+
+    /// Set the comment, or remove it with None.
+    pub fn set_comment(&mut self, comment: Option<&str>) -> &mut Self {
+        match comment {
+            Some(comment) => self.set(b"comment", &ByteString::from(comment.as_bytes())),
+            None => self.remove(b"comment"),
+        }
+        self
+    }
+
+    /// Set or clear the private flag (BEP 27). It's in the info dict, so unlike the other
+    /// fields, changing it changes the info hash.
+    pub fn set_private(&mut self, private: bool) -> anyhow::Result<&mut Self> {
+        let info = get_entry(&self.entries, b"info").context("no info")?;
+        let mut info = split_dict(info).context("error parsing info")?;
+        let current = get_entry(&info, b"private").is_some_and(|v| v[..] == b"i1e"[..]);
+        if current == private {
+            return Ok(self);
+        }
+        if private {
+            set_entry(&mut info, b"private", b"i1e".to_vec());
+        } else {
+            info.retain(|(k, _)| k[..] != b"private"[..]);
+        }
+        let info = join_dict(&info);
+        set_entry(&mut self.entries, b"info", info);
+        Ok(self)
+    }
+
+    pub fn info_hash(&self) -> anyhow::Result<Id20> {
+        Ok(torrent_from_bytes::<ByteBuf>(&self.to_bytes())?.info_hash)
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        join_dict(&self.entries)
+    }
+
+    fn set<T: Serialize + ?Sized>(&mut self, key: &[u8], value: &T) {
+        let mut buf = Vec::new();
+        // Serializing byte strings and lists of them into a Vec doesn't fail.
+        bencode_serialize_to_writer(value, &mut buf).unwrap();
+        set_entry(&mut self.entries, key, buf);
+    }
+
+    fn remove(&mut self, key: &[u8]) {
+        self.entries.retain(|(k, _)| k[..] != key[..]);
+    }
+}
+
+fn get_entry<'a>(entries: &'a Entries, key: &[u8]) -> Option<&'a Vec<u8>> {
+    entries
+        .iter()
+        .find(|(k, _)| k[..] == key[..])
+        .map(|(_, v)| v)
+}
+
+// Replaces the value in place. New keys go before the first greater key, which keeps sorted
+// dicts sorted.
+fn set_entry(entries: &mut Entries, key: &[u8], value: Vec<u8>) {
+    if let Some((_, v)) = entries.iter_mut().find(|(k, _)| k[..] == key[..]) {
+        *v = value;
+        return;
+    }
+    let pos = entries
+        .iter()
+        .position(|(k, _)| k[..] > key[..])
+        .unwrap_or(entries.len());
+    entries.insert(pos, (key.into(), value));
+}
+
+// The length of the byte string at the start of "buf", and where its contents start.
+fn bytes_header(buf: &[u8]) -> anyhow::Result<(usize, usize)> {
+    let colon = buf
+        .iter()
+        .position(|b| *b == b':')
+        .context("unterminated byte string length")?;
+    let len: usize = std::str::from_utf8(&buf[..colon])?
+        .parse()
+        .context("invalid byte string length")?;
+    if colon + 1 + len > buf.len() {
+        bail!("byte string past the end of data");
+    }
+    Ok((len, colon + 1))
+}
+
+// The length of the bencoded value at the start of "buf".
+fn value_len(buf: &[u8], depth: usize) -> anyhow::Result<usize> {
+    if depth > MAX_DEPTH {
+        bail!("nested too deep");
+    }
+    match buf.first() {
+        Some(b'i') => Ok(buf
+            .iter()
+            .position(|b| *b == b'e')
+            .context("unterminated integer")?
+            + 1),
+        Some(b'l' | b'd') => {
+            let mut pos = 1;
+            loop {
+                match buf.get(pos) {
+                    Some(b'e') => return Ok(pos + 1),
+                    Some(_) => pos += value_len(&buf[pos..], depth + 1)?,
+                    None => bail!("unterminated list or dict"),
+                }
+            }
+        }
+        Some(b'0'..=b'9') => {
+            let (len, start) = bytes_header(buf)?;
+            Ok(start + len)
+        }
+        _ => bail!("invalid bencode"),
+    }
+}
+
+fn split_dict(buf: &[u8]) -> anyhow::Result<Entries> {
+    if buf.first() != Some(&b'd') {
+        bail!("expected a dict");
+    }
+    let mut entries = Entries::new();
+    let mut pos = 1;
+    loop {
+        match buf.get(pos) {
+            Some(b'e') => return Ok(entries),
+            Some(b'0'..=b'9') => {
+                let (len, start) = bytes_header(&buf[pos..])?;
+                let key = ByteString::from(&buf[pos + start..pos + start + len]);
+                pos += start + len;
+                let value_len = value_len(&buf[pos..], 1)?;
+                entries.push((key, buf[pos..pos + value_len].to_vec()));
+                pos += value_len;
+            }
+            Some(_) => bail!("dict keys must be byte strings"),
+            None => bail!("unterminated dict"),
+        }
+    }
+}
+
+// Keys are written in the order they were read in, so unsorted ones stay unsorted.
+fn join_dict(entries: &Entries) -> Vec<u8> {
+    let mut buf = vec![b'd'];
+    for (key, value) in entries {
+        buf.extend_from_slice(key.len().to_string().as_bytes());
+        buf.push(b':');
+        buf.extend_from_slice(key);
+        buf.extend_from_slice(value);
+    }
+    buf.push(b'e');
+    buf
+}
+
+#[cfg(test)]
+mod tests {
+    use super::TorrentEditor;
+    use crate::torrent_metainfo::torrent_from_bytes;
+    use buffers::ByteBuf;
+
+    // Keys out of order and a field unknown to TorrentMetaV1, which must be kept as they are.
+    const TORRENT: &[u8] = b"d8:announce14:http://a/track7:comment3:old9:x-unknowni42e4:infod6:lengthi5e4:name1:a12:piece lengthi16384e6:pieces20:aaaaaaaaaaaaaaaaaaaaee";
+
+    #[test]
+    fn test_edit_keeps_info_hash() {
+        let original = torrent_from_bytes::<ByteBuf>(TORRENT).unwrap();
+        let mut editor = TorrentEditor::from_bytes(TORRENT).unwrap();
+        assert_eq!(editor.to_bytes(), TORRENT);
+        editor
+            .set_trackers(&[
+                vec!["http://b/announce".to_owned()],
+                vec!["udp://c:80".to_owned(), "udp://d:80".to_owned()],
+            ])
+            .set_web_seeds(&["http://e/files/".to_owned()])
+            .set_comment(None);
+        let bytes = editor.to_bytes();
+
+        let edited = torrent_from_bytes::<ByteBuf>(&bytes).unwrap();
+        assert_eq!(edited.info_hash, original.info_hash);
+        assert_eq!(editor.info_hash().unwrap(), original.info_hash);
+        let trackers: Vec<&[u8]> = edited.iter_announce().map(|t| t.as_ref()).collect();
+        assert_eq!(
+            trackers,
+            vec![
+                &b"http://b/announce"[..],
+                &b"udp://c:80"[..],
+                &b"udp://d:80"[..]
+            ]
+        );
+        assert!(edited.comment.is_none());
+        let bytes = String::from_utf8(bytes).unwrap();
+        assert!(bytes.contains("8:url-listl15:http://e/files/e"));
+        assert!(bytes.contains("9:x-unknowni42e4:infod"));
+
+        editor.set_trackers(&[]);
+        let bytes = editor.to_bytes();
+        let edited = torrent_from_bytes::<ByteBuf>(&bytes).unwrap();
+        assert!(edited.announce.is_empty());
+        assert!(edited.announce_list.is_empty());
+    }
+
+    #[test]
+    fn test_edit_private() {
+        let original = torrent_from_bytes::<ByteBuf>(TORRENT).unwrap();
+        let mut editor = TorrentEditor::from_bytes(TORRENT).unwrap();
+
+        editor.set_private(true).unwrap();
+        let private = editor.to_bytes();
+        assert!(String::from_utf8_lossy(&private).contains("7:privatei1e"));
+        assert_ne!(editor.info_hash().unwrap(), original.info_hash);
+
+        editor.set_private(false).unwrap();
+        assert_eq!(editor.info_hash().unwrap(), original.info_hash);
+    }
+
+    #[test]
+    fn test_invalid_torrents() {
+        assert!(TorrentEditor::from_bytes(b"d8:announce1:ae").is_err());
+        assert!(TorrentEditor::from_bytes(&TORRENT[..TORRENT.len() - 1]).is_err());
+    }
+}
@@ -13,6 +13,10 @@ pub type TorrentMetaV1Borrowed<'a> = TorrentMetaV1<ByteBuf<'a>>;
 pub type TorrentMetaV1Owned = TorrentMetaV1<ByteString>;
 
 /// Parse torrent metainfo from bytes.
+///
+/// As [`TorrentMetaV1Borrowed`] nothing is copied out of `buf`. For huge torrents, `buf` can be a
+/// `bencode::MmapFile` (with the "mmap" feature), so that the file isn't read into memory either,
+/// see its safety requirements.
 pub fn torrent_from_bytes<'de, ByteBuf: Deserialize<'de>>(
     buf: &'de [u8],
 ) -> anyhow::Result<TorrentMetaV1<ByteBuf>> {
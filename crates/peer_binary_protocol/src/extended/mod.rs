@@ -4,19 +4,21 @@ use bencode::BencodeValue;
 use clone_to_owned::CloneToOwned;
 use serde::{Deserialize, Serialize};
 
-use self::{handshake::ExtendedHandshake, ut_metadata::UtMetadata};
+use self::{handshake::ExtendedHandshake, ut_holepunch::UtHolepunch, ut_metadata::UtMetadata};
 
 use super::MessageDeserializeError;
 
 pub mod handshake;
+pub mod ut_holepunch;
 pub mod ut_metadata;
 
-use super::MY_EXTENDED_UT_METADATA;
+use super::{MY_EXTENDED_UT_HOLEPUNCH, MY_EXTENDED_UT_METADATA};
 
 #[derive(Debug)]
 pub enum ExtendedMessage<ByteBuf: std::hash::Hash + Eq> {
     Handshake(ExtendedHandshake<ByteBuf>),
     UtMetadata(UtMetadata<ByteBuf>),
+    UtHolepunch(UtHolepunch),
     Dyn(u8, BencodeValue<ByteBuf>),
 }
 
@@ -32,6 +34,7 @@ where
             ExtendedMessage::Handshake(h) => ExtendedMessage::Handshake(h.clone_to_owned()),
             ExtendedMessage::Dyn(u, d) => ExtendedMessage::Dyn(*u, d.clone_to_owned()),
             ExtendedMessage::UtMetadata(m) => ExtendedMessage::UtMetadata(m.clone_to_owned()),
+            ExtendedMessage::UtHolepunch(h) => ExtendedMessage::UtHolepunch(*h),
         }
     }
 }
@@ -40,7 +43,7 @@ impl<'a, ByteBuf: 'a + std::hash::Hash + Eq + Serialize> ExtendedMessage<ByteBuf
     pub fn serialize(
         &self,
         out: &mut Vec<u8>,
-        extended_handshake_ut_metadata: &dyn Fn() -> Option<u8>,
+        peer_extended_msgid: &dyn Fn(&[u8]) -> Option<u8>,
     ) -> anyhow::Result<()>
     where
         ByteBuf: AsRef<[u8]>,
@@ -55,12 +58,18 @@ impl<'a, ByteBuf: 'a + std::hash::Hash + Eq + Serialize> ExtendedMessage<ByteBuf
                 bencode_serialize_to_writer(h, out)?;
             }
             ExtendedMessage::UtMetadata(u) => {
-                let emsg_id = extended_handshake_ut_metadata().ok_or_else(|| {
+                let emsg_id = peer_extended_msgid(b"ut_metadata").ok_or_else(|| {
                     anyhow::anyhow!("need peer's handshake to serialize ut_metadata")
                 })?;
                 out.push(emsg_id);
                 u.serialize(out);
             }
+            ExtendedMessage::UtHolepunch(h) => {
+                let emsg_id = peer_extended_msgid(b"ut_holepunch")
+                    .ok_or_else(|| anyhow::anyhow!("peer doesn't support ut_holepunch"))?;
+                out.push(emsg_id);
+                h.serialize(out);
+            }
         }
         Ok(())
     }
@@ -86,6 +95,9 @@ impl<'a, ByteBuf: 'a + std::hash::Hash + Eq + Serialize> ExtendedMessage<ByteBuf
             MY_EXTENDED_UT_METADATA => {
                 Ok(ExtendedMessage::UtMetadata(UtMetadata::deserialize(buf)?))
             }
+            MY_EXTENDED_UT_HOLEPUNCH => {
+                Ok(ExtendedMessage::UtHolepunch(UtHolepunch::deserialize(buf)?))
+            }
             _ => Ok(ExtendedMessage::Dyn(emsg_id, from_bytes(buf)?)),
         }
     }
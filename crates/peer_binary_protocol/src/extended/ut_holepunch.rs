@@ -0,0 +1,129 @@
+// The holepunch extension (BEP 55). A peer that can't reach another one asks a peer connected
+// to both of them to relay a "rendezvous". The relay then tells both sides to connect to each
+// other at the same time, which gets through NATs that only let in replies to outgoing
+// connections.
+
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+
+use byteorder::{ByteOrder, BE};
+use clone_to_owned::CloneToOwned;
+
+use crate::MessageDeserializeError;
+
+const MSG_TYPE_RENDEZVOUS: u8 = 0;
+const MSG_TYPE_CONNECT: u8 = 1;
+const MSG_TYPE_ERROR: u8 = 2;
+
+const ADDR_TYPE_IPV4: u8 = 0;
+const ADDR_TYPE_IPV6: u8 = 1;
+
+/// Why a relay couldn't pass a rendezvous on.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum UtHolepunchError {
+    NoSuchPeer,
+    NotConnected,
+    NoSupport,
+    NoSelf,
+    Unknown(u32),
+}
+
+impl UtHolepunchError {
+    fn code(&self) -> u32 {
+        match self {
+            UtHolepunchError::NoSuchPeer => 1,
+            UtHolepunchError::NotConnected => 2,
+            UtHolepunchError::NoSupport => 3,
+            UtHolepunchError::NoSelf => 4,
+            UtHolepunchError::Unknown(code) => *code,
+        }
+    }
+
+    fn from_code(code: u32) -> Self {
+        match code {
+            1 => UtHolepunchError::NoSuchPeer,
+            2 => UtHolepunchError::NotConnected,
+            3 => UtHolepunchError::NoSupport,
+            4 => UtHolepunchError::NoSelf,
+            code => UtHolepunchError::Unknown(code),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum UtHolepunch {
+    /// Sent to a relay: please get us connected to this peer.
+    Rendezvous(SocketAddr),
+    /// Sent by a relay to both sides: connect to this peer now.
+    Connect(SocketAddr),
+    /// Sent by a relay that couldn't pass the rendezvous for this peer on.
+    Error(SocketAddr, UtHolepunchError),
+}
+
+impl CloneToOwned for UtHolepunch {
+    type Target = UtHolepunch;
+
+    fn clone_to_owned(&self) -> Self::Target {
+        *self
+    }
+}
+
+impl UtHolepunch {
+    pub fn serialize(&self, buf: &mut Vec<u8>) {
+        let (msg_type, addr, error) = match self {
+            UtHolepunch::Rendezvous(addr) => (MSG_TYPE_RENDEZVOUS, addr, 0),
+            UtHolepunch::Connect(addr) => (MSG_TYPE_CONNECT, addr, 0),
+            UtHolepunch::Error(addr, error) => (MSG_TYPE_ERROR, addr, error.code()),
+        };
+        buf.push(msg_type);
+        match addr.ip() {
+            IpAddr::V4(ip) => {
+                buf.push(ADDR_TYPE_IPV4);
+                buf.extend_from_slice(&ip.octets());
+            }
+            IpAddr::V6(ip) => {
+                buf.push(ADDR_TYPE_IPV6);
+                buf.extend_from_slice(&ip.octets());
+            }
+        }
+        buf.extend_from_slice(&addr.port().to_be_bytes());
+        buf.extend_from_slice(&error.to_be_bytes());
+    }
+
+    pub fn deserialize(buf: &[u8]) -> Result<Self, MessageDeserializeError> {
+        let not_enough_data =
+            || MessageDeserializeError::NotEnoughData(buf.len(), "ut_holepunch message");
+        let (msg_type, addr_type) = match buf {
+            [msg_type, addr_type, ..] => (*msg_type, *addr_type),
+            _ => return Err(not_enough_data()),
+        };
+        let (ip, rest): (IpAddr, &[u8]) = match addr_type {
+            ADDR_TYPE_IPV4 if buf.len() >= 6 => {
+                (Ipv4Addr::from(BE::read_u32(&buf[2..])).into(), &buf[6..])
+            }
+            ADDR_TYPE_IPV6 if buf.len() >= 18 => {
+                (Ipv6Addr::from(BE::read_u128(&buf[2..])).into(), &buf[18..])
+            }
+            ADDR_TYPE_IPV4 | ADDR_TYPE_IPV6 => return Err(not_enough_data()),
+            t => {
+                return Err(MessageDeserializeError::Other(anyhow::anyhow!(
+                    "unknown ut_holepunch address type {t}"
+                )))
+            }
+        };
+        if rest.len() < 6 {
+            return Err(not_enough_data());
+        }
+        let addr = SocketAddr::new(ip, BE::read_u16(rest));
+        match msg_type {
+            MSG_TYPE_RENDEZVOUS => Ok(UtHolepunch::Rendezvous(addr)),
+            MSG_TYPE_CONNECT => Ok(UtHolepunch::Connect(addr)),
+            MSG_TYPE_ERROR => Ok(UtHolepunch::Error(
+                addr,
+                UtHolepunchError::from_code(BE::read_u32(&rest[2..])),
+            )),
+            t => Err(MessageDeserializeError::Other(anyhow::anyhow!(
+                "unknown ut_holepunch message type {t}"
+            ))),
+        }
+    }
+}
@@ -44,6 +44,7 @@ const MSGID_CANCEL: u8 = 8;
 const MSGID_EXTENDED: u8 = 20;
 
 pub const MY_EXTENDED_UT_METADATA: u8 = 3;
+pub const MY_EXTENDED_UT_HOLEPUNCH: u8 = 4;
 
 #[derive(Debug)]
 pub enum MessageDeserializeError {
@@ -243,7 +244,8 @@ where
 {
     pub fn len_prefix_and_msg_id(&self) -> (u32, u8) {
         match self {
-            Message::Request(_) | Message::Cancel(_) => (LEN_PREFIX_REQUEST, MSGID_REQUEST),
+            Message::Request(_) => (LEN_PREFIX_REQUEST, MSGID_REQUEST),
+            Message::Cancel(_) => (LEN_PREFIX_REQUEST, MSGID_CANCEL),
             Message::Bitfield(b) => (1 + b.as_ref().len() as u32, MSGID_BITFIELD),
             Message::Choke => (LEN_PREFIX_CHOKE, MSGID_CHOKE),
             Message::Unchoke => (LEN_PREFIX_UNCHOKE, MSGID_UNCHOKE),
@@ -261,7 +263,7 @@ where
     pub fn serialize(
         &self,
         out: &mut Vec<u8>,
-        extended_handshake_ut_metadata: &dyn Fn() -> Option<u8>,
+        peer_extended_msgid: &dyn Fn(&[u8]) -> Option<u8>,
     ) -> anyhow::Result<usize> {
         let (lp, msg_id) = self.len_prefix_and_msg_id();
 
@@ -311,7 +313,7 @@ where
                 Ok(msg_len)
             }
             Message::Extended(e) => {
-                e.serialize(out, extended_handshake_ut_metadata)?;
+                e.serialize(out, peer_extended_msgid)?;
                 let msg_size = out.len();
                 // no fucking idea why +1, but I tweaked that for it all to match up
                 // with real messages.
@@ -621,10 +623,85 @@ mod tests {
     fn test_extended_serialize() {
         let msg = Message::Extended(ExtendedMessage::Handshake(ExtendedHandshake::new()));
         let mut out = Vec::new();
-        msg.serialize(&mut out, &|| None).unwrap();
+        msg.serialize(&mut out, &|_| None).unwrap();
         dbg!(out);
     }
 
+    #[test]
+    fn test_extended_handshake_fields_roundtrip() {
+        let mut handshake = ExtendedHandshake::new();
+        handshake.v = Some(ByteBuf(b"rqbit 1.0"));
+        handshake.p = Some(6881);
+        handshake.reqq = Some(250);
+        handshake.ipv4 = Some(ByteBuf(&[203, 0, 113, 7]));
+        let mut out = Vec::new();
+        Message::Extended(ExtendedMessage::Handshake(handshake))
+            .serialize(&mut out, &|_| None)
+            .unwrap();
+        match MessageBorrowed::deserialize(&out).unwrap() {
+            (Message::Extended(ExtendedMessage::Handshake(h)), _) => {
+                assert_eq!(h.v.as_ref().map(|v| v.as_ref()), Some(&b"rqbit 1.0"[..]));
+                assert_eq!(h.p, Some(6881));
+                assert_eq!(h.reqq, Some(250));
+                assert_eq!(
+                    h.ipv4.as_ref().map(|v| v.as_ref()),
+                    Some(&[203, 0, 113, 7][..])
+                );
+                assert_eq!(h.ut_metadata(), Some(MY_EXTENDED_UT_METADATA));
+            }
+            (msg, _) => panic!("unexpected {msg:?}"),
+        }
+    }
+
+    #[test]
+    fn test_cancel_serialize() {
+        let request = Request::new(1, 16384, 16384);
+        let mut out = Vec::new();
+        MessageBorrowed::Cancel(request)
+            .serialize(&mut out, &|_| None)
+            .unwrap();
+        assert_eq!(out[4], MSGID_CANCEL);
+        match MessageBorrowed::deserialize(&out).unwrap() {
+            (Message::Cancel(r), 17) => {
+                assert_eq!((r.index, r.begin, r.length), (1, 16384, 16384))
+            }
+            (msg, size) => panic!("unexpected {msg:?} of size {size}"),
+        }
+    }
+
+    #[test]
+    fn test_ut_holepunch_roundtrip() {
+        use crate::extended::ut_holepunch::{UtHolepunch, UtHolepunchError};
+
+        for h in [
+            UtHolepunch::Rendezvous("203.0.113.7:6881".parse().unwrap()),
+            UtHolepunch::Connect("[2001:db8::1]:51413".parse().unwrap()),
+            UtHolepunch::Error(
+                "203.0.113.7:6881".parse().unwrap(),
+                UtHolepunchError::NotConnected,
+            ),
+        ] {
+            let mut out = Vec::new();
+            MessageBorrowed::Extended(ExtendedMessage::UtHolepunch(h))
+                .serialize(&mut out, &|name| {
+                    (name == b"ut_holepunch").then_some(MY_EXTENDED_UT_HOLEPUNCH)
+                })
+                .unwrap();
+            match MessageBorrowed::deserialize(&out).unwrap() {
+                (Message::Extended(ExtendedMessage::UtHolepunch(d)), size) => {
+                    assert_eq!(d, h);
+                    assert_eq!(size, out.len());
+                }
+                (msg, _) => panic!("unexpected {msg:?}"),
+            }
+        }
+
+        let h = UtHolepunch::Rendezvous("203.0.113.7:6881".parse().unwrap());
+        assert!(MessageBorrowed::Extended(ExtendedMessage::UtHolepunch(h))
+            .serialize(&mut Vec::new(), &|_| None)
+            .is_err());
+    }
+
     #[test]
     fn test_deserialize_serialize_extended_is_same() {
         use std::fs::File;
@@ -637,7 +714,7 @@ mod tests {
         let (msg, size) = MessageBorrowed::deserialize(&buf).unwrap();
         assert_eq!(size, buf.len());
         let mut write_buf = Vec::new();
-        msg.serialize(&mut write_buf, &|| None).unwrap();
+        msg.serialize(&mut write_buf, &|_| None).unwrap();
         if buf != write_buf {
             {
                 use std::io::Write;
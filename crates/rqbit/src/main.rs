@@ -1,14 +1,18 @@
-use std::{net::SocketAddr, path::PathBuf, sync::Arc, time::Duration};
+use std::{collections::HashMap, net::SocketAddr, path::PathBuf, sync::Arc, time::Duration};
 
 use anyhow::Context;
 use clap::{Parser, ValueEnum};
 use librqbit::{
     api::ApiAddTorrentResponse,
+    create_torrent,
     http_api::{HttpApi, HttpApiOptions},
     http_api_client, librqbit_spawn,
     tracing_subscriber_config_utils::{init_logging, InitLoggingOptions},
-    AddTorrent, AddTorrentOptions, AddTorrentResponse, Api, ListOnlyResponse,
-    PeerConnectionOptions, Session, SessionOptions, TorrentStatsState,
+    AddTorrent, AddTorrentOptions, AddTorrentResponse, AddressBook, AnnounceOptions, Api,
+    CompletionAction, CreateTorrentOptions, DiskRetryPolicy, FileAllocation, FilePriority,
+    FinishedPeerPolicy, ListOnlyResponse, PeerConnectionOptions, PeerLimits, PeerSocketOptions,
+    ProxyIsolation, SeedLimitAction, SeedLimits, Session, SessionOptions, Sha1Backend,
+    TorrentEditor, TorrentStatsState, TorrentVersion, UploadCoupling,
 };
 use size_format::SizeFormatterBinary as SF;
 use tracing::{error, error_span, info, trace_span, warn};
@@ -47,6 +51,11 @@ struct Opts {
     #[arg(long = "http-api-listen-addr", default_value = "127.0.0.1:3030")]
     http_api_listen_addr: SocketAddr,
 
+    /// Require "user:password" with HTTP basic auth to browse and download finished torrents
+    /// at /mirror/ of the HTTP API.
+    #[arg(long = "http-mirror-auth")]
+    http_mirror_auth: Option<String>,
+
     /// Set this flag if you want to use tokio's single threaded runtime.
     /// It MAY perform better, but the main purpose is easier debugging, as time
     /// profilers work better with this one.
@@ -62,6 +71,19 @@ struct Opts {
     #[arg(long = "disable-dht-persistence")]
     disable_dht_persistence: bool,
 
+    /// An extra DHT bootstrap node, as host:port. Can be given multiple times.
+    #[arg(long = "dht-bootstrap-node")]
+    dht_bootstrap_nodes: Vec<String>,
+
+    /// Don't bootstrap DHT from the default public nodes, e.g. on a private network.
+    #[arg(long = "disable-dht-default-bootstrap")]
+    disable_dht_default_bootstrap: bool,
+
+    /// Announce to DHT with implied_port, so that nodes store the UDP port our DHT traffic
+    /// comes from (as mapped by a NAT) instead of the announce port.
+    #[arg(long = "dht-announce-implied-port")]
+    dht_announce_implied_port: bool,
+
     /// The connect timeout, e.g. 1s, 1.5s, 100ms etc.
     #[arg(long = "peer-connect-timeout", value_parser = parse_duration::parse, default_value="2s")]
     peer_connect_timeout: Duration,
@@ -70,6 +92,32 @@ struct Opts {
     #[arg(long = "peer-read-write-timeout" , value_parser = parse_duration::parse, default_value="10s")]
     peer_read_write_timeout: Duration,
 
+    /// Vary handshake timing, message order and reserved bits of peer connections, to make
+    /// throttling based on deep packet inspection less effective.
+    #[arg(long = "randomize-peer-fingerprint")]
+    randomize_peer_fingerprint: bool,
+
+    /// Turn Nagle's algorithm on peer connections off (true) or on (false). The OS default if
+    /// not set.
+    #[arg(long = "peer-tcp-nodelay")]
+    peer_tcp_nodelay: Option<bool>,
+
+    /// Turn on TCP keepalive on peer connections, probing peers idle this long, e.g. 30s.
+    #[arg(long = "peer-tcp-keepalive", value_parser = parse_duration::parse)]
+    peer_tcp_keepalive: Option<Duration>,
+
+    /// The time between TCP keepalive probes, e.g. 10s.
+    #[arg(long = "peer-tcp-keepalive-interval", value_parser = parse_duration::parse)]
+    peer_tcp_keepalive_interval: Option<Duration>,
+
+    /// The send buffer size of peer sockets, e.g. 4M. The OS default if not set.
+    #[arg(long = "peer-send-buffer", value_parser = parse_size)]
+    peer_send_buffer: Option<u64>,
+
+    /// The receive buffer size of peer sockets, e.g. 4M. The OS default if not set.
+    #[arg(long = "peer-recv-buffer", value_parser = parse_size)]
+    peer_recv_buffer: Option<u64>,
+
     /// How many threads to spawn for the executor.
     #[arg(short = 't', long)]
     worker_threads: Option<usize>,
@@ -90,6 +138,115 @@ struct Opts {
     #[arg(long = "disable-upnp")]
     disable_upnp: bool,
 
+    /// The port to tell trackers and DHT peers to connect to, if your router forwards a
+    /// different external port to the one listened on.
+    #[arg(long = "announce-port")]
+    announce_port: Option<u16>,
+
+    /// Limit the write rate to the disk that PATH is on, e.g. /mnt/hdd=20M. Torrents writing
+    /// to the same disk share the limit. Can be given several times.
+    #[arg(long = "disk-write-limit", value_parser = parse_disk_write_limit)]
+    disk_write_limits: Vec<(PathBuf, u64)>,
+
+    /// Memory for assembling received pieces before writing them to disk in batches, shared
+    /// by all torrents, e.g. 256M. 0 writes every chunk as it arrives. Defaults to 64M.
+    #[arg(long = "write-cache", value_parser = parse_size)]
+    write_cache: Option<u64>,
+
+    /// Start from the DHT nodes and peers in this file, as exported by another instance
+    /// from "GET /address_book" of its HTTP API.
+    #[arg(long = "address-book")]
+    address_book: Option<PathBuf>,
+
+    /// Don't download files matching this pattern in any torrent, e.g. "*.nfo" or "sample".
+    /// A pattern without "/" matches the name of a file or of any of its parent directories.
+    /// Can be repeated. Turned off per torrent with --ignore-default-skip-paths.
+    #[arg(long = "default-skip-path")]
+    default_skip_paths: Vec<String>,
+
+    /// How many times a disk read or write that failed with a possibly transient error
+    /// (e.g. a network filesystem timing out) is retried before the torrent is stopped.
+    #[arg(long = "disk-retries", default_value = "3")]
+    disk_retries: u32,
+
+    /// The wait before the first retry of a failed disk operation, doubled for every next one.
+    #[arg(long = "disk-retry-backoff", value_parser = parse_duration::parse, default_value = "200ms")]
+    disk_retry_backoff: Duration,
+
+    /// How many threads verify downloaded pieces. Defaults to the number of CPUs, up to 4.
+    #[arg(long = "hashing-threads")]
+    hashing_threads: Option<usize>,
+
+    /// Download speed per torrent (e.g. 1M for 1 MiB/s) below which more peers are looked for
+    /// while there's room for more connections.
+    #[arg(long = "target-download-speed", value_parser = parse_size)]
+    target_download_speed: Option<u64>,
+
+    /// The SHA-1 implementation to verify pieces with: system, openssl or rust. Only the
+    /// compiled-in ones are available, and default builds only have "system": build with the
+    /// "sha1-openssl" or "sha1-rust" features to choose. Defaults to the fastest one on this
+    /// machine.
+    #[arg(long = "sha1-backend")]
+    sha1_backend: Option<Sha1Backend>,
+
+    /// The max number of peer connections of all the torrents together. Shared fairly
+    /// between the torrents.
+    #[arg(long = "connection-budget")]
+    connection_budget: Option<usize>,
+
+    /// The max number of outgoing connections being established at the same time, of all
+    /// the torrents together. 0 doesn't limit [default: 32]
+    #[arg(long = "max-half-open")]
+    max_half_open_connections: Option<usize>,
+
+    /// How many outgoing connections can be started per second. 0 doesn't limit [default: 20]
+    #[arg(long = "dials-per-second")]
+    dials_per_second: Option<u32>,
+
+    /// Connect to peers through a SOCKS5 proxy, e.g. a VPN gateway or Tor:
+    /// socks5://[username:password@]host:port. HTTP trackers go through it too, DHT, UDP
+    /// trackers and listening are disabled.
+    #[arg(long = "socks-url")]
+    socks_url: Option<String>,
+
+    /// Use separate proxy credentials per "torrent" or per "peer", so that Tor puts their
+    /// connections on separate circuits.
+    #[arg(long = "socks-isolation", default_value_t = ProxyIsolation::None)]
+    socks_isolation: ProxyIsolation,
+
+    /// Announce to HTTP(S) trackers through this proxy, e.g. http://proxy.lan:3128
+    #[arg(long = "tracker-proxy-url")]
+    tracker_proxy_url: Option<String>,
+
+    /// Don't use HTTP_PROXY, HTTPS_PROXY or ALL_PROXY from the environment for trackers.
+    #[arg(long = "tracker-no-system-proxy")]
+    tracker_ignore_system_proxy: bool,
+
+    /// Connect to peers and trackers from this local IP address.
+    #[arg(long = "bind-address")]
+    bind_address: Option<std::net::IpAddr>,
+
+    /// Connect to peers and trackers, listen and run the DHT only through this network
+    /// interface, e.g. wg0. Linux only. Needs --bind-address too for HTTP trackers.
+    #[arg(long = "bind-device")]
+    bind_device: Option<String>,
+
+    /// Your public IP address, told to trackers. Detected from what trackers and peers report
+    /// if not set.
+    #[arg(long = "external-ip")]
+    external_ip: Option<std::net::IpAddr>,
+
+    /// Only connect to and accept connections from this peer, as ip:port. Can be given
+    /// multiple times. Peers from trackers and DHT are ignored then.
+    #[arg(long = "trusted-peer")]
+    trusted_peers: Vec<SocketAddr>,
+
+    /// Relay holepunch rendezvous between peers behind NATs, and connect to the peers relays
+    /// ask us to from the port listened on. Other programs of the same user can then listen on
+    /// that port too.
+    #[arg(long = "enable-holepunch")]
+    enable_holepunch: bool,
+
     #[command(subcommand)]
     subcommand: SubCommand,
 }
@@ -105,6 +262,11 @@ struct ServerStartOptions {
     disable_persistence: bool,
     #[arg(long = "persistence-filename")]
     persistence_filename: Option<String>,
+
+    /// Import torrents from a Transmission config directory on start, e.g. ~/.config/transmission.
+    /// Torrents already in the session are skipped.
+    #[arg(long = "import-transmission")]
+    import_transmission: Option<PathBuf>,
 }
 
 #[derive(Parser)]
@@ -140,6 +302,35 @@ struct DownloadOpts {
     #[arg(short = 'r', long = "filename-re")]
     only_files_matching_regex: Option<String>,
 
+    /// File ids not to download. Can be repeated. Run with --list to see the file ids.
+    #[arg(long = "skip-file")]
+    skip_files: Vec<usize>,
+
+    /// Only download files under this path within the torrent. Can be repeated.
+    /// Either a directory/file path, or a glob, e.g. "Season 1" or "**/*.mkv".
+    #[arg(long = "only-path")]
+    only_files_paths: Vec<String>,
+
+    /// Don't download files under this path within the torrent. Can be repeated.
+    /// Same syntax as --only-path.
+    #[arg(long = "skip-path")]
+    skip_files_paths: Vec<String>,
+
+    /// Download the files matching the server's --default-skip-path patterns too.
+    #[arg(long = "ignore-default-skip-paths")]
+    ignore_default_skip_paths: bool,
+
+    /// Download priority of a file, as FILE_ID=PRIORITY, where PRIORITY is one of
+    /// low, normal, high. Can be repeated.
+    #[arg(long = "file-priority", value_parser = parse_file_priority)]
+    file_priorities: Vec<(usize, FilePriority)>,
+
+    /// Download priority of the files under a path within the torrent, as PATH=PRIORITY, e.g.
+    /// "Season 1=high". Same path syntax as --only-path. Can be repeated, later ones override
+    /// earlier ones, and --file-priority overrides both.
+    #[arg(long = "path-priority", value_parser = parse_path_priority)]
+    file_priorities_paths: Vec<(String, FilePriority)>,
+
     /// Only list the torrent metadata contents, don't do anything else.
     #[arg(short, long)]
     list: bool,
@@ -148,6 +339,17 @@ struct DownloadOpts {
     #[arg(long)]
     overwrite: bool,
 
+    /// Check all pieces of existing files, even if they didn't change since the torrent
+    /// last ran
+    #[arg(long)]
+    force_recheck: bool,
+
+    /// How to allocate disk space for the selected files. "sparse" (the default) only sets
+    /// the file lengths, so that space is used as pieces are downloaded. "preallocate"
+    /// allocates it all before downloading, failing right away if the disk is too small.
+    #[arg(long, default_value_t = FileAllocation::Sparse)]
+    file_allocation: FileAllocation,
+
     /// Exit the program once the torrents complete download.
     #[arg(short = 'e', long)]
     exit_on_finish: bool,
@@ -155,10 +357,113 @@ struct DownloadOpts {
     #[arg(long = "disable-trackers")]
     disable_trackers: bool,
 
+    /// How many peers to ask trackers for. They usually give 50.
+    #[arg(long = "tracker-numwant")]
+    tracker_numwant: Option<u32>,
+
+    /// Don't announce to trackers more often than this, e.g. 30m.
+    #[arg(long = "min-announce-interval", value_parser = parse_duration::parse)]
+    min_announce_interval: Option<Duration>,
+
+    /// Announce to trackers at least this often, e.g. 10m.
+    #[arg(long = "max-announce-interval", value_parser = parse_duration::parse)]
+    max_announce_interval: Option<Duration>,
+
+    /// Ask HTTP trackers for the non-compact list of peers, for old trackers that need it.
+    #[arg(long = "tracker-disable-compact")]
+    tracker_disable_compact: bool,
+
+    /// What to do with connections to seeds once the download finishes:
+    /// "disconnect" (default), "keep", or the max number of seed connections to keep.
+    #[arg(long = "finished-peer-policy")]
+    finished_peer_policy: Option<FinishedPeerPolicy>,
+
+    /// Upload no more than RATIO times what was downloaded until the download finishes,
+    /// e.g. "0.5". Append ":peer" to count it for each peer separately (tit-for-tat).
+    #[arg(long = "upload-coupling", value_name = "RATIO[:peer]")]
+    upload_coupling: Option<UploadCoupling>,
+
+    /// What to do once a torrent finishes downloading: "seed" (default), "pause" or "forget".
+    #[arg(long = "completion-action", default_value_t = CompletionAction::Seed)]
+    completion_action: CompletionAction,
+
+    /// The max number of live and connecting peers of each torrent [default: 128]
+    #[arg(long = "max-connections")]
+    max_connections: Option<usize>,
+
+    /// The max number of connected peers that have the full torrent.
+    #[arg(long = "max-seeds")]
+    max_seeds: Option<usize>,
+
+    /// The max number of outgoing connections being established at the same time.
+    #[arg(long = "max-pending-dials")]
+    max_pending_dials: Option<usize>,
+
+    /// Stop seeding once the torrent uploaded this many times what it downloaded, e.g. "2".
+    #[arg(long = "seed-ratio-limit")]
+    seed_ratio_limit: Option<f64>,
+
+    /// Stop seeding once the torrent seeded this long in total, e.g. 48h.
+    #[arg(long = "seed-time-limit", value_parser = parse_duration::parse)]
+    seed_time_limit: Option<Duration>,
+
+    /// What to do once a seed limit is reached: "pause" (default) or "forget".
+    #[arg(long = "seed-limit-action", default_value_t = SeedLimitAction::Pause)]
+    seed_limit_action: SeedLimitAction,
+
+    /// Limit the download speed of each torrent, e.g. 1M for 1 MiB/s, on top of the limit of
+    /// its label.
+    #[arg(long = "download-rate-limit", value_parser = parse_size)]
+    download_rate_limit: Option<u64>,
+
+    /// Limit the upload speed of each torrent, e.g. 512K, on top of the limit of its label.
+    #[arg(long = "upload-rate-limit", value_parser = parse_size)]
+    upload_rate_limit: Option<u64>,
+
+    /// Add the torrents with this label. They get the label's policy, if the server has one.
+    #[arg(long)]
+    label: Option<String>,
+
     #[arg(long = "initial-peers")]
     initial_peers: Option<InitialPeers>,
 }
 
+// Bytes with an optional K, M or G suffix (powers of 1024).
+fn parse_size(s: &str) -> anyhow::Result<u64> {
+    let (digits, multiplier) = match s.char_indices().last() {
+        Some((i, 'K' | 'k')) => (&s[..i], 1024),
+        Some((i, 'M' | 'm')) => (&s[..i], 1024 * 1024),
+        Some((i, 'G' | 'g')) => (&s[..i], 1024 * 1024 * 1024),
+        _ => (s, 1),
+    };
+    let size: u64 = digits
+        .parse()
+        .with_context(|| format!("invalid size {s:?}"))?;
+    Ok(size * multiplier)
+}
+
+// Bytes per second.
+fn parse_disk_write_limit(s: &str) -> anyhow::Result<(PathBuf, u64)> {
+    let (path, rate) = s
+        .rsplit_once('=')
+        .context("expected PATH=RATE, e.g. /mnt/hdd=20M")?;
+    Ok((PathBuf::from(path), parse_size(rate)?))
+}
+
+fn parse_file_priority(s: &str) -> anyhow::Result<(usize, FilePriority)> {
+    let (id, priority) = s
+        .split_once('=')
+        .context("expected FILE_ID=PRIORITY, e.g. 0=high")?;
+    Ok((id.parse().context("invalid file id")?, priority.parse()?))
+}
+
+fn parse_path_priority(s: &str) -> anyhow::Result<(String, FilePriority)> {
+    let (path, priority) = s
+        .rsplit_once('=')
+        .context("expected PATH=PRIORITY, e.g. \"Season 1=high\"")?;
+    Ok((path.to_owned(), priority.parse()?))
+}
+
 #[derive(Clone)]
 struct InitialPeers(Vec<SocketAddr>);
 
@@ -176,9 +481,100 @@ impl From<&str> for InitialPeers {
 // download [--connect-to-existing] --output-folder(required) [file1] [file2]
 
 #[derive(Parser)]
+#[allow(clippy::large_enum_variant)]
 enum SubCommand {
     Server(ServerOpts),
     Download(DownloadOpts),
+    /// Share a file or a directory with another machine on the local network.
+    Send(SendOpts),
+    /// Download what another machine shares with "rqbit send".
+    Receive(ReceiveOpts),
+    /// Create a .torrent file from a file or a directory.
+    Create(CreateOpts),
+    /// Change the trackers, web seeds, comment or private flag of a .torrent file.
+    Edit(EditOpts),
+}
+
+#[derive(Parser)]
+struct SendOpts {
+    /// The file or directory to share.
+    path: PathBuf,
+}
+
+#[derive(Parser)]
+struct ReceiveOpts {
+    /// The pairing code printed by "rqbit send".
+    code: String,
+
+    /// The output folder to write to. Defaults to the current directory.
+    #[arg(short = 'o', long, default_value = ".")]
+    output_folder: String,
+
+    /// Set if you are ok to write on top of existing files
+    #[arg(long)]
+    overwrite: bool,
+}
+
+#[derive(Parser)]
+struct CreateOpts {
+    /// The file or directory to create the torrent from.
+    path: PathBuf,
+
+    /// Where to write the .torrent file.
+    #[arg(short = 'o', long)]
+    output: PathBuf,
+
+    /// The name of the torrent. Defaults to the name of the file or directory.
+    #[arg(long)]
+    name: Option<String>,
+
+    /// The piece length, e.g. 256K. Chosen from the size of the content if not set.
+    #[arg(long, value_parser = parse_size)]
+    piece_length: Option<u64>,
+
+    /// Also add BitTorrent v2 hashes, creating a hybrid v1/v2 torrent.
+    #[arg(long)]
+    v2: bool,
+
+    /// Create a torrent with only BitTorrent v2 hashes. Such torrents can't be downloaded with
+    /// rqbit or other v1-only clients.
+    #[arg(long, conflicts_with = "v2")]
+    v2_only: bool,
+}
+
+#[derive(Parser)]
+struct EditOpts {
+    /// The .torrent file to edit.
+    input: PathBuf,
+
+    /// Where to write the edited .torrent file. Can be the input itself.
+    #[arg(short = 'o', long)]
+    output: PathBuf,
+
+    /// Replace the trackers. Each occurrence is a tier, with its trackers separated by commas.
+    #[arg(long = "tracker")]
+    trackers: Vec<String>,
+
+    /// Remove all the trackers.
+    #[arg(long, conflicts_with = "trackers")]
+    clear_trackers: bool,
+
+    /// Replace the web seeds (BEP 19).
+    #[arg(long = "web-seed")]
+    web_seeds: Vec<String>,
+
+    /// Remove all the web seeds.
+    #[arg(long, conflicts_with = "web_seeds")]
+    clear_web_seeds: bool,
+
+    /// Set the comment. An empty one removes it.
+    #[arg(long)]
+    comment: Option<String>,
+
+    /// Set or clear the private flag. This changes the info hash, so the result is a
+    /// different torrent.
+    #[arg(long)]
+    private: Option<bool>,
 }
 
 fn _start_deadlock_detector_thread() {
@@ -233,6 +629,25 @@ fn main() -> anyhow::Result<()> {
     rt.block_on(async_main(opts))
 }
 
+// Wait for Ctrl-C or SIGTERM.
+async fn shutdown_signal() -> anyhow::Result<()> {
+    #[cfg(unix)]
+    {
+        use tokio::signal::unix::{signal, SignalKind};
+        let mut sigterm = signal(SignalKind::terminate()).context("error handling SIGTERM")?;
+        tokio::select! {
+            r = tokio::signal::ctrl_c() => r.context("error handling Ctrl-C"),
+            _ = sigterm.recv() => Ok(()),
+        }
+    }
+    #[cfg(not(unix))]
+    {
+        tokio::signal::ctrl_c()
+            .await
+            .context("error handling Ctrl-C")
+    }
+}
+
 async fn async_main(opts: Opts) -> anyhow::Result<()> {
     let log_config = init_logging(InitLoggingOptions {
         default_rust_log_value: Some(match opts.log_level.unwrap_or(LogLevel::Info) {
@@ -255,6 +670,9 @@ async fn async_main(opts: Opts) -> anyhow::Result<()> {
         disable_dht: opts.disable_dht,
         disable_dht_persistence: opts.disable_dht_persistence,
         dht_config: None,
+        dht_bootstrap_nodes: opts.dht_bootstrap_nodes.clone(),
+        disable_dht_default_bootstrap: opts.disable_dht_default_bootstrap,
+        dht_announce_implied_port: opts.dht_announce_implied_port,
         // This will be overriden by "server start" below if needed.
         persistence: false,
         persistence_filename: None,
@@ -262,6 +680,14 @@ async fn async_main(opts: Opts) -> anyhow::Result<()> {
         peer_opts: Some(PeerConnectionOptions {
             connect_timeout: Some(opts.peer_connect_timeout),
             read_write_timeout: Some(opts.peer_read_write_timeout),
+            randomize_fingerprint: opts.randomize_peer_fingerprint,
+            socket: PeerSocketOptions {
+                nodelay: opts.peer_tcp_nodelay,
+                keepalive_time: opts.peer_tcp_keepalive,
+                keepalive_interval: opts.peer_tcp_keepalive_interval,
+                send_buffer_size: opts.peer_send_buffer.map(|b| b as usize),
+                recv_buffer_size: opts.peer_recv_buffer.map(|b| b as usize),
+            },
             ..Default::default()
         }),
         listen_port_range: if !opts.disable_tcp_listen {
@@ -270,6 +696,39 @@ async fn async_main(opts: Opts) -> anyhow::Result<()> {
             None
         },
         enable_upnp_port_forwarding: !opts.disable_upnp,
+        announce_port: opts.announce_port,
+        disk_write_limits: opts.disk_write_limits.iter().cloned().collect(),
+        write_cache_bytes: opts.write_cache,
+        address_book: opts
+            .address_book
+            .as_deref()
+            .map(AddressBook::from_json_file)
+            .transpose()?,
+        default_skip_paths: opts.default_skip_paths.clone(),
+        disk_retry_policy: Some(DiskRetryPolicy {
+            retries: opts.disk_retries,
+            initial_backoff: opts.disk_retry_backoff,
+            ..Default::default()
+        }),
+        hashing_threads: opts.hashing_threads,
+        target_download_speed: opts.target_download_speed,
+        sha1_backend: opts.sha1_backend,
+        connection_budget: opts.connection_budget,
+        max_half_open_connections: opts.max_half_open_connections,
+        dials_per_second: opts.dials_per_second,
+        socks_proxy_url: opts.socks_url.clone(),
+        socks_proxy_isolation: opts.socks_isolation,
+        tracker_proxy_url: opts.tracker_proxy_url.clone(),
+        tracker_ignore_system_proxy: opts.tracker_ignore_system_proxy,
+        bind_address: opts.bind_address,
+        bind_device: opts.bind_device.clone(),
+        external_ip: opts.external_ip,
+        trusted_peers: if opts.trusted_peers.is_empty() {
+            None
+        } else {
+            Some(opts.trusted_peers.clone())
+        },
+        enable_holepunch: opts.enable_holepunch,
     };
 
     let stats_printer = |session: Arc<Session>| async move {
@@ -334,22 +793,46 @@ async fn async_main(opts: Opts) -> anyhow::Result<()> {
                     Session::new_with_opts(PathBuf::from(&start_opts.output_folder), sopts)
                         .await
                         .context("error initializing rqbit session")?;
+                if let Some(dir) = &start_opts.import_transmission {
+                    let imported = session
+                        .import_transmission(dir)
+                        .await
+                        .context("error importing from Transmission")?;
+                    let failed = imported.iter().filter(|t| t.error.is_some()).count();
+                    info!(
+                        "imported {} torrents from Transmission, {} failed",
+                        imported.len() - failed,
+                        failed
+                    );
+                }
                 librqbit_spawn(
                     "stats_printer",
                     trace_span!("stats_printer"),
                     stats_printer(session.clone()),
                 );
                 let api = Api::new(
-                    session,
+                    session.clone(),
                     Some(log_config.rust_log_reload_tx),
                     Some(log_config.line_broadcast),
                 );
-                let http_api = HttpApi::new(api, Some(HttpApiOptions { read_only: false }));
+                let http_api = HttpApi::new(
+                    api,
+                    Some(HttpApiOptions {
+                        read_only: false,
+                        mirror_basic_auth: opts.http_mirror_auth.clone(),
+                    }),
+                );
                 let http_api_listen_addr = opts.http_api_listen_addr;
-                http_api
-                    .make_http_api_and_run(http_api_listen_addr)
-                    .await
-                    .context("error running HTTP API")
+                tokio::select! {
+                    r = http_api.make_http_api_and_run(http_api_listen_addr) => {
+                        r.context("error running HTTP API")
+                    }
+                    r = shutdown_signal() => {
+                        r?;
+                        info!("shutting down, saving progress");
+                        session.shutdown().await.context("error shutting down")
+                    }
+                }
             }
         },
         SubCommand::Download(download_opts) => {
@@ -360,13 +843,47 @@ async fn async_main(opts: Opts) -> anyhow::Result<()> {
             let client = http_api_client::HttpApiClient::new(&http_api_url)?;
             let torrent_opts = AddTorrentOptions {
                 only_files_regex: download_opts.only_files_matching_regex.clone(),
+                skip_files: Some(download_opts.skip_files.clone()).filter(|s| !s.is_empty()),
+                only_files_paths: Some(download_opts.only_files_paths.clone())
+                    .filter(|s| !s.is_empty()),
+                skip_files_paths: Some(download_opts.skip_files_paths.clone())
+                    .filter(|s| !s.is_empty()),
+                ignore_default_skip_paths: download_opts.ignore_default_skip_paths,
+                file_priorities: Some(download_opts.file_priorities.iter().copied().collect())
+                    .filter(|p: &HashMap<_, _>| !p.is_empty()),
+                file_priorities_paths: Some(download_opts.file_priorities_paths.clone())
+                    .filter(|p| !p.is_empty()),
+                finished_peer_policy: download_opts.finished_peer_policy,
+                upload_coupling: download_opts.upload_coupling,
+                completion_action: download_opts.completion_action,
+                peer_limits: PeerLimits {
+                    max_connections: download_opts.max_connections,
+                    max_seeds: download_opts.max_seeds,
+                    max_pending_dials: download_opts.max_pending_dials,
+                },
+                seed_limits: SeedLimits {
+                    ratio: download_opts.seed_ratio_limit,
+                    time: download_opts.seed_time_limit,
+                    action: download_opts.seed_limit_action,
+                },
+                download_rate_limit: download_opts.download_rate_limit,
+                upload_rate_limit: download_opts.upload_rate_limit,
                 overwrite: download_opts.overwrite,
+                force_recheck: download_opts.force_recheck,
+                file_allocation: download_opts.file_allocation,
                 list_only: download_opts.list,
                 force_tracker_interval: opts.force_tracker_interval,
+                announce_options: AnnounceOptions {
+                    numwant: download_opts.tracker_numwant,
+                    min_interval: download_opts.min_announce_interval,
+                    max_interval: download_opts.max_announce_interval,
+                    disable_compact: download_opts.tracker_disable_compact,
+                },
                 output_folder: download_opts.output_folder.clone(),
                 sub_folder: download_opts.sub_folder.clone(),
                 initial_peers: download_opts.initial_peers.clone().map(|p| p.0),
                 disable_trackers: download_opts.disable_trackers,
+                label: download_opts.label.clone(),
                 ..Default::default()
             };
             let connect_to_existing = match client.validate_rqbit_server().await {
@@ -429,7 +946,13 @@ async fn async_main(opts: Opts) -> anyhow::Result<()> {
                     Some(log_config.rust_log_reload_tx),
                     Some(log_config.line_broadcast),
                 );
-                let http_api = HttpApi::new(api, Some(HttpApiOptions { read_only: true }));
+                let http_api = HttpApi::new(
+                    api,
+                    Some(HttpApiOptions {
+                        read_only: true,
+                        mirror_basic_auth: opts.http_mirror_auth.clone(),
+                    }),
+                );
                 let http_api_listen_addr = opts.http_api_listen_addr;
                 librqbit_spawn(
                     "http_api",
@@ -441,21 +964,27 @@ async fn async_main(opts: Opts) -> anyhow::Result<()> {
 
                 let mut handles = Vec::new();
 
-                for path in &download_opts.torrent_path {
-                    let handle = match session
-                        .add_torrent(
+                let adds = download_opts
+                    .torrent_path
+                    .iter()
+                    .map(|path| {
+                        Ok((
                             AddTorrent::from_cli_argument(path)?,
                             Some(torrent_opts.clone()),
-                        )
-                        .await
-                    {
+                        ))
+                    })
+                    .collect::<anyhow::Result<Vec<_>>>()?;
+                let results = session.add_torrents(adds).await;
+
+                for (path, result) in download_opts.torrent_path.iter().zip(results) {
+                    let handle = match result {
                         Ok(v) => match v {
                             AddTorrentResponse::AlreadyManaged(id, handle) => {
                                 info!(
                                     "torrent {:?} is already managed, id={}, downloaded to {:?}",
                                     handle.info_hash(),
                                     id,
-                                    handle.info().out_dir
+                                    handle.info().out_dir()
                                 );
                                 continue;
                             }
@@ -519,5 +1048,119 @@ async fn async_main(opts: Opts) -> anyhow::Result<()> {
                 }
             }
         }
+        SubCommand::Send(send_opts) => {
+            let session = Session::new_with_opts(std::env::temp_dir(), sopts)
+                .await
+                .context("error initializing rqbit session")?;
+            let sent = session.lan_send(&send_opts.path).await?;
+            info!("sharing {:?}", send_opts.path);
+            println!(
+                "On the other machine, run:\n\n    rqbit receive {}\n",
+                sent.code
+            );
+            librqbit_spawn(
+                "stats_printer",
+                trace_span!("stats_printer"),
+                stats_printer(session.clone()),
+            );
+            // Seed until interrupted.
+            loop {
+                tokio::time::sleep(Duration::from_secs(60)).await;
+            }
+        }
+        SubCommand::Receive(receive_opts) => {
+            let session = Session::new_with_opts(PathBuf::from(&receive_opts.output_folder), sopts)
+                .await
+                .context("error initializing rqbit session")?;
+            librqbit_spawn(
+                "stats_printer",
+                trace_span!("stats_printer"),
+                stats_printer(session.clone()),
+            );
+            let handle = session
+                .lan_receive(
+                    &receive_opts.code,
+                    Some(AddTorrentOptions {
+                        overwrite: receive_opts.overwrite,
+                        ..Default::default()
+                    }),
+                )
+                .await?;
+            handle.wait_until_completed().await?;
+            info!("download completed, exiting");
+            Ok(())
+        }
+        SubCommand::Create(create_opts) => {
+            let piece_length = create_opts
+                .piece_length
+                .map(u32::try_from)
+                .transpose()
+                .context("piece length is too big")?;
+            let torrent = create_torrent(
+                &create_opts.path,
+                CreateTorrentOptions {
+                    name: create_opts.name.as_deref(),
+                    piece_length,
+                    version: if create_opts.v2_only {
+                        TorrentVersion::V2
+                    } else if create_opts.v2 {
+                        TorrentVersion::Hybrid
+                    } else {
+                        TorrentVersion::V1
+                    },
+                },
+            )
+            .await
+            .with_context(|| format!("error creating torrent from {:?}", create_opts.path))?;
+            let bytes = torrent.as_bytes()?;
+            let output = &create_opts.output;
+            std::fs::write(output, bytes).with_context(|| format!("error writing {:?}", output))?;
+            info!(
+                "created {:?}, info hash {}, piece length {}",
+                output,
+                torrent.info_hash().as_string(),
+                torrent.piece_length()
+            );
+            Ok(())
+        }
+        SubCommand::Edit(edit_opts) => {
+            let input = &edit_opts.input;
+            let bytes =
+                std::fs::read(input).with_context(|| format!("error reading {:?}", input))?;
+            let mut editor = TorrentEditor::from_bytes(&bytes)
+                .with_context(|| format!("error parsing {:?}", input))?;
+            if !edit_opts.trackers.is_empty() || edit_opts.clear_trackers {
+                let tiers = edit_opts
+                    .trackers
+                    .iter()
+                    .map(|tier| {
+                        tier.split(',')
+                            .map(str::trim)
+                            .filter(|t| !t.is_empty())
+                            .map(str::to_owned)
+                            .collect()
+                    })
+                    .collect::<Vec<_>>();
+                editor.set_trackers(&tiers);
+            }
+            if !edit_opts.web_seeds.is_empty() || edit_opts.clear_web_seeds {
+                editor.set_web_seeds(&edit_opts.web_seeds);
+            }
+            if let Some(comment) = &edit_opts.comment {
+                editor.set_comment(Some(comment.as_str()).filter(|c| !c.is_empty()));
+            }
+            if let Some(private) = edit_opts.private {
+                editor.set_private(private)?;
+            }
+            let output = &edit_opts.output;
+            std::fs::write(output, editor.to_bytes())
+                .with_context(|| format!("error writing {:?}", output))?;
+            info!(
+                "wrote {:?}, info hash {}",
+                output,
+                editor.info_hash()?.as_string()
+            );
+            Ok(())
+        }
     }
 }
@@ -4,15 +4,202 @@
 // Sha1 computation is the majority of CPU usage of librqbit.
 // openssl is 2-3x faster than rust's sha1.
 // system library is the best choice probably (it's the default anyway).
+//
+// Several implementations may be compiled in at once (one per "sha1-*" feature). [`Sha1`] uses
+// the one chosen with [`set_sha1_backend`], which is process-wide, or the fastest one on this
+// machine if none was chosen, see [`auto_detect_sha1_backend`]. Only "sha1-system" is a default
+// feature, so there's nothing to choose from unless another one is enabled.
 
-#[cfg(feature = "sha1-openssl")]
-pub type Sha1 = Sha1Openssl;
+use std::sync::atomic::{AtomicU8, Ordering};
 
-#[cfg(feature = "sha1-rust")]
-pub type Sha1 = Sha1Rust;
+#[cfg(not(any(
+    feature = "sha1-system",
+    feature = "sha1-openssl",
+    feature = "sha1-rust"
+)))]
+compile_error!("enable at least one of \"sha1-system\", \"sha1-openssl\" or \"sha1-rust\"");
 
-#[cfg(feature = "sha1-system")]
-pub type Sha1 = Sha1System;
+/// A SHA-1 implementation.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Sha1Backend {
+    /// The system's crypto library (CommonCrypto, CNG or OpenSSL, depending on the platform).
+    System,
+    /// OpenSSL, which uses SHA-NI and other CPU extensions when available.
+    Openssl,
+    /// The pure Rust "sha1" crate.
+    Rust,
+}
+
+impl Sha1Backend {
+    pub const ALL: [Sha1Backend; 3] = [Self::System, Self::Openssl, Self::Rust];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::System => "system",
+            Self::Openssl => "openssl",
+            Self::Rust => "rust",
+        }
+    }
+
+    /// Whether it was compiled in.
+    pub fn is_available(&self) -> bool {
+        match self {
+            Self::System => cfg!(feature = "sha1-system"),
+            Self::Openssl => cfg!(feature = "sha1-openssl"),
+            Self::Rust => cfg!(feature = "sha1-rust"),
+        }
+    }
+
+    pub fn available() -> impl Iterator<Item = Sha1Backend> {
+        Self::ALL.iter().copied().filter(|b| b.is_available())
+    }
+
+    fn to_u8(self) -> u8 {
+        match self {
+            Self::System => 1,
+            Self::Openssl => 2,
+            Self::Rust => 3,
+        }
+    }
+
+    fn from_u8(v: u8) -> Option<Self> {
+        match v {
+            1 => Some(Self::System),
+            2 => Some(Self::Openssl),
+            3 => Some(Self::Rust),
+            _ => None,
+        }
+    }
+}
+
+impl std::fmt::Display for Sha1Backend {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+impl std::str::FromStr for Sha1Backend {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Self::ALL
+            .iter()
+            .copied()
+            .find(|b| b.name() == s)
+            .ok_or_else(|| {
+                format!("unknown SHA-1 implementation {s:?}, expected system, openssl or rust")
+            })
+    }
+}
+
+// 0 means not chosen yet.
+static BACKEND: AtomicU8 = AtomicU8::new(0);
+
+/// Use "backend" for all SHA-1 hashing in this process from now on.
+pub fn set_sha1_backend(backend: Sha1Backend) -> Result<(), String> {
+    if !backend.is_available() {
+        return Err(format!(
+            "SHA-1 implementation \"{backend}\" isn't compiled in, enable the \"sha1-{backend}\" feature"
+        ));
+    }
+    BACKEND.store(backend.to_u8(), Ordering::Relaxed);
+    Ok(())
+}
+
+/// The implementation [`Sha1`] uses. Auto-detected on first use if none was set.
+pub fn sha1_backend() -> Sha1Backend {
+    if let Some(b) = Sha1Backend::from_u8(BACKEND.load(Ordering::Relaxed)) {
+        return b;
+    }
+    let detected = auto_detect_sha1_backend();
+    // Another thread may have set or detected it in the meantime, that one wins.
+    let _ = BACKEND.compare_exchange(0, detected.to_u8(), Ordering::Relaxed, Ordering::Relaxed);
+    Sha1Backend::from_u8(BACKEND.load(Ordering::Relaxed)).unwrap_or(detected)
+}
+
+/// Picks the fastest of the compiled-in implementations by hashing a few MiB with each. Which
+/// one wins depends on the CPU (e.g. SHA-NI support) and on how the system library was built.
+pub fn auto_detect_sha1_backend() -> Sha1Backend {
+    let mut available = Sha1Backend::available().peekable();
+    let first = available
+        .next()
+        .expect("at least one SHA-1 implementation is compiled in");
+    if available.peek().is_none() {
+        return first;
+    }
+    let buf = vec![0xabu8; 256 * 1024];
+    std::iter::once(first)
+        .chain(available)
+        .min_by_key(|b| {
+            let start = std::time::Instant::now();
+            for _ in 0..8 {
+                let mut h = Sha1::new_with_backend(*b);
+                h.update(&buf);
+                std::hint::black_box(h.finish());
+            }
+            start.elapsed()
+        })
+        .unwrap_or(first)
+}
+
+/// SHA-1 with the implementation from [`sha1_backend`].
+pub struct Sha1 {
+    inner: Sha1Inner,
+}
+
+enum Sha1Inner {
+    #[cfg(feature = "sha1-system")]
+    System(Sha1System),
+    #[cfg(feature = "sha1-openssl")]
+    Openssl(Sha1Openssl),
+    #[cfg(feature = "sha1-rust")]
+    Rust(Sha1Rust),
+}
+
+impl Sha1 {
+    /// Use "backend" for this hash only. Panics if it isn't compiled in.
+    pub fn new_with_backend(backend: Sha1Backend) -> Self {
+        let inner = match backend {
+            #[cfg(feature = "sha1-system")]
+            Sha1Backend::System => Sha1Inner::System(ISha1::new()),
+            #[cfg(feature = "sha1-openssl")]
+            Sha1Backend::Openssl => Sha1Inner::Openssl(ISha1::new()),
+            #[cfg(feature = "sha1-rust")]
+            Sha1Backend::Rust => Sha1Inner::Rust(ISha1::new()),
+            #[allow(unreachable_patterns)]
+            b => panic!("SHA-1 implementation {} isn't compiled in", b),
+        };
+        Self { inner }
+    }
+}
+
+impl ISha1 for Sha1 {
+    fn new() -> Self {
+        Self::new_with_backend(sha1_backend())
+    }
+
+    fn update(&mut self, buf: &[u8]) {
+        match &mut self.inner {
+            #[cfg(feature = "sha1-system")]
+            Sha1Inner::System(h) => h.update(buf),
+            #[cfg(feature = "sha1-openssl")]
+            Sha1Inner::Openssl(h) => h.update(buf),
+            #[cfg(feature = "sha1-rust")]
+            Sha1Inner::Rust(h) => h.update(buf),
+        }
+    }
+
+    fn finish(self) -> [u8; 20] {
+        match self.inner {
+            #[cfg(feature = "sha1-system")]
+            Sha1Inner::System(h) => h.finish(),
+            #[cfg(feature = "sha1-openssl")]
+            Sha1Inner::Openssl(h) => h.finish(),
+            #[cfg(feature = "sha1-rust")]
+            Sha1Inner::Rust(h) => h.finish(),
+        }
+    }
+}
 
 pub trait ISha1 {
     fn new() -> Self;
@@ -94,3 +281,134 @@ impl ISha1 for Sha1System {
         result_arr
     }
 }
+
+// SHA-256 is only needed for BitTorrent v2 hashes, e.g. when creating hybrid torrents. It isn't
+// selectable at runtime: OpenSSL is preferred when compiled in, then the system library, then
+// the pure rust "sha2" crate.
+
+#[cfg(feature = "sha1-openssl")]
+pub type Sha256 = Sha256Openssl;
+
+#[cfg(all(feature = "sha1-system", not(feature = "sha1-openssl")))]
+pub type Sha256 = Sha256System;
+
+#[cfg(all(
+    feature = "sha1-rust",
+    not(any(feature = "sha1-system", feature = "sha1-openssl"))
+))]
+pub type Sha256 = Sha256Rust;
+
+pub trait ISha256 {
+    fn new() -> Self;
+    fn update(&mut self, buf: &[u8]);
+    fn finish(self) -> [u8; 32];
+}
+
+#[cfg(feature = "sha1-rust")]
+pub struct Sha256Rust {
+    inner: sha2::Sha256,
+}
+
+#[cfg(feature = "sha1-rust")]
+impl ISha256 for Sha256Rust {
+    fn new() -> Self {
+        Self {
+            inner: sha2::Sha256::default(),
+        }
+    }
+
+    fn update(&mut self, buf: &[u8]) {
+        use sha2::Digest;
+        sha2::Sha256::update(&mut self.inner, buf)
+    }
+
+    fn finish(self) -> [u8; 32] {
+        use sha2::Digest;
+        self.inner.finalize().into()
+    }
+}
+
+#[cfg(feature = "sha1-openssl")]
+pub struct Sha256Openssl {
+    inner: openssl::sha::Sha256,
+}
+
+#[cfg(feature = "sha1-openssl")]
+impl ISha256 for Sha256Openssl {
+    fn new() -> Self {
+        Self {
+            inner: openssl::sha::Sha256::new(),
+        }
+    }
+
+    fn update(&mut self, buf: &[u8]) {
+        self.inner.update(buf)
+    }
+
+    fn finish(self) -> [u8; 32] {
+        self.inner.finish()
+    }
+}
+
+#[cfg(feature = "sha1-system")]
+pub struct Sha256System {
+    inner: crypto_hash::Hasher,
+}
+
+#[cfg(feature = "sha1-system")]
+impl ISha256 for Sha256System {
+    fn new() -> Self {
+        Self {
+            inner: crypto_hash::Hasher::new(crypto_hash::Algorithm::SHA256),
+        }
+    }
+
+    fn update(&mut self, buf: &[u8]) {
+        use std::io::Write;
+        self.inner.write_all(buf).unwrap();
+    }
+
+    fn finish(mut self) -> [u8; 32] {
+        let result = self.inner.finish();
+        debug_assert_eq!(result.len(), 32);
+        let mut result_arr = [0u8; 32];
+        result_arr.copy_from_slice(&result);
+        result_arr
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{ISha1, Sha1, Sha1Backend};
+
+    #[test]
+    fn test_all_backends_agree() {
+        let expected = [
+            0xa9, 0x99, 0x3e, 0x36, 0x47, 0x06, 0x81, 0x6a, 0xba, 0x3e, 0x25, 0x71, 0x78, 0x50,
+            0xc2, 0x6c, 0x9c, 0xd0, 0xd8, 0x9d,
+        ];
+        for backend in Sha1Backend::available() {
+            let mut h = Sha1::new_with_backend(backend);
+            h.update(b"ab");
+            h.update(b"c");
+            assert_eq!(h.finish(), expected, "{backend}");
+        }
+        assert!(Sha1Backend::available().any(|b| b == super::auto_detect_sha1_backend()));
+    }
+
+    #[test]
+    fn test_sha256() {
+        use super::{ISha256, Sha256};
+        let mut h = Sha256::new();
+        h.update(b"ab");
+        h.update(b"c");
+        assert_eq!(
+            h.finish(),
+            [
+                0xba, 0x78, 0x16, 0xbf, 0x8f, 0x01, 0xcf, 0xea, 0x41, 0x41, 0x40, 0xde, 0x5d, 0xae,
+                0x22, 0x23, 0xb0, 0x03, 0x61, 0xa3, 0x96, 0x17, 0x7a, 0x9c, 0xb4, 0x10, 0xff, 0x61,
+                0xf2, 0x00, 0x15, 0xad,
+            ]
+        );
+    }
+}
@@ -1,14 +1,17 @@
-use std::net::SocketAddr;
-use std::sync::Arc;
-use std::time::Duration;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
 
 use anyhow::bail;
 use anyhow::Context;
+use futures::future::BoxFuture;
 use futures::future::Either;
 use futures::stream::BoxStream;
 use futures::stream::FuturesUnordered;
 use futures::FutureExt;
 use futures::StreamExt;
+use serde::{Deserialize, Serialize};
+use serde_with::serde_as;
 use tracing::debug;
 use tracing::error_span;
 use tracing::trace;
@@ -17,6 +20,8 @@ use url::Url;
 
 use crate::tracker_comms_http;
 use crate::tracker_comms_udp;
+use librqbit_core::bind::OutgoingBind;
+use librqbit_core::clock::{sleep_or_clock_jump, wake_up_jitter};
 use librqbit_core::hash_id::Id20;
 
 pub struct TrackerComms {
@@ -26,6 +31,99 @@ pub struct TrackerComms {
     force_tracker_interval: Option<Duration>,
     tx: Sender,
     tcp_listen_port: Option<u16>,
+    // For HTTP(S) trackers, configured by the caller, e.g. with a proxy.
+    http_client: reqwest::Client,
+    // For UDP trackers. HTTP ones are bound through http_client.
+    bind: OutgoingBind,
+    // Sent in all announces, see TorrentStatsProvider::announce_key().
+    key: u32,
+    options: AnnounceOptions,
+    // Trackers that know about us, to tell them when we leave.
+    announced: Mutex<Vec<AnnouncedTracker>>,
+    // What the last announce would have sent. The torrent may be gone when we leave.
+    last_stats: Mutex<LastStats>,
+}
+
+// How long to wait for trackers to acknowledge the "stopped" announce.
+const STOPPED_ANNOUNCE_TIMEOUT: Duration = Duration::from_secs(5);
+
+#[derive(Clone, PartialEq, Eq)]
+enum AnnouncedTracker {
+    Http { url: Url, trackerid: Option<String> },
+    Udp(SocketAddr),
+}
+
+#[derive(Default, Clone, Copy)]
+struct LastStats {
+    uploaded: u64,
+    downloaded: u64,
+    left: u64,
+}
+
+/// How announcing to a tracker went.
+#[derive(Debug, Clone, Default)]
+pub struct TrackerStatus {
+    /// When the last announce finished, successfully or not.
+    pub last_announce: Option<Instant>,
+    /// Why the last announce failed. None if it succeeded.
+    pub last_error: Option<String>,
+    pub next_announce: Option<Instant>,
+    /// Peers the last successful announce returned.
+    pub peers: Option<usize>,
+    /// Seeders and leechers the tracker knows of, as of the last successful announce.
+    pub seeders: Option<u64>,
+    pub leechers: Option<u64>,
+}
+
+impl TrackerStatus {
+    fn on_success(&mut self, peers: usize, seeders: u64, leechers: u64) {
+        self.last_announce = Some(Instant::now());
+        self.last_error = None;
+        self.peers = Some(peers);
+        self.seeders = Some(seeders);
+        self.leechers = Some(leechers);
+    }
+
+    fn on_error(&mut self, error: &anyhow::Error) {
+        self.last_announce = Some(Instant::now());
+        self.last_error = Some(format!("{error:#}"));
+    }
+}
+
+/// Tunes how a torrent is announced to trackers.
+#[serde_as]
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct AnnounceOptions {
+    /// How many peers to ask for. Trackers usually give 50 if not set. Worth raising in
+    /// small swarms.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub numwant: Option<u32>,
+    /// Don't announce more often than this, even if the tracker or a lack of peers asks for it.
+    #[serde_as(as = "Option<serde_with::DurationSeconds>")]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub min_interval: Option<Duration>,
+    /// Announce at least this often, even if the tracker asks for a longer interval.
+    #[serde_as(as = "Option<serde_with::DurationSeconds>")]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub max_interval: Option<Duration>,
+    /// Ask HTTP trackers for the original list of peer dicts instead of the compact format.
+    /// Only some old trackers need it.
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    pub disable_compact: bool,
+}
+
+impl AnnounceOptions {
+    fn clamp_interval(&self, interval: Duration) -> Duration {
+        let interval = match self.max_interval {
+            Some(max) => interval.min(max),
+            None => interval,
+        };
+        match self.min_interval {
+            Some(min) => interval.max(min),
+            None => interval,
+        }
+    }
 }
 
 #[derive(Default)]
@@ -37,12 +135,110 @@ pub enum TrackerCommsStatsState {
     Live,
 }
 
+/// How much the torrent needs more peers. Used to adapt the announce interval, unless
+/// it's forced.
+#[derive(Default, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PeerDemand {
+    #[default]
+    Normal,
+    /// At the connection limit with healthy speeds. Announces are stretched to reduce
+    /// tracker load.
+    Saturated,
+    /// Too few peers. Announces are more frequent, down to the tracker's min interval.
+    Starved,
+}
+
+// Bounds of the adapted interval. The interval the tracker asked for is never exceeded in
+// the opposite direction of the adaptation.
+const MAX_STRETCHED_INTERVAL: Duration = Duration::from_secs(60 * 60);
+const MIN_SHRUNK_INTERVAL: Duration = Duration::from_secs(60);
+
+// How often the peer demand is checked between announces, to announce early when starved.
+const DEMAND_CHECK_INTERVAL: Duration = Duration::from_secs(15);
+
+fn adapt_interval(
+    interval: Duration,
+    min_interval: Option<Duration>,
+    demand: PeerDemand,
+) -> Duration {
+    match demand {
+        PeerDemand::Normal => interval,
+        PeerDemand::Saturated => (interval * 2).min(MAX_STRETCHED_INTERVAL).max(interval),
+        PeerDemand::Starved => (interval / 2)
+            .max(min_interval.unwrap_or(MIN_SHRUNK_INTERVAL))
+            .min(interval),
+    }
+}
+
+// Delays before retrying a tracker that failed, doubling with each failure in a row. A tracker
+// that is down or overloaded isn't hammered by every client at once.
+const MIN_RETRY_INTERVAL: Duration = Duration::from_secs(60);
+const MAX_RETRY_INTERVAL: Duration = Duration::from_secs(60 * 60);
+// An absurd Retry-After isn't waited for.
+const MAX_RETRY_AFTER: Duration = Duration::from_secs(24 * 60 * 60);
+
+#[derive(Default)]
+struct RetryBackoff {
+    failures: u32,
+}
+
+impl RetryBackoff {
+    fn on_success(&mut self) {
+        self.failures = 0;
+    }
+
+    // The delay before retrying after another failure. The tracker's Retry-After is respected
+    // when it asks for longer.
+    fn next_delay(&mut self, retry_after: Option<Duration>) -> Duration {
+        let delay = MIN_RETRY_INTERVAL
+            .saturating_mul(1 << self.failures.min(16))
+            .min(MAX_RETRY_INTERVAL);
+        self.failures = self.failures.saturating_add(1);
+        match retry_after {
+            Some(r) => r.min(MAX_RETRY_AFTER).max(delay),
+            None => delay,
+        }
+    }
+}
+
+// The tracker is overloaded or rate limiting us (HTTP 429 or 503).
+#[derive(Debug)]
+struct TrackerBusy {
+    status: reqwest::StatusCode,
+    retry_after: Option<Duration>,
+}
+
+impl std::fmt::Display for TrackerBusy {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "tracker responded with {:?}", self.status)?;
+        if let Some(r) = self.retry_after {
+            write!(f, ", retry after {:?}", r)?;
+        }
+        Ok(())
+    }
+}
+
+impl std::error::Error for TrackerBusy {}
+
+// Only the delay in seconds form of Retry-After, which is what trackers send.
+fn parse_retry_after(value: &str) -> Option<Duration> {
+    value.trim().parse().ok().map(Duration::from_secs)
+}
+
 #[derive(Default)]
 pub struct TrackerCommsStats {
     pub uploaded_bytes: u64,
     pub downloaded_bytes: u64,
     pub total_bytes: u64,
     pub torrent_state: TrackerCommsStatsState,
+    pub peer_demand: PeerDemand,
+    /// The download finished while running, rather than being complete from the start.
+    /// Trackers are told once with the "completed" event.
+    pub completed_while_live: bool,
+    /// Everything selected was downloaded, but not the whole torrent. HTTP trackers are told
+    /// with the "paused" event (BEP 21), so that they don't count us as a leecher. UDP trackers
+    /// have no such event.
+    pub partial_seed: bool,
 }
 
 impl TrackerCommsStats {
@@ -62,6 +258,56 @@ impl TrackerCommsStats {
 
 pub trait TorrentStatsProvider: Send + Sync {
     fn get(&self) -> TrackerCommsStats;
+
+    /// Called after each successful announce. The tracker might check if we are connectable
+    /// right after it.
+    fn on_announced(&self) {}
+
+    /// Our address as "tracker" sees it, if it told us.
+    fn on_external_ip(&self, _tracker: &str, _ip: IpAddr) {}
+
+    /// The address to tell trackers to give to peers, instead of the one they see.
+    fn announce_ip(&self) -> Option<IpAddr> {
+        None
+    }
+
+    /// The "key" sent in all announces of the torrent, so that trackers can tell it's still us
+    /// after our IP address changes. Random for each start if None.
+    fn announce_key(&self) -> Option<u32> {
+        None
+    }
+
+    /// The "tracker id" that "tracker" gave us before, to send back to it.
+    fn tracker_id(&self, _tracker: &str) -> Option<String> {
+        None
+    }
+
+    /// Called when "tracker" gives us a tracker id.
+    fn on_tracker_id(&self, _tracker: &str, _id: String) {}
+
+    /// Called after each announce to "tracker", and when it failed to start.
+    fn on_tracker_status(&self, _tracker: &str, _status: &TrackerStatus) {}
+
+    /// The forced announce interval if it was changed since the start, instead of the one given
+    /// to [`TrackerComms::start`]. None keeps that one.
+    fn force_interval(&self) -> Option<Option<Duration>> {
+        None
+    }
+
+    /// Resolves when asked to announce right away, instead of waiting for the interval.
+    fn wait_reannounce(&self) -> BoxFuture<'static, ()> {
+        futures::future::pending().boxed()
+    }
+
+    /// Runs the "stopped" announces sent when the torrent stops. The default doesn't wait for
+    /// them, override to know when they are done.
+    fn spawn_stopped_announce(
+        &self,
+        runtime: &tokio::runtime::Handle,
+        fut: BoxFuture<'static, ()>,
+    ) {
+        runtime.spawn(fut);
+    }
 }
 
 impl TorrentStatsProvider for () {
@@ -78,13 +324,17 @@ enum SupportedTracker {
 }
 
 impl TrackerComms {
+    #[allow(clippy::too_many_arguments)]
     pub fn start(
         info_hash: Id20,
         peer_id: Id20,
         trackers: Vec<String>,
         stats: Box<dyn TorrentStatsProvider>,
         force_interval: Option<Duration>,
+        options: AnnounceOptions,
         tcp_listen_port: Option<u16>,
+        http_client: reqwest::Client,
+        bind: OutgoingBind,
     ) -> Option<BoxStream<'static, SocketAddr>> {
         let trackers = trackers
             .into_iter()
@@ -108,6 +358,7 @@ impl TrackerComms {
         }
 
         let (tx, mut rx) = tokio::sync::mpsc::channel::<SocketAddr>(16);
+        let key = stats.announce_key().unwrap_or_else(rand::random);
 
         let s = async_stream::stream! {
             use futures::StreamExt;
@@ -118,6 +369,12 @@ impl TrackerComms {
                 force_tracker_interval: force_interval,
                 tx,
                 tcp_listen_port,
+                http_client,
+                bind,
+                key,
+                options,
+                announced: Default::default(),
+                last_stats: Default::default(),
             });
             let mut futures = FuturesUnordered::new();
             for tracker in trackers {
@@ -172,9 +429,24 @@ impl TrackerComms {
     }
 
     async fn task_single_tracker_monitor_http(&self, mut tracker_url: Url) -> anyhow::Result<()> {
-        let mut event = Some(tracker_comms_http::TrackerRequestEvent::Started);
+        let tracker = tracker_url.to_string();
+        let announced_url = tracker_url.clone();
+        let mut started = false;
+        let mut completed_sent = false;
+        let mut status = TrackerStatus::default();
+        let mut backoff = RetryBackoff::default();
         loop {
-            let stats = self.stats.get();
+            let stats = self.get_stats();
+            let event = if !started {
+                Some(tracker_comms_http::TrackerRequestEvent::Started)
+            } else if stats.completed_while_live && !completed_sent {
+                Some(tracker_comms_http::TrackerRequestEvent::Completed)
+            } else if stats.partial_seed {
+                Some(tracker_comms_http::TrackerRequestEvent::Paused)
+            } else {
+                None
+            };
+            let announce_ip = self.stats.announce_ip();
             let request = tracker_comms_http::TrackerRequest {
                 info_hash: self.info_hash,
                 peer_id: self.peer_id,
@@ -182,42 +454,213 @@ impl TrackerComms {
                 uploaded: stats.uploaded_bytes,
                 downloaded: stats.downloaded_bytes,
                 left: stats.get_left_to_download_bytes(),
-                compact: true,
+                compact: !self.options.disable_compact,
                 no_peer_id: false,
                 event,
-                ip: None,
-                numwant: None,
-                key: None,
-                trackerid: None,
+                ip: announce_ip.filter(|ip| ip.is_ipv4()),
+                ipv6: match announce_ip {
+                    Some(IpAddr::V6(ip)) => Some(ip),
+                    _ => self.tcp_listen_port.and_then(|_| self.bind.public_ipv6()),
+                },
+                numwant: self.options.numwant.map(|n| n as usize),
+                key: Some(format!("{:08X}", self.key)),
+                trackerid: self.stats.tracker_id(&tracker),
             };
 
             let request_query = request.as_querystring();
             tracker_url.set_query(Some(&request_query));
 
-            match self.tracker_one_request_http(tracker_url.clone()).await {
-                Ok(interval) => {
-                    event = None;
-                    let interval = self
-                        .force_tracker_interval
-                        .unwrap_or_else(|| Duration::from_secs(interval));
+            match self
+                .tracker_one_request_http(&tracker, tracker_url.clone(), &mut status)
+                .await
+            {
+                Ok((interval, min_interval)) => {
+                    self.on_announced(AnnouncedTracker::Http {
+                        url: announced_url.clone(),
+                        trackerid: self.stats.tracker_id(&tracker),
+                    });
+                    started = true;
+                    backoff.on_success();
+                    if matches!(
+                        event,
+                        Some(tracker_comms_http::TrackerRequestEvent::Completed)
+                    ) {
+                        completed_sent = true;
+                    }
+                    let interval = self.next_interval(
+                        Duration::from_secs(interval),
+                        min_interval.map(Duration::from_secs),
+                    );
                     debug!(
                         "sleeping for {:?} after calling tracker {}",
                         interval,
                         tracker_url.host().unwrap()
                     );
-                    tokio::time::sleep(interval).await;
+                    status.next_announce = Some(Instant::now() + interval);
+                    self.stats.on_tracker_status(&tracker, &status);
+                    self.wait_for_next_announce(
+                        interval,
+                        min_interval.map(Duration::from_secs),
+                        !completed_sent,
+                    )
+                    .await;
                 }
                 Err(e) => {
                     debug!("error calling the tracker {}: {:#}", tracker_url, e);
-                    tokio::time::sleep(Duration::from_secs(60)).await;
+                    let retry_after = e.downcast_ref::<TrackerBusy>().and_then(|b| b.retry_after);
+                    let retry = backoff.next_delay(retry_after);
+                    status.on_error(&e);
+                    status.next_announce = Some(Instant::now() + retry);
+                    self.stats.on_tracker_status(&tracker, &status);
+                    self.wait_for_retry(retry).await;
                 }
             };
         }
     }
 
-    async fn tracker_one_request_http(&self, tracker_url: Url) -> anyhow::Result<u64> {
-        let response: reqwest::Response = reqwest::get(tracker_url).await?;
-        if !response.status().is_success() {
+    // The interval before the next announce, adapted from the one the tracker asked for. Never
+    // shorter than the tracker's min interval, whatever the options say.
+    fn next_interval(&self, interval: Duration, min_interval: Option<Duration>) -> Duration {
+        let next = match self.force_interval() {
+            Some(forced) => forced,
+            None => self.options.clamp_interval(adapt_interval(
+                interval,
+                min_interval,
+                self.get_stats().peer_demand,
+            )),
+        };
+        next.max(min_interval.unwrap_or_default())
+    }
+
+    fn force_interval(&self) -> Option<Duration> {
+        self.stats
+            .force_interval()
+            .unwrap_or(self.force_tracker_interval)
+    }
+
+    // Stats from the provider, remembered for the "stopped" announce.
+    fn get_stats(&self) -> TrackerCommsStats {
+        let stats = self.stats.get();
+        // None if the torrent is gone.
+        if !matches!(stats.torrent_state, TrackerCommsStatsState::None) {
+            *self.last_stats.lock().unwrap() = LastStats {
+                uploaded: stats.uploaded_bytes,
+                downloaded: stats.downloaded_bytes,
+                left: stats.get_left_to_download_bytes(),
+            };
+        }
+        stats
+    }
+
+    fn on_announced(&self, tracker: AnnouncedTracker) {
+        {
+            let mut announced = self.announced.lock().unwrap();
+            announced.retain(|t| match (t, &tracker) {
+                (AnnouncedTracker::Http { url, .. }, AnnouncedTracker::Http { url: u, .. }) => {
+                    url != u
+                }
+                (t, tracker) => t != tracker,
+            });
+            announced.push(tracker);
+        }
+        self.stats.on_announced();
+    }
+
+    // After a clock jump, e.g. a wake up from a suspend, the tracker may have forgotten about
+    // us, and the network may have changed. Announce again soon, but not all trackers and
+    // torrents at once.
+    async fn on_clock_jump(&self) {
+        debug!("clock jumped, announcing soon");
+        tokio::time::sleep(wake_up_jitter()).await;
+    }
+
+    async fn wait_for_retry(&self, backoff: Duration) {
+        tokio::select! {
+            jumped = sleep_or_clock_jump(backoff) => {
+                if jumped {
+                    self.on_clock_jump().await;
+                }
+            }
+            _ = self.stats.wait_reannounce() => debug!("reannouncing"),
+        }
+    }
+
+    async fn wait_for_next_announce(
+        &self,
+        interval: Duration,
+        min_interval: Option<Duration>,
+        on_completed: bool,
+    ) {
+        tokio::select! {
+            _ = self.sleep_until_next_announce(interval, min_interval, on_completed) => {}
+            _ = self.stats.wait_reannounce() => debug!("reannouncing"),
+        }
+    }
+
+    // Sleeps for "interval", but announces early if the torrent becomes starved of peers
+    // meanwhile, though not before the min interval. Also announces early if the clock jumps,
+    // or to tell that the download completed if "on_completed".
+    async fn sleep_until_next_announce(
+        &self,
+        interval: Duration,
+        min_interval: Option<Duration>,
+        on_completed: bool,
+    ) {
+        if self.force_interval().is_some() {
+            return self.wait_for_retry(interval).await;
+        }
+        let start = tokio::time::Instant::now();
+        let earliest = self
+            .options
+            .clamp_interval(min_interval.unwrap_or(MIN_SHRUNK_INTERVAL))
+            .max(min_interval.unwrap_or_default())
+            .min(interval);
+        loop {
+            let elapsed = start.elapsed();
+            if elapsed >= interval {
+                return;
+            }
+            if sleep_or_clock_jump((interval - elapsed).min(DEMAND_CHECK_INTERVAL)).await {
+                return self.on_clock_jump().await;
+            }
+            let elapsed = start.elapsed();
+            let stats = self.get_stats();
+            if on_completed && stats.completed_while_live {
+                debug!("download completed, announcing");
+                return;
+            }
+            let peer_demand = stats.peer_demand;
+            if elapsed >= earliest && elapsed < interval && peer_demand == PeerDemand::Starved {
+                debug!("starved of peers, announcing early");
+                return;
+            }
+        }
+    }
+
+    // Returns the interval and the min interval.
+    async fn tracker_one_request_http(
+        &self,
+        tracker: &str,
+        tracker_url: Url,
+        status: &mut TrackerStatus,
+    ) -> anyhow::Result<(u64, Option<u64>)> {
+        let tracker_host = tracker_url.host_str().unwrap_or_default().to_owned();
+        let response: reqwest::Response = self.http_client.get(tracker_url).send().await?;
+        let http_status = response.status();
+        if http_status == reqwest::StatusCode::TOO_MANY_REQUESTS
+            || http_status == reqwest::StatusCode::SERVICE_UNAVAILABLE
+        {
+            return Err(TrackerBusy {
+                status: http_status,
+                retry_after: response
+                    .headers()
+                    .get(reqwest::header::RETRY_AFTER)
+                    .and_then(|v| v.to_str().ok())
+                    .and_then(parse_retry_after),
+            }
+            .into());
+        }
+        if !http_status.is_success() {
             anyhow::bail!("tracker responded with {:?}", response.status());
         }
         let bytes = response.bytes().await?;
@@ -229,15 +672,31 @@ impl TrackerComms {
         };
         let response = bencode::from_bytes::<tracker_comms_http::TrackerResponse>(&bytes)?;
 
-        for peer in response.peers.iter_sockaddrs() {
+        if let Some(ip) = response.external_ip() {
+            self.stats.on_external_ip(&tracker_host, ip);
+        }
+        if let Some(id) = &response.tracker_id {
+            let id = String::from_utf8_lossy(id.as_ref()).into_owned();
+            if self.stats.tracker_id(tracker).as_ref() != Some(&id) {
+                debug!(tracker_id = id, "tracker gave us a tracker id");
+                self.stats.on_tracker_id(tracker, id);
+            }
+        }
+        if let Some(warning) = &response.warning_message {
+            debug!(
+                "tracker warning: {}",
+                String::from_utf8_lossy(warning.as_ref())
+            );
+        }
+        let peers = response.iter_peers().collect::<Vec<_>>();
+        status.on_success(peers.len(), response.complete, response.incomplete);
+        for peer in peers {
             self.tx.send(peer).await?;
         }
-        Ok(response.interval)
+        Ok((response.interval, response.min_interval))
     }
 
     async fn task_single_tracker_monitor_udp(&self, url: Url) -> anyhow::Result<()> {
-        use tracker_comms_udp::*;
-
         if url.scheme() != "udp" {
             bail!("expected UDP scheme in {}", url);
         }
@@ -245,63 +704,508 @@ impl TrackerComms {
             url.host_str().context("missing host")?,
             url.port().context("missing port")?,
         );
-        let mut requester = UdpTrackerRequester::new(hp)
+        // Announce over both IPv4 and IPv6 if the tracker has both, so that peers of either
+        // version learn about us.
+        let addrs = tokio::net::lookup_host(hp)
+            .await
+            .context("error resolving tracker address")?
+            .collect::<Vec<_>>();
+        let ipv4 = addrs.iter().find(|a| a.is_ipv4());
+        let ipv6 = addrs.iter().find(|a| a.is_ipv6());
+        let addrs = match self.bind.address {
+            // The other version is unreachable then.
+            Some(a) if a.is_ipv4() => vec![ipv4],
+            Some(_) => vec![ipv6],
+            None => vec![ipv4, ipv6],
+        };
+        let monitors = addrs
+            .into_iter()
+            .flatten()
+            .map(|addr| self.task_single_tracker_monitor_udp_addr(&url, *addr))
+            .collect::<Vec<_>>();
+        if monitors.is_empty() {
+            bail!("{url} has no address of the IP version we can use");
+        }
+        let results = futures::future::join_all(monitors).await;
+        results.into_iter().collect::<anyhow::Result<Vec<()>>>()?;
+        Ok(())
+    }
+
+    async fn task_single_tracker_monitor_udp_addr(
+        &self,
+        url: &Url,
+        addr: SocketAddr,
+    ) -> anyhow::Result<()> {
+        use tracker_comms_udp::*;
+
+        // Both the IPv4 and IPv6 addresses of a tracker may be announced to.
+        let tracker = if addr.is_ipv6() {
+            format!("{url} (IPv6)")
+        } else {
+            url.to_string()
+        };
+        let mut status = TrackerStatus::default();
+        let mut requester = match UdpTrackerRequester::new(addr, &self.bind)
             .await
-            .context("error creating UDP tracker requester")?;
+            .with_context(|| format!("error creating UDP tracker requester for {addr}"))
+        {
+            Ok(r) => r,
+            Err(e) => {
+                status.on_error(&e);
+                self.stats.on_tracker_status(&tracker, &status);
+                return Err(e);
+            }
+        };
 
         let mut sleep_interval: Option<Duration> = None;
+        let mut started = false;
+        let mut completed_sent = false;
+        let mut backoff = RetryBackoff::default();
         loop {
             if let Some(i) = sleep_interval {
                 trace!(interval=?sleep_interval, "sleeping");
-                tokio::time::sleep(i).await;
+                status.next_announce = Some(Instant::now() + i);
+                self.stats.on_tracker_status(&tracker, &status);
+                self.wait_for_next_announce(i, None, !completed_sent).await;
             }
 
-            let stats = self.stats.get();
+            let stats = self.get_stats();
+            let event = if !started {
+                EVENT_STARTED
+            } else if stats.completed_while_live && !completed_sent {
+                EVENT_COMPLETED
+            } else {
+                EVENT_NONE
+            };
+            let announce_ip = self.stats.announce_ip();
             let request = AnnounceFields {
                 info_hash: self.info_hash,
                 peer_id: self.peer_id,
                 downloaded: stats.downloaded_bytes,
                 left: stats.get_left_to_download_bytes(),
                 uploaded: stats.uploaded_bytes,
-                event: match stats.torrent_state {
-                    TrackerCommsStatsState::None => EVENT_NONE,
-                    TrackerCommsStatsState::Initializing => EVENT_STARTED,
-                    TrackerCommsStatsState::Paused => EVENT_STOPPED,
-                    TrackerCommsStatsState::Live => {
-                        if stats.is_completed() {
-                            EVENT_COMPLETED
-                        } else {
-                            EVENT_STARTED
-                        }
-                    }
+                event,
+                ip: match announce_ip {
+                    Some(IpAddr::V4(ip)) => Some(ip),
+                    _ => None,
                 },
-                key: 0, // whatever that is?
+                key: self.key,
+                num_want: self.options.numwant,
                 port: self.tcp_listen_port.unwrap_or(0),
             };
 
             match requester.announce(request).await {
                 Ok(response) => {
                     trace!(len = response.addrs.len(), "received announce response");
+                    self.on_announced(AnnouncedTracker::Udp(addr));
+                    started = true;
+                    completed_sent |= event == EVENT_COMPLETED;
+                    backoff.on_success();
+                    status.on_success(
+                        response.addrs.len(),
+                        response.seeders as u64,
+                        response.leechers as u64,
+                    );
                     for addr in response.addrs {
-                        self.tx
-                            .send(SocketAddr::V4(addr))
-                            .await
-                            .context("rx closed")?;
+                        self.tx.send(addr).await.context("rx closed")?;
                     }
                     let new_interval = response.interval.max(5);
                     let new_interval = Duration::from_secs(new_interval as u64);
-                    sleep_interval = Some(self.force_tracker_interval.unwrap_or(new_interval));
+                    sleep_interval = Some(self.next_interval(new_interval, None));
                 }
                 Err(e) => {
                     debug!(url = ?url, "error reading announce response: {e:#}");
-                    if sleep_interval.is_none() {
-                        sleep_interval = Some(
-                            self.force_tracker_interval
-                                .unwrap_or(Duration::from_secs(60)),
-                        );
-                    }
+                    status.on_error(&e);
+                    sleep_interval = Some(backoff.next_delay(None));
+                }
+            }
+        }
+    }
+}
+
+// Tell the trackers that we leave, so that they stop giving our address to peers. Best effort, as
+// this runs when the torrent is paused or removed, or the session is dropped.
+impl Drop for TrackerComms {
+    fn drop(&mut self) {
+        let announced = std::mem::take(&mut *self.announced.lock().unwrap());
+        if announced.is_empty() {
+            return;
+        }
+        let runtime = match tokio::runtime::Handle::try_current() {
+            Ok(r) => r,
+            Err(_) => return,
+        };
+        let stopped = StoppedAnnounce {
+            info_hash: self.info_hash,
+            peer_id: self.peer_id,
+            port: self.tcp_listen_port.unwrap_or(0),
+            key: self.key,
+            stats: *self.last_stats.lock().unwrap(),
+            http_client: self.http_client.clone(),
+            bind: self.bind.clone(),
+        };
+        let span = error_span!(parent: None, "stopped_announce", info_hash = ?self.info_hash);
+        self.stats.spawn_stopped_announce(
+            &runtime,
+            stopped.send_all(announced).instrument(span).boxed(),
+        );
+    }
+}
+
+struct StoppedAnnounce {
+    info_hash: Id20,
+    peer_id: Id20,
+    port: u16,
+    key: u32,
+    stats: LastStats,
+    http_client: reqwest::Client,
+    bind: OutgoingBind,
+}
+
+impl StoppedAnnounce {
+    async fn send_all(self, trackers: Vec<AnnouncedTracker>) {
+        let this = &self;
+        futures::future::join_all(trackers.into_iter().map(|tracker| async move {
+            match tokio::time::timeout(STOPPED_ANNOUNCE_TIMEOUT, this.send(&tracker)).await {
+                Ok(Ok(())) => trace!(tracker = %tracker, "sent stopped announce"),
+                Ok(Err(e)) => debug!(tracker = %tracker, "error sending stopped announce: {e:#}"),
+                Err(_) => debug!(tracker = %tracker, "timeout sending stopped announce"),
+            }
+        }))
+        .await;
+    }
+
+    async fn send(&self, tracker: &AnnouncedTracker) -> anyhow::Result<()> {
+        match tracker {
+            AnnouncedTracker::Http { url, trackerid } => {
+                let request = tracker_comms_http::TrackerRequest {
+                    info_hash: self.info_hash,
+                    peer_id: self.peer_id,
+                    port: self.port,
+                    uploaded: self.stats.uploaded,
+                    downloaded: self.stats.downloaded,
+                    left: self.stats.left,
+                    compact: true,
+                    no_peer_id: false,
+                    event: Some(tracker_comms_http::TrackerRequestEvent::Stopped),
+                    ip: None,
+                    ipv6: None,
+                    numwant: Some(0),
+                    key: Some(format!("{:08X}", self.key)),
+                    trackerid: trackerid.clone(),
+                };
+                let mut url = url.clone();
+                url.set_query(Some(&request.as_querystring()));
+                let response = self.http_client.get(url).send().await?;
+                if !response.status().is_success() {
+                    bail!("tracker responded with {:?}", response.status());
                 }
             }
+            AnnouncedTracker::Udp(addr) => {
+                use tracker_comms_udp::*;
+                let mut requester = UdpTrackerRequester::new(*addr, &self.bind).await?;
+                requester
+                    .announce(AnnounceFields {
+                        info_hash: self.info_hash,
+                        peer_id: self.peer_id,
+                        downloaded: self.stats.downloaded,
+                        left: self.stats.left,
+                        uploaded: self.stats.uploaded,
+                        event: EVENT_STOPPED,
+                        ip: None,
+                        key: self.key,
+                        num_want: Some(0),
+                        port: self.port,
+                    })
+                    .await?;
+            }
+        }
+        Ok(())
+    }
+}
+
+impl std::fmt::Display for AnnouncedTracker {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            AnnouncedTracker::Http { url, .. } => write!(f, "{url}"),
+            AnnouncedTracker::Udp(addr) => write!(f, "udp://{addr}"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{net::SocketAddr, sync::Arc, time::Duration};
+
+    use futures::{
+        future::BoxFuture,
+        stream::{BoxStream, StreamExt},
+        FutureExt,
+    };
+    use librqbit_core::hash_id::Id20;
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::TcpListener,
+        sync::Notify,
+    };
+
+    use super::{
+        adapt_interval, parse_retry_after, AnnounceOptions, PeerDemand, RetryBackoff,
+        TorrentStatsProvider, TrackerComms, TrackerCommsStats,
+    };
+
+    struct Reannounce(Arc<Notify>);
+
+    impl TorrentStatsProvider for Reannounce {
+        fn get(&self) -> TrackerCommsStats {
+            Default::default()
+        }
+
+        fn wait_reannounce(&self) -> BoxFuture<'static, ()> {
+            let notify = self.0.clone();
+            async move { notify.notified().await }.boxed()
+        }
+    }
+
+    struct PartialSeed(Arc<Notify>);
+
+    impl TorrentStatsProvider for PartialSeed {
+        fn get(&self) -> TrackerCommsStats {
+            TrackerCommsStats {
+                partial_seed: true,
+                ..Default::default()
+            }
+        }
+
+        fn wait_reannounce(&self) -> BoxFuture<'static, ()> {
+            let notify = self.0.clone();
+            async move { notify.notified().await }.boxed()
+        }
+    }
+
+    struct CompletedWhileLive(Arc<Notify>);
+
+    impl TorrentStatsProvider for CompletedWhileLive {
+        fn get(&self) -> TrackerCommsStats {
+            TrackerCommsStats {
+                completed_while_live: true,
+                ..Default::default()
+            }
+        }
+
+        fn wait_reannounce(&self) -> BoxFuture<'static, ()> {
+            let notify = self.0.clone();
+            async move { notify.notified().await }.boxed()
+        }
+    }
+
+    fn start(url: String, stats: Box<dyn TorrentStatsProvider>) -> BoxStream<'static, SocketAddr> {
+        TrackerComms::start(
+            Id20::new([1; 20]),
+            Id20::new([2; 20]),
+            vec![url],
+            stats,
+            None,
+            Default::default(),
+            Some(6881),
+            reqwest::Client::builder().no_proxy().build().unwrap(),
+            Default::default(),
+        )
+        .unwrap()
+    }
+
+    // Returns the request line.
+    async fn respond(listener: &TcpListener, body: &[u8]) -> String {
+        let (mut conn, _) = listener.accept().await.unwrap();
+        let mut buf = vec![0u8; 4096];
+        let mut len = 0;
+        while !buf[..len].windows(4).any(|w| w == b"\r\n\r\n") {
+            len += conn.read(&mut buf[len..]).await.unwrap();
+        }
+        let head = format!(
+            "HTTP/1.1 200 OK\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+            body.len()
+        );
+        conn.write_all(head.as_bytes()).await.unwrap();
+        conn.write_all(body).await.unwrap();
+        String::from_utf8_lossy(&buf[..len])
+            .lines()
+            .next()
+            .unwrap()
+            .to_owned()
+    }
+
+    #[tokio::test]
+    async fn test_stopped_announce_on_drop() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}/announce", listener.local_addr().unwrap());
+        let mut peers = start(url, Box::new(()));
+
+        let peer =
+            b"d8:completei1e10:incompletei0e8:intervali1800e5:peers6:\x7f\x00\x00\x01\x1a\xe1e";
+        let (started, peer) = tokio::join!(respond(&listener, peer), peers.next());
+        assert!(started.contains("event=started"), "{}", started);
+        assert_eq!(peer, Some("127.0.0.1:6881".parse().unwrap()));
+
+        drop(peers);
+        let stopped = tokio::time::timeout(
+            Duration::from_secs(5),
+            respond(&listener, b"d8:intervali1800ee"),
+        )
+        .await
+        .unwrap();
+        assert!(stopped.contains("event=stopped"), "{}", stopped);
+        assert!(stopped.contains("numwant=0"), "{}", stopped);
+    }
+
+    #[tokio::test]
+    async fn test_reannounce() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}/announce", listener.local_addr().unwrap());
+        let notify = Arc::new(Notify::new());
+        let mut peers = start(url, Box::new(Reannounce(notify.clone())));
+        let response = b"d8:completei0e10:incompletei0e8:intervali1800e5:peers0:e";
+        let poll = async {
+            // Drives the announce loop, it yields no peers.
+            let _ = tokio::time::timeout(Duration::from_millis(200), peers.next()).await;
+        };
+
+        let (started, _) = tokio::join!(respond(&listener, response), poll);
+        assert!(started.contains("event=started"), "{}", started);
+
+        let poll = async {
+            notify.notify_waiters();
+            let _ = tokio::time::timeout(Duration::from_millis(200), peers.next()).await;
+        };
+        let (again, _) = tokio::join!(
+            tokio::time::timeout(Duration::from_secs(5), respond(&listener, response)),
+            poll
+        );
+        assert!(!again.unwrap().contains("event="));
+    }
+
+    #[tokio::test]
+    async fn test_partial_seed_announce() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}/announce", listener.local_addr().unwrap());
+        let notify = Arc::new(Notify::new());
+        let mut peers = start(url, Box::new(PartialSeed(notify.clone())));
+        let response = b"d8:completei0e10:incompletei0e8:intervali1800e5:peers0:e";
+        let poll = async {
+            let _ = tokio::time::timeout(Duration::from_millis(200), peers.next()).await;
+        };
+
+        let (started, _) = tokio::join!(respond(&listener, response), poll);
+        assert!(started.contains("event=started"), "{}", started);
+
+        let poll = async {
+            notify.notify_waiters();
+            let _ = tokio::time::timeout(Duration::from_millis(200), peers.next()).await;
+        };
+        let (again, _) = tokio::join!(
+            tokio::time::timeout(Duration::from_secs(5), respond(&listener, response)),
+            poll
+        );
+        let again = again.unwrap();
+        assert!(again.contains("event=paused"), "{}", again);
+    }
+
+    #[tokio::test]
+    async fn test_completed_announced_once() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}/announce", listener.local_addr().unwrap());
+        let notify = Arc::new(Notify::new());
+        let mut peers = start(url, Box::new(CompletedWhileLive(notify.clone())));
+        let response = b"d8:completei0e10:incompletei0e8:intervali1800e5:peers0:e";
+        let poll = async {
+            let _ = tokio::time::timeout(Duration::from_millis(200), peers.next()).await;
+        };
+
+        let (started, _) = tokio::join!(respond(&listener, response), poll);
+        assert!(started.contains("event=started"), "{}", started);
+
+        // Only the first announce after completing tells it.
+        for event in [Some("event=completed"), None] {
+            let poll = async {
+                notify.notify_waiters();
+                let _ = tokio::time::timeout(Duration::from_millis(200), peers.next()).await;
+            };
+            let (request, _) = tokio::join!(
+                tokio::time::timeout(Duration::from_secs(5), respond(&listener, response)),
+                poll
+            );
+            let request = request.unwrap();
+            match event {
+                Some(event) => assert!(request.contains(event), "{}", request),
+                None => assert!(!request.contains("event="), "{}", request),
+            }
         }
     }
+
+    #[test]
+    fn test_retry_backoff() {
+        let mut backoff = RetryBackoff::default();
+        let delays = (0..8)
+            .map(|_| backoff.next_delay(None).as_secs())
+            .collect::<Vec<_>>();
+        assert_eq!(delays, [60, 120, 240, 480, 960, 1920, 3600, 3600]);
+
+        backoff.on_success();
+        assert_eq!(backoff.next_delay(None), Duration::from_secs(60));
+        // Retry-After only makes it longer.
+        assert_eq!(
+            backoff.next_delay(parse_retry_after("10")),
+            Duration::from_secs(120)
+        );
+        assert_eq!(
+            backoff.next_delay(parse_retry_after(" 7200 ")),
+            Duration::from_secs(7200)
+        );
+        assert_eq!(parse_retry_after("Wed, 21 Oct 2015 07:28:00 GMT"), None);
+    }
+
+    #[test]
+    fn test_adapt_interval() {
+        let secs = Duration::from_secs;
+        let adapt = |interval, min_interval: Option<u64>, demand| {
+            adapt_interval(secs(interval), min_interval.map(secs), demand).as_secs()
+        };
+        assert_eq!(adapt(1800, None, PeerDemand::Normal), 1800);
+
+        assert_eq!(adapt(1800, None, PeerDemand::Saturated), 3600);
+        assert_eq!(adapt(2400, None, PeerDemand::Saturated), 3600);
+        // Never shorter than what the tracker asked for.
+        assert_eq!(adapt(7200, None, PeerDemand::Saturated), 7200);
+
+        assert_eq!(adapt(1800, None, PeerDemand::Starved), 900);
+        assert_eq!(adapt(1800, Some(1200), PeerDemand::Starved), 1200);
+        assert_eq!(adapt(90, None, PeerDemand::Starved), 60);
+        // Never longer than what the tracker asked for.
+        assert_eq!(adapt(30, None, PeerDemand::Starved), 30);
+    }
+
+    #[test]
+    fn test_announce_interval_bounds() {
+        let secs = Duration::from_secs;
+        let options = AnnounceOptions {
+            min_interval: Some(secs(600)),
+            max_interval: Some(secs(3600)),
+            ..Default::default()
+        };
+        assert_eq!(options.clamp_interval(secs(1800)), secs(1800));
+        assert_eq!(options.clamp_interval(secs(7200)), secs(3600));
+        // Even when starved, and the tracker allows it.
+        assert_eq!(
+            options.clamp_interval(adapt_interval(
+                secs(900),
+                Some(secs(60)),
+                PeerDemand::Starved
+            )),
+            secs(600)
+        );
+        assert_eq!(
+            AnnounceOptions::default().clamp_interval(secs(7200)),
+            secs(7200)
+        );
+    }
 }
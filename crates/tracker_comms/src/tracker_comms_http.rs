@@ -4,7 +4,7 @@ use serde::{Deserialize, Deserializer};
 use std::{
     fmt::Write,
     marker::PhantomData,
-    net::{IpAddr, Ipv4Addr, SocketAddr, SocketAddrV4},
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, SocketAddrV4, SocketAddrV6},
     str::FromStr,
 };
 
@@ -13,10 +13,10 @@ use librqbit_core::hash_id::Id20;
 #[derive(Clone, Copy)]
 pub enum TrackerRequestEvent {
     Started,
-    #[allow(dead_code)]
     Stopped,
-    #[allow(dead_code)]
     Completed,
+    /// We are a partial seed, see [`crate::TrackerCommsStats::partial_seed`].
+    Paused,
 }
 
 pub struct TrackerRequest {
@@ -31,6 +31,9 @@ pub struct TrackerRequest {
     pub no_peer_id: bool,
 
     pub ip: Option<std::net::IpAddr>,
+    // Our IPv6 address, so that the tracker gives it to IPv6 peers even if we announce over
+    // IPv4 (BEP 7).
+    pub ipv6: Option<Ipv6Addr>,
     pub numwant: Option<usize>,
     pub key: Option<String>,
     pub trackerid: Option<String>,
@@ -58,7 +61,7 @@ impl<'a> DictPeer<'a> {
     }
 }
 
-#[derive(Debug)]
+#[derive(Debug, Default)]
 pub struct Peers {
     addrs: Vec<SocketAddr>,
 }
@@ -113,6 +116,41 @@ impl<'de> serde::de::Deserialize<'de> for Peers {
     }
 }
 
+// The "peers6" key of compact responses (BEP 7).
+#[derive(Debug, Default)]
+pub struct Peers6 {
+    addrs: Vec<SocketAddr>,
+}
+
+impl<'de> serde::de::Deserialize<'de> for Peers6 {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        struct Visitor;
+        impl<'de> serde::de::Visitor<'de> for Visitor {
+            type Value = Peers6;
+
+            fn expecting(&self, formatter: &mut std::fmt::Formatter) -> std::fmt::Result {
+                formatter.write_str("IPv6 peers in binary format")
+            }
+
+            fn visit_bytes<E>(self, v: &[u8]) -> Result<Self::Value, E>
+            where
+                E: serde::de::Error,
+            {
+                Ok(Peers6 {
+                    addrs: parse_compact_peers6(v)
+                        .into_iter()
+                        .map(|v| v.into())
+                        .collect(),
+                })
+            }
+        }
+        deserializer.deserialize_any(Visitor)
+    }
+}
+
 fn deserialize_ip_string<'de, D>(de: D) -> Result<IpAddr, D::Error>
 where
     D: Deserializer<'de>,
@@ -147,6 +185,17 @@ fn parse_compact_peers(b: &[u8]) -> Vec<SocketAddrV4> {
     ips
 }
 
+fn parse_compact_peers6(b: &[u8]) -> Vec<SocketAddrV6> {
+    let mut ips = Vec::new();
+    for chunk in b.chunks_exact(18) {
+        let mut ip = [0u8; 16];
+        ip.copy_from_slice(&chunk[..16]);
+        let port = byteorder::BigEndian::read_u16(&chunk[16..18]);
+        ips.push(SocketAddrV6::new(Ipv6Addr::from(ip), port, 0, 0));
+    }
+    ips
+}
+
 #[derive(Deserialize, Debug)]
 pub struct TrackerResponse<'a> {
     #[serde(rename = "warning message", borrow)]
@@ -155,9 +204,37 @@ pub struct TrackerResponse<'a> {
     pub interval: u64,
     #[serde(rename = "min interval")]
     pub min_interval: Option<u64>,
+    #[serde(rename = "tracker id", borrow)]
     pub tracker_id: Option<ByteBuf<'a>>,
     pub incomplete: u64,
+    #[serde(default)]
     pub peers: Peers,
+    #[serde(default)]
+    pub peers6: Peers6,
+    // Our address as the tracker sees it (BEP 24).
+    #[serde(rename = "external ip", borrow)]
+    pub external_ip: Option<ByteBuf<'a>>,
+}
+
+impl<'a> TrackerResponse<'a> {
+    pub fn external_ip(&self) -> Option<IpAddr> {
+        let b = self.external_ip.as_ref()?.as_ref();
+        match b.len() {
+            4 => Some(Ipv4Addr::new(b[0], b[1], b[2], b[3]).into()),
+            16 => {
+                let mut octets = [0u8; 16];
+                octets.copy_from_slice(b);
+                Some(Ipv6Addr::from(octets).into())
+            }
+            _ => None,
+        }
+    }
+
+    pub fn iter_peers(&self) -> impl Iterator<Item = SocketAddr> + '_ {
+        self.peers
+            .iter_sockaddrs()
+            .chain(self.peers6.addrs.iter().copied())
+    }
 }
 
 impl TrackerRequest {
@@ -176,6 +253,7 @@ impl TrackerRequest {
                     TrackerRequestEvent::Started => "started",
                     TrackerRequestEvent::Stopped => "stopped",
                     TrackerRequestEvent::Completed => "completed",
+                    TrackerRequestEvent::Paused => "paused",
                 }
             )
             .unwrap();
@@ -189,6 +267,9 @@ impl TrackerRequest {
         if let Some(ip) = &self.ip {
             write!(s, "&ip={ip}").unwrap();
         }
+        if let Some(ipv6) = &self.ipv6 {
+            write!(s, "&ipv6={}", u::encode(&ipv6.to_string())).unwrap();
+        }
         if let Some(numwant) = &self.numwant {
             write!(s, "&numwant={numwant}").unwrap();
         }
@@ -224,10 +305,35 @@ mod tests {
             no_peer_id: false,
             event: Some(TrackerRequestEvent::Started),
             ip: Some("127.0.0.1".parse().unwrap()),
+            ipv6: None,
             numwant: None,
             key: None,
             trackerid: None,
         };
         dbg!(request.as_querystring());
     }
+
+    #[test]
+    fn test_parse_peers6() {
+        let mut b = b"d8:completei1e10:incompletei2e8:intervali1800e5:peers6:".to_vec();
+        b.extend_from_slice(&[127, 0, 0, 1, 0x1a, 0xe1]);
+        b.extend_from_slice(b"6:peers618:");
+        b.extend_from_slice(&"2001:db8::1".parse::<Ipv6Addr>().unwrap().octets());
+        b.extend_from_slice(&[0x1a, 0xe1, b'e']);
+        let response = bencode::from_bytes::<TrackerResponse>(&b).unwrap();
+        assert_eq!(
+            response.iter_peers().collect::<Vec<_>>(),
+            vec![
+                "127.0.0.1:6881".parse::<SocketAddr>().unwrap(),
+                "[2001:db8::1]:6881".parse().unwrap()
+            ]
+        );
+    }
+
+    #[test]
+    fn test_parse_tracker_id() {
+        let b = b"d8:completei1e10:incompletei2e8:intervali1800e10:tracker id3:abce";
+        let response = bencode::from_bytes::<TrackerResponse>(b).unwrap();
+        assert_eq!(response.tracker_id.unwrap().as_ref(), b"abc");
+    }
 }
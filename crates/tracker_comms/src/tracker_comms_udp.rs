@@ -1,9 +1,8 @@
-use std::net::{Ipv4Addr, SocketAddrV4};
+use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr};
 
 use anyhow::{bail, Context};
-use librqbit_core::hash_id::Id20;
+use librqbit_core::{bind::OutgoingBind, hash_id::Id20};
 use rand::Rng;
-use tokio::net::ToSocketAddrs;
 use tracing::trace;
 
 const ACTION_CONNECT: u32 = 0;
@@ -33,7 +32,11 @@ pub struct AnnounceFields {
     pub left: u64,
     pub uploaded: u64,
     pub event: u32,
+    // The address to give to peers instead of the one the tracker sees.
+    pub ip: Option<Ipv4Addr>,
     pub key: u32,
+    // How many peers to ask for. The tracker decides if None.
+    pub num_want: Option<u32>,
     pub port: u16,
 }
 
@@ -62,9 +65,10 @@ impl Request {
                 buf.extend_from_slice(&fields.left.to_be_bytes());
                 buf.extend_from_slice(&fields.uploaded.to_be_bytes());
                 buf.extend_from_slice(&fields.event.to_be_bytes());
-                buf.extend_from_slice(&0u32.to_be_bytes()); // ip address 0
+                buf.extend_from_slice(&fields.ip.map(u32::from).unwrap_or(0).to_be_bytes());
                 buf.extend_from_slice(&fields.key.to_be_bytes());
-                buf.extend_from_slice(&(-1i32).to_be_bytes()); // num want -1
+                let num_want = fields.num_want.map(|n| n.min(i32::MAX as u32) as i32);
+                buf.extend_from_slice(&num_want.unwrap_or(-1).to_be_bytes());
                 buf.extend_from_slice(&fields.port.to_be_bytes());
             }
         }
@@ -77,7 +81,7 @@ pub struct AnnounceResponse {
     pub interval: u32,
     pub leechers: u32,
     pub seeders: u32,
-    pub addrs: Vec<SocketAddrV4>,
+    pub addrs: Vec<SocketAddr>,
 }
 
 #[derive(Debug)]
@@ -124,7 +128,8 @@ parse_impl!(i64, 8);
 parse_impl!(i16, 2);
 
 impl Response {
-    pub fn parse(buf: &[u8]) -> anyhow::Result<(TransactionId, Self)> {
+    // Peers in announce responses are IPv6 if the tracker was contacted over IPv6 (BEP 15).
+    pub fn parse(buf: &[u8], ipv6: bool) -> anyhow::Result<(TransactionId, Self)> {
         let (action, buf) = u32::parse_num(buf).context("can't parse action")?;
         let (tid, mut buf) = u32::parse_num(buf).context("can't parse transaction id")?;
         let response = match action {
@@ -140,13 +145,19 @@ impl Response {
                 let (seeders, mut b) = u32::parse_num(b).context("can't parse seeders")?;
                 let mut addrs = Vec::new();
                 while !b.is_empty() {
-                    let (ip, b2) = u32::parse_num(b)?;
-                    let ip = Ipv4Addr::from(ip);
-                    b = b2;
+                    let ip = if ipv6 {
+                        let (ip, b2) = split_slice(b, 16).context("expected 16 bytes")?;
+                        b = b2;
+                        Ipv6Addr::from(s_to_arr::<16>(ip)).into()
+                    } else {
+                        let (ip, b2) = u32::parse_num(b)?;
+                        b = b2;
+                        Ipv4Addr::from(ip).into()
+                    };
 
                     let (port, b2) = u16::parse_num(b)?;
                     b = b2;
-                    addrs.push(SocketAddrV4::new(ip, port));
+                    addrs.push(SocketAddr::new(ip, port));
                 }
                 buf = b;
                 Response::Announce(AnnounceResponse {
@@ -172,15 +183,17 @@ impl Response {
 
 pub struct UdpTrackerRequester {
     sock: tokio::net::UdpSocket,
+    ipv6: bool,
     connection_id: ConnectionId,
     read_buf: Vec<u8>,
     write_buf: Vec<u8>,
 }
 
 impl UdpTrackerRequester {
-    // Addr is "host:port"
-    pub async fn new(addr: impl ToSocketAddrs) -> anyhow::Result<Self> {
-        let sock = tokio::net::UdpSocket::bind("0.0.0.0:0")
+    pub async fn new(addr: SocketAddr, bind: &OutgoingBind) -> anyhow::Result<Self> {
+        let ipv6 = addr.is_ipv6();
+        let sock = bind
+            .udp_socket(addr.ip())
             .await
             .context("error binding UDP socket")?;
         sock.connect(addr)
@@ -204,7 +217,7 @@ impl UdpTrackerRequester {
             .context("error receiving from socket")?;
 
         let (rtid, response) =
-            Response::parse(&read_buf[..size]).context("error parsing response")?;
+            Response::parse(&read_buf[..size], ipv6).context("error parsing response")?;
         if tid != rtid {
             bail!("expected transaction id {} == {}", tid, rtid);
         }
@@ -219,6 +232,7 @@ impl UdpTrackerRequester {
 
         Ok(Self {
             sock,
+            ipv6,
             connection_id,
             read_buf,
             write_buf,
@@ -245,7 +259,8 @@ impl UdpTrackerRequester {
             .context("error sending")?;
         let size = self.sock.recv(&mut self.read_buf).await.unwrap();
 
-        let (rtid, response) = Response::parse(&self.read_buf[..size]).unwrap();
+        let (rtid, response) =
+            Response::parse(&self.read_buf[..size], self.ipv6).context("error parsing response")?;
         trace!("received response");
         if tid != rtid {
             bail!("unexpected transaction id");
@@ -267,10 +282,61 @@ mod tests {
     #[test]
     fn test_parse_announce() {
         let b = include_bytes!("../resources/test/udp-tracker-announce-response.bin");
-        let (tid, response) = Response::parse(b).unwrap();
+        let (tid, response) = Response::parse(b, false).unwrap();
         dbg!(tid, response);
     }
 
+    #[test]
+    fn test_parse_announce_ipv6() {
+        let mut b = Vec::new();
+        for n in [1u32, 42, 1800, 2, 3] {
+            b.extend_from_slice(&n.to_be_bytes());
+        }
+        b.extend_from_slice(
+            &"2001:db8::1"
+                .parse::<std::net::Ipv6Addr>()
+                .unwrap()
+                .octets(),
+        );
+        b.extend_from_slice(&6881u16.to_be_bytes());
+        let (tid, response) = Response::parse(&b, true).unwrap();
+        assert_eq!(tid, 42);
+        match response {
+            Response::Announce(r) => {
+                assert_eq!(r.addrs, vec!["[2001:db8::1]:6881".parse().unwrap()])
+            }
+            other => panic!("unexpected {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_serialize_num_want() {
+        let serialize = |num_want| {
+            let request = Request::Announce(
+                1,
+                AnnounceFields {
+                    info_hash: Id20::default(),
+                    peer_id: Id20::default(),
+                    downloaded: 0,
+                    left: 0,
+                    uploaded: 0,
+                    event: EVENT_NONE,
+                    ip: None,
+                    key: 0,
+                    num_want,
+                    port: 6881,
+                },
+            );
+            let mut buf = Vec::new();
+            let size = request.serialize(2, &mut buf);
+            assert_eq!(size, 98);
+            i32::from_be_bytes([buf[92], buf[93], buf[94], buf[95]])
+        };
+        assert_eq!(serialize(Some(200)), 200);
+        // The tracker's default.
+        assert_eq!(serialize(None), -1);
+    }
+
     #[ignore]
     #[tokio::test]
     async fn test_announce() {
@@ -287,7 +353,7 @@ mod tests {
 
         let size = sock.recv(&mut read_buf).await.unwrap();
 
-        let (rtid, response) = Response::parse(&read_buf[..size]).unwrap();
+        let (rtid, response) = Response::parse(&read_buf[..size], false).unwrap();
         assert_eq!(tid, rtid);
         let connection_id = match response {
             Response::Connect(connection_id) => {
@@ -308,7 +374,9 @@ mod tests {
                 left: 0,
                 uploaded: 0,
                 event: EVENT_NONE,
-                key: 0, // whatever that is?
+                ip: None,
+                key: 0,
+                num_want: None,
                 port: 24563,
             },
         );
@@ -329,7 +397,7 @@ mod tests {
         }
 
         dbg!(&read_buf[..size]);
-        let (rtid, response) = Response::parse(&read_buf[..size]).unwrap();
+        let (rtid, response) = Response::parse(&read_buf[..size], false).unwrap();
         assert_eq!(tid, rtid);
         match response {
             Response::Announce(r) => {